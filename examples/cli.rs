@@ -41,6 +41,7 @@ async fn main() {
                     &log.title
                 );
             }
+            _ => {}
         }
     }
 }
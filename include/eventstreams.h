@@ -0,0 +1,87 @@
+#ifndef EVENTSTREAMS_H
+#define EVENTSTREAMS_H
+
+// Generated by cbindgen from src/ffi.rs, don't edit by hand
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// A stream created by [`es_stream_new()`]
+typedef struct EsStream EsStream;
+
+// Called with each event as a JSON document, which is only valid during
+// the call, and the `user_data` given when registering. Called from the
+// stream's background thread.
+typedef void (*EsEventCallback)(const char *json, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The reason the last call on this thread failed, or null. The string
+// is valid until the next call that fails.
+const char *es_last_error(void);
+
+// Create a stream reading `streams`, a comma-separated list of stream
+// names such as `recentchange,page-create`, or recent changes if it's
+// null. Returns null on failure.
+//
+// # Safety
+//
+// `streams` must be null or a nul-terminated string.
+EsStream *es_stream_new(const char *streams);
+
+// Connect to `url` instead of Wikimedia's EventStreams, see
+// [`EventStreamBuilder::url()`]. Fails if `url` isn't a valid URL. Only
+// before the stream is started.
+//
+// # Safety
+//
+// `stream` must come from [`es_stream_new()`] and not be closed, and
+// `url` must be a nul-terminated string.
+int es_stream_set_url(EsStream *stream, const char *url);
+
+// Identify the tool to Wikimedia, as its
+// [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+// asks. Only before the stream is started.
+//
+// # Safety
+//
+// `stream` must come from [`es_stream_new()`] and not be closed, and
+// `user_agent` must be a nul-terminated string.
+int es_stream_set_user_agent(EsStream *stream, const char *user_agent);
+
+// Call `callback` with every event once the stream is started. Only
+// before the stream is started.
+//
+// # Safety
+//
+// `stream` must come from [`es_stream_new()`] and not be closed, and
+// `callback` must be safe to call with `user_data` from another thread
+// until the stream is closed.
+int es_stream_on_event(EsStream *stream, EsEventCallback callback, void *user_data);
+
+// Start reading events on a background thread. Fails if the stream can't
+// be built from its configuration, e.g. a proxy URL that doesn't parse.
+//
+// # Safety
+//
+// `stream` must come from [`es_stream_new()`] and not be closed.
+int es_stream_start(EsStream *stream);
+
+// Stop the stream, waiting for its thread to finish, and free it. Does
+// nothing if `stream` is null.
+//
+// # Safety
+//
+// `stream` must come from [`es_stream_new()`] and not be closed already.
+// It must not be called from one of the stream's callbacks.
+void es_stream_close(EsStream *stream);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif // EVENTSTREAMS_H
@@ -0,0 +1,602 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Reading facts out of `log_action_comment` in any language
+//!
+//! [`LogEvent::log_action_comment`] is rendered from MediaWiki interface
+//! messages in the wiki's content language, e.g. `blocked [[User:Example]]
+//! with an expiration time of 1 week` on English wikis but
+//! `sperrte „[[Benutzer:Example]]“ …` on German ones. [`LogMessages`]
+//! holds the message templates for a language and matches comments
+//! against them to get a structured [`ActionFacts`]. English templates are
+//! built in; with the `enrichment` feature, [`LogMessageClient`] fetches
+//! each wiki's own from its Action API, including local customizations.
+//!
+//! When no template matches, the facts fall back to the wikilinks in the
+//! comment, which are the same in every language.
+#[cfg(feature = "enrichment")]
+use crate::api::{ApiClient, ApiError};
+#[cfg(feature = "enrichment")]
+use crate::cache::{Cache, MemoryCache};
+use crate::LogEvent;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "enrichment")]
+use serde_json::Value;
+use std::collections::HashMap;
+#[cfg(feature = "enrichment")]
+use std::sync::Arc;
+
+/// Interface messages that log action comments are rendered from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LogMessage {
+    /// `block/block`, with the target, expiry and block options
+    Block,
+    /// `block/reblock`, like [`Block`](Self::Block)
+    Reblock,
+    /// `block/unblock`, with the target
+    Unblock,
+    /// `move/move`, with the old and new titles
+    Move,
+    /// `move/move_redir`, like [`Move`](Self::Move)
+    MoveOverRedirect,
+    /// `delete/delete`, with the page
+    Delete,
+    /// `protect/protect`, with the page and protection levels
+    Protect,
+    /// `protect/modify`, like [`Protect`](Self::Protect)
+    ModifyProtection,
+    /// `protect/unprotect`, with the page
+    Unprotect,
+    /// `rights/rights`, with the target and old and new groups
+    Rights,
+}
+
+impl LogMessage {
+    pub const ALL: [LogMessage; 10] = [
+        LogMessage::Block,
+        LogMessage::Reblock,
+        LogMessage::Unblock,
+        LogMessage::Move,
+        LogMessage::MoveOverRedirect,
+        LogMessage::Delete,
+        LogMessage::Protect,
+        LogMessage::ModifyProtection,
+        LogMessage::Unprotect,
+        LogMessage::Rights,
+    ];
+
+    /// Key of the MediaWiki message, e.g. `blocklogentry`
+    pub fn key(self) -> &'static str {
+        match self {
+            LogMessage::Block => "blocklogentry",
+            LogMessage::Reblock => "reblock-logentry",
+            LogMessage::Unblock => "unblocklogentry",
+            LogMessage::Move => "1movedto2",
+            LogMessage::MoveOverRedirect => "1movedto2_redir",
+            LogMessage::Delete => "deletedarticle",
+            LogMessage::Protect => "protectedarticle",
+            LogMessage::ModifyProtection => "modifiedarticleprotection",
+            LogMessage::Unprotect => "unprotectedarticle",
+            LogMessage::Rights => "rightslogentry",
+        }
+    }
+
+    /// The message a log entry's comment is rendered from, if it's one of
+    /// the supported ones
+    pub fn for_log(log_type: &str, log_action: &str) -> Option<Self> {
+        Some(match (log_type, log_action) {
+            ("block", "block") => LogMessage::Block,
+            ("block", "reblock") => LogMessage::Reblock,
+            ("block", "unblock") => LogMessage::Unblock,
+            ("move", "move") => LogMessage::Move,
+            ("move", "move_redir") => LogMessage::MoveOverRedirect,
+            ("delete", "delete") => LogMessage::Delete,
+            ("protect", "protect") => LogMessage::Protect,
+            ("protect", "modify") => LogMessage::ModifyProtection,
+            ("protect", "unprotect") => LogMessage::Unprotect,
+            ("rights", "rights") => LogMessage::Rights,
+            _ => return None,
+        })
+    }
+
+    /// Default English text, with `$1`, `$2`… for the parameters
+    fn english(self) -> &'static str {
+        match self {
+            LogMessage::Block => {
+                "blocked [[$1]] with an expiration time of $2 $3"
+            }
+            LogMessage::Reblock => {
+                "changed block settings for [[$1]] with an expiration time of $2 $3"
+            }
+            LogMessage::Unblock => "unblocked $1",
+            LogMessage::Move => "moved [[$1]] to [[$2]]",
+            LogMessage::MoveOverRedirect => {
+                "moved [[$1]] to [[$2]] over redirect"
+            }
+            LogMessage::Delete => "deleted \"[[$1]]\"",
+            LogMessage::Protect => "protected \"[[$1]]\"",
+            LogMessage::ModifyProtection => {
+                "changed protection level for \"[[$1]]\""
+            }
+            LogMessage::Unprotect => "removed protection from \"[[$1]]\"",
+            LogMessage::Rights => {
+                "changed group membership for $1 from $2 to $3"
+            }
+        }
+    }
+}
+
+/// What a log action comment says, as far as it could be read
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ActionFacts {
+    /// User acted on, e.g. blocked or given rights, without the namespace
+    pub user: Option<String>,
+    /// Prefixed title of the page acted on, e.g. the old title of a move
+    pub page: Option<String>,
+    /// Prefixed title a page was moved to
+    pub moved_to: Option<String>,
+    /// Block expiry as written, e.g. `1 week` or `infinite`
+    pub expiry: Option<String>,
+    /// Whether a message template matched, rather than only wikilinks
+    pub matched: bool,
+}
+
+/// Message templates for one language
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogMessages {
+    templates: HashMap<LogMessage, String>,
+}
+
+impl LogMessages {
+    /// No templates, so only wikilinks are used
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// MediaWiki's default English messages
+    pub fn english() -> Self {
+        let mut messages = Self::new();
+        for message in LogMessage::ALL {
+            messages.insert(message, message.english());
+        }
+        messages
+    }
+
+    /// Use `template`, e.g. from `MediaWiki:Blocklogentry`, for `message`
+    pub fn insert(&mut self, message: LogMessage, template: &str) {
+        self.templates.insert(message, template.to_string());
+    }
+
+    pub fn get(&self, message: LogMessage) -> Option<&str> {
+        self.templates.get(&message).map(String::as_str)
+    }
+
+    /// Read the facts out of `log`'s action comment
+    pub fn parse(&self, log: &LogEvent) -> ActionFacts {
+        let text = action_text(&log.log_action_comment, &log.comment);
+        let message = LogMessage::for_log(&log.log_type, &log.log_action);
+        let matched = message.and_then(|message| {
+            let template = self.get(message)?;
+            Some((message, match_message(message, template, &text)?))
+        });
+        match matched {
+            Some((message, params)) => facts(message, &params),
+            None => fallback(message, &text),
+        }
+    }
+}
+
+/// The action text without the reason appended after it, and with HTML
+/// escaping undone
+fn action_text(action_comment: &str, reason: &str) -> String {
+    let text = unescape(action_comment);
+    let reason = reason.trim();
+    let text = match text.strip_suffix(reason) {
+        Some(text) if !reason.is_empty() => text,
+        _ => &text,
+    };
+    // The colon-separator differs between languages, e.g. ` : ` or `：`
+    text.trim_end_matches(|c: char| {
+        c.is_whitespace() || c == ':' || c == '：' || is_direction_mark(c)
+    })
+    .trim_start_matches(is_direction_mark)
+    .to_string()
+}
+
+fn is_direction_mark(c: char) -> bool {
+    c == '\u{200e}' || c == '\u{200f}'
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#039;", "'")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Match `text` against `template`, returning its parameters by number.
+/// Templates with wikitext magic like `{{GENDER:…}}` aren't supported.
+fn match_message(
+    message: LogMessage,
+    template: &str,
+    text: &str,
+) -> Option<HashMap<u8, String>> {
+    if template.contains("{{") {
+        return None;
+    }
+    let mut template = template.trim();
+    let mut text = text.trim();
+    let mut params = HashMap::new();
+    // Block options come last in parentheses, but may be left out, and
+    // the expiry before them can contain spaces
+    if matches!(message, LogMessage::Block | LogMessage::Reblock) {
+        if let Some(stripped) = template.strip_suffix("$3") {
+            template = stripped.trim_end();
+            if text.ends_with(')') {
+                if let Some(open) = text.rfind(" (") {
+                    params.insert(3, text[open + 1..].to_string());
+                    text = &text[..open];
+                }
+            }
+        }
+    }
+    let (literal, mut rest) = split_placeholder(template);
+    text = text.strip_prefix(literal)?;
+    while let Some((number, after)) = rest {
+        let (literal, next) = split_placeholder(after);
+        let value = if next.is_none() {
+            // The last literal has to end the text
+            let value = text.strip_suffix(literal)?;
+            text = "";
+            value
+        } else if literal.is_empty() {
+            return None;
+        } else {
+            let end = text.find(literal)?;
+            let value = &text[..end];
+            text = &text[end + literal.len()..];
+            value
+        };
+        params.insert(number, value.trim().to_string());
+        rest = next;
+    }
+    Some(params)
+}
+
+/// Split off the literal text up to the next `$n`, and the number and
+/// everything after it
+fn split_placeholder(template: &str) -> (&str, Option<(u8, &str)>) {
+    let mut search = 0;
+    while let Some(found) = template[search..].find('$') {
+        let start = search + found;
+        let digits = template[start + 1..]
+            .find(|c: char| !c.is_ascii_digit())
+            .map_or(template.len(), |end| start + 1 + end);
+        if let Ok(number) = template[start + 1..digits].parse() {
+            return (&template[..start], Some((number, &template[digits..])));
+        }
+        search = start + 1;
+    }
+    (template, None)
+}
+
+fn facts(message: LogMessage, params: &HashMap<u8, String>) -> ActionFacts {
+    let param = |number| {
+        params
+            .get(&number)
+            .map(|value| unlink(value))
+            .filter(|value| !value.is_empty())
+    };
+    let mut facts = ActionFacts {
+        matched: true,
+        ..ActionFacts::default()
+    };
+    match message {
+        LogMessage::Block | LogMessage::Reblock => {
+            facts.user = param(1).map(|title| without_namespace(&title));
+            facts.expiry = param(2);
+        }
+        LogMessage::Unblock | LogMessage::Rights => {
+            facts.user = param(1).map(|title| without_namespace(&title));
+        }
+        LogMessage::Move | LogMessage::MoveOverRedirect => {
+            facts.page = param(1);
+            facts.moved_to = param(2);
+        }
+        LogMessage::Delete
+        | LogMessage::Protect
+        | LogMessage::ModifyProtection
+        | LogMessage::Unprotect => {
+            // Protection levels follow the title in the same parameter
+            facts.page = param(1).map(|page| match page.find(" [") {
+                Some(levels) => page[..levels].to_string(),
+                None => page,
+            });
+        }
+    }
+    facts
+}
+
+/// Facts from the wikilinks alone
+fn fallback(message: Option<LogMessage>, text: &str) -> ActionFacts {
+    let links = crate::comment::wikilinks(text);
+    let mut facts = ActionFacts::default();
+    match message {
+        Some(
+            LogMessage::Block
+            | LogMessage::Reblock
+            | LogMessage::Unblock
+            | LogMessage::Rights,
+        ) => facts.user = links.first().map(|title| without_namespace(title)),
+        Some(LogMessage::Move | LogMessage::MoveOverRedirect) => {
+            facts.page = links.first().cloned();
+            facts.moved_to = links.get(1).cloned();
+        }
+        _ => facts.page = links.first().cloned(),
+    }
+    facts
+}
+
+/// `value` without surrounding `[[…]]`
+fn unlink(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix("[[").and_then(|v| v.strip_suffix("]]")) {
+        Some(target) => target.split('|').next().unwrap_or_default().into(),
+        None => value.to_string(),
+    }
+}
+
+/// `User:Example` → `Example`, in any language
+fn without_namespace(title: &str) -> String {
+    match title.split_once(':') {
+        Some((_, name)) => name.to_string(),
+        None => title.to_string(),
+    }
+}
+
+/// Fetches and caches each wiki's [`LogMessages`] in its content language
+#[cfg(feature = "enrichment")]
+#[derive(Clone)]
+pub struct LogMessageClient {
+    api: ApiClient,
+    cache: Arc<dyn Cache>,
+    ttl: std::time::Duration,
+}
+
+#[cfg(feature = "enrichment")]
+impl LogMessageClient {
+    pub fn new() -> Self {
+        Self {
+            api: ApiClient::new(),
+            cache: Arc::new(MemoryCache::default()),
+            ttl: std::time::Duration::from_secs(24 * 3600),
+        }
+    }
+
+    /// Make requests with `api`, e.g. to share its limits with other
+    /// stages
+    pub fn api(mut self, api: ApiClient) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// Keep messages in `cache` instead, e.g. one shared with other stages
+    pub fn cache(mut self, cache: Arc<dyn Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// How long messages are cached for (default 1 day)
+    pub fn ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Fetch the messages via the API at `api_url`, e.g. from
+    /// [`LogEvent::api_url()`]. Messages the wiki doesn't have are left
+    /// out.
+    pub async fn messages(
+        &self,
+        api_url: &str,
+    ) -> Result<LogMessages, ApiError> {
+        let key = format!("logmessages:{}", api_url);
+        if let Some(messages) = self
+            .cache
+            .get(&key)
+            .and_then(|cached| serde_json::from_str(&cached).ok())
+        {
+            return Ok(messages);
+        }
+        let keys: Vec<_> = LogMessage::ALL
+            .iter()
+            .map(|message| message.key())
+            .collect();
+        let mut url = surf::Url::parse(api_url)?;
+        url.query_pairs_mut()
+            .append_pair("action", "query")
+            .append_pair("meta", "allmessages")
+            .append_pair("ammessages", &keys.join("|"))
+            .append_pair("amlang", "content")
+            .append_pair("format", "json")
+            .append_pair("formatversion", "2");
+        let resp: Value = self.api.get(url).await?;
+        let mut messages = LogMessages::new();
+        for found in resp["query"]["allmessages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            let message = LogMessage::ALL
+                .iter()
+                .copied()
+                .find(|message| found["name"] == message.key());
+            if let (Some(message), Some(template)) =
+                (message, found["content"].as_str())
+            {
+                messages.insert(message, template);
+            }
+        }
+        if let Ok(cached) = serde_json::to_string(&messages) {
+            self.cache.put(&key, cached, self.ttl);
+        }
+        Ok(messages)
+    }
+
+    /// Read the facts out of `log`'s action comment using its wiki's
+    /// messages
+    pub async fn parse(&self, log: &LogEvent) -> Result<ActionFacts, ApiError> {
+        Ok(self.messages(&log.api_url()).await?.parse(log))
+    }
+}
+
+#[cfg(feature = "enrichment")]
+impl std::fmt::Debug for LogMessageClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LogMessageClient")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "enrichment")]
+impl Default for LogMessageClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    fn log(
+        log_type: &str,
+        action: &str,
+        comment: &str,
+        reason: &str,
+    ) -> LogEvent {
+        let mut log = testing::log(1, "A", at(0));
+        log["log_type"] = log_type.into();
+        log["log_action"] = action.into();
+        log["log_action_comment"] = comment.into();
+        log["comment"] = reason.into();
+        match testing::event(&log) {
+            Event::Log(log) => log,
+            other => panic!("not a log entry: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn matches_english_templates() {
+        let english = LogMessages::english();
+        let block = log(
+            "block",
+            "block",
+            "blocked [[User:Vandal]] with an expiration time of 1 week \
+             (account creation disabled): Vandalism",
+            "Vandalism",
+        );
+        assert_eq!(
+            english.parse(&block),
+            ActionFacts {
+                user: Some("Vandal".to_string()),
+                expiry: Some("1 week".to_string()),
+                matched: true,
+                ..ActionFacts::default()
+            }
+        );
+        let moved = log(
+            "move",
+            "move",
+            "moved [[Old &amp; busted]] to [[New]]: Rename",
+            "Rename",
+        );
+        let facts = english.parse(&moved);
+        assert_eq!(facts.page.as_deref(), Some("Old & busted"));
+        assert_eq!(facts.moved_to.as_deref(), Some("New"));
+        let protect = log(
+            "protect",
+            "protect",
+            "protected \"[[Main Page [edit=sysop] (indefinite)]]\"",
+            "",
+        );
+        assert_eq!(english.parse(&protect).page.as_deref(), Some("Main Page"));
+    }
+
+    #[test]
+    fn reads_other_languages() {
+        let mut german = LogMessages::new();
+        german.insert(
+            LogMessage::Block,
+            "sperrte „[[$1]]“ für einen Zeitraum von $2 $3",
+        );
+        let block = log(
+            "block",
+            "block",
+            "sperrte „[[Benutzer:Vandale]]“ für einen Zeitraum von 1 Tag \
+             (Benutzerkonten-Erstellung gesperrt)",
+            "",
+        );
+        let facts = german.parse(&block);
+        assert_eq!(facts.user.as_deref(), Some("Vandale"));
+        assert_eq!(facts.expiry.as_deref(), Some("1 Tag"));
+        assert!(facts.matched);
+
+        let moved = log(
+            "move",
+            "move",
+            "verschob die Seite [[Alt]] nach [[Neu]]",
+            "",
+        );
+        assert_eq!(
+            german.parse(&moved),
+            ActionFacts {
+                page: Some("Alt".to_string()),
+                moved_to: Some("Neu".to_string()),
+                ..ActionFacts::default()
+            }
+        );
+    }
+
+    #[cfg(feature = "enrichment")]
+    #[test]
+    fn fetches_the_wikis_messages() {
+        let server = testing::serve_json(vec![serde_json::json!({
+            "query": { "allmessages": [
+                { "name": "1movedto2", "content": "verschob [[$1]] nach [[$2]]" },
+                { "name": "deletedarticle", "missing": true },
+            ] },
+        })]);
+        let client =
+            LogMessageClient::new().api(ApiClient::new().rate_limit(1000.0));
+        let api_url = format!("http://{}/w/api.php", server.addr());
+        let messages =
+            futures::executor::block_on(client.messages(&api_url)).unwrap();
+        assert_eq!(
+            messages.get(LogMessage::Move),
+            Some("verschob [[$1]] nach [[$2]]")
+        );
+        assert_eq!(messages.get(LogMessage::Delete), None);
+        // Cached, so the server isn't asked again
+        futures::executor::block_on(client.messages(&api_url)).unwrap();
+        assert_eq!(server.requests().len(), 1);
+        assert!(server.requests()[0].path.contains("amlang=content"));
+    }
+}
@@ -0,0 +1,351 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! HTTP API for managing a running daemon
+//!
+//! Every request needs an `Authorization: Bearer <token>` header. Bodies
+//! and responses are JSON:
+//!
+//! * `GET /subscriptions` lists all [`SubscriptionDef`]s
+//! * `GET /subscriptions/<name>` returns one definition
+//! * `PUT /subscriptions/<name>` adds or replaces a definition
+//! * `DELETE /subscriptions/<name>` removes it
+//! * `POST /subscriptions/<name>/enable` and `.../disable`
+//! * `POST /subscriptions/<name>/promote` switches to the candidate filter
+//! * `GET /metrics` returns a [`MetricsSnapshot`](crate::metrics::MetricsSnapshot)
+//! * `GET /debug` returns a [`DaemonSnapshot`], e.g. to attach to a bug
+//!   report
+use crate::debug::DaemonSnapshot;
+use crate::metrics::Metrics;
+use crate::subscription::{
+    RegistryError, SubscriptionDef, SubscriptionRegistry,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+/// Largest request body that will be accepted
+const MAX_BODY: usize = 1024 * 1024;
+
+/// How long a client may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) struct Request {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: HashMap<String, String>,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Read an HTTP/1.1 request. Header names are lowercased.
+pub(crate) fn read_request(stream: &TcpStream) -> io::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("GET").to_string();
+    let path = parts.next().unwrap_or("/").to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers
+                .insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+    let length: usize = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    if length > MAX_BODY {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request body too large",
+        ));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+pub(crate) fn write_response(
+    mut stream: TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+/// Compare without returning early, so the token can't be guessed from
+/// response times
+fn token_matches(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub(crate) fn serve(
+    listener: TcpListener,
+    registry: SubscriptionRegistry,
+    metrics: Metrics,
+    token: String,
+) {
+    // Each connection on its own thread, so a slow client doesn't hold up
+    // the others
+    for stream in listener.incoming().flatten() {
+        let registry = registry.clone();
+        let metrics = metrics.clone();
+        let token = token.clone();
+        thread::spawn(move || {
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            handle(stream, &registry, &metrics, &token)
+        });
+    }
+}
+
+fn json(value: &impl Serialize) -> (&'static str, String) {
+    ("200 OK", serde_json::to_string(value).unwrap())
+}
+
+fn error(status: &'static str, message: &str) -> (&'static str, String) {
+    (status, serde_json::json!({ "error": message }).to_string())
+}
+
+fn handle(
+    stream: TcpStream,
+    registry: &SubscriptionRegistry,
+    metrics: &Metrics,
+    token: &str,
+) -> io::Result<()> {
+    let request = match read_request(&stream) {
+        Ok(request) => request,
+        Err(err) => {
+            let (status, body) = error("400 Bad Request", &err.to_string());
+            return write_response(stream, status, "application/json", &body);
+        }
+    };
+    let authorized = request
+        .headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| token_matches(given, token));
+    let (status, body) = if authorized {
+        route(&request, registry, metrics)
+    } else {
+        error("401 Unauthorized", "missing or invalid token")
+    };
+    write_response(stream, status, "application/json", &body)
+}
+
+fn route(
+    request: &Request,
+    registry: &SubscriptionRegistry,
+    metrics: &Metrics,
+) -> (&'static str, String) {
+    let segments: Vec<&str> =
+        request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics"]) => json(&metrics.snapshot()),
+        ("GET", ["debug"]) => {
+            json(&DaemonSnapshot::new(registry.list(), metrics.snapshot()))
+        }
+        ("GET", ["subscriptions"]) => json(&registry.list()),
+        ("GET", ["subscriptions", name]) => match registry.get(name) {
+            Some(def) => json(&def),
+            None => error("404 Not Found", "no such subscription"),
+        },
+        ("PUT", ["subscriptions", name]) => {
+            // The name comes from the path, so it's optional in the body
+            let mut body: serde_json::Value =
+                match serde_json::from_slice(&request.body) {
+                    Ok(body) => body,
+                    Err(err) => {
+                        return error("400 Bad Request", &err.to_string())
+                    }
+                };
+            match body.as_object_mut() {
+                Some(fields) => {
+                    fields.insert("name".to_string(), (*name).into());
+                }
+                None => {
+                    return error("400 Bad Request", "body must be an object")
+                }
+            }
+            let def = match serde_json::from_value::<SubscriptionDef>(body) {
+                Ok(def) => def,
+                Err(err) => return error("400 Bad Request", &err.to_string()),
+            };
+            match registry.define(def.clone()) {
+                Ok(()) => json(&def),
+                Err(err) => error("400 Bad Request", &err.to_string()),
+            }
+        }
+        ("DELETE", ["subscriptions", name]) => match registry.remove(name) {
+            Some(def) => json(&def),
+            None => error("404 Not Found", "no such subscription"),
+        },
+        ("POST", ["subscriptions", name, action @ ("enable" | "disable")]) => {
+            match registry.set_enabled(name, *action == "enable") {
+                Ok(()) => json(&registry.get(name)),
+                Err(err) => error("404 Not Found", &err.to_string()),
+            }
+        }
+        ("POST", ["subscriptions", name, "promote"]) => {
+            match registry.promote(name) {
+                Ok(()) => json(&registry.get(name)),
+                Err(err @ RegistryError::NoCandidate(_)) => {
+                    error("409 Conflict", &err.to_string())
+                }
+                Err(err) => error("404 Not Found", &err.to_string()),
+            }
+        }
+        _ => error("404 Not Found", "not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::StdoutSink;
+    use std::net::SocketAddr;
+
+    fn start() -> (SocketAddr, SubscriptionRegistry) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let registry = SubscriptionRegistry::new();
+        registry.register_sink("stdout", || StdoutSink);
+        let metrics = Metrics::new();
+        metrics.increment("daemon.events", 3);
+        let served = registry.clone();
+        std::thread::spawn(move || {
+            serve(listener, served, metrics, "secret".to_string())
+        });
+        (addr, registry)
+    }
+
+    /// Send a request, returning the status line and body
+    fn send(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: &str,
+        body: &str,
+    ) -> (String, serde_json::Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\n\
+             Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn manages_subscriptions() {
+        let (addr, registry) = start();
+        let (status, _) = send(addr, "GET", "/subscriptions", "wrong", "");
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+
+        let def = r#"{"filter": {"wikis": ["enwiki"]}, "sink": "stdout"}"#;
+        let (status, body) =
+            send(addr, "PUT", "/subscriptions/en", "secret", def);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["name"], "en");
+        assert!(registry.get("en").is_some());
+        let bad = r#"{"sink": "nowhere"}"#;
+        let (status, _) = send(addr, "PUT", "/subscriptions/x", "secret", bad);
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        for bad in ["[1]", "\"x\"", "5"] {
+            let (status, _) =
+                send(addr, "PUT", "/subscriptions/x", "secret", bad);
+            assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        }
+
+        let path = "/subscriptions/en/disable";
+        let (_, body) = send(addr, "POST", path, "secret", "");
+        assert_eq!(body["enabled"], false);
+        let path = "/subscriptions/en/promote";
+        let (status, _) = send(addr, "POST", path, "secret", "");
+        assert_eq!(status, "HTTP/1.1 409 Conflict");
+
+        let (_, body) = send(addr, "GET", "/metrics", "secret", "");
+        assert_eq!(body["counters"]["daemon.events"], 3);
+        let (status, _) =
+            send(addr, "DELETE", "/subscriptions/en", "secret", "");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        let (status, _) = send(addr, "GET", "/subscriptions/en", "secret", "");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        assert!(registry.list().is_empty());
+    }
+
+    #[test]
+    fn is_not_held_up_by_idle_clients() {
+        let (addr, _) = start();
+        let _idle = TcpStream::connect(addr).unwrap();
+        let (status, _) = send(addr, "GET", "/subscriptions", "secret", "");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+    }
+
+    #[test]
+    fn serves_debug_snapshots() {
+        let (addr, registry) = start();
+        registry
+            .define(SubscriptionDef::new(
+                "en",
+                crate::filter::Filter::new(),
+                "stdout",
+            ))
+            .unwrap();
+        let (status, body) = send(addr, "GET", "/debug", "secret", "");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(body["subscriptions"][0]["name"], "en");
+        assert_eq!(body["metrics"]["counters"]["daemon.events"], 3);
+    }
+}
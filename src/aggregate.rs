@@ -0,0 +1,378 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Running totals over event-time windows
+//!
+//! Where [`TumblingWindows`](crate::window::TumblingWindows) hands over
+//! every event in a window, an [`Aggregator`] only keeps running totals,
+//! so e.g. counting edits per wiki per minute takes memory for each wiki
+//! rather than each event. Windows are based on when events happened
+//! (`meta.dt`), and are either tumbling or sliding. Like other windows,
+//! they are closed once the [`Watermark`] passes their end.
+//!
+//! What is totalled up is up to an [`Aggregate`]. [`EditsPerWiki`],
+//! [`TopEditors`] and [`BytesPerNamespace`] are built in, e.g. for stats
+//! dashboards.
+use crate::watermark::Watermark;
+use crate::Event;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::{BTreeMap, HashMap};
+
+/// Totals for a window, built up one event at a time
+pub trait Aggregate: Clone {
+    /// Summary of a window
+    type Output;
+
+    /// Count `event`
+    fn add(&mut self, event: &Event);
+
+    /// Add the totals of `other`, which covers a different part of the
+    /// same window. Sliding windows are made up of several parts.
+    fn merge(&mut self, other: &Self);
+
+    /// Summary of everything counted so far
+    fn finish(&self) -> Self::Output;
+}
+
+/// The summary of a closed window
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary<T> {
+    /// Inclusive start of the window
+    pub start: DateTime<Utc>,
+    /// Exclusive end of the window
+    pub end: DateTime<Utc>,
+    pub value: T,
+    /// Events that arrived after all of their windows had been closed
+    /// since the previous summary, and weren't counted
+    pub late: u64,
+}
+
+/// Keeps an [`Aggregate`] for each window and emits a [`Summary`] once the
+/// window closes, see the [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct Aggregator<A> {
+    size: Duration,
+    slide: Duration,
+    empty: A,
+    watermark: Watermark,
+    /// Totals for each `slide` of time, keyed by start
+    panes: BTreeMap<DateTime<Utc>, A>,
+    /// Start of the next window to close
+    next: Option<DateTime<Utc>>,
+    late: u64,
+}
+
+impl<A: Aggregate> Aggregator<A> {
+    /// Windows of length `size` that don't overlap, aligned to the Unix
+    /// epoch, starting from `aggregate` with nothing counted. They close
+    /// once events are `allowed_lateness` past their end.
+    pub fn tumbling(
+        size: std::time::Duration,
+        allowed_lateness: std::time::Duration,
+        aggregate: A,
+    ) -> Self {
+        Self::sliding(size, size, allowed_lateness, aggregate)
+    }
+
+    /// Windows of length `size` starting every `slide`, e.g. the last hour
+    /// every minute. `slide` has to be at least a millisecond, and `size`
+    /// a multiple of it.
+    pub fn sliding(
+        size: std::time::Duration,
+        slide: std::time::Duration,
+        allowed_lateness: std::time::Duration,
+        aggregate: A,
+    ) -> Self {
+        let size = Duration::from_std(size).expect("window size out of range");
+        let slide = Duration::from_std(slide).expect("slide out of range");
+        // Panes are aligned in whole milliseconds
+        let (size_ms, slide_ms) =
+            (size.num_milliseconds(), slide.num_milliseconds());
+        assert!(slide_ms > 0, "slide must be at least a millisecond");
+        assert!(
+            size >= slide && size_ms % slide_ms == 0,
+            "window size must be a multiple of the slide"
+        );
+        Self {
+            size,
+            slide,
+            empty: aggregate,
+            watermark: Watermark::new(allowed_lateness),
+            panes: BTreeMap::new(),
+            next: None,
+            late: 0,
+        }
+    }
+
+    fn pane_start(&self, dt: DateTime<Utc>) -> DateTime<Utc> {
+        let slide = self.slide.num_milliseconds();
+        let millis = dt.timestamp_millis();
+        Utc.timestamp_millis_opt(millis - millis.rem_euclid(slide))
+            .unwrap()
+    }
+
+    /// Count an event, returning the summaries of any windows that were
+    /// closed as a result
+    pub fn push(&mut self, event: &Event) -> Vec<Summary<A::Output>> {
+        let pane = self.pane_start(event.dt());
+        if self.next.is_some_and(|next| pane < next) {
+            self.late += 1;
+            return vec![];
+        }
+        self.watermark.observe(event);
+        let empty = &self.empty;
+        self.panes
+            .entry(pane)
+            .or_insert_with(|| empty.clone())
+            .add(event);
+        self.close(self.watermark.current())
+    }
+
+    /// Close all remaining windows, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<Summary<A::Output>> {
+        self.close(None)
+    }
+
+    /// Close windows ending at or before `watermark`, or all of them
+    fn close(
+        &mut self,
+        watermark: Option<DateTime<Utc>>,
+    ) -> Vec<Summary<A::Output>> {
+        let mut closed = vec![];
+        while let Some(&first) = self.panes.keys().next() {
+            // Skip over windows with nothing in them
+            let earliest = first - self.size + self.slide;
+            let start = self.next.map_or(earliest, |next| next.max(earliest));
+            let end = start + self.size;
+            if watermark.is_some_and(|watermark| end > watermark) {
+                break;
+            }
+            let mut total = self.empty.clone();
+            for pane in self.panes.range(start..end).map(|(_, pane)| pane) {
+                total.merge(pane);
+            }
+            closed.push(Summary {
+                start,
+                end,
+                value: total.finish(),
+                late: std::mem::take(&mut self.late),
+            });
+            let next = start + self.slide;
+            self.next = Some(next);
+            // Later windows all start after the panes before `next`
+            self.panes = self.panes.split_off(&next);
+        }
+        closed
+    }
+}
+
+/// Edits per wiki (internal database name), including page creations
+#[derive(Clone, Debug, Default)]
+pub struct EditsPerWiki {
+    counts: BTreeMap<String, u64>,
+}
+
+impl EditsPerWiki {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Aggregate for EditsPerWiki {
+    type Output = BTreeMap<String, u64>;
+
+    fn add(&mut self, event: &Event) {
+        if let Event::Edit(edit) | Event::New(edit) = event {
+            *self.counts.entry(edit.wiki.to_string()).or_default() += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (wiki, count) in &other.counts {
+            *self.counts.entry(wiki.clone()).or_default() += count;
+        }
+    }
+
+    fn finish(&self) -> Self::Output {
+        self.counts.clone()
+    }
+}
+
+/// The users with the most edits, including page creations, across all
+/// wikis. Since accounts are global, the same name on different wikis is
+/// counted as the same user.
+#[derive(Clone, Debug)]
+pub struct TopEditors {
+    limit: usize,
+    bots: bool,
+    counts: HashMap<String, u64>,
+}
+
+impl TopEditors {
+    /// Keep the `limit` users with the most edits
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            bots: true,
+            counts: HashMap::new(),
+        }
+    }
+
+    /// Leave out edits flagged as made by bots
+    pub fn without_bots(mut self) -> Self {
+        self.bots = false;
+        self
+    }
+}
+
+impl Aggregate for TopEditors {
+    /// Users and their number of edits, most first
+    type Output = Vec<(String, u64)>;
+
+    fn add(&mut self, event: &Event) {
+        if let Event::Edit(edit) | Event::New(edit) = event {
+            if self.bots || !edit.bot {
+                *self.counts.entry(edit.user.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (user, count) in &other.counts {
+            *self.counts.entry(user.clone()).or_default() += count;
+        }
+    }
+
+    fn finish(&self) -> Self::Output {
+        let mut top: Vec<_> = self
+            .counts
+            .iter()
+            .map(|(user, count)| (user.clone(), *count))
+            .collect();
+        top.sort_by(|(a, a_count), (b, b_count)| {
+            b_count.cmp(a_count).then_with(|| a.cmp(b))
+        });
+        top.truncate(self.limit);
+        top
+    }
+}
+
+/// Bytes added and removed by edits in a namespace
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByteCounts {
+    pub added: u64,
+    pub removed: u64,
+}
+
+impl ByteCounts {
+    /// Bytes added minus bytes removed
+    pub fn net(&self) -> i64 {
+        self.added as i64 - self.removed as i64
+    }
+}
+
+/// Bytes added and removed per namespace, summed over all wikis
+#[derive(Clone, Debug, Default)]
+pub struct BytesPerNamespace {
+    counts: BTreeMap<i32, ByteCounts>,
+}
+
+impl BytesPerNamespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Aggregate for BytesPerNamespace {
+    type Output = BTreeMap<i32, ByteCounts>;
+
+    fn add(&mut self, event: &Event) {
+        if let Event::Edit(edit) | Event::New(edit) = event {
+            let counts = self.counts.entry(edit.namespace).or_default();
+            let change = edit.byte_change();
+            if change >= 0 {
+                counts.added += change.unsigned_abs();
+            } else {
+                counts.removed += change.unsigned_abs();
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for (namespace, other) in &other.counts {
+            let counts = self.counts.entry(*namespace).or_default();
+            counts.added += other.added;
+            counts.removed += other.removed;
+        }
+    }
+
+    fn finish(&self) -> Self::Output {
+        self.counts.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn sums_panes_into_sliding_windows() {
+        let mut aggregator = Aggregator::sliding(
+            std::time::Duration::from_secs(120),
+            std::time::Duration::from_secs(60),
+            std::time::Duration::ZERO,
+            EditsPerWiki::new(),
+        );
+        let mut push = |offset, seconds| {
+            let edit = testing::edit(offset, "A", at(seconds));
+            aggregator
+                .push(&testing::event(&edit))
+                .into_iter()
+                .map(|summary| {
+                    assert_eq!(
+                        summary.end - summary.start,
+                        Duration::seconds(120)
+                    );
+                    (summary.start, summary.value["enwiki"], summary.late)
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(push(1, 10).is_empty());
+        assert_eq!(push(2, 70), [(at(-60), 1, 0)]);
+        assert_eq!(push(3, 200), [(at(0), 2, 0), (at(60), 1, 0)]);
+        assert!(push(4, 50).is_empty());
+        let flushed: Vec<_> = aggregator
+            .flush()
+            .into_iter()
+            .map(|summary| {
+                (summary.start, summary.value["enwiki"], summary.late)
+            })
+            .collect();
+        assert_eq!(flushed, [(at(120), 1, 1), (at(180), 1, 0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "slide must be at least a millisecond")]
+    fn rejects_slides_under_a_millisecond() {
+        Aggregator::sliding(
+            std::time::Duration::from_secs(1),
+            std::time::Duration::from_micros(500),
+            std::time::Duration::ZERO,
+            EditsPerWiki::new(),
+        );
+    }
+}
@@ -0,0 +1,63 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Hooks called straight from the reader, see
+//! [`EventStreamBuilder::alert()`](crate::EventStreamBuilder::alert)
+use crate::Event;
+use futures::future::BoxFuture;
+use std::fmt;
+use std::sync::Arc;
+
+/// Called with each event, e.g. to send it to a sink, before the next
+/// message is read
+pub(crate) type Hook =
+    Arc<dyn Fn(&Event) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Hooks that see each message as soon as it's read, ahead of the queue,
+/// chaos and anything else between the connection and the stream
+#[derive(Clone, Default)]
+pub(crate) struct Alerts {
+    /// Shared, since every message gets a handle to them
+    hooks: Arc<Vec<Hook>>,
+}
+
+impl Alerts {
+    pub(crate) fn push(&mut self, hook: Hook) {
+        Arc::make_mut(&mut self.hooks).push(hook);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Pass `event` to every hook. Canaries are skipped unless
+    /// `keep_canaries`.
+    pub(crate) async fn check(&self, event: &Event, keep_canaries: bool) {
+        if keep_canaries || !event.is_canary() {
+            for hook in self.hooks.iter() {
+                hook(event).await;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Alerts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Alerts")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
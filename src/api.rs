@@ -0,0 +1,336 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! A polite client for the Action API
+//!
+//! Enrichment stages can end up making a request for a large share of
+//! events, which is enough to put real load on the wikis. [`ApiClient`]
+//! keeps that in check for every stage sharing it: it caps the number of
+//! requests in flight, spaces out requests to each wiki, sends
+//! [`maxlag`](https://www.mediawiki.org/wiki/Manual:Maxlag_parameter) so
+//! requests back off when replication is lagging, and retries throttled or
+//! failed requests with backoff.
+use crate::backend::{Backoff, ClientOptions, USER_AGENT};
+use crate::clock::{Clock, SystemClock};
+use crate::transport::{self, TransportError};
+use async_lock::Semaphore;
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use surf::{StatusCode, Url};
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// The API URL isn't valid
+    Url(surf::http::url::ParseError),
+    /// Making the request failed
+    Http(surf::Error),
+    /// The server responded with an HTTP error
+    Status(u16),
+    /// The API returned an error
+    Api { code: String, info: String },
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Url(err) => write!(f, "invalid API URL: {}", err),
+            Self::Http(err) => write!(f, "HTTP request failed: {}", err),
+            Self::Status(status) => write!(f, "HTTP error {}", status),
+            Self::Api { code, info } => {
+                write!(f, "API error: {}: {}", code, info)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<surf::http::url::ParseError> for ApiError {
+    fn from(err: surf::http::url::ParseError) -> Self {
+        Self::Url(err)
+    }
+}
+
+impl From<surf::Error> for ApiError {
+    fn from(err: surf::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+/// Makes Action API requests on behalf of enrichment stages. Clones share
+/// the same limits.
+#[derive(Clone)]
+pub struct ApiClient {
+    client: surf::Client,
+    user_agent: String,
+    concurrency: Arc<Semaphore>,
+    /// Earliest time the next request to each host may start
+    next_request: Arc<Mutex<HashMap<String, DateTime<Utc>>>>,
+    interval: Duration,
+    maxlag: Option<u32>,
+    retries: u32,
+    backoff: Backoff,
+    clock: Arc<dyn Clock>,
+}
+
+impl ApiClient {
+    /// At most 4 requests at a time, 10 per second to each wiki, with
+    /// `maxlag=5` and up to 3 retries
+    pub fn new() -> Self {
+        Self {
+            client: transport::default_client(),
+            user_agent: USER_AGENT.to_string(),
+            concurrency: Arc::new(Semaphore::new(4)),
+            next_request: Arc::new(Mutex::new(HashMap::new())),
+            interval: Duration::from_millis(100),
+            maxlag: Some(5),
+            retries: 3,
+            backoff: Backoff::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Identify as `user_agent`, which should include contact details as
+    /// the [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+    /// asks
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Make requests through `options`' transport, with its proxy and
+    /// certificates, and wait with its [clock](ClientOptions::clock).
+    /// Fails if no client can be created with them, e.g. because the proxy
+    /// URL is invalid.
+    pub fn client_options(
+        mut self,
+        options: &ClientOptions,
+    ) -> Result<Self, TransportError> {
+        self.client = transport::client(options)?;
+        self.clock = options.clock.clone();
+        Ok(self)
+    }
+
+    /// Space out requests and wait between retries with `clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Allow at most `max` requests in flight across all wikis
+    pub fn max_concurrent(mut self, max: usize) -> Self {
+        self.concurrency = Arc::new(Semaphore::new(max.max(1)));
+        self
+    }
+
+    /// Send at most `per_second` requests to each wiki
+    pub fn rate_limit(mut self, per_second: f64) -> Self {
+        self.interval = Duration::from_secs_f64(1.0 / per_second);
+        self
+    }
+
+    /// Ask the API to refuse requests while replication lag is over
+    /// `seconds`, or `None` to not send maxlag. Refused requests are
+    /// retried.
+    pub fn maxlag(mut self, seconds: Option<u32>) -> Self {
+        self.maxlag = seconds;
+        self
+    }
+
+    /// Retry throttled and failed requests up to `retries` times, waiting
+    /// according to `backoff` unless the server says how long to wait
+    pub fn retries(mut self, retries: u32, backoff: Backoff) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+
+    /// Make a GET request to `url`, which should already have all
+    /// parameters but `maxlag`, returning the JSON response
+    pub async fn get(&self, mut url: Url) -> Result<Value, ApiError> {
+        if let Some(maxlag) = self.maxlag {
+            url.query_pairs_mut()
+                .append_pair("maxlag", &maxlag.to_string());
+        }
+        self.request(url, None).await
+    }
+
+    /// POST `body` as JSON to `url`, returning the JSON response, for APIs
+    /// other than the Action API (so without `maxlag`), with the same
+    /// limits and retries
+    pub async fn post(
+        &self,
+        url: Url,
+        body: &Value,
+    ) -> Result<Value, ApiError> {
+        self.request(url, Some(body)).await
+    }
+
+    async fn request(
+        &self,
+        url: Url,
+        body: Option<&Value>,
+    ) -> Result<Value, ApiError> {
+        let _permit = self.concurrency.acquire().await;
+        let mut attempt = 0;
+        loop {
+            self.wait_turn(url.host_str().unwrap_or_default()).await;
+            let (result, retry_after) = self.send(url.clone(), body).await;
+            let retryable = match &result {
+                Ok(_) => false,
+                Err(ApiError::Url(_)) => false,
+                Err(ApiError::Http(_)) => true,
+                Err(ApiError::Status(status)) => {
+                    *status == 429 || *status >= 500
+                }
+                Err(ApiError::Api { code, .. }) => {
+                    code == "maxlag" || code == "ratelimited"
+                }
+            };
+            if !retryable || attempt >= self.retries {
+                return result;
+            }
+            attempt += 1;
+            let delay = retry_after.unwrap_or_else(|| {
+                self.backoff.delay_at(attempt, self.clock.now())
+            });
+            self.clock.sleep(delay).await;
+        }
+    }
+
+    /// Wait until the next request to `host` is allowed, and reserve it
+    async fn wait_turn(&self, host: &str) {
+        let start = {
+            let mut next_request = self.next_request.lock().unwrap();
+            let now = self.clock.now();
+            let start = next_request
+                .get(host)
+                .copied()
+                .filter(|next| *next > now)
+                .unwrap_or(now);
+            let interval = chrono::Duration::from_std(self.interval)
+                .expect("interval out of range");
+            next_request.insert(host.to_string(), start + interval);
+            start
+        };
+        let wait = (start - self.clock.now()).to_std().unwrap_or_default();
+        if !wait.is_zero() {
+            self.clock.sleep(wait).await;
+        }
+    }
+
+    /// Send one request, along with how long the server asked to wait
+    /// before retrying, if it did
+    async fn send(
+        &self,
+        url: Url,
+        body: Option<&Value>,
+    ) -> (Result<Value, ApiError>, Option<Duration>) {
+        let request = match body {
+            Some(body) => self.client.post(url).body(body.clone()),
+            None => self.client.get(url),
+        };
+        let mut resp = match request
+            .header("User-Agent", self.user_agent.as_str())
+            .await
+        {
+            Ok(resp) => resp,
+            Err(err) => return (Err(err.into()), None),
+        };
+        let retry_after = resp
+            .header("Retry-After")
+            .and_then(|value| value.as_str().parse().ok())
+            .map(Duration::from_secs);
+        if resp.status() != StatusCode::Ok {
+            return (Err(ApiError::Status(resp.status().into())), retry_after);
+        }
+        let value: Value = match resp.body_json().await {
+            Ok(value) => value,
+            Err(err) => return (Err(err.into()), retry_after),
+        };
+        if let Some(error) = value.get("error") {
+            let field = |name: &str| {
+                error[name].as_str().unwrap_or_default().to_string()
+            };
+            let err = ApiError::Api {
+                code: field("code"),
+                info: field("info"),
+            };
+            return (Err(err), retry_after);
+        }
+        (Ok(value), retry_after)
+    }
+}
+
+impl Default for ApiClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for ApiClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ApiClient")
+            .field("user_agent", &self.user_agent)
+            .field("interval", &self.interval)
+            .field("maxlag", &self.maxlag)
+            .field("retries", &self.retries)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    #[test]
+    fn retries_when_lagged() {
+        let server = testing::serve_json(vec![
+            json!({ "error": { "code": "maxlag", "info": "Waiting" } }),
+            json!({ "query": { "general": { "wikiid": "enwiki" } } }),
+            json!({ "error": { "code": "badtoken", "info": "Invalid" } }),
+        ]);
+        let api = ApiClient::new().rate_limit(1000.0).retries(
+            1,
+            Backoff::new(
+                Duration::from_millis(1),
+                Duration::from_millis(1),
+                1.0,
+            ),
+        );
+        let url: Url =
+            format!("http://{}/w/api.php?action=query", server.addr())
+                .parse()
+                .unwrap();
+        let value = block_on(api.get(url.clone())).unwrap();
+        assert_eq!(value["query"]["general"]["wikiid"], "enwiki");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.ends_with("?action=query&maxlag=5"));
+        match block_on(api.get(url)) {
+            Err(ApiError::Api { code, .. }) => assert_eq!(code, "badtoken"),
+            result => panic!("expected an API error, got {:?}", result),
+        }
+        assert_eq!(server.requests().len(), 3);
+    }
+}
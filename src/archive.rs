@@ -0,0 +1,633 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Replaying recorded events
+//!
+//! Recordings are NDJSON files with one raw event per line, as received
+//! from EventStreams. Large recordings are usually split up, e.g. by day
+//! or by partition. [`Archive`] reads all of the files in parallel and
+//! merges them back into a single backend stream ordered by `meta.dt`, so
+//! days of events can be replayed through a pipeline quickly, e.g. to
+//! backtest filters.
+//!
+//! An archive can also be queried directly, e.g. for a user's
+//! [edits](Archive::edits_by_user) or a page's
+//! [history](Archive::page_history), which reads through the whole
+//! recording.
+//!
+//! Recordings that keep growing can be [compacted](Archive::compact) now
+//! and then, e.g. from a daily timer, to drop old events or only keep the
+//! latest event for each page past some age.
+//!
+//! For machine learning, an archive can be [split](Archive::split) into
+//! reproducible train, validation and test sets.
+//!
+//! Whether an archive arrived intact, e.g. after copying it elsewhere or
+//! splitting it up differently, can be checked by comparing its
+//! [digest](Archive::digest) with the original's.
+use crate::backend::BackendError;
+use crate::clock::{Clock, SystemClock};
+use crate::fingerprint::{Digest, Fingerprint};
+use crate::resume::{Position, ResumeToken};
+use crate::split::{Partition, Split, SplitCounts};
+use crate::title;
+use crate::{EditEvent, Event};
+use async_stream::stream;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Messages read ahead from each file
+const DEFAULT_BUFFER: usize = 1024;
+
+/// Where a message sorts in the merged stream
+type Key = (DateTime<Utc>, u32, u64);
+
+#[derive(Deserialize)]
+struct Payload {
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    dt: DateTime<Utc>,
+    #[serde(flatten)]
+    position: Position,
+}
+
+impl Meta {
+    fn key(&self) -> Key {
+        (self.dt, self.position.partition, self.position.offset)
+    }
+}
+
+/// Just the ID of a message, for splitting
+#[derive(Deserialize)]
+struct Identified {
+    meta: Id,
+}
+
+#[derive(Deserialize)]
+struct Id {
+    id: String,
+}
+
+/// The page a message is about, for compaction
+#[derive(Deserialize)]
+struct Page {
+    meta: Meta,
+    #[serde(alias = "database")]
+    wiki: Option<String>,
+    #[serde(alias = "page_title")]
+    title: Option<String>,
+}
+
+impl Page {
+    fn name(&self) -> Option<(String, String)> {
+        Some((self.wiki.clone()?, self.title.clone()?))
+    }
+}
+
+/// What [`Archive::compact()`] removes. Nothing is removed unless limits
+/// are set.
+#[derive(Clone, Debug)]
+pub struct Retention {
+    max_age: Option<Duration>,
+    latest_after: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Retention {
+    pub fn new() -> Self {
+        Self {
+            max_age: None,
+            latest_after: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Remove events that happened more than `max_age` ago
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Of events that happened more than `horizon` ago, only keep the
+    /// latest one for each page. Events that aren't about a page are kept.
+    pub fn latest_per_page_after(mut self, horizon: Duration) -> Self {
+        self.latest_after = Some(horizon);
+        self
+    }
+
+    /// Measure ages against `clock` rather than the system time
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What [`Archive::compact()`] did
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Compaction {
+    /// Events left in the archive
+    pub kept: u64,
+    /// Events removed from the archive
+    pub removed: u64,
+    /// Files deleted because none of their events were kept
+    pub deleted: Vec<PathBuf>,
+}
+
+/// Time `age` before `now`, or `None` if that's out of range
+fn cutoff(now: DateTime<Utc>, age: Option<Duration>) -> Option<DateTime<Utc>> {
+    now.checked_sub_signed(chrono::Duration::from_std(age?).ok()?)
+}
+
+/// A recording split across several NDJSON files, each of which must be
+/// ordered by `meta.dt`
+#[derive(Clone, Debug)]
+pub struct Archive {
+    files: Vec<PathBuf>,
+    after: ResumeToken,
+    buffer: usize,
+}
+
+impl Archive {
+    pub fn new<I, P>(files: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        Self {
+            files: files.into_iter().map(Into::into).collect(),
+            after: ResumeToken::new(),
+            buffer: DEFAULT_BUFFER,
+        }
+    }
+
+    /// Skip events at or before the positions in `token`, e.g. to pick up
+    /// where an earlier replay stopped
+    pub fn after(mut self, token: ResumeToken) -> Self {
+        self.after = token;
+        self
+    }
+
+    /// Read up to `buffer` messages ahead from each file (default 1024)
+    pub fn buffer(mut self, buffer: usize) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    /// Turn into a backend stream. Each file is read on its own thread.
+    pub fn into_stream(
+        self,
+    ) -> impl Stream<Item = Result<String, BackendError>> {
+        let Self {
+            files,
+            after,
+            buffer,
+        } = self;
+        let mut readers: Vec<_> = files
+            .into_iter()
+            .map(|path| read(path, after.clone(), buffer))
+            .collect();
+        stream! {
+            let mut heads = Vec::with_capacity(readers.len());
+            for reader in &mut readers {
+                heads.push(reader.next().await);
+            }
+            loop {
+                // Errors go first, otherwise the earliest message
+                let next = heads
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, head)| match head {
+                        Some(Ok((key, _))) => Some((index, Some(*key))),
+                        Some(Err(_)) => Some((index, None)),
+                        None => None,
+                    })
+                    .min_by_key(|(_, key)| *key)
+                    .map(|(index, _)| index);
+                let index = match next {
+                    Some(index) => index,
+                    None => break,
+                };
+                let head = std::mem::replace(
+                    &mut heads[index],
+                    readers[index].next().await,
+                );
+                match head {
+                    Some(Ok((_, data))) => yield Ok(data),
+                    Some(Err(err)) => yield Err(err),
+                    None => unreachable!("only files with messages left"),
+                }
+            }
+        }
+    }
+
+    /// Edits and page creations by `user` that happened within `range`,
+    /// oldest first
+    pub fn edits_by_user(
+        &self,
+        user: &str,
+        range: impl RangeBounds<DateTime<Utc>>,
+    ) -> Result<Vec<EditEvent>, BackendError> {
+        self.edits(|edit| edit.user == user && range.contains(&edit.meta.dt))
+    }
+
+    /// Edits and page creations of `title` (prefixed, which is
+    /// [canonicalized](crate::title::canonicalize) first) on `wiki`
+    /// (internal database name), oldest first
+    pub fn page_history(
+        &self,
+        wiki: &str,
+        title: &str,
+    ) -> Result<Vec<EditEvent>, BackendError> {
+        let title = title::canonicalize(wiki, title);
+        self.edits(|edit| edit.wiki == wiki && edit.title == title)
+    }
+
+    /// Rewrite the archive's files without the events that `retention`
+    /// removes, deleting files left empty. Each file is replaced in one
+    /// step, but nothing else should write to the files in the meantime.
+    /// Lines that can't be parsed are kept, and files that don't exist
+    /// (any more) are skipped.
+    pub fn compact(&self, retention: &Retention) -> io::Result<Compaction> {
+        let files: Vec<_> =
+            self.files.iter().filter(|path| path.exists()).collect();
+        let now = retention.clock.now();
+        let expired = cutoff(now, retention.max_age);
+        let horizon = cutoff(now, retention.latest_after);
+        let is_expired =
+            |page: &Page| expired.is_some_and(|e| page.meta.dt < e);
+        let is_old = |page: &Page| horizon.is_some_and(|h| page.meta.dt < h);
+        // Find the latest old event for each page, across all files
+        let mut latest: HashMap<(String, String), Key> = HashMap::new();
+        if horizon.is_some() {
+            for path in &files {
+                for line in BufReader::new(File::open(path)?).lines() {
+                    let page = match serde_json::from_str::<Page>(&line?) {
+                        Ok(page) if is_old(&page) && !is_expired(&page) => page,
+                        _ => continue,
+                    };
+                    if let Some(name) = page.name() {
+                        let key = latest.entry(name).or_insert(page.meta.key());
+                        *key = page.meta.key().max(*key);
+                    }
+                }
+            }
+        }
+        let keep = |line: &str| match serde_json::from_str::<Page>(line) {
+            Ok(page) if is_expired(&page) => false,
+            Ok(page) if is_old(&page) => match page.name() {
+                Some(name) => latest.get(&name) == Some(&page.meta.key()),
+                None => true,
+            },
+            _ => true,
+        };
+        let mut compaction = Compaction::default();
+        for path in files {
+            let (kept, removed) = rewrite(path, keep)?;
+            compaction.kept += kept;
+            compaction.removed += removed;
+            if kept == 0 {
+                fs::remove_file(path)?;
+                compaction.deleted.push(path.clone());
+            }
+        }
+        Ok(compaction)
+    }
+
+    /// [`Digest`] of every message in the archive, which doesn't depend on
+    /// how messages are spread over files or ordered within them. Lines
+    /// that aren't JSON are included as they are, so damaged ones change
+    /// the digest too.
+    pub fn digest(&self) -> io::Result<Digest> {
+        let mut digest = Digest::new();
+        for path in &self.files {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                digest.add(Fingerprint::of_message(&line).unwrap_or_else(
+                    |_| Fingerprint::of_json(&serde_json::Value::String(line)),
+                ));
+            }
+        }
+        Ok(digest)
+    }
+
+    /// Write every event into `train.ndjson`, `validation.ndjson` or
+    /// `test.ndjson` in `dir` according to `split`, replacing any existing
+    /// files. Events keep their order within each input file, and files
+    /// that don't exist are skipped.
+    pub fn split(&self, split: &Split, dir: &Path) -> io::Result<SplitCounts> {
+        fs::create_dir_all(dir)?;
+        let mut writers = HashMap::new();
+        for partition in
+            [Partition::Train, Partition::Validation, Partition::Test]
+        {
+            let path = dir.join(format!("{}.ndjson", partition.name()));
+            writers.insert(partition, BufWriter::new(File::create(path)?));
+        }
+        let mut counts = SplitCounts::default();
+        for path in self.files.iter().filter(|path| path.exists()) {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let partition = match serde_json::from_str::<Identified>(&line)
+                {
+                    Ok(message) => split.partition_of(&message.meta.id),
+                    Err(_) => {
+                        counts.skipped += 1;
+                        continue;
+                    }
+                };
+                writeln!(writers.get_mut(&partition).unwrap(), "{}", line)?;
+                counts.add(partition);
+            }
+        }
+        for writer in writers.values_mut() {
+            writer.flush()?;
+        }
+        Ok(counts)
+    }
+
+    /// Read through the whole archive for matching edits, stopping at the
+    /// first file that can't be read
+    fn edits(
+        &self,
+        matches: impl Fn(&EditEvent) -> bool,
+    ) -> Result<Vec<EditEvent>, BackendError> {
+        let stream = self.clone().into_stream();
+        futures::pin_mut!(stream);
+        futures::executor::block_on(async {
+            let mut edits = vec![];
+            while let Some(message) = stream.next().await {
+                match crate::handle_event(&message?) {
+                    Some(Ok(Event::Edit(edit)))
+                    | Some(Ok(Event::New(edit)))
+                        if matches(&edit) =>
+                    {
+                        edits.push(edit)
+                    }
+                    _ => {}
+                }
+            }
+            Ok(edits)
+        })
+    }
+}
+
+/// Replace `path` with only the lines to `keep`, returning how many
+/// messages were kept and removed
+fn rewrite(path: &Path, keep: impl Fn(&str) -> bool) -> io::Result<(u64, u64)> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".compacting");
+    let temporary = PathBuf::from(temporary);
+    let mut writer = BufWriter::new(File::create(&temporary)?);
+    let (mut kept, mut removed) = (0, 0);
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if keep(&line) {
+            writeln!(writer, "{}", line)?;
+            kept += 1;
+        } else {
+            removed += 1;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    if removed == 0 {
+        fs::remove_file(&temporary)?;
+    } else {
+        fs::rename(&temporary, path)?;
+    }
+    Ok((kept, removed))
+}
+
+/// Read `path` on a new thread, keying each message for the merge
+fn read(
+    path: PathBuf,
+    after: ResumeToken,
+    buffer: usize,
+) -> mpsc::Receiver<Result<(Key, String), BackendError>> {
+    let (mut sender, receiver) = mpsc::channel(buffer);
+    thread::spawn(move || {
+        let io_error = |err: std::io::Error| {
+            BackendError::Io(format!("{}: {}", path.display(), err))
+        };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(err) => {
+                let _ = futures::executor::block_on(
+                    sender.send(Err(io_error(err))),
+                );
+                return;
+            }
+        };
+        // Lines without a usable `meta` stay where they were in the file
+        let mut key = (DateTime::<Utc>::MIN_UTC, 0, 0);
+        for line in BufReader::new(file).lines() {
+            let message = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => {
+                    if let Ok(payload) = serde_json::from_str::<Payload>(&line)
+                    {
+                        let meta = payload.meta;
+                        if after.contains(&meta.position) {
+                            continue;
+                        }
+                        key = meta.key();
+                    }
+                    Ok((key, line))
+                }
+                Err(err) => Err(io_error(err)),
+            };
+            if futures::executor::block_on(sender.send(message)).is_err() {
+                // Nobody is listening anymore
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    /// Write `messages` to a file named after `name`, one per line
+    fn write(name: &str, messages: &[serde_json::Value]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "eventstreams-archive-{}-{}.ndjson",
+            name,
+            std::process::id()
+        ));
+        let lines: Vec<_> = messages.iter().map(|m| m.to_string()).collect();
+        fs::write(&path, lines.join("\n") + "\n").unwrap();
+        path
+    }
+
+    fn titles(archive: Archive) -> Vec<String> {
+        let messages: Vec<_> = block_on(archive.into_stream().collect());
+        messages
+            .into_iter()
+            .map(|message| {
+                let value: serde_json::Value =
+                    serde_json::from_str(&message.unwrap()).unwrap();
+                value["title"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_files_by_time() {
+        let first = testing::edit(0, "A", at(0));
+        let second = testing::edit(1, "B", at(1));
+        let files = [
+            write("merge-1", &[first.clone(), testing::edit(2, "C", at(2))]),
+            write("merge-2", &[second.clone(), testing::edit(3, "D", at(3))]),
+        ];
+        let archive = Archive::new(files.clone()).buffer(1);
+        assert_eq!(titles(archive.clone()), ["A", "B", "C", "D"]);
+
+        let mut token = ResumeToken::new();
+        token.observe_raw(&first.to_string());
+        token.observe_raw(&second.to_string());
+        assert_eq!(titles(archive.after(token)), ["C", "D"]);
+        for file in &files {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn finds_edits_by_user_and_page() {
+        let mut by_bob = testing::edit(1, "Ada Lovelace", at(10));
+        by_bob["user"] = "Bob".into();
+        let file = write(
+            "queries",
+            &[
+                testing::edit(0, "Ada Lovelace", at(0)),
+                by_bob,
+                testing::log(2, "File:Ada.png", at(20)),
+                testing::edit(3, "Babbage", at(30)),
+            ],
+        );
+        let archive = Archive::new([&file]);
+        let revisions = |edits: Vec<EditEvent>| -> Vec<u32> {
+            edits.iter().map(|edit| edit.revision.new).collect()
+        };
+        let by_alice = archive.edits_by_user("Alice", at(0)..at(30)).unwrap();
+        assert_eq!(revisions(by_alice), [1]);
+        let by_alice = archive.edits_by_user("Alice", ..).unwrap();
+        assert_eq!(revisions(by_alice), [1, 4]);
+        let history = archive.page_history("enwiki", "ada_Lovelace").unwrap();
+        assert_eq!(revisions(history), [1, 2]);
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn compacts_old_events() {
+        let files = [
+            write("compact-1", &[testing::edit(0, "C", at(0))]),
+            write(
+                "compact-2",
+                &[
+                    testing::edit(1, "A", at(50)),
+                    testing::edit(2, "A", at(200)),
+                    testing::edit(3, "B", at(300)),
+                ],
+            ),
+            write(
+                "compact-3",
+                &[
+                    testing::edit(4, "A", at(400)),
+                    testing::edit(5, "A", at(600)),
+                ],
+            ),
+        ];
+        let retention = Retention::new()
+            .clock(Arc::new(crate::clock::ManualClock::new(at(1000))))
+            .max_age(Duration::from_secs(900))
+            .latest_per_page_after(Duration::from_secs(500));
+        let archive = Archive::new(files.clone());
+        assert_eq!(
+            archive.compact(&retention).unwrap(),
+            Compaction {
+                kept: 3,
+                removed: 3,
+                deleted: vec![files[0].clone()],
+            }
+        );
+        let archive = Archive::new(files[1..].to_vec());
+        assert_eq!(titles(archive), ["B", "A", "A"]);
+        for file in &files[1..] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+
+    #[test]
+    fn splits_events_by_id() {
+        let messages: Vec<_> = (0..100)
+            .map(|offset| testing::edit(offset, "A", at(offset as i64)))
+            .collect();
+        let mut without_id = vec![serde_json::json!({ "meta": {} })];
+        without_id.extend(messages);
+        let file = write("split", &without_id);
+        let dir = std::env::temp_dir()
+            .join(format!("eventstreams-split-{}", std::process::id()));
+        let split = Split::new(0.2, 0.2);
+        let counts = Archive::new([&file]).split(&split, &dir).unwrap();
+        assert_eq!(counts.skipped, 1);
+        assert_eq!(counts.train + counts.validation + counts.test, 100);
+        for partition in
+            [Partition::Train, Partition::Validation, Partition::Test]
+        {
+            let path = dir.join(format!("{}.ndjson", partition.name()));
+            for line in fs::read_to_string(path).unwrap().lines() {
+                let value: serde_json::Value =
+                    serde_json::from_str(line).unwrap();
+                let id = value["meta"]["id"].as_str().unwrap();
+                assert_eq!(split.partition_of(id), partition);
+            }
+        }
+        fs::remove_file(&file).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,235 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Audit feed of administrative actions
+//!
+//! [`subscription()`] is a preset that picks out blocks, deletions,
+//! protections and user rights changes on a set of wikis, and
+//! [`AuditSink`] writes them out as one JSON [`AuditEntry`] per line, with
+//! the log parameters already parsed.
+use crate::sink::{Sink, SinkError};
+use crate::subscription::Subscription;
+use crate::{Event, LogEvent, LogParams};
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use serde::Serialize;
+use std::io::Write;
+
+/// Log types that are considered administrative actions
+pub const ADMIN_LOG_TYPES: &[&str] = &["block", "delete", "protect", "rights"];
+
+/// An administrative action, with its parameters
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum AdminAction {
+    Block {
+        /// `block`, `reblock` or `unblock`
+        action: String,
+        /// Requested duration, e.g. `1 week` or `infinite`
+        duration: Option<String>,
+        /// Block options, e.g. `nocreate`
+        flags: Vec<String>,
+        /// Whether the block covers the whole wiki rather than specific
+        /// pages or namespaces
+        sitewide: Option<bool>,
+    },
+    Delete {
+        /// `delete`, `restore`, `revision`, etc.
+        action: String,
+    },
+    Protect {
+        /// `protect`, `modify`, `unprotect` or `move_prot`
+        action: String,
+        /// Summary of the protection levels and expiries
+        description: Option<String>,
+        cascade: bool,
+    },
+    Rights {
+        old_groups: Vec<String>,
+        new_groups: Vec<String>,
+    },
+}
+
+impl AdminAction {
+    /// Parse the action from a log entry, or `None` if it isn't an
+    /// administrative action
+    pub fn from_log(log: &LogEvent) -> Option<Self> {
+        let action = log.log_action.to_string();
+        Some(match log.params() {
+            LogParams::Block {
+                duration,
+                flags,
+                sitewide,
+            } => AdminAction::Block {
+                action,
+                duration,
+                flags,
+                sitewide,
+            },
+            LogParams::Protect {
+                description,
+                cascade,
+            } => AdminAction::Protect {
+                action,
+                description,
+                cascade,
+            },
+            LogParams::Rights {
+                old_groups,
+                new_groups,
+            } => AdminAction::Rights {
+                old_groups,
+                new_groups,
+            },
+            _ if log.log_type == "delete" => AdminAction::Delete { action },
+            _ => return None,
+        })
+    }
+}
+
+/// An administrative action formatted for an audit trail
+#[derive(Clone, Debug, Serialize)]
+pub struct AuditEntry {
+    /// Internal database name of the wiki
+    pub wiki: String,
+    pub dt: DateTime<Utc>,
+    pub log_id: u32,
+    /// Whoever performed the action
+    pub user: String,
+    /// Prefixed title of the page or user acted on
+    pub title: String,
+    pub comment: String,
+    pub action: AdminAction,
+}
+
+impl AuditEntry {
+    /// Format an event, or `None` if it isn't an administrative action
+    pub fn from_event(event: &Event) -> Option<Self> {
+        let log = match event {
+            Event::Log(log) => log,
+            _ => return None,
+        };
+        Some(Self {
+            action: AdminAction::from_log(log)?,
+            wiki: log.wiki.to_string(),
+            dt: log.meta.dt,
+            log_id: log.log_id,
+            user: log.user.to_string(),
+            title: log.title.clone(),
+            comment: log.comment.clone(),
+        })
+    }
+}
+
+/// Whether the event is an administrative action
+pub fn is_admin_action(event: &Event) -> bool {
+    match event {
+        Event::Log(log) => ADMIN_LOG_TYPES.contains(&log.log_type.as_str()),
+        _ => false,
+    }
+}
+
+/// A subscription sending administrative actions on `wikis` (internal
+/// database names, or all wikis if empty) to `sink`
+pub fn subscription<I, S>(
+    name: impl Into<String>,
+    wikis: I,
+    sink: impl Sink + 'static,
+) -> Subscription
+where
+    I: IntoIterator<Item = S>,
+    S: Into<String>,
+{
+    let wikis: Vec<String> = wikis.into_iter().map(Into::into).collect();
+    Subscription::new(
+        name,
+        move |event| {
+            is_admin_action(event)
+                && (wikis.is_empty()
+                    || wikis.iter().any(|wiki| wiki == event.wiki()))
+        },
+        sink,
+    )
+}
+
+/// Writes each administrative action as a line of JSON. Other events are
+/// skipped.
+pub struct AuditSink<W>(pub W);
+
+impl<W: Write + Send> Sink for AuditSink<W> {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        let result = AuditEntry::from_event(event)
+            .map(|entry| -> Result<(), SinkError> {
+                serde_json::to_writer(&mut self.0, &entry)?;
+                writeln!(self.0)?;
+                Ok(())
+            })
+            .unwrap_or(Ok(()));
+        Box::pin(future::ready(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    fn block(seconds: i64) -> Event {
+        let mut log = testing::log(1, "User:Mallory", at(seconds));
+        log["log_type"] = "block".into();
+        log["log_action"] = "block".into();
+        log["log_params"] = serde_json::json!({
+            "duration": "1 week",
+            "flags": ["nocreate"],
+            "sitewide": true,
+        });
+        testing::event(&log)
+    }
+
+    #[test]
+    fn writes_admin_actions_only() {
+        let upload = testing::event(&testing::log(2, "File:A.png", at(0)));
+        let edit = testing::edit_event(3, "A", at(0));
+        assert!(is_admin_action(&block(0)));
+        assert!(!is_admin_action(&upload));
+
+        let mut sink = AuditSink(Vec::new());
+        for event in [block(0), upload, edit] {
+            block_on(sink.send(&event)).unwrap();
+        }
+        let output = String::from_utf8(sink.0).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["title"], "User:Mallory");
+        assert_eq!(
+            lines[0]["action"],
+            serde_json::json!({
+                "type": "block",
+                "action": "block",
+                "duration": "1 week",
+                "flags": ["nocreate"],
+                "sitewide": true,
+            })
+        );
+    }
+}
@@ -0,0 +1,1350 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Sources of raw event data
+//!
+//! A backend is a stream of the raw JSON payload of each message, which is
+//! then parsed into [`Event`](crate::Event)s by
+//! [`from_backend()`](crate::from_backend). Besides the [`live()`] feed,
+//! there's [`memory()`] for feeding in fixed data,
+//! [`Archive`](crate::archive::Archive) for replaying recordings and
+//! [`FaultInjector`] for testing how consumers hold up against things
+//! going wrong. [`pipe`](crate::pipe) reads from stdin or another byte
+//! stream instead of connecting.
+//!
+//! Live messages are only passed on once complete. Lines split across
+//! reads are buffered until their line ending, `data:` fields spread over
+//! several lines are joined, and a message cut off by a dropped connection
+//! is discarded, then sent again after resuming from the last complete
+//! one.
+//!
+//! In browsers, on `wasm32-unknown-unknown`, the live feed is read through
+//! the browser's own `EventSource` instead, see
+//! [`browser`](crate::browser).
+#[cfg(target_arch = "wasm32")]
+use crate::browser::EventSource;
+use crate::clock::{Clock, SystemClock};
+use crate::etiquette::Guardrails;
+use crate::maintenance::MaintenanceCalendar;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::resume::ResumeToken;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::{self, HttpClient, Transport};
+use async_stream::stream;
+use chrono::{DateTime, Utc};
+use futures::future::Either;
+#[cfg(not(target_arch = "wasm32"))]
+use futures::io::{AsyncRead, BufReader};
+use futures::{future, Stream, StreamExt};
+use std::collections::VecDeque;
+use std::fmt;
+use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+#[cfg(not(target_arch = "wasm32"))]
+use std::task::{Context, Poll};
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use surf::middleware::{Middleware, Next};
+#[cfg(not(target_arch = "wasm32"))]
+use surf_sse::{EventSource, ReadyState};
+use url::Url;
+
+/// Sent unless another `User-Agent` is set, as required by Wikimedia's
+/// [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+pub(crate) const USER_AGENT: &str = concat!(
+    "eventstreams-rs/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://gitlab.com/legoktm/eventstreams)"
+);
+
+/// Something that went wrong in the backend. Backends keep going after
+/// errors, reconnecting if necessary, except after
+/// [`Refused`](Self::Refused) and [`Transport`](Self::Transport).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BackendError {
+    /// The server closed the connection
+    Disconnected,
+    /// Connecting to the server failed
+    Connection(String),
+    /// The server responded with an HTTP error
+    Http(u16),
+    /// Nothing was received from the server for too long
+    Timeout,
+    /// Reading recorded events failed
+    Io(String),
+    /// Connecting would have gone against Wikimedia's etiquette, see
+    /// [`etiquette`](crate::etiquette)
+    Refused(String),
+    /// No HTTP client could be created, e.g. because the proxy URL is
+    /// invalid, see [`TransportError`](crate::transport::TransportError)
+    Transport(String),
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disconnected => write!(f, "disconnected from server"),
+            Self::Connection(err) => write!(f, "connection failed: {}", err),
+            Self::Http(status) => write!(f, "HTTP error {}", status),
+            Self::Timeout => write!(f, "timed out waiting for the server"),
+            Self::Io(err) => write!(f, "reading failed: {}", err),
+            Self::Refused(err) => write!(f, "refused to connect: {}", err),
+            Self::Transport(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// The live EventStreams recent changes feed
+pub fn live() -> impl Stream<Item = Result<String, BackendError>> {
+    connect(live_url())
+}
+
+fn live_url() -> Url {
+    Url::parse("https://stream.wikimedia.org/v2/stream/recentchange")
+        .expect("valid URL")
+}
+
+/// An EventStreams feed at `url`
+pub fn connect(url: Url) -> impl Stream<Item = Result<String, BackendError>> {
+    resume(url, None)
+}
+
+/// An EventStreams feed at `url`, continuing after `last_event_id` as
+/// sent with an earlier message (see
+/// [`ResumeToken`](crate::resume::ResumeToken))
+pub fn resume(
+    url: Url,
+    last_event_id: Option<String>,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    reconnecting(
+        url,
+        last_event_id,
+        Backoff::default(),
+        ClientOptions::default(),
+        |_| {},
+    )
+}
+
+/// HTTP settings for connecting to EventStreams. Browsers make their own
+/// choices about most of these, see [`browser`](crate::browser).
+#[derive(Clone, Debug)]
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub struct ClientOptions {
+    user_agent: Option<String>,
+    headers: Vec<(String, String)>,
+    pub(crate) proxy: Option<String>,
+    pub(crate) connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    pub(crate) ca_certificate: Option<PathBuf>,
+    #[cfg(not(target_arch = "wasm32"))]
+    transport: Option<Arc<dyn Transport>>,
+    guardrails: Guardrails,
+    diagnostics: bool,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: None,
+            headers: vec![],
+            proxy: None,
+            connect_timeout: None,
+            read_timeout: None,
+            ca_certificate: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            transport: None,
+            guardrails: Guardrails::default(),
+            diagnostics: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Identify as `user_agent`, which should include contact details as
+    /// required by Wikimedia's
+    /// [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Send an extra header with every request. Header names must be
+    /// ASCII.
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Connect through the HTTP proxy at `proxy`, e.g.
+    /// `http://localhost:3128`. Without one, the usual `http_proxy` and
+    /// `https_proxy` environment variables are respected.
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Give up on connecting after `timeout`. This is enforced by the HTTP
+    /// client, rather than the [`clock()`](Self::clock).
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Reconnect if nothing is received for `timeout`, e.g. because the
+    /// connection silently died
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Report what the server sends besides events, as
+    /// [`ConnectionEvent::Diagnostic`]s, e.g. to debug disconnects
+    pub fn diagnostics(mut self) -> Self {
+        self.diagnostics = true;
+        self
+    }
+
+    /// Also trust the root certificates in the PEM file at `path`, e.g.
+    /// for a proxy that intercepts TLS
+    pub fn ca_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.ca_certificate = Some(path.into());
+        self
+    }
+
+    /// Make requests through `transport` instead of the
+    /// [default one](transport::default_transport)
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    /// Check connections against Wikimedia's etiquette with `guardrails`,
+    /// see [`etiquette`](crate::etiquette)
+    pub fn guardrails(mut self, guardrails: Guardrails) -> Self {
+        self.guardrails = guardrails;
+        self
+    }
+
+    /// Wait for backoff, read timeouts and probes with `clock`, e.g. a
+    /// [`ManualClock`](crate::clock::ManualClock) in tests
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn transport_or_default(&self) -> Arc<dyn Transport> {
+        self.transport
+            .clone()
+            .unwrap_or_else(transport::default_transport)
+    }
+
+    pub(crate) fn user_agent_or_default(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(USER_AGENT)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn headers(&self) -> Vec<(String, String)> {
+        let user_agent = self.user_agent_or_default();
+        let mut headers = vec![("User-Agent".to_string(), user_agent.into())];
+        headers.extend(self.headers.iter().cloned());
+        headers
+    }
+}
+
+/// Alternative URLs for the same feed, e.g. EventStreams in different
+/// data centers, of which the one that responds fastest is used
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoints {
+    urls: Vec<Url>,
+    reprobe_after: u32,
+    probe_timeout: Duration,
+}
+
+impl Endpoints {
+    /// Choose between `urls`, each of which should include the streams.
+    /// Panics if there are none.
+    pub fn new(urls: Vec<Url>) -> Self {
+        assert!(!urls.is_empty(), "no endpoints");
+        Self {
+            urls,
+            reprobe_after: 3,
+            probe_timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Probe the endpoints again after this many failed reconnects in a
+    /// row, 3 by default. 0 only probes them once, at startup.
+    pub fn reprobe_after(mut self, failures: u32) -> Self {
+        self.reprobe_after = failures;
+        self
+    }
+
+    /// Give up on an endpoint that hasn't responded to a probe after
+    /// `timeout`, 10 seconds by default
+    pub fn probe_timeout(mut self, timeout: Duration) -> Self {
+        self.probe_timeout = timeout;
+        self
+    }
+}
+
+/// How an endpoint fared in [`probe()`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Probe {
+    pub url: Url,
+    /// Time until the server responded, or why it couldn't be reached
+    pub result: Result<Duration, BackendError>,
+}
+
+/// Connect to all of `endpoints` at once and measure how long each takes
+/// to respond, giving up after `timeout`. Results are sorted with the
+/// fastest first and unreachable endpoints last.
+#[cfg(not(target_arch = "wasm32"))]
+pub async fn probe(
+    endpoints: &[Url],
+    options: &ClientOptions,
+    timeout: Duration,
+) -> Vec<Probe> {
+    let http: &Arc<dyn HttpClient> =
+        &match options.transport_or_default().http_client(options) {
+            Ok(http) => http.into(),
+            Err(err) => {
+                let err = BackendError::Transport(err.to_string());
+                return endpoints
+                    .iter()
+                    .map(|url| Probe {
+                        url: url.clone(),
+                        result: Err(err.clone()),
+                    })
+                    .collect();
+            }
+        };
+    let headers = &options.headers();
+    let clock = &options.clock;
+    let mut probes = future::join_all(endpoints.iter().map(|url| async move {
+        let client = client(http, headers.clone());
+        let start = clock.now();
+        let request = client.get(url).header("Accept", "text/event-stream");
+        let result = match future::select(request, clock.sleep(timeout)).await {
+            Either::Left((Ok(response), _))
+                if response.status().is_success() =>
+            {
+                Ok((clock.now() - start).to_std().unwrap_or_default())
+            }
+            Either::Left((Ok(response), _)) => {
+                Err(BackendError::Http(response.status().into()))
+            }
+            Either::Left((Err(err), _)) => {
+                Err(BackendError::Connection(err.to_string()))
+            }
+            Either::Right(_) => Err(BackendError::Timeout),
+        };
+        Probe {
+            url: url.clone(),
+            result,
+        }
+    }))
+    .await;
+    probes.sort_by_key(|probe| match probe.result {
+        Ok(latency) => (false, latency),
+        Err(_) => (true, Duration::default()),
+    });
+    probes
+}
+
+/// How long to wait before reconnecting, growing exponentially with each
+/// failed attempt in a row
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    multiplier: f64,
+    jitter: f64,
+    maintenance: Option<Box<MaintenanceCalendar>>,
+}
+
+impl Backoff {
+    /// Wait `initial` after the first failure, multiplying by `multiplier`
+    /// for each one after that, up to `max`. Panics unless `multiplier` is
+    /// a finite number of at least 1.
+    pub fn new(initial: Duration, max: Duration, multiplier: f64) -> Self {
+        assert!(
+            multiplier.is_finite() && multiplier >= 1.0,
+            "multiplier must be finite and at least 1"
+        );
+        Self {
+            initial,
+            max,
+            multiplier,
+            jitter: 0.0,
+            maintenance: None,
+        }
+    }
+
+    /// Randomly shorten each delay by up to this fraction (0 to 1), so
+    /// that many clients don't all reconnect at once. Panics if `jitter`
+    /// isn't finite.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        assert!(jitter.is_finite(), "jitter must be finite");
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// During the windows in `calendar`, wait as its
+    /// [backoff](MaintenanceCalendar::backoff) says instead, so an
+    /// expected outage isn't met with a burst of reconnects
+    pub fn maintenance(mut self, calendar: MaintenanceCalendar) -> Self {
+        self.maintenance = Some(Box::new(calendar));
+        self
+    }
+
+    /// Delay before reconnect `attempt`, starting at 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.delay_at(attempt, Utc::now())
+    }
+
+    /// Like [`delay()`](Self::delay), checking for maintenance windows as
+    /// of `now` rather than the system time, e.g. from a
+    /// [`Clock`](crate::clock::Clock)
+    pub fn delay_at(&self, attempt: u32, now: DateTime<Utc>) -> Duration {
+        if let Some(calendar) = &self.maintenance {
+            if calendar.active_at(now).is_some() {
+                return calendar.maintenance_backoff().delay(attempt);
+            }
+        }
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        // Saturates at the maximum rather than overflowing
+        let factor = self.multiplier.powi(exponent);
+        let delay =
+            Duration::try_from_secs_f64(self.initial.as_secs_f64() * factor)
+                .map_or(self.max, |delay| delay.min(self.max));
+        let jitter = 1.0 - self.jitter * fastrand::f64();
+        Duration::try_from_secs_f64(delay.as_secs_f64() * jitter)
+            .map_or(delay, |jittered| jittered.min(delay))
+    }
+}
+
+impl Default for Backoff {
+    /// 1 second, doubling up to a minute, with 50% jitter
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60), 2.0)
+            .jitter(0.5)
+    }
+}
+
+/// A change in the state of a [`reconnecting()`] connection
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// Connected to the server, initially or after a reconnect
+    Open,
+    /// The connection was lost, or couldn't be made
+    Disconnected(BackendError),
+    /// About to reconnect after waiting `delay`. `attempt` counts the
+    /// attempts since the last message was received, starting at 1.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// Probing [`Endpoints`] picked `url`, which responded in `latency`
+    EndpointSelected { url: Url, latency: Duration },
+    /// Something the server sent besides events, if
+    /// [enabled](ClientOptions::diagnostics)
+    Diagnostic(Diagnostic),
+    /// The [`EventStream`](crate::EventStream) was shut down or dropped,
+    /// so the connection is closed for good
+    Closed,
+}
+
+/// Details of a connection that are normally hidden, see
+/// [`ClientOptions::diagnostics()`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Diagnostic {
+    /// The server responded, sent once per connection
+    Response {
+        status: u16,
+        headers: Vec<(String, String)>,
+    },
+    /// A comment line, without the leading `:`. EventStreams sends these
+    /// as keepalives while there are no events.
+    Comment(String),
+}
+
+impl Diagnostic {
+    /// Value of the response header `name`, e.g. `server-timing` or
+    /// `x-request-id`
+    pub fn header(&self, name: &str) -> Option<&str> {
+        match self {
+            Diagnostic::Response { headers, .. } => headers
+                .iter()
+                .find(|(other, _)| other.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str()),
+            Diagnostic::Comment(_) => None,
+        }
+    }
+}
+
+/// An EventStreams feed at `url` that reconnects whenever the connection
+/// is lost, waiting according to `backoff` between attempts. Reconnects
+/// continue after the last message received (or `last_event_id`, until
+/// there is one), so nothing is missed as long as EventStreams still has
+/// it. `on_connection` is called whenever the connection changes state.
+///
+/// If no HTTP client can be created with `options`, e.g. because the proxy
+/// isn't a valid URL, the stream ends after a [`BackendError::Transport`].
+pub fn reconnecting(
+    url: Url,
+    last_event_id: Option<String>,
+    backoff: Backoff,
+    options: ClientOptions,
+    on_connection: impl FnMut(&ConnectionEvent) + 'static,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    reconnecting_to(
+        Endpoints::new(vec![url]),
+        last_event_id,
+        backoff,
+        options,
+        on_connection,
+    )
+}
+
+/// Like [`reconnecting()`], but connecting to whichever of `endpoints`
+/// responds fastest. They are [probed](probe()) before connecting, and
+/// again after repeated failures.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn reconnecting_to(
+    endpoints: Endpoints,
+    last_event_id: Option<String>,
+    backoff: Backoff,
+    options: ClientOptions,
+    mut on_connection: impl FnMut(&ConnectionEvent) + 'static,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    let mut token = last_event_id
+        .as_deref()
+        .and_then(ResumeToken::from_last_event_id)
+        .unwrap_or_default();
+    let http = options.transport_or_default().http_client(&options);
+    let mut url = endpoints.urls[0].clone();
+    let diagnostics = Arc::new(Mutex::new(VecDeque::new()));
+    stream! {
+        let http: Arc<dyn HttpClient> = match http {
+            Ok(http) => http.into(),
+            Err(err) => {
+                yield Err(BackendError::Transport(err.to_string()));
+                return;
+            }
+        };
+        let mut attempt = 0;
+        let mut probed = false;
+        loop {
+            let reprobe = endpoints.reprobe_after > 0
+                && attempt > 0
+                && attempt % endpoints.reprobe_after == 0;
+            if endpoints.urls.len() > 1 && (!probed || reprobe) {
+                probed = true;
+                let probes =
+                    probe(&endpoints.urls, &options, endpoints.probe_timeout)
+                        .await;
+                // If nothing could be reached, stick with the current one
+                if let Some(Probe { url: best, result: Ok(latency) }) =
+                    probes.into_iter().next()
+                {
+                    url = best;
+                    #[cfg(feature = "tracing")]
+                    tracing::info!(
+                        url = url.as_str(),
+                        latency_ms = latency.as_millis() as u64,
+                        "selected endpoint"
+                    );
+                    on_connection(&ConnectionEvent::EndpointSelected {
+                        url: url.clone(),
+                        latency,
+                    });
+                }
+            }
+            let id = if token.is_empty() {
+                last_event_id.clone()
+            } else {
+                Some(token.to_last_event_id())
+            };
+            let connection = options.guardrails.open(
+                url.host_str().unwrap_or_default(),
+                options.user_agent_or_default(),
+            );
+            let connection = match connection {
+                Ok(connection) => connection,
+                Err(violation) => {
+                    yield Err(BackendError::Refused(violation.to_string()));
+                    return;
+                }
+            };
+            let mut headers = options.headers();
+            if let Some(id) = id {
+                headers.push(("Last-Event-ID".to_string(), id));
+            }
+            let mut client = client(&http, headers);
+            if options.diagnostics {
+                client = client.with(Tap(diagnostics.clone()));
+            }
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                url = url.as_str(),
+                attempt,
+                resuming = !token.is_empty() || last_event_id.is_some(),
+                "connecting"
+            );
+            let mut source = EventSource::with_client(client, url.clone());
+            let mut open = false;
+            let err = loop {
+                // Check the state after every poll, since the connection
+                // opens before any message arrives
+                let next = future::poll_fn(|cx| {
+                    let poll = source.poll_next_unpin(cx);
+                    let pending: Vec<_> =
+                        diagnostics.lock().unwrap().drain(..).collect();
+                    for diagnostic in pending {
+                        on_connection(&ConnectionEvent::Diagnostic(diagnostic));
+                    }
+                    if !open && source.ready_state() == ReadyState::Open {
+                        open = true;
+                        #[cfg(feature = "tracing")]
+                        tracing::info!(url = url.as_str(), "connected");
+                        on_connection(&ConnectionEvent::Open);
+                    }
+                    poll
+                });
+                let message = match options.read_timeout {
+                    Some(timeout) => {
+                        match future::select(next, options.clock.sleep(timeout))
+                            .await
+                        {
+                            Either::Left((message, _)) => message,
+                            Either::Right(_) => break BackendError::Timeout,
+                        }
+                    }
+                    None => next.await,
+                };
+                match message {
+                    Some(Ok(message)) => {
+                        attempt = 0;
+                        token.observe_raw(&message.data);
+                        yield Ok(message.data);
+                    }
+                    Some(Err(surf_sse::Error::ConnectionError(err))) => {
+                        break BackendError::Connection(err.to_string());
+                    }
+                    Some(Err(surf_sse::Error::Retry)) | None => {
+                        break BackendError::Disconnected;
+                    }
+                }
+            };
+            // EventSource would retry on its own, but only after a fixed
+            // delay and without always waking up, so start over instead
+            drop(source);
+            drop(connection);
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                url = url.as_str(),
+                error = %err,
+                "connection lost"
+            );
+            on_connection(&ConnectionEvent::Disconnected(err.clone()));
+            yield Err(err);
+            attempt += 1;
+            let delay = backoff.delay_at(attempt, options.clock.now());
+            #[cfg(feature = "tracing")]
+            tracing::info!(
+                attempt,
+                delay_ms = delay.as_millis() as u64,
+                "reconnecting"
+            );
+            on_connection(&ConnectionEvent::Reconnecting { attempt, delay });
+            options.clock.sleep(delay).await;
+        }
+    }
+}
+
+/// Like [`reconnecting()`], through the browser's `EventSource`. Only the
+/// first of `endpoints` is used, and `last_event_id` is only sent by the
+/// browser on its own reconnects, see [`browser`](crate::browser).
+#[cfg(target_arch = "wasm32")]
+pub fn reconnecting_to(
+    endpoints: Endpoints,
+    last_event_id: Option<String>,
+    backoff: Backoff,
+    options: ClientOptions,
+    mut on_connection: impl FnMut(&ConnectionEvent) + 'static,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    if last_event_id.is_some() {
+        log::warn!(
+            "browsers can't send Last-Event-ID on the first connection, \
+             starting from the latest events"
+        );
+    }
+    let url = endpoints.urls[0].clone();
+    stream! {
+        let mut attempt = 0;
+        loop {
+            let connection = options.guardrails.open(
+                url.host_str().unwrap_or_default(),
+                options.user_agent_or_default(),
+            );
+            let connection = match connection {
+                Ok(connection) => connection,
+                Err(violation) => {
+                    yield Err(BackendError::Refused(violation.to_string()));
+                    return;
+                }
+            };
+            let mut open = false;
+            let err = match EventSource::new(&url) {
+                Ok(mut source) => loop {
+                    let next = future::poll_fn(|cx| {
+                        let poll = source.poll_next_unpin(cx);
+                        if !open && source.is_open() {
+                            open = true;
+                            on_connection(&ConnectionEvent::Open);
+                        }
+                        poll
+                    });
+                    let message = match options.read_timeout {
+                        Some(timeout) => {
+                            match future::select(
+                                next,
+                                options.clock.sleep(timeout),
+                            )
+                            .await
+                            {
+                                Either::Left((message, _)) => message,
+                                Either::Right(_) => {
+                                    break BackendError::Timeout
+                                }
+                            }
+                        }
+                        None => next.await,
+                    };
+                    match message {
+                        Some(Ok(message)) => {
+                            attempt = 0;
+                            yield Ok(message);
+                        }
+                        // The browser reconnects on its own after these
+                        Some(Err(err)) => {
+                            open = false;
+                            on_connection(&ConnectionEvent::Disconnected(
+                                err.clone(),
+                            ));
+                            yield Err(err);
+                        }
+                        None => break BackendError::Disconnected,
+                    }
+                },
+                Err(err) => err,
+            };
+            drop(connection);
+            on_connection(&ConnectionEvent::Disconnected(err.clone()));
+            yield Err(err);
+            attempt += 1;
+            let delay = backoff.delay_at(attempt, options.clock.now());
+            on_connection(&ConnectionEvent::Reconnecting { attempt, delay });
+            options.clock.sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn client(
+    http: &Arc<dyn HttpClient>,
+    headers: Vec<(String, String)>,
+) -> surf::Client {
+    surf::Client::with_http_client(Shared(http.clone()))
+        .with(DefaultHeaders(headers))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// One HTTP client shared between connections, so they can reuse its
+/// connection pool
+#[derive(Debug)]
+struct Shared(Arc<dyn HttpClient>);
+
+#[cfg(not(target_arch = "wasm32"))]
+#[surf::utils::async_trait]
+impl HttpClient for Shared {
+    async fn send(
+        &self,
+        req: http_client::Request,
+    ) -> Result<http_client::Response, http_client::Error> {
+        self.0.send(req).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Records the response headers, and comments as they are read
+#[derive(Debug)]
+struct Tap(Arc<Mutex<VecDeque<Diagnostic>>>);
+
+#[cfg(not(target_arch = "wasm32"))]
+#[surf::utils::async_trait]
+impl Middleware for Tap {
+    async fn handle(
+        &self,
+        req: surf::Request,
+        client: surf::Client,
+        next: Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        let mut res = next.run(req, client).await?;
+        let headers = res
+            .iter()
+            .flat_map(|(name, values)| {
+                values
+                    .iter()
+                    .map(move |value| (name.to_string(), value.to_string()))
+            })
+            .collect();
+        self.0.lock().unwrap().push_back(Diagnostic::Response {
+            status: res.status().into(),
+            headers,
+        });
+        let body = CommentReader {
+            inner: res.take_body(),
+            line: Line::Start,
+            comment: vec![],
+            diagnostics: self.0.clone(),
+        };
+        res.set_body(surf::Body::from_reader(BufReader::new(body), None));
+        Ok(res)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Where [`CommentReader`] is in the current line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Line {
+    Start,
+    Comment,
+    Other,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Passes the body through, picking out comment lines
+struct CommentReader {
+    inner: surf::Body,
+    line: Line,
+    comment: Vec<u8>,
+    diagnostics: Arc<Mutex<VecDeque<Diagnostic>>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl CommentReader {
+    fn scan(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.line = match (self.line, byte) {
+                (Line::Comment, b'\n') => {
+                    let comment = String::from_utf8_lossy(&self.comment);
+                    let comment = comment.trim_end_matches('\r');
+                    let comment = comment.strip_prefix(' ').unwrap_or(comment);
+                    self.diagnostics
+                        .lock()
+                        .unwrap()
+                        .push_back(Diagnostic::Comment(comment.to_string()));
+                    self.comment.clear();
+                    Line::Start
+                }
+                (Line::Comment, _) => {
+                    self.comment.push(byte);
+                    Line::Comment
+                }
+                (_, b'\n') => Line::Start,
+                (Line::Start, b':') => Line::Comment,
+                _ => Line::Other,
+            };
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AsyncRead for CommentReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(read)) = poll {
+            self.scan(&buf[..read]);
+        }
+        poll
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// Sends headers, like `Last-Event-ID`, on requests that don't have them
+/// yet
+#[derive(Debug)]
+struct DefaultHeaders(Vec<(String, String)>);
+
+#[cfg(not(target_arch = "wasm32"))]
+#[surf::utils::async_trait]
+impl Middleware for DefaultHeaders {
+    async fn handle(
+        &self,
+        mut req: surf::Request,
+        client: surf::Client,
+        next: Next<'_>,
+    ) -> surf::Result<surf::Response> {
+        for (name, value) in &self.0 {
+            if req.header(name.as_str()).is_none() {
+                req.insert_header(name.as_str(), value.as_str());
+            }
+        }
+        next.run(req, client).await
+    }
+}
+
+/// Messages from memory, e.g. recorded fixtures
+pub fn memory(
+    messages: impl IntoIterator<Item = String>,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    futures::stream::iter(messages.into_iter().map(Ok))
+}
+
+/// A failure for [`FaultInjector`] to simulate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Drop the connection
+    Disconnect,
+    /// Fail with an HTTP error status
+    HttpError(u16),
+    /// Cut the next message off halfway through
+    PartialLine,
+    /// Deliver the next message twice
+    Duplicate,
+    /// Swap the order of the next two messages
+    OutOfOrder,
+}
+
+/// Wraps a backend, injecting [`Fault`]s on demand. Faults are queued up
+/// with a [`FaultHandle`] and applied in order as messages come through.
+pub struct FaultInjector<B> {
+    inner: B,
+    faults: Arc<Mutex<VecDeque<Fault>>>,
+}
+
+/// Queues up faults for a [`FaultInjector`]
+#[derive(Clone, Debug)]
+pub struct FaultHandle {
+    faults: Arc<Mutex<VecDeque<Fault>>>,
+}
+
+impl FaultHandle {
+    pub fn inject(&self, fault: Fault) {
+        self.faults.lock().unwrap().push_back(fault);
+    }
+}
+
+impl<B> FaultInjector<B>
+where
+    B: Stream<Item = Result<String, BackendError>> + Unpin,
+{
+    pub fn new(inner: B) -> (Self, FaultHandle) {
+        let faults = Arc::new(Mutex::new(VecDeque::new()));
+        (
+            Self {
+                inner,
+                faults: faults.clone(),
+            },
+            FaultHandle { faults },
+        )
+    }
+
+    /// Turn into a backend stream
+    pub fn into_stream(
+        mut self,
+    ) -> impl Stream<Item = Result<String, BackendError>> {
+        stream! {
+            while let Some(message) = self.inner.next().await {
+                let fault = self.faults.lock().unwrap().pop_front();
+                match fault {
+                    None => yield message,
+                    Some(Fault::Disconnect) => {
+                        yield Err(BackendError::Disconnected);
+                        yield message;
+                    }
+                    Some(Fault::HttpError(status)) => {
+                        yield Err(BackendError::Http(status));
+                        yield message;
+                    }
+                    Some(Fault::PartialLine) => {
+                        yield message.map(|mut data| {
+                            let mut half = data.len() / 2;
+                            while !data.is_char_boundary(half) {
+                                half -= 1;
+                            }
+                            data.truncate(half);
+                            data
+                        });
+                    }
+                    Some(Fault::Duplicate) => {
+                        yield message.clone();
+                        yield message;
+                    }
+                    Some(Fault::OutOfOrder) => {
+                        if let Some(next) = self.inner.next().await {
+                            yield next;
+                        }
+                        yield message;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        memory, probe, BackendError, Backoff, ClientOptions, CommentReader,
+        ConnectionEvent, Diagnostic, Fault, FaultInjector, Line,
+    };
+    use crate::maintenance::{MaintenanceCalendar, MaintenanceWindow};
+    use crate::testing::{self, MockServer};
+    use crate::EventStreamBuilder;
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn backs_off_for_maintenance_as_of_the_given_time() {
+        let start = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let calendar = MaintenanceCalendar::new()
+            .margin(Duration::ZERO)
+            .window(MaintenanceWindow {
+                start,
+                end: start + chrono::Duration::hours(1),
+                description: String::new(),
+            })
+            .backoff(Backoff::new(
+                Duration::from_secs(30),
+                Duration::from_secs(30),
+                1.0,
+            ));
+        let backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(1), 1.0)
+                .maintenance(calendar);
+        assert_eq!(backoff.delay_at(1, start), Duration::from_secs(30));
+        let after = start + chrono::Duration::hours(2);
+        assert_eq!(backoff.delay_at(1, after), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn multi_line_data_split_across_reads() {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let mut edit = testing::edit(1, "Foo", dt);
+        edit["comment"] = "a long comment ".repeat(100).into();
+        // One `data:` line per line of JSON
+        let message = serde_json::to_string_pretty(&edit).unwrap();
+        // So a mangled message shows up as the wrong event rather than none
+        let next = testing::edit(2, "Bar", dt).to_string();
+        let server = MockServer::new(vec![message.clone(), next])
+            .write_size(7)
+            .start()
+            .unwrap();
+        let mut stream = EventStreamBuilder::new()
+            .url(server.url())
+            .keep_raw()
+            .build()
+            .unwrap();
+        let event = futures::executor::block_on(stream.next()).unwrap();
+        assert_eq!(event.raw(), Some(message.as_str()));
+        assert_eq!(event.title(), "Foo");
+    }
+
+    #[test]
+    fn injects_faults_in_order() {
+        let messages = ["a", "b", "c", "d"].iter().map(|m| m.to_string());
+        let (injector, faults) = FaultInjector::new(memory(messages));
+        faults.inject(Fault::Duplicate);
+        faults.inject(Fault::HttpError(503));
+        faults.inject(Fault::OutOfOrder);
+        let received: Vec<_> =
+            futures::executor::block_on(injector.into_stream().collect());
+        assert_eq!(
+            received,
+            [
+                Ok("a".to_string()),
+                Ok("a".to_string()),
+                Err(BackendError::Http(503)),
+                Ok("b".to_string()),
+                Ok("d".to_string()),
+                Ok("c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_maximum() {
+        let backoff =
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(5), 2.0);
+        let delays: Vec<_> =
+            (1..=5).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5].map(Duration::from_secs));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(5));
+        let jittered = backoff.jitter(0.5).delay(3);
+        assert!(jittered > Duration::from_secs(2));
+        assert!(jittered <= Duration::from_secs(4));
+        // Delays too long to represent are capped too
+        let long = Backoff::new(Duration::MAX, Duration::MAX, 2.0);
+        assert_eq!(long.delay(u32::MAX), Duration::MAX);
+    }
+
+    #[test]
+    #[should_panic(expected = "multiplier must be finite and at least 1")]
+    fn rejects_shrinking_backoffs() {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(5), 0.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "jitter must be finite")]
+    fn rejects_jitter_that_is_not_a_number() {
+        Backoff::default().jitter(f64::NAN);
+    }
+
+    #[test]
+    fn reconnects_where_it_left_off() {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let messages =
+            ["A", "B", "C"].iter().enumerate().map(|(offset, title)| {
+                testing::edit(offset as u64, title, dt).to_string()
+            });
+        let server = MockServer::new(messages)
+            .disconnect_after(2)
+            .start()
+            .unwrap();
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .backoff(Backoff::new(
+                Duration::from_millis(1),
+                Duration::from_millis(1),
+                1.0,
+            ))
+            .build()
+            .unwrap();
+        let changes = Arc::new(Mutex::new(vec![]));
+        {
+            let changes = changes.clone();
+            stream.listeners().on_connection(move |change| {
+                changes.lock().unwrap().push(change.clone())
+            });
+        }
+        let titles: Vec<_> =
+            futures::executor::block_on(stream.take(3).collect::<Vec<_>>())
+                .iter()
+                .map(|event| event.title().to_string())
+                .collect();
+        assert_eq!(titles, ["A", "B", "C"]);
+        let changes = changes.lock().unwrap();
+        assert_eq!(changes[0], ConnectionEvent::Open);
+        assert!(matches!(changes[1], ConnectionEvent::Disconnected(_)));
+        assert_eq!(
+            changes[2],
+            ConnectionEvent::Reconnecting {
+                attempt: 1,
+                delay: Duration::from_millis(1),
+            }
+        );
+        assert_eq!(changes[3], ConnectionEvent::Open);
+    }
+
+    #[test]
+    fn connects_to_the_fastest_endpoint() {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let server =
+            MockServer::new(vec![testing::edit(1, "A", dt).to_string()])
+                .start()
+                .unwrap();
+        // Answers everything with a 404
+        let missing = testing::serve_json(vec![]);
+        let dead = format!("http://{}/v2/stream/recentchange", missing.addr());
+        let urls = [dead.parse().unwrap(), server.url().parse().unwrap()];
+        let probes = futures::executor::block_on(probe(
+            &urls,
+            &ClientOptions::default(),
+            Duration::from_secs(5),
+        ));
+        assert_eq!(probes[0].url, urls[1]);
+        assert!(probes[0].result.is_ok());
+        assert_eq!(probes[1].url, urls[0]);
+        assert_eq!(probes[1].result, Err(BackendError::Http(404)));
+
+        let stream = EventStreamBuilder::new()
+            .endpoints([dead, server.url()])
+            .build()
+            .unwrap();
+        let changes = Arc::new(Mutex::new(vec![]));
+        {
+            let changes = changes.clone();
+            stream.listeners().on_connection(move |change| {
+                changes.lock().unwrap().push(change.clone())
+            });
+        }
+        let event = futures::executor::block_on(stream.take(1).next());
+        assert_eq!(event.unwrap().title(), "A");
+        let changes = changes.lock().unwrap();
+        match &changes[0] {
+            ConnectionEvent::EndpointSelected { url, .. } => {
+                assert_eq!(url.port(), Some(server.addr().port()))
+            }
+            change => panic!("expected an endpoint, got {:?}", change),
+        }
+    }
+
+    #[test]
+    fn reports_response_headers() {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let server =
+            MockServer::new(vec![testing::edit(1, "A", dt).to_string()])
+                .start()
+                .unwrap();
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .diagnostics()
+            .build()
+            .unwrap();
+        let diagnostics = Arc::new(Mutex::new(vec![]));
+        {
+            let diagnostics = diagnostics.clone();
+            stream.listeners().on_diagnostic(move |diagnostic| {
+                diagnostics.lock().unwrap().push(diagnostic.clone())
+            });
+        }
+        let event = futures::executor::block_on(stream.take(1).next());
+        assert_eq!(event.unwrap().title(), "A");
+        let diagnostics = diagnostics.lock().unwrap();
+        assert!(matches!(
+            diagnostics[0],
+            Diagnostic::Response { status: 200, .. }
+        ));
+        assert_eq!(
+            diagnostics[0].header("Content-Type"),
+            Some("text/event-stream")
+        );
+    }
+
+    #[test]
+    fn picks_out_comments_split_across_reads() {
+        let diagnostics = Arc::new(Mutex::new(VecDeque::new()));
+        let mut reader = CommentReader {
+            inner: surf::Body::empty(),
+            line: Line::Start,
+            comment: vec![],
+            diagnostics: diagnostics.clone(),
+        };
+        reader.scan(b": keep");
+        reader.scan(b"alive\r\ndata: {\"a\": \":b\"}\n");
+        reader.scan(b":\n\n");
+        assert_eq!(
+            diagnostics.lock().unwrap().drain(..).collect::<Vec<_>>(),
+            [
+                Diagnostic::Comment("keepalive".to_string()),
+                Diagnostic::Comment(String::new()),
+            ]
+        );
+    }
+
+    /// Records the message of every `tracing` event
+    #[cfg(feature = "tracing")]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for Recorder {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::Id {
+            tracing::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::Id, _: &tracing::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Message<'a>(&'a mut String);
+            impl tracing::field::Visit for Message<'_> {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    if field.name() == "message" {
+                        *self.0 = format!("{:?}", value);
+                    }
+                }
+            }
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            let level = event.metadata().level();
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", level, message));
+        }
+
+        fn enter(&self, _: &tracing::Id) {}
+
+        fn exit(&self, _: &tracing::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn traces_connecting_and_parsing() {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let messages = vec![
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(1, "A", dt).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let recorded = Arc::new(Mutex::new(vec![]));
+        let recorder = Recorder(recorded.clone());
+        tracing::subscriber::with_default(recorder, || {
+            let stream =
+                EventStreamBuilder::new().url(server.url()).build().unwrap();
+            futures::executor::block_on(stream.take(1).next()).unwrap();
+        });
+        let recorded = recorded.lock().unwrap();
+        let position = |message: &str| {
+            recorded.iter().position(|recorded| recorded == message)
+        };
+        let connecting = position("DEBUG connecting").unwrap();
+        let connected = position("INFO connected").unwrap();
+        let malformed = position("WARN malformed message").unwrap();
+        assert!(connecting < connected && connected < malformed);
+    }
+}
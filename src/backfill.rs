@@ -0,0 +1,372 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Filling in missed events from the Action API
+//!
+//! After a [gap](crate::gaps) or downtime, the events that were missed can
+//! be reconstructed from each wiki's
+//! [recent changes](https://www.mediawiki.org/wiki/API:RecentChanges) and
+//! injected into the pipeline. Reconstructed events are flagged with
+//! `is_backfilled()`, and only carry what the Action API provides: for
+//! example there's no `log_action_comment`, and the Kafka position in
+//! `meta` is empty.
+use crate::api::{ApiClient, ApiError};
+use crate::types::EventMeta;
+use crate::{EditEvent, Event, LogEvent};
+use async_stream::try_stream;
+use chrono::{DateTime, SecondsFormat, Utc};
+use compact_str::CompactString;
+use futures::Stream;
+use serde::Deserialize;
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum BackfillError {
+    /// Making the request failed
+    Http(surf::Error),
+    /// The API returned an error
+    Api(String),
+}
+
+impl From<ApiError> for BackfillError {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::Http(err) => Self::Http(err),
+            ApiError::Api { info, .. } => Self::Api(info),
+            err => Self::Api(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for BackfillError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "HTTP request failed: {}", err),
+            Self::Api(err) => write!(f, "API error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BackfillError {}
+
+/// A wiki to backfill events for
+#[derive(Clone, Debug)]
+pub struct Wiki {
+    /// URL of wiki with protocol, e.g. `https://en.wikipedia.org`
+    pub server_url: String,
+    /// Base URL path of wiki, e.g. `/w`
+    pub server_script_path: String,
+}
+
+impl Wiki {
+    pub fn new(
+        server_url: impl Into<String>,
+        server_script_path: impl Into<String>,
+    ) -> Self {
+        Self {
+            server_url: server_url.into(),
+            server_script_path: server_script_path.into(),
+        }
+    }
+
+    fn api_url(&self) -> String {
+        format!("{}{}/api.php", self.server_url, self.server_script_path)
+    }
+}
+
+#[derive(Deserialize)]
+struct RecentChange {
+    #[serde(rename = "type")]
+    type_: CompactString,
+    ns: i32,
+    title: String,
+    rcid: u64,
+    #[serde(default)]
+    revid: u32,
+    #[serde(default)]
+    old_revid: u32,
+    #[serde(default)]
+    user: CompactString,
+    #[serde(default)]
+    bot: bool,
+    #[serde(default)]
+    minor: bool,
+    #[serde(default)]
+    oldlen: u32,
+    #[serde(default)]
+    newlen: u32,
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    comment: String,
+    #[serde(default)]
+    parsedcomment: String,
+    #[serde(default)]
+    logid: u32,
+    #[serde(default)]
+    logtype: CompactString,
+    #[serde(default)]
+    logaction: CompactString,
+    #[serde(default)]
+    logparams: Value,
+}
+
+/// Fetches recent changes in a time range, in chronological order
+pub struct Backfill {
+    api: ApiClient,
+}
+
+impl Backfill {
+    pub fn new() -> Self {
+        Self::with_api(ApiClient::new())
+    }
+
+    /// Make requests with `api`, e.g. to share its limits with other
+    /// stages
+    pub fn with_api(api: ApiClient) -> Self {
+        Self { api }
+    }
+
+    /// Reconstruct edits, page creations and log entries on `wiki` between
+    /// `start` and `end`
+    pub fn fetch(
+        &self,
+        wiki: Wiki,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> impl Stream<Item = Result<Event, BackfillError>> {
+        let api = self.api.clone();
+        try_stream! {
+            let mut continue_: Option<String> = None;
+            loop {
+                let mut url = surf::Url::parse(&wiki.api_url())
+                    .map_err(|err| BackfillError::Api(err.to_string()))?;
+                url.query_pairs_mut()
+                    .append_pair("action", "query")
+                    .append_pair("list", "recentchanges")
+                    .append_pair("meta", "siteinfo")
+                    .append_pair("rctype", "edit|new|log")
+                    .append_pair("rcdir", "newer")
+                    .append_pair(
+                        "rcstart",
+                        &start.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    )
+                    .append_pair(
+                        "rcend",
+                        &end.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    )
+                    .append_pair(
+                        "rcprop",
+                        "title|ids|sizes|flags|user|timestamp|comment|parsedcomment|loginfo",
+                    )
+                    .append_pair("rclimit", "max")
+                    .append_pair("format", "json")
+                    .append_pair("formatversion", "2");
+                if let Some(continue_) = &continue_ {
+                    url.query_pairs_mut().append_pair("rccontinue", continue_);
+                }
+                let resp: Value = api.get(url).await?;
+                let general = &resp["query"]["general"];
+                let dbname = general["wikiid"].as_str().unwrap_or_default();
+                let server_name = general["servername"]
+                    .as_str()
+                    .unwrap_or_default();
+                let changes: Vec<RecentChange> = serde_json::from_value(
+                    resp["query"]["recentchanges"].clone(),
+                )
+                .map_err(|err| BackfillError::Api(err.to_string()))?;
+                for change in changes {
+                    yield to_event(change, &wiki, server_name, dbname);
+                }
+                match resp["continue"]["rccontinue"].as_str() {
+                    Some(next) => continue_ = Some(next.to_string()),
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+impl Default for Backfill {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn to_event(
+    change: RecentChange,
+    wiki: &Wiki,
+    server_name: &str,
+    dbname: &str,
+) -> Event {
+    let meta = EventMeta {
+        uri: format!(
+            "{}/wiki/{}",
+            &wiki.server_url,
+            change.title.replace(' ', "_")
+        ),
+        request_id: CompactString::default(),
+        id: format!("backfill-{}-{}", dbname, change.rcid),
+        dt: change.timestamp,
+        domain: server_name.into(),
+        stream: "mediawiki.recentchange".into(),
+        topic: String::new(),
+        partition: 0,
+        offset: 0,
+        extra: Default::default(),
+        raw: None,
+    };
+    let timestamp = change.timestamp.timestamp() as u32;
+    if change.type_ == "log" {
+        Event::Log(LogEvent {
+            schema: String::new(),
+            meta,
+            id: Some(change.rcid),
+            type_: change.type_,
+            namespace: change.ns,
+            title: change.title,
+            comment: change.comment,
+            parsedcomment: change.parsedcomment,
+            timestamp,
+            user: change.user,
+            bot: change.bot,
+            log_id: change.logid,
+            log_type: change.logtype,
+            log_action: change.logaction,
+            log_params: change.logparams,
+            log_action_comment: String::new(),
+            server_url: wiki.server_url.as_str().into(),
+            server_name: server_name.into(),
+            server_script_path: wiki.server_script_path.as_str().into(),
+            wiki: dbname.into(),
+            extra: Default::default(),
+            backfilled: true,
+        })
+    } else {
+        let new = change.type_ == "new";
+        let edit = EditEvent {
+            schema: String::new(),
+            meta,
+            id: change.revid,
+            type_: change.type_,
+            namespace: change.ns,
+            title: change.title,
+            comment: change.comment,
+            parsedcomment: change.parsedcomment,
+            timestamp,
+            user: change.user,
+            bot: change.bot,
+            minor: Some(change.minor),
+            patrolled: None,
+            length: crate::types::EventLength {
+                old: Some(change.oldlen).filter(|_| !new),
+                new: change.newlen,
+            },
+            revision: crate::types::EventRevision {
+                old: Some(change.old_revid).filter(|_| !new),
+                new: change.revid,
+            },
+            server_url: wiki.server_url.as_str().into(),
+            server_name: server_name.into(),
+            server_script_path: wiki.server_script_path.as_str().into(),
+            wiki: dbname.into(),
+            extra: Default::default(),
+            backfilled: true,
+        };
+        if new {
+            Event::New(edit)
+        } else {
+            Event::Edit(edit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use serde_json::json;
+
+    fn response(changes: Value, continue_: Option<&str>) -> Value {
+        let mut response = json!({
+            "query": {
+                "general": {
+                    "wikiid": "enwiki",
+                    "servername": "en.wikipedia.org",
+                },
+                "recentchanges": changes,
+            },
+        });
+        if let Some(continue_) = continue_ {
+            response["continue"] = json!({ "rccontinue": continue_ });
+        }
+        response
+    }
+
+    #[test]
+    fn reconstructs_events_across_continuations() {
+        let server = testing::serve_json(vec![
+            response(
+                json!([{
+                    "type": "edit", "ns": 0, "title": "A", "rcid": 1,
+                    "revid": 11, "old_revid": 10, "user": "Alice",
+                    "oldlen": 5, "newlen": 8,
+                    "timestamp": "2021-01-01T00:00:10Z",
+                }]),
+                Some("20210101000010|2"),
+            ),
+            response(
+                json!([{
+                    "type": "log", "ns": 6, "title": "File:B.png",
+                    "rcid": 2, "user": "Bob", "logid": 3,
+                    "logtype": "upload", "logaction": "upload",
+                    "timestamp": "2021-01-01T00:00:20Z",
+                }]),
+                None,
+            ),
+        ]);
+        let wiki = Wiki::new(format!("http://{}", server.addr()), "/w");
+        let events: Vec<_> = block_on(
+            Backfill::new()
+                .fetch(wiki, at(0), at(60))
+                .collect::<Vec<_>>(),
+        )
+        .into_iter()
+        .collect::<Result<_, _>>()
+        .unwrap();
+        match events.as_slice() {
+            [Event::Edit(edit), Event::Log(log)] => {
+                assert_eq!(edit.title, "A");
+                assert_eq!(edit.length.old, Some(5));
+                assert_eq!(edit.meta.dt, at(10));
+                assert_eq!(edit.meta.id, "backfill-enwiki-1");
+                assert!(edit.backfilled);
+                assert_eq!(log.log_type, "upload");
+                assert_eq!(log.server_name, "en.wikipedia.org");
+            }
+            events => panic!("unexpected events: {:?}", events),
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].path.starts_with("/w/api.php?"));
+        assert!(requests[0]
+            .path
+            .contains("rcstart=2021-01-01T00%3A00%3A00Z"));
+        assert!(requests[1].path.contains("rccontinue=20210101000010%7C2"));
+    }
+}
@@ -0,0 +1,271 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Trying out filters and detectors on recorded events
+//!
+//! Before a patrol rule goes live, it's worth knowing how often it fires
+//! and how often it's right. A [`Backtest`] runs a [`Detector`], like a
+//! [`Filter`], over recorded events (e.g. an
+//! [`Archive`](crate::archive::Archive)) and counts its matches. Given
+//! labels saying which events it should have matched, the
+//! [`BacktestReport`] also has its precision and recall.
+//!
+//! ```no_run
+//! use eventstreams::archive::Archive;
+//! use eventstreams::backtest::Backtest;
+//! use eventstreams::filter::Filter;
+//! # async fn run() -> Result<(), regex::Error> {
+//! let filter = Filter::new().comment_matches(vec!["(?i)vandal"])?;
+//! let events = eventstreams::from_backend(
+//!     Archive::new(vec!["2021-01-01.ndjson", "2021-01-02.ndjson"])
+//!         .into_stream(),
+//!     None,
+//! );
+//! let report = Backtest::new(filter)
+//!     .labeled_revisions(vec![("enwiki", 1234)])
+//!     .run(events)
+//!     .await;
+//! println!("{} matches, precision {:?}", report.matches, report.precision());
+//! # Ok(())
+//! # }
+//! ```
+use crate::filter::Filter;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use futures::{Stream, StreamExt};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Anything that picks out events, like a [`Filter`] or a closure
+pub trait Detector {
+    /// Whether `event` should be flagged
+    fn detect(&mut self, event: &Event) -> bool;
+}
+
+impl Detector for Filter {
+    fn detect(&mut self, event: &Event) -> bool {
+        self.matches(event)
+    }
+}
+
+impl<F: FnMut(&Event) -> bool> Detector for F {
+    fn detect(&mut self, event: &Event) -> bool {
+        self(event)
+    }
+}
+
+/// How a detector did over a set of events
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BacktestReport {
+    /// Events checked
+    pub events: u64,
+    /// Events the detector flagged
+    pub matches: u64,
+    /// Flagged events that were labeled
+    pub true_positives: u64,
+    /// Flagged events that weren't labeled
+    pub false_positives: u64,
+    /// Labeled events that weren't flagged
+    pub false_negatives: u64,
+    /// Time of the first and last event checked
+    pub first: Option<DateTime<Utc>>,
+    pub last: Option<DateTime<Utc>>,
+}
+
+impl BacktestReport {
+    /// Share of flagged events that were labeled. `None` without labels or
+    /// matches.
+    pub fn precision(&self) -> Option<f64> {
+        let flagged = self.true_positives + self.false_positives;
+        if flagged == 0 {
+            return None;
+        }
+        Some(self.true_positives as f64 / flagged as f64)
+    }
+
+    /// Share of labeled events that were flagged. `None` without labels.
+    pub fn recall(&self) -> Option<f64> {
+        let labeled = self.true_positives + self.false_negatives;
+        if labeled == 0 {
+            return None;
+        }
+        Some(self.true_positives as f64 / labeled as f64)
+    }
+
+    /// Matches per hour of recorded time
+    pub fn rate(&self) -> Option<f64> {
+        let hours = (self.last? - self.first?).num_seconds() as f64 / 3600.0;
+        if hours <= 0.0 {
+            return None;
+        }
+        Some(self.matches as f64 / hours)
+    }
+}
+
+impl fmt::Display for BacktestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} events matched", self.matches, self.events)?;
+        if let Some(rate) = self.rate() {
+            write!(f, " ({:.1}/hour)", rate)?;
+        }
+        if let (Some(precision), Some(recall)) =
+            (self.precision(), self.recall())
+        {
+            write!(
+                f,
+                ", precision {:.1}%, recall {:.1}%",
+                precision * 100.0,
+                recall * 100.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type Labels = Box<dyn Fn(&Event) -> bool>;
+
+/// Runs a [`Detector`] over events and scores it
+pub struct Backtest<D> {
+    detector: D,
+    labels: Option<Labels>,
+    report: BacktestReport,
+}
+
+impl<D: Detector> Backtest<D> {
+    pub fn new(detector: D) -> Self {
+        Self {
+            detector,
+            labels: None,
+            report: BacktestReport::default(),
+        }
+    }
+
+    /// Score against `labels`, which says whether an event should be
+    /// flagged
+    pub fn labels(mut self, labels: impl Fn(&Event) -> bool + 'static) -> Self {
+        self.labels = Some(Box::new(labels));
+        self
+    }
+
+    /// Score against a list of revisions, by wiki (e.g. `enwiki`) and
+    /// revision ID, that should be flagged
+    pub fn labeled_revisions<I, S>(self, revisions: I) -> Self
+    where
+        I: IntoIterator<Item = (S, u64)>,
+        S: Into<String>,
+    {
+        let revisions: HashSet<(String, u64)> = revisions
+            .into_iter()
+            .map(|(wiki, revision)| (wiki.into(), revision))
+            .collect();
+        self.labels(move |event| {
+            let revision = match event {
+                Event::Edit(edit) | Event::New(edit) => {
+                    edit.revision.new.into()
+                }
+                Event::RevisionCreate(revision) => revision.rev_id,
+                _ => return false,
+            };
+            revisions.contains(&(event.wiki().to_string(), revision))
+        })
+    }
+
+    /// Check a single event, returning whether the detector flagged it
+    pub fn push(&mut self, event: &Event) -> bool {
+        let report = &mut self.report;
+        report.events += 1;
+        report.first.get_or_insert(event.dt());
+        report.last = Some(event.dt());
+        let flagged = self.detector.detect(event);
+        if flagged {
+            report.matches += 1;
+        }
+        if let Some(labels) = &self.labels {
+            match (flagged, labels(event)) {
+                (true, true) => report.true_positives += 1,
+                (true, false) => report.false_positives += 1,
+                (false, true) => report.false_negatives += 1,
+                (false, false) => {}
+            }
+        }
+        flagged
+    }
+
+    /// The report so far
+    pub fn report(&self) -> &BacktestReport {
+        &self.report
+    }
+
+    /// Check every event in `events`, returning the report
+    pub async fn run(
+        mut self,
+        events: impl Stream<Item = Event>,
+    ) -> BacktestReport {
+        futures::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            self.push(&event);
+        }
+        self.report
+    }
+}
+
+impl<D> fmt::Debug for Backtest<D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Backtest")
+            .field("labeled", &self.labels.is_some())
+            .field("report", &self.report)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    #[test]
+    fn scores_detectors_against_labels() {
+        let events: Vec<_> = ["A", "B", "A", "C"]
+            .iter()
+            .enumerate()
+            .map(|(offset, title)| {
+                let dt = at(offset as i64 * 1800);
+                testing::edit_event(offset as u64, title, dt)
+            })
+            .collect();
+        // Revisions of the first and last edit
+        let backtest = Backtest::new(|event: &Event| event.title() == "A")
+            .labeled_revisions(vec![("enwiki", 1), ("enwiki", 4)]);
+        let report = block_on(backtest.run(futures::stream::iter(events)));
+        assert_eq!(
+            report,
+            BacktestReport {
+                events: 4,
+                matches: 2,
+                true_positives: 1,
+                false_positives: 1,
+                false_negatives: 1,
+                first: Some(at(0)),
+                last: Some(at(5400)),
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "2 of 4 events matched (1.3/hour), precision 50.0%, recall 50.0%"
+        );
+    }
+}
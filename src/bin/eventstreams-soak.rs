@@ -0,0 +1,242 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Soak test against the live feed
+//!
+//! Consumes the live stream for a while, checking that nothing panics,
+//! memory stays bounded, no Kafka offsets are skipped and no event is
+//! delivered twice, then writes a JSON report.
+//!
+//! Run with `cargo run --features soak --bin eventstreams-soak -- \
+//!     [--hours N] [--max-rss-mb N] [--report PATH]`
+use eventstreams::backend;
+use eventstreams::dedup::{self, DedupStatus, DedupWindow};
+use eventstreams::gaps::{self, GapDetected};
+use eventstreams::side_output::SideOutput;
+use eventstreams::StreamExt;
+use futures::future::{self, Either};
+use futures::FutureExt;
+use serde::{Serialize, Serializer};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default, Serialize)]
+struct Report {
+    runtime_secs: u64,
+    messages: u64,
+    events: u64,
+    excluded: u64,
+    backend_errors: u64,
+    /// Events delivered again within the dedup window
+    duplicates: u64,
+    max_rss_kb: u64,
+    #[serde(serialize_with = "serialize_gaps")]
+    gaps: Vec<GapDetected>,
+    violations: Vec<String>,
+}
+
+/// Skipped offsets, as (topic, partition, first missing, last missing)
+fn serialize_gaps<S: Serializer>(
+    gaps: &[GapDetected],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(gaps.iter().map(|gap| {
+        (
+            &gap.topic,
+            gap.partition,
+            gap.first_missing,
+            gap.last_missing,
+        )
+    }))
+}
+
+struct Args {
+    duration: Duration,
+    max_rss_kb: u64,
+    report: String,
+}
+
+fn parse_args(mut iter: impl Iterator<Item = String>) -> Args {
+    let mut args = Args {
+        duration: Duration::from_secs(3600),
+        max_rss_kb: 256 * 1024,
+        report: "soak-report.json".to_string(),
+    };
+    while let Some(arg) = iter.next() {
+        let value = iter.next().expect("missing value for argument");
+        match arg.as_str() {
+            "--hours" => {
+                let hours: f64 = value.parse().expect("invalid --hours");
+                args.duration = Duration::from_secs_f64(hours * 3600.0);
+            }
+            "--max-rss-mb" => {
+                let mb: u64 = value.parse().expect("invalid --max-rss-mb");
+                args.max_rss_kb = mb * 1024;
+            }
+            "--report" => args.report = value,
+            _ => panic!("unknown argument: {}", arg),
+        }
+    }
+    args
+}
+
+/// Resident memory of this process, in kB (Linux only)
+fn rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+async fn soak(args: &Args, report: Arc<Mutex<Report>>) {
+    let counter = report.clone();
+    let gaps = report.clone();
+    let backend = backend::live().inspect(move |message| {
+        let mut report = counter.lock().unwrap();
+        match message {
+            Ok(_) => report.messages += 1,
+            Err(_) => report.backend_errors += 1,
+        }
+    });
+    let backend = gaps::detect_gaps(backend, move |gap| {
+        gaps.lock().unwrap().gaps.push(gap);
+    });
+    let (side_output, excluded) = SideOutput::new("soak");
+    let stream = eventstreams::from_backend(backend, Some(side_output));
+    eventstreams::pin_mut!(stream);
+    eventstreams::pin_mut!(excluded);
+    let mut seen = DedupWindow::new(dedup::DEFAULT_CAPACITY);
+    // Stop once the time is up, even if nothing is coming in
+    let mut deadline = futures_timer::Delay::new(args.duration);
+    while let Either::Left((Some(event), _)) =
+        future::select(stream.next(), &mut deadline).await
+    {
+        let mut report = report.lock().unwrap();
+        report.events += 1;
+        if seen.check(&event) == DedupStatus::Duplicate {
+            report.duplicates += 1;
+        }
+        while let Some(Some(_)) = excluded.next().now_or_never() {
+            report.excluded += 1;
+        }
+        if report.events.is_multiple_of(1000) {
+            if let Some(rss) = rss_kb() {
+                report.max_rss_kb = report.max_rss_kb.max(rss);
+            }
+        }
+    }
+}
+
+/// Record the invariants `report` violates, once each
+fn check(report: &mut Report, args: &Args) {
+    if report.max_rss_kb > args.max_rss_kb {
+        let violation =
+            format!("peak RSS of {} kB over limit", report.max_rss_kb);
+        report.violations.push(violation);
+    }
+    if !report.gaps.is_empty() {
+        let violation = format!("{} offset gaps", report.gaps.len());
+        report.violations.push(violation);
+    }
+    if report.duplicates > 0 {
+        let violation = format!("{} duplicate events", report.duplicates);
+        report.violations.push(violation);
+    }
+}
+
+fn main() {
+    let args = parse_args(std::env::args().skip(1));
+    let start = Instant::now();
+    let report = Arc::new(Mutex::new(Report::default()));
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        futures::executor::block_on(soak(&args, report.clone()))
+    }));
+    let mut report = match Arc::try_unwrap(report) {
+        Ok(report) => {
+            report.into_inner().unwrap_or_else(|err| err.into_inner())
+        }
+        Err(report) => std::mem::take(&mut *report.lock().unwrap()),
+    };
+    if result.is_err() {
+        report.violations.push("panicked".to_string());
+    }
+    check(&mut report, &args);
+    report.runtime_secs = start.elapsed().as_secs();
+    std::fs::write(&args.report, serde_json::to_vec_pretty(&report).unwrap())
+        .expect("failed to write report");
+    println!("Wrote report to {}", &args.report);
+    if !report.violations.is_empty() {
+        eprintln!("Invariants violated: {:?}", &report.violations);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_arguments() {
+        let args = ["--hours", "0.5", "--max-rss-mb", "64", "--report", "r"];
+        let args = parse_args(args.iter().map(|arg| arg.to_string()));
+        assert_eq!(args.duration, Duration::from_secs(1800));
+        assert_eq!(args.max_rss_kb, 64 * 1024);
+        assert_eq!(args.report, "r");
+    }
+
+    #[test]
+    fn reports_each_violation_once() {
+        let args = parse_args(std::iter::empty());
+        let mut report = Report {
+            max_rss_kb: args.max_rss_kb + 1,
+            duplicates: 3,
+            ..Report::default()
+        };
+        check(&mut report, &args);
+        assert_eq!(
+            report.violations,
+            [
+                format!("peak RSS of {} kB over limit", args.max_rss_kb + 1),
+                "3 duplicate events".to_string(),
+            ]
+        );
+
+        let mut report = Report {
+            max_rss_kb: args.max_rss_kb,
+            ..Report::default()
+        };
+        check(&mut report, &args);
+        assert!(report.violations.is_empty());
+    }
+
+    #[test]
+    fn reports_gaps_as_tuples() {
+        let report = Report {
+            gaps: vec![GapDetected {
+                topic: "eqiad.mediawiki.recentchange".to_string(),
+                partition: 0,
+                first_missing: 5,
+                last_missing: 7,
+            }],
+            ..Report::default()
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["gaps"],
+            serde_json::json!([["eqiad.mediawiki.recentchange", 0, 5, 7]])
+        );
+    }
+}
@@ -0,0 +1,143 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Command-line interface
+//!
+//! `eventstreams serve [--listen ADDR] [--wiki WIKI]... [--admin ADDR]`
+//! runs a daemon that prints events from the given wikis (by domain,
+//! server URL or database name), with `/healthz` and `/metrics` served on
+//! `--listen` (default `127.0.0.1:8080`). With `--admin`, the admin API
+//! is served there too, using the token in `EVENTSTREAMS_ADMIN_TOKEN`;
+//! subscriptions added through it can use the `stdout` sink.
+//!
+//! `eventstreams stats [--bucket SECONDS] [--top N]` prints per-language
+//! activity as tab-separated `start language count` lines, one block per
+//! bucket (default 60 seconds).
+//!
+//! `eventstreams relay [--listen ADDR]` re-serves the stream to local SSE
+//! and WebSocket clients connecting to `/stream` on `--listen` (default
+//! `127.0.0.1:8090`), each with an optional `filter` query parameter.
+use eventstreams::daemon::Daemon;
+use eventstreams::relay::Relay;
+use eventstreams::sink::StdoutSink;
+use eventstreams::subscription::Subscription;
+use eventstreams::{EventStreamExt, StreamExt};
+use std::time::Duration;
+
+const USAGE: &str =
+    "usage: eventstreams serve [--listen ADDR] [--wiki WIKI]... [--admin ADDR]
+       eventstreams stats [--bucket SECONDS] [--top N]
+       eventstreams relay [--listen ADDR]";
+
+fn serve(mut args: impl Iterator<Item = String>) {
+    let mut listen = "127.0.0.1:8080".to_string();
+    let mut wikis = vec![];
+    let mut admin = None;
+    while let Some(arg) = args.next() {
+        let value = args.next().expect(USAGE);
+        match arg.as_str() {
+            "--listen" => listen = value,
+            "--wiki" => wikis.push(value),
+            "--admin" => admin = Some(value),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let daemon = Daemon::new().subscribe(Subscription::new(
+        "stdout",
+        move |event| {
+            wikis.is_empty() || wikis.iter().any(|wiki| event.is_on_wiki(wiki))
+        },
+        StdoutSink,
+    ));
+    daemon.serve_http(&listen).unwrap_or_else(|err| {
+        panic!("failed to listen on {}: {}", listen, err)
+    });
+    if let Some(admin) = admin {
+        let token = std::env::var("EVENTSTREAMS_ADMIN_TOKEN")
+            .expect("EVENTSTREAMS_ADMIN_TOKEN must be set to use --admin");
+        daemon.registry().register_sink("stdout", || StdoutSink);
+        daemon.serve_admin(&admin, token).unwrap_or_else(|err| {
+            panic!("failed to listen on {}: {}", admin, err)
+        });
+    }
+    // Only once the servers are up, so systemd doesn't consider the
+    // service started before it can be reached
+    #[cfg(unix)]
+    if let Err(err) = daemon.notify_systemd() {
+        eprintln!("failed to notify systemd: {}", err);
+    }
+    futures::executor::block_on(daemon.run());
+}
+
+fn stats(mut args: impl Iterator<Item = String>) {
+    let mut bucket = 60;
+    let mut top = usize::MAX;
+    while let Some(arg) = args.next() {
+        let value = args.next().expect(USAGE);
+        match arg.as_str() {
+            "--bucket" => bucket = value.parse().expect(USAGE),
+            "--top" => top = value.parse().expect(USAGE),
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let activity = eventstreams::stream().language_activity(
+        Duration::from_secs(bucket),
+        Duration::from_secs(10),
+    );
+    eventstreams::pin_mut!(activity);
+    futures::executor::block_on(async {
+        while let Some(activity) = activity.next().await {
+            for (language, count) in activity.ranked().into_iter().take(top) {
+                println!(
+                    "{}\t{}\t{}",
+                    activity.start.to_rfc3339(),
+                    language,
+                    count
+                );
+            }
+        }
+    });
+}
+
+fn relay(mut args: impl Iterator<Item = String>) {
+    let mut listen = "127.0.0.1:8090".to_string();
+    while let Some(arg) = args.next() {
+        let value = args.next().expect(USAGE);
+        match arg.as_str() {
+            "--listen" => listen = value,
+            _ => panic!("{}", USAGE),
+        }
+    }
+    let relay = Relay::bind(&listen).unwrap_or_else(|err| {
+        panic!("failed to listen on {}: {}", listen, err)
+    });
+    let daemon =
+        Daemon::new().subscribe(Subscription::new("relay", |_| true, relay));
+    futures::executor::block_on(daemon.run());
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("serve") => serve(args),
+        Some("stats") => stats(args),
+        Some("relay") => relay(args),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,267 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Pausing delivery to a failing sink
+//!
+//! A [`CircuitBreaker`] keeps track of how many of a
+//! [`Subscription`](crate::subscription::Subscription)'s recent deliveries
+//! failed. Once more than its error budget did, it opens: the subscription
+//! is paused and skips events instead of piling up retries against a sink
+//! that's down, and the position of the last event the sink took is
+//! checkpointed so the gap can be [backfilled](Checkpoint) later. After a
+//! cooldown, the next event is sent as a probe; if the sink takes it,
+//! delivery resumes, otherwise the breaker stays open for another
+//! cooldown.
+use crate::checkpoint::{Checkpoint, Checkpointer};
+use crate::clock::{Clock, SystemClock};
+use crate::Event;
+use chrono::{DateTime, Utc};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Whether a [`CircuitBreaker`] lets events through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Delivering normally
+    Closed,
+    /// Paused until the cooldown ends
+    Open { until: DateTime<Utc> },
+    /// Cooldown over, the next event is a probe
+    HalfOpen,
+}
+
+/// Pauses a subscription whose sink exceeds its error budget, see the
+/// [module documentation](self)
+pub struct CircuitBreaker {
+    budget: f64,
+    window: usize,
+    cooldown: Duration,
+    clock: Arc<dyn Clock>,
+    checkpointer: Option<Box<dyn Checkpointer + Send>>,
+    /// Whether each of the most recent deliveries failed, oldest first
+    outcomes: VecDeque<bool>,
+    state: BreakerState,
+    /// Position of the last event the sink took
+    position: Checkpoint,
+    paused_at: Option<Checkpoint>,
+    skipped: u64,
+}
+
+impl CircuitBreaker {
+    /// Open once more than `budget` (between 0 and 1) of the last `window`
+    /// deliveries failed, and probe the sink again after `cooldown`
+    pub fn new(budget: f64, window: usize, cooldown: Duration) -> Self {
+        Self {
+            budget: budget.clamp(0.0, 1.0),
+            window: window.max(1),
+            cooldown,
+            clock: Arc::new(SystemClock),
+            checkpointer: None,
+            outcomes: VecDeque::new(),
+            state: BreakerState::Closed,
+            position: Checkpoint::default(),
+            paused_at: None,
+            skipped: 0,
+        }
+    }
+
+    /// Measure the cooldown with `clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Also save the position to `checkpointer` whenever the breaker opens
+    pub fn checkpointer(
+        mut self,
+        checkpointer: impl Checkpointer + Send + 'static,
+    ) -> Self {
+        self.checkpointer = Some(Box::new(checkpointer));
+        self
+    }
+
+    /// Current state, moving to [`HalfOpen`](BreakerState::HalfOpen) if
+    /// the cooldown has ended
+    pub fn state(&mut self) -> BreakerState {
+        if let BreakerState::Open { until } = self.state {
+            if self.clock.now() >= until {
+                self.state = BreakerState::HalfOpen;
+            }
+        }
+        self.state
+    }
+
+    pub fn is_paused(&mut self) -> bool {
+        matches!(self.state(), BreakerState::Open { .. })
+    }
+
+    /// Whether to send the next event to the sink. Events that aren't let
+    /// through are counted as skipped.
+    pub fn allow(&mut self) -> bool {
+        if self.is_paused() {
+            self.skipped += 1;
+            return false;
+        }
+        true
+    }
+
+    /// Record that the sink took `event`
+    pub fn record_success(&mut self, event: &Event) {
+        self.position.token.observe(event);
+        self.position.dt = self.position.dt.max(Some(event.dt()));
+        if self.state == BreakerState::HalfOpen {
+            if let Some(paused_at) = self.paused_at.take() {
+                log::info!(
+                    target: "eventstreams::breaker",
+                    "sink recovered, resuming after skipping {} events since \
+                     {}",
+                    self.skipped,
+                    paused_at.token.to_last_event_id()
+                );
+            }
+            self.state = BreakerState::Closed;
+            self.outcomes.clear();
+            return;
+        }
+        self.push(false);
+    }
+
+    /// Record that the sink failed to take an event, opening the breaker
+    /// if that exceeds the budget or the event was a probe
+    pub fn record_failure(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            self.open();
+            return;
+        }
+        self.push(true);
+        let errors = self.outcomes.iter().filter(|failed| **failed).count();
+        if self.outcomes.len() == self.window
+            && errors as f64 > self.budget * self.window as f64
+        {
+            self.paused_at = Some(self.position.clone());
+            if let Some(checkpointer) = &self.checkpointer {
+                if let Err(err) = checkpointer.save(&self.position) {
+                    log::warn!(
+                        target: "eventstreams::breaker",
+                        "failed to save checkpoint: {}",
+                        err
+                    );
+                }
+            }
+            self.open();
+        }
+    }
+
+    /// Where delivery was when the breaker opened, i.e. the position of
+    /// the last event the sink took, while it's paused or probing
+    pub fn paused_at(&self) -> Option<&Checkpoint> {
+        self.paused_at.as_ref()
+    }
+
+    /// Number of events skipped while paused
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    fn push(&mut self, failed: bool) {
+        if self.outcomes.len() == self.window {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(failed);
+    }
+
+    fn open(&mut self) {
+        let cooldown = chrono::Duration::from_std(self.cooldown)
+            .unwrap_or(chrono::Duration::MAX);
+        let until = self
+            .clock
+            .now()
+            .checked_add_signed(cooldown)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.state = BreakerState::Open { until };
+        log::warn!(
+            target: "eventstreams::breaker",
+            "error budget exhausted, pausing for {:?}",
+            self.cooldown
+        );
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("budget", &self.budget)
+            .field("window", &self.window)
+            .field("cooldown", &self.cooldown)
+            .field("state", &self.state)
+            .field("skipped", &self.skipped)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::FileCheckpointer;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn pauses_over_budget_and_probes_after_cooldown() {
+        let path = std::env::temp_dir()
+            .join(format!("eventstreams-breaker-{}.json", std::process::id()));
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let mut breaker = CircuitBreaker::new(0.5, 4, Duration::from_secs(60))
+            .clock(clock.clone())
+            .checkpointer(FileCheckpointer::new(&path));
+        let edit = testing::edit_event(1, "A", at(5));
+        breaker.record_success(&edit);
+        breaker.record_failure();
+        breaker.record_failure();
+        // Half of the window is within budget
+        breaker.record_success(&edit);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open { until: at(60) });
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(breaker.skipped(), 2);
+        let paused_at = breaker.paused_at().unwrap().clone();
+        assert_eq!(paused_at.dt, Some(at(5)));
+        assert_eq!(
+            FileCheckpointer::new(&path).load().unwrap(),
+            Some(paused_at)
+        );
+
+        // A failed probe opens it for another cooldown
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(breaker.state(), BreakerState::HalfOpen);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Open { until: at(120) });
+        clock.advance(Duration::from_secs(60));
+        assert!(breaker.allow());
+        breaker.record_success(&edit);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        assert!(breaker.paused_at().is_none());
+        // The window starts over
+        breaker.record_failure();
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
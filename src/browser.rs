@@ -0,0 +1,163 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Reading EventStreams from browsers
+//!
+//! Built for `wasm32-unknown-unknown` with the `wasm` feature, e.g. for
+//! dashboards written with Yew or Leptos, typed events, filters and
+//! [`EventStreamBuilder`](crate::EventStreamBuilder) work as usual, but
+//! connections go through the browser's own
+//! [`EventSource`](https://developer.mozilla.org/en-US/docs/Web/API/EventSource)
+//! instead of an HTTP client. The browser makes most of the decisions
+//! about requests itself, so:
+//!
+//! * the `User-Agent`, extra headers, proxy, connect timeout and
+//!   certificates in [`ClientOptions`](crate::backend::ClientOptions) are
+//!   ignored, and [diagnostics](crate::backend::ClientOptions::diagnostics)
+//!   aren't available
+//! * `Last-Event-ID` is only sent on the browser's own reconnects, so
+//!   catching up after a reload needs
+//!   [`since()`](crate::EventStreamBuilder::since)
+//! * only the first of several
+//!   [endpoints](crate::EventStreamBuilder::endpoints) is used
+//!
+//! Browsers have no threads, so nothing that spawns one works, e.g.
+//! [`into_channel()`](crate::EventStreamBuilder::into_channel) or a
+//! [`Queue`](crate::queue::Queue). `enrichment` and `matrix` make HTTP
+//! requests of their own, and `columnar` needs a random number generator
+//! that isn't set up for browsers, so these need a native target; depend
+//! on the crate with `default-features = false` and only the features
+//! that are needed.
+use crate::backend::BackendError;
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::{Stream, StreamExt};
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use url::Url;
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::MessageEvent;
+
+/// What the browser reported
+enum Signal {
+    Open,
+    Message(String),
+    /// The connection was lost, and the browser is reconnecting
+    Error,
+}
+
+/// Messages from a browser `EventSource`, which reconnects on its own
+/// after the connection is lost, reporting that as
+/// [`BackendError::Disconnected`]. The stream ends once the browser gives
+/// up, e.g. after an HTTP error. Dropping it closes the connection.
+pub struct EventSource {
+    source: web_sys::EventSource,
+    signals: UnboundedReceiver<Signal>,
+    _on_open: Closure<dyn FnMut(web_sys::Event)>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_error: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl EventSource {
+    /// Start connecting to `url`
+    pub fn new(url: &Url) -> Result<Self, BackendError> {
+        let source = web_sys::EventSource::new(url.as_str())
+            .map_err(|err| BackendError::Connection(describe(&err)))?;
+        let (sender, signals) = mpsc::unbounded();
+        let opened = sender.clone();
+        let on_open = Closure::<dyn FnMut(_)>::new(move |_| {
+            let _ = opened.unbounded_send(Signal::Open);
+        });
+        let messages = sender.clone();
+        let on_message =
+            Closure::<dyn FnMut(_)>::new(move |event: MessageEvent| {
+                if let Some(data) = event.data().as_string() {
+                    let _ = messages.unbounded_send(Signal::Message(data));
+                }
+            });
+        let errored = source.clone();
+        let on_error = Closure::<dyn FnMut(_)>::new(move |_| {
+            if errored.ready_state() == web_sys::EventSource::CLOSED {
+                sender.close_channel();
+            } else {
+                let _ = sender.unbounded_send(Signal::Error);
+            }
+        });
+        source.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+        source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+        source.set_onerror(Some(on_error.as_ref().unchecked_ref()));
+        Ok(Self {
+            source,
+            signals,
+            _on_open: on_open,
+            _on_message: on_message,
+            _on_error: on_error,
+        })
+    }
+
+    /// Whether the connection is currently open
+    pub fn is_open(&self) -> bool {
+        self.source.ready_state() == web_sys::EventSource::OPEN
+    }
+}
+
+impl Stream for EventSource {
+    type Item = Result<String, BackendError>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            match self.signals.poll_next_unpin(cx) {
+                // Opening has no item of its own, but wakes the task so
+                // that callers can check is_open()
+                Poll::Ready(Some(Signal::Open)) => {}
+                Poll::Ready(Some(Signal::Message(data))) => {
+                    return Poll::Ready(Some(Ok(data)));
+                }
+                Poll::Ready(Some(Signal::Error)) => {
+                    return Poll::Ready(Some(Err(BackendError::Disconnected)));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for EventSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventSource")
+            .field("url", &self.source.url())
+            .field("ready_state", &self.source.ready_state())
+            .finish()
+    }
+}
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        self.source.set_onopen(None);
+        self.source.set_onmessage(None);
+        self.source.set_onerror(None);
+        self.source.close();
+    }
+}
+
+fn describe(err: &JsValue) -> String {
+    err.as_string().unwrap_or_else(|| format!("{:?}", err))
+}
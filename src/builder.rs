@@ -0,0 +1,1141 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::alert::{self, Alerts};
+use crate::backend::{BackendError, Backoff, ClientOptions, Endpoints};
+use crate::chaos::Chaos;
+use crate::clock::{Clock, SystemClock};
+use crate::compaction;
+use crate::drift::DriftDetector;
+use crate::drops::DropLogger;
+use crate::envelope::{Envelope, Enveloper};
+use crate::etiquette::Guardrails;
+use crate::listener::Listeners;
+use crate::queue::Queue;
+use crate::resume::ResumeToken;
+use crate::schema::{DeserializeMode, Deserializer, SchemaMismatch};
+use crate::side_output::{Excluded, SideOutput};
+#[cfg(feature = "sinks")]
+use crate::subscription::Subscription;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::Transport;
+use crate::watermark::Watermark;
+use crate::worker::{self, StreamWorker};
+use crate::{
+    backend, BuildError, Event, EventStream, EventStreamError, Parser, Read,
+    SinceError,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::future::{self, FutureExt};
+use futures::{Stream, StreamExt};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+pub(crate) const BASE_URL: &str = "https://stream.wikimedia.org/v2/stream/";
+
+/// How far back EventStreams keeps events, and so how far back
+/// [`since`](EventStreamBuilder::since) can go
+pub const RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Streams available from EventStreams, see
+/// <https://stream.wikimedia.org/?doc>
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StreamKind {
+    /// Edits, page creations, log entries and categorization
+    RecentChange,
+    RevisionCreate,
+    PageCreate,
+    PageDelete,
+    PageMove,
+    PageUndelete,
+    PageLinksChange,
+    /// ORES scores for new revisions
+    RevisionScore,
+    /// Test events produced regularly by EventGate, see
+    /// [`readiness`](crate::readiness)
+    Test,
+}
+
+impl StreamKind {
+    /// Name used in the URL, e.g. `revision-create`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::RecentChange => "recentchange",
+            Self::RevisionCreate => "revision-create",
+            Self::PageCreate => "page-create",
+            Self::PageDelete => "page-delete",
+            Self::PageMove => "page-move",
+            Self::PageUndelete => "page-undelete",
+            Self::PageLinksChange => "page-links-change",
+            Self::RevisionScore => "revision-score",
+            Self::Test => "test",
+        }
+    }
+
+    /// Name in each event's `meta.stream`, e.g. `mediawiki.revision-create`
+    pub fn stream(&self) -> &'static str {
+        match self {
+            Self::RecentChange => "mediawiki.recentchange",
+            Self::RevisionCreate => "mediawiki.revision-create",
+            Self::PageCreate => "mediawiki.page-create",
+            Self::PageDelete => "mediawiki.page-delete",
+            Self::PageMove => "mediawiki.page-move",
+            Self::PageUndelete => "mediawiki.page-undelete",
+            Self::PageLinksChange => "mediawiki.page-links-change",
+            Self::RevisionScore => "mediawiki.revision-score",
+            Self::Test => "eventgate-main.test.event",
+        }
+    }
+
+    pub(crate) fn from_stream(stream: &str) -> Option<Self> {
+        [
+            Self::RecentChange,
+            Self::RevisionCreate,
+            Self::PageCreate,
+            Self::PageDelete,
+            Self::PageMove,
+            Self::PageUndelete,
+            Self::PageLinksChange,
+            Self::RevisionScore,
+            Self::Test,
+        ]
+        .iter()
+        .copied()
+        .find(|kind| kind.stream() == stream)
+    }
+}
+
+/// What [`into_channel()`](EventStreamBuilder::into_channel) does with
+/// events once the channel is full
+#[derive(Clone, Debug)]
+pub enum Overflow {
+    /// Stop reading until there's room again. Nothing is lost, but if the
+    /// consumer falls behind for long, EventStreams may close the
+    /// connection, in which case it's resumed after the last event read.
+    Block,
+    /// Drop events until there's room again, recording them with the
+    /// [`DropLogger`]
+    Drop(DropLogger),
+}
+
+/// Configures a connection to EventStreams
+#[derive(Clone, Debug)]
+pub struct EventStreamBuilder {
+    url: String,
+    endpoints: Vec<String>,
+    reprobe_after: Option<u32>,
+    since: Option<DateTime<Utc>>,
+    last_event_id: Option<String>,
+    until: Option<DateTime<Utc>>,
+    allowed_lateness: Duration,
+    max_age: Option<Duration>,
+    side_output: Option<SideOutput>,
+    drift: Option<DriftDetector>,
+    deserializer: Deserializer,
+    keep_canaries: bool,
+    alerts: Alerts,
+    queue: Option<Queue>,
+    bound: Option<usize>,
+    compaction_interval: Option<Duration>,
+    chaos: Option<Chaos>,
+    backoff: Backoff,
+    options: ClientOptions,
+    clock: Arc<dyn Clock>,
+}
+
+impl EventStreamBuilder {
+    pub fn new() -> Self {
+        Self {
+            url: format!("{}{}", BASE_URL, StreamKind::RecentChange.name()),
+            endpoints: vec![],
+            reprobe_after: None,
+            since: None,
+            last_event_id: None,
+            until: None,
+            allowed_lateness: Duration::from_secs(60),
+            max_age: None,
+            side_output: None,
+            drift: None,
+            deserializer: Deserializer::default(),
+            keep_canaries: false,
+            alerts: Alerts::default(),
+            queue: None,
+            bound: None,
+            compaction_interval: Some(compaction::DEFAULT_INTERVAL),
+            chaos: None,
+            backoff: Backoff::default(),
+            options: ClientOptions::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Read from `streams` instead of just recent changes. EventStreams
+    /// interleaves them into one connection, and they can be told apart
+    /// again with [`Event::stream_kind()`] or
+    /// [`Listeners::on_stream()`](crate::listener::Listeners::on_stream).
+    pub fn streams(self, streams: &[StreamKind]) -> Self {
+        self.stream_names(streams.iter().map(StreamKind::name))
+    }
+
+    /// Like [`streams()`](Self::streams), but by the name used in the URL,
+    /// e.g. `revision-tags-change`, for streams registered as
+    /// [extensions](crate::extension)
+    pub fn stream_names<I, S>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let names: Vec<_> = names
+            .into_iter()
+            .map(|name| name.as_ref().to_string())
+            .collect();
+        self.url = format!("{}{}", BASE_URL, names.join(","));
+        self
+    }
+
+    /// Connect to `url` instead of Wikimedia's EventStreams, e.g. a mirror
+    /// or test server. This replaces any [`streams()`](Self::streams), so
+    /// the URL should include them.
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// Connect to whichever of `urls` responds fastest, e.g. the same
+    /// streams from servers in different regions. Like with
+    /// [`url()`](Self::url), each should include the streams. See
+    /// [`Endpoints`] for how they are chosen.
+    pub fn endpoints<I, S>(mut self, urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.endpoints = urls.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Choose between the [`endpoints()`](Self::endpoints) again after
+    /// this many failed reconnects in a row
+    pub fn reprobe_after(mut self, failures: u32) -> Self {
+        self.reprobe_after = Some(failures);
+        self
+    }
+
+    /// Start with events from this time onwards, rather than from now.
+    /// If it's further back than the [`RETENTION`] window, EventStreams
+    /// starts with the oldest event it still has.
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Like [`since()`](Self::since), but fails if events from `since`
+    /// are no longer available, or it's in the future
+    pub fn checked_since(
+        self,
+        since: DateTime<Utc>,
+    ) -> Result<Self, SinceError> {
+        let now = self.clock.now();
+        let oldest = now
+            - chrono::Duration::from_std(RETENTION)
+                .expect("retention out of range");
+        if since < oldest {
+            Err(SinceError::TooOld { since, oldest })
+        } else if since > now {
+            Err(SinceError::Future(since))
+        } else {
+            Ok(self.since(since))
+        }
+    }
+
+    /// Continue just after the position in `token`, e.g. as saved from
+    /// [`EventStream::resume_token()`] before a restart. This takes
+    /// precedence over [`since()`](Self::since) for the topics and
+    /// partitions in the token.
+    pub fn resume_from(self, token: &ResumeToken) -> Self {
+        if token.is_empty() {
+            return self;
+        }
+        self.last_event_id(token.to_last_event_id())
+    }
+
+    /// Continue after a raw `Last-Event-ID`, as sent by EventStreams
+    pub fn last_event_id(mut self, id: impl Into<String>) -> Self {
+        self.last_event_id = Some(id.into());
+        self
+    }
+
+    /// Skip events from after this time, and end the stream once the
+    /// watermark passes it, i.e. once an event from more than
+    /// [`allowed_lateness()`](Self::allowed_lateness) after it arrives
+    pub fn until(mut self, until: DateTime<Utc>) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// How long to keep reading past [`until()`](Self::until) for events
+    /// that arrive out of order (default 1 minute)
+    pub fn allowed_lateness(mut self, allowed_lateness: Duration) -> Self {
+        self.allowed_lateness = allowed_lateness;
+        self
+    }
+
+    /// Replay events from `start` to `end`, then end the stream
+    pub fn between(self, start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        self.since(start).until(end)
+    }
+
+    /// Drop events that happened more than `max_age` ago, e.g. while
+    /// catching up after a long reconnect, for consumers that would rather
+    /// miss an event than act on it late. Dropped events go to the
+    /// [`side_output()`](Self::side_output), if there is one.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Send events that can't be parsed to `side_output`
+    pub fn side_output(mut self, side_output: SideOutput) -> Self {
+        self.side_output = Some(side_output);
+        self
+    }
+
+    /// Report fields appearing in or vanishing from messages with
+    /// `detector`, as [`EventStreamError::SchemaDrift`] errors
+    pub fn detect_schema_drift(mut self, detector: DriftDetector) -> Self {
+        self.drift = Some(detector);
+        self
+    }
+
+    /// How to handle messages that don't fit their event type, e.g. after
+    /// a schema change upstream (default [`DeserializeMode::Strict`])
+    pub fn deserialize_mode(mut self, mode: DeserializeMode) -> Self {
+        self.deserializer.mode = mode;
+        self
+    }
+
+    /// Call `hook` for each message that doesn't fit its event type, with
+    /// which fields didn't and whether the event was still delivered
+    pub fn on_schema_mismatch(
+        mut self,
+        hook: impl Fn(&SchemaMismatch) + Send + Sync + 'static,
+    ) -> Self {
+        self.deserializer.hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// After `failures` messages in a row of one stream and `type` fail to
+    /// parse, deliver them as [`Event::Unknown`] instead of dropping them
+    /// (default 20), see [`schema`](crate::schema). `None` keeps treating
+    /// them as malformed.
+    pub fn degrade_after(mut self, failures: Option<u32>) -> Self {
+        self.deserializer.degrade_after = failures;
+        self
+    }
+
+    /// Deliver canary events like any other, rather than only to
+    /// [`on_canary()`](crate::listener::Listeners::on_canary) listeners.
+    /// Canaries are injected by Wikimedia to check that streams work, see
+    /// [`Event::is_canary()`].
+    pub fn keep_canaries(mut self) -> Self {
+        self.keep_canaries = true;
+        self
+    }
+
+    /// Keep the message each event was parsed from, for
+    /// [`Event::raw()`]. This roughly doubles the memory each event takes.
+    pub fn keep_raw(mut self) -> Self {
+        self.deserializer.keep_raw = true;
+        self
+    }
+
+    /// Call `handler` for each event matching `predicate` as soon as it's
+    /// read from the connection, ahead of the [`queue()`](Self::queue),
+    /// [`chaos()`](Self::chaos) and anything else between the connection
+    /// and the stream, e.g. for vandalism alerts where latency matters more
+    /// than order. Handlers run on the thread reading the connection, the
+    /// queue's if there is one, so they should return quickly. Canaries
+    /// are skipped unless [kept](Self::keep_canaries).
+    pub fn alert(
+        self,
+        predicate: impl Fn(&Event) -> bool + Send + Sync + 'static,
+        handler: impl Fn(&Event) + Send + Sync + 'static,
+    ) -> Self {
+        self.alert_hook(Arc::new(move |event| {
+            if predicate(event) {
+                handler(event);
+            }
+            future::ready(()).boxed()
+        }))
+    }
+
+    /// Deliver to `subscription` straight from the reader, like
+    /// [`alert()`](Self::alert). Reading waits for the sink to take each
+    /// matching event.
+    #[cfg(feature = "sinks")]
+    pub fn alert_subscription(self, subscription: Subscription) -> Self {
+        let subscription = Arc::new(futures::lock::Mutex::new(subscription));
+        self.alert_hook(Arc::new(move |event| {
+            let subscription = subscription.clone();
+            let event = event.clone();
+            async move {
+                let mut subscription = subscription.lock().await;
+                if let Err(err) = subscription.deliver(&event).await {
+                    log::warn!(
+                        target: "eventstreams::alert",
+                        "alert subscription {} failed: {}",
+                        subscription.name(),
+                        err
+                    );
+                }
+            }
+            .boxed()
+        }))
+    }
+
+    pub(crate) fn alert_hook(mut self, hook: alert::Hook) -> Self {
+        self.alerts.push(hook);
+        self
+    }
+
+    /// Read messages into `queue` from a background thread, so slow
+    /// listeners don't hold up reading from the connection
+    pub fn queue(mut self, queue: Queue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Cap every buffer of this stream at `limit` items (at least 1), like
+    /// [bounded mode](crate::metrics::set_bounded) does for all of them:
+    /// the [`queue()`](Self::queue), the channels of
+    /// [`into_channel()`](Self::into_channel) and [`spawn()`](Self::spawn),
+    /// and [`EventStream::errors()`] streams. Bounded mode still applies
+    /// if its cap is lower.
+    pub fn bounded(mut self, limit: usize) -> Self {
+        self.bound = Some(limit.max(1));
+        self
+    }
+
+    /// `capacity`, lowered to the cap set with [`bounded()`](Self::bounded)
+    /// or by bounded mode
+    fn capped(&self, capacity: usize) -> usize {
+        crate::metrics::cap_limit(self.bound)
+            .map_or(capacity, |limit| capacity.min(limit))
+    }
+
+    /// How often to shrink buffers that grew during a burst, such as the
+    /// [`queue()`](Self::queue), and drop bookkeeping for listeners and
+    /// error streams that are gone (default every 10 minutes). This keeps
+    /// memory use of processes running for weeks from creeping up.
+    /// `None` turns it off.
+    pub fn compaction_interval(mut self, interval: Option<Duration>) -> Self {
+        self.compaction_interval = interval;
+        self
+    }
+
+    /// Inject random failures with `chaos`, to rehearse recovering from
+    /// them against live data
+    pub fn chaos(mut self, chaos: Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
+    }
+
+    /// How long to wait before reconnecting after the connection is lost
+    /// (default [`Backoff::default()`])
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Measure time with `clock` rather than the system time, e.g. a
+    /// [`ManualClock`](crate::clock::ManualClock) to test without waiting.
+    /// Every wait and timestamp taken by the stream goes through it.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.options = self.options.clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Identify as `user_agent`, which should include contact details as
+    /// required by Wikimedia's
+    /// [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.options = self.options.user_agent(user_agent);
+        self
+    }
+
+    /// Send an extra header when connecting
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.options = self.options.header(name, value);
+        self
+    }
+
+    /// Connect through the HTTP proxy at `proxy`, e.g.
+    /// `http://localhost:3128`
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.options = self.options.proxy(proxy);
+        self
+    }
+
+    /// Give up on connecting after `timeout`, and try again
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options = self.options.connect_timeout(timeout);
+        self
+    }
+
+    /// Also trust the root certificates in the PEM file at `path`
+    pub fn ca_certificate(mut self, path: impl Into<PathBuf>) -> Self {
+        self.options = self.options.ca_certificate(path);
+        self
+    }
+
+    /// Connect through `transport`, see [`transport`](crate::transport)
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn transport(mut self, transport: Arc<dyn Transport>) -> Self {
+        self.options = self.options.transport(transport);
+        self
+    }
+
+    /// Check connections against Wikimedia's etiquette with `guardrails`,
+    /// e.g. to refuse rather than warn, see [`etiquette`](crate::etiquette)
+    pub fn guardrails(mut self, guardrails: Guardrails) -> Self {
+        self.options = self.options.guardrails(guardrails);
+        self
+    }
+
+    /// Reconnect if nothing is received for `timeout`
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.options = self.options.read_timeout(timeout);
+        self
+    }
+
+    /// Report response headers and keepalive comments to the stream's
+    /// [listeners](crate::listener::Listeners::on_diagnostic), e.g. to
+    /// debug disconnects
+    pub fn diagnostics(mut self) -> Self {
+        self.options = self.options.diagnostics();
+        self
+    }
+
+    /// The URLs to connect to, each with the starting time
+    fn request_urls(&self) -> Result<Vec<Url>, BuildError> {
+        let urls = if self.endpoints.is_empty() {
+            std::slice::from_ref(&self.url)
+        } else {
+            &self.endpoints
+        };
+        urls.iter()
+            .map(|url| {
+                let mut parsed: Url =
+                    url.parse().map_err(|err: url::ParseError| {
+                        BuildError::InvalidUrl {
+                            url: url.clone(),
+                            reason: err.to_string(),
+                        }
+                    })?;
+                if let Some(since) = self.since {
+                    parsed.query_pairs_mut().append_pair(
+                        "since",
+                        &since.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    );
+                }
+                Ok(parsed)
+            })
+            .collect()
+    }
+
+    /// Check that a stream can be built from this configuration, e.g.
+    /// that the URLs are valid and a client can be created with the proxy
+    /// and certificates
+    pub(crate) fn validate(&self) -> Result<(), BuildError> {
+        self.request_urls()?;
+        #[cfg(not(target_arch = "wasm32"))]
+        self.options
+            .transport_or_default()
+            .http_client(&self.options)?;
+        Ok(())
+    }
+
+    /// Connect and start streaming events. Connection changes are passed
+    /// to the stream's [listeners](EventStream::listeners). Fails if the
+    /// configuration is invalid, e.g. the proxy URL can't be parsed.
+    pub fn build(self) -> Result<EventStream, BuildError> {
+        self.validate()?;
+        Ok(self.into_stream())
+    }
+
+    /// Like [`build()`](Self::build), without validating first. Invalid
+    /// configuration shows up as a [`BackendError`] that ends the stream
+    /// instead.
+    pub(crate) fn into_stream(self) -> EventStream {
+        let listeners = Listeners::new();
+        let keep_canaries = self.keep_canaries;
+        let compaction_interval = self.compaction_interval;
+        let endpoints = if self.endpoints.is_empty() {
+            vec![self.url.clone()]
+        } else {
+            self.endpoints.clone()
+        };
+        let bound = crate::metrics::cap_limit(self.bound);
+        let queue = self.queue.clone().map(|queue| queue.cap(bound).stats());
+        let clock = self.clock.clone();
+        let inner = self.connect(listeners.clone()).boxed_local();
+        EventStream::with_listeners(inner, listeners)
+            .bounded(bound)
+            .clock(clock)
+            .keep_canaries(keep_canaries)
+            .compaction_interval(compaction_interval)
+            .origin(endpoints, queue)
+    }
+
+    /// Connect from a background thread, which sends events to the
+    /// returned channel, e.g. for consumers that are built around threads
+    /// rather than async. The channel holds at most `capacity` events
+    /// that haven't been received yet, see [`Overflow`] for what happens
+    /// beyond that. The thread stops once the receiver is dropped.
+    ///
+    /// This is on the builder rather than [`EventStream`], since the
+    /// connection can't be moved to another thread once it's made. The
+    /// configuration is validated before the thread is started.
+    pub fn into_channel(
+        self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<mpsc::Receiver<Event>, BuildError> {
+        self.validate()?;
+        let (sender, receiver) = mpsc::sync_channel(self.capped(capacity));
+        thread::spawn(move || {
+            worker::forward(
+                self.into_stream(),
+                sender,
+                overflow,
+                future::pending::<()>(),
+            )
+        });
+        Ok(receiver)
+    }
+
+    /// Like [`into_channel()`](Self::into_channel), but the thread can be
+    /// stopped without waiting for another event, and shut down
+    /// gracefully
+    pub fn spawn(
+        self,
+        capacity: usize,
+        overflow: Overflow,
+    ) -> Result<StreamWorker, BuildError> {
+        self.validate()?;
+        let clock = self.clock.clone();
+        let capacity = self.capped(capacity);
+        Ok(StreamWorker::spawn(
+            move || self.into_stream(),
+            capacity,
+            overflow,
+            clock,
+        ))
+    }
+
+    /// Like [`build()`](Self::build), but with errors inline, in the order
+    /// they happened relative to events. Canaries are dropped unless
+    /// [kept](Self::keep_canaries).
+    pub fn build_with_errors(
+        self,
+    ) -> Result<impl Stream<Item = Result<Event, EventStreamError>>, BuildError>
+    {
+        self.validate()?;
+        Ok(self.into_stream_with_errors())
+    }
+
+    pub(crate) fn into_stream_with_errors(
+        self,
+    ) -> impl Stream<Item = Result<Event, EventStreamError>> {
+        let keep_canaries = self.keep_canaries;
+        self.connect(Listeners::new()).filter(move |result| {
+            future::ready(match result {
+                Ok(event) => keep_canaries || !event.is_canary(),
+                Err(_) => true,
+            })
+        })
+    }
+
+    /// Like [`build()`](Self::build), but each event is wrapped in an
+    /// [`Envelope`] with details about where it came from. Canaries are
+    /// dropped unless [kept](Self::keep_canaries).
+    pub fn build_with_envelopes(
+        self,
+    ) -> Result<impl Stream<Item = Envelope>, BuildError> {
+        self.validate()?;
+        Ok(self.into_stream_with_envelopes())
+    }
+
+    pub(crate) fn into_stream_with_envelopes(
+        self,
+    ) -> impl Stream<Item = Envelope> {
+        Enveloper::new("live")
+            .clock(self.clock.clone())
+            .keep_canaries(self.keep_canaries)
+            .wrap_events(self.connect(Listeners::new()))
+    }
+
+    fn connect(
+        self,
+        listeners: Listeners,
+    ) -> impl Stream<Item = Result<Event, EventStreamError>> {
+        let until = self.until;
+        let mut watermark = Watermark::new(self.allowed_lateness);
+        let max_age = self.max_age.map(|max_age| {
+            chrono::Duration::from_std(max_age).unwrap_or(chrono::Duration::MAX)
+        });
+        let side_output = self.side_output.clone();
+        let clock = self.clock.clone();
+        // Checked by build(), but into_stream() reports invalid URLs as an
+        // error that ends the stream
+        let reprobe_after = self.reprobe_after;
+        let endpoints = self
+            .request_urls()
+            .map(|urls| match reprobe_after {
+                Some(failures) => Endpoints::new(urls).reprobe_after(failures),
+                None => Endpoints::new(urls),
+            })
+            .map_err(|err| BackendError::Connection(err.to_string()));
+        let last_event_id = self.last_event_id.clone();
+        let backoff = self.backoff.clone();
+        let options = self.options.clone();
+        let alerts = self.alerts.clone();
+        let keep_canaries = self.keep_canaries;
+        // With alerts, messages are parsed as they're read, so the stream
+        // gets the same events without parsing them again
+        let (mut parser, drift) = if alerts.is_empty() {
+            (None, self.drift)
+        } else {
+            let parser = Parser::new(self.deserializer.clone(), self.drift);
+            (Some(parser), None)
+        };
+        let connect = move || {
+            let endpoints = match endpoints {
+                Ok(endpoints) => endpoints,
+                Err(err) => {
+                    return futures::stream::iter([Err(err)]).left_stream()
+                }
+            };
+            backend::reconnecting_to(
+                endpoints,
+                last_event_id,
+                backoff,
+                options,
+                move |event| listeners.dispatch_connection(event),
+            )
+            .then(move |message| {
+                let alerts = alerts.clone();
+                let message = message.map(|data| match &mut parser {
+                    Some(parser) => {
+                        let parsed = parser.parse(&data);
+                        Read::parsed(data, parsed)
+                    }
+                    None => Read::from(data),
+                });
+                async move {
+                    if let Some(event) =
+                        message.as_ref().ok().and_then(Read::event)
+                    {
+                        alerts.check(event, keep_canaries).await;
+                    }
+                    message
+                }
+            })
+            .right_stream()
+        };
+        let bound = crate::metrics::cap_limit(self.bound);
+        let backend = match self.queue {
+            Some(queue) => queue
+                .cap(bound)
+                .compaction_interval(self.compaction_interval, self.clock)
+                .start(connect)
+                .boxed_local(),
+            None => connect().boxed_local(),
+        };
+        let backend = match self.chaos {
+            Some(chaos) => chaos.wrap(backend).boxed_local(),
+            None => backend,
+        };
+        crate::parse_with_errors(
+            backend,
+            self.side_output,
+            drift,
+            self.deserializer,
+        )
+        .take_while(move |result| {
+            if let Ok(event) = result {
+                watermark.observe(event);
+            }
+            futures::future::ready(match (until, watermark.current()) {
+                (Some(until), Some(watermark)) => watermark <= until,
+                _ => true,
+            })
+        })
+        .filter(move |result| {
+            futures::future::ready(match result {
+                Ok(event) => until.is_none_or(|until| event.dt() <= until),
+                Err(_) => true,
+            })
+        })
+        .filter(move |result| {
+            let stale = match (result, max_age) {
+                (Ok(event), Some(max_age)) => event
+                    .dt()
+                    .checked_add_signed(max_age)
+                    .is_some_and(|deadline| deadline < clock.now()),
+                _ => false,
+            };
+            if let (true, Some(side_output), Ok(event)) =
+                (stale, &side_output, result)
+            {
+                side_output.send(Excluded::Filtered {
+                    event: event.clone(),
+                    reason: "stale".to_string(),
+                });
+            }
+            futures::future::ready(!stale)
+        })
+    }
+}
+
+impl Default for EventStreamBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at, MockHandle, MockServer};
+    use futures::executor::block_on;
+    use std::sync::Mutex;
+
+    fn serve(titles: &[&str]) -> MockHandle {
+        let messages = titles.iter().enumerate().map(|(offset, title)| {
+            testing::edit(offset as u64, title, at(0)).to_string()
+        });
+        MockServer::new(messages).start().unwrap()
+    }
+
+    fn titles(events: Vec<Event>) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| event.title().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn alerts_straight_from_the_reader() {
+        let server = serve(&["A", "B", "C"]);
+        let alerted = Arc::new(Mutex::new(vec![]));
+        let stream = {
+            let alerted = alerted.clone();
+            EventStreamBuilder::new()
+                .url(server.url())
+                .alert(
+                    |event| event.title() != "B",
+                    move |event| {
+                        alerted.lock().unwrap().push(event.title().to_string())
+                    },
+                )
+                .build()
+                .unwrap()
+        };
+        assert_eq!(titles(block_on(stream.take(3).collect())), ["A", "B", "C"]);
+        assert_eq!(*alerted.lock().unwrap(), ["A", "C"]);
+    }
+
+    #[test]
+    fn alerts_and_streams_the_same_parse() {
+        let messages = vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(1, "B", at(0)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let alerted = Arc::new(Mutex::new(vec![]));
+        let stream = {
+            let alerted = alerted.clone();
+            EventStreamBuilder::new()
+                .url(server.url())
+                .keep_raw()
+                .alert(
+                    |_| true,
+                    move |event| alerted.lock().unwrap().push(event.clone()),
+                )
+                .build_with_errors()
+                .unwrap()
+        };
+        let results: Vec<_> = block_on(stream.take(3).collect());
+        assert!(matches!(
+            results[1],
+            Err(EventStreamError::Malformed { .. })
+        ));
+        let streamed: Vec<_> =
+            results.into_iter().filter_map(Result::ok).collect();
+        let alerted = alerted.lock().unwrap();
+        assert_eq!(titles(alerted.clone()), ["A", "B"]);
+        for (alerted, streamed) in alerted.iter().zip(&streamed) {
+            // Parsed with the builder's settings, for both
+            assert!(alerted.raw().is_some());
+            assert_eq!(alerted.raw(), streamed.raw());
+        }
+        assert_eq!(streamed.len(), 2);
+    }
+
+    #[test]
+    fn replays_a_time_range() {
+        let messages = [0, 10, 20, 30].iter().map(|seconds| {
+            testing::edit(*seconds as u64, "A", at(*seconds)).to_string()
+        });
+        let server = MockServer::new(messages).start().unwrap();
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .between(at(0), at(15))
+            .allowed_lateness(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let events: Vec<_> = block_on(stream.collect());
+        assert_eq!(
+            events.iter().map(|event| event.dt()).collect::<Vec<_>>(),
+            [at(0), at(10)]
+        );
+        assert!(server.requests()[0]
+            .path
+            .ends_with("?since=2021-01-01T00%3A00%3A00Z"));
+    }
+
+    #[test]
+    fn waits_for_out_of_order_events_past_the_end() {
+        let messages = [0, 20, 10, 14, 16, 30, 12].iter().map(|seconds| {
+            testing::edit(*seconds as u64, "A", at(*seconds)).to_string()
+        });
+        let server = MockServer::new(messages).start().unwrap();
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .between(at(0), at(15))
+            .allowed_lateness(Duration::from_secs(10))
+            .build()
+            .unwrap();
+        let events: Vec<_> = block_on(stream.collect());
+        // 20 and 16 are past the end, and 30 ends the stream
+        assert_eq!(
+            events.iter().map(|event| event.dt()).collect::<Vec<_>>(),
+            [at(0), at(10), at(14)]
+        );
+    }
+
+    #[test]
+    fn yields_errors_inline() {
+        let messages = vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(1, "B", at(0)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .build_with_errors()
+            .unwrap();
+        let results: Vec<_> = block_on(stream.take(3).collect());
+        assert_eq!(results[0].as_ref().unwrap().title(), "A");
+        assert!(matches!(
+            results[1],
+            Err(EventStreamError::Malformed { .. })
+        ));
+        assert_eq!(results[2].as_ref().unwrap().title(), "B");
+    }
+
+    #[test]
+    fn reads_other_streams() {
+        let urls = EventStreamBuilder::new()
+            .streams(&[StreamKind::RevisionCreate, StreamKind::PageDelete])
+            .request_urls()
+            .unwrap();
+        assert_eq!(
+            urls[0].as_str(),
+            "https://stream.wikimedia.org/v2/stream/revision-create,page-delete"
+        );
+
+        let mut meta = testing::edit(1, "A", at(0))["meta"].clone();
+        meta["stream"] = "mediawiki.page-delete".into();
+        let delete = testing::event(&serde_json::json!({
+            "$schema": "/mediawiki/page/delete/1.0.0",
+            "meta": meta,
+            "database": "enwiki",
+            "page_id": 12,
+            "page_title": "A",
+            "page_namespace": 0,
+            "rev_count": 3,
+            "performer": { "user_text": "Alice" },
+        }));
+        assert_eq!(delete.stream_kind(), Some(StreamKind::PageDelete));
+        match delete {
+            Event::PageDelete(delete) => assert_eq!(delete.rev_count, Some(3)),
+            event => panic!("parsed as {:?}", event),
+        }
+        let edit = testing::edit_event(2, "A", at(0));
+        assert_eq!(edit.stream_kind(), Some(StreamKind::RecentChange));
+    }
+
+    #[test]
+    fn resumes_after_the_last_event() {
+        let server = serve(&["A", "B", "C"]);
+        let mut stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let first = block_on((&mut stream).take(1).collect());
+        assert_eq!(titles(first), ["A"]);
+        let token = stream.resume_token();
+        drop(stream);
+
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .resume_from(&token)
+            .build()
+            .unwrap();
+        assert_eq!(titles(block_on(stream.take(2).collect())), ["B", "C"]);
+        let requests = server.requests();
+        assert!(!requests[0].headers.contains_key("last-event-id"));
+        assert_eq!(
+            requests[1].headers["last-event-id"],
+            token.to_last_event_id()
+        );
+    }
+
+    #[test]
+    fn sends_configured_headers() {
+        let server = serve(&["A"]);
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .user_agent("test-bot (test@example.org)")
+            .header("X-Test", "1")
+            .build()
+            .unwrap();
+        assert_eq!(titles(block_on(stream.take(1).collect())), ["A"]);
+        let requests = server.requests();
+        assert_eq!(
+            requests[0].headers["user-agent"],
+            "test-bot (test@example.org)"
+        );
+        assert_eq!(requests[0].headers["x-test"], "1");
+    }
+
+    #[test]
+    fn only_starts_from_retained_events() {
+        let week = chrono::Duration::from_std(RETENTION).unwrap();
+        let now = at(0) + week;
+        let builder = || {
+            EventStreamBuilder::new()
+                .clock(Arc::new(crate::clock::ManualClock::new(now)))
+        };
+        assert_eq!(builder().checked_since(at(0)).unwrap().since, Some(at(0)));
+        assert_eq!(
+            builder().checked_since(at(-1)).err(),
+            Some(SinceError::TooOld {
+                since: at(-1),
+                oldest: at(0)
+            })
+        );
+        let later = now + chrono::Duration::seconds(1);
+        assert_eq!(
+            builder().checked_since(later).err(),
+            Some(SinceError::Future(later))
+        );
+    }
+
+    #[test]
+    fn drops_stale_events() {
+        let messages = ["A", "B", "C"].iter().enumerate().map(|(i, title)| {
+            testing::edit(i as u64, title, at(i as i64 * 100)).to_string()
+        });
+        let server = MockServer::new(messages).start().unwrap();
+        let (side_output, excluded) = SideOutput::new("stale");
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .clock(Arc::new(crate::clock::ManualClock::new(at(200))))
+            .max_age(Duration::from_secs(60))
+            .side_output(side_output)
+            .build()
+            .unwrap();
+        assert_eq!(titles(block_on(stream.take(1).collect())), ["C"]);
+        let stale: Vec<_> = block_on(excluded.take(2).collect());
+        let stale: Vec<_> = stale
+            .iter()
+            .map(|excluded| match excluded {
+                Excluded::Filtered { event, reason } => {
+                    format!("{} ({})", event.title(), reason)
+                }
+                Excluded::Malformed { .. } => panic!("not stale"),
+            })
+            .collect();
+        assert_eq!(stale, ["A (stale)", "B (stale)"]);
+    }
+
+    #[test]
+    fn sends_events_to_a_channel() {
+        let server = serve(&["A", "B"]);
+        let receiver = EventStreamBuilder::new()
+            .url(server.url())
+            .into_channel(1, Overflow::Block)
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        let titles: Vec<_> = (0..2)
+            .map(|_| receiver.recv_timeout(timeout).unwrap())
+            .map(|event| event.title().to_string())
+            .collect();
+        assert_eq!(titles, ["A", "B"]);
+        let invalid = EventStreamBuilder::new()
+            .url("not a url")
+            .into_channel(1, Overflow::Block);
+        assert!(matches!(invalid, Err(BuildError::InvalidUrl { .. })));
+    }
+
+    #[test]
+    fn ends_with_an_error_for_invalid_urls_without_build() {
+        let results: Vec<_> = block_on(
+            EventStreamBuilder::new()
+                .url("not a url")
+                .into_stream_with_errors()
+                .collect(),
+        );
+        assert!(matches!(
+            &results[..],
+            [Err(EventStreamError::Backend(BackendError::Connection(_)))]
+        ));
+    }
+
+    #[test]
+    fn reads_streams_by_name() {
+        let urls = EventStreamBuilder::new()
+            .stream_names(["revision-tags-change", "recentchange"])
+            .request_urls()
+            .unwrap();
+        assert_eq!(
+            urls[0].path(),
+            "/v2/stream/revision-tags-change,recentchange"
+        );
+    }
+}
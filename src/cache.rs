@@ -0,0 +1,221 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Caching lookups made by enrichment stages
+//!
+//! Enrichment stages like [`UserInfoClient`](crate::users::UserInfoClient)
+//! make an API request per lookup, which adds up quickly across millions
+//! of events. They keep the results in a [`Cache`], which by default is a
+//! [`MemoryCache`] private to the stage. With the `redis` feature, a
+//! [`RedisCache`] can be shared between processes and survives restarts.
+//!
+//! Caches are best effort: a failure to read counts as a miss, and a
+//! failure to write is ignored.
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Stores values, usually serialized as JSON, for a limited time
+pub trait Cache: Send + Sync {
+    /// The value stored under `key`, unless it has expired
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Store `value` under `key` for `ttl`
+    fn put(&self, key: &str, value: String, ttl: Duration);
+}
+
+/// Default number of entries a [`MemoryCache`] holds
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Cache in this process' memory
+#[derive(Debug)]
+pub struct MemoryCache {
+    capacity: usize,
+    entries: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl MemoryCache {
+    /// Hold at most `capacity` entries. Once full, expired entries are
+    /// removed, and if that isn't enough, everything is.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Expire entries by `clock` rather than the system time
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for MemoryCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        let (value, expires) = entries.get(key)?;
+        if *expires <= self.clock.now() {
+            entries.remove(key);
+            return None;
+        }
+        Some(value.clone())
+    }
+
+    fn put(&self, key: &str, value: String, ttl: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        let now = self.clock.now();
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            entries.retain(|_, (_, expires)| *expires > now);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+        let expires = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| now.checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        entries.insert(key.to_string(), (value, expires));
+    }
+}
+
+/// Cache in a Redis server, shared by everything that connects to it.
+/// Requests are blocking, so the server should be close by.
+#[cfg(feature = "redis")]
+pub struct RedisCache {
+    client: redis::Client,
+    prefix: String,
+    connection: Mutex<Option<redis::Connection>>,
+}
+
+#[cfg(feature = "redis")]
+impl RedisCache {
+    /// Connect to the server at `url`, e.g. `redis://127.0.0.1/`. Keys are
+    /// prefixed with `eventstreams:`.
+    pub fn new(url: &str) -> redis::RedisResult<Self> {
+        Ok(Self {
+            client: redis::Client::open(url)?,
+            prefix: "eventstreams:".to_string(),
+            connection: Mutex::new(None),
+        })
+    }
+
+    /// Prefix keys with `prefix` instead, e.g. to keep separate
+    /// deployments apart
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Run `command` on the shared connection, connecting first if needed.
+    /// The connection is dropped after an error, so the next command
+    /// reconnects.
+    fn with_connection<T>(
+        &self,
+        command: impl FnOnce(&mut redis::Connection) -> redis::RedisResult<T>,
+    ) -> Option<T> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = self.client.get_connection().ok();
+        }
+        let result = command(connection.as_mut()?);
+        if result.is_err() {
+            *connection = None;
+        }
+        result.ok()
+    }
+}
+
+#[cfg(feature = "redis")]
+impl Cache for RedisCache {
+    fn get(&self, key: &str) -> Option<String> {
+        use redis::Commands;
+        let key = format!("{}{}", self.prefix, key);
+        self.with_connection(|connection| connection.get(key))?
+    }
+
+    fn put(&self, key: &str, value: String, ttl: Duration) {
+        use redis::Commands;
+        let key = format!("{}{}", self.prefix, key);
+        // Redis rejects an expiry of 0
+        let seconds = ttl.as_secs().max(1);
+        self.with_connection(|connection| {
+            connection.set_ex::<_, _, ()>(key, value, seconds)
+        });
+    }
+}
+
+#[cfg(feature = "redis")]
+impl std::fmt::Debug for RedisCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisCache")
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::at;
+
+    #[test]
+    fn expires_and_evicts_entries() {
+        let clock = ManualClock::new(at(0));
+        let cache = MemoryCache::new(2).clock(Arc::new(clock.clone()));
+        cache.put("a", "1".to_string(), Duration::from_secs(10));
+        cache.put("b", "2".to_string(), Duration::from_secs(60));
+        assert_eq!(cache.get("a").as_deref(), Some("1"));
+        clock.advance(Duration::from_secs(10));
+        assert!(cache.get("a").is_none());
+        assert_eq!(cache.len(), 1);
+        // Full of unexpired entries, so everything goes
+        cache.put("c", "3".to_string(), Duration::from_secs(60));
+        cache.put("d", "4".to_string(), Duration::from_secs(60));
+        assert!(cache.get("b").is_none());
+        assert_eq!(cache.get("d").as_deref(), Some("4"));
+        assert_eq!(cache.len(), 1);
+        cache.put("e", "5".to_string(), Duration::MAX);
+        assert_eq!(cache.get("e").as_deref(), Some("5"));
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn treats_unreachable_redis_as_a_miss() {
+        let cache = RedisCache::new("redis://127.0.0.1:1/").unwrap();
+        cache.put("a", "1".to_string(), Duration::from_secs(10));
+        assert!(cache.get("a").is_none());
+    }
+}
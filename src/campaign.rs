@@ -0,0 +1,144 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Campaign and hashtag tracking
+//!
+//! Outreach campaigns usually ask participants to tag their edit summaries
+//! with a hashtag like `#1lib1ref`. A [`CampaignTracker`] looks for a
+//! configured set of such markers and keeps a running count per campaign.
+use crate::{EditEvent, Event};
+use std::collections::BTreeMap;
+
+/// An edit whose summary contains a campaign marker
+#[derive(Clone, Debug)]
+pub struct CampaignEdit {
+    /// The marker as configured, e.g. `#1lib1ref`
+    pub campaign: String,
+    pub edit: EditEvent,
+    /// Matching edits for this campaign so far, including this one
+    pub count: u64,
+}
+
+/// Scans edit summaries for campaign markers
+#[derive(Clone, Debug)]
+pub struct CampaignTracker {
+    /// Configured markers, along with their lowercased form for matching
+    markers: Vec<(String, String)>,
+    counts: BTreeMap<String, u64>,
+}
+
+impl CampaignTracker {
+    /// Track edits whose summary contains any of `markers`. Matching is
+    /// case-insensitive, and a marker must not be directly followed by a
+    /// letter or digit, so `#1lib1ref` doesn't match `#1lib1refs`.
+    pub fn new<I, S>(markers: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let markers: Vec<(String, String)> = markers
+            .into_iter()
+            .map(Into::into)
+            .map(|marker| {
+                let lower = marker.to_lowercase();
+                (marker, lower)
+            })
+            .collect();
+        let counts = markers
+            .iter()
+            .map(|(marker, _)| (marker.clone(), 0))
+            .collect();
+        Self { markers, counts }
+    }
+
+    /// Markers found in `summary`
+    pub fn matches<'a>(
+        &'a self,
+        summary: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        let summary = summary.to_lowercase();
+        self.markers
+            .iter()
+            .filter(move |(_, lower)| contains_marker(&summary, lower))
+            .map(|(marker, _)| marker.as_str())
+    }
+
+    /// Check an event, returning one [`CampaignEdit`] per matching marker.
+    /// Events other than edits and page creations are ignored.
+    pub fn push(&mut self, event: &Event) -> Vec<CampaignEdit> {
+        let edit = match event {
+            Event::Edit(edit) | Event::New(edit) => edit,
+            _ => return vec![],
+        };
+        let campaigns: Vec<String> =
+            self.matches(&edit.comment).map(str::to_string).collect();
+        campaigns
+            .into_iter()
+            .map(|campaign| {
+                let count = self.counts.get_mut(&campaign).unwrap();
+                *count += 1;
+                CampaignEdit {
+                    count: *count,
+                    campaign,
+                    edit: edit.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Number of matching edits seen so far, per campaign
+    pub fn counts(&self) -> &BTreeMap<String, u64> {
+        &self.counts
+    }
+}
+
+fn contains_marker(summary: &str, marker: &str) -> bool {
+    summary.match_indices(marker).any(|(index, _)| {
+        !summary[index + marker.len()..]
+            .starts_with(|c: char| c.is_alphanumeric() || c == '_')
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    fn edit_with(comment: &str) -> Event {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["comment"] = comment.into();
+        testing::event(&edit)
+    }
+
+    #[test]
+    fn counts_edits_per_campaign() {
+        let mut tracker = CampaignTracker::new(["#1lib1ref", "#WPWP"]);
+        let found = tracker.push(&edit_with("Added a source #1Lib1Ref"));
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].campaign, "#1lib1ref");
+        assert_eq!(found[0].count, 1);
+        assert!(tracker
+            .push(&edit_with("Not this one #1lib1refs"))
+            .is_empty());
+        let found = tracker.push(&edit_with("#wpwp and #1lib1ref, again"));
+        let found: Vec<_> = found
+            .iter()
+            .map(|c| (c.campaign.as_str(), c.count))
+            .collect();
+        assert_eq!(found, [("#1lib1ref", 2), ("#WPWP", 1)]);
+        assert_eq!(tracker.counts()["#1lib1ref"], 2);
+    }
+}
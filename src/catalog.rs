@@ -0,0 +1,166 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Sampling streams to see how busy they are
+//!
+//! Streams differ a lot in volume: `revision-score` carries a few events a
+//! second, while `page-links-change` can be much busier. [`sample()`]
+//! subscribes to some streams for a while and reports what was seen on
+//! each, to help decide which ones are worth subscribing to.
+use crate::backend::{self, Backoff, ClientOptions};
+use crate::builder::BASE_URL;
+use crate::runtime;
+use crate::StreamKind;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// What was observed on one stream while sampling
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamStats {
+    pub stream: StreamKind,
+    /// Number of events received
+    pub events: u64,
+    /// Total size of their JSON payloads, in bytes
+    pub bytes: u64,
+    /// How long the stream was sampled for
+    pub window: Duration,
+}
+
+impl StreamStats {
+    /// Events per second
+    pub fn rate(&self) -> f64 {
+        self.events as f64 / self.window.as_secs_f64()
+    }
+
+    /// Average payload size in bytes, or 0 if there were no events
+    pub fn average_size(&self) -> u64 {
+        self.bytes.checked_div(self.events).unwrap_or(0)
+    }
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    meta: Meta,
+}
+
+#[derive(Deserialize)]
+struct Meta {
+    stream: String,
+}
+
+/// Subscribe to `streams` for `window`, returning statistics for each of
+/// them in the same order. All streams are sampled over one connection.
+pub async fn sample(
+    streams: &[StreamKind],
+    window: Duration,
+) -> Vec<StreamStats> {
+    sample_from(BASE_URL, streams, window).await
+}
+
+async fn sample_from(
+    base_url: &str,
+    streams: &[StreamKind],
+    window: Duration,
+) -> Vec<StreamStats> {
+    let names: Vec<_> = streams.iter().map(StreamKind::name).collect();
+    let url = format!("{}{}", base_url, names.join(","))
+        .parse()
+        .expect("invalid URL");
+    let mut seen: HashMap<StreamKind, (u64, u64)> = HashMap::new();
+    let mut messages = Box::pin(
+        backend::reconnecting(
+            url,
+            None,
+            Backoff::default(),
+            ClientOptions::default(),
+            |_| {},
+        )
+        .take_until(runtime::sleep(window)),
+    );
+    while let Some(message) = messages.next().await {
+        let data = match message {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        let kind = serde_json::from_str::<Payload>(&data)
+            .ok()
+            .and_then(|payload| StreamKind::from_stream(&payload.meta.stream));
+        if let Some(kind) = kind {
+            let (events, bytes) = seen.entry(kind).or_default();
+            *events += 1;
+            *bytes += data.len() as u64;
+        }
+    }
+    streams
+        .iter()
+        .map(|stream| {
+            let (events, bytes) = seen.get(stream).copied().unwrap_or_default();
+            StreamStats {
+                stream: *stream,
+                events,
+                bytes,
+                window,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at, MockServer};
+    use futures::executor::block_on;
+
+    #[test]
+    fn counts_events_per_stream() {
+        let messages: Vec<_> = (0..3)
+            .map(|offset| testing::edit(offset, "Ada", at(offset as i64)))
+            .map(|message| message.to_string())
+            .collect();
+        let bytes = messages.iter().map(|m| m.len() as u64).sum();
+        let server = MockServer::new(messages).start().unwrap();
+        let base_url = format!("http://{}/v2/stream/", server.addr());
+        let window = Duration::from_millis(500);
+        let streams = [StreamKind::RecentChange, StreamKind::PageCreate];
+        let stats = block_on(sample_from(&base_url, &streams, window));
+        assert_eq!(
+            stats,
+            [
+                StreamStats {
+                    stream: StreamKind::RecentChange,
+                    events: 3,
+                    bytes,
+                    window,
+                },
+                StreamStats {
+                    stream: StreamKind::PageCreate,
+                    events: 0,
+                    bytes: 0,
+                    window,
+                },
+            ]
+        );
+        assert_eq!(stats[0].rate(), 6.0);
+        assert_eq!(stats[0].average_size(), bytes / 3);
+        assert_eq!(stats[1].average_size(), 0);
+        assert_eq!(
+            server.requests()[0].path,
+            "/v2/stream/recentchange,page-create"
+        );
+    }
+}
@@ -0,0 +1,196 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tracking pages entering and leaving categories
+//!
+//! MediaWiki emits a separate [`CategorizeEvent`] after the edit that
+//! changed a page's categories, usually a few seconds later once the job
+//! queue has caught up. [`CategoryTracker`] joins the two back together
+//! and keeps a live view of which pages have entered or left each of a
+//! configured set of categories.
+use crate::title;
+use crate::watermark::Watermark;
+use crate::{CategorizeEvent, CategoryChange, EditEvent, Event};
+use chrono::Duration;
+use std::collections::{BTreeMap, HashMap};
+
+/// A page entering or leaving one of the tracked categories
+#[derive(Clone, Debug)]
+pub struct MembershipChange {
+    /// Prefixed title of the category
+    pub category: String,
+    /// Prefixed title of the page
+    pub page: String,
+    pub change: CategoryChange,
+    pub categorize: CategorizeEvent,
+    /// The most recent edit to the page within the tracker's window, which
+    /// is normally the one responsible. `None` if the change came from
+    /// somewhere else, e.g. an edit to a template the page uses.
+    pub edit: Option<EditEvent>,
+}
+
+/// Follows membership changes for a set of categories on one wiki
+#[derive(Clone, Debug)]
+pub struct CategoryTracker {
+    wiki: String,
+    window: Duration,
+    watermark: Watermark,
+    /// Latest state of each page seen changing, per category
+    categories: BTreeMap<String, BTreeMap<String, CategoryChange>>,
+    /// Most recent edit to each page, for attributing changes
+    edits: HashMap<String, EditEvent>,
+}
+
+impl CategoryTracker {
+    /// Track `categories` (prefixed titles, e.g. `Category:Living people`,
+    /// which are [canonicalized](crate::title::canonicalize)) on `wiki`
+    /// (internal database name). Changes are attributed to an
+    /// edit of the page made at most `window` earlier.
+    pub fn new<I, S>(
+        wiki: &str,
+        categories: I,
+        window: std::time::Duration,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            wiki: wiki.to_string(),
+            window: Duration::from_std(window).expect("window out of range"),
+            watermark: Watermark::new(window),
+            categories: categories
+                .into_iter()
+                .map(|category| {
+                    let category = title::canonicalize(wiki, &category.into());
+                    (category, BTreeMap::new())
+                })
+                .collect(),
+            edits: HashMap::new(),
+        }
+    }
+
+    /// Add an event, returning a change if a page entered or left one of
+    /// the tracked categories
+    pub fn push(&mut self, event: &Event) -> Option<MembershipChange> {
+        if event.wiki() != self.wiki {
+            return None;
+        }
+        self.watermark.observe(event);
+        let window = self.window;
+        if let Some(watermark) = self.watermark.current() {
+            self.edits
+                .retain(|_, edit| edit.meta.dt + window >= watermark);
+        }
+        match event {
+            Event::Edit(edit) | Event::New(edit) => {
+                self.edits.insert(edit.title.clone(), edit.clone());
+                None
+            }
+            Event::Categorize(categorize) => {
+                let pages = self.categories.get_mut(&categorize.title)?;
+                let (change, page) = categorize.change()?;
+                pages.insert(page.to_string(), change);
+                Some(MembershipChange {
+                    category: categorize.title.clone(),
+                    page: page.to_string(),
+                    change,
+                    categorize: categorize.clone(),
+                    edit: self.edits.get(page).cloned(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Pages seen entering `category` that haven't left it since
+    pub fn entered(&self, category: &str) -> Vec<&str> {
+        self.pages(category, CategoryChange::Added)
+    }
+
+    /// Pages seen leaving `category` that haven't come back since
+    pub fn left(&self, category: &str) -> Vec<&str> {
+        self.pages(category, CategoryChange::Removed)
+    }
+
+    fn pages(&self, category: &str, state: CategoryChange) -> Vec<&str> {
+        self.categories
+            .get(category)
+            .into_iter()
+            .flatten()
+            .filter(|(_, change)| **change == state)
+            .map(|(page, _)| page.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    fn categorize(category: &str, comment: &str, seconds: i64) -> Event {
+        let mut categorize = testing::edit(2, category, at(seconds));
+        let fields = categorize.as_object_mut().unwrap();
+        for field in ["length", "revision", "minor", "patrolled"] {
+            fields.remove(field);
+        }
+        fields.insert("type".to_string(), "categorize".into());
+        fields.insert("namespace".to_string(), 14.into());
+        fields.insert("comment".to_string(), comment.into());
+        testing::event(&categorize)
+    }
+
+    #[test]
+    fn attributes_membership_changes_to_edits() {
+        let mut tracker = CategoryTracker::new(
+            "enwiki",
+            ["category:living people"],
+            StdDuration::from_secs(60),
+        );
+        let edit = testing::edit_event(1, "Ada", at(0));
+        assert!(tracker.push(&edit).is_none());
+        let change = tracker
+            .push(&categorize(
+                "Category:Living people",
+                "[[:Ada]] added to category",
+                5,
+            ))
+            .unwrap();
+        assert_eq!(change.page, "Ada");
+        assert_eq!(change.change, CategoryChange::Added);
+        assert_eq!(change.edit.unwrap().title, "Ada");
+        // Long after the edit, so left unattributed
+        let change = tracker
+            .push(&categorize(
+                "Category:Living people",
+                "[[:Ada]] removed from category",
+                600,
+            ))
+            .unwrap();
+        assert!(change.edit.is_none());
+        assert!(tracker.entered("Category:Living people").is_empty());
+        assert_eq!(tracker.left("Category:Living people"), ["Ada"]);
+        assert!(tracker
+            .push(&categorize(
+                "Category:Dogs",
+                "[[:Rex]] added to category",
+                601
+            ))
+            .is_none());
+    }
+}
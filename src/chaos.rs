@@ -0,0 +1,216 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Random failures for rehearsing recovery
+//!
+//! Where a [`FaultInjector`](crate::backend::FaultInjector) injects
+//! specific faults on demand, [`Chaos`] injects them at random, at
+//! configurable probabilities per message: disconnects, delays and
+//! duplicated messages. It wraps any backend, so it can be used against
+//! live data with [`EventStreamBuilder::chaos()`](crate::EventStreamBuilder::chaos)
+//! or against replayed data with
+//! [`EventStream::from_backend()`](crate::EventStream::from_backend).
+//!
+//! Disconnects are simulated by reporting the connection as lost, which
+//! the stream and its listeners handle like a real one; the underlying
+//! connection is kept.
+use crate::backend::BackendError;
+use crate::clock::{Clock, SystemClock};
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Number of failures a [`Chaos`] layer injected. Clones share the same
+/// counts.
+#[derive(Clone, Debug, Default)]
+pub struct ChaosStats {
+    disconnects: Arc<AtomicU64>,
+    delays: Arc<AtomicU64>,
+    duplicates: Arc<AtomicU64>,
+}
+
+impl ChaosStats {
+    pub fn disconnects(&self) -> u64 {
+        self.disconnects.load(Ordering::Relaxed)
+    }
+
+    pub fn delays(&self) -> u64 {
+        self.delays.load(Ordering::Relaxed)
+    }
+
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates.load(Ordering::Relaxed)
+    }
+}
+
+/// Injects random failures into a backend, see the
+/// [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct Chaos {
+    disconnect: f64,
+    delay: f64,
+    max_delay: Duration,
+    duplicate: f64,
+    rng: Arc<Mutex<fastrand::Rng>>,
+    clock: Arc<dyn Clock>,
+    stats: ChaosStats,
+}
+
+impl Chaos {
+    /// A layer that injects nothing until probabilities are set
+    pub fn new() -> Self {
+        Self {
+            disconnect: 0.0,
+            delay: 0.0,
+            max_delay: Duration::ZERO,
+            duplicate: 0.0,
+            rng: Arc::new(Mutex::new(fastrand::Rng::new())),
+            clock: Arc::new(SystemClock),
+            stats: ChaosStats::default(),
+        }
+    }
+
+    /// Report the connection as lost before a message with probability
+    /// `probability`
+    pub fn disconnects(mut self, probability: f64) -> Self {
+        self.disconnect = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Hold a message back for a random time up to `max` with probability
+    /// `probability`
+    pub fn delays(mut self, probability: f64, max: Duration) -> Self {
+        self.delay = probability.clamp(0.0, 1.0);
+        self.max_delay = max;
+        self
+    }
+
+    /// Deliver a message twice with probability `probability`, as
+    /// EventStreams can after a reconnect
+    pub fn duplicates(mut self, probability: f64) -> Self {
+        self.duplicate = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Use a fixed seed, so a rehearsal can be repeated exactly against
+    /// the same messages
+    pub fn seed(self, seed: u64) -> Self {
+        *self.rng.lock().unwrap() = fastrand::Rng::with_seed(seed);
+        self
+    }
+
+    /// Clock used for delays
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn stats(&self) -> ChaosStats {
+        self.stats.clone()
+    }
+
+    /// Whether to inject a failure with `probability`
+    fn roll(&self, probability: f64) -> bool {
+        probability > 0.0 && self.rng.lock().unwrap().f64() < probability
+    }
+
+    /// Inject failures into the messages from `backend`. Errors from the
+    /// backend itself are passed through untouched.
+    pub fn wrap<T: Clone>(
+        self,
+        backend: impl Stream<Item = Result<T, BackendError>>,
+    ) -> impl Stream<Item = Result<T, BackendError>> {
+        stream! {
+            futures::pin_mut!(backend);
+            while let Some(message) = backend.next().await {
+                let data = match message {
+                    Ok(data) => data,
+                    Err(err) => {
+                        yield Err(err);
+                        continue;
+                    }
+                };
+                if self.roll(self.disconnect) {
+                    self.stats.disconnects.fetch_add(1, Ordering::Relaxed);
+                    yield Err(BackendError::Disconnected);
+                }
+                if self.roll(self.delay) {
+                    self.stats.delays.fetch_add(1, Ordering::Relaxed);
+                    let delay =
+                        self.max_delay.mul_f64(self.rng.lock().unwrap().f64());
+                    self.clock.sleep(delay).await;
+                }
+                if self.roll(self.duplicate) {
+                    self.stats.duplicates.fetch_add(1, Ordering::Relaxed);
+                    yield Ok(data.clone());
+                }
+                yield Ok(data);
+            }
+        }
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::at;
+    use futures::executor::block_on;
+
+    fn rehearse(chaos: Chaos) -> Vec<Option<String>> {
+        let messages = (0..200).map(|n| Ok(n.to_string()));
+        let wrapped = chaos.wrap(futures::stream::iter(messages));
+        block_on(wrapped.map(Result::ok).collect())
+    }
+
+    #[test]
+    fn injects_failures_repeatably_with_a_seed() {
+        let chaos = || {
+            Chaos::new()
+                .disconnects(0.1)
+                .duplicates(0.1)
+                .delays(0.5, Duration::ZERO)
+                .clock(Arc::new(ManualClock::new(at(0))))
+                .seed(7)
+        };
+        let first = chaos();
+        let stats = first.stats();
+        let messages = rehearse(first);
+        assert_eq!(messages, rehearse(chaos()));
+        let disconnects = messages.iter().filter(|m| m.is_none()).count();
+        assert_eq!(stats.disconnects(), disconnects as u64);
+        assert!(disconnects > 0);
+        assert_eq!(
+            messages.len() as u64,
+            200 + stats.disconnects() + stats.duplicates()
+        );
+        assert!(stats.duplicates() > 0);
+        assert!(stats.delays() > 0);
+
+        let calm = Chaos::new();
+        let stats = calm.stats();
+        assert_eq!(rehearse(calm).len(), 200);
+        assert_eq!(stats.disconnects() + stats.duplicates(), 0);
+    }
+}
@@ -0,0 +1,231 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Persisting the stream position across restarts
+//!
+//! A [`Checkpointer`] saves a [`Checkpoint`] of the latest positions,
+//! `meta.dt` and recent event IDs every so often, and on startup the bot
+//! [resumes](Checkpoints::resume) from it. Events processed after the last
+//! save are delivered again after a crash, and EventStreams resends a few
+//! events around the resume point, so events whose `meta.id` is in the
+//! checkpoint are [recognized](Checkpoints::check) as duplicates. Together
+//! this means a crash-restarted bot neither misses events nor processes
+//! many of them twice.
+//!
+//! [`EventStreamExt::checkpointed()`](crate::EventStreamExt::checkpointed)
+//! takes care of all of this for a stream.
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::{DedupStatus, DedupWindow, DEFAULT_CAPACITY};
+use crate::resume::ResumeToken;
+use crate::{Event, EventStreamBuilder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How far a consumer got, as saved by a [`Checkpointer`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Positions of the latest events processed
+    pub token: ResumeToken,
+    /// `meta.dt` of the latest event processed, for resuming with
+    /// [`since()`](EventStreamBuilder::since) where the token has no
+    /// position
+    pub dt: Option<DateTime<Utc>>,
+    /// IDs (`meta.id`) of the most recent events processed, oldest first
+    pub recent_ids: Vec<String>,
+}
+
+/// Somewhere to persist a [`Checkpoint`] across restarts
+pub trait Checkpointer {
+    /// Load the saved checkpoint, or `None` if nothing has been saved yet
+    fn load(&self) -> io::Result<Option<Checkpoint>>;
+    /// Save `checkpoint`, replacing whatever was saved before
+    fn save(&self, checkpoint: &Checkpoint) -> io::Result<()>;
+}
+
+/// Stores the checkpoint as a JSON file
+#[derive(Clone, Debug)]
+pub struct FileCheckpointer {
+    path: PathBuf,
+}
+
+impl FileCheckpointer {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Checkpointer for FileCheckpointer {
+    fn load(&self) -> io::Result<Option<Checkpoint>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Some(serde_json::from_str(&contents)?))
+    }
+
+    fn save(&self, checkpoint: &Checkpoint) -> io::Result<()> {
+        // Write to a temporary file first so a crash can't leave a
+        // half-written file behind
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(checkpoint)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+/// Tracks processed events and saves a [`Checkpoint`] at most every
+/// interval
+#[derive(Debug)]
+pub struct Checkpoints<C> {
+    checkpointer: C,
+    token: ResumeToken,
+    dt: Option<DateTime<Utc>>,
+    dedup: DedupWindow,
+    interval: Duration,
+    last_saved: DateTime<Utc>,
+    clock: Arc<dyn Clock>,
+}
+
+impl<C: Checkpointer> Checkpoints<C> {
+    /// Load the saved checkpoint from `checkpointer`, if there is one, and
+    /// save a new one every `interval`. The last 10,000 event IDs are kept
+    /// for recognizing duplicates.
+    pub fn new(checkpointer: C, interval: Duration) -> io::Result<Self> {
+        Self::with_clock(checkpointer, interval, Arc::new(SystemClock))
+    }
+
+    /// Like [`new()`](Self::new), but measure the interval with `clock`
+    pub fn with_clock(
+        checkpointer: C,
+        interval: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> io::Result<Self> {
+        let checkpoint = checkpointer.load()?.unwrap_or_default();
+        let mut dedup = DedupWindow::new(DEFAULT_CAPACITY);
+        for id in &checkpoint.recent_ids {
+            dedup.check_id(id);
+        }
+        Ok(Self {
+            checkpointer,
+            token: checkpoint.token,
+            dt: checkpoint.dt,
+            dedup,
+            interval,
+            last_saved: clock.now(),
+            clock,
+        })
+    }
+
+    /// Continue from the loaded checkpoint: just after its positions, or
+    /// from its `meta.dt` if it has none. Nothing changes if no checkpoint
+    /// was saved yet.
+    pub fn resume(&self, builder: EventStreamBuilder) -> EventStreamBuilder {
+        let builder = match self.dt {
+            Some(dt) if self.token.is_empty() => builder.since(dt),
+            _ => builder,
+        };
+        builder.resume_from(&self.token)
+    }
+
+    /// Check whether `event` was already processed, and if not, record it
+    /// as processed
+    pub fn check(&mut self, event: &Event) -> DedupStatus {
+        let status = self.dedup.check(event);
+        if status == DedupStatus::Unique {
+            self.token.observe(event);
+            self.dt = self.dt.max(Some(event.dt()));
+        }
+        status
+    }
+
+    /// The checkpoint as it would be saved now
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            token: self.token.clone(),
+            dt: self.dt,
+            recent_ids: self.dedup.ids().map(str::to_string).collect(),
+        }
+    }
+
+    /// Save the checkpoint if the interval has passed since the last save,
+    /// returning whether it was saved
+    pub fn save_if_due(&mut self) -> io::Result<bool> {
+        let elapsed = (self.clock.now() - self.last_saved)
+            .to_std()
+            .unwrap_or_default();
+        if elapsed < self.interval {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Save the checkpoint now
+    pub fn save(&mut self) -> io::Result<()> {
+        self.checkpointer.save(&self.checkpoint())?;
+        self.last_saved = self.clock.now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn resumes_where_the_last_run_left_off() {
+        let path = std::env::temp_dir().join(format!(
+            "eventstreams-checkpoint-{}.json",
+            std::process::id()
+        ));
+        let store = FileCheckpointer::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let interval = Duration::from_secs(60);
+        let mut checkpoints =
+            Checkpoints::with_clock(store.clone(), interval, clock.clone())
+                .unwrap();
+        let first = testing::edit_event(1, "A", at(5));
+        let second = testing::edit_event(2, "B", at(10));
+        assert_eq!(checkpoints.check(&first), DedupStatus::Unique);
+        assert_eq!(checkpoints.check(&first), DedupStatus::Duplicate);
+        assert!(!checkpoints.save_if_due().unwrap());
+        clock.advance(interval);
+        assert!(checkpoints.save_if_due().unwrap());
+        assert_eq!(checkpoints.check(&second), DedupStatus::Unique);
+
+        // Only what was saved survives a crash
+        let mut restarted =
+            Checkpoints::with_clock(store.clone(), interval, clock).unwrap();
+        let saved = store.load().unwrap().unwrap();
+        assert_eq!(saved.dt, Some(at(5)));
+        assert_eq!(saved.recent_ids, ["id-1"]);
+        assert_eq!(restarted.check(&first), DedupStatus::Duplicate);
+        assert_eq!(restarted.check(&second), DedupStatus::Unique);
+        restarted.save().unwrap();
+        assert_eq!(store.load().unwrap().unwrap(), restarted.checkpoint());
+        fs::remove_file(&path).unwrap();
+    }
+}
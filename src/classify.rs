@@ -0,0 +1,255 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Classifying edits by their summary
+//!
+//! A [`Classifier`] looks at an edit's summary along with the rest of the
+//! edit and decides what kind of edit it is, e.g. a revert or a
+//! translation. Give one to an [`Enveloper`](crate::envelope::Enveloper)
+//! and the result is attached to each
+//! [`Envelope`](crate::envelope::Envelope). [`HeuristicClassifier`] goes
+//! by default summaries and change tags in a handful of languages; a
+//! trained model can be plugged in by implementing [`Classifier`], or
+//! with a closure.
+use crate::EditEvent;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What kind of edit a [`Classifier`] thinks it is
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Label {
+    /// Undoes one or more earlier edits
+    Revert,
+    /// Adds links to promote something
+    Spam,
+    /// Translates a page from another wiki
+    Translation,
+    /// Removes all of a page's content
+    Blanking,
+    /// A label from a custom classifier
+    Other(String),
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Label::Revert => write!(f, "revert"),
+            Label::Spam => write!(f, "spam"),
+            Label::Translation => write!(f, "translation"),
+            Label::Blanking => write!(f, "blanking"),
+            Label::Other(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// Result of classifying an edit
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Classification {
+    pub label: Label,
+    /// How sure the classifier is, from 0 to 1
+    pub confidence: f64,
+}
+
+impl Classification {
+    pub fn new(label: Label, confidence: f64) -> Self {
+        Self {
+            label,
+            confidence: confidence.clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Decides what kind of edit an edit is, going by its summary and
+/// metadata. Called for every edit and page creation, so it shouldn't
+/// block for long.
+pub trait Classifier: Send + Sync {
+    /// Classify `edit`, whose summary is `summary`. `None` means nothing
+    /// stands out about it.
+    fn classify(
+        &self,
+        summary: &str,
+        edit: &EditEvent,
+    ) -> Option<Classification>;
+}
+
+impl<F> Classifier for F
+where
+    F: Fn(&str, &EditEvent) -> Option<Classification> + Send + Sync,
+{
+    fn classify(
+        &self,
+        summary: &str,
+        edit: &EditEvent,
+    ) -> Option<Classification> {
+        self(summary, edit)
+    }
+}
+
+/// Default undo and rollback summaries by language, or the parts of them
+/// that don't depend on the revision or users
+const REVERT_PHRASES: &[(&str, &str)] = &[
+    ("de", "rückgängig gemacht"),
+    ("de", "auf die letzte Version von"),
+    ("es", "Deshecha la edición"),
+    ("es", "Revertidos los cambios de"),
+    ("fr", "Annulation de la modification"),
+    ("fr", "Révocation des modifications de"),
+    ("it", "Annullata la modifica"),
+    ("it", "Annullate le modifiche di"),
+    ("ja", "の版を取り消し"),
+    ("ja", "による版へ巻き戻し"),
+    ("nl", "ongedaan gemaakt"),
+    ("nl", "hersteld tot de laatste versie"),
+    ("pt", "Desfeita a edição"),
+    ("pt", "Foram revertidas as edições de"),
+    ("ru", "Отмена правки"),
+    ("ru", "откачены к версии"),
+    ("zh", "撤销"),
+    ("zh", "回退到"),
+];
+
+/// Default summaries of pages created with
+/// [Content Translation](https://www.mediawiki.org/wiki/Content_translation)
+const TRANSLATION_PHRASES: &[(&str, &str)] = &[
+    ("en", "Created by translating the page"),
+    ("de", "Erstellt durch Übersetzen der Seite"),
+    ("es", "Creado al traducir la página"),
+];
+
+/// Classifies edits by their default summaries and change tags
+///
+/// Recognizes:
+/// * reverts, by [`EditEvent::is_probable_revert()`] and the default
+///   undo and rollback summaries of a few other languages
+/// * translations, by Content Translation's tags and summaries
+/// * blanking, by the `mw-blank` tag
+/// * spam, by external links in the summary
+///
+/// Summaries are only checked against phrases in the wiki's language and
+/// English, or all languages for multilingual wikis like Commons. Custom
+/// summaries aren't recognized, so edits it has nothing to say about
+/// aren't necessarily harmless.
+#[derive(Clone, Debug, Default)]
+pub struct HeuristicClassifier {
+    _private: (),
+}
+
+impl HeuristicClassifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Classifier for HeuristicClassifier {
+    fn classify(
+        &self,
+        summary: &str,
+        edit: &EditEvent,
+    ) -> Option<Classification> {
+        let language = edit.language();
+        let matches = |phrases: &[(&str, &str)]| {
+            phrases.iter().any(|(lang, phrase)| {
+                (language.is_none() || language == Some(*lang) || *lang == "en")
+                    && summary.contains(phrase)
+            })
+        };
+        if has_tag(edit, |tag| {
+            matches!(tag, "mw-undo" | "mw-rollback" | "mw-manual-revert")
+        }) {
+            Some(Classification::new(Label::Revert, 0.95))
+        } else if edit.is_probable_revert() || matches(REVERT_PHRASES) {
+            Some(Classification::new(Label::Revert, 0.8))
+        } else if has_tag(edit, |tag| tag.starts_with("contenttranslation")) {
+            Some(Classification::new(Label::Translation, 0.95))
+        } else if matches(TRANSLATION_PHRASES) {
+            Some(Classification::new(Label::Translation, 0.8))
+        } else if has_tag(edit, |tag| tag == "mw-blank") {
+            Some(Classification::new(Label::Blanking, 0.9))
+        } else if summary.contains("http://")
+            || summary.contains("https://")
+            || summary.contains("www.")
+        {
+            Some(Classification::new(Label::Spam, 0.5))
+        } else {
+            None
+        }
+    }
+}
+
+fn has_tag(edit: &EditEvent, predicate: impl Fn(&str) -> bool) -> bool {
+    edit.tags().into_iter().any(predicate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    fn edit(server_name: &str, comment: &str, tags: &[&str]) -> EditEvent {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["server_name"] = server_name.into();
+        edit["comment"] = comment.into();
+        edit["tags"] = tags.into();
+        match testing::event(&edit) {
+            Event::Edit(edit) => edit,
+            other => panic!("not an edit: {:?}", other),
+        }
+    }
+
+    fn label(server_name: &str, comment: &str, tags: &[&str]) -> Option<Label> {
+        let edit = edit(server_name, comment, tags);
+        HeuristicClassifier::new()
+            .classify(&edit.comment, &edit)
+            .map(|classification| classification.label)
+    }
+
+    #[test]
+    fn classifies_by_tags_and_summaries() {
+        let en = "en.wikipedia.org";
+        let de = "de.wikipedia.org";
+        let undone = "Änderung 1 von Bob rückgängig gemacht";
+        assert_eq!(label(en, "", &["mw-rollback"]), Some(Label::Revert));
+        assert_eq!(label(de, undone, &[]), Some(Label::Revert));
+        // Only in the wiki's own language
+        assert_eq!(label(en, undone, &[]), None);
+        assert_eq!(
+            label(en, "", &["contenttranslation-v2"]),
+            Some(Label::Translation)
+        );
+        assert_eq!(
+            label(de, "Created by translating the page [[:en:A]]", &[]),
+            Some(Label::Translation)
+        );
+        assert_eq!(label(en, "", &["mw-blank"]), Some(Label::Blanking));
+        assert_eq!(label(en, "see www.example.com", &[]), Some(Label::Spam));
+        assert_eq!(label(en, "Fixed a typo", &[]), None);
+    }
+
+    #[test]
+    fn takes_custom_classifiers() {
+        let classifier = |summary: &str, _: &EditEvent| {
+            summary.contains("typo").then(|| {
+                Classification::new(Label::Other("copyedit".into()), 2.0)
+            })
+        };
+        let edit = edit("en.wikipedia.org", "Fixed a typo", &[]);
+        let classification = classifier.classify(&edit.comment, &edit).unwrap();
+        assert_eq!(classification.label.to_string(), "copyedit");
+        assert_eq!(classification.confidence, 1.0);
+    }
+}
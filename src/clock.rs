@@ -0,0 +1,206 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Clocks for anything that depends on wall-clock time
+//!
+//! Everything time-based goes through a [`Clock`], so tests can use a
+//! [`ManualClock`] and advance time by hand instead of sleeping. A
+//! stream's clock is set with
+//! [`EventStreamBuilder::clock()`](crate::EventStreamBuilder::clock), and
+//! is passed on to its backoff, timeouts, staleness and health checks.
+//! Components used on their own, such as
+//! [`Throttle`](crate::pacing::Throttle), take one with a `clock()`
+//! method of their own, and otherwise default to [`SystemClock`].
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+pub trait Clock: Debug + Send + Sync {
+    /// The current time
+    fn now(&self) -> DateTime<Utc>;
+    /// Wait for `duration` to pass
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Real time, as reported by the system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        crate::runtime::sleep(duration)
+    }
+}
+
+#[derive(Debug)]
+struct ManualState {
+    now: DateTime<Utc>,
+    /// The waker of each pending sleep, by ID
+    sleepers: HashMap<u64, Waker>,
+    next_sleeper: u64,
+}
+
+impl ManualState {
+    /// `duration` after now, or as late as possible if that's out of range
+    fn after(&self, duration: Duration) -> DateTime<Utc> {
+        chrono::Duration::from_std(duration)
+            .ok()
+            .and_then(|duration| self.now.checked_add_signed(duration))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+}
+
+/// A clock that only moves when [`advance`](ManualClock::advance)d, for
+/// tests. Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    state: Arc<Mutex<ManualState>>,
+}
+
+impl ManualClock {
+    /// Create a clock starting at `now`
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ManualState {
+                now,
+                sleepers: HashMap::new(),
+                next_sleeper: 0,
+            })),
+        }
+    }
+
+    /// Move time forward, waking up anything that was sleeping until then.
+    /// Time stops at the latest time chrono can represent.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now = state.after(duration);
+        for (_, waker) in state.sleepers.drain() {
+            waker.wake();
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_sleeper;
+        state.next_sleeper += 1;
+        Box::pin(ManualSleep {
+            clock: self.clone(),
+            id,
+            deadline: state.after(duration),
+        })
+    }
+}
+
+struct ManualSleep {
+    clock: ManualClock,
+    id: u64,
+    deadline: DateTime<Utc>,
+}
+
+impl Future for ManualSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.clock.state.lock().unwrap();
+        if state.now >= self.deadline {
+            state.sleepers.remove(&self.id);
+            return Poll::Ready(());
+        }
+        // Only the latest waker needs waking
+        let registered = state
+            .sleepers
+            .get(&self.id)
+            .is_some_and(|waker| waker.will_wake(cx.waker()));
+        if !registered {
+            state.sleepers.insert(self.id, cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for ManualSleep {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.clock.state.lock() {
+            state.sleepers.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::task::noop_waker;
+    use futures::FutureExt;
+
+    #[test]
+    fn sleeps_until_advanced() {
+        let clock = ManualClock::new(Utc::now());
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        for _ in 0..3 {
+            assert!(sleep.poll_unpin(&mut cx).is_pending());
+        }
+        // Polling again doesn't register another waker
+        assert_eq!(clock.state.lock().unwrap().sleepers.len(), 1);
+        clock.advance(Duration::from_secs(5));
+        assert!(sleep.poll_unpin(&mut cx).is_pending());
+        clock.advance(Duration::from_secs(5));
+        assert!(sleep.poll_unpin(&mut cx).is_ready());
+        assert!(clock.state.lock().unwrap().sleepers.is_empty());
+    }
+
+    #[test]
+    fn forgets_dropped_sleeps() {
+        let clock = ManualClock::new(Utc::now());
+        let waker = noop_waker();
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        let _ = sleep.poll_unpin(&mut Context::from_waker(&waker));
+        drop(sleep);
+        assert!(clock.state.lock().unwrap().sleepers.is_empty());
+    }
+
+    #[test]
+    fn saturates_out_of_range_durations() {
+        let clock = ManualClock::new(Utc::now());
+        let waker = noop_waker();
+        let mut sleep = clock.sleep(Duration::MAX);
+        assert!(sleep
+            .poll_unpin(&mut Context::from_waker(&waker))
+            .is_pending());
+        clock.advance(Duration::MAX);
+        assert_eq!(clock.now(), DateTime::<Utc>::MAX_UTC);
+        assert!(sleep
+            .poll_unpin(&mut Context::from_waker(&waker))
+            .is_ready());
+    }
+}
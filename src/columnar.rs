@@ -0,0 +1,332 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Buffering events into Arrow columns
+//!
+//! [`EventColumnarBuffer`] appends the [`Column`]s it was created with
+//! straight into Arrow builders as events arrive, and hands back a
+//! [`RecordBatch`] every `batch_size` events. Batches can be queried with
+//! any Arrow-based engine or written out with the `parquet` crate's
+//! `ArrowWriter`, without ever holding a `Vec<EditEvent>`.
+use crate::{EditEvent, Event};
+use arrow_array::builder::{
+    BooleanBuilder, Int32Builder, Int64Builder, StringBuilder,
+    TimestampMillisecondBuilder, UInt32Builder, UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch, RecordBatchOptions};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use std::fmt;
+use std::sync::Arc;
+
+/// A field that can be collected into its own column
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    /// When the event happened, `meta.dt`
+    Timestamp,
+    /// Name of the stream, e.g. `mediawiki.recentchange`
+    Stream,
+    /// Internal database name of the wiki
+    Wiki,
+    /// Prefixed title, see [`Event::title()`]
+    Title,
+    /// Namespace ID, null if not known
+    Namespace,
+    /// See [`Event::user()`]
+    User,
+    /// See [`Event::is_bot()`]
+    Bot,
+    /// See [`Event::comment()`]
+    Comment,
+    /// New revision ID, null for anything but edits and page creations
+    Revision,
+    /// Previous revision ID, null for page creations
+    OldRevision,
+    /// Change in page length in bytes, null for anything but edits and
+    /// page creations
+    ByteChange,
+    /// Kafka offset within the partition
+    Offset,
+}
+
+impl Column {
+    /// Every column, in the order above
+    pub const ALL: [Column; 12] = [
+        Column::Timestamp,
+        Column::Stream,
+        Column::Wiki,
+        Column::Title,
+        Column::Namespace,
+        Column::User,
+        Column::Bot,
+        Column::Comment,
+        Column::Revision,
+        Column::OldRevision,
+        Column::ByteChange,
+        Column::Offset,
+    ];
+
+    /// Name of the column in the schema
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Timestamp => "timestamp",
+            Column::Stream => "stream",
+            Column::Wiki => "wiki",
+            Column::Title => "title",
+            Column::Namespace => "namespace",
+            Column::User => "user",
+            Column::Bot => "bot",
+            Column::Comment => "comment",
+            Column::Revision => "revision",
+            Column::OldRevision => "old_revision",
+            Column::ByteChange => "byte_change",
+            Column::Offset => "offset",
+        }
+    }
+
+    fn field(self) -> Field {
+        let (data_type, nullable) = match self {
+            Column::Timestamp => (
+                DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+                false,
+            ),
+            Column::Stream
+            | Column::Wiki
+            | Column::Title
+            | Column::User
+            | Column::Comment => (DataType::Utf8, false),
+            Column::Namespace => (DataType::Int32, true),
+            Column::Bot => (DataType::Boolean, false),
+            Column::Revision | Column::OldRevision => (DataType::UInt32, true),
+            Column::ByteChange => (DataType::Int64, true),
+            Column::Offset => (DataType::UInt64, false),
+        };
+        Field::new(self.name(), data_type, nullable)
+    }
+
+    fn builder(self, capacity: usize) -> Builder {
+        match self {
+            Column::Timestamp => Builder::Timestamp(
+                TimestampMillisecondBuilder::with_capacity(capacity)
+                    .with_timezone("UTC"),
+            ),
+            Column::Stream
+            | Column::Wiki
+            | Column::Title
+            | Column::User
+            | Column::Comment => Builder::String(StringBuilder::new()),
+            Column::Namespace => {
+                Builder::Int32(Int32Builder::with_capacity(capacity))
+            }
+            Column::Bot => {
+                Builder::Boolean(BooleanBuilder::with_capacity(capacity))
+            }
+            Column::Revision | Column::OldRevision => {
+                Builder::UInt32(UInt32Builder::with_capacity(capacity))
+            }
+            Column::ByteChange => {
+                Builder::Int64(Int64Builder::with_capacity(capacity))
+            }
+            Column::Offset => {
+                Builder::UInt64(UInt64Builder::with_capacity(capacity))
+            }
+        }
+    }
+}
+
+enum Builder {
+    Timestamp(TimestampMillisecondBuilder),
+    String(StringBuilder),
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    UInt32(UInt32Builder),
+    UInt64(UInt64Builder),
+    Boolean(BooleanBuilder),
+}
+
+impl Builder {
+    fn append(&mut self, column: Column, event: &Event) {
+        let edit = match event {
+            Event::Edit(edit) | Event::New(edit) => Some(edit),
+            _ => None,
+        };
+        match (self, column) {
+            (Builder::Timestamp(builder), _) => {
+                builder.append_value(event.dt().timestamp_millis())
+            }
+            (Builder::String(builder), Column::Stream) => {
+                builder.append_value(&event.meta().stream)
+            }
+            (Builder::String(builder), Column::Wiki) => {
+                builder.append_value(event.wiki())
+            }
+            (Builder::String(builder), Column::Title) => {
+                builder.append_value(event.title())
+            }
+            (Builder::String(builder), Column::User) => {
+                builder.append_value(event.user())
+            }
+            (Builder::String(builder), _) => {
+                builder.append_value(event.comment())
+            }
+            (Builder::Int32(builder), _) => {
+                builder.append_option(event.namespace())
+            }
+            (Builder::Boolean(builder), _) => {
+                builder.append_value(event.is_bot())
+            }
+            (Builder::UInt32(builder), Column::Revision) => {
+                builder.append_option(edit.map(|edit| edit.revision.new))
+            }
+            (Builder::UInt32(builder), _) => {
+                builder.append_option(edit.and_then(|edit| edit.revision.old))
+            }
+            (Builder::Int64(builder), _) => {
+                builder.append_option(edit.map(EditEvent::byte_change))
+            }
+            (Builder::UInt64(builder), _) => {
+                builder.append_value(event.meta().offset)
+            }
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Builder::Timestamp(builder) => Arc::new(builder.finish()),
+            Builder::String(builder) => Arc::new(builder.finish()),
+            Builder::Int32(builder) => Arc::new(builder.finish()),
+            Builder::Int64(builder) => Arc::new(builder.finish()),
+            Builder::UInt32(builder) => Arc::new(builder.finish()),
+            Builder::UInt64(builder) => Arc::new(builder.finish()),
+            Builder::Boolean(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Collects selected fields of events into Arrow arrays
+pub struct EventColumnarBuffer {
+    columns: Vec<(Column, Builder)>,
+    schema: SchemaRef,
+    batch_size: usize,
+    len: usize,
+}
+
+impl EventColumnarBuffer {
+    /// Collect `columns`, in that order, emitting a batch every
+    /// `batch_size` events
+    pub fn new(columns: &[Column], batch_size: usize) -> Self {
+        assert!(batch_size > 0, "batch_size must be positive");
+        Self {
+            columns: columns
+                .iter()
+                .map(|column| (*column, column.builder(batch_size)))
+                .collect(),
+            schema: Arc::new(Schema::new(
+                columns
+                    .iter()
+                    .map(|column| column.field())
+                    .collect::<Vec<_>>(),
+            )),
+            batch_size,
+            len: 0,
+        }
+    }
+
+    /// Schema of the batches this buffer produces
+    pub fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    /// Number of events buffered since the last batch
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Append an event, returning a batch once `batch_size` events have
+    /// been buffered
+    pub fn push(&mut self, event: &Event) -> Option<RecordBatch> {
+        for (column, builder) in &mut self.columns {
+            builder.append(*column, event);
+        }
+        self.len += 1;
+        if self.len >= self.batch_size {
+            Some(self.finish())
+        } else {
+            None
+        }
+    }
+
+    /// Take everything buffered so far as a batch, e.g. once the stream
+    /// has ended. The batch is empty if nothing was buffered.
+    pub fn finish(&mut self) -> RecordBatch {
+        let arrays = self
+            .columns
+            .iter_mut()
+            .map(|(_, builder)| builder.finish())
+            .collect();
+        let options = RecordBatchOptions::new().with_row_count(Some(self.len));
+        self.len = 0;
+        RecordBatch::try_new_with_options(self.schema.clone(), arrays, &options)
+            .expect("builders match the schema")
+    }
+}
+
+impl fmt::Debug for EventColumnarBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventColumnarBuffer")
+            .field("schema", &self.schema)
+            .field("batch_size", &self.batch_size)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use arrow_array::{Array, Int64Array, StringArray, UInt32Array};
+
+    #[test]
+    fn collects_columns_in_batches() {
+        let columns = [Column::Title, Column::Revision, Column::ByteChange];
+        let mut buffer = EventColumnarBuffer::new(&columns, 2);
+        let edit = testing::edit_event(1, "A", at(0));
+        let upload = testing::event(&testing::log(2, "File:B.png", at(0)));
+        assert!(buffer.push(&edit).is_none());
+        assert_eq!(buffer.len(), 1);
+        let batch = buffer.push(&upload).unwrap();
+        assert!(buffer.is_empty());
+        assert_eq!(batch.schema(), buffer.schema());
+        assert_eq!(batch.num_rows(), 2);
+        let titles = batch.column(0).as_any();
+        let titles = titles.downcast_ref::<StringArray>().unwrap();
+        assert_eq!(titles.value(0), "A");
+        assert_eq!(titles.value(1), "File:B.png");
+        let revisions = batch.column(1).as_any();
+        let revisions = revisions.downcast_ref::<UInt32Array>().unwrap();
+        assert_eq!(revisions.value(0), 2);
+        assert!(revisions.is_null(1));
+        let changes = batch.column(2).as_any();
+        let changes = changes.downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(changes.value(0), 10);
+        assert!(changes.is_null(1));
+        assert_eq!(buffer.finish().num_rows(), 0);
+    }
+}
@@ -0,0 +1,105 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Structured information in edit summaries
+//!
+//! MediaWiki prefixes the summary of section edits with the section's
+//! name in an "autocomment", e.g. `/* History */ fix typo`, and summaries
+//! can mention pages with `[[wikilinks]]`. These parse them out of the
+//! raw `comment`; the HTML in `parsedcomment` is left alone. They're also
+//! available as methods on events, e.g. [`EditEvent::section()`] and
+//! [`EditEvent::summary_links()`].
+//!
+//! [`EditEvent::section()`]: crate::EditEvent::section
+//! [`EditEvent::summary_links()`]: crate::EditEvent::summary_links
+use serde_json::{Map, Value};
+
+/// Name of the section edited, from the first `/* … */` autocomment in
+/// `comment`
+pub fn section(comment: &str) -> Option<&str> {
+    let start = comment.find("/*")?;
+    let rest = &comment[start + 2..];
+    let end = rest.find("*/")?;
+    let name = rest[..end].trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Targets of the `[[wikilinks]]` in `comment`, in order, without any
+/// `|label`
+pub fn wikilinks(comment: &str) -> Vec<String> {
+    let mut links = vec![];
+    let mut rest = comment;
+    while let Some(start) = rest.find("[[") {
+        rest = &rest[start + 2..];
+        let end = match rest.find("]]") {
+            Some(end) => end,
+            None => break,
+        };
+        let target = rest[..end].split('|').next().unwrap_or_default();
+        links.push(target.trim().to_string());
+        rest = &rest[end + 2..];
+    }
+    links
+}
+
+/// [Change tags](https://www.mediawiki.org/wiki/Manual:Tags) in the
+/// unparsed fields of an event, for streams that include them
+pub(crate) fn tags(extra: &Map<String, Value>) -> Vec<&str> {
+    extra
+        .get("tags")
+        .and_then(Value::as_array)
+        .map(|tags| tags.iter().filter_map(Value::as_str).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    #[test]
+    fn reads_sections_and_links() {
+        assert_eq!(section("/* Early life */ fix typo"), Some("Early life"));
+        assert_eq!(section("typo in /*  History*/"), Some("History"));
+        assert_eq!(section("/* */ fix"), None);
+        assert_eq!(section("/* unclosed"), None);
+        assert_eq!(
+            wikilinks("Merge [[A]] into [[ B | the b ]], see [[C"),
+            ["A", "B"]
+        );
+        assert!(wikilinks("no links").is_empty());
+    }
+
+    #[test]
+    fn reads_edit_summaries_and_tags() {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["comment"] = "/* Career */ per [[Talk:A]]".into();
+        edit["tags"] = serde_json::json!(["mw-undo", 5, "visualeditor"]);
+        let edit = match testing::event(&edit) {
+            Event::Edit(edit) => edit,
+            other => panic!("not an edit: {:?}", other),
+        };
+        assert_eq!(edit.section(), Some("Career"));
+        assert_eq!(edit.summary_links(), ["Talk:A"]);
+        assert_eq!(edit.tags(), ["mw-undo", "visualeditor"]);
+        assert!(edit.is_probable_revert());
+    }
+}
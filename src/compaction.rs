@@ -0,0 +1,113 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Periodically giving back memory from buffers that grew during bursts
+//!
+//! Queues and per-wiki state grow to fit the largest burst they've seen
+//! and then keep that allocation, so a process that runs for weeks slowly
+//! fragments its heap. Each of them checks a [`Compaction`] as it goes
+//! and, once per interval, shrinks its buffers and forgets state that
+//! no longer matters, see
+//! [`EventStreamBuilder::compaction_interval()`](crate::EventStreamBuilder::compaction_interval).
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often buffers are compacted unless configured otherwise
+pub(crate) const DEFAULT_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Says when it's time to compact again
+#[derive(Clone, Debug)]
+pub(crate) struct Compaction {
+    interval: Option<Duration>,
+    /// Wall-clock time rather than an `Instant`, which browsers don't have
+    last: DateTime<Utc>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Compaction {
+    /// Compact every `interval`, or never if it's `None`
+    pub(crate) fn new(interval: Option<Duration>) -> Self {
+        Self {
+            interval,
+            ..Self::default()
+        }
+    }
+
+    /// Measure intervals with `clock`, starting from its current time
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Whether a whole interval passed since the last compaction, in which
+    /// case the caller should compact now
+    pub(crate) fn due(&mut self) -> bool {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let now = self.clock.now();
+        if (now - self.last).to_std().unwrap_or_default() < interval {
+            return false;
+        }
+        self.last = now;
+        true
+    }
+}
+
+impl Default for Compaction {
+    fn default() -> Self {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            interval: Some(DEFAULT_INTERVAL),
+            last: clock.now(),
+            clock,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::at;
+
+    #[test]
+    fn is_due_once_per_interval_by_the_clock() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let mut compaction =
+            Compaction::new(Some(Duration::from_secs(60))).clock(clock.clone());
+        assert!(!compaction.due());
+        clock.advance(Duration::from_secs(59));
+        assert!(!compaction.due());
+        clock.advance(Duration::from_secs(1));
+        assert!(compaction.due());
+        assert!(!compaction.due());
+        clock.advance(Duration::from_secs(3600));
+        assert!(compaction.due());
+
+        let mut never = Compaction::new(None).clock(clock.clone());
+        clock.advance(Duration::from_secs(3600));
+        assert!(!never.due());
+    }
+}
@@ -0,0 +1,494 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Running subscriptions as a long-lived service
+//!
+//! A [`Daemon`] reads from one upstream stream and delivers events to all
+//! of its [`Subscription`]s, followed by the enabled subscriptions in its
+//! [`SubscriptionRegistry`]. [Alert](Subscription::alert) subscriptions
+//! go first, and in a daemon created [with a
+//! builder](Daemon::with_builder), are delivered to straight from the
+//! reader. If the upstream stream ends or panics, it is restarted.
+//! [`Daemon::serve_http`] exposes `/healthz` and `/metrics` endpoints for
+//! monitoring, [`Daemon::serve_admin`] an authenticated API for managing
+//! subscriptions, and [`Daemon::notify_systemd`] integrates with systemd's
+//! watchdog.
+use crate::admin;
+use crate::alert;
+use crate::breaker::BreakerState;
+use crate::clock::{Clock, SystemClock};
+use crate::metrics::Metrics;
+use crate::subscription::{
+    Subscription, SubscriptionDef, SubscriptionRegistry,
+};
+use crate::{BuildError, Event, EventStreamBuilder, Stream, StreamExt};
+use chrono::{DateTime, Utc};
+use futures::future::FutureExt;
+use futures::lock::Mutex as AsyncMutex;
+use futures::stream::LocalBoxStream;
+use std::io;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+type Source = Box<dyn Fn() -> LocalBoxStream<'static, Event>>;
+
+/// Whether events are still flowing
+#[derive(Clone, Debug)]
+struct Health {
+    clock: Arc<dyn Clock>,
+    started: DateTime<Utc>,
+    last_event: Arc<Mutex<Option<DateTime<Utc>>>>,
+    stall_after: Duration,
+}
+
+impl Health {
+    fn is_healthy(&self) -> bool {
+        let since = self.last_event.lock().unwrap().unwrap_or(self.started);
+        let stall_after = chrono::Duration::from_std(self.stall_after)
+            .unwrap_or(chrono::Duration::MAX);
+        self.clock.now() - since < stall_after
+    }
+}
+
+/// Send `WATCHDOG=1` with `notify` twice every `interval` from a
+/// background thread, skipping pings while `health` isn't healthy, until
+/// `notify` reports that systemd is gone. A zero interval turns the
+/// watchdog off.
+#[cfg(unix)]
+fn watchdog(
+    health: Health,
+    interval: Duration,
+    notify: impl Fn(&str) -> io::Result<bool> + Send + 'static,
+) {
+    if interval.is_zero() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(interval / 2);
+        if health.is_healthy() && matches!(notify("WATCHDOG=1"), Ok(false)) {
+            return;
+        }
+    });
+}
+
+/// Supervises delivery of events to subscriptions
+pub struct Daemon {
+    source: Source,
+    /// What `source` builds, if the daemon was created from a builder, so
+    /// alert subscriptions can be hooked into its reader
+    builder: Option<EventStreamBuilder>,
+    subscriptions: Vec<Subscription>,
+    alerts: Vec<Arc<AsyncMutex<Subscription>>>,
+    registry: SubscriptionRegistry,
+    /// Subscriptions built from the registry, as of `registry_version`
+    managed: Vec<(SubscriptionDef, Subscription)>,
+    registry_version: Option<u64>,
+    metrics: Metrics,
+    clock: Arc<dyn Clock>,
+    started: DateTime<Utc>,
+    last_event: Arc<Mutex<Option<DateTime<Utc>>>>,
+    stall_after: Duration,
+    restart_delay: Duration,
+}
+
+impl Daemon {
+    /// Create a daemon reading from the live feed
+    pub fn new() -> Self {
+        Self::from_builder(EventStreamBuilder::new())
+    }
+
+    /// Create a daemon reading from streams built by `builder`, with
+    /// [alert](Subscription::alert) subscriptions delivered to from its
+    /// reader, see
+    /// [`EventStreamBuilder::alert()`](crate::EventStreamBuilder::alert).
+    /// Fails if `builder` can't [build](EventStreamBuilder::build) a
+    /// stream.
+    pub fn with_builder(
+        builder: EventStreamBuilder,
+    ) -> Result<Self, BuildError> {
+        builder.validate()?;
+        Ok(Self::from_builder(builder))
+    }
+
+    fn from_builder(builder: EventStreamBuilder) -> Self {
+        let source = builder.clone();
+        Self {
+            builder: Some(builder),
+            ..Self::with_source(move || source.clone().into_stream())
+        }
+    }
+
+    /// Create a daemon reading from streams created by `source`, which is
+    /// called again whenever the daemon needs to restart
+    pub fn with_source<S>(source: impl Fn() -> S + 'static) -> Self
+    where
+        S: Stream<Item = Event> + 'static,
+    {
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            source: Box::new(move || source().boxed_local()),
+            builder: None,
+            subscriptions: vec![],
+            alerts: vec![],
+            registry: SubscriptionRegistry::new(),
+            managed: vec![],
+            registry_version: None,
+            metrics: Metrics::new(),
+            started: clock.now(),
+            clock,
+            last_event: Arc::new(Mutex::new(None)),
+            stall_after: Duration::from_secs(60),
+            restart_delay: Duration::from_secs(5),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.started = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    pub fn subscribe(mut self, subscription: Subscription) -> Self {
+        if subscription.is_alert() {
+            self.alerts.push(Arc::new(AsyncMutex::new(subscription)));
+        } else {
+            self.subscriptions.push(subscription);
+        }
+        self
+    }
+
+    /// Use `registry` for managed subscriptions, e.g. one loaded from disk
+    pub fn with_registry(mut self, registry: SubscriptionRegistry) -> Self {
+        self.registry = registry;
+        self
+    }
+
+    /// Handle for managing subscriptions while the daemon is running
+    pub fn registry(&self) -> SubscriptionRegistry {
+        self.registry.clone()
+    }
+
+    /// Report unhealthy if no events arrive for this long (default 60s)
+    pub fn stall_after(mut self, stall_after: Duration) -> Self {
+        self.stall_after = stall_after;
+        self
+    }
+
+    /// How long to wait before restarting the upstream stream (default 5s)
+    pub fn restart_delay(mut self, restart_delay: Duration) -> Self {
+        self.restart_delay = restart_delay;
+        self
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    fn health(&self) -> Health {
+        Health {
+            clock: self.clock.clone(),
+            started: self.started,
+            last_event: self.last_event.clone(),
+            stall_after: self.stall_after,
+        }
+    }
+
+    /// Serve `/healthz` and `/metrics` on `addr` from a background thread
+    pub fn serve_http(
+        &self,
+        addr: impl ToSocketAddrs,
+    ) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        let metrics = self.metrics.clone();
+        let health = self.health();
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let _ = respond(stream, &metrics, &health);
+            }
+        }))
+    }
+
+    /// Serve the [admin API](crate::admin) on `addr` from a background
+    /// thread, accepting requests that carry `token`
+    pub fn serve_admin(
+        &self,
+        addr: impl ToSocketAddrs,
+        token: impl Into<String>,
+    ) -> io::Result<thread::JoinHandle<()>> {
+        let listener = TcpListener::bind(addr)?;
+        let registry = self.registry.clone();
+        let metrics = self.metrics.clone();
+        let token = token.into();
+        Ok(thread::spawn(move || {
+            admin::serve(listener, registry, metrics, token)
+        }))
+    }
+
+    /// Tell systemd the daemon is ready and, if the watchdog is enabled,
+    /// keep pinging it from a background thread for as long as events keep
+    /// flowing. If the stream stalls the pings stop, and systemd restarts
+    /// the service.
+    #[cfg(unix)]
+    pub fn notify_systemd(&self) -> io::Result<()> {
+        if !crate::systemd::notify("READY=1")? {
+            return Ok(());
+        }
+        if let Some(interval) = crate::systemd::watchdog_interval() {
+            watchdog(self.health(), interval, crate::systemd::notify);
+        }
+        Ok(())
+    }
+
+    /// Deliver events to subscriptions, forever
+    pub async fn run(mut self) {
+        loop {
+            let result = AssertUnwindSafe(self.run_once()).catch_unwind().await;
+            let reason = if result.is_err() { "panicked" } else { "ended" };
+            self.metrics
+                .increment(&format!("daemon.restarts.{}", reason), 1);
+            self.clock.sleep(self.restart_delay).await;
+        }
+    }
+
+    async fn run_once(&mut self) {
+        let mut stream = match &self.builder {
+            Some(builder) => self
+                .alerts
+                .iter()
+                .fold(builder.clone(), |builder, subscription| {
+                    builder.alert_hook(alert_hook(
+                        &self.metrics,
+                        subscription.clone(),
+                    ))
+                })
+                .into_stream()
+                .boxed_local(),
+            None => (self.source)(),
+        };
+        while let Some(event) = stream.next().await {
+            *self.last_event.lock().unwrap() = Some(self.clock.now());
+            self.metrics.increment("daemon.events", 1);
+            if self.builder.is_none() {
+                for subscription in &self.alerts {
+                    let mut subscription = subscription.lock().await;
+                    deliver(&self.metrics, &mut subscription, &event).await;
+                }
+            }
+            self.sync_registry();
+            let managed = self.managed.iter_mut().map(|(_, sub)| sub);
+            for subscription in self.subscriptions.iter_mut().chain(managed) {
+                deliver(&self.metrics, subscription, &event).await;
+            }
+        }
+    }
+
+    /// Rebuild managed subscriptions if the registry has changed, keeping
+    /// the ones whose definition is the same
+    fn sync_registry(&mut self) {
+        let version = self.registry.version();
+        if self.registry_version == Some(version) {
+            return;
+        }
+        let mut old = std::mem::take(&mut self.managed);
+        for def in self.registry.list() {
+            if !def.enabled {
+                continue;
+            }
+            let existing = old.iter().position(|(old, _)| *old == def);
+            let subscription = match existing {
+                Some(index) => old.swap_remove(index).1,
+                None => match self.registry.build(&def) {
+                    Some(subscription) => subscription,
+                    None => continue,
+                },
+            };
+            self.managed.push((def, subscription));
+        }
+        self.registry_version = Some(version);
+    }
+}
+
+/// Delivers to an alert subscription from the reader
+fn alert_hook(
+    metrics: &Metrics,
+    subscription: Arc<AsyncMutex<Subscription>>,
+) -> alert::Hook {
+    let metrics = metrics.clone();
+    Arc::new(move |event| {
+        let metrics = metrics.clone();
+        let subscription = subscription.clone();
+        let event = event.clone();
+        async move {
+            let mut subscription = subscription.lock().await;
+            deliver(&metrics, &mut subscription, &event).await;
+        }
+        .boxed()
+    })
+}
+
+async fn deliver(
+    metrics: &Metrics,
+    subscription: &mut Subscription,
+    event: &Event,
+) {
+    let name = format!("subscription.{}", subscription.name());
+    let matched = subscription.matched();
+    let shadowed = subscription.shadowed();
+    let shadow_errors = subscription.shadow_errors();
+    let divergent = subscription
+        .dual_run()
+        .map_or(0, |dual_run| dual_run.counts().divergent());
+    let (paused, skipped) = breaker_state(subscription);
+    let result = subscription.deliver(event).await;
+    let now_divergent = subscription
+        .dual_run()
+        .map_or(0, |dual_run| dual_run.counts().divergent());
+    let (now_paused, now_skipped) = breaker_state(subscription);
+    if now_paused != paused {
+        let transition = if now_paused { "paused" } else { "resumed" };
+        metrics.increment(&format!("{}.{}", name, transition), 1);
+    }
+    if now_skipped > skipped {
+        metrics.increment(&format!("{}.skipped", name), 1);
+    }
+    if now_divergent > divergent {
+        metrics.increment(&format!("{}.divergent", name), 1);
+    }
+    if subscription.matched() > matched {
+        metrics.increment(&format!("{}.matched", name), 1);
+    }
+    if subscription.shadowed() > shadowed {
+        metrics.increment(&format!("{}.shadowed", name), 1);
+    }
+    if subscription.shadow_errors() > shadow_errors {
+        metrics.increment(&format!("{}.shadow_errors", name), 1);
+    }
+    match result {
+        Ok(true) => metrics.increment(&format!("{}.delivered", name), 1),
+        Ok(false) => {}
+        Err(_) => metrics.increment(&format!("{}.errors", name), 1),
+    }
+}
+
+/// Whether the subscription's circuit breaker is open or probing, and how
+/// many events it has skipped
+fn breaker_state(subscription: &mut Subscription) -> (bool, u64) {
+    subscription
+        .circuit_breaker()
+        .map_or((false, 0), |breaker| {
+            (breaker.state() != BreakerState::Closed, breaker.skipped())
+        })
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn respond(
+    stream: std::net::TcpStream,
+    metrics: &Metrics,
+    health: &Health,
+) -> io::Result<()> {
+    let request = admin::read_request(&stream)?;
+    let (status, body) = match request.path.as_str() {
+        "/healthz" if health.is_healthy() => ("200 OK", "ok\n".to_string()),
+        "/healthz" => ("503 Service Unavailable", "stalled\n".to_string()),
+        "/metrics" => ("200 OK", metrics.snapshot().to_prometheus()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    admin::write_response(stream, status, "text/plain; charset=utf-8", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::sink::FnSink;
+    use crate::testing;
+    use chrono::TimeZone;
+    use futures::executor::block_on;
+
+    #[cfg(unix)]
+    #[test]
+    fn pings_the_watchdog_only_while_healthy() {
+        let start = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let clock = ManualClock::new(start);
+        let daemon = Daemon::with_source(futures::stream::empty)
+            .with_clock(Arc::new(clock.clone()))
+            .stall_after(Duration::from_secs(60));
+        let (sender, pings) = std::sync::mpsc::channel();
+        let notify =
+            move |state: &str| Ok(sender.send(state.to_string()).is_ok());
+        let interval = Duration::from_millis(10);
+        watchdog(daemon.health(), Duration::ZERO, notify.clone());
+        watchdog(daemon.health(), interval, notify);
+        let timeout = Duration::from_secs(5);
+        assert_eq!(pings.recv_timeout(timeout).unwrap(), "WATCHDOG=1");
+
+        // Stalled, so the pings stop
+        clock.advance(Duration::from_secs(61));
+        while pings.recv_timeout(interval * 5).is_ok() {}
+        assert!(pings.recv_timeout(interval * 5).is_err());
+
+        // And start again once events flow
+        *daemon.last_event.lock().unwrap() = Some(clock.now());
+        assert_eq!(pings.recv_timeout(timeout).unwrap(), "WATCHDOG=1");
+    }
+
+    #[test]
+    fn delivers_events_and_reports_stalls() {
+        let start = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        let clock = ManualClock::new(start);
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let delivered = delivered.clone();
+            FnSink(move |event: &Event| {
+                delivered.lock().unwrap().push(event.title().to_string());
+                Ok(())
+            })
+        };
+        let mut daemon = Daemon::with_source(|| {
+            futures::stream::iter(vec![
+                testing::edit_event(1, "A", Utc::now()),
+                testing::edit_event(2, "B", Utc::now()),
+            ])
+        })
+        .with_clock(Arc::new(clock.clone()))
+        .stall_after(Duration::from_secs(10))
+        .subscribe(Subscription::new(
+            "all",
+            |event: &Event| event.title() != "B",
+            sink,
+        ));
+        let health = daemon.health();
+        clock.advance(Duration::from_secs(5));
+        block_on(daemon.run_once());
+        assert_eq!(*delivered.lock().unwrap(), vec!["A".to_string()]);
+        let counters = daemon.metrics().snapshot().counters;
+        assert_eq!(counters["daemon.events"], 2);
+        assert_eq!(counters["subscription.all.delivered"], 1);
+        // Measured from the last event, not from when the daemon started
+        clock.advance(Duration::from_secs(9));
+        assert!(health.is_healthy());
+        clock.advance(Duration::from_secs(1));
+        assert!(!health.is_healthy());
+    }
+}
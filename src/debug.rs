@@ -0,0 +1,133 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Dumps of internal state for bug reports
+//!
+//! [`EventStream::debug_snapshot()`](crate::EventStream::debug_snapshot)
+//! captures what a stream is connected to, how far it got, how full its
+//! queue is and what's listening to it, as a [`DebugSnapshot`] that
+//! serializes to JSON for attaching to a bug report. The daemon's
+//! [admin API](crate::admin) serves a [`DaemonSnapshot`] at `GET /debug`.
+//!
+//! Snapshots leave out request headers and other
+//! [client options](crate::backend::ClientOptions), so they don't leak
+//! credentials.
+use crate::health::Health;
+#[cfg(feature = "server")]
+use crate::metrics::MetricsSnapshot;
+use crate::queue::QueueStats;
+use crate::report::ShutdownReport;
+#[cfg(feature = "server")]
+use crate::subscription::SubscriptionDef;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Version of this crate, to include in reports
+pub(crate) const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Internal state of an [`EventStream`](crate::EventStream) at one point
+/// in time
+#[derive(Clone, Debug, Serialize)]
+pub struct DebugSnapshot {
+    pub taken_at: DateTime<Utc>,
+    /// Version of this crate
+    pub version: &'static str,
+    pub connection: ConnectionInfo,
+    /// Events and errors so far, including the resume position
+    pub report: ShutdownReport,
+    pub health: Health,
+    pub listeners: ListenerCounts,
+    /// Streams returned by [`errors()`](crate::EventStream::errors) that
+    /// are still being sent to
+    pub error_streams: usize,
+    /// With a [queue](crate::EventStreamBuilder::queue)
+    pub queue: Option<QueueInfo>,
+    pub keep_canaries: bool,
+    pub compaction_interval: Option<Duration>,
+}
+
+/// Where a stream connects to, see [`DebugSnapshot`]
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConnectionInfo {
+    /// URLs it was configured with, empty for streams that were not made
+    /// by an [`EventStreamBuilder`](crate::EventStreamBuilder)
+    pub endpoints: Vec<String>,
+    /// See [`EventStream::generation()`](crate::EventStream::generation)
+    pub generation: u64,
+    /// Whether the stream was shut down
+    pub closed: bool,
+}
+
+/// Number of listeners of each kind, see
+/// [`Listeners`](crate::listener::Listeners)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ListenerCounts {
+    pub events: usize,
+    pub connection: usize,
+    pub errors: usize,
+    pub canaries: usize,
+}
+
+/// How full a [`Queue`](crate::queue::Queue) is, see [`QueueStats`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct QueueInfo {
+    pub capacity: usize,
+    pub depth: usize,
+    pub max_depth: usize,
+    pub dropped_oldest: u64,
+    pub dropped_newest: u64,
+}
+
+impl From<&QueueStats> for QueueInfo {
+    fn from(stats: &QueueStats) -> Self {
+        Self {
+            capacity: stats.capacity(),
+            depth: stats.depth(),
+            max_depth: stats.max_depth(),
+            dropped_oldest: stats.dropped_oldest(),
+            dropped_newest: stats.dropped_newest(),
+        }
+    }
+}
+
+/// State of a running [`Daemon`](crate::daemon::Daemon), as served by the
+/// [admin API](crate::admin)
+#[cfg(feature = "server")]
+#[derive(Clone, Debug, Serialize)]
+pub struct DaemonSnapshot {
+    pub taken_at: DateTime<Utc>,
+    /// Version of this crate
+    pub version: &'static str,
+    /// Managed subscriptions, along with their filters
+    pub subscriptions: Vec<SubscriptionDef>,
+    pub metrics: MetricsSnapshot,
+}
+
+#[cfg(feature = "server")]
+impl DaemonSnapshot {
+    pub(crate) fn new(
+        subscriptions: Vec<SubscriptionDef>,
+        metrics: MetricsSnapshot,
+    ) -> Self {
+        Self {
+            taken_at: Utc::now(),
+            version: VERSION,
+            subscriptions,
+            metrics,
+        }
+    }
+}
@@ -0,0 +1,122 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Recognizing events that have already been seen
+use crate::Event;
+use std::collections::{HashSet, VecDeque};
+
+/// Default number of recent event IDs remembered by a [`DedupWindow`]
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DedupStatus {
+    /// Not seen within the dedup window
+    Unique,
+    /// Already seen within the dedup window
+    Duplicate,
+}
+
+/// Remembers the IDs (`meta.id`) of the most recent events
+#[derive(Clone, Debug)]
+pub struct DedupWindow {
+    capacity: usize,
+    seen: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl DedupWindow {
+    /// Remember the last `capacity` event IDs, or fewer in
+    /// [bounded mode](crate::metrics::set_bounded)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: crate::metrics::cap(capacity),
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Number of event IDs remembered
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Check whether the event was seen before, and remember it
+    pub fn check(&mut self, event: &Event) -> DedupStatus {
+        self.check_id(&event.meta().id)
+    }
+
+    /// Like [`check()`](Self::check), for an event ID (`meta.id`)
+    pub fn check_id(&mut self, id: &str) -> DedupStatus {
+        if self.seen.contains(id) {
+            return DedupStatus::Duplicate;
+        }
+        if self.capacity == 0 {
+            return DedupStatus::Unique;
+        }
+        if self.order.len() >= self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.to_string());
+        self.order.push_back(id.to_string());
+        DedupStatus::Unique
+    }
+
+    /// The remembered event IDs, oldest first
+    pub fn ids(&self) -> impl Iterator<Item = &str> {
+        self.order.iter().map(String::as_str)
+    }
+}
+
+impl Default for DedupWindow {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn recognizes_repeated_events() {
+        let mut window = DedupWindow::new(10);
+        let event = testing::edit_event(1, "A", at(0));
+        assert_eq!(window.check(&event), DedupStatus::Unique);
+        assert_eq!(window.check(&event), DedupStatus::Duplicate);
+        assert_eq!(window.ids().collect::<Vec<_>>(), vec!["id-1"]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_past_capacity() {
+        let mut window = DedupWindow::new(2);
+        for id in ["a", "b", "c"] {
+            assert_eq!(window.check_id(id), DedupStatus::Unique);
+        }
+        assert_eq!(window.ids().collect::<Vec<_>>(), vec!["b", "c"]);
+        assert_eq!(window.check_id("c"), DedupStatus::Duplicate);
+        assert_eq!(window.check_id("a"), DedupStatus::Unique);
+    }
+
+    #[test]
+    fn remembers_nothing_without_capacity() {
+        let mut window = DedupWindow::new(0);
+        assert_eq!(window.check_id("a"), DedupStatus::Unique);
+        assert_eq!(window.check_id("a"), DedupStatus::Unique);
+    }
+}
@@ -0,0 +1,303 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Periodic digests of matching events
+//!
+//! For notifications that can wait, like a watchlist, a [`DigestSink`]
+//! collects a subscription's events instead of passing each one on, and
+//! every so often hands them to a mail callback as a single [`Digest`],
+//! rendered as plain text and HTML.
+//!
+//! ```
+//! use eventstreams::digest::DigestSink;
+//! use eventstreams::subscription::Subscription;
+//! use std::time::Duration;
+//!
+//! let digests = DigestSink::new(
+//!     "watchlist",
+//!     Duration::from_secs(24 * 60 * 60),
+//!     |digest| {
+//!         // Send digest.subject(), digest.to_text() and digest.to_html()
+//!         // with the mail library of your choice
+//!         Ok(())
+//!     },
+//! );
+//! let subscription = Subscription::new(
+//!     "watchlist",
+//!     |event| event.title() == "Main Page",
+//!     digests.clone(),
+//! );
+//! ```
+use crate::clock::{Clock, SystemClock};
+use crate::sink::{self, escape, link, Sink, SinkError};
+use crate::Event;
+use chrono::{DateTime, Utc};
+use futures::future::{self, BoxFuture};
+use std::fmt;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Events collected for a subscription over one period
+#[derive(Clone, Debug)]
+pub struct Digest {
+    /// Name the [`DigestSink`] was created with
+    pub subscription: String,
+    /// When collecting started
+    pub start: DateTime<Utc>,
+    /// When the digest was made
+    pub end: DateTime<Utc>,
+    /// In the order they were received
+    pub events: Vec<Event>,
+}
+
+impl Digest {
+    /// Subject line, e.g. `watchlist: 3 changes`
+    pub fn subject(&self) -> String {
+        match self.events.len() {
+            1 => format!("{}: 1 change", self.subscription),
+            count => format!("{}: {} changes", self.subscription, count),
+        }
+    }
+
+    /// Plain-text body, one paragraph per event
+    pub fn to_text(&self) -> String {
+        let mut out = format!(
+            "{} between {} and {}\n",
+            self.subject(),
+            format_dt(self.start),
+            format_dt(self.end)
+        );
+        for event in &self.events {
+            let _ = write!(
+                out,
+                "\n{}  {}\n",
+                format_dt(event.dt()),
+                sink::summary(event)
+            );
+            if !event.comment().is_empty() {
+                let _ = writeln!(out, "    {}", event.comment());
+            }
+            if let Some(url) = link(event) {
+                let _ = writeln!(out, "    {}", url);
+            }
+        }
+        out
+    }
+
+    /// HTML body, with a list item per event
+    pub fn to_html(&self) -> String {
+        let mut out = format!(
+            "<p>{} between {} and {}</p>\n<ul>\n",
+            escape(&self.subject()),
+            format_dt(self.start),
+            format_dt(self.end)
+        );
+        for event in &self.events {
+            let summary = escape(&sink::summary(event));
+            let _ = write!(out, "<li>{} ", format_dt(event.dt()));
+            match link(event) {
+                Some(url) => {
+                    let _ = write!(
+                        out,
+                        "<a href=\"{}\">{}</a>",
+                        escape(&url),
+                        summary
+                    );
+                }
+                None => out.push_str(&summary),
+            }
+            if !event.comment().is_empty() {
+                let _ = write!(out, "<br><i>{}</i>", escape(event.comment()));
+            }
+            out.push_str("</li>\n");
+        }
+        out.push_str("</ul>\n");
+        out
+    }
+}
+
+fn format_dt(dt: DateTime<Utc>) -> impl fmt::Display {
+    dt.format("%Y-%m-%d %H:%M UTC")
+}
+
+type Mailer = dyn FnMut(&Digest) -> Result<(), SinkError> + Send;
+
+#[derive(Debug)]
+struct State {
+    start: DateTime<Utc>,
+    events: Vec<Event>,
+}
+
+/// Collects events into a [`Digest`] and passes it to a mail callback
+/// every `interval`. Clones share the same events, so a clone can be kept
+/// to [`flush()`](Self::flush) after handing the sink to a
+/// [`Subscription`](crate::subscription::Subscription).
+#[derive(Clone)]
+pub struct DigestSink {
+    name: String,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<State>>,
+    mailer: Arc<Mutex<Mailer>>,
+}
+
+impl DigestSink {
+    /// Send a digest named `name` to `mailer` at most every `interval`
+    pub fn new(
+        name: impl Into<String>,
+        interval: Duration,
+        mailer: impl FnMut(&Digest) -> Result<(), SinkError> + Send + 'static,
+    ) -> Self {
+        Self::with_clock(name, interval, Arc::new(SystemClock), mailer)
+    }
+
+    pub fn with_clock(
+        name: impl Into<String>,
+        interval: Duration,
+        clock: Arc<dyn Clock>,
+        mailer: impl FnMut(&Digest) -> Result<(), SinkError> + Send + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            interval,
+            state: Arc::new(Mutex::new(State {
+                start: clock.now(),
+                events: vec![],
+            })),
+            clock,
+            mailer: Arc::new(Mutex::new(mailer)),
+        }
+    }
+
+    /// Number of events waiting for the next digest
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().events.len()
+    }
+
+    /// Whether `interval` has passed since the last digest
+    pub fn is_due(&self) -> bool {
+        let interval = chrono::Duration::from_std(self.interval)
+            .unwrap_or(chrono::Duration::MAX);
+        self.clock.now() - self.state.lock().unwrap().start >= interval
+    }
+
+    /// Send a digest of everything collected so far, unless there's
+    /// nothing to send. Digests are otherwise only sent as events arrive,
+    /// so call this from a timer to not hold on to events while a
+    /// subscription is quiet, and before shutting down. If the callback
+    /// fails, the events are kept for the next digest.
+    pub fn flush(&self) -> Result<(), SinkError> {
+        let now = self.clock.now();
+        let digest = {
+            let mut state = self.state.lock().unwrap();
+            if state.events.is_empty() {
+                state.start = now;
+                return Ok(());
+            }
+            Digest {
+                subscription: self.name.clone(),
+                start: std::mem::replace(&mut state.start, now),
+                end: now,
+                events: std::mem::take(&mut state.events),
+            }
+        };
+        let mut mailer = self.mailer.lock().unwrap();
+        if let Err(err) = mailer(&digest) {
+            let mut state = self.state.lock().unwrap();
+            state.start = digest.start;
+            let newer = std::mem::replace(&mut state.events, digest.events);
+            state.events.extend(newer);
+            return Err(err);
+        }
+        Ok(())
+    }
+}
+
+impl Sink for DigestSink {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        self.state.lock().unwrap().events.push(event.clone());
+        let sent = if self.is_due() { self.flush() } else { Ok(()) };
+        Box::pin(future::ready(sent))
+    }
+}
+
+impl fmt::Debug for DigestSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DigestSink")
+            .field("name", &self.name)
+            .field("interval", &self.interval)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    #[test]
+    fn sends_a_digest_every_interval() {
+        let clock = ManualClock::new(at(0));
+        let digests = Arc::new(Mutex::new(vec![]));
+        let mailed = digests.clone();
+        let mut sink = DigestSink::with_clock(
+            "watchlist",
+            Duration::from_secs(3600),
+            Arc::new(clock.clone()),
+            move |digest: &Digest| {
+                mailed.lock().unwrap().push(digest.clone());
+                Ok(())
+            },
+        );
+        let edit = testing::edit_event(1, "Q&A", at(0));
+        let upload = testing::event(&testing::log(2, "File:A.png", at(60)));
+        block_on(sink.send(&edit)).unwrap();
+        assert_eq!(sink.pending(), 1);
+        assert!(digests.lock().unwrap().is_empty());
+        clock.advance(Duration::from_secs(3600));
+        block_on(sink.send(&upload)).unwrap();
+        assert_eq!(sink.pending(), 0);
+
+        let digests = digests.lock().unwrap();
+        assert_eq!(digests.len(), 1);
+        let digest = &digests[0];
+        assert_eq!((digest.start, digest.end), (at(0), at(3600)));
+        assert_eq!(digest.subject(), "watchlist: 2 changes");
+        let text = digest.to_text();
+        assert!(text.starts_with(
+            "watchlist: 2 changes between 2021-01-01 00:00 UTC and \
+             2021-01-01 01:00 UTC\n"
+        ));
+        assert!(text.contains("00:00 UTC  en.wikipedia.org: Alice edited Q&A"));
+        assert!(text.contains("    Fixed a typo\n"));
+        let html = digest.to_html();
+        assert!(html.contains("Alice edited Q&amp;A</a>"));
+        assert!(html.contains(
+            "<li>2021-01-01 00:01 UTC en.wikipedia.org: Alice performed \
+             upload/upload on File:A.png<br><i>Fixed a typo</i></li>"
+        ));
+        // Nothing to send
+        sink.flush().unwrap();
+        assert_eq!(digests.len(), 1);
+    }
+}
@@ -0,0 +1,268 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Noticing when upstream payloads change shape
+//!
+//! EventStreams schemas evolve, and a field that disappears upstream
+//! usually only shows up as deserialization errors once it's too late.
+//! [`DriftDetector`] learns which fields each stream's messages have, then
+//! reports a [`SchemaDrift`] when a new field shows up or an expected one
+//! stops appearing. With
+//! [`EventStreamBuilder::detect_schema_drift()`](crate::EventStreamBuilder::detect_schema_drift)
+//! these arrive as [`EventStreamError::SchemaDrift`](crate::EventStreamError::SchemaDrift),
+//! so they reach error listeners and [metrics](crate::EventStream::instrument).
+use serde_json::{Map, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+
+/// Messages per stream and type to learn the fields from
+const DEFAULT_WARMUP: u64 = 100;
+/// Consecutive messages an expected field must be missing from
+const DEFAULT_MISSING_AFTER: u64 = 100;
+/// How deep to look into nested objects
+const DEFAULT_DEPTH: usize = 2;
+
+/// How a field changed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriftKind {
+    /// The field wasn't seen before
+    Added,
+    /// The field was in every message but has stopped appearing
+    Missing,
+}
+
+/// A field that appeared or vanished in a stream's messages
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaDrift {
+    /// Name of the stream, e.g. `mediawiki.recentchange`
+    pub stream: String,
+    /// `type` of the messages, for streams that mix several, e.g. `edit`
+    pub event_type: Option<String>,
+    /// Path to the field, e.g. `meta.domain` or `length.new`
+    pub field: String,
+    pub kind: DriftKind,
+}
+
+impl fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            DriftKind::Added => "new field",
+            DriftKind::Missing => "missing field",
+        };
+        write!(f, "{} {} in {}", kind, self.field, self.stream)?;
+        if let Some(event_type) = &self.event_type {
+            write!(f, " ({})", event_type)?;
+        }
+        Ok(())
+    }
+}
+
+/// What's known about one stream and type
+#[derive(Clone, Debug, Default)]
+struct Shape {
+    messages: u64,
+    /// Every field seen so far
+    known: BTreeSet<String>,
+    /// Fields in every message during warmup, and how many messages in a
+    /// row each has been missing from since
+    expected: HashMap<String, u64>,
+}
+
+/// Learns the fields of each stream, and reports changes
+#[derive(Clone, Debug)]
+pub struct DriftDetector {
+    warmup: u64,
+    missing_after: u64,
+    depth: usize,
+    ignored: Vec<String>,
+    shapes: HashMap<(String, Option<String>), Shape>,
+}
+
+impl DriftDetector {
+    pub fn new() -> Self {
+        Self {
+            warmup: DEFAULT_WARMUP,
+            missing_after: DEFAULT_MISSING_AFTER,
+            depth: DEFAULT_DEPTH,
+            ignored: vec!["log_params".to_string()],
+            shapes: HashMap::new(),
+        }
+    }
+
+    /// Learn the fields from the first `warmup` messages of each stream and
+    /// type (default 100, at least 1) before reporting anything
+    pub fn warmup(mut self, warmup: u64) -> Self {
+        self.warmup = warmup.max(1);
+        self
+    }
+
+    /// Report an expected field as missing once it's been absent from
+    /// `messages` messages in a row (default 100)
+    pub fn missing_after(mut self, messages: u64) -> Self {
+        self.missing_after = messages;
+        self
+    }
+
+    /// Look `depth` levels into nested objects (default 2, e.g.
+    /// `meta.domain` but not `meta.domain.x`)
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Don't look inside the object at `field`, e.g. because its keys vary
+    /// between messages. `log_params` is ignored by default.
+    pub fn ignore(mut self, field: &str) -> Self {
+        self.ignored.push(field.to_string());
+        self
+    }
+
+    /// Check a raw message, returning any changes in its stream's fields.
+    /// Messages that aren't JSON objects with a `meta.stream` are skipped.
+    pub fn observe_raw(&mut self, data: &str) -> Vec<SchemaDrift> {
+        match serde_json::from_str(data) {
+            Ok(Value::Object(object)) => self.observe(&object),
+            _ => vec![],
+        }
+    }
+
+    /// Check a parsed message, see [`observe_raw()`](Self::observe_raw)
+    pub fn observe(
+        &mut self,
+        message: &Map<String, Value>,
+    ) -> Vec<SchemaDrift> {
+        let stream = match message
+            .get("meta")
+            .and_then(|meta| meta.get("stream"))
+            .and_then(Value::as_str)
+        {
+            Some(stream) => stream.to_string(),
+            None => return vec![],
+        };
+        let event_type = message
+            .get("type")
+            .and_then(Value::as_str)
+            .map(str::to_string);
+        let mut fields = BTreeSet::new();
+        self.collect(message, "", 1, &mut fields);
+        let drift = |field: &str, kind| SchemaDrift {
+            stream: stream.clone(),
+            event_type: event_type.clone(),
+            field: field.to_string(),
+            kind,
+        };
+        let shape = self
+            .shapes
+            .entry((stream.clone(), event_type.clone()))
+            .or_default();
+        shape.messages += 1;
+        let mut changes = vec![];
+        if shape.messages == 1 {
+            shape.expected = fields.iter().map(|f| (f.clone(), 0)).collect();
+        }
+        if shape.messages <= self.warmup {
+            shape.expected.retain(|field, _| fields.contains(field));
+        } else {
+            for field in fields.difference(&shape.known) {
+                changes.push(drift(field, DriftKind::Added));
+            }
+            for (field, absent) in shape.expected.iter_mut() {
+                if fields.contains(field) {
+                    *absent = 0;
+                    continue;
+                }
+                *absent += 1;
+                if *absent == self.missing_after {
+                    changes.push(drift(field, DriftKind::Missing));
+                }
+            }
+        }
+        shape.known.extend(fields);
+        changes
+    }
+
+    fn collect(
+        &self,
+        object: &Map<String, Value>,
+        prefix: &str,
+        depth: usize,
+        fields: &mut BTreeSet<String>,
+    ) {
+        for (key, value) in object {
+            let path = format!("{}{}", prefix, key);
+            if let Value::Object(nested) = value {
+                if depth < self.depth && !self.ignored.contains(&path) {
+                    self.collect(
+                        nested,
+                        &format!("{}.", path),
+                        depth + 1,
+                        fields,
+                    );
+                }
+            }
+            fields.insert(path);
+        }
+    }
+}
+
+impl Default for DriftDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn reports_added_and_missing_fields_after_warmup() {
+        let mut detector = DriftDetector::new().warmup(2).missing_after(2);
+        let mut edit = testing::edit(1, "A", at(0));
+        let mut observe = |message: &serde_json::Value| {
+            detector.observe_raw(&message.to_string())
+        };
+        assert!(observe(&edit).is_empty());
+        // Only fields in every warmup message are expected
+        edit.as_object_mut().unwrap().remove("minor");
+        assert!(observe(&edit).is_empty());
+        assert!(observe(&testing::log(3, "File:A.png", at(0))).is_empty());
+
+        edit["length"]["diff"] = 10.into();
+        edit.as_object_mut().unwrap().remove("patrolled");
+        let changes = observe(&edit);
+        assert_eq!(
+            changes,
+            [SchemaDrift {
+                stream: "mediawiki.recentchange".to_string(),
+                event_type: Some("edit".to_string()),
+                field: "length.diff".to_string(),
+                kind: DriftKind::Added,
+            }]
+        );
+        assert_eq!(
+            changes[0].to_string(),
+            "new field length.diff in mediawiki.recentchange (edit)"
+        );
+        let changes = observe(&edit);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].field, "patrolled");
+        assert_eq!(changes[0].kind, DriftKind::Missing);
+        assert!(observe(&edit).is_empty());
+        assert!(observe(&serde_json::json!({ "meta": {} })).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Accounting for events that were dropped
+//!
+//! Anything that has to drop events records it with a [`DropLogger`],
+//! which aggregates drops by reason and periodically logs a summary using
+//! the [`log`] crate (target `eventstreams::drops`), with `reason`, `count`
+//! and `wikis` as structured fields.
+use crate::clock::{Clock, SystemClock};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Events dropped for a single reason since the last report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DropReport {
+    pub reason: String,
+    pub count: u64,
+    /// Number of events dropped per wiki (`server_name`), where known
+    pub wikis: BTreeMap<String, u64>,
+}
+
+#[derive(Debug)]
+struct State {
+    last_report: DateTime<Utc>,
+    pending: BTreeMap<String, DropReport>,
+}
+
+/// Aggregates and periodically logs dropped events. Clones share the same
+/// state.
+#[derive(Clone, Debug)]
+pub struct DropLogger {
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+    state: Arc<Mutex<State>>,
+}
+
+impl DropLogger {
+    /// Log a summary at most every `interval`
+    pub fn new(interval: Duration) -> Self {
+        Self::with_clock(interval, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(interval: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            interval,
+            state: Arc::new(Mutex::new(State {
+                last_report: clock.now(),
+                pending: BTreeMap::new(),
+            })),
+            clock,
+        }
+    }
+
+    /// Record a dropped event, logging a summary if it's time to
+    pub fn record(&self, reason: &str, wiki: Option<&str>) {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            let report = state
+                .pending
+                .entry(reason.to_string())
+                .or_insert_with(|| DropReport {
+                    reason: reason.to_string(),
+                    count: 0,
+                    wikis: BTreeMap::new(),
+                });
+            report.count += 1;
+            if let Some(wiki) = wiki {
+                *report.wikis.entry(wiki.to_string()).or_default() += 1;
+            }
+            let interval = chrono::Duration::from_std(self.interval)
+                .unwrap_or(chrono::Duration::MAX);
+            self.clock.now() - state.last_report >= interval
+        };
+        if due {
+            self.flush();
+        }
+    }
+
+    /// Log and return everything dropped since the last report
+    pub fn flush(&self) -> Vec<DropReport> {
+        let reports: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            state.last_report = self.clock.now();
+            std::mem::take(&mut state.pending).into_values().collect()
+        };
+        for report in &reports {
+            log::warn!(
+                target: "eventstreams::drops",
+                reason = report.reason.as_str(),
+                count = report.count,
+                wikis:? = report.wikis;
+                "dropped {} events: {}",
+                report.count,
+                &report.reason
+            );
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::at;
+
+    #[test]
+    fn aggregates_drops_by_reason_and_wiki() {
+        let logger = DropLogger::new(Duration::from_secs(60));
+        logger.record("queue full", Some("en.wikipedia.org"));
+        logger.record("queue full", Some("en.wikipedia.org"));
+        logger.record("queue full", None);
+        logger.record("stale", Some("de.wikipedia.org"));
+        let reports = logger.flush();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].reason, "queue full");
+        assert_eq!(reports[0].count, 3);
+        assert_eq!(reports[0].wikis["en.wikipedia.org"], 2);
+        assert_eq!(reports[1].reason, "stale");
+        assert!(logger.flush().is_empty());
+    }
+
+    #[test]
+    fn reports_once_the_interval_has_passed() {
+        let clock = ManualClock::new(at(0));
+        let logger = DropLogger::with_clock(
+            Duration::from_secs(60),
+            Arc::new(clock.clone()),
+        );
+        logger.record("stale", None);
+        clock.advance(Duration::from_secs(60));
+        // Logged and cleared by this one
+        logger.record("stale", None);
+        assert!(logger.flush().is_empty());
+    }
+}
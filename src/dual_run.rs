@@ -0,0 +1,204 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Comparing an old and a new filter on the same events
+//!
+//! Before switching a subscription to a new [`Filter`], a [`DualRun`]
+//! evaluates both side by side on live traffic and reports where they
+//! diverge, i.e. events matched by one but not the other. Subscriptions
+//! can run one for a [candidate](crate::subscription::Subscription::candidate)
+//! filter while still delivering according to their current one.
+use crate::filter::{Explanation, Filter};
+use crate::Event;
+use std::collections::VecDeque;
+
+/// Default number of divergent events kept as samples
+const DEFAULT_SAMPLES: usize = 100;
+
+/// Which of the two filters matched a divergent event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchedBy {
+    Old,
+    New,
+}
+
+/// An event matched by only one of the filters
+#[derive(Clone, Debug)]
+pub struct Divergence {
+    pub event: Event,
+    pub matched_by: MatchedBy,
+    /// Why the old filter did or didn't match
+    pub old: Explanation,
+    /// Why the new filter did or didn't match
+    pub new: Explanation,
+}
+
+/// How often the two filters agreed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DualRunCounts {
+    /// Events matched by both filters
+    pub both: u64,
+    /// Events only the old filter matched, which would stop being
+    /// delivered
+    pub old_only: u64,
+    /// Events only the new filter matched, which would start being
+    /// delivered
+    pub new_only: u64,
+    /// Events matched by neither
+    pub neither: u64,
+}
+
+impl DualRunCounts {
+    /// Events matched by only one of the filters
+    pub fn divergent(&self) -> u64 {
+        self.old_only + self.new_only
+    }
+
+    /// Share of events matched by either filter that both matched, or 1
+    /// if neither has matched anything yet
+    pub fn agreement(&self) -> f64 {
+        let matched = self.both + self.divergent();
+        if matched == 0 {
+            1.0
+        } else {
+            self.both as f64 / matched as f64
+        }
+    }
+}
+
+/// Evaluates two filters side by side, see the [module documentation](self)
+#[derive(Clone, Debug)]
+pub struct DualRun {
+    old: Filter,
+    new: Filter,
+    counts: DualRunCounts,
+    samples: VecDeque<Divergence>,
+    max_samples: usize,
+}
+
+impl DualRun {
+    /// Compare `old` and `new`, keeping the last 100 divergent events
+    pub fn new(old: Filter, new: Filter) -> Self {
+        Self {
+            old,
+            new,
+            counts: DualRunCounts::default(),
+            samples: VecDeque::new(),
+            max_samples: DEFAULT_SAMPLES,
+        }
+    }
+
+    /// Keep the last `max` divergent events instead
+    pub fn max_samples(mut self, max: usize) -> Self {
+        self.max_samples = max;
+        self.samples.truncate(max);
+        self
+    }
+
+    pub fn old(&self) -> &Filter {
+        &self.old
+    }
+
+    pub fn new_filter(&self) -> &Filter {
+        &self.new
+    }
+
+    /// Evaluate both filters on `event`, returning the divergence if only
+    /// one matched
+    pub fn push(&mut self, event: &Event) -> Option<&Divergence> {
+        let matched_by =
+            match (self.old.matches(event), self.new.matches(event)) {
+                (true, true) => {
+                    self.counts.both += 1;
+                    return None;
+                }
+                (false, false) => {
+                    self.counts.neither += 1;
+                    return None;
+                }
+                (true, false) => {
+                    self.counts.old_only += 1;
+                    MatchedBy::Old
+                }
+                (false, true) => {
+                    self.counts.new_only += 1;
+                    MatchedBy::New
+                }
+            };
+        if self.max_samples == 0 {
+            return None;
+        }
+        if self.samples.len() == self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(Divergence {
+            event: event.clone(),
+            matched_by,
+            old: self.old.explain(event),
+            new: self.new.explain(event),
+        });
+        self.samples.back()
+    }
+
+    pub fn counts(&self) -> DualRunCounts {
+        self.counts
+    }
+
+    /// The most recent divergent events, oldest first
+    pub fn samples(&self) -> impl Iterator<Item = &Divergence> {
+        self.samples.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn counts_agreement_and_samples_divergences() {
+        let mut dual_run = DualRun::new(
+            Filter::new().titles(["A", "B"]),
+            Filter::new().titles(["B", "C"]),
+        )
+        .max_samples(1);
+        let edit = |offset, title| testing::edit_event(offset, title, at(0));
+        assert_eq!(dual_run.counts().agreement(), 1.0);
+        assert!(dual_run.push(&edit(1, "B")).is_none());
+        assert!(dual_run.push(&edit(2, "D")).is_none());
+        let divergence = dual_run.push(&edit(3, "A")).unwrap();
+        assert_eq!(divergence.matched_by, MatchedBy::Old);
+        assert!(divergence.old.matched);
+        assert!(!divergence.new.matched);
+        let divergence = dual_run.push(&edit(4, "C")).unwrap();
+        assert_eq!(divergence.matched_by, MatchedBy::New);
+        assert_eq!(
+            dual_run.counts(),
+            DualRunCounts {
+                both: 1,
+                old_only: 1,
+                new_only: 1,
+                neither: 1,
+            }
+        );
+        assert_eq!(dual_run.counts().divergent(), 2);
+        assert!((dual_run.counts().agreement() - 1.0 / 3.0).abs() < 1e-9);
+        // Only the most recent divergence is kept
+        let samples: Vec<_> = dual_run.samples().collect();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].matched_by, MatchedBy::New);
+    }
+}
@@ -0,0 +1,448 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Provenance details for delivered events
+//!
+//! An [`Envelope`] records when an event was received next to when it
+//! happened, and whether it was [replayed](Envelope::replayed) from
+//! history after resuming, so pipelines mixing live and replayed events
+//! can tell how recent their data is. [`Envelope::timestamps()`] formats
+//! all of these together, e.g. for attaching to output records. Edits
+//! can also be [classified](Enveloper::classifier) on the way.
+use crate::backend::BackendError;
+use crate::classify::{Classification, Classifier};
+use crate::clock::{Clock, SystemClock};
+use crate::dedup::{self, DedupStatus, DedupWindow};
+use crate::side_output::SideOutput;
+use crate::{Event, EventStreamError};
+use async_stream::stream;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// An event along with where and when it was received
+#[derive(Clone, Debug)]
+pub struct Envelope {
+    pub event: Event,
+    /// When the event was received
+    pub received_at: DateTime<Utc>,
+    /// Number of times the backend had reconnected when the event was
+    /// received, starting at 0
+    pub generation: u64,
+    /// Which kind of backend the event came from, e.g. `live`
+    pub backend: Arc<str>,
+    /// Whether the event had already been delivered recently, e.g. because
+    /// it was sent again after a reconnect
+    pub dedup: DedupStatus,
+    /// Whether the event happened before the stream was started, so it
+    /// was replayed from history rather than received live. Always false
+    /// unless the [`Enveloper`] was told it's
+    /// [resuming](Enveloper::resuming).
+    pub replayed: bool,
+    /// What kind of edit the event is, if it's an edit or page creation
+    /// and the [`Enveloper`] has a [classifier](Enveloper::classifier)
+    pub classification: Option<Classification>,
+}
+
+impl Envelope {
+    /// When the event happened, going by `meta.dt`
+    pub fn event_time(&self) -> DateTime<Utc> {
+        self.event.dt()
+    }
+
+    /// How long after it happened the event was received
+    pub fn lag(&self) -> Duration {
+        self.received_at - self.event_time()
+    }
+
+    /// Receive time, event time and replay status, with times in `format`
+    pub fn timestamps(&self, format: TimestampFormat) -> Timestamps {
+        Timestamps {
+            received_at: format.format(self.received_at),
+            event_time: format.format(self.event_time()),
+            lag_ms: self.lag().num_milliseconds(),
+            replayed: self.replayed,
+        }
+    }
+}
+
+/// How [`Timestamps`] represents times
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestampFormat {
+    /// RFC 3339 in UTC with millisecond precision, e.g.
+    /// `2021-01-01T12:00:00.000Z`
+    #[default]
+    Rfc3339,
+    /// Seconds since the Unix epoch
+    UnixSeconds,
+    /// Milliseconds since the Unix epoch
+    UnixMillis,
+}
+
+impl TimestampFormat {
+    pub fn format(self, dt: DateTime<Utc>) -> Timestamp {
+        match self {
+            TimestampFormat::Rfc3339 => {
+                Timestamp::Text(dt.to_rfc3339_opts(SecondsFormat::Millis, true))
+            }
+            TimestampFormat::UnixSeconds => Timestamp::Number(dt.timestamp()),
+            TimestampFormat::UnixMillis => {
+                Timestamp::Number(dt.timestamp_millis())
+            }
+        }
+    }
+}
+
+/// A time formatted with a [`TimestampFormat`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(untagged)]
+pub enum Timestamp {
+    Text(String),
+    Number(i64),
+}
+
+impl std::fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timestamp::Text(text) => write!(f, "{}", text),
+            Timestamp::Number(number) => write!(f, "{}", number),
+        }
+    }
+}
+
+/// Timing details of an [`Envelope`]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Timestamps {
+    pub received_at: Timestamp,
+    pub event_time: Timestamp,
+    /// See [`Envelope::lag()`]
+    pub lag_ms: i64,
+    pub replayed: bool,
+}
+
+/// Duplicates seen per connection generation, shared with the stream
+/// created by an [`Enveloper`]
+#[derive(Clone, Debug, Default)]
+pub struct DedupStats {
+    capacity: usize,
+    per_generation: Arc<Mutex<BTreeMap<u64, u64>>>,
+}
+
+impl DedupStats {
+    /// Number of recent event IDs remembered for recognizing duplicates
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of duplicates seen on each connection generation. After a
+    /// reconnect, some duplicates are expected since the server resends a
+    /// few events; none at all may mean events were skipped.
+    pub fn per_generation(&self) -> BTreeMap<u64, u64> {
+        self.per_generation.lock().unwrap().clone()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.per_generation.lock().unwrap().values().sum()
+    }
+}
+
+/// Parses events from a backend, wrapping each one in an [`Envelope`]
+pub struct Enveloper {
+    backend_name: Arc<str>,
+    side_output: Option<SideOutput>,
+    clock: Arc<dyn Clock>,
+    suppress_duplicates: bool,
+    resuming: bool,
+    keep_canaries: bool,
+    classifier: Option<Arc<dyn Classifier>>,
+    stats: DedupStats,
+}
+
+impl Enveloper {
+    /// `backend_name` describes the kind of backend, e.g. `live`
+    pub fn new(backend_name: &str) -> Self {
+        Self {
+            backend_name: backend_name.into(),
+            side_output: None,
+            clock: Arc::new(SystemClock),
+            suppress_duplicates: false,
+            resuming: false,
+            keep_canaries: false,
+            classifier: None,
+            stats: DedupStats {
+                capacity: crate::metrics::cap(dedup::DEFAULT_CAPACITY),
+                per_generation: Default::default(),
+            },
+        }
+    }
+
+    /// Send events that can't be parsed to `side_output`
+    pub fn side_output(mut self, side_output: SideOutput) -> Self {
+        self.side_output = Some(side_output);
+        self
+    }
+
+    /// Clock used for receive times
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Remember the last `capacity` event IDs for recognizing duplicates
+    /// (default [`dedup::DEFAULT_CAPACITY`]), or fewer in
+    /// [bounded mode](crate::metrics::set_bounded)
+    pub fn dedup_capacity(mut self, capacity: usize) -> Self {
+        self.stats.capacity = crate::metrics::cap(capacity);
+        self
+    }
+
+    /// Drop duplicates instead of delivering them marked as
+    /// [`DedupStatus::Duplicate`]. They are still counted in
+    /// [`DedupStats`].
+    pub fn suppress_duplicates(mut self, suppress: bool) -> Self {
+        self.suppress_duplicates = suppress;
+        self
+    }
+
+    /// Whether `backend` continues from an earlier position, e.g. one
+    /// created with [`backend::resume()`](crate::backend::resume). If so,
+    /// events that happened before the stream was started are marked as
+    /// [replayed](Envelope::replayed).
+    pub fn resuming(mut self, resuming: bool) -> Self {
+        self.resuming = resuming;
+        self
+    }
+
+    /// Deliver [canary events](crate::Event::is_canary) too, which are
+    /// skipped by default
+    pub fn keep_canaries(mut self, keep: bool) -> Self {
+        self.keep_canaries = keep;
+        self
+    }
+
+    /// Classify edits and page creations with `classifier`, see
+    /// [`classify`](crate::classify)
+    pub fn classifier(mut self, classifier: impl Classifier + 'static) -> Self {
+        self.classifier = Some(Arc::new(classifier));
+        self
+    }
+
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.stats.clone()
+    }
+
+    /// Start parsing events from `backend`
+    pub fn wrap(
+        self,
+        backend: impl Stream<Item = Result<String, BackendError>>,
+    ) -> impl Stream<Item = Envelope> {
+        let side_output = self.side_output.clone();
+        self.wrap_events(
+            crate::parse_backend(backend, side_output)
+                .map(|message| message.map_err(EventStreamError::Backend)),
+        )
+    }
+
+    /// Wrap events that have already been parsed, e.g. by
+    /// [`EventStreamBuilder`](crate::EventStreamBuilder)
+    pub(crate) fn wrap_events(
+        self,
+        events: impl Stream<Item = Result<Event, EventStreamError>>,
+    ) -> impl Stream<Item = Envelope> {
+        let mut dedup = DedupWindow::new(self.stats.capacity);
+        let mut generation = 0;
+        stream! {
+            let started_at = self.clock.now();
+            for await message in events {
+                match message {
+                    Ok(event) if !self.keep_canaries && event.is_canary() => {}
+                    Ok(event) => {
+                        let status = dedup.check(&event);
+                        if status == DedupStatus::Duplicate {
+                            *self
+                                .stats
+                                .per_generation
+                                .lock()
+                                .unwrap()
+                                .entry(generation)
+                                .or_default() += 1;
+                            if self.suppress_duplicates {
+                                continue;
+                            }
+                        }
+                        let replayed =
+                            self.resuming && event.dt() < started_at;
+                        let classification = match (&self.classifier, &event) {
+                            (
+                                Some(classifier),
+                                Event::Edit(edit) | Event::New(edit),
+                            ) => classifier.classify(&edit.comment, edit),
+                            _ => None,
+                        };
+                        yield Envelope {
+                            event,
+                            received_at: self.clock.now(),
+                            generation,
+                            backend: self.backend_name.clone(),
+                            dedup: status,
+                            replayed,
+                            classification,
+                        };
+                    }
+                    // Every backend error means it's going to reconnect
+                    Err(EventStreamError::Backend(_)) => generation += 1,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use futures::stream;
+
+    fn messages(offsets: &[u64]) -> Vec<Result<String, BackendError>> {
+        offsets
+            .iter()
+            .map(|offset| Ok(testing::edit(*offset, "A", at(0)).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn records_when_and_where_events_were_received() {
+        let clock = ManualClock::new(at(5));
+        let mut messages = messages(&[1]);
+        messages.push(Err(BackendError::Disconnected));
+        messages.extend(self::messages(&[2]));
+        let envelopes: Vec<_> = block_on(
+            Enveloper::new("test")
+                .clock(Arc::new(clock))
+                .wrap(stream::iter(messages))
+                .collect(),
+        );
+        assert_eq!(envelopes.len(), 2);
+        assert_eq!(&*envelopes[0].backend, "test");
+        assert_eq!(envelopes[0].received_at, at(5));
+        assert_eq!(envelopes[0].lag(), Duration::seconds(5));
+        assert_eq!(envelopes[0].generation, 0);
+        assert_eq!(envelopes[1].generation, 1);
+        assert!(!envelopes[0].replayed);
+    }
+
+    #[test]
+    fn marks_duplicates_per_generation() {
+        let mut messages = messages(&[1, 2, 1]);
+        messages.push(Err(BackendError::Disconnected));
+        messages.extend(self::messages(&[2]));
+        let enveloper = Enveloper::new("test");
+        let stats = enveloper.dedup_stats();
+        let envelopes: Vec<_> =
+            block_on(enveloper.wrap(stream::iter(messages)).collect());
+        let statuses: Vec<_> =
+            envelopes.iter().map(|envelope| envelope.dedup).collect();
+        assert_eq!(
+            statuses,
+            [
+                DedupStatus::Unique,
+                DedupStatus::Unique,
+                DedupStatus::Duplicate,
+                DedupStatus::Duplicate,
+            ]
+        );
+        assert_eq!(stats.total(), 2);
+        assert_eq!(stats.per_generation().get(&0), Some(&1));
+        assert_eq!(stats.per_generation().get(&1), Some(&1));
+    }
+
+    #[test]
+    fn suppresses_duplicates() {
+        let enveloper = Enveloper::new("test").suppress_duplicates(true);
+        let envelopes: Vec<_> = block_on(
+            enveloper.wrap(stream::iter(messages(&[1, 1, 2]))).collect(),
+        );
+        assert_eq!(envelopes.len(), 2);
+    }
+
+    #[test]
+    fn drops_canaries_unless_kept() {
+        let mut canary = testing::edit(2, "A", at(0));
+        canary["meta"]["domain"] = "canary".into();
+        let mut messages = messages(&[1]);
+        messages.push(Ok(canary.to_string()));
+        for keep in [false, true] {
+            let envelopes: Vec<_> = block_on(
+                Enveloper::new("test")
+                    .keep_canaries(keep)
+                    .wrap(stream::iter(messages.clone()))
+                    .collect(),
+            );
+            let canaries = envelopes
+                .iter()
+                .filter(|envelope| envelope.event.is_canary());
+            assert_eq!(canaries.count(), usize::from(keep));
+        }
+    }
+
+    #[test]
+    fn marks_replayed_events_and_formats_timestamps() {
+        let clock = Arc::new(ManualClock::new(at(5)));
+        let messages = vec![
+            Ok(testing::edit(1, "A", at(0)).to_string()),
+            Ok(testing::edit(2, "A", at(5)).to_string()),
+        ];
+        let envelopes: Vec<_> = block_on(
+            Enveloper::new("test")
+                .clock(clock)
+                .resuming(true)
+                .wrap(stream::iter(messages))
+                .collect(),
+        );
+        assert!(envelopes[0].replayed);
+        assert!(!envelopes[1].replayed);
+        assert_eq!(
+            envelopes[0].timestamps(TimestampFormat::Rfc3339),
+            Timestamps {
+                received_at: Timestamp::Text(
+                    "2021-01-01T00:00:05.000Z".to_string()
+                ),
+                event_time: Timestamp::Text(
+                    "2021-01-01T00:00:00.000Z".to_string()
+                ),
+                lag_ms: 5000,
+                replayed: true,
+            }
+        );
+        let millis = envelopes[1].timestamps(TimestampFormat::UnixMillis);
+        assert_eq!(
+            serde_json::to_value(&millis).unwrap(),
+            serde_json::json!({
+                "received_at": 1_609_459_205_000_i64,
+                "event_time": 1_609_459_205_000_i64,
+                "lag_ms": 0,
+                "replayed": false,
+            })
+        );
+        let seconds = TimestampFormat::UnixSeconds.format(at(1));
+        assert_eq!(seconds.to_string(), "1609459201");
+    }
+}
@@ -0,0 +1,149 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::backend::BackendError;
+use crate::drift::SchemaDrift;
+use crate::gaps::GapDetected;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::TransportError;
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// Something that went wrong while streaming events. None of these end
+/// the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventStreamError {
+    /// The connection failed or was lost, and is being retried
+    Backend(BackendError),
+    /// A message couldn't be parsed
+    Malformed { data: String, reason: String },
+    /// A message was cut off partway through, e.g. by a dropped
+    /// connection
+    Truncated { data: String },
+    /// Events were skipped, e.g. across a reconnect
+    Gap(GapDetected),
+    /// A field appeared or vanished in a stream's messages, see
+    /// [`drift`](crate::drift)
+    SchemaDrift(SchemaDrift),
+}
+
+impl fmt::Display for EventStreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Backend(err) => write!(f, "{}", err),
+            Self::Malformed { reason, .. } => {
+                write!(f, "malformed event: {}", reason)
+            }
+            Self::Truncated { data } => {
+                write!(f, "truncated event after {} bytes", data.len())
+            }
+            Self::Gap(gap) => write!(
+                f,
+                "missed {} events in {}/{}",
+                gap.missing(),
+                gap.topic,
+                gap.partition
+            ),
+            Self::SchemaDrift(drift) => write!(f, "schema drift: {}", drift),
+        }
+    }
+}
+
+impl std::error::Error for EventStreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Backend(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<BackendError> for EventStreamError {
+    fn from(err: BackendError) -> Self {
+        Self::Backend(err)
+    }
+}
+
+/// A [`since`](crate::EventStreamBuilder::checked_since) time that
+/// EventStreams can't start from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinceError {
+    /// Older than the [`RETENTION`](crate::RETENTION) window, so some
+    /// events would be silently missing
+    TooOld {
+        since: DateTime<Utc>,
+        /// Oldest time that is still available
+        oldest: DateTime<Utc>,
+    },
+    /// In the future
+    Future(DateTime<Utc>),
+}
+
+impl fmt::Display for SinceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooOld { since, oldest } => write!(
+                f,
+                "{} is too long ago, events are only kept since {}",
+                since, oldest
+            ),
+            Self::Future(since) => write!(f, "{} is in the future", since),
+        }
+    }
+}
+
+impl std::error::Error for SinceError {}
+
+/// Configuration that an [`EventStreamBuilder`](crate::EventStreamBuilder)
+/// can't build a stream from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// A URL to connect to couldn't be parsed
+    InvalidUrl { url: String, reason: String },
+    /// No HTTP client could be created with the connection options
+    #[cfg(not(target_arch = "wasm32"))]
+    Transport(TransportError),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::InvalidUrl {
+                ref url,
+                ref reason,
+            } => write!(f, "invalid URL {}: {}", url, reason),
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Transport(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            #[cfg(not(target_arch = "wasm32"))]
+            Self::Transport(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl From<TransportError> for BuildError {
+    fn from(err: TransportError) -> Self {
+        Self::Transport(err)
+    }
+}
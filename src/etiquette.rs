@@ -0,0 +1,272 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Guardrails for using EventStreams considerately
+//!
+//! Wikimedia asks clients to send a
+//! [User-Agent](https://meta.wikimedia.org/wiki/User-Agent_policy) that
+//! says how to contact whoever runs them, to keep using the same one
+//! rather than rotating through several, and to keep the number of
+//! concurrent connections to EventStreams low; one connection can carry
+//! several streams. [`Guardrails`] checks every connection to a Wikimedia
+//! host against this before it's opened, counting connections across the
+//! whole process. By default violations are logged as warnings, once each,
+//! but they can also be refused.
+use crate::backend::USER_AGENT;
+use std::fmt;
+use std::sync::Mutex;
+
+/// Concurrent connections to Wikimedia hosts allowed by default
+pub const RECOMMENDED_CONNECTIONS: usize = 2;
+
+/// What to do about a [`Violation`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Enforcement {
+    /// Log a warning and connect anyway
+    #[default]
+    Warn,
+    /// Don't connect, ending the stream with a
+    /// [`BackendError::Refused`](crate::backend::BackendError::Refused)
+    Refuse,
+    /// Don't check anything
+    Off,
+}
+
+/// A way a connection would go against Wikimedia's etiquette
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    /// The User-Agent has no contact details, or is this crate's default
+    NoContact { user_agent: String },
+    /// A different User-Agent than earlier connections
+    UserAgentRotated {
+        previous: String,
+        user_agent: String,
+    },
+    /// Opening the connection would exceed the maximum
+    TooManyConnections { open: usize, max: usize },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoContact { user_agent } => write!(
+                f,
+                "User-Agent {:?} has no contact details for your tool, see \
+                 https://meta.wikimedia.org/wiki/User-Agent_policy",
+                user_agent
+            ),
+            Self::UserAgentRotated {
+                previous,
+                user_agent,
+            } => write!(
+                f,
+                "User-Agent changed from {:?} to {:?}; use the same one for \
+                 every connection",
+                previous, user_agent
+            ),
+            Self::TooManyConnections { open, max } => write!(
+                f,
+                "{} connections to Wikimedia are already open, and at most {} \
+                 are allowed; combine streams into one connection instead",
+                open, max
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// Checks connections against Wikimedia's etiquette, see the
+/// [module documentation](self)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Guardrails {
+    enforcement: Enforcement,
+    max_connections: usize,
+}
+
+impl Default for Guardrails {
+    fn default() -> Self {
+        Self {
+            enforcement: Enforcement::Warn,
+            max_connections: RECOMMENDED_CONNECTIONS,
+        }
+    }
+}
+
+impl Guardrails {
+    /// Warn about violations, allowing
+    /// [`RECOMMENDED_CONNECTIONS`] at a time
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enforcement(mut self, enforcement: Enforcement) -> Self {
+        self.enforcement = enforcement;
+        self
+    }
+
+    /// Allow up to `max` concurrent connections, e.g. for a tool that has
+    /// arranged a higher limit
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+
+    /// Check a connection to `host` with `user_agent` about to be opened,
+    /// returning a slot that counts it as open until dropped. Connections
+    /// to hosts outside Wikimedia aren't checked or counted.
+    pub(crate) fn open(
+        &self,
+        host: &str,
+        user_agent: &str,
+    ) -> Result<Option<Connection>, Violation> {
+        if self.enforcement == Enforcement::Off || !is_wikimedia(host) {
+            return Ok(None);
+        }
+        let mut state = STATE.lock().unwrap();
+        let mut violations = vec![];
+        if user_agent == USER_AGENT || !has_contact(user_agent) {
+            violations.push(Violation::NoContact {
+                user_agent: user_agent.to_string(),
+            });
+        }
+        match &state.user_agent {
+            Some(previous) if previous != user_agent => {
+                violations.push(Violation::UserAgentRotated {
+                    previous: previous.clone(),
+                    user_agent: user_agent.to_string(),
+                });
+            }
+            _ => {}
+        }
+        if state.open >= self.max_connections {
+            violations.push(Violation::TooManyConnections {
+                open: state.open,
+                max: self.max_connections,
+            });
+        }
+        if self.enforcement == Enforcement::Refuse {
+            if let Some(violation) = violations.into_iter().next() {
+                return Err(violation);
+            }
+        } else {
+            for violation in violations {
+                let message = violation.to_string();
+                if !state.warned.contains(&message) {
+                    log::warn!(target: "eventstreams::etiquette", "{}", message);
+                    state.warned.push(message);
+                }
+            }
+        }
+        state.user_agent = Some(user_agent.to_string());
+        state.open += 1;
+        Ok(Some(Connection { _private: () }))
+    }
+}
+
+/// Number of connections to Wikimedia hosts currently open in this process
+pub fn open_connections() -> usize {
+    STATE.lock().unwrap().open
+}
+
+struct State {
+    open: usize,
+    /// User-Agent of the latest connection
+    user_agent: Option<String>,
+    /// Violations already warned about
+    warned: Vec<String>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    open: 0,
+    user_agent: None,
+    warned: Vec::new(),
+});
+
+/// Counts a connection as open until dropped
+#[derive(Debug)]
+pub(crate) struct Connection {
+    _private: (),
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        STATE.lock().unwrap().open -= 1;
+    }
+}
+
+fn is_wikimedia(host: &str) -> bool {
+    host == "wikimedia.org" || host.ends_with(".wikimedia.org")
+}
+
+/// Whether the User-Agent has an email address, URL or wiki username to
+/// reach its operator by
+fn has_contact(user_agent: &str) -> bool {
+    user_agent.contains('@')
+        || user_agent.contains("://")
+        || user_agent.contains("User:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Connection counts are process-wide, so everything is checked in one
+    // test
+    #[test]
+    fn refuses_connections_against_etiquette() {
+        let refuse = Guardrails::new()
+            .enforcement(Enforcement::Refuse)
+            .max_connections(1);
+        let user_agent = "patrol-bot/1.0 (https://example.org/patrol-bot)";
+        assert!(refuse.open("localhost", USER_AGENT).unwrap().is_none());
+        assert!(matches!(
+            refuse.open("stream.wikimedia.org", USER_AGENT),
+            Err(Violation::NoContact { .. })
+        ));
+        assert!(matches!(
+            refuse.open("stream.wikimedia.org", "patrol-bot/1.0"),
+            Err(Violation::NoContact { .. })
+        ));
+
+        let connection =
+            refuse.open("stream.wikimedia.org", user_agent).unwrap();
+        assert!(connection.is_some());
+        assert_eq!(open_connections(), 1);
+        assert_eq!(
+            refuse.open("stream.wikimedia.org", user_agent).unwrap_err(),
+            Violation::TooManyConnections { open: 1, max: 1 }
+        );
+        // Only warned about
+        let warn = Guardrails::new().max_connections(1);
+        let second = warn.open("stream.wikimedia.org", user_agent).unwrap();
+        assert_eq!(open_connections(), 2);
+        drop((connection, second));
+        assert_eq!(open_connections(), 0);
+
+        let rotated = "other-bot/1.0 (User:Example)";
+        assert_eq!(
+            refuse.open("stream.wikimedia.org", rotated).unwrap_err(),
+            Violation::UserAgentRotated {
+                previous: user_agent.to_string(),
+                user_agent: rotated.to_string(),
+            }
+        );
+        let off = Guardrails::new().enforcement(Enforcement::Off);
+        assert!(off.open("stream.wikimedia.org", rotated).unwrap().is_none());
+        assert_eq!(open_connections(), 0);
+    }
+}
@@ -0,0 +1,695 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+#[cfg(feature = "analytics")]
+use crate::aggregate::{Aggregate, Aggregator, Summary};
+#[cfg(feature = "analytics")]
+use crate::campaign::{CampaignEdit, CampaignTracker};
+#[cfg(feature = "analytics")]
+use crate::category::{CategoryTracker, MembershipChange};
+use crate::checkpoint::{Checkpointer, Checkpoints};
+use crate::dedup::DedupStatus;
+#[cfg(feature = "sinks")]
+use crate::filter::Filter;
+#[cfg(feature = "geoip")]
+use crate::geoip::{GeoEnriched, GeoIp};
+#[cfg(feature = "analytics")]
+use crate::heatmap::{LanguageActivity, LanguageHeatmap};
+#[cfg(feature = "analytics")]
+use crate::join::{Action, EditLogJoin};
+#[cfg(feature = "analytics")]
+use crate::keyed::{KeyedState, StateStore};
+use crate::pacing::{Pacer, Throttle};
+#[cfg(feature = "analytics")]
+use crate::page_rate::{PageRateAlert, PageRateMonitor};
+#[cfg(feature = "analytics")]
+use crate::patrol::{BacklogSample, PatrolBacklog};
+#[cfg(feature = "analytics")]
+use crate::rollup::{Period, Rollup, RollupEmitter};
+#[cfg(all(feature = "analytics", feature = "enrichment"))]
+use crate::scores::ScoreJoin;
+#[cfg(feature = "enrichment")]
+use crate::scores::{LiftWingClient, Scored};
+#[cfg(feature = "analytics")]
+use crate::shard::{FileCoordinator, Shard};
+use crate::side_output::{Excluded, SideOutput};
+#[cfg(all(feature = "analytics", feature = "enrichment"))]
+use crate::users::UserInfoClient;
+#[cfg(all(feature = "analytics", feature = "enrichment"))]
+use crate::velocity::{CreationVelocity, VelocityAlert};
+#[cfg(feature = "analytics")]
+use crate::watermark::{Watermark, Watermarked};
+#[cfg(feature = "analytics")]
+use crate::window::{
+    EditSession, SessionWindows, TumblingWindows, WindowOutput,
+};
+use crate::Event;
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use std::future::Future;
+#[cfg(feature = "analytics")]
+use std::hash::Hash;
+#[cfg(feature = "analytics")]
+use std::io;
+#[cfg(feature = "analytics")]
+use std::time::Duration;
+
+/// Adapters for streams of [`Event`]s
+pub trait EventStreamExt: Stream<Item = Event> + Sized {
+    /// Call `listener` for every event matching `filter`, until the stream
+    /// ends. Both are generic rather than boxed, so for the common case of
+    /// a single listener this compiles down to a plain loop with no dynamic
+    /// dispatch or reference counting.
+    fn listen<F, L>(
+        self,
+        mut filter: F,
+        mut listener: L,
+    ) -> impl Future<Output = ()>
+    where
+        F: FnMut(&Event) -> bool,
+        L: FnMut(Event),
+    {
+        async move {
+            let stream = self;
+            futures_util::pin_mut!(stream);
+            while let Some(event) = stream.next().await {
+                if filter(&event) {
+                    listener(event);
+                }
+            }
+        }
+    }
+
+    /// Call `handler` as soon as an event matching `predicate` comes out of
+    /// the stream, before it reaches any windowing, joining or other
+    /// buffering that is chained on afterwards. Events are passed on
+    /// unchanged. To see events before the stream's own
+    /// [queue](crate::EventStreamBuilder::queue) too, straight from the
+    /// reader, use
+    /// [`EventStreamBuilder::alert()`](crate::EventStreamBuilder::alert).
+    fn alert<P, H>(
+        self,
+        mut predicate: P,
+        mut handler: H,
+    ) -> impl Stream<Item = Event>
+    where
+        P: FnMut(&Event) -> bool,
+        H: FnMut(&Event),
+    {
+        self.inspect(move |event| {
+            if predicate(event) {
+                handler(event);
+            }
+        })
+    }
+
+    /// Pass events on at no more than `per_second` on average, holding
+    /// back bursts larger than `burst`, e.g. so a backlog after a
+    /// reconnect doesn't flood a rate-limited webhook. See [`Pacer`].
+    fn paced(self, per_second: f64, burst: u32) -> impl Stream<Item = Event> {
+        let mut pacer = Pacer::new(per_second, burst);
+        stream! {
+            for await event in self {
+                pacer.wait().await;
+                yield event;
+            }
+        }
+    }
+
+    /// Limit events with `throttle`, holding back or dropping those over
+    /// the limit depending on its [policy](crate::pacing::ThrottlePolicy)
+    fn throttled(self, mut throttle: Throttle) -> impl Stream<Item = Event> {
+        stream! {
+            for await event in self {
+                if throttle.admit(&event).await {
+                    yield event;
+                }
+            }
+        }
+    }
+
+    /// Keep only events for wikis in `shard`
+    #[cfg(feature = "analytics")]
+    fn sharded(self, shard: Shard) -> impl Stream<Item = Event> {
+        self.filter(move |event| futures::future::ready(shard.owns(event)))
+    }
+
+    /// Keep only events for wikis in this process' shard, as determined by
+    /// `coordinator`. A heartbeat is sent every `interval`, at which point
+    /// shards are rebalanced if processes have joined or left. If a
+    /// heartbeat fails, the previous shard is kept; until the first one
+    /// succeeds, no events are passed on.
+    #[cfg(feature = "analytics")]
+    fn coordinated(
+        self,
+        coordinator: FileCoordinator,
+        interval: Duration,
+    ) -> impl Stream<Item = Event> {
+        stream! {
+            let mut shard = coordinator.heartbeat().ok();
+            let mut last_heartbeat = coordinator.clock().now();
+            for await event in self {
+                let now = coordinator.clock().now();
+                if now - last_heartbeat
+                    >= chrono::Duration::from_std(interval)
+                        .unwrap_or(chrono::Duration::MAX)
+                {
+                    if let Ok(new_shard) = coordinator.heartbeat() {
+                        shard = Some(new_shard);
+                    }
+                    last_heartbeat = now;
+                }
+                if shard.is_some_and(|shard| shard.owns(&event)) {
+                    yield event;
+                }
+            }
+        }
+    }
+
+    /// Skip events that were already processed according to `checkpoints`,
+    /// and save a checkpoint now and then. An event counts as processed
+    /// once the next one is asked for, and a final checkpoint is saved when
+    /// the stream ends. Failures to save are passed on as errors, but
+    /// events keep coming.
+    fn checkpointed<C: Checkpointer>(
+        self,
+        mut checkpoints: Checkpoints<C>,
+    ) -> impl Stream<Item = std::io::Result<Event>> {
+        stream! {
+            for await event in self {
+                if let Err(err) = checkpoints.save_if_due() {
+                    yield Err(err);
+                }
+                if checkpoints.check(&event) == DedupStatus::Unique {
+                    yield Ok(event);
+                }
+            }
+            if let Err(err) = checkpoints.save() {
+                yield Err(err);
+            }
+        }
+    }
+
+    /// Keep only events matching `predicate`; the rest are sent to
+    /// `side_output`, labeled with `reason`
+    fn filter_with_side_output(
+        self,
+        side_output: SideOutput,
+        reason: impl Into<String>,
+        mut predicate: impl FnMut(&Event) -> bool,
+    ) -> impl Stream<Item = Event> {
+        let reason = reason.into();
+        stream! {
+            for await event in self {
+                if predicate(&event) {
+                    yield event;
+                } else {
+                    side_output.send(Excluded::Filtered {
+                        event,
+                        reason: reason.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Attach the current [`Watermark`] to every event, assuming events
+    /// arrive at most `max_delay` out of order
+    #[cfg(feature = "analytics")]
+    fn watermarked(
+        self,
+        max_delay: Duration,
+    ) -> impl Stream<Item = Watermarked> {
+        let mut watermark = Watermark::new(max_delay);
+        stream! {
+            for await event in self {
+                watermark.observe(&event);
+                // Just observed an event, so there's always a watermark
+                let current = watermark.current().unwrap();
+                yield Watermarked { event, watermark: current };
+            }
+        }
+    }
+
+    /// Group events into event-time [`TumblingWindows`] of length `size`.
+    /// Events arriving more than `allowed_lateness` after their window ended
+    /// are passed through as [`WindowOutput::Late`].
+    #[cfg(feature = "analytics")]
+    fn tumbling_windows(
+        self,
+        size: Duration,
+        allowed_lateness: Duration,
+    ) -> impl Stream<Item = WindowOutput> {
+        let mut windows = TumblingWindows::new(size, allowed_lateness);
+        stream! {
+            for await event in self {
+                for output in windows.push(event) {
+                    yield output;
+                }
+            }
+            for window in windows.flush() {
+                yield WindowOutput::Window(window);
+            }
+        }
+    }
+
+    /// Count activity per wiki and emit a [`Rollup`] at the end of every
+    /// `period`, waiting `allowed_lateness` for out-of-order events
+    #[cfg(feature = "analytics")]
+    fn rollups(
+        self,
+        period: Period,
+        allowed_lateness: Duration,
+    ) -> impl Stream<Item = Rollup> {
+        let mut emitter = RollupEmitter::new(period, allowed_lateness);
+        stream! {
+            for await event in self {
+                for rollup in emitter.push(&event) {
+                    yield rollup;
+                }
+            }
+            for rollup in emitter.flush() {
+                yield rollup;
+            }
+        }
+    }
+
+    /// Total up events with `aggregator`, emitting a [`Summary`] as each
+    /// window closes
+    #[cfg(feature = "analytics")]
+    fn aggregate<A: Aggregate>(
+        self,
+        mut aggregator: Aggregator<A>,
+    ) -> impl Stream<Item = Summary<A::Output>> {
+        stream! {
+            for await event in self {
+                for summary in aggregator.push(&event) {
+                    yield summary;
+                }
+            }
+            for summary in aggregator.flush() {
+                yield summary;
+            }
+        }
+    }
+
+    /// Pass on edits matching one of `tracker`'s campaign markers, once
+    /// per matching campaign
+    #[cfg(feature = "analytics")]
+    fn campaign_edits(
+        self,
+        mut tracker: CampaignTracker,
+    ) -> impl Stream<Item = CampaignEdit> {
+        stream! {
+            for await event in self {
+                for edit in tracker.push(&event) {
+                    yield edit;
+                }
+            }
+        }
+    }
+
+    /// Only pass on events matching `filter`
+    #[cfg(feature = "sinks")]
+    fn filtered(self, filter: Filter) -> impl Stream<Item = Event> {
+        self.filter(move |event| futures::future::ready(filter.matches(event)))
+    }
+
+    /// Pass on pages entering or leaving the categories followed by
+    /// `tracker`, along with the edit responsible
+    #[cfg(feature = "analytics")]
+    fn category_changes(
+        self,
+        mut tracker: CategoryTracker,
+    ) -> impl Stream<Item = MembershipChange> {
+        stream! {
+            for await event in self {
+                if let Some(change) = tracker.push(&event) {
+                    yield change;
+                }
+            }
+        }
+    }
+
+    /// Look up the creator of every page creation with `users`, passing
+    /// on alerts from `velocity`. Creations by unregistered users, or whose
+    /// lookup fails, are skipped.
+    #[cfg(all(feature = "analytics", feature = "enrichment"))]
+    fn creation_velocity_alerts(
+        self,
+        users: UserInfoClient,
+        mut velocity: CreationVelocity,
+    ) -> impl Stream<Item = VelocityAlert> {
+        stream! {
+            for await event in self {
+                let new = match event {
+                    Event::New(new) => new,
+                    _ => continue,
+                };
+                let user = match users.lookup(&new.api_url(), &new.user).await {
+                    Ok(Some(user)) => user,
+                    _ => continue,
+                };
+                if let Some(alert) = velocity.push(&new, &user) {
+                    yield alert;
+                }
+            }
+        }
+    }
+
+    /// Pass on edits along with their scores from `revision-score` events
+    /// in the same stream, see [`ScoreJoin`]. Edits whose scores don't
+    /// arrive within `window` are passed on without them.
+    #[cfg(all(feature = "analytics", feature = "enrichment"))]
+    fn scored(self, window: Duration) -> impl Stream<Item = Scored> {
+        let mut join = ScoreJoin::new(window);
+        stream! {
+            for await event in self {
+                for scored in join.push(&event) {
+                    yield scored;
+                }
+            }
+            for scored in join.flush() {
+                yield scored;
+            }
+        }
+    }
+
+    /// Pass on edits along with their scores from Lift Wing. Edits whose
+    /// lookup fails are passed on without scores.
+    #[cfg(feature = "enrichment")]
+    fn scored_by_lift_wing(
+        self,
+        client: LiftWingClient,
+    ) -> impl Stream<Item = Scored> {
+        stream! {
+            for await event in self {
+                let edit = match event {
+                    Event::Edit(edit) | Event::New(edit) => edit,
+                    _ => continue,
+                };
+                let scores = client.score(&edit).await.unwrap_or_default();
+                yield Scored { edit, scores };
+            }
+        }
+    }
+
+    /// Count events per language in buckets of length `bucket`, for
+    /// building a [`heatmap`](crate::heatmap)
+    #[cfg(feature = "analytics")]
+    fn language_activity(
+        self,
+        bucket: Duration,
+        allowed_lateness: Duration,
+    ) -> impl Stream<Item = LanguageActivity> {
+        let mut heatmap = LanguageHeatmap::new(bucket, allowed_lateness);
+        stream! {
+            for await event in self {
+                for activity in heatmap.push(&event) {
+                    yield activity;
+                }
+            }
+            for activity in heatmap.flush() {
+                yield activity;
+            }
+        }
+    }
+
+    /// Attach the location of anonymous editors, looked up with `geoip`
+    #[cfg(feature = "geoip")]
+    fn geo_enriched(self, geoip: GeoIp) -> impl Stream<Item = GeoEnriched> {
+        self.map(move |event| geoip.enrich(event))
+    }
+
+    /// Pass on alerts from `monitor` for watched pages being edited too
+    /// quickly
+    #[cfg(feature = "analytics")]
+    fn page_rate_alerts(
+        self,
+        mut monitor: PageRateMonitor,
+    ) -> impl Stream<Item = PageRateAlert> {
+        stream! {
+            for await event in self {
+                if let Some(alert) = monitor.push(&event) {
+                    yield alert;
+                }
+            }
+        }
+    }
+
+    /// Sample the [`PatrolBacklog`] of every wiki each `interval` of event
+    /// time
+    #[cfg(feature = "analytics")]
+    fn patrol_backlog(
+        self,
+        interval: Duration,
+    ) -> impl Stream<Item = BacklogSample> {
+        let mut backlog = PatrolBacklog::new(interval);
+        stream! {
+            for await event in self {
+                if let Some(sample) = backlog.push(&event) {
+                    yield sample;
+                }
+            }
+        }
+    }
+
+    /// Group each user's edits on a wiki into [`SessionWindows`], where a
+    /// session ends after `gap` without edits. Sessions are kept open for
+    /// `allowed_lateness` longer in case of out-of-order edits.
+    #[cfg(feature = "analytics")]
+    fn edit_sessions(
+        self,
+        gap: Duration,
+        allowed_lateness: Duration,
+    ) -> impl Stream<Item = EditSession> {
+        let mut sessions = SessionWindows::new(gap, allowed_lateness);
+        stream! {
+            for await event in self {
+                for session in sessions.push(event) {
+                    yield session;
+                }
+            }
+            for session in sessions.flush() {
+                yield session;
+            }
+        }
+    }
+
+    /// Pair up edits and log entries for the same action (see
+    /// [`EditLogJoin`]), where both happened within `window` of each other
+    #[cfg(feature = "analytics")]
+    fn join_actions(self, window: Duration) -> impl Stream<Item = Action> {
+        let mut join = EditLogJoin::new(window);
+        stream! {
+            for await event in self {
+                for action in join.push(event) {
+                    yield action;
+                }
+            }
+            for action in join.flush() {
+                yield action;
+            }
+        }
+    }
+
+    /// Process events with per-key state, e.g. the last revision seen for
+    /// each page. `key_fn` picks the key for an event, and `state_fn` is
+    /// called with that key's state (created with `Default`) and the event;
+    /// whatever it returns is passed on. In
+    /// [bounded mode](crate::metrics::set_bounded), state is kept for at
+    /// most that many keys, see
+    /// [`KeyedState::max_keys()`](crate::keyed::KeyedState::max_keys).
+    #[cfg(feature = "analytics")]
+    fn keyed_process<K, S, O>(
+        self,
+        mut key_fn: impl FnMut(&Event) -> K,
+        mut state_fn: impl FnMut(&mut S, Event) -> Option<O>,
+    ) -> impl Stream<Item = O>
+    where
+        K: Eq + Hash + Clone,
+        S: Default,
+    {
+        let mut state = KeyedState::new();
+        stream! {
+            for await event in self {
+                let key = key_fn(&event);
+                if let Some(output) =
+                    state.process(key, |state| state_fn(state, event))
+                {
+                    yield output;
+                }
+            }
+        }
+    }
+
+    /// Like [`keyed_process`](EventStreamExt::keyed_process), but state is
+    /// loaded from `store` at the start, and saved every `save_every` events
+    /// as well as when the stream ends. Failures to load or save are passed
+    /// on as errors, but processing continues. Panics if `save_every` is 0.
+    #[cfg(feature = "analytics")]
+    fn persistent_keyed_process<K, S, O, St>(
+        self,
+        store: St,
+        save_every: usize,
+        mut key_fn: impl FnMut(&Event) -> K,
+        mut state_fn: impl FnMut(&mut S, Event) -> Option<O>,
+    ) -> impl Stream<Item = io::Result<O>>
+    where
+        K: Eq + Hash + Clone,
+        S: Default,
+        St: StateStore<K, S>,
+    {
+        assert!(save_every > 0, "save_every must be positive");
+        let mut state = KeyedState::new();
+        stream! {
+            if let Err(err) = state.load(&store) {
+                yield Err(err);
+            }
+            let mut unsaved = 0;
+            for await event in self {
+                let key = key_fn(&event);
+                let output = state.process(key, |state| state_fn(state, event));
+                unsaved += 1;
+                if let Some(output) = output {
+                    yield Ok(output);
+                }
+                if unsaved >= save_every {
+                    unsaved = 0;
+                    if let Err(err) = state.save(&store) {
+                        yield Err(err);
+                    }
+                }
+            }
+            if let Err(err) = state.save(&store) {
+                yield Err(err);
+            }
+        }
+    }
+}
+
+impl<S: Stream<Item = Event>> EventStreamExt for S {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    fn edits(titles: &[&str]) -> impl Stream<Item = Event> {
+        let events: Vec<_> = titles
+            .iter()
+            .enumerate()
+            .map(|(offset, title)| {
+                testing::edit_event(offset as u64, title, at(0))
+            })
+            .collect();
+        futures::stream::iter(events)
+    }
+
+    fn titles(events: Vec<Event>) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| event.title().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn listens_to_matching_events() {
+        let mut heard = vec![];
+        block_on(edits(&["A", "Talk:A", "B"]).listen(
+            |event| !event.title().starts_with("Talk:"),
+            |event| heard.push(event),
+        ));
+        assert_eq!(titles(heard), ["A", "B"]);
+    }
+
+    #[test]
+    fn alerts_before_anything_chained_on() {
+        let alerted = std::cell::RefCell::new(vec![]);
+        let seen: Vec<_> = block_on(
+            edits(&["A", "B"])
+                .alert(
+                    |event| event.title() == "B",
+                    |event| {
+                        alerted.borrow_mut().push(event.title().to_string())
+                    },
+                )
+                .map(|event| {
+                    (event.title().to_string(), alerted.borrow().len())
+                })
+                .collect(),
+        );
+        assert_eq!(seen, [("A".to_string(), 0), ("B".to_string(), 1)]);
+    }
+
+    #[cfg(feature = "analytics")]
+    #[derive(Default)]
+    struct CountingStore(std::rc::Rc<std::cell::Cell<usize>>);
+
+    #[cfg(feature = "analytics")]
+    impl StateStore<String, u32> for CountingStore {
+        fn load(
+            &self,
+        ) -> io::Result<Option<std::collections::HashMap<String, u32>>>
+        {
+            Ok(None)
+        }
+
+        fn save(
+            &self,
+            _: &std::collections::HashMap<String, u32>,
+        ) -> io::Result<()> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "analytics")]
+    #[test]
+    fn saves_state_every_few_events_and_at_the_end() {
+        let store = CountingStore::default();
+        let saves = store.0.clone();
+        let counts: Vec<_> = block_on(
+            edits(&["A", "B", "A", "A", "B"])
+                .persistent_keyed_process(
+                    store,
+                    2,
+                    |event| event.title().to_string(),
+                    |count: &mut u32, _| {
+                        *count += 1;
+                        Some(*count)
+                    },
+                )
+                .map(Result::unwrap)
+                .collect(),
+        );
+        assert_eq!(counts, [1, 1, 2, 3, 2]);
+        assert_eq!(saves.get(), 3);
+    }
+
+    #[cfg(feature = "analytics")]
+    #[test]
+    #[should_panic(expected = "save_every must be positive")]
+    fn rejects_saving_every_0_events() {
+        let _ = edits(&["A"]).persistent_keyed_process(
+            CountingStore::default(),
+            0,
+            |event| event.title().to_string(),
+            |_: &mut u32, _| Some(()),
+        );
+    }
+}
@@ -0,0 +1,195 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Event types defined outside this crate
+//!
+//! Other crates can [`register()`] a type for an EventStreams stream that
+//! isn't supported here, e.g. `mediawiki.revision-tags-change`. Events
+//! from that stream are then deserialized into the registered type and
+//! delivered as [`Event::Extension`] alongside everything else.
+//!
+//! ```
+//! #[derive(serde::Deserialize)]
+//! struct RevisionTagsChange {
+//!     rev_id: u64,
+//!     tags: Vec<String>,
+//! }
+//!
+//! eventstreams::extension::register::<RevisionTagsChange>(
+//!     "mediawiki.revision-tags-change",
+//! );
+//! ```
+use crate::types::EventMeta;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock, RwLock};
+
+type Payload = Arc<dyn Any + Send + Sync>;
+type Deserializer = fn(Value) -> Result<Payload, serde_json::Error>;
+
+fn registry() -> &'static RwLock<HashMap<String, Deserializer>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Deserializer>>> =
+        OnceLock::new();
+    REGISTRY.get_or_init(Default::default)
+}
+
+fn deserialize<T>(value: Value) -> Result<Payload, serde_json::Error>
+where
+    T: DeserializeOwned + Any + Send + Sync,
+{
+    Ok(Arc::new(serde_json::from_value::<T>(value)?))
+}
+
+/// Deserialize events from `stream` (as in `meta.stream`) into `T`,
+/// replacing any type registered for it before
+pub fn register<T>(stream: &str)
+where
+    T: DeserializeOwned + Any + Send + Sync,
+{
+    registry()
+        .write()
+        .unwrap()
+        .insert(stream.to_string(), deserialize::<T>);
+}
+
+/// Stop handling events from `stream`
+pub fn unregister(stream: &str) {
+    registry().write().unwrap().remove(stream);
+}
+
+pub fn is_registered(stream: &str) -> bool {
+    registry().read().unwrap().contains_key(stream)
+}
+
+/// Parse an event from a registered stream, or `None` if its stream isn't
+/// registered
+pub(crate) fn parse(value: Value) -> Option<Result<Event, serde_json::Error>> {
+    let stream = value["meta"]["stream"].as_str()?;
+    let deserializer = *registry().read().unwrap().get(stream)?;
+    Some((|| {
+        let meta: EventMeta = serde_json::from_value(value["meta"].clone())?;
+        Ok(Event::Extension(ExtensionEvent {
+            meta,
+            payload: deserializer(value.clone())?,
+            value: Arc::new(value),
+        }))
+    })())
+}
+
+/// An event from a stream registered with [`register()`]. The payload is
+/// reference counted, so cloning the event is cheap.
+///
+/// Serializes to the event as received, since the payload type doesn't
+/// have to implement `Serialize`.
+#[derive(Clone)]
+pub struct ExtensionEvent {
+    pub meta: EventMeta,
+    payload: Payload,
+    value: Arc<Value>,
+}
+
+impl ExtensionEvent {
+    /// Name of the stream the event came from
+    pub fn stream(&self) -> &str {
+        &self.meta.stream
+    }
+
+    /// Domain of the wiki the event is about
+    pub fn domain(&self) -> &str {
+        &self.meta.domain
+    }
+
+    /// Time the event happened
+    pub fn dt(&self) -> DateTime<Utc> {
+        self.meta.dt
+    }
+
+    /// The deserialized event, if it's of type `T`
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.payload.downcast_ref()
+    }
+
+    /// The whole event as received
+    pub fn value(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl Serialize for ExtensionEvent {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        self.value.serialize(serializer)
+    }
+}
+
+impl fmt::Debug for ExtensionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExtensionEvent")
+            .field("meta", &self.meta)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct TagsChange {
+        rev_id: u64,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn parses_registered_streams() {
+        // Not a real stream, so other tests aren't affected
+        let stream = "test.revision-tags-change";
+        let mut meta = testing::edit(1, "A", at(0))["meta"].clone();
+        meta["stream"] = stream.into();
+        let message = serde_json::json!({ "meta": meta, "rev_id": 5, "tags": ["mw-undo"] });
+        assert!(matches!(testing::event(&message), Event::Unknown(_)));
+
+        register::<TagsChange>(stream);
+        assert!(is_registered(stream));
+        let extension = match testing::event(&message) {
+            Event::Extension(extension) => extension,
+            event => panic!("parsed as {:?}", event),
+        };
+        assert_eq!(extension.stream(), stream);
+        assert_eq!(
+            extension.downcast_ref::<TagsChange>(),
+            Some(&TagsChange {
+                rev_id: 5,
+                tags: vec!["mw-undo".to_string()],
+            })
+        );
+        assert!(extension.downcast_ref::<String>().is_none());
+        assert_eq!(serde_json::to_value(&extension).unwrap(), message);
+
+        unregister(stream);
+        assert!(matches!(testing::event(&message), Event::Unknown(_)));
+    }
+}
@@ -0,0 +1,371 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! C interface, for embedding in tools written in other languages
+//!
+//! A stream is created with [`es_stream_new()`], configured, given
+//! callbacks with [`es_stream_on_event()`], and then started with
+//! [`es_stream_start()`], which reads events on a background thread and
+//! passes each one to the callbacks as JSON. [`es_stream_close()`] stops
+//! it and frees the handle. Functions that can fail return 0 on success
+//! and -1 on failure, with the reason available from [`es_last_error()`].
+//!
+//! The C header is `include/eventstreams.h`, generated with
+//! `cbindgen --config cbindgen.toml --output include/eventstreams.h`.
+//! To build a library to link against:
+//!
+//! ```text
+//! cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+use crate::{Event, EventStreamBuilder};
+use futures::channel::oneshot;
+use futures::future::{self, FutureExt};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::thread;
+
+/// Called with each event as a JSON document, which is only valid during
+/// the call, and the `user_data` given when registering. Called from the
+/// stream's background thread.
+pub type EsEventCallback =
+    unsafe extern "C" fn(json: *const c_char, user_data: *mut c_void);
+
+/// A stream created by [`es_stream_new()`]
+pub struct EsStream {
+    /// Until the stream is started
+    builder: Option<EventStreamBuilder>,
+    callbacks: Vec<(EsEventCallback, UserData)>,
+    running: Option<Running>,
+}
+
+struct Running {
+    shutdown: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// Pointer passed back to a callback as it is
+struct UserData(*mut c_void);
+
+// The caller vouches for `user_data` being usable from the stream's thread
+// when registering the callback
+unsafe impl Send for UserData {}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_error(message: &str) -> c_int {
+    let message = CString::new(message.replace('\0', ""))
+        .expect("nul bytes were removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    -1
+}
+
+/// `ptr` as a string, or `None` if it's null
+unsafe fn string_arg(ptr: *const c_char) -> Result<Option<String>, String> {
+    if ptr.is_null() {
+        return Ok(None);
+    }
+    match CStr::from_ptr(ptr).to_str() {
+        Ok(string) => Ok(Some(string.to_string())),
+        Err(_) => Err("argument is not valid UTF-8".to_string()),
+    }
+}
+
+/// The builder of a stream that hasn't been started yet
+unsafe fn builder<'a>(
+    stream: *mut EsStream,
+) -> Result<&'a mut EsStream, &'static str> {
+    match stream.as_mut() {
+        Some(stream) if stream.builder.is_some() => Ok(stream),
+        Some(_) => Err("stream was already started"),
+        None => Err("stream is null"),
+    }
+}
+
+/// The reason the last call on this thread failed, or null. The string
+/// is valid until the next call that fails.
+#[no_mangle]
+pub extern "C" fn es_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Create a stream reading `streams`, a comma-separated list of stream
+/// names such as `recentchange,page-create`, or recent changes if it's
+/// null. Returns null on failure.
+///
+/// # Safety
+///
+/// `streams` must be null or a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_new(
+    streams: *const c_char,
+) -> *mut EsStream {
+    let mut builder = EventStreamBuilder::new();
+    match string_arg(streams) {
+        Ok(Some(streams)) => {
+            builder = builder.stream_names(streams.split(',').map(str::trim))
+        }
+        Ok(None) => {}
+        Err(err) => {
+            set_error(&err);
+            return ptr::null_mut();
+        }
+    }
+    Box::into_raw(Box::new(EsStream {
+        builder: Some(builder),
+        callbacks: vec![],
+        running: None,
+    }))
+}
+
+/// Connect to `url` instead of Wikimedia's EventStreams, see
+/// [`EventStreamBuilder::url()`]. Fails if `url` isn't a valid URL. Only
+/// before the stream is started.
+///
+/// # Safety
+///
+/// `stream` must come from [`es_stream_new()`] and not be closed, and
+/// `url` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_set_url(
+    stream: *mut EsStream,
+    url: *const c_char,
+) -> c_int {
+    configure(stream, url, |builder, url| match url::Url::parse(&url) {
+        Ok(_) => Ok(builder.url(url)),
+        Err(err) => Err(format!("invalid URL {}: {}", url, err)),
+    })
+}
+
+/// Identify the tool to Wikimedia, as its
+/// [User-Agent policy](https://meta.wikimedia.org/wiki/User-Agent_policy)
+/// asks. Only before the stream is started.
+///
+/// # Safety
+///
+/// `stream` must come from [`es_stream_new()`] and not be closed, and
+/// `user_agent` must be a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_set_user_agent(
+    stream: *mut EsStream,
+    user_agent: *const c_char,
+) -> c_int {
+    configure(stream, user_agent, |builder, user_agent| {
+        Ok(builder.user_agent(user_agent))
+    })
+}
+
+unsafe fn configure(
+    stream: *mut EsStream,
+    value: *const c_char,
+    apply: impl FnOnce(
+        EventStreamBuilder,
+        String,
+    ) -> Result<EventStreamBuilder, String>,
+) -> c_int {
+    let stream = match builder(stream) {
+        Ok(stream) => stream,
+        Err(err) => return set_error(err),
+    };
+    let value = match string_arg(value) {
+        Ok(Some(value)) => value,
+        Ok(None) => return set_error("argument is null"),
+        Err(err) => return set_error(&err),
+    };
+    let builder = stream.builder.clone().expect("checked above");
+    match apply(builder, value) {
+        Ok(builder) => {
+            stream.builder = Some(builder);
+            0
+        }
+        Err(err) => set_error(&err),
+    }
+}
+
+/// Call `callback` with every event once the stream is started. Only
+/// before the stream is started.
+///
+/// # Safety
+///
+/// `stream` must come from [`es_stream_new()`] and not be closed, and
+/// `callback` must be safe to call with `user_data` from another thread
+/// until the stream is closed.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_on_event(
+    stream: *mut EsStream,
+    callback: Option<EsEventCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    let stream = match builder(stream) {
+        Ok(stream) => stream,
+        Err(err) => return set_error(err),
+    };
+    match callback {
+        Some(callback) => {
+            stream.callbacks.push((callback, UserData(user_data)));
+            0
+        }
+        None => set_error("callback is null"),
+    }
+}
+
+/// Start reading events on a background thread. Fails if the stream can't
+/// be built from its configuration, e.g. a proxy URL that doesn't parse.
+///
+/// # Safety
+///
+/// `stream` must come from [`es_stream_new()`] and not be closed.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_start(stream: *mut EsStream) -> c_int {
+    let stream = match builder(stream) {
+        Ok(stream) => stream,
+        Err(err) => return set_error(err),
+    };
+    // Building happens on the stream's thread, where errors would be lost
+    let builder = stream.builder.as_ref().expect("checked above");
+    if let Err(err) = builder.validate() {
+        return set_error(&err.to_string());
+    }
+    let builder = stream.builder.take().expect("checked above");
+    let callbacks = std::mem::take(&mut stream.callbacks);
+    let (shutdown, stopped) = oneshot::channel();
+    let thread = thread::Builder::new()
+        .name("eventstreams-ffi".into())
+        .spawn(move || {
+            let events = builder.into_stream();
+            let listeners = events.listeners();
+            for (callback, user_data) in callbacks {
+                listeners.on_event(move |event| {
+                    deliver(callback, &user_data, event)
+                });
+            }
+            futures::executor::block_on(future::select(
+                events.run().boxed_local(),
+                stopped,
+            ));
+        });
+    match thread {
+        Ok(thread) => {
+            stream.running = Some(Running { shutdown, thread });
+            0
+        }
+        Err(err) => set_error(&err.to_string()),
+    }
+}
+
+fn deliver(callback: EsEventCallback, user_data: &UserData, event: &Event) {
+    let json = serde_json::to_string(event)
+        .ok()
+        .and_then(|json| CString::new(json).ok());
+    if let Some(json) = json {
+        unsafe { callback(json.as_ptr(), user_data.0) };
+    }
+}
+
+/// Stop the stream, waiting for its thread to finish, and free it. Does
+/// nothing if `stream` is null.
+///
+/// # Safety
+///
+/// `stream` must come from [`es_stream_new()`] and not be closed already.
+/// It must not be called from one of the stream's callbacks.
+#[no_mangle]
+pub unsafe extern "C" fn es_stream_close(stream: *mut EsStream) {
+    if stream.is_null() {
+        return;
+    }
+    let stream = Box::from_raw(stream);
+    if let Some(running) = stream.running {
+        let _ = running.shutdown.send(());
+        let _ = running.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at, MockServer};
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    fn last_error() -> String {
+        let error = es_last_error();
+        assert!(!error.is_null());
+        unsafe { CStr::from_ptr(error) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    unsafe extern "C" fn collect(json: *const c_char, user_data: *mut c_void) {
+        let titles = &*(user_data as *const Mutex<Vec<String>>);
+        let event: serde_json::Value =
+            serde_json::from_slice(CStr::from_ptr(json).to_bytes()).unwrap();
+        titles
+            .lock()
+            .unwrap()
+            .push(event["title"].as_str().unwrap().to_string());
+    }
+
+    #[test]
+    fn reports_invalid_configuration() {
+        unsafe {
+            let url = CString::new("not a URL").unwrap();
+            assert_eq!(es_stream_set_url(ptr::null_mut(), url.as_ptr()), -1);
+            assert_eq!(last_error(), "stream is null");
+            let stream = es_stream_new(ptr::null());
+            assert_eq!(es_stream_set_url(stream, url.as_ptr()), -1);
+            assert!(last_error().starts_with("invalid URL not a URL"));
+            assert_eq!(es_stream_on_event(stream, None, ptr::null_mut()), -1);
+            assert_eq!(last_error(), "callback is null");
+            es_stream_close(stream);
+        }
+    }
+
+    #[test]
+    fn calls_back_with_each_event() {
+        let server = MockServer::new(vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            testing::edit(1, "B", at(0)).to_string(),
+        ])
+        .start()
+        .unwrap();
+        let titles: Mutex<Vec<String>> = Mutex::new(vec![]);
+        unsafe {
+            let stream = es_stream_new(ptr::null());
+            let url = CString::new(server.url()).unwrap();
+            assert_eq!(es_stream_set_url(stream, url.as_ptr()), 0);
+            let user_data = &titles as *const _ as *mut c_void;
+            assert_eq!(es_stream_on_event(stream, Some(collect), user_data), 0);
+            assert_eq!(es_stream_start(stream), 0);
+            assert_eq!(es_stream_start(stream), -1);
+            assert_eq!(last_error(), "stream was already started");
+            let started = Instant::now();
+            while titles.lock().unwrap().len() < 2 {
+                assert!(started.elapsed() < Duration::from_secs(5));
+                thread::sleep(Duration::from_millis(5));
+            }
+            es_stream_close(stream);
+        }
+        assert_eq!(titles.lock().unwrap()[..2], ["A", "B"]);
+    }
+}
@@ -0,0 +1,907 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Filtering events
+//!
+//! A [`Filter`] is a set of conditions that an event has to meet. Values
+//! are compiled up front: exact titles, users and wikis go into hash sets,
+//! and title substrings into a single
+//! [Aho-Corasick](https://docs.rs/aho-corasick) automaton, and regular
+//! expressions on the same field into one [`RegexSet`]. Checking an event
+//! costs about the same for a watchlist of ten pages as for one of ten
+//! thousand, and for one patrol rule as for a hundred.
+//!
+//! Filters can be combined with [`and()`](Filter::and),
+//! [`or()`](Filter::or) and `!`, e.g.
+//!
+//! ```
+//! # fn main() -> Result<(), regex::Error> {
+//! use eventstreams::filter::Filter;
+//!
+//! let filter = Filter::new()
+//!     .wiki("en.wikipedia.org")
+//!     .namespace(0)
+//!     .exclude_bots()
+//!     .and(!Filter::new().title_matches(vec![r"^List of "])?)
+//!     .or(Filter::new().min_byte_change(5000));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Filters can be serialized, e.g. as part of a
+//! [`SubscriptionDef`](crate::subscription::SubscriptionDef), in which
+//! case they are stored as the lists of values they were built from.
+use crate::project;
+use crate::title;
+use crate::Event;
+use aho_corasick::AhoCorasick;
+use regex::RegexSet;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Not;
+
+/// A text field of an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Title,
+    Comment,
+}
+
+impl Field {
+    fn name(self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Comment => "summary",
+        }
+    }
+
+    fn get(self, event: &Event) -> &str {
+        match self {
+            Field::Title => event.title(),
+            Field::Comment => event.comment(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    /// Domain of the wiki is one of these
+    Wikis(HashSet<String>),
+    /// Title is one of these
+    Titles(HashSet<String>),
+    /// User is one of these
+    Users(HashSet<String>),
+    /// Title contains one of these
+    TitleContains {
+        patterns: Vec<String>,
+        matcher: AhoCorasick,
+    },
+    /// Field matches one of these regular expressions
+    Regex { field: Field, set: RegexSet },
+    /// Namespace of the page is one of these
+    Namespaces(HashSet<i32>),
+    /// Whether the event was caused by a bot
+    Bot(bool),
+    /// Edit changed the page's size by at least this many bytes, either way
+    MinByteChange(u64),
+    /// Every one of these filters matches
+    All(Vec<Filter>),
+    /// At least one of these filters matches
+    Any(Vec<Filter>),
+    /// This filter doesn't match
+    Not(Box<Filter>),
+}
+
+/// Size of an edit in bytes, `None` for other kinds of events
+fn byte_change(event: &Event) -> Option<u64> {
+    match event {
+        Event::Edit(edit) | Event::New(edit) => {
+            Some(edit.byte_change().unsigned_abs())
+        }
+        _ => None,
+    }
+}
+
+impl Condition {
+    fn matches(&self, event: &Event) -> bool {
+        match self {
+            Condition::Wikis(wikis) => {
+                wikis.contains(event.server_name())
+                    || (!event.wiki().is_empty()
+                        && wikis.contains(event.wiki()))
+            }
+            Condition::Titles(titles) => {
+                titles.contains(event.title())
+                    || event.namespace().is_some_and(|namespace| {
+                        title::uncapitalized(
+                            event.wiki(),
+                            namespace,
+                            event.title(),
+                        )
+                        .is_some_and(|title| titles.contains(&title))
+                    })
+            }
+            Condition::Users(users) => users.contains(event.user()),
+            Condition::TitleContains { matcher, .. } => {
+                matcher.is_match(event.title())
+            }
+            Condition::Regex { field, set } => set.is_match(field.get(event)),
+            Condition::Namespaces(namespaces) => event
+                .namespace()
+                .is_some_and(|namespace| namespaces.contains(&namespace)),
+            Condition::Bot(bot) => event.is_bot() == *bot,
+            Condition::MinByteChange(min) => {
+                byte_change(event).is_some_and(|change| change >= *min)
+            }
+            Condition::All(filters) => {
+                filters.iter().all(|filter| filter.matches(event))
+            }
+            Condition::Any(filters) => {
+                filters.iter().any(|filter| filter.matches(event))
+            }
+            Condition::Not(filter) => !filter.matches(event),
+        }
+    }
+
+    fn explain(&self, event: &Event) -> ConditionReport {
+        let (condition, value, detail) = match self {
+            Condition::Wikis(wikis) => (
+                format!("wiki is one of {} wikis", wikis.len()),
+                // Whichever form of the wiki's name is listed
+                if !event.wiki().is_empty() && wikis.contains(event.wiki()) {
+                    event.wiki()
+                } else {
+                    event.server_name()
+                },
+                None,
+            ),
+            Condition::Titles(titles) => (
+                format!("title is one of {} titles", titles.len()),
+                event.title(),
+                None,
+            ),
+            Condition::Users(users) => (
+                format!("user is one of {} users", users.len()),
+                event.user(),
+                None,
+            ),
+            Condition::TitleContains { patterns, matcher } => (
+                format!("title contains one of {} strings", patterns.len()),
+                event.title(),
+                matcher.find(event.title()).map(|found| {
+                    format!(
+                        "contains {:?}",
+                        patterns[found.pattern().as_usize()]
+                    )
+                }),
+            ),
+            Condition::Regex { field, set } => {
+                let text = field.get(event);
+                let matched: Vec<_> = set
+                    .matches(text)
+                    .into_iter()
+                    .map(|index| set.patterns()[index].as_str())
+                    .collect();
+                (
+                    format!(
+                        "{} matches one of {} patterns",
+                        field.name(),
+                        set.len()
+                    ),
+                    text,
+                    Some(format!("matches {:?}", matched))
+                        .filter(|_| !matched.is_empty()),
+                )
+            }
+            Condition::Namespaces(namespaces) => (
+                format!("namespace is one of {} namespaces", namespaces.len()),
+                "",
+                Some(match event.namespace() {
+                    Some(namespace) if namespaces.contains(&namespace) => {
+                        format!("{} is included", namespace)
+                    }
+                    Some(namespace) => format!("{} is not included", namespace),
+                    None => "has no namespace".to_string(),
+                }),
+            ),
+            Condition::Bot(bot) => (
+                if *bot { "is a bot" } else { "isn't a bot" }.to_string(),
+                "",
+                Some(format!("bot is {}", event.is_bot())),
+            ),
+            Condition::MinByteChange(min) => (
+                format!("size changed by at least {} bytes", min),
+                "",
+                Some(match byte_change(event) {
+                    Some(change) => format!("changed by {} bytes", change),
+                    None => "not an edit".to_string(),
+                }),
+            ),
+            Condition::All(filters) | Condition::Any(filters) => (
+                format!(
+                    "{} of {} filters match",
+                    if let Condition::All(_) = self {
+                        "all"
+                    } else {
+                        "any"
+                    },
+                    filters.len()
+                ),
+                "",
+                Some(format!(
+                    "{} matched",
+                    filters
+                        .iter()
+                        .filter(|filter| filter.matches(event))
+                        .count()
+                )),
+            ),
+            Condition::Not(filter) => (
+                "filter doesn't match".to_string(),
+                "",
+                Some(
+                    if filter.matches(event) {
+                        "it matched"
+                    } else {
+                        "it didn't match"
+                    }
+                    .to_string(),
+                ),
+            ),
+        };
+        let matched = self.matches(event);
+        let detail = detail.unwrap_or_else(|| {
+            if matched {
+                format!("{:?} is included", value)
+            } else {
+                format!("{:?} is not included", value)
+            }
+        });
+        ConditionReport {
+            condition,
+            matched,
+            detail,
+        }
+    }
+}
+
+/// How one condition of a [`Filter`] fared against an event
+#[derive(Clone, Debug)]
+pub struct ConditionReport {
+    /// Description of the condition
+    pub condition: String,
+    pub matched: bool,
+    /// Why it did or didn't match
+    pub detail: String,
+}
+
+/// Result of [`Filter::explain()`]
+#[derive(Clone, Debug)]
+pub struct Explanation {
+    /// Whether the filter as a whole matched
+    pub matched: bool,
+    /// Every condition, including those after the first one that failed
+    pub conditions: Vec<ConditionReport>,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}",
+            if self.matched {
+                "matched"
+            } else {
+                "did not match"
+            }
+        )?;
+        for report in &self.conditions {
+            writeln!(
+                f,
+                "  [{}] {}: {}",
+                if report.matched { "pass" } else { "fail" },
+                report.condition,
+                report.detail
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Conditions that an event must all meet. Calling the same method more
+/// than once adds to the accepted values, e.g. `.users(["A"]).users(["B"])`
+/// matches edits by either user.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "FilterDef", into = "FilterDef")]
+pub struct Filter {
+    conditions: Vec<Condition>,
+}
+
+/// Serialized form of a [`Filter`]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+struct FilterDef {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    wikis: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    titles: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    users: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    title_contains: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    title_matches: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    comment_matches: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    namespaces: Vec<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bot: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_byte_change: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    all: Vec<Filter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    any: Vec<Filter>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    not: Option<Box<Filter>>,
+}
+
+impl From<Filter> for FilterDef {
+    fn from(filter: Filter) -> Self {
+        let sorted = |set: HashSet<String>| {
+            let mut values: Vec<_> = set.into_iter().collect();
+            values.sort();
+            values
+        };
+        let mut def = FilterDef::default();
+        for condition in filter.conditions {
+            match condition {
+                Condition::Wikis(set) => def.wikis = sorted(set),
+                Condition::Titles(set) => def.titles = sorted(set),
+                Condition::Users(set) => def.users = sorted(set),
+                Condition::TitleContains { patterns, .. } => {
+                    def.title_contains = patterns
+                }
+                Condition::Regex { field, set } => {
+                    let patterns = set.patterns().to_vec();
+                    match field {
+                        Field::Title => def.title_matches = patterns,
+                        Field::Comment => def.comment_matches = patterns,
+                    }
+                }
+                Condition::Namespaces(set) => {
+                    def.namespaces = set.into_iter().collect();
+                    def.namespaces.sort_unstable();
+                }
+                Condition::Bot(bot) => def.bot = Some(bot),
+                Condition::MinByteChange(min) => {
+                    def.min_byte_change = Some(min)
+                }
+                Condition::All(filters) => def.all = filters,
+                Condition::Any(filters) => def.any = filters,
+                Condition::Not(filter) => def.not = Some(filter),
+            }
+        }
+        def
+    }
+}
+
+/// Why a [`FilterDef`] couldn't be compiled into a [`Filter`]
+#[derive(Debug)]
+pub enum FilterError {
+    /// A title or summary pattern isn't a valid regex
+    Regex(regex::Error),
+    /// The title substrings couldn't be built into an automaton
+    Patterns(aho_corasick::BuildError),
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Regex(err) => write!(f, "{}", err),
+            Self::Patterns(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Regex(err) => Some(err),
+            Self::Patterns(err) => Some(err),
+        }
+    }
+}
+
+impl From<regex::Error> for FilterError {
+    fn from(err: regex::Error) -> Self {
+        Self::Regex(err)
+    }
+}
+
+impl From<aho_corasick::BuildError> for FilterError {
+    fn from(err: aho_corasick::BuildError) -> Self {
+        Self::Patterns(err)
+    }
+}
+
+impl TryFrom<FilterDef> for Filter {
+    type Error = FilterError;
+
+    fn try_from(def: FilterDef) -> Result<Self, Self::Error> {
+        let mut filter = Filter::new();
+        if !def.wikis.is_empty() {
+            filter = filter.wikis(def.wikis);
+        }
+        if !def.titles.is_empty() {
+            filter = filter.titles(def.titles);
+        }
+        if !def.users.is_empty() {
+            filter = filter.users(def.users);
+        }
+        if !def.title_contains.is_empty() {
+            filter = filter.title_contains(def.title_contains)?;
+        }
+        if !def.title_matches.is_empty() {
+            filter = filter.title_matches(def.title_matches)?;
+        }
+        if !def.comment_matches.is_empty() {
+            filter = filter.comment_matches(def.comment_matches)?;
+        }
+        if !def.namespaces.is_empty() {
+            filter = filter.namespaces(def.namespaces);
+        }
+        if let Some(bot) = def.bot {
+            filter.conditions.push(Condition::Bot(bot));
+        }
+        if let Some(min) = def.min_byte_change {
+            filter = filter.min_byte_change(min);
+        }
+        if !def.all.is_empty() {
+            filter.conditions.push(Condition::All(def.all));
+        }
+        if !def.any.is_empty() {
+            filter.conditions.push(Condition::Any(def.any));
+        }
+        if let Some(not) = def.not {
+            filter.conditions.push(Condition::Not(not));
+        }
+        Ok(filter)
+    }
+}
+
+impl PartialEq for Filter {
+    fn eq(&self, other: &Self) -> bool {
+        FilterDef::from(self.clone()) == FilterDef::from(other.clone())
+    }
+}
+
+impl Eq for Filter {}
+
+impl Filter {
+    /// A filter that matches everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn set<I, S>(
+        mut self,
+        values: I,
+        get: fn(&mut Condition) -> Option<&mut HashSet<String>>,
+        new: fn(HashSet<String>) -> Condition,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let values = values.into_iter().map(Into::into);
+        match self.conditions.iter_mut().find_map(get) {
+            Some(set) => set.extend(values),
+            None => self.conditions.push(new(values.collect())),
+        }
+        self
+    }
+
+    /// Only events on these wikis, by domain (`en.wikipedia.org`), server
+    /// URL (`https://en.wikipedia.org`) or internal database name
+    /// (`enwiki`)
+    pub fn wikis<I, S>(self, wikis: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.set(
+            wikis
+                .into_iter()
+                .map(|wiki| project::wiki_id(&wiki.into()).to_string()),
+            |condition| match condition {
+                Condition::Wikis(set) => Some(set),
+                _ => None,
+            },
+            Condition::Wikis,
+        )
+    }
+
+    /// Only events about these pages, by prefixed title. Titles are
+    /// [normalized](crate::title::normalize), and match regardless of the
+    /// case of their first letter on wikis that
+    /// [capitalize](crate::title::capitalizes) it.
+    pub fn titles<I, S>(self, titles: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.set(
+            titles
+                .into_iter()
+                .map(|title| title::normalize(&title.into())),
+            |condition| match condition {
+                Condition::Titles(set) => Some(set),
+                _ => None,
+            },
+            Condition::Titles,
+        )
+    }
+
+    /// Only events caused by these users
+    pub fn users<I, S>(self, users: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.set(
+            users,
+            |condition| match condition {
+                Condition::Users(set) => Some(set),
+                _ => None,
+            },
+            Condition::Users,
+        )
+    }
+
+    /// Only events on this wiki, see [`wikis()`](Self::wikis)
+    pub fn wiki(self, wiki: impl Into<String>) -> Self {
+        self.wikis(std::iter::once(wiki))
+    }
+
+    /// Only events caused by this user, see [`users()`](Self::users)
+    pub fn user(self, user: impl Into<String>) -> Self {
+        self.users(std::iter::once(user))
+    }
+
+    /// Only events about pages in these namespaces, by ID
+    pub fn namespaces(
+        mut self,
+        namespaces: impl IntoIterator<Item = i32>,
+    ) -> Self {
+        let existing =
+            self.conditions
+                .iter_mut()
+                .find_map(|condition| match condition {
+                    Condition::Namespaces(set) => Some(set),
+                    _ => None,
+                });
+        match existing {
+            Some(set) => set.extend(namespaces),
+            None => self
+                .conditions
+                .push(Condition::Namespaces(namespaces.into_iter().collect())),
+        }
+        self
+    }
+
+    /// Only events about pages in this namespace, e.g. `0` for articles
+    pub fn namespace(self, namespace: i32) -> Self {
+        self.namespaces(std::iter::once(namespace))
+    }
+
+    fn bot(mut self, bot: bool) -> Self {
+        self.conditions
+            .retain(|condition| !matches!(condition, Condition::Bot(_)));
+        self.conditions.push(Condition::Bot(bot));
+        self
+    }
+
+    /// Only events caused by users that aren't bots, see
+    /// [`Event::is_bot()`]
+    pub fn exclude_bots(self) -> Self {
+        self.bot(false)
+    }
+
+    /// Only events caused by bots
+    pub fn only_bots(self) -> Self {
+        self.bot(true)
+    }
+
+    /// Only edits that grew or shrank the page by at least `bytes`. Other
+    /// kinds of events don't match.
+    pub fn min_byte_change(mut self, bytes: u64) -> Self {
+        self.conditions.retain(|condition| {
+            !matches!(condition, Condition::MinByteChange(_))
+        });
+        self.conditions.push(Condition::MinByteChange(bytes));
+        self
+    }
+
+    /// Only events that `other` matches as well
+    pub fn and(mut self, other: Filter) -> Self {
+        let existing =
+            self.conditions
+                .iter_mut()
+                .find_map(|condition| match condition {
+                    Condition::All(filters) => Some(filters),
+                    _ => None,
+                });
+        match existing {
+            Some(filters) => filters.push(other),
+            None => self.conditions.push(Condition::All(vec![other])),
+        }
+        self
+    }
+
+    /// Events that either this filter or `other` matches
+    pub fn or(mut self, other: Filter) -> Self {
+        let mut filters = match self.conditions.as_mut_slice() {
+            [Condition::Any(filters)] => std::mem::take(filters),
+            _ => vec![self],
+        };
+        filters.push(other);
+        Filter {
+            conditions: vec![Condition::Any(filters)],
+        }
+    }
+
+    /// Only events about pages whose title contains one of `patterns`.
+    /// Fails if the patterns are too many or too long to build one
+    /// automaton from.
+    pub fn title_contains<I, S>(
+        mut self,
+        patterns: I,
+    ) -> Result<Self, aho_corasick::BuildError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut all: Vec<String> = vec![];
+        self.conditions.retain(|condition| match condition {
+            Condition::TitleContains { patterns, .. } => {
+                all.extend(patterns.iter().cloned());
+                false
+            }
+            _ => true,
+        });
+        all.extend(patterns.into_iter().map(Into::into));
+        let matcher = AhoCorasick::new(&all)?;
+        self.conditions.push(Condition::TitleContains {
+            patterns: all,
+            matcher,
+        });
+        Ok(self)
+    }
+
+    fn regex<I, S>(
+        mut self,
+        field: Field,
+        patterns: I,
+    ) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let mut all: Vec<String> = vec![];
+        self.conditions.retain(|condition| match condition {
+            Condition::Regex { field: f, set } if *f == field => {
+                all.extend(set.patterns().iter().cloned());
+                false
+            }
+            _ => true,
+        });
+        all.extend(patterns.into_iter().map(Into::into));
+        let set = RegexSet::new(&all)?;
+        self.conditions.push(Condition::Regex { field, set });
+        Ok(self)
+    }
+
+    /// Only events about pages whose title matches one of the regular
+    /// expressions in `patterns`
+    pub fn title_matches<I, S>(self, patterns: I) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.regex(Field::Title, patterns)
+    }
+
+    /// Only events whose summary matches one of the regular expressions in
+    /// `patterns`
+    pub fn comment_matches<I, S>(
+        self,
+        patterns: I,
+    ) -> Result<Self, regex::Error>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.regex(Field::Comment, patterns)
+    }
+
+    /// The title and summary patterns that match the event, e.g. to tell
+    /// which patrol rule was triggered. Each regex set is only evaluated
+    /// once.
+    pub fn matching_patterns(&self, event: &Event) -> Vec<&str> {
+        self.conditions
+            .iter()
+            .flat_map(|condition| match condition {
+                Condition::Regex { field, set } => set
+                    .matches(field.get(event))
+                    .into_iter()
+                    .map(|index| set.patterns()[index].as_str())
+                    .collect(),
+                Condition::All(filters) | Condition::Any(filters) => filters
+                    .iter()
+                    .flat_map(|filter| filter.matching_patterns(event))
+                    .collect(),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    /// Check every condition against the event and report why each one
+    /// did or didn't match, for debugging filter configurations
+    pub fn explain(&self, event: &Event) -> Explanation {
+        let conditions: Vec<_> = self
+            .conditions
+            .iter()
+            .map(|condition| condition.explain(event))
+            .collect();
+        Explanation {
+            matched: conditions.iter().all(|report| report.matched),
+            conditions,
+        }
+    }
+
+    /// Whether the event meets every condition
+    pub fn matches(&self, event: &Event) -> bool {
+        self.conditions
+            .iter()
+            .all(|condition| condition.matches(event))
+    }
+}
+
+/// Events that the filter doesn't match
+impl Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter {
+            conditions: vec![Condition::Not(Box::new(self))],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    fn edit(title: &str, user: &str) -> Event {
+        let mut edit = testing::edit(1, title, at(0));
+        edit["user"] = user.into();
+        testing::event(&edit)
+    }
+
+    #[test]
+    fn merges_repeated_conditions() {
+        let filter = Filter::new().users(["Alice"]).users(["Bob"]);
+        assert!(filter.matches(&edit("A", "Alice")));
+        assert!(filter.matches(&edit("A", "Bob")));
+        assert!(!filter.matches(&edit("A", "Carol")));
+        let filter = Filter::new()
+            .title_contains(["Foo"])
+            .unwrap()
+            .title_contains(["Bar"])
+            .unwrap();
+        assert!(filter.matches(&edit("A Bar", "Alice")));
+        assert!(!filter.matches(&edit("A Baz", "Alice")));
+        assert_eq!(
+            filter,
+            Filter::new().title_contains(["Foo", "Bar"]).unwrap()
+        );
+    }
+
+    #[test]
+    fn compiles_regexes_into_one_set() {
+        let filter = Filter::new()
+            .title_matches(["^Talk:"])
+            .unwrap()
+            .title_matches(["Sandbox$"])
+            .unwrap();
+        assert!(filter.matches(&edit("Talk:A", "Alice")));
+        assert!(filter.matches(&edit("User:Alice/Sandbox", "Alice")));
+        assert!(!filter.matches(&edit("A", "Alice")));
+        assert_eq!(
+            filter.matching_patterns(&edit("Talk:Sandbox", "Alice")),
+            vec!["^Talk:", "Sandbox$"]
+        );
+        assert!(Filter::new().title_matches(["("]).is_err());
+    }
+
+    #[test]
+    fn explains_every_condition() {
+        let filter =
+            Filter::new().users(["Bob"]).title_matches(["^A"]).unwrap();
+        let explanation = filter.explain(&edit("Ada", "Alice"));
+        assert!(!explanation.matched);
+        let matched: Vec<_> = explanation
+            .conditions
+            .iter()
+            .map(|report| report.matched)
+            .collect();
+        assert_eq!(matched, [false, true]);
+        assert_eq!(
+            explanation.conditions[0].detail,
+            "\"Alice\" is not included"
+        );
+        assert!(filter.explain(&edit("Ada", "Bob")).matched);
+    }
+
+    #[test]
+    fn round_trips_through_serde() {
+        let filter = Filter::new()
+            .wiki("enwiki")
+            .users(["Bob", "Alice"])
+            .title_contains(["Foo"])
+            .unwrap()
+            .comment_matches(["typo"])
+            .unwrap();
+        let json = serde_json::to_string(&filter).unwrap();
+        let parsed: Filter = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, filter);
+        assert!(!parsed.matches(&edit("A", "Alice")));
+        assert!(parsed.matches(&edit("Foo", "Alice")));
+        let invalid = r#"{"title_matches": ["("]}"#;
+        assert!(serde_json::from_str::<Filter>(invalid).is_err());
+    }
+
+    #[test]
+    fn combines_conditions() {
+        let ada = edit("Ada", "Alice");
+        let mut bot = testing::edit(2, "Talk:Ada", at(0));
+        bot["namespace"] = 1.into();
+        bot["bot"] = true.into();
+        bot["length"]["new"] = 12.into();
+        let bot = testing::event(&bot);
+
+        assert!(Filter::new().namespace(0).matches(&ada));
+        assert!(!Filter::new().namespace(0).matches(&bot));
+        assert!(Filter::new().exclude_bots().matches(&ada));
+        assert!(Filter::new().only_bots().matches(&bot));
+        assert!(Filter::new().min_byte_change(10).matches(&ada));
+        assert!(!Filter::new().min_byte_change(10).matches(&bot));
+
+        let articles = Filter::new().namespace(0);
+        let bots = Filter::new().only_bots();
+        let either = articles.clone().or(bots.clone());
+        assert!(either.matches(&ada) && either.matches(&bot));
+        let both = articles.clone().and(bots);
+        assert!(!both.matches(&ada) && !both.matches(&bot));
+        assert!(!(!articles.clone()).matches(&ada));
+        assert!((!articles).matches(&bot));
+    }
+}
@@ -0,0 +1,308 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Stable fingerprints of event content
+//!
+//! A [`Fingerprint`] is a SHA-256 hash over a canonical form of an event:
+//! its JSON with object keys sorted and without the Kafka topic, partition
+//! and offset, which differ between data centers and say nothing about
+//! the event itself. Independent recorders that received the same event
+//! end up with the same fingerprint, so it can be used to deduplicate
+//! their recordings.
+//!
+//! A [`Digest`] combines the fingerprints of many events regardless of
+//! their order, e.g. to check that an [archive](crate::archive::Archive)
+//! arrived intact after being transferred or re-split.
+//!
+//! Fingerprints of a raw message and of the [`Event`] parsed from it can
+//! differ, since parsing may fill in defaults; compare like with like.
+use crate::Event;
+use serde_json::Value;
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+/// Fields of `meta` left out of fingerprints
+const POSITION: [&str; 3] = ["topic", "partition", "offset"];
+
+/// SHA-256 hash of an event's canonical form, see the
+/// [module documentation](self)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    /// Fingerprint of a parsed event
+    pub fn of(event: &Event) -> Self {
+        Self::of_json(
+            &serde_json::to_value(event).expect("events serialize to JSON"),
+        )
+    }
+
+    /// Fingerprint of a raw message, e.g. a line of a recording
+    pub fn of_message(data: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self::of_json(&serde_json::from_str(data)?))
+    }
+
+    /// Fingerprint of an event as JSON
+    pub fn of_json(value: &Value) -> Self {
+        let mut hasher = Sha256::new();
+        let mut canonical = String::new();
+        write_canonical(&mut canonical, value, true);
+        hasher.update(canonical.as_bytes());
+        Self(hasher.finalize().into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl From<&Event> for Fingerprint {
+    fn from(event: &Event) -> Self {
+        Self::of(event)
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Fingerprint({})", self)
+    }
+}
+
+/// A fingerprint or digest that isn't 64 hexadecimal digits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseFingerprintError;
+
+impl fmt::Display for ParseFingerprintError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "expected 64 hexadecimal digits")
+    }
+}
+
+impl std::error::Error for ParseFingerprintError {}
+
+impl FromStr for Fingerprint {
+    type Err = ParseFingerprintError;
+
+    fn from_str(hex: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 32];
+        if hex.len() != 64 {
+            return Err(ParseFingerprintError);
+        }
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = hex
+                .get(i * 2..i * 2 + 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or(ParseFingerprintError)?;
+        }
+        Ok(Self(bytes))
+    }
+}
+
+/// Compact JSON with object keys sorted, leaving out the Kafka position
+/// from the top-level `meta`
+fn write_canonical(out: &mut String, value: &Value, top: bool) {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<_> = map.keys().collect();
+            keys.sort();
+            out.push('{');
+            let mut first = true;
+            for key in keys {
+                let value = &map[key];
+                let value = match (top && key == "meta", value) {
+                    (true, Value::Object(meta)) => {
+                        let mut meta = meta.clone();
+                        for field in POSITION {
+                            meta.remove(field);
+                        }
+                        Value::Object(meta)
+                    }
+                    _ => value.clone(),
+                };
+                if !first {
+                    out.push(',');
+                }
+                first = false;
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_canonical(out, &value, false);
+            }
+            out.push('}');
+        }
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(out, value, false);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// Order-independent combination of the fingerprints of many events, along
+/// with how many there were. Fingerprints are added up rather than XORed,
+/// so duplicated events change the digest too.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Digest {
+    count: u64,
+    sum: [u8; 32],
+}
+
+impl Digest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a fingerprint
+    pub fn add(&mut self, fingerprint: Fingerprint) {
+        self.count += 1;
+        let mut carry = 0;
+        for (sum, byte) in self.sum.iter_mut().zip(fingerprint.0).rev() {
+            let total = u16::from(*sum) + u16::from(byte) + carry;
+            *sum = total as u8;
+            carry = total >> 8;
+        }
+    }
+
+    /// Combine with the digest of other events, e.g. another file
+    pub fn merge(&mut self, other: &Digest) {
+        let count = self.count;
+        self.add(Fingerprint(other.sum));
+        self.count = count + other.count;
+    }
+
+    /// Number of events added
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl Extend<Fingerprint> for Digest {
+    fn extend<I: IntoIterator<Item = Fingerprint>>(&mut self, iter: I) {
+        for fingerprint in iter {
+            self.add(fingerprint);
+        }
+    }
+}
+
+impl FromIterator<Fingerprint> for Digest {
+    fn from_iter<I: IntoIterator<Item = Fingerprint>>(iter: I) -> Self {
+        let mut digest = Self::new();
+        digest.extend(iter);
+        digest
+    }
+}
+
+/// Formatted as `<count>:<hex>`
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.count, Fingerprint(self.sum))
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = ParseFingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, sum) = s.split_once(':').ok_or(ParseFingerprintError)?;
+        Ok(Self {
+            count: count.parse().map_err(|_| ParseFingerprintError)?,
+            sum: sum.parse::<Fingerprint>()?.0,
+        })
+    }
+}
+
+impl Event {
+    /// Stable fingerprint of the event's content, see
+    /// [`fingerprint`](crate::fingerprint)
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint::of(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn ignores_key_order_and_kafka_positions() {
+        let message = testing::edit(1, "A", at(0));
+        let mut elsewhere = message.clone();
+        elsewhere["meta"]["topic"] = "codfw.mediawiki.recentchange".into();
+        elsewhere["meta"]["offset"] = 12345.into();
+        let reordered: String = {
+            // serde_json keeps keys sorted, so reverse them by hand
+            let fields = message.as_object().unwrap();
+            let fields: Vec<_> = fields
+                .iter()
+                .rev()
+                .map(|(key, value)| format!("{:?}:{}", key, value))
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        };
+        let fingerprint = Fingerprint::of_json(&message);
+        assert_eq!(Fingerprint::of_json(&elsewhere), fingerprint);
+        assert_eq!(Fingerprint::of_message(&reordered).unwrap(), fingerprint);
+        assert_ne!(
+            Fingerprint::of_json(&testing::edit(1, "B", at(0))),
+            fingerprint
+        );
+        assert_eq!(fingerprint.to_string().parse(), Ok(fingerprint));
+        assert_eq!("ab".parse::<Fingerprint>(), Err(ParseFingerprintError));
+    }
+
+    #[test]
+    fn digests_regardless_of_order() {
+        let fingerprints: Vec<_> = (0..3)
+            .map(|offset| testing::edit_event(offset, "A", at(0)))
+            .map(|event| event.fingerprint())
+            .collect();
+        let digest: Digest = fingerprints.iter().copied().collect();
+        let reversed: Digest = fingerprints.iter().rev().copied().collect();
+        assert_eq!(digest, reversed);
+        assert_eq!(digest.count(), 3);
+
+        let mut merged: Digest = fingerprints[..1].iter().copied().collect();
+        merged.merge(&fingerprints[1..].iter().copied().collect());
+        assert_eq!(merged, digest);
+        let mut duplicated = digest;
+        duplicated.add(fingerprints[0]);
+        assert_ne!(duplicated, digest);
+        assert_eq!(digest.to_string().parse(), Ok(digest));
+    }
+}
@@ -0,0 +1,157 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Detecting skipped events using Kafka offsets
+//!
+//! Every event carries the Kafka topic, partition and offset it was read
+//! from, and offsets within a partition are consecutive. A jump means some
+//! events were never delivered, which consumers that need completeness can
+//! then backfill.
+use crate::backend::BackendError;
+use crate::resume::RawEvent;
+use futures::{Stream, StreamExt};
+use std::collections::HashMap;
+
+/// Offsets that were skipped in a partition
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GapDetected {
+    pub topic: String,
+    pub partition: u32,
+    /// First missing offset
+    pub first_missing: u64,
+    /// Last missing offset, inclusive
+    pub last_missing: u64,
+}
+
+impl GapDetected {
+    /// Number of missing events
+    pub fn missing(&self) -> u64 {
+        self.last_missing - self.first_missing + 1
+    }
+}
+
+/// Tracks the latest offset per topic and partition
+#[derive(Clone, Debug, Default)]
+pub struct GapDetector {
+    offsets: HashMap<(String, u32), u64>,
+}
+
+impl GapDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an offset, returning the gap before it if there is one.
+    /// Offsets at or before the latest one (e.g. duplicates after a
+    /// reconnect) are ignored.
+    pub fn observe(
+        &mut self,
+        topic: &str,
+        partition: u32,
+        offset: u64,
+    ) -> Option<GapDetected> {
+        let key = (topic.to_string(), partition);
+        let gap = match self.offsets.get(&key) {
+            Some(last) if offset <= *last => return None,
+            Some(last) if offset > last + 1 => Some(GapDetected {
+                topic: topic.to_string(),
+                partition,
+                first_missing: last + 1,
+                last_missing: offset - 1,
+            }),
+            _ => None,
+        };
+        self.offsets.insert(key, offset);
+        gap
+    }
+
+    /// Record the offset from an event's raw JSON, see
+    /// [`observe()`](GapDetector::observe)
+    pub fn observe_raw(&mut self, data: &str) -> Option<GapDetected> {
+        let RawEvent { meta } = serde_json::from_str(data).ok()?;
+        self.observe(&meta.topic, meta.partition, meta.offset)
+    }
+}
+
+/// Watch a backend for gaps, calling `on_gap` for each one. Every message
+/// is checked, including ones that won't parse into an
+/// [`Event`](crate::Event), so that filtered out events aren't mistaken
+/// for missing ones.
+pub fn detect_gaps<B>(
+    backend: B,
+    mut on_gap: impl FnMut(GapDetected),
+) -> impl Stream<Item = Result<String, BackendError>>
+where
+    B: Stream<Item = Result<String, BackendError>>,
+{
+    let mut detector = GapDetector::new();
+    backend.inspect(move |message| {
+        if let Ok(data) = message {
+            if let Some(gap) = detector.observe_raw(data) {
+                on_gap(gap);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::stream;
+
+    #[test]
+    fn reports_skipped_offsets() {
+        let mut detector = GapDetector::new();
+        let topic = "eqiad.mediawiki.recentchange";
+        assert_eq!(detector.observe(topic, 0, 1), None);
+        assert_eq!(detector.observe(topic, 0, 2), None);
+        let gap = detector.observe(topic, 0, 5).unwrap();
+        assert_eq!((gap.first_missing, gap.last_missing), (3, 4));
+        assert_eq!(gap.missing(), 2);
+        // Partitions are tracked separately
+        assert_eq!(detector.observe(topic, 1, 10), None);
+    }
+
+    #[test]
+    fn ignores_old_and_duplicate_offsets() {
+        let mut detector = GapDetector::new();
+        let topic = "eqiad.mediawiki.recentchange";
+        detector.observe(topic, 0, 5);
+        assert_eq!(detector.observe(topic, 0, 5), None);
+        assert_eq!(detector.observe(topic, 0, 2), None);
+        assert_eq!(detector.observe(topic, 0, 6), None);
+    }
+
+    #[test]
+    fn checks_every_message() {
+        let messages = [1, 2, 4, 7]
+            .iter()
+            .map(|offset| Ok(testing::edit(*offset, "A", at(0)).to_string()))
+            .chain(std::iter::once(Ok("not json".to_string())))
+            .collect::<Vec<_>>();
+        let mut gaps = vec![];
+        let delivered = futures::executor::block_on(
+            detect_gaps(stream::iter(messages), |gap| gaps.push(gap)).count(),
+        );
+        assert_eq!(delivered, 5);
+        let missing: Vec<_> = gaps
+            .iter()
+            .map(|gap| (gap.first_missing, gap.last_missing))
+            .collect();
+        assert_eq!(missing, vec![(3, 3), (5, 6)]);
+    }
+}
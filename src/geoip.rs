@@ -0,0 +1,155 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Locating anonymous editors
+//!
+//! Edits by logged out users are attributed to their IP address, which
+//! [`GeoIp`] resolves to a country and autonomous system (roughly, the
+//! network provider) using MaxMind databases, e.g. the free GeoLite2
+//! Country and ASN ones. The databases aren't included, and have to be
+//! downloaded separately.
+use crate::Event;
+use maxminddb::{MaxMindDbError, Reader};
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Where an IP address is, as far as the databases know
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 country code, e.g. `DE`
+    pub country: Option<String>,
+    /// Autonomous system number
+    pub asn: Option<u32>,
+    /// Organization the autonomous system is registered to
+    pub as_organization: Option<String>,
+}
+
+/// An event along with where its anonymous editor is
+#[derive(Clone, Debug)]
+pub struct GeoEnriched {
+    pub event: Event,
+    /// `None` if the event wasn't by an IP address, or it couldn't be
+    /// looked up
+    pub geo: Option<GeoInfo>,
+}
+
+#[derive(Deserialize)]
+struct CountryRecord {
+    country: Option<Country>,
+}
+
+#[derive(Deserialize)]
+struct Country {
+    iso_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AsnRecord {
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<String>,
+}
+
+/// Looks up IP addresses in MaxMind databases
+#[derive(Debug, Default)]
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Without any databases, every lookup is empty
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Look up countries in the database at `path`, e.g.
+    /// `GeoLite2-Country.mmdb`
+    pub fn country_database(
+        mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, MaxMindDbError> {
+        self.country = Some(Reader::open_readfile(path)?);
+        Ok(self)
+    }
+
+    /// Look up autonomous systems in the database at `path`, e.g.
+    /// `GeoLite2-ASN.mmdb`
+    pub fn asn_database(
+        mut self,
+        path: impl AsRef<Path>,
+    ) -> Result<Self, MaxMindDbError> {
+        self.asn = Some(Reader::open_readfile(path)?);
+        Ok(self)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Result<GeoInfo, MaxMindDbError> {
+        let mut info = GeoInfo::default();
+        if let Some(reader) = &self.country {
+            if let Some(record) =
+                reader.lookup(ip)?.decode::<CountryRecord>()?
+            {
+                info.country = record.country.and_then(|c| c.iso_code);
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Some(record) = reader.lookup(ip)?.decode::<AsnRecord>()? {
+                info.asn = record.autonomous_system_number;
+                info.as_organization = record.autonomous_system_organization;
+            }
+        }
+        Ok(info)
+    }
+
+    /// Look up whoever caused `event`, or `None` if it wasn't an IP address
+    pub fn lookup_event(
+        &self,
+        event: &Event,
+    ) -> Option<Result<GeoInfo, MaxMindDbError>> {
+        let ip: IpAddr = event.user().parse().ok()?;
+        Some(self.lookup(ip))
+    }
+
+    /// Attach [`GeoInfo`] to `event`, if it was by an IP address
+    pub fn enrich(&self, event: Event) -> GeoEnriched {
+        let geo = self.lookup_event(&event).and_then(Result::ok);
+        GeoEnriched { event, geo }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    fn edit_by(user: &str) -> Event {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["user"] = user.into();
+        testing::event(&edit)
+    }
+
+    #[test]
+    fn only_looks_up_ip_addresses() {
+        let geoip = GeoIp::new();
+        assert!(geoip.lookup_event(&edit_by("Alice")).is_none());
+        assert!(geoip.enrich(edit_by("Alice")).geo.is_none());
+        // Without databases there's nothing to find
+        let enriched = geoip.enrich(edit_by("2001:db8::1"));
+        assert_eq!(enriched.geo, Some(GeoInfo::default()));
+        let missing = std::env::temp_dir().join("eventstreams-missing.mmdb");
+        assert!(GeoIp::new().country_database(missing).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Handing off the stream position to a replacement process
+//!
+//! For zero-downtime deploys, the running process listens on a Unix socket
+//! with [`HandoffListener`], checking it regularly while consuming events.
+//! The replacement process calls [`request()`] to receive the running
+//! process' [`ResumeToken`], after which the old process should stop.
+use crate::resume::ResumeToken;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Sent by [`request()`], so that connections that only check whether the
+/// socket is live, as [`HandoffListener::bind()`] does, aren't mistaken
+/// for a replacement
+const REQUEST: u8 = b'?';
+
+/// How long [`HandoffListener::try_handoff()`] waits for a connection to
+/// say what it wants
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Waits for a replacement process to ask for the stream position
+#[derive(Debug)]
+pub struct HandoffListener {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl HandoffListener {
+    /// Listen on the socket at `path`, replacing a stale socket left behind
+    /// by a process that's gone. Fails if a process is still listening
+    /// there, or something other than a socket is in the way.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!(
+                            "{} is still being listened on",
+                            path.display()
+                        ),
+                    ));
+                }
+                fs::remove_file(path)?;
+            }
+            Ok(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", path.display()),
+                ))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// If a replacement process is waiting, hand it `token` and return
+    /// `true`; the caller should then stop consuming events. Doesn't block
+    /// unless something connected, and then only until it sends its
+    /// request. Connections that close without one are ignored.
+    pub fn try_handoff(&self, token: &ResumeToken) -> io::Result<bool> {
+        let mut stream = match self.listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        let mut request = [0];
+        match stream.read(&mut request) {
+            Ok(1) if request[0] == REQUEST => {}
+            Ok(_) => return Ok(false),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(false)
+            }
+            Err(err) => return Err(err),
+        }
+        stream.write_all(&serde_json::to_vec(token)?)?;
+        Ok(true)
+    }
+}
+
+impl Drop for HandoffListener {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Ask the process listening on `path` for its stream position
+pub fn request(path: impl AsRef<Path>) -> io::Result<ResumeToken> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(&[REQUEST])?;
+    let mut buf = vec![];
+    stream.read_to_end(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use chrono::Utc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "eventstreams-handoff-{}-{}.sock",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn hands_off_the_position() {
+        let path = socket_path("hands-off");
+        let listener = HandoffListener::bind(&path).unwrap();
+        let mut token = ResumeToken::default();
+        token.observe(&testing::edit_event(7, "A", Utc::now()));
+        assert!(!listener.try_handoff(&token).unwrap());
+        let requester = {
+            let path = path.clone();
+            thread::spawn(move || request(path).unwrap())
+        };
+        while !listener.try_handoff(&token).unwrap() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(requester.join().unwrap(), token);
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replaces_stale_sockets_only() {
+        let path = socket_path("stale");
+        // Dropping a std listener leaves its socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = HandoffListener::bind(&path).unwrap();
+        let err = HandoffListener::bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+
+        fs::write(&path, "not a socket").unwrap();
+        let err = HandoffListener::bind(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "not a socket");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ignores_liveness_checks() {
+        let path = socket_path("liveness");
+        let listener = HandoffListener::bind(&path).unwrap();
+        for _ in 0..2 {
+            let err = HandoffListener::bind(&path).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        }
+        let token = ResumeToken::default();
+        for _ in 0..3 {
+            assert!(!listener.try_handoff(&token).unwrap());
+        }
+        assert!(path.exists());
+    }
+}
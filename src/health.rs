@@ -0,0 +1,135 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! How recently each stream delivered something
+//!
+//! A connection can stall without being closed, in which case nothing
+//! arrives and nothing fails either. Wikimedia injects canary events
+//! (`meta.domain` is `canary`) into every stream regularly, so even a
+//! stream with little real traffic should have had a recent canary.
+//! [`EventStream::health()`](crate::EventStream::health) reports when each
+//! stream's last event and canary arrived, going by when they were
+//! received rather than `meta.dt`, by the stream's
+//! [clock](crate::EventStreamBuilder::clock).
+use crate::clock::{Clock, SystemClock};
+use crate::Event;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What has arrived from one stream
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct StreamHealth {
+    /// When the last event other than a canary arrived
+    pub last_event: Option<DateTime<Utc>>,
+    /// When the last canary arrived
+    pub last_canary: Option<DateTime<Utc>>,
+    pub events: u64,
+    pub canaries: u64,
+}
+
+impl StreamHealth {
+    /// When anything last arrived, canary or not
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_event.max(self.last_canary)
+    }
+}
+
+/// How recently each stream delivered something, as of `checked_at`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Health {
+    pub checked_at: DateTime<Utc>,
+    /// By stream name (`meta.stream`), for streams that delivered anything
+    pub streams: BTreeMap<String, StreamHealth>,
+}
+
+impl Health {
+    /// Time from the last event on `stream` until the check, other than
+    /// canaries
+    pub fn since_last_event(&self, stream: &str) -> Option<Duration> {
+        self.since(self.streams.get(stream)?.last_event?)
+    }
+
+    /// Time from the last canary on `stream` until the check
+    pub fn since_last_canary(&self, stream: &str) -> Option<Duration> {
+        self.since(self.streams.get(stream)?.last_canary?)
+    }
+
+    fn since(&self, time: DateTime<Utc>) -> Option<Duration> {
+        Some((self.checked_at - time).to_std().unwrap_or_default())
+    }
+
+    /// Streams that have delivered nothing, canaries included, for longer
+    /// than `threshold`
+    pub fn stalled(&self, threshold: Duration) -> Vec<&str> {
+        self.streams
+            .iter()
+            .filter(|(_, health)| {
+                health
+                    .last_seen()
+                    .and_then(|seen| self.since(seen))
+                    .is_some_and(|since| since > threshold)
+            })
+            .map(|(stream, _)| stream.as_str())
+            .collect()
+    }
+}
+
+/// Builds a [`Health`] as events pass through
+#[derive(Debug)]
+pub(crate) struct HealthTracker {
+    streams: BTreeMap<String, StreamHealth>,
+    clock: Arc<dyn Clock>,
+}
+
+impl HealthTracker {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            streams: BTreeMap::new(),
+            clock,
+        }
+    }
+
+    pub(crate) fn observe(&mut self, event: &Event) {
+        let now = self.clock.now();
+        let health = self
+            .streams
+            .entry(event.meta().stream.to_string())
+            .or_default();
+        if event.is_canary() {
+            health.last_canary = Some(now);
+            health.canaries += 1;
+        } else {
+            health.last_event = Some(now);
+            health.events += 1;
+        }
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        Health {
+            checked_at: self.clock.now(),
+            streams: self.streams.clone(),
+        }
+    }
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
@@ -0,0 +1,186 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Per-language activity series
+//!
+//! Events are counted per language, as parsed from the wiki's domain, in
+//! fixed event-time buckets. Each [`LanguageActivity`] covers one bucket,
+//! so a series of them makes up a language × time heatmap. Wikis that
+//! aren't language editions (Wikidata, Commons, etc.) are counted under
+//! [`MULTILINGUAL`].
+use crate::privacy::Noise;
+use crate::watermark::Watermark;
+use crate::Event;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+/// Key used for wikis that don't have a language in their domain
+pub const MULTILINGUAL: &str = "mul";
+
+/// Number of events per language during a single bucket
+#[derive(Clone, Debug)]
+pub struct LanguageActivity {
+    /// Inclusive start of the bucket
+    pub start: DateTime<Utc>,
+    /// Exclusive end of the bucket
+    pub end: DateTime<Utc>,
+    /// Event counts keyed by language code
+    pub languages: BTreeMap<String, u64>,
+}
+
+impl LanguageActivity {
+    /// Languages ordered from most to least active
+    pub fn ranked(&self) -> Vec<(&str, u64)> {
+        let mut ranked: Vec<_> = self
+            .languages
+            .iter()
+            .map(|(language, count)| (language.as_str(), *count))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        ranked
+    }
+
+    /// A copy with `noise` added to every language's count, for
+    /// publishing
+    pub fn with_noise(&self, noise: &mut Noise) -> LanguageActivity {
+        let mut noisy = self.clone();
+        for count in noisy.languages.values_mut() {
+            *count = noise.count(*count);
+        }
+        noisy
+    }
+}
+
+/// Counts events per language in fixed-size buckets aligned to the Unix
+/// epoch
+#[derive(Clone, Debug)]
+pub struct LanguageHeatmap {
+    bucket: Duration,
+    watermark: Watermark,
+    open: BTreeMap<DateTime<Utc>, BTreeMap<String, u64>>,
+}
+
+impl LanguageHeatmap {
+    /// Count events in buckets of length `bucket`, at least a millisecond,
+    /// waiting `allowed_lateness` past the end of each for out-of-order
+    /// events. Events arriving later than that are not counted.
+    pub fn new(
+        bucket: std::time::Duration,
+        allowed_lateness: std::time::Duration,
+    ) -> Self {
+        let bucket =
+            Duration::from_std(bucket).expect("bucket size out of range");
+        // Buckets are aligned in whole milliseconds
+        assert!(
+            bucket.num_milliseconds() > 0,
+            "bucket size must be at least a millisecond"
+        );
+        Self {
+            bucket,
+            watermark: Watermark::new(allowed_lateness),
+            open: BTreeMap::new(),
+        }
+    }
+
+    fn bucket_start(&self, dt: DateTime<Utc>) -> DateTime<Utc> {
+        let size = self.bucket.num_milliseconds();
+        let millis = dt.timestamp_millis();
+        Utc.timestamp_millis_opt(millis - millis.rem_euclid(size))
+            .unwrap()
+    }
+
+    /// Count an event, returning any buckets that were closed as a result
+    pub fn push(&mut self, event: &Event) -> Vec<LanguageActivity> {
+        let start = self.bucket_start(event.dt());
+        let bucket = self.bucket;
+        if self
+            .watermark
+            .current()
+            .is_some_and(|watermark| start + bucket <= watermark)
+        {
+            return vec![];
+        }
+        self.watermark.observe(event);
+        let language = event.language().unwrap_or(MULTILINGUAL);
+        *self
+            .open
+            .entry(start)
+            .or_default()
+            .entry(language.to_string())
+            .or_default() += 1;
+        let watermark = self.watermark.current().unwrap();
+        let mut closed = vec![];
+        while let Some(entry) = self.open.first_entry() {
+            if *entry.key() + bucket > watermark {
+                break;
+            }
+            let (start, languages) = entry.remove_entry();
+            closed.push(LanguageActivity {
+                start,
+                end: start + bucket,
+                languages,
+            });
+        }
+        closed
+    }
+
+    /// Close all remaining buckets, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<LanguageActivity> {
+        let bucket = self.bucket;
+        std::mem::take(&mut self.open)
+            .into_iter()
+            .map(|(start, languages)| LanguageActivity {
+                start,
+                end: start + bucket,
+                languages,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    fn edit_on(server_name: &str, seconds: i64) -> Event {
+        let mut edit = testing::edit(1, "A", at(seconds));
+        edit["server_name"] = server_name.into();
+        testing::event(&edit)
+    }
+
+    #[test]
+    fn counts_events_per_language() {
+        let mut heatmap =
+            LanguageHeatmap::new(StdDuration::from_secs(60), StdDuration::ZERO);
+        heatmap.push(&edit_on("de.wikipedia.org", 0));
+        heatmap.push(&edit_on("en.wikipedia.org", 10));
+        heatmap.push(&edit_on("de.wiktionary.org", 20));
+        heatmap.push(&edit_on("www.wikidata.org", 30));
+        let closed = heatmap.push(&edit_on("en.wikipedia.org", 70));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start, at(0));
+        assert_eq!(
+            closed[0].ranked(),
+            [("de", 2), ("en", 1), (MULTILINGUAL, 1)]
+        );
+        // Past the watermark, so not counted
+        assert!(heatmap.push(&edit_on("fr.wikipedia.org", 0)).is_empty());
+        let flushed = heatmap.flush();
+        assert_eq!(flushed[0].ranked(), [("en", 1)]);
+    }
+}
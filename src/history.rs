@@ -0,0 +1,486 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Exporting events in the layout of the mediawiki_history dumps
+//!
+//! Wikimedia publishes the full history of every wiki as
+//! [mediawiki_history](https://wikitech.wikimedia.org/wiki/Data_Platform/Data_Lake/Edits/Mediawiki_history_dumps)
+//! TSV dumps, with one denormalized row per revision, page or user
+//! event. [`HistoryRow`] produces rows in the same layout from live
+//! events, so captured data can be appended to a dump and analyzed with
+//! the same tools.
+//!
+//! Rows can only hold what the event says: fields that the dumps compute
+//! from the whole history, like revert detection or revision counts per
+//! page, are left empty, and the "current" variants of historical fields
+//! are filled in with their value at the time of the event. Page titles
+//! are written without their namespace prefix and with underscores, as in
+//! the dumps, which assumes the prefix is everything up to the first `:`
+//! outside the main namespace.
+use crate::{EditEvent, Event, LogEvent, Performer, RevisionCreateEvent};
+use chrono::{DateTime, Utc};
+
+/// Names of the dump's columns, in order
+pub const COLUMNS: [&str; 70] = [
+    "wiki_db",
+    "event_entity",
+    "event_type",
+    "event_timestamp",
+    "event_comment_escaped",
+    "event_user_id",
+    "event_user_text_historical_escaped",
+    "event_user_text_escaped",
+    "event_user_blocks_historical_string",
+    "event_user_blocks_string",
+    "event_user_groups_historical_string",
+    "event_user_groups_string",
+    "event_user_is_bot_by_historical_string",
+    "event_user_is_bot_by_string",
+    "event_user_is_created_by_self",
+    "event_user_is_created_by_system",
+    "event_user_is_created_by_peer",
+    "event_user_is_anonymous",
+    "event_user_registration_timestamp",
+    "event_user_creation_timestamp",
+    "event_user_first_edit_timestamp",
+    "event_user_revision_count",
+    "event_user_seconds_since_previous_revision",
+    "page_id",
+    "page_title_historical_escaped",
+    "page_title_escaped",
+    "page_namespace_historical",
+    "page_namespace_is_content_historical",
+    "page_namespace",
+    "page_namespace_is_content",
+    "page_is_redirect",
+    "page_is_deleted",
+    "page_creation_timestamp",
+    "page_first_edit_timestamp",
+    "page_revision_count",
+    "page_seconds_since_previous_revision",
+    "user_id",
+    "user_text_historical_escaped",
+    "user_text_escaped",
+    "user_blocks_historical_string",
+    "user_blocks_string",
+    "user_groups_historical_string",
+    "user_groups_string",
+    "user_is_bot_by_historical_string",
+    "user_is_bot_by_string",
+    "user_is_created_by_self",
+    "user_is_created_by_system",
+    "user_is_created_by_peer",
+    "user_is_anonymous",
+    "user_registration_timestamp",
+    "user_creation_timestamp",
+    "user_first_edit_timestamp",
+    "revision_id",
+    "revision_parent_id",
+    "revision_minor_edit",
+    "revision_deleted_parts_string",
+    "revision_deleted_parts_are_suppressed",
+    "revision_text_bytes",
+    "revision_text_bytes_diff",
+    "revision_text_sha1",
+    "revision_content_model",
+    "revision_content_format",
+    "revision_is_deleted_by_page_deletion",
+    "revision_deleted_by_page_deletion_timestamp",
+    "revision_is_identity_reverted",
+    "revision_first_identity_reverting_revision_id",
+    "revision_seconds_to_identity_revert",
+    "revision_is_identity_revert",
+    "revision_is_from_before_page_creation",
+    "revision_tags_string",
+];
+
+/// What a row is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Entity {
+    Revision,
+    Page,
+    User,
+}
+
+impl Entity {
+    /// Name used in the `event_entity` column
+    pub fn name(self) -> &'static str {
+        match self {
+            Entity::Revision => "revision",
+            Entity::Page => "page",
+            Entity::User => "user",
+        }
+    }
+}
+
+/// One row of a mediawiki_history dump. Fields are `None` where the event
+/// doesn't say.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryRow {
+    pub wiki_db: String,
+    pub event_entity: Entity,
+    /// e.g. `create`, `move`, `delete` or `altergroups`
+    pub event_type: String,
+    pub event_timestamp: DateTime<Utc>,
+    pub event_comment: String,
+    pub event_user_id: Option<u64>,
+    /// `None` if the user has been suppressed
+    pub event_user_text: Option<String>,
+    pub event_user_groups: Vec<String>,
+    pub event_user_is_bot: Option<bool>,
+    pub event_user_is_anonymous: Option<bool>,
+    pub event_user_registration_timestamp: Option<DateTime<Utc>>,
+    pub event_user_revision_count: Option<u64>,
+    pub page_id: Option<u64>,
+    /// Title without the namespace prefix, with underscores
+    pub page_title: Option<String>,
+    pub page_namespace: Option<i32>,
+    pub page_is_redirect: Option<bool>,
+    /// Name of the user a user event is about
+    pub user_text: Option<String>,
+    /// Groups of the user a user event is about, after the event
+    pub user_groups: Vec<String>,
+    pub revision_id: Option<u64>,
+    pub revision_parent_id: Option<u64>,
+    pub revision_minor_edit: Option<bool>,
+    pub revision_text_bytes: Option<u64>,
+    pub revision_text_bytes_diff: Option<i64>,
+    pub revision_text_sha1: Option<String>,
+    pub revision_content_model: Option<String>,
+}
+
+impl HistoryRow {
+    fn new(
+        wiki_db: &str,
+        event_entity: Entity,
+        event_type: &str,
+        event_timestamp: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            wiki_db: wiki_db.to_string(),
+            event_entity,
+            event_type: event_type.to_string(),
+            event_timestamp,
+            event_comment: String::new(),
+            event_user_id: None,
+            event_user_text: None,
+            event_user_groups: vec![],
+            event_user_is_bot: None,
+            event_user_is_anonymous: None,
+            event_user_registration_timestamp: None,
+            event_user_revision_count: None,
+            page_id: None,
+            page_title: None,
+            page_namespace: None,
+            page_is_redirect: None,
+            user_text: None,
+            user_groups: vec![],
+            revision_id: None,
+            revision_parent_id: None,
+            revision_minor_edit: None,
+            revision_text_bytes: None,
+            revision_text_bytes_diff: None,
+            revision_text_sha1: None,
+            revision_content_model: None,
+        }
+    }
+
+    fn page(mut self, title: &str, namespace: i32) -> Self {
+        self.page_title = Some(unprefixed(title, namespace));
+        self.page_namespace = Some(namespace);
+        self
+    }
+
+    fn performer(mut self, performer: &Option<Performer>) -> Self {
+        if let Some(performer) = performer {
+            self.event_user_id = performer.user_id;
+            self.event_user_text = Some(performer.user_text.to_string());
+            self.event_user_groups = performer.user_groups.clone();
+            self.event_user_is_bot = Some(performer.user_is_bot);
+            self.event_user_is_anonymous = Some(performer.user_id.is_none());
+            self.event_user_registration_timestamp =
+                performer.user_registration_dt;
+            self.event_user_revision_count = performer.user_edit_count;
+        }
+        self
+    }
+
+    fn from_edit(edit: &EditEvent, entity: Entity) -> Self {
+        let mut row = Self::new(&edit.wiki, entity, "create", edit.meta.dt)
+            .page(&edit.title, edit.namespace);
+        row.event_comment = edit.comment.clone();
+        row.event_user_text = Some(edit.user.to_string());
+        row.event_user_is_bot = Some(edit.bot);
+        if entity == Entity::Revision {
+            row.revision_id = Some(edit.revision.new.into());
+            row.revision_parent_id = edit.revision.old.map(u64::from);
+            row.revision_minor_edit = Some(edit.is_minor());
+            row.revision_text_bytes = Some(edit.length.new.into());
+            row.revision_text_bytes_diff = Some(edit.byte_change());
+        }
+        row
+    }
+
+    fn from_revision(revision: &RevisionCreateEvent, entity: Entity) -> Self {
+        let mut row =
+            Self::new(&revision.database, entity, "create", revision.meta.dt)
+                .page(&revision.page_title, revision.page_namespace)
+                .performer(&revision.performer);
+        row.event_comment = revision.comment.clone();
+        row.page_id = Some(revision.page_id);
+        row.page_is_redirect = Some(revision.page_is_redirect);
+        if entity == Entity::Revision {
+            row.revision_id = Some(revision.rev_id);
+            row.revision_parent_id = revision.rev_parent_id;
+            row.revision_minor_edit = Some(revision.rev_minor_edit);
+            row.revision_text_bytes = revision.rev_len;
+            row.revision_text_sha1 = revision.rev_sha1.clone();
+            row.revision_content_model = revision.rev_content_model.clone();
+        }
+        row
+    }
+
+    fn from_log(log: &LogEvent) -> Option<Self> {
+        let (entity, event_type) =
+            match (log.log_type.as_str(), log.log_action.as_str()) {
+                ("move", _) => (Entity::Page, "move"),
+                ("delete", "delete") => (Entity::Page, "delete"),
+                ("delete", "restore") => (Entity::Page, "restore"),
+                ("block", _) => (Entity::User, "alterblocks"),
+                ("rights", _) => (Entity::User, "altergroups"),
+                ("newusers", _) => (Entity::User, "create"),
+                ("renameuser", _) => (Entity::User, "rename"),
+                _ => return None,
+            };
+        let mut row = Self::new(&log.wiki, entity, event_type, log.meta.dt);
+        row.event_comment = log.comment.clone();
+        row.event_user_text = Some(log.user.to_string());
+        row.event_user_is_bot = Some(log.bot);
+        match entity {
+            Entity::User => {
+                row.user_text = Some(unprefixed(&log.title, log.namespace));
+                row.user_groups = log.new_groups();
+            }
+            _ => row = row.page(&log.title, log.namespace),
+        }
+        Some(row)
+    }
+
+    /// The row as it appears in the dump, without a line ending
+    pub fn to_tsv_line(&self) -> String {
+        let text = |value: &Option<String>| {
+            value.as_deref().map(escape).unwrap_or_default()
+        };
+        let list = |values: &[String]| values.join(",");
+        let bot = |is_bot: Option<bool>| {
+            if is_bot == Some(true) { "group" } else { "" }.to_string()
+        };
+        let mut values = vec![String::new(); COLUMNS.len()];
+        let mut set = |column: &str, value: String| {
+            let index = COLUMNS.iter().position(|name| *name == column);
+            values[index.expect("known column")] = value;
+        };
+        set("wiki_db", self.wiki_db.clone());
+        set("event_entity", self.event_entity.name().to_string());
+        set("event_type", self.event_type.clone());
+        set("event_timestamp", timestamp(Some(self.event_timestamp)));
+        set("event_comment_escaped", escape(&self.event_comment));
+        set("event_user_id", optional(self.event_user_id));
+        for column in &[
+            "event_user_text_historical_escaped",
+            "event_user_text_escaped",
+        ] {
+            set(column, text(&self.event_user_text));
+        }
+        for column in &[
+            "event_user_groups_historical_string",
+            "event_user_groups_string",
+        ] {
+            set(column, list(&self.event_user_groups));
+        }
+        for column in &[
+            "event_user_is_bot_by_historical_string",
+            "event_user_is_bot_by_string",
+        ] {
+            set(column, bot(self.event_user_is_bot));
+        }
+        set(
+            "event_user_is_anonymous",
+            optional(self.event_user_is_anonymous),
+        );
+        set(
+            "event_user_registration_timestamp",
+            timestamp(self.event_user_registration_timestamp),
+        );
+        set(
+            "event_user_revision_count",
+            optional(self.event_user_revision_count),
+        );
+        set("page_id", optional(self.page_id));
+        for column in &["page_title_historical_escaped", "page_title_escaped"] {
+            set(column, text(&self.page_title));
+        }
+        for column in &["page_namespace_historical", "page_namespace"] {
+            set(column, optional(self.page_namespace));
+        }
+        set("page_is_redirect", optional(self.page_is_redirect));
+        for column in &["user_text_historical_escaped", "user_text_escaped"] {
+            set(column, text(&self.user_text));
+        }
+        for column in &["user_groups_historical_string", "user_groups_string"] {
+            set(column, list(&self.user_groups));
+        }
+        set("revision_id", optional(self.revision_id));
+        set("revision_parent_id", optional(self.revision_parent_id));
+        set("revision_minor_edit", optional(self.revision_minor_edit));
+        set("revision_text_bytes", optional(self.revision_text_bytes));
+        set(
+            "revision_text_bytes_diff",
+            optional(self.revision_text_bytes_diff),
+        );
+        set("revision_text_sha1", text(&self.revision_text_sha1));
+        set("revision_content_model", text(&self.revision_content_model));
+        values.join("\t")
+    }
+}
+
+/// Title without its namespace prefix, in database form
+fn unprefixed(title: &str, namespace: i32) -> String {
+    let title = match title.split_once(':') {
+        Some((_, title)) if namespace != 0 => title,
+        _ => title,
+    };
+    title.replace(' ', "_")
+}
+
+/// Escape the characters that would break up the TSV
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Timestamps are written as e.g. `2021-05-01 12:34:56.0`
+fn timestamp(value: Option<DateTime<Utc>>) -> String {
+    optional(value.map(|value| value.format("%Y-%m-%d %H:%M:%S.0")))
+}
+
+impl Event {
+    /// The event as mediawiki_history rows. Page creations from recent
+    /// changes give both a page and a revision row, as in the dumps, and
+    /// events that the dumps don't cover give none.
+    pub fn to_history(&self) -> Vec<HistoryRow> {
+        match self {
+            Event::Edit(edit) => {
+                vec![HistoryRow::from_edit(edit, Entity::Revision)]
+            }
+            Event::New(new) => vec![
+                HistoryRow::from_edit(new, Entity::Page),
+                HistoryRow::from_edit(new, Entity::Revision),
+            ],
+            Event::RevisionCreate(revision) => {
+                vec![HistoryRow::from_revision(revision, Entity::Revision)]
+            }
+            Event::PageCreate(revision) => {
+                vec![HistoryRow::from_revision(revision, Entity::Page)]
+            }
+            Event::PageDelete(delete) => {
+                let mut row = HistoryRow::new(
+                    &delete.database,
+                    Entity::Page,
+                    "delete",
+                    delete.meta.dt,
+                )
+                .page(&delete.page_title, delete.page_namespace)
+                .performer(&delete.performer);
+                row.event_comment = delete.comment.clone();
+                row.page_id = Some(delete.page_id);
+                vec![row]
+            }
+            Event::PageUndelete(undelete) => {
+                let mut row = HistoryRow::new(
+                    &undelete.database,
+                    Entity::Page,
+                    "undelete",
+                    undelete.meta.dt,
+                )
+                .page(&undelete.page_title, undelete.page_namespace)
+                .performer(&undelete.performer);
+                row.event_comment = undelete.comment.clone();
+                row.page_id = Some(undelete.page_id);
+                vec![row]
+            }
+            Event::PageMove(move_) => {
+                let mut row = HistoryRow::new(
+                    &move_.database,
+                    Entity::Page,
+                    "move",
+                    move_.meta.dt,
+                )
+                .page(&move_.page_title, move_.page_namespace)
+                .performer(&move_.performer);
+                row.event_comment = move_.comment.clone();
+                row.page_id = Some(move_.page_id);
+                vec![row]
+            }
+            Event::Log(log) => HistoryRow::from_log(log).into_iter().collect(),
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn exports_page_creations_as_two_rows() {
+        let mut new = testing::edit(1, "Talk:Ada Lovelace", at(0));
+        new["type"] = "new".into();
+        new["namespace"] = 1.into();
+        new["comment"] = "Created\tpage".into();
+        let rows = testing::event(&new).to_history();
+        let entities: Vec<_> =
+            rows.iter().map(|row| row.event_entity).collect();
+        assert_eq!(entities, [Entity::Page, Entity::Revision]);
+        assert_eq!(rows[1].page_title.as_deref(), Some("Ada_Lovelace"));
+        assert_eq!(rows[1].revision_id, Some(2));
+        assert_eq!(rows[1].revision_text_bytes_diff, Some(10));
+
+        let line = rows[1].to_tsv_line();
+        let values: Vec<_> = line.split('\t').collect();
+        assert_eq!(values.len(), COLUMNS.len());
+        let value = |column: &str| {
+            values[COLUMNS.iter().position(|name| *name == column).unwrap()]
+        };
+        assert_eq!(value("wiki_db"), "enwiki");
+        assert_eq!(value("event_entity"), "revision");
+        assert_eq!(value("event_timestamp"), "2021-01-01 00:00:00.0");
+        assert_eq!(value("event_comment_escaped"), "Created\\tpage");
+        assert_eq!(value("page_namespace"), "1");
+        assert_eq!(value("revision_parent_id"), "1");
+
+        let upload = testing::event(&testing::log(2, "File:A.png", at(0)));
+        assert!(upload.to_history().is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Acting on events with [mwbot](https://docs.rs/mwbot)
+//!
+//! Responder bots, like ones that welcome new users or revert vandalism,
+//! usually want to edit a page related to the event they're reacting to.
+//! These helpers turn events into mwbot [`Page`]s, checking that the
+//! [`Bot`] is for the same wiki the event came from.
+//!
+//! ```no_run
+//! # async fn welcome(edit: eventstreams::EditEvent, bot: mwbot::Bot)
+//! # -> Result<(), Box<dyn std::error::Error>> {
+//! let talk = edit.user_talk_page(&bot)?;
+//! if !talk.exists().await? {
+//!     let options = mwbot::SaveOptions::summary("Welcome!");
+//!     talk.save("{{subst:welcome}} ~~~~", &options).await?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::{EditEvent, LogEvent};
+use mwbot::{Bot, Page};
+use std::fmt;
+
+/// User namespace ID
+const NS_USER: i32 = 2;
+/// User talk namespace ID
+const NS_USER_TALK: i32 = 3;
+
+#[derive(Debug)]
+pub enum InteropError {
+    /// The event is from a different wiki than the bot is for
+    WrongWiki {
+        /// Internal database name of the bot's wiki
+        bot: String,
+        /// Internal database name of the event's wiki
+        event: String,
+    },
+    /// mwbot rejected the page, e.g. because the title is invalid
+    Mwbot(mwbot::Error),
+}
+
+impl fmt::Display for InteropError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongWiki { bot, event } => {
+                write!(f, "event is from {}, but the bot is for {}", event, bot)
+            }
+            Self::Mwbot(err) => write!(f, "mwbot error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for InteropError {}
+
+impl From<mwbot::Error> for InteropError {
+    fn from(err: mwbot::Error) -> Self {
+        Self::Mwbot(err)
+    }
+}
+
+fn check_wiki(bot: &Bot, wiki: &str) -> Result<(), InteropError> {
+    if bot.wiki_id() == wiki {
+        Ok(())
+    } else {
+        Err(InteropError::WrongWiki {
+            bot: bot.wiki_id().to_string(),
+            event: wiki.to_string(),
+        })
+    }
+}
+
+fn user_page(
+    bot: &Bot,
+    wiki: &str,
+    namespace: i32,
+    user: &str,
+) -> Result<Page, InteropError> {
+    check_wiki(bot, wiki)?;
+    Ok(bot.page_from_database(namespace, &user.replace(' ', "_"))?)
+}
+
+impl EditEvent {
+    /// The page that was edited
+    pub fn page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        check_wiki(bot, &self.wiki)?;
+        Ok(bot.page(&self.title)?)
+    }
+
+    /// User page of the editor
+    pub fn user_page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        user_page(bot, &self.wiki, NS_USER, &self.user)
+    }
+
+    /// User talk page of the editor, e.g. for leaving a welcome message
+    /// or warning
+    pub fn user_talk_page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        user_page(bot, &self.wiki, NS_USER_TALK, &self.user)
+    }
+}
+
+impl LogEvent {
+    /// The page the action was performed on
+    pub fn page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        check_wiki(bot, &self.wiki)?;
+        Ok(bot.page(&self.title)?)
+    }
+
+    /// User page of the performer
+    pub fn user_page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        user_page(bot, &self.wiki, NS_USER, &self.user)
+    }
+
+    /// User talk page of the performer
+    pub fn user_talk_page(&self, bot: &Bot) -> Result<Page, InteropError> {
+        user_page(bot, &self.wiki, NS_USER_TALK, &self.user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+    use serde_json::json;
+
+    fn siteinfo() -> serde_json::Value {
+        let namespace = |id: i32, name: &str| json!({ "id": id, "case": "first-letter", "name": name, "canonical": name });
+        json!({ "query": {
+            "general": {
+                "generator": "MediaWiki 1.42.0",
+                "servername": "en.wikipedia.org",
+                "wikiid": "enwiki",
+                "mainpage": "Main Page",
+                "lang": "en",
+                "legaltitlechars": " %!\"$&'()*,\\-.\\/0-9:;=?@A-Z\\\\^_`a-z~\\x80-\\xFF+",
+            },
+            "namespaces": {
+                "0": namespace(0, ""),
+                "2": namespace(2, "User"),
+                "3": namespace(3, "User talk"),
+            },
+            "namespacealiases": [],
+        }})
+    }
+
+    #[tokio::test]
+    async fn finds_pages_on_the_same_wiki() {
+        let server = testing::serve_json(vec![siteinfo()]);
+        let bot = Bot::builder(format!("http://{}/w/", server.addr()))
+            .build()
+            .await
+            .unwrap();
+        let mut edit = testing::edit(1, "Ada Lovelace", at(0));
+        edit["user"] = "Some user".into();
+        let edit = match testing::event(&edit) {
+            Event::Edit(edit) => edit,
+            event => panic!("parsed as {:?}", event),
+        };
+        assert_eq!(edit.page(&bot).unwrap().title(), "Ada Lovelace");
+        assert_eq!(
+            edit.user_talk_page(&bot).unwrap().title(),
+            "User talk:Some user"
+        );
+        let mut other = edit.clone();
+        other.wiki = "dewiki".into();
+        match other.user_page(&bot) {
+            Err(InteropError::WrongWiki { bot, event }) => {
+                assert_eq!((bot.as_str(), event.as_str()), ("enwiki", "dewiki"))
+            }
+            result => panic!("expected the wrong wiki, got {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,386 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Posting events to an IRC channel
+//!
+//! [`IrcSink`] connects to an IRC network such as Libera.Chat, joins a
+//! channel and posts a line per event, in the colored format of the
+//! classic `irc.wikimedia.org` feed by default, so RC bots and the tools
+//! built around them can keep working. Lines are paced to stay under the
+//! network's flood limits, and the connection is re-established if it's
+//! lost. Only plaintext connections are supported, e.g. port 6667.
+use crate::pacing::Pacer;
+use crate::sink::{self, Sink, SinkError};
+use crate::Event;
+use futures::future::BoxFuture;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long to wait for the server to accept the connection
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest message sent, in bytes, leaving room for the prefix the server
+/// adds within IRC's 512 byte limit
+const MAX_MESSAGE: usize = 400;
+
+#[derive(Debug)]
+pub enum IrcError {
+    Io(io::Error),
+    /// The server refused the connection, e.g. because the nick is
+    /// reserved, or closed it before it was ready
+    Registration(String),
+}
+
+impl fmt::Display for IrcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "IRC connection failed: {}", err),
+            Self::Registration(reason) => {
+                write!(f, "IRC server refused connection: {}", reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IrcError {}
+
+impl From<io::Error> for IrcError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+type Formatter = dyn Fn(&Event) -> String + Send + Sync;
+
+/// Posts a line per event to an IRC channel
+pub struct IrcSink {
+    server: String,
+    nick: String,
+    channel: String,
+    password: Option<String>,
+    pacer: Pacer,
+    format: Box<Formatter>,
+    connection: Option<Connection>,
+}
+
+impl IrcSink {
+    /// Post to `channel` (e.g. `#wikipedia-en-rc`) on `server` (e.g.
+    /// `irc.libera.chat:6667`) as `nick`, at most one line every 2
+    /// seconds after a burst of 5
+    pub fn new(
+        server: impl Into<String>,
+        nick: impl Into<String>,
+        channel: impl Into<String>,
+    ) -> Self {
+        Self {
+            server: server.into(),
+            nick: nick.into(),
+            channel: channel.into(),
+            password: None,
+            pacer: Pacer::new(0.5, 5),
+            format: Box::new(format_line),
+            connection: None,
+        }
+    }
+
+    /// Identify with `password`, which most networks pass on to services,
+    /// e.g. as `account:password` for Libera.Chat's NickServ
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Post at most `per_second` lines a second, after bursts of up to
+    /// `burst` lines
+    pub fn flood_control(mut self, per_second: f64, burst: u32) -> Self {
+        self.pacer = Pacer::new(per_second, burst);
+        self
+    }
+
+    /// Format events with `format` instead. Line breaks are replaced and
+    /// long lines are cut short.
+    pub fn format(
+        mut self,
+        format: impl Fn(&Event) -> String + Send + Sync + 'static,
+    ) -> Self {
+        self.format = Box::new(format);
+        self
+    }
+
+    /// Whether the sink is connected and has joined the channel
+    pub fn is_connected(&self) -> bool {
+        self.connection
+            .as_ref()
+            .is_some_and(|connection| connection.alive.load(Ordering::Relaxed))
+    }
+
+    fn connected(&mut self) -> Result<&Connection, IrcError> {
+        if !self.is_connected() {
+            self.connection = None;
+            self.connection = Some(Connection::open(
+                &self.server,
+                &self.nick,
+                &self.channel,
+                self.password.as_deref(),
+            )?);
+        }
+        Ok(self.connection.as_ref().unwrap())
+    }
+
+    async fn post(&mut self, line: String) -> Result<(), IrcError> {
+        self.pacer.wait().await;
+        let message = format!("PRIVMSG {} :{}", self.channel, line);
+        let sent = self.connected()?.send(&message);
+        if sent.is_err() {
+            self.connection = None;
+        }
+        sent
+    }
+}
+
+impl Sink for IrcSink {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        let line = clean((self.format)(event));
+        Box::pin(async move { Ok(self.post(line).await?) })
+    }
+}
+
+impl fmt::Debug for IrcSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IrcSink")
+            .field("server", &self.server)
+            .field("nick", &self.nick)
+            .field("channel", &self.channel)
+            .field("connected", &self.is_connected())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A registered connection. A thread reads from it to answer the server's
+/// pings, and notices when it's closed.
+struct Connection {
+    writer: Arc<Mutex<TcpStream>>,
+    alive: Arc<AtomicBool>,
+}
+
+impl Connection {
+    fn open(
+        server: &str,
+        nick: &str,
+        channel: &str,
+        password: Option<&str>,
+    ) -> Result<Self, IrcError> {
+        let stream = TcpStream::connect(server)?;
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(REGISTRATION_TIMEOUT))?;
+        if let Some(password) = password {
+            write_line(&mut writer, &format!("PASS {}", password))?;
+        }
+        let mut nick = nick.to_string();
+        write_line(&mut writer, &format!("NICK {}", nick))?;
+        write_line(&mut writer, &format!("USER {} 0 * :{}", nick, nick))?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(IrcError::Registration(
+                    "connection closed".to_string(),
+                ));
+            }
+            match command(&line) {
+                ("PING", token) => {
+                    write_line(&mut writer, &format!("PONG {}", token))?
+                }
+                // Welcome
+                ("001", _) => break,
+                // Nickname in use
+                ("433", _) => {
+                    nick.push('_');
+                    write_line(&mut writer, &format!("NICK {}", nick))?;
+                }
+                ("ERROR", reason) => {
+                    return Err(IrcError::Registration(
+                        reason.trim_start_matches(':').to_string(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+        write_line(&mut writer, &format!("JOIN {}", channel))?;
+        stream.set_read_timeout(None)?;
+        let writer = Arc::new(Mutex::new(writer));
+        let alive = Arc::new(AtomicBool::new(true));
+        let (pong, still_alive) = (writer.clone(), alive.clone());
+        std::thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                match command(&line) {
+                    ("PING", token) => {
+                        let reply = format!("PONG {}", token);
+                        if write_line(&mut pong.lock().unwrap(), &reply)
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    ("ERROR", _) => break,
+                    _ => {}
+                }
+            }
+            still_alive.store(false, Ordering::Relaxed);
+        });
+        Ok(Self { writer, alive })
+    }
+
+    fn send(&self, line: &str) -> Result<(), IrcError> {
+        Ok(write_line(&mut self.writer.lock().unwrap(), line)?)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut writer = self.writer.lock().unwrap();
+        let _ = write_line(&mut writer, "QUIT");
+        // Also ends the reading thread
+        let _ = writer.shutdown(Shutdown::Both);
+    }
+}
+
+fn write_line(stream: &mut TcpStream, line: &str) -> io::Result<()> {
+    stream.write_all(format!("{}\r\n", line).as_bytes())
+}
+
+/// Command of a line from the server, and everything after it
+fn command(line: &str) -> (&str, &str) {
+    let line = line.trim_end();
+    let line = match line.strip_prefix(':') {
+        Some(prefixed) => prefixed.split_once(' ').map_or("", |(_, rest)| rest),
+        None => line,
+    };
+    line.split_once(' ').unwrap_or((line, ""))
+}
+
+/// Make a formatted line safe to send as a single message
+fn clean(line: String) -> String {
+    let mut line = line.replace(['\r', '\n'], " ");
+    if line.len() > MAX_MESSAGE {
+        let mut end = MAX_MESSAGE;
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+        line.truncate(end);
+    }
+    line
+}
+
+/// Format an event the way `irc.wikimedia.org` did, with mIRC colors
+fn format_line(event: &Event) -> String {
+    match event {
+        Event::Edit(edit) | Event::New(edit) => {
+            let mut flags = String::new();
+            if edit.is_page_creation() {
+                flags.push('N');
+            }
+            if edit.is_minor() {
+                flags.push('M');
+            }
+            if edit.bot {
+                flags.push('B');
+            }
+            format!(
+                "\x0314[[\x0307{}\x0314]]\x034 {}\x0310 \x0302{}\x03 \x035*\x03 \x0303{}\x03 \x035*\x03 ({:+}) \x0310{}\x03",
+                edit.title,
+                flags,
+                edit.diff_url(),
+                edit.user,
+                edit.byte_change(),
+                edit.comment
+            )
+        }
+        Event::Log(log) => format!(
+            "\x0314[[\x0307Special:Log/{}\x0314]]\x034 {}\x0310 \x0302\x03 \x035*\x03 \x0303{}\x03 \x035*\x03  \x0310{}\x03",
+            log.log_type, log.log_action, log.user, log.comment
+        ),
+        _ => sink::summary(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use std::net::TcpListener;
+
+    #[test]
+    fn registers_then_posts_to_the_channel() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let irc = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            let mut next = || lines.next().unwrap().unwrap();
+            let mut received = vec![next(), next()];
+            write_line(
+                &mut writer,
+                ":irc 433 * bot :Nickname is already in use",
+            )
+            .unwrap();
+            received.push(next());
+            write_line(&mut writer, "PING :irc").unwrap();
+            received.push(next());
+            write_line(&mut writer, ":irc 001 bot_ :Welcome").unwrap();
+            received.push(next());
+            received.push(next());
+            received
+        });
+        let mut sink = IrcSink::new(server, "bot", "#rc")
+            .format(|_| format!("line\nbreak{}", "!".repeat(500)));
+        assert!(!sink.is_connected());
+        let edit = testing::edit_event(1, "A", at(0));
+        block_on(sink.send(&edit)).unwrap();
+        let received = irc.join().unwrap();
+        assert_eq!(
+            received[..5],
+            [
+                "NICK bot",
+                "USER bot 0 * :bot",
+                "NICK bot_",
+                "PONG :irc",
+                "JOIN #rc"
+            ]
+        );
+        let message = format!("line break{}", "!".repeat(MAX_MESSAGE - 10));
+        assert_eq!(received[5], format!("PRIVMSG #rc :{}", message));
+    }
+
+    #[test]
+    fn formats_edits_like_the_wikimedia_feed() {
+        let edit = testing::edit_event(1, "A", at(0));
+        let line = format_line(&edit);
+        assert!(line.starts_with("\x0314[[\x0307A\x0314]]\x034 \x0310"));
+        assert!(line.contains("\x0303Alice\x03"));
+        assert!(line.ends_with("(+10) \x0310Fixed a typo\x03"));
+    }
+}
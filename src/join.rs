@@ -0,0 +1,244 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Correlating edits with their log entries
+//!
+//! Some actions, like uploads and page moves, show up as both an edit and
+//! a log entry. [`EditLogJoin`] pairs them back up into one [`Action`].
+use crate::metrics::{Metrics, Reporter};
+use crate::watermark::Watermark;
+use crate::{EditEvent, Event, LogEvent};
+use chrono::Duration;
+use std::collections::HashMap;
+
+/// One logical action on a page
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Action {
+    /// An edit and log entry for the same action
+    Both { edit: EditEvent, log: LogEvent },
+    /// An edit with no matching log entry
+    Edit(EditEvent),
+    /// A log entry with no matching edit
+    Log(LogEvent),
+}
+
+/// Joins edits and log entries on the same page (by wiki and title) that
+/// happened within `window` of each other
+#[derive(Clone, Debug)]
+pub struct EditLogJoin {
+    window: Duration,
+    watermark: Watermark,
+    pending: HashMap<(String, String), Vec<Event>>,
+    buffered: usize,
+    max_pending: Option<usize>,
+    reporter: Option<Reporter>,
+}
+
+impl EditLogJoin {
+    pub fn new(window: std::time::Duration) -> Self {
+        Self {
+            window: Duration::from_std(window).expect("window out of range"),
+            watermark: Watermark::new(window),
+            pending: HashMap::new(),
+            buffered: 0,
+            max_pending: crate::metrics::bounded(),
+            reporter: None,
+        }
+    }
+
+    /// Hold at most `max` events waiting for a counterpart. If there are
+    /// more, the oldest are given up on early. No more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap, if it's on.
+    pub fn max_pending(mut self, max: usize) -> Self {
+        self.max_pending = Some(crate::metrics::cap(max));
+        self
+    }
+
+    /// Report waiting events to `metrics`, as `<name>.buffered`, and events
+    /// given up on early as `<name>.evicted`
+    pub fn with_metrics(mut self, metrics: Metrics, name: &str) -> Self {
+        self.reporter = Some(Reporter::new(metrics, name));
+        self
+    }
+
+    /// Number of events waiting for a counterpart
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Add an event, returning any actions that are now complete. Events
+    /// other than edits and log entries are ignored.
+    pub fn push(&mut self, event: Event) -> Vec<Action> {
+        let key = match &event {
+            Event::Edit(edit) | Event::New(edit) => {
+                (edit.wiki.to_string(), edit.title.clone())
+            }
+            Event::Log(log) => (log.wiki.to_string(), log.title.clone()),
+            _ => return vec![],
+        };
+        self.watermark.observe(&event);
+        let dt = event.dt();
+        let window = self.window;
+        let mut actions = vec![];
+        let pending = self.pending.entry(key).or_default();
+        let counterpart = pending.iter().position(|other| {
+            (other.dt() - dt).abs() <= window
+                && matches!(
+                    (&event, other),
+                    (Event::Edit(_) | Event::New(_), Event::Log(_))
+                        | (Event::Log(_), Event::Edit(_) | Event::New(_))
+                )
+        });
+        match counterpart {
+            Some(index) => {
+                let other = pending.remove(index);
+                self.buffered -= 1;
+                actions.push(Self::combine(event, other));
+            }
+            None => {
+                pending.push(event);
+                self.buffered += 1;
+            }
+        }
+        // Anything that has been waiting longer than the window won't be
+        // matched anymore
+        let watermark = self.watermark.current().unwrap();
+        for pending in self.pending.values_mut() {
+            while let Some(index) = pending
+                .iter()
+                .position(|event| event.dt() + window < watermark)
+            {
+                actions.push(Self::single(pending.remove(index)));
+                self.buffered -= 1;
+            }
+        }
+        let mut evicted = 0;
+        while self.max_pending.is_some_and(|max| self.buffered > max) {
+            let (key, index) = self
+                .pending
+                .iter()
+                .flat_map(|(key, pending)| {
+                    pending
+                        .iter()
+                        .enumerate()
+                        .map(move |(index, event)| (key, index, event.dt()))
+                })
+                .min_by_key(|(_, _, dt)| *dt)
+                .map(|(key, index, _)| (key.clone(), index))
+                .unwrap();
+            let event = self.pending.get_mut(&key).unwrap().remove(index);
+            actions.push(Self::single(event));
+            self.buffered -= 1;
+            evicted += 1;
+        }
+        self.pending.retain(|_, pending| !pending.is_empty());
+        if let Some(reporter) = &self.reporter {
+            reporter.buffered(self.buffered);
+            reporter.evicted(evicted);
+        }
+        actions
+    }
+
+    /// Emit all unmatched events, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<Action> {
+        self.buffered = 0;
+        std::mem::take(&mut self.pending)
+            .into_values()
+            .flatten()
+            .map(Self::single)
+            .collect()
+    }
+
+    fn combine(first: Event, second: Event) -> Action {
+        match (first, second) {
+            (Event::Edit(edit) | Event::New(edit), Event::Log(log))
+            | (Event::Log(log), Event::Edit(edit) | Event::New(edit)) => {
+                Action::Both { edit, log }
+            }
+            _ => unreachable!("only edits and logs are combined"),
+        }
+    }
+
+    fn single(event: Event) -> Action {
+        match event {
+            Event::Edit(edit) | Event::New(edit) => Action::Edit(edit),
+            Event::Log(log) => Action::Log(log),
+            _ => unreachable!("never pending"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    const MINUTE: StdDuration = StdDuration::from_secs(60);
+
+    #[test]
+    fn pairs_edits_with_their_log_entries() {
+        let mut join = EditLogJoin::new(MINUTE);
+        let edit = testing::edit_event(1, "File:A.png", at(0));
+        assert!(join.push(edit).is_empty());
+        let log = testing::event(&testing::log(2, "File:A.png", at(1)));
+        let actions = join.push(log);
+        assert!(matches!(
+            actions.as_slice(),
+            [Action::Both { edit, log }]
+                if edit.title == "File:A.png" && log.title == "File:A.png"
+        ));
+        assert_eq!(join.buffered(), 0);
+    }
+
+    #[test]
+    fn gives_up_once_the_window_has_passed() {
+        let mut join = EditLogJoin::new(MINUTE);
+        join.push(testing::edit_event(1, "A", at(0)));
+        let actions = join.push(testing::event(&testing::log(2, "A", at(180))));
+        assert!(
+            matches!(actions.as_slice(), [Action::Edit(edit)] if edit.title == "A")
+        );
+        assert!(matches!(join.flush().as_slice(), [Action::Log(_)]));
+    }
+
+    #[test]
+    fn only_pairs_the_same_page() {
+        let mut join = EditLogJoin::new(MINUTE);
+        join.push(testing::edit_event(1, "A", at(0)));
+        let actions = join.push(testing::event(&testing::log(2, "B", at(0))));
+        assert!(actions.is_empty());
+        assert_eq!(join.buffered(), 2);
+    }
+
+    #[test]
+    fn gives_up_early_past_max_pending() {
+        let metrics = Metrics::new();
+        let mut join = EditLogJoin::new(MINUTE)
+            .max_pending(1)
+            .with_metrics(metrics.clone(), "join");
+        join.push(testing::edit_event(1, "A", at(0)));
+        let actions = join.push(testing::edit_event(2, "B", at(1)));
+        assert!(
+            matches!(actions.as_slice(), [Action::Edit(edit)] if edit.title == "A")
+        );
+        assert_eq!(join.buffered(), 1);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.gauges["join.buffered"], 1);
+        assert_eq!(snapshot.counters["join.evicted"], 1);
+    }
+}
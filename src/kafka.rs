@@ -0,0 +1,359 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Re-publishing events into Kafka
+//!
+//! [`KafkaSink`] turns each event into a [`KafkaRecord`] for a topic of
+//! your own, keeping where it came from in EventStreams' Kafka as headers,
+//! and hands it to a [`KafkaProducer`]. With the `rdkafka` feature,
+//! `RdkafkaProducer` produces through librdkafka:
+//!
+//! ```ignore
+//! use eventstreams::kafka::{KafkaSink, RdkafkaProducer};
+//!
+//! let producer = RdkafkaProducer::new("localhost:9092")?;
+//! let sink = KafkaSink::new(producer, "wiki-changes");
+//! ```
+//!
+//! Otherwise the producer is whatever client the application already
+//! uses, wrapped in a few lines, so this crate doesn't need to link
+//! librdkafka itself.
+use crate::sink::{self, Json, Serializer, Sink, SinkError};
+use crate::Event;
+use futures::future::BoxFuture;
+#[cfg(feature = "rdkafka")]
+use rdkafka::client::DefaultClientContext;
+#[cfg(feature = "rdkafka")]
+use rdkafka::error::KafkaError;
+#[cfg(feature = "rdkafka")]
+use rdkafka::message::{Header, OwnedHeaders};
+#[cfg(feature = "rdkafka")]
+use rdkafka::producer::{FutureProducer, FutureRecord};
+#[cfg(feature = "rdkafka")]
+use rdkafka::ClientConfig;
+use std::collections::HashMap;
+use std::sync::Arc;
+#[cfg(feature = "rdkafka")]
+use std::time::Duration;
+
+/// A message to produce
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KafkaRecord {
+    pub topic: String,
+    /// `<wiki>:<title>`, so that events about the same page stay in order
+    pub key: String,
+    pub payload: Vec<u8>,
+    /// `eventstreams.stream`, `eventstreams.topic`,
+    /// `eventstreams.partition`, `eventstreams.offset` and
+    /// `eventstreams.id`, from the event's `meta`
+    pub headers: Vec<(String, Vec<u8>)>,
+}
+
+impl KafkaRecord {
+    fn new(event: &Event, topic: &str, payload: Vec<u8>) -> Self {
+        let meta = event.meta();
+        let header = |name: &str, value: String| {
+            (format!("eventstreams.{}", name), value.into_bytes())
+        };
+        Self {
+            topic: topic.to_string(),
+            key: format!("{}:{}", event.wiki(), event.title()),
+            payload,
+            headers: vec![
+                header("stream", meta.stream.to_string()),
+                header("topic", meta.topic.clone()),
+                header("partition", meta.partition.to_string()),
+                header("offset", meta.offset.to_string()),
+                header("id", meta.id.clone()),
+            ],
+        }
+    }
+}
+
+/// Sends records to Kafka, resolving once the broker has acknowledged them
+pub trait KafkaProducer: Send {
+    fn send(
+        &mut self,
+        record: KafkaRecord,
+    ) -> BoxFuture<'_, Result<(), SinkError>>;
+}
+
+/// How long [`RdkafkaProducer`] waits for room in librdkafka's queue by
+/// default
+#[cfg(feature = "rdkafka")]
+const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Timers for rdkafka from [`runtime`](crate::runtime), so that it doesn't
+/// need tokio
+#[cfg(feature = "rdkafka")]
+struct Runtime;
+
+#[cfg(feature = "rdkafka")]
+impl rdkafka::util::AsyncRuntime for Runtime {
+    type Delay = BoxFuture<'static, ()>;
+
+    fn spawn<T>(task: T)
+    where
+        T: std::future::Future<Output = ()> + Send + 'static,
+    {
+        std::thread::spawn(move || futures::executor::block_on(task));
+    }
+
+    fn delay_for(duration: Duration) -> Self::Delay {
+        crate::runtime::sleep(duration)
+    }
+}
+
+/// A [`KafkaProducer`] built on librdkafka's `FutureProducer`
+#[cfg(feature = "rdkafka")]
+pub struct RdkafkaProducer {
+    producer: FutureProducer<DefaultClientContext, Runtime>,
+    queue_timeout: Duration,
+}
+
+#[cfg(feature = "rdkafka")]
+impl RdkafkaProducer {
+    /// Produce to the cluster at `bootstrap_servers`, a comma-separated
+    /// list of `host:port` pairs
+    pub fn new(bootstrap_servers: &str) -> Result<Self, KafkaError> {
+        Self::with_config(
+            ClientConfig::new().set("bootstrap.servers", bootstrap_servers),
+        )
+    }
+
+    /// Produce with a client configured by `config`, e.g. for
+    /// authentication or compression
+    pub fn with_config(config: &ClientConfig) -> Result<Self, KafkaError> {
+        Ok(Self {
+            producer: config.create()?,
+            queue_timeout: DEFAULT_QUEUE_TIMEOUT,
+        })
+    }
+
+    /// Wait up to `timeout` for room in librdkafka's queue when it's full,
+    /// rather than 5 seconds, before failing the record
+    pub fn queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = timeout;
+        self
+    }
+}
+
+#[cfg(feature = "rdkafka")]
+impl KafkaProducer for RdkafkaProducer {
+    fn send(
+        &mut self,
+        record: KafkaRecord,
+    ) -> BoxFuture<'_, Result<(), SinkError>> {
+        Box::pin(async move {
+            let mut headers =
+                OwnedHeaders::new_with_capacity(record.headers.len());
+            for (name, value) in &record.headers {
+                headers = headers.insert(Header {
+                    key: name,
+                    value: Some(value),
+                });
+            }
+            let future = FutureRecord::to(&record.topic)
+                .key(&record.key)
+                .payload(&record.payload)
+                .headers(headers);
+            self.producer
+                .send(future, self.queue_timeout)
+                .await
+                .map_err(|(err, _)| err)?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(feature = "rdkafka")]
+impl std::fmt::Debug for RdkafkaProducer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RdkafkaProducer")
+            .field("queue_timeout", &self.queue_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+type FailureCallback = Box<dyn FnMut(&KafkaRecord, &SinkError) + Send>;
+
+/// Produces every event to a Kafka topic, picked by the stream the event
+/// came from
+pub struct KafkaSink<P> {
+    producer: P,
+    default_topic: Option<String>,
+    topics: HashMap<String, String>,
+    serializer: Arc<dyn Serializer>,
+    on_failure: Option<FailureCallback>,
+}
+
+impl<P: KafkaProducer> KafkaSink<P> {
+    /// Produce every event to `topic`, encoded with the
+    /// [default serializer](sink::set_serializer)
+    pub fn new(producer: P, topic: &str) -> Self {
+        Self {
+            producer,
+            default_topic: Some(topic.to_string()),
+            topics: HashMap::new(),
+            serializer: sink::serializer(),
+            on_failure: None,
+        }
+    }
+
+    /// Only produce events from streams given a topic with
+    /// [`topic_for()`](Self::topic_for), skipping the rest
+    pub fn per_stream(producer: P) -> Self {
+        Self {
+            default_topic: None,
+            ..Self::new(producer, "")
+        }
+    }
+
+    /// Produce events from `stream` (e.g. `mediawiki.recentchange`) to
+    /// `topic` instead
+    pub fn topic_for(mut self, stream: &str, topic: &str) -> Self {
+        self.topics.insert(stream.to_string(), topic.to_string());
+        self
+    }
+
+    /// Encode payloads with `serializer` instead. JSON payloads
+    /// don't have the trailing newline that [`Json`] adds.
+    pub fn serializer(mut self, serializer: Arc<dyn Serializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    /// Call `callback` with every record the producer fails to deliver,
+    /// e.g. to count or spool them. The failure is still returned from
+    /// [`send()`](Sink::send).
+    pub fn on_delivery_failure(
+        mut self,
+        callback: impl FnMut(&KafkaRecord, &SinkError) + Send + 'static,
+    ) -> Self {
+        self.on_failure = Some(Box::new(callback));
+        self
+    }
+
+    /// Topic for `event`, if it should be produced at all
+    fn topic(&self, event: &Event) -> Option<&str> {
+        self.topics
+            .get(event.meta().stream.as_str())
+            .or(self.default_topic.as_ref())
+            .map(String::as_str)
+    }
+}
+
+impl<P: KafkaProducer> Sink for KafkaSink<P> {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let topic = match self.topic(event) {
+                Some(topic) => topic.to_string(),
+                None => return Ok(()),
+            };
+            let mut payload = self.serializer.serialize(event)?;
+            if self.serializer.content_type() == Json.content_type() {
+                payload.pop();
+            }
+            let record = KafkaRecord::new(event, &topic, payload);
+            let failed = self.on_failure.as_ref().map(|_| record.clone());
+            let result = self.producer.send(record).await;
+            if let (Err(err), Some(record), Some(on_failure)) =
+                (&result, failed, &mut self.on_failure)
+            {
+                on_failure(&record, err);
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use std::sync::Mutex;
+
+    /// Keeps every record, failing for the topic `down`
+    #[derive(Clone, Default)]
+    struct Recorded(Arc<Mutex<Vec<KafkaRecord>>>);
+
+    impl KafkaProducer for Recorded {
+        fn send(
+            &mut self,
+            record: KafkaRecord,
+        ) -> BoxFuture<'_, Result<(), SinkError>> {
+            Box::pin(async move {
+                if record.topic == "down" {
+                    return Err("broker unavailable".into());
+                }
+                self.0.lock().unwrap().push(record);
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn produces_events_with_their_origin() {
+        let produced = Recorded::default();
+        let mut sink = KafkaSink::new(produced.clone(), "wiki-changes")
+            .serializer(Arc::new(Json));
+        let edit = testing::edit_event(1, "A", at(0));
+        block_on(sink.send(&edit)).unwrap();
+        let records = produced.0.lock().unwrap();
+        assert_eq!(records[0].topic, "wiki-changes");
+        assert_eq!(records[0].key, "enwiki:A");
+        let payload: serde_json::Value =
+            serde_json::from_slice(&records[0].payload).unwrap();
+        assert_eq!(payload["title"], "A");
+        assert!(!records[0].payload.ends_with(b"\n"));
+        let header = |name: &str| {
+            records[0]
+                .headers
+                .iter()
+                .find(|(header, _)| header == name)
+                .map(|(_, value)| String::from_utf8(value.clone()).unwrap())
+        };
+        assert_eq!(header("eventstreams.offset").as_deref(), Some("1"));
+        assert_eq!(header("eventstreams.id").as_deref(), Some("id-1"));
+    }
+
+    #[test]
+    fn routes_by_stream_and_reports_failures() {
+        let produced = Recorded::default();
+        let failures = Arc::new(Mutex::new(vec![]));
+        let failed = failures.clone();
+        let mut sink = KafkaSink::per_stream(produced.clone())
+            .topic_for("mediawiki.recentchange", "down")
+            .on_delivery_failure(move |record, err| {
+                failed
+                    .lock()
+                    .unwrap()
+                    .push(format!("{}: {}", record.key, err))
+            });
+        let edit = testing::edit_event(1, "A", at(0));
+        assert!(block_on(sink.send(&edit)).is_err());
+        assert_eq!(*failures.lock().unwrap(), ["enwiki:A: broker unavailable"]);
+
+        let mut sink = KafkaSink::per_stream(produced.clone())
+            .topic_for("mediawiki.page-create", "creations");
+        block_on(sink.send(&edit)).unwrap();
+        assert!(produced.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,247 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Per-key state for stateful stream processing
+//!
+//! See [`EventStreamExt::keyed_process`](crate::EventStreamExt::keyed_process)
+//! for the stream adapter built on top of this.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::PathBuf;
+
+/// State for each key, created with `Default` the first time a key is seen
+#[derive(Clone, Debug)]
+pub struct KeyedState<K, S> {
+    states: HashMap<K, S>,
+    /// Keys in the order they were first seen, while capped
+    order: VecDeque<K>,
+    max_keys: Option<usize>,
+}
+
+impl<K: Eq + Hash + Clone, S: Default> KeyedState<K, S> {
+    /// Keep state for any number of keys, unless
+    /// [bounded mode](crate::metrics::set_bounded) is on
+    pub fn new() -> Self {
+        Self {
+            states: HashMap::new(),
+            order: VecDeque::new(),
+            max_keys: crate::metrics::bounded(),
+        }
+    }
+
+    /// Keep state for at most `max` keys (at least 1). If there are more,
+    /// the state of the key seen first is forgotten, and starts over from
+    /// `Default` if that key comes up again. No more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap, if it's on.
+    pub fn max_keys(mut self, max: usize) -> Self {
+        self.max_keys = Some(crate::metrics::cap(max.max(1)));
+        self.order = self.states.keys().cloned().collect();
+        self.evict();
+        self
+    }
+
+    /// Run `f` against the state for `key`
+    pub fn process<O>(&mut self, key: K, f: impl FnOnce(&mut S) -> O) -> O {
+        if self.max_keys.is_some() && !self.states.contains_key(&key) {
+            self.order.push_back(key.clone());
+            self.states.insert(key.clone(), S::default());
+            self.evict();
+        }
+        f(self.states.entry(key).or_default())
+    }
+
+    /// Forget the keys seen first until there are no more than allowed
+    fn evict(&mut self) {
+        let max = match self.max_keys {
+            Some(max) => max,
+            None => return,
+        };
+        while self.states.len() > max {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.states.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// State for `key`, if it has been seen
+    pub fn get(&self, key: &K) -> Option<&S> {
+        self.states.get(key)
+    }
+
+    /// Forget the state for `key`
+    pub fn remove(&mut self, key: &K) -> Option<S> {
+        let state = self.states.remove(key)?;
+        if self.max_keys.is_some() {
+            self.order.retain(|other| other != key);
+        }
+        Some(state)
+    }
+
+    /// Number of keys with state
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Load previously saved state from `store`, replacing the current
+    /// state. If there are more keys than allowed, arbitrary ones are
+    /// forgotten.
+    pub fn load(&mut self, store: &impl StateStore<K, S>) -> io::Result<()> {
+        if let Some(states) = store.load()? {
+            self.states = states;
+            if self.max_keys.is_some() {
+                self.order = self.states.keys().cloned().collect();
+                self.evict();
+            }
+        }
+        Ok(())
+    }
+
+    /// Save the current state to `store`
+    pub fn save(&self, store: &impl StateStore<K, S>) -> io::Result<()> {
+        store.save(&self.states)
+    }
+}
+
+impl<K: Eq + Hash + Clone, S: Default> Default for KeyedState<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Somewhere to persist [`KeyedState`] across restarts
+pub trait StateStore<K, S> {
+    /// Load saved state, or `None` if nothing has been saved yet
+    fn load(&self) -> io::Result<Option<HashMap<K, S>>>;
+    /// Save state, replacing whatever was saved before
+    fn save(&self, states: &HashMap<K, S>) -> io::Result<()>;
+}
+
+/// Stores state as a JSON file
+#[derive(Clone, Debug)]
+pub struct JsonStateStore {
+    path: PathBuf,
+}
+
+impl JsonStateStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl<K, S> StateStore<K, S> for JsonStateStore
+where
+    K: Eq + Hash + Serialize + DeserializeOwned,
+    S: Serialize + DeserializeOwned,
+{
+    fn load(&self) -> io::Result<Option<HashMap<K, S>>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        // Keys aren't necessarily strings, so save as a list of pairs
+        let pairs: Vec<(K, S)> = serde_json::from_str(&contents)?;
+        Ok(Some(pairs.into_iter().collect()))
+    }
+
+    fn save(&self, states: &HashMap<K, S>) -> io::Result<()> {
+        let pairs: Vec<(&K, &S)> = states.iter().collect();
+        // Write to a temporary file first so a crash can't leave a
+        // half-written file behind
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&pairs)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::{Event, EventStreamExt, StreamExt};
+    use futures::executor::block_on;
+
+    #[test]
+    fn keeps_state_per_key() {
+        let events = ["A", "B", "A", "A"]
+            .iter()
+            .enumerate()
+            .map(|(offset, title)| {
+                testing::edit_event(offset as u64, title, at(0))
+            })
+            .collect::<Vec<_>>();
+        let counts: Vec<_> = block_on(
+            futures::stream::iter(events)
+                .keyed_process(
+                    |event: &Event| event.title().to_string(),
+                    |count: &mut u32, event| {
+                        *count += 1;
+                        Some(format!("{} {}", event.title(), count))
+                    },
+                )
+                .collect(),
+        );
+        assert_eq!(counts, ["A 1", "B 1", "A 2", "A 3"]);
+    }
+
+    #[test]
+    fn saves_and_loads_state() {
+        let path = std::env::temp_dir()
+            .join(format!("eventstreams-keyed-{}.json", std::process::id()));
+        let store = JsonStateStore::new(&path);
+        let mut state: KeyedState<(String, u64), u32> = KeyedState::new();
+        state.load(&store).unwrap();
+        assert!(state.is_empty());
+        state.process(("enwiki".to_string(), 1), |count| *count += 2);
+        state.save(&store).unwrap();
+        let mut loaded: KeyedState<(String, u64), u32> = KeyedState::new();
+        loaded.load(&store).unwrap();
+        assert_eq!(loaded.get(&("enwiki".to_string(), 1)), Some(&2));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn forgets_the_oldest_keys_beyond_the_limit() {
+        let mut state: KeyedState<&str, u32> = KeyedState::new().max_keys(2);
+        for key in ["A", "B", "A", "C"] {
+            state.process(key, |count| *count += 1);
+        }
+        assert_eq!(state.len(), 2);
+        assert_eq!(state.get(&"A"), None);
+        assert_eq!(state.get(&"B"), Some(&1));
+        assert_eq!(state.get(&"C"), Some(&1));
+        // A removed key no longer counts towards the limit
+        state.remove(&"B");
+        state.process("A", |count| *count += 1);
+        state.process("D", |count| *count += 1);
+        assert_eq!(state.get(&"C"), None);
+        assert_eq!(state.get(&"A"), Some(&1));
+        assert_eq!(state.get(&"D"), Some(&1));
+    }
+}
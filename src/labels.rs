@@ -0,0 +1,241 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Labeling events for training data
+//!
+//! Anti-vandalism models are trained on edits that people have reviewed.
+//! A [`LabelStore`] keeps a label for each reviewed event, keyed by its
+//! unique ID (`meta.id`), along with the event itself so the labeled set
+//! can be [exported](LabelStore::export) later as NDJSON. Stores can be
+//! [saved](LabelStore::save) to a file, and with the `enrichment` feature,
+//! written through to a [`Cache`](crate::cache::Cache) so that other
+//! processes, e.g. the reviewing tool and the bot, see the same labels.
+#[cfg(feature = "enrichment")]
+use crate::cache::Cache;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "enrichment")]
+use std::time::Duration;
+
+/// A reviewer's verdict on an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Label {
+    Vandalism,
+    Ok,
+}
+
+/// A labeled event
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Annotation {
+    /// `meta.id` of the event
+    pub id: String,
+    pub label: Label,
+    /// When the label was given
+    pub labeled_at: DateTime<Utc>,
+    /// The event, as JSON
+    pub event: Value,
+}
+
+/// Labels given to events. Clones share the same labels.
+#[derive(Clone, Default)]
+pub struct LabelStore {
+    annotations: Arc<Mutex<BTreeMap<String, Annotation>>>,
+    #[cfg(feature = "enrichment")]
+    cache: Option<(Arc<dyn Cache>, Duration)>,
+}
+
+impl LabelStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also store labels in `cache` for `ttl`, and look up labels there
+    /// that aren't known locally
+    #[cfg(feature = "enrichment")]
+    pub fn with_cache(mut self, cache: Arc<dyn Cache>, ttl: Duration) -> Self {
+        self.cache = Some((cache, ttl));
+        self
+    }
+
+    /// Label `event`, replacing any earlier label
+    pub fn label(&self, event: &Event, label: Label) {
+        let annotation = Annotation {
+            id: event.meta().id.clone(),
+            label,
+            labeled_at: Utc::now(),
+            event: serde_json::to_value(event).unwrap_or_default(),
+        };
+        #[cfg(feature = "enrichment")]
+        if let Some((cache, ttl)) = &self.cache {
+            if let Ok(json) = serde_json::to_string(&annotation) {
+                cache.put(&cache_key(&annotation.id), json, *ttl);
+            }
+        }
+        self.annotations
+            .lock()
+            .unwrap()
+            .insert(annotation.id.clone(), annotation);
+    }
+
+    /// Label of the event with ID `id`, if it has one
+    pub fn get(&self, id: &str) -> Option<Label> {
+        self.annotation(id).map(|annotation| annotation.label)
+    }
+
+    /// The labeled event with ID `id`, if there is one
+    pub fn annotation(&self, id: &str) -> Option<Annotation> {
+        if let Some(annotation) = self.annotations.lock().unwrap().get(id) {
+            return Some(annotation.clone());
+        }
+        #[cfg(feature = "enrichment")]
+        if let Some((cache, _)) = &self.cache {
+            let annotation: Annotation =
+                serde_json::from_str(&cache.get(&cache_key(id))?).ok()?;
+            self.annotations
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), annotation.clone());
+            return Some(annotation);
+        }
+        None
+    }
+
+    /// Forget the label of the event with ID `id`. Labels already written
+    /// to a cache stay there until they expire.
+    pub fn remove(&self, id: &str) -> Option<Annotation> {
+        self.annotations.lock().unwrap().remove(id)
+    }
+
+    /// Number of events labeled locally
+    pub fn len(&self) -> usize {
+        self.annotations.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write every locally known labeled event to `writer` as NDJSON, one
+    /// `{"label": ..., "event": {...}}` object per line, in the order they
+    /// were labeled. Returns the number of events written.
+    pub fn export(&self, mut writer: impl Write) -> io::Result<usize> {
+        let mut annotations: Vec<_> =
+            self.annotations.lock().unwrap().values().cloned().collect();
+        annotations.sort_by_key(|annotation| annotation.labeled_at);
+        for annotation in &annotations {
+            let line = serde_json::json!({
+                "label": annotation.label,
+                "event": annotation.event,
+            });
+            writeln!(writer, "{}", line)?;
+        }
+        Ok(annotations.len())
+    }
+
+    /// Write all labels to `path` as JSON
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let annotations: Vec<_> =
+            self.annotations.lock().unwrap().values().cloned().collect();
+        std::fs::write(path, serde_json::to_string(&annotations)?)
+    }
+
+    /// Add every label stored at `path` by [`save()`](Self::save),
+    /// replacing any for the same events
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let loaded: Vec<Annotation> =
+            serde_json::from_slice(&std::fs::read(path)?)?;
+        let mut annotations = self.annotations.lock().unwrap();
+        for annotation in loaded {
+            annotations.insert(annotation.id.clone(), annotation);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "enrichment")]
+fn cache_key(id: &str) -> String {
+    format!("label:{}", id)
+}
+
+impl fmt::Debug for LabelStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LabelStore")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn exports_saves_and_loads_labels() {
+        let store = LabelStore::new();
+        let first = testing::edit_event(1, "A", at(0));
+        let second = testing::edit_event(2, "B", at(1));
+        store.label(&first, Label::Ok);
+        store.label(&second, Label::Ok);
+        store.label(&first, Label::Vandalism);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get("id-1"), Some(Label::Vandalism));
+        assert_eq!(store.get("id-3"), None);
+
+        let mut exported = Vec::new();
+        assert_eq!(store.export(&mut exported).unwrap(), 2);
+        let lines: Vec<Value> = String::from_utf8(exported)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["label"], "ok");
+        assert_eq!(lines[0]["event"]["title"], "B");
+        assert_eq!(lines[1]["label"], "vandalism");
+
+        let path = std::env::temp_dir()
+            .join(format!("eventstreams-labels-{}.json", std::process::id()));
+        store.save(&path).unwrap();
+        let loaded = LabelStore::new();
+        loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get("id-2"), Some(Label::Ok));
+        assert_eq!(loaded.remove("id-1").unwrap().label, Label::Vandalism);
+        assert_eq!(loaded.len(), 1);
+    }
+
+    #[cfg(feature = "enrichment")]
+    #[test]
+    fn shares_labels_through_a_cache() {
+        let cache = Arc::new(crate::cache::MemoryCache::new(10));
+        let ttl = Duration::from_secs(60);
+        let reviewer = LabelStore::new().with_cache(cache.clone(), ttl);
+        let bot = LabelStore::new().with_cache(cache, ttl);
+        let edit = testing::edit_event(1, "A", at(0));
+        reviewer.label(&edit, Label::Vandalism);
+        assert!(bot.is_empty());
+        assert_eq!(bot.get("id-1"), Some(Label::Vandalism));
+        assert_eq!(bot.len(), 1);
+    }
+}
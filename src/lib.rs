@@ -44,47 +44,564 @@ along with this program.  If not, see <https://www.gnu.org/licenses/>.
 //!                &log.title
 //!            );
 //!        }
+//!        _ => {}
 //!    }
 //! }
 //! # }
 //! ```
-mod types;
+//!
+//! ## Features
+//!
+//! The core client and event types are always available. The rest is split
+//! into features, the first four of which are enabled by default:
+//!
+//! * `curl`: making requests through libcurl; `native-tls` and `rustls`
+//!   are alternatives that don't need it, see [`transport`]
+//! * `sinks`: filters, sinks and subscriptions
+//! * `analytics`: windowing, joins, rollups and other stateful
+//!   processing, as well as archives, sharding across processes,
+//!   fingerprints and title normalization
+//! * `enrichment`: looking up extra information from the Action API and
+//!   Lift Wing, e.g. backfilling missed events or scoring edits
+//! * `server`: running subscriptions as a daemon
+//! * `relay`: re-serving events to local SSE and WebSocket clients, so
+//!   tools can share one upstream connection
+//! * `cli`: the `eventstreams` command-line tool
+//! * `geoip`: locating anonymous editors with MaxMind databases
+//! * `gzip`: compressing [recordings](recorder::Recorder) as they're
+//!   written
+//! * `redis`: sharing enrichment caches through Redis
+//! * `signing`: signing events that are relayed to other consumers
+//! * `columnar`: buffering events into Arrow columns for analytics
+//! * `kafka`: re-publishing events into Kafka through the application's
+//!   own producer
+//! * `rdkafka`: a Kafka producer built on librdkafka, which is compiled
+//!   from source
+//! * `matrix`: posting events to a Matrix room
+//! * `irc`: posting events to an IRC channel
+//! * `msgpack`, `cbor`, `protobuf`: compact binary encodings for sinks
+//! * `tracing`: [`tracing`](https://docs.rs/tracing) spans and events for
+//!   connecting, reconnecting, parsing messages and dispatching to
+//!   listeners
+//! * `metrics`: reporting what a stream receives to a
+//!   [`MetricsSink`](metrics::MetricsSink)
+//! * `testing`: a mock SSE server and fixture replay for testing offline
+//! * `mw-interop`: acting on events with mwbot, e.g. editing the page
+//!   that was changed
+//! * `tokio`, `async-std`: timers for backoff, timeouts and pacing from
+//!   that runtime rather than a background thread of their own. Streams
+//!   work on any executor either way.
+//! * `ffi`: a C interface for embedding in tools written in other
+//!   languages, declared in `include/eventstreams.h`
+//! * `python`: a Python module with the stream, filters and events, built
+//!   with maturin, see [`python`](crate::python)
+//! * `wasm`: running in browsers on `wasm32-unknown-unknown`, connecting
+//!   through the browser's `EventSource`, see
+//!   [`browser`](https://docs.rs/eventstreams/*/wasm32-unknown-unknown/eventstreams/browser/)
+#[cfg(all(target_arch = "wasm32", not(feature = "wasm")))]
+compile_error!("building for wasm32 needs the `wasm` feature");
+
+pub mod action_comment;
+#[cfg(feature = "server")]
+pub mod admin;
+#[cfg(feature = "analytics")]
+pub mod aggregate;
+mod alert;
+#[cfg(feature = "enrichment")]
+pub mod api;
+#[cfg(feature = "analytics")]
+pub mod archive;
+#[cfg(feature = "sinks")]
+pub mod audit;
+pub mod backend;
+#[cfg(feature = "enrichment")]
+pub mod backfill;
+#[cfg(feature = "sinks")]
+pub mod backtest;
+#[cfg(feature = "sinks")]
+pub mod breaker;
+#[cfg(target_arch = "wasm32")]
+pub mod browser;
+mod builder;
+#[cfg(feature = "enrichment")]
+pub mod cache;
+#[cfg(feature = "analytics")]
+pub mod campaign;
+pub mod catalog;
+#[cfg(feature = "analytics")]
+pub mod category;
+pub mod chaos;
+pub mod checkpoint;
+pub mod classify;
+pub mod clock;
+#[cfg(feature = "columnar")]
+pub mod columnar;
+pub mod comment;
+mod compaction;
+#[cfg(feature = "server")]
+pub mod daemon;
+pub mod debug;
+pub mod dedup;
+#[cfg(feature = "sinks")]
+pub mod digest;
+pub mod drift;
+pub mod drops;
+#[cfg(feature = "sinks")]
+pub mod dual_run;
+pub mod envelope;
+mod error;
+pub mod etiquette;
+mod ext;
+pub mod extension;
+#[cfg(all(feature = "ffi", not(target_arch = "wasm32")))]
+pub mod ffi;
+#[cfg(feature = "sinks")]
+pub mod filter;
+#[cfg(feature = "analytics")]
+pub mod fingerprint;
+pub mod gaps;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+#[cfg(unix)]
+pub mod handoff;
+pub mod health;
+#[cfg(feature = "analytics")]
+pub mod heatmap;
+pub mod history;
+#[cfg(feature = "mw-interop")]
+pub mod interop;
+#[cfg(feature = "irc")]
+pub mod irc;
+#[cfg(feature = "analytics")]
+pub mod join;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "analytics")]
+pub mod keyed;
+pub mod labels;
+pub mod links;
+pub mod listener;
+mod log_params;
+pub mod maintenance;
+#[cfg(feature = "matrix")]
+pub mod matrix;
+pub mod metrics;
+#[cfg(feature = "analytics")]
+pub mod moves;
+pub mod namespace;
+pub mod pacing;
+#[cfg(feature = "analytics")]
+pub mod page_rate;
+pub mod partial;
+#[cfg(feature = "analytics")]
+pub mod patrol;
+pub mod pipe;
+pub mod pool;
+#[cfg(feature = "analytics")]
+pub mod privacy;
+mod project;
+#[cfg(all(feature = "python", not(target_arch = "wasm32")))]
+pub mod python;
+pub mod queue;
+pub mod rcfeed;
+pub mod readiness;
+pub mod recorder;
+#[cfg(feature = "relay")]
+pub mod relay;
+pub mod report;
+pub mod resume;
+#[cfg(feature = "analytics")]
+pub mod rollup;
+mod runtime;
+pub mod schema;
+#[cfg(feature = "enrichment")]
+pub mod scores;
+#[cfg(feature = "analytics")]
+pub mod seen;
+#[cfg(feature = "analytics")]
+pub mod shard;
+pub mod side_output;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "sinks")]
+pub mod sink;
+#[cfg(feature = "analytics")]
+pub mod split;
+mod stream;
+#[cfg(feature = "sinks")]
+pub mod subscription;
+#[cfg(all(feature = "server", unix))]
+pub mod systemd;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(any(feature = "sinks", feature = "analytics"))]
+pub mod title;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
+pub mod types;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod upstream;
+#[cfg(feature = "enrichment")]
+pub mod users;
+#[cfg(all(feature = "analytics", feature = "enrichment"))]
+pub mod velocity;
+pub mod watermark;
+pub mod wikidata;
+#[cfg(feature = "analytics")]
+pub mod window;
+pub mod worker;
 
 use async_stream::stream;
+use backend::BackendError;
+pub use builder::{EventStreamBuilder, Overflow, StreamKind, RETENTION};
+pub use compact_str::CompactString;
+pub use envelope::Envelope;
+pub use error::{BuildError, EventStreamError, SinceError};
+pub use ext::EventStreamExt;
 pub use futures::{Stream, StreamExt};
 pub use futures_util::pin_mut;
+pub use log_params::LogParams;
+pub use project::Project;
 use serde_json::Value;
-use surf_sse::{Event as SSEEvent, EventSource};
-pub use types::{EditEvent, Event, LogEvent};
+use side_output::{Excluded, SideOutput};
+pub use stream::{EventIter, EventStream};
+pub use types::prelude::*;
+
+fn handle_event(data: &str) -> Option<Result<Event, Excluded>> {
+    handle_event_with(data, &schema::Deserializer::default())
+}
+
+/// How to parse `value`, if it's one of the known event types
+fn parser(value: &Value) -> Option<schema::Parse> {
+    let kind = value["meta"]["stream"]
+        .as_str()
+        .and_then(StreamKind::from_stream);
+    let parse: schema::Parse = match kind {
+        Some(StreamKind::RevisionCreate) => {
+            |value| serde_json::from_value(value).map(Event::RevisionCreate)
+        }
+        Some(StreamKind::PageCreate) => {
+            |value| serde_json::from_value(value).map(Event::PageCreate)
+        }
+        Some(StreamKind::PageDelete) => {
+            |value| serde_json::from_value(value).map(Event::PageDelete)
+        }
+        Some(StreamKind::PageMove) => {
+            |value| serde_json::from_value(value).map(Event::PageMove)
+        }
+        Some(StreamKind::PageUndelete) => {
+            |value| serde_json::from_value(value).map(Event::PageUndelete)
+        }
+        Some(StreamKind::PageLinksChange) => {
+            |value| serde_json::from_value(value).map(Event::PageLinksChange)
+        }
+        Some(StreamKind::RevisionScore) => {
+            |value| serde_json::from_value(value).map(Event::RevisionScore)
+        }
+        Some(StreamKind::Test) => {
+            |value| serde_json::from_value(value).map(Event::Test)
+        }
+        // Recent changes, which are told apart by type
+        _ if value["type"] == "log" => {
+            |value| serde_json::from_value(value).map(Event::Log)
+        }
+        _ if value["type"] == "edit" => {
+            |value| serde_json::from_value(value).map(Event::Edit)
+        }
+        _ if value["type"] == "new" => {
+            |value| serde_json::from_value(value).map(Event::New)
+        }
+        _ if value["type"] == "categorize" => {
+            |value| serde_json::from_value(value).map(Event::Categorize)
+        }
+        _ if value["type"] == "external" => {
+            |value| serde_json::from_value(value).map(Event::External)
+        }
+        _ => return None,
+    };
+    Some(parse)
+}
+
+fn handle_event_with(
+    data: &str,
+    deserializer: &schema::Deserializer,
+) -> Option<Result<Event, Excluded>> {
+    handle_event_observed(data, deserializer, None).0
+}
+
+/// What [`Parser::parse()`] made of a message
+pub(crate) type Parsed =
+    (Option<Result<Event, Excluded>>, Vec<drift::SchemaDrift>);
+
+/// Parses messages the way a stream does: with its deserializer, checking
+/// for drift and [degrading](schema::Degrader) kinds that keep failing
+pub(crate) struct Parser {
+    deserializer: schema::Deserializer,
+    drift: Option<drift::DriftDetector>,
+    degrader: schema::Degrader,
+}
+
+impl Parser {
+    pub(crate) fn new(
+        deserializer: schema::Deserializer,
+        drift: Option<drift::DriftDetector>,
+    ) -> Self {
+        let degrader = schema::Degrader::new(&deserializer);
+        Self {
+            deserializer,
+            drift,
+            degrader,
+        }
+    }
+
+    pub(crate) fn parse(&mut self, data: &str) -> Parsed {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("parse", bytes = data.len()).entered();
+        let (parsed, drifted) = handle_event_observed(
+            data,
+            &self.deserializer,
+            self.drift.as_mut(),
+        );
+        (self.degrader.check(data, parsed), drifted)
+    }
+}
+
+/// A message from a backend, already parsed if something had to look at
+/// it before the stream, e.g. [alerts](alert::Alerts)
+#[derive(Clone)]
+pub(crate) struct Read {
+    data: String,
+    parsed: Option<Parsed>,
+}
 
-fn handle_event(event: SSEEvent) -> Option<Event> {
-    if event.data.is_empty() {
-        return None;
+impl Read {
+    pub(crate) fn parsed(data: String, parsed: Parsed) -> Self {
+        Self {
+            data,
+            parsed: Some(parsed),
+        }
+    }
+
+    /// The event the message was parsed as, if it was and is one
+    pub(crate) fn event(&self) -> Option<&Event> {
+        match &self.parsed {
+            Some((Some(Ok(event)), _)) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for Read {
+    fn from(data: String) -> Self {
+        Self { data, parsed: None }
+    }
+}
+
+/// Like [`handle_event_with()`], also checking the message's fields with
+/// `drift` once it's been parsed as JSON
+fn handle_event_observed(
+    data: &str,
+    deserializer: &schema::Deserializer,
+    drift: Option<&mut drift::DriftDetector>,
+) -> Parsed {
+    if data.is_empty() {
+        return (None, vec![]);
     }
-    let value: Value = match serde_json::from_str(&event.data) {
+    let malformed = |reason: String| Excluded::Malformed {
+        data: data.to_string(),
+        reason,
+    };
+    let value: Value = match serde_json::from_str(data) {
         Ok(value) => value,
-        Err(_) => return None,
+        Err(err) => return (Some(Err(malformed(err.to_string()))), vec![]),
+    };
+    // Before the value is consumed by parsing
+    let drifted = match (drift, &value) {
+        (Some(drift), Value::Object(message)) => drift.observe(message),
+        _ => vec![],
     };
-    if value["type"] == "log" {
-        Some(Event::Log(serde_json::from_value(value).unwrap()))
-    } else if value["type"] == "edit" {
-        Some(Event::Edit(serde_json::from_value(value).unwrap()))
-    } else {
-        None
+    let parsed = handle_value(data, value, deserializer).map_err(malformed);
+    (Some(parsed), drifted)
+}
+
+/// Parse an event from `value`, which was parsed from `data`. Errors are
+/// the reason the message is malformed.
+fn handle_value(
+    data: &str,
+    value: Value,
+    deserializer: &schema::Deserializer,
+) -> Result<Event, String> {
+    let parsed = match parser(&value) {
+        Some(parse) => deserializer.parse(value, parse),
+        None => match extension::parse(value.clone()) {
+            Some(parsed) => parsed,
+            // Keep anything else that looks like an event as it is
+            None => match serde_json::from_value(value["meta"].clone()) {
+                Ok(meta) => Ok(Event::Unknown(UnknownEvent { meta, value })),
+                Err(_) => {
+                    return Err(format!(
+                        "unsupported event type: {}",
+                        value["type"]
+                    ))
+                }
+            },
+        },
+    };
+    parsed
+        .map(|mut event| {
+            if deserializer.keep_raw {
+                event.meta_mut().raw = Some(data.into());
+            }
+            event
+        })
+        .map_err(|err| err.to_string())
+}
+
+/// Whether `data` is the start of a JSON document that ends too early
+fn is_truncated(data: &str) -> bool {
+    matches!(
+        serde_json::from_str::<serde::de::IgnoredAny>(data),
+        Err(err) if err.is_eof()
+    )
+}
+
+/// Parse events from a [`backend`], e.g. one wrapped in a
+/// [`FaultInjector`](backend::FaultInjector) for testing. Events that can't
+/// be parsed are sent to `side_output` if there is one.
+/// Backend errors are skipped over, as backends recover on their own.
+pub fn from_backend(
+    backend: impl Stream<Item = Result<String, BackendError>>,
+    side_output: Option<SideOutput>,
+) -> impl Stream<Item = Event> {
+    parse_backend(backend, side_output)
+        .filter_map(|message| futures::future::ready(message.ok()))
+}
+
+/// Parse events from a backend, reporting everything that goes wrong
+/// along the way inline. Unparseable messages are still sent to
+/// `side_output` if there is one. Messages that were already
+/// [parsed](Read::parsed) aren't parsed again.
+pub(crate) fn parse_with_errors(
+    backend: impl Stream<Item = Result<impl Into<Read>, BackendError>>,
+    side_output: Option<SideOutput>,
+    drift: Option<drift::DriftDetector>,
+    deserializer: schema::Deserializer,
+) -> impl Stream<Item = Result<Event, EventStreamError>> {
+    let mut gaps = gaps::GapDetector::new();
+    let mut parser = Parser::new(deserializer, drift);
+    stream! {
+        for await message in backend {
+            let Read { data, parsed } = match message {
+                Ok(read) => read.into(),
+                Err(err) => {
+                    yield Err(EventStreamError::Backend(err));
+                    continue;
+                }
+            };
+            let (parsed, drifted) =
+                parsed.unwrap_or_else(|| parser.parse(&data));
+            // Check every message, so excluded events aren't mistaken for
+            // missing ones
+            let gap = match &parsed {
+                Some(Ok(event)) => {
+                    let meta = event.meta();
+                    gaps.observe(&meta.topic, meta.partition, meta.offset)
+                }
+                _ => gaps.observe_raw(&data),
+            };
+            if let Some(gap) = gap {
+                yield Err(EventStreamError::Gap(gap));
+            }
+            for change in drifted {
+                yield Err(EventStreamError::SchemaDrift(change));
+            }
+            match parsed {
+                Some(Ok(event)) => yield Ok(event),
+                Some(Err(excluded)) => {
+                    if let Excluded::Malformed { data, reason } = &excluded {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!(
+                            reason = reason.as_str(),
+                            bytes = data.len(),
+                            truncated = is_truncated(data),
+                            "malformed message"
+                        );
+                        if is_truncated(data) {
+                            yield Err(EventStreamError::Truncated {
+                                data: data.clone(),
+                            });
+                        } else {
+                            yield Err(EventStreamError::Malformed {
+                                data: data.clone(),
+                                reason: reason.clone(),
+                            });
+                        }
+                    }
+                    if let Some(side_output) = &side_output {
+                        side_output.send(excluded);
+                    }
+                }
+                None => {}
+            }
+        }
     }
 }
 
-pub fn stream() -> impl Stream<Item = Event> {
-    let source = EventSource::new(
-        "https://stream.wikimedia.org/v2/stream/recentchange"
-            .parse()
-            .unwrap(),
-    );
+/// Parse events from a backend, passing through backend errors
+pub(crate) fn parse_backend(
+    backend: impl Stream<Item = Result<String, BackendError>>,
+    side_output: Option<SideOutput>,
+) -> impl Stream<Item = Result<Event, BackendError>> {
     stream! {
-        for await event in source {
-            if let Some(event) = handle_event(event.unwrap()) {
-                yield event;
+        for await message in backend {
+            let data = match message {
+                Ok(data) => data,
+                Err(err) => {
+                    yield Err(err);
+                    continue;
+                }
+            };
+            match handle_event(&data) {
+                Some(Ok(event)) => yield Ok(event),
+                Some(Err(excluded)) => {
+                    if let Some(side_output) = &side_output {
+                        side_output.send(excluded);
+                    }
+                }
+                None => {}
             }
         }
     }
 }
+
+/// Stream events from the live recent changes feed. This is a regular
+/// [`Stream`], so it can be used from any async runtime, but it isn't
+/// `Send`: poll it on the thread that created it, or use
+/// [`EventStreamBuilder::into_channel()`] or
+/// [`EventStreamBuilder::spawn()`] to receive events on another thread.
+pub fn stream() -> EventStream {
+    EventStreamBuilder::new().into_stream()
+}
+
+/// Like [`stream()`], but with every parse failure, reconnect and gap
+/// inline in the same stream as events
+pub fn stream_with_errors(
+) -> impl Stream<Item = Result<Event, EventStreamError>> {
+    EventStreamBuilder::new().into_stream_with_errors()
+}
+
+/// Like [`stream()`], but each event is wrapped in an [`Envelope`] with
+/// details about where it came from
+pub fn stream_with_envelopes() -> impl Stream<Item = Envelope> {
+    EventStreamBuilder::new().into_stream_with_envelopes()
+}
+
+/// Like [`stream()`], but events that can't be parsed are sent to
+/// `side_output` instead of being dropped
+pub fn stream_with_side_output(
+    side_output: SideOutput,
+) -> impl Stream<Item = Event> {
+    EventStreamBuilder::new()
+        .side_output(side_output)
+        .into_stream()
+}
@@ -0,0 +1,258 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Link changes from the `page-links-change` stream
+//!
+//! Separately from recent changes, EventStreams has a
+//! [`mediawiki.page-links-change`](https://stream.wikimedia.org/?doc#/streams/get_v2_stream_mediawiki_page_links_change)
+//! stream listing the links added to and removed from a page by each
+//! edit. [`LinkTracker`] uses it to notify when links to a configured set
+//! of pages, e.g. templates, come and go anywhere on a wiki.
+//!
+//! MediaWiki only includes wikilinks and external links in this stream, so
+//! a template that is transcluded without being linked to won't show up.
+use crate::backend::{self, BackendError};
+use crate::types::EventMeta;
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+const URL: &str =
+    "https://stream.wikimedia.org/v2/stream/mediawiki.page-links-change";
+
+/// Links added to and removed from a page by one edit
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PageLinksChange {
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// Prefixed title of the page
+    pub page_title: String,
+    pub page_namespace: i32,
+    /// Revision that changed the links, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub added_links: Vec<Link>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed_links: Vec<Link>,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl PageLinksChange {
+    /// Time the change happened
+    pub fn dt(&self) -> DateTime<Utc> {
+        self.meta.dt
+    }
+}
+
+/// A link from a page
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Link {
+    /// For wikilinks, the path to the target, e.g. `/wiki/Template:Cite_web`;
+    /// otherwise the URL
+    pub link: String,
+    /// Whether this is an external link rather than a wikilink
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub external: bool,
+}
+
+impl Link {
+    /// Prefixed title of a wikilink's target, with spaces rather than
+    /// underscores. `None` for external links.
+    pub fn title(&self) -> Option<String> {
+        if self.external {
+            return None;
+        }
+        let path = self.link.strip_prefix("/wiki/")?;
+        Some(percent_decode(path).replace('_', " "))
+    }
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = input
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parse link changes from a [`backend`], skipping anything that can't be
+/// parsed
+pub fn from_backend(
+    backend: impl Stream<Item = Result<String, BackendError>>,
+) -> impl Stream<Item = PageLinksChange> {
+    backend.filter_map(|message| {
+        futures::future::ready(
+            message
+                .ok()
+                .and_then(|data| serde_json::from_str(&data).ok()),
+        )
+    })
+}
+
+/// The live `page-links-change` stream
+pub fn stream() -> impl Stream<Item = PageLinksChange> {
+    from_backend(backend::connect(URL.parse().unwrap()))
+}
+
+/// A link to a tracked page being added or removed
+#[derive(Clone, Debug)]
+pub struct LinkChange {
+    /// The tracked page, as configured
+    pub target: String,
+    /// Prefixed title of the page the link is on
+    pub page: String,
+    /// Whether the link was added rather than removed
+    pub added: bool,
+    pub change: PageLinksChange,
+}
+
+/// Watches for links to a set of pages on one wiki
+#[derive(Clone, Debug)]
+pub struct LinkTracker {
+    wiki: String,
+    targets: Vec<String>,
+}
+
+impl LinkTracker {
+    /// Track links to `targets` (prefixed titles, e.g.
+    /// `Template:Citation needed`) on `wiki` (internal database name)
+    pub fn new<I, S>(wiki: &str, targets: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            wiki: wiki.to_string(),
+            targets: targets
+                .into_iter()
+                .map(|target| target.into().replace('_', " "))
+                .collect(),
+        }
+    }
+
+    /// Check a change, returning one [`LinkChange`] for each link to a
+    /// tracked page that was added or removed
+    pub fn push(&self, change: &PageLinksChange) -> Vec<LinkChange> {
+        if change.database != self.wiki {
+            return vec![];
+        }
+        let added = change.added_links.iter().map(|link| (link, true));
+        let removed = change.removed_links.iter().map(|link| (link, false));
+        added
+            .chain(removed)
+            .filter_map(|(link, added)| {
+                let title = link.title()?;
+                let target = self.targets.iter().find(|t| **t == title)?;
+                Some(LinkChange {
+                    target: target.clone(),
+                    page: change.page_title.replace('_', " "),
+                    added,
+                    change: change.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Pass on link changes to tracked pages from `changes`, e.g.
+    /// [`stream()`]
+    pub fn track(
+        self,
+        changes: impl Stream<Item = PageLinksChange>,
+    ) -> impl Stream<Item = LinkChange> {
+        changes
+            .flat_map(move |change| futures::stream::iter(self.push(&change)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    fn change(
+        database: &str,
+        added: &[&str],
+        removed: &[&str],
+    ) -> PageLinksChange {
+        let links = |links: &[&str]| -> Vec<Value> {
+            links
+                .iter()
+                .map(|link| serde_json::json!({ "link": link }))
+                .collect()
+        };
+        serde_json::from_value(serde_json::json!({
+            "meta": testing::edit(1, "Ada_Lovelace", at(0))["meta"],
+            "database": database,
+            "page_id": 1,
+            "page_title": "Ada_Lovelace",
+            "page_namespace": 0,
+            "rev_id": 2,
+            "added_links": links(added),
+            "removed_links": links(removed),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_links_to_tracked_pages() {
+        let tracker = LinkTracker::new("enwiki", ["Template:Citation_needed"]);
+        let found = tracker.push(&change(
+            "enwiki",
+            &[
+                "/wiki/Template:Citation%20needed",
+                "/wiki/Analytical_Engine",
+            ],
+            &["/wiki/Template:Citation_needed"],
+        ));
+        let found: Vec<_> = found
+            .iter()
+            .map(|c| (c.target.as_str(), c.page.as_str(), c.added))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("Template:Citation needed", "Ada Lovelace", true),
+                ("Template:Citation needed", "Ada Lovelace", false),
+            ]
+        );
+        assert!(tracker
+            .push(&change("dewiki", &["/wiki/Template:Citation_needed"], &[]))
+            .is_empty());
+    }
+}
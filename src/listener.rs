@@ -0,0 +1,1134 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Callbacks for events
+//!
+//! Instead of consuming an [`EventStream`](crate::EventStream) directly,
+//! listeners can be registered on its [`Listeners`] and are called for
+//! every event as it passes through, e.g. while
+//! [`run()`](crate::EventStream::run) drives the stream. A listener can
+//! unsubscribe itself by returning `ControlFlow::Break` or `false`.
+//!
+//! Listeners may be `FnMut`, so they can keep state like counters without
+//! any interior mutability of their own; each one is wrapped in a mutex.
+//!
+//! Listeners borrow each event, so no matter how many there are, nothing
+//! is copied. Those that need to hold on to events can use
+//! [`on_shared()`](Listeners::on_shared) instead of cloning them, which
+//! copies each event at most once, for all of them together.
+//!
+//! Registering a listener returns a [`ListenerHandle`] that can remove it
+//! again later, or be turned into a [`ListenerGuard`] that removes it when
+//! dropped.
+//!
+//! Changes to the connection, like disconnects and reconnects, and errors
+//! can be listened for too.
+//!
+//! A [`SubscriptionManager`] routes events to handlers for individual
+//! wikis, which can be subscribed and unsubscribed at runtime, e.g. from
+//! configuration. Each event only reaches the handlers for its own wiki.
+use crate::backend::{BackendError, ConnectionEvent, Diagnostic};
+use crate::debug::ListenerCounts;
+#[cfg(feature = "sinks")]
+use crate::filter::Filter;
+use crate::partial::PartialEvent;
+use crate::project;
+use crate::{
+    CategorizeEvent, EditEvent, Event, EventStreamError, ExternalEvent,
+    LogEvent, NewPageEvent, PageDeleteEvent, PageLifecycle, PageMoveEvent,
+    PageUndeleteEvent, Project, RevisionCreateEvent, StreamKind,
+};
+use std::cell::OnceCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+/// What a listener returns: `()` to keep listening, or `false` /
+/// `ControlFlow::Break` to unsubscribe
+pub trait ListenerResult {
+    /// Whether the listener wants more events
+    fn keep_listening(self) -> bool;
+}
+
+impl ListenerResult for () {
+    fn keep_listening(self) -> bool {
+        true
+    }
+}
+
+impl ListenerResult for bool {
+    fn keep_listening(self) -> bool {
+        self
+    }
+}
+
+impl<B, C> ListenerResult for ControlFlow<B, C> {
+    fn keep_listening(self) -> bool {
+        self.is_continue()
+    }
+}
+
+type Callback = Arc<dyn Fn(&Delivery<'_>) -> bool + Send + Sync>;
+type ConnectionCallback = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+type ErrorCallback = Arc<dyn Fn(&EventStreamError) + Send + Sync>;
+type CanaryCallback = Arc<dyn Fn(&Event) + Send + Sync>;
+
+#[derive(Default)]
+struct Registry {
+    next_id: u64,
+    // Copied on write, so dispatching only has to clone the `Arc`
+    callbacks: Arc<Vec<(u64, Callback)>>,
+    connection: Vec<(u64, ConnectionCallback)>,
+    errors: Vec<(u64, ErrorCallback)>,
+    canaries: Vec<(u64, CanaryCallback)>,
+}
+
+impl Registry {
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        self.callbacks.iter().any(|(other, _)| *other == id)
+            || self.connection.iter().any(|(other, _)| *other == id)
+            || self.errors.iter().any(|(other, _)| *other == id)
+            || self.canaries.iter().any(|(other, _)| *other == id)
+    }
+
+    fn remove(&mut self, id: u64) {
+        if self.callbacks.iter().any(|(other, _)| *other == id) {
+            Arc::make_mut(&mut self.callbacks)
+                .retain(|(other, _)| *other != id);
+        }
+        self.connection.retain(|(other, _)| *other != id);
+        self.errors.retain(|(other, _)| *other != id);
+        self.canaries.retain(|(other, _)| *other != id);
+    }
+
+    /// Give back memory left over from listeners that were removed
+    fn compact(&mut self) {
+        // Skipped while a dispatch holds a copy, until next time
+        if let Some(callbacks) = Arc::get_mut(&mut self.callbacks) {
+            callbacks.shrink_to_fit();
+        }
+        self.connection.shrink_to_fit();
+        self.errors.shrink_to_fit();
+        self.canaries.shrink_to_fit();
+    }
+}
+
+/// An event being dispatched
+struct Delivery<'a> {
+    event: &'a Event,
+    shared: OnceCell<Arc<Event>>,
+}
+
+impl Delivery<'_> {
+    /// The event in an `Arc`, cloned the first time it's needed
+    fn shared(&self) -> Arc<Event> {
+        self.shared
+            .get_or_init(|| Arc::new(self.event.clone()))
+            .clone()
+    }
+}
+
+/// Refers to a registered listener, so it can be removed again. Dropping
+/// the handle leaves the listener in place; see
+/// [`guard()`](Self::guard) for one that doesn't.
+#[derive(Clone, Debug)]
+pub struct ListenerHandle {
+    registry: Weak<Mutex<Registry>>,
+    id: u64,
+}
+
+impl ListenerHandle {
+    /// Remove the listener. If events are being dispatched at the same
+    /// time, it may still be called for the current one.
+    pub fn remove(&self) {
+        if let Some(registry) = self.registry.upgrade() {
+            registry.lock().unwrap().remove(self.id);
+        }
+    }
+
+    /// Whether the listener is still registered, i.e. it hasn't been
+    /// removed and hasn't unsubscribed itself
+    pub fn is_active(&self) -> bool {
+        self.registry
+            .upgrade()
+            .is_some_and(|registry| registry.lock().unwrap().contains(self.id))
+    }
+
+    /// Remove the listener once the returned guard is dropped
+    pub fn guard(self) -> ListenerGuard {
+        ListenerGuard(Some(self))
+    }
+}
+
+/// Removes its listener when dropped, see [`ListenerHandle::guard()`]
+#[derive(Debug)]
+#[must_use = "the listener is removed as soon as the guard is dropped"]
+pub struct ListenerGuard(Option<ListenerHandle>);
+
+impl ListenerGuard {
+    /// Keep the listener registered after all, returning its handle
+    pub fn into_handle(mut self) -> ListenerHandle {
+        self.0.take().expect("only taken here")
+    }
+}
+
+impl Drop for ListenerGuard {
+    fn drop(&mut self) {
+        if let Some(handle) = &self.0 {
+            handle.remove();
+        }
+    }
+}
+
+/// Shared set of listeners. Clones refer to the same set, so listeners can
+/// be added while events are being dispatched.
+#[derive(Clone, Default)]
+pub struct Listeners {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl Listeners {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn handle(&self, id: u64) -> ListenerHandle {
+        ListenerHandle {
+            registry: Arc::downgrade(&self.inner),
+            id,
+        }
+    }
+
+    fn add(&self, callback: Callback) -> ListenerHandle {
+        let mut registry = self.inner.lock().unwrap();
+        let id = registry.next_id();
+        Arc::make_mut(&mut registry.callbacks).push((id, callback));
+        self.handle(id)
+    }
+
+    /// Call `listener` for every event
+    pub fn on_event<R: ListenerResult>(
+        &self,
+        listener: impl FnMut(&Event) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        let listener = Mutex::new(listener);
+        self.add(Arc::new(move |delivery| {
+            let mut listener =
+                listener.lock().unwrap_or_else(PoisonError::into_inner);
+            listener(delivery.event).keep_listening()
+        }))
+    }
+
+    /// Like [`on_event()`](Self::on_event), for a listener that can be
+    /// called from several threads at once, e.g. by a
+    /// [`DispatchPool`](crate::pool::DispatchPool). It isn't wrapped in a
+    /// mutex, so calls don't wait for each other.
+    pub fn on_event_sync<R: ListenerResult>(
+        &self,
+        listener: impl Fn(&Event) -> R + Send + Sync + 'static,
+    ) -> ListenerHandle {
+        self.add(Arc::new(move |delivery| {
+            listener(delivery.event).keep_listening()
+        }))
+    }
+
+    /// Call `listener` with a shared copy of every event, e.g. to keep
+    /// some of them around or send them elsewhere. Events are copied once
+    /// for all such listeners together, rather than once for each.
+    pub fn on_shared<R: ListenerResult>(
+        &self,
+        listener: impl FnMut(Arc<Event>) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        let listener = Mutex::new(listener);
+        self.add(Arc::new(move |delivery| {
+            let mut listener =
+                listener.lock().unwrap_or_else(PoisonError::into_inner);
+            listener(delivery.shared()).keep_listening()
+        }))
+    }
+
+    /// Call `listener` for every event matching `filter`
+    #[cfg(feature = "sinks")]
+    pub fn on_filtered<R: ListenerResult>(
+        &self,
+        filter: Filter,
+        mut listener: impl FnMut(&Event) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| {
+            !filter.matches(event) || listener(event).keep_listening()
+        })
+    }
+
+    /// Call `listener` for every event from `stream`, e.g. to handle each
+    /// stream of a [multiplexed](crate::EventStreamBuilder::streams)
+    /// connection separately
+    pub fn on_stream<R: ListenerResult>(
+        &self,
+        stream: StreamKind,
+        mut listener: impl FnMut(&Event) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| {
+            event.stream_kind() != Some(stream)
+                || listener(event).keep_listening()
+        })
+    }
+
+    /// Call `listener` for every edit
+    pub fn on_edit<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&EditEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::Edit(edit) => listener(edit).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every edit to a wiki of `project`, e.g.
+    /// [`Project::Wikipedia`]
+    pub fn on_project_edit<R: ListenerResult>(
+        &self,
+        project: Project,
+        mut listener: impl FnMut(&EditEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_edit(move |edit| {
+            edit.project() != project || listener(edit).keep_listening()
+        })
+    }
+
+    /// Call `listener` for every page creation
+    pub fn on_new_page<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&NewPageEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::New(new) => listener(new).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every log entry
+    pub fn on_log<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&LogEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::Log(log) => listener(log).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every categorization change
+    pub fn on_categorize<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&CategorizeEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::Categorize(categorize) => {
+                listener(categorize).keep_listening()
+            }
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every new revision from the `revision-create`
+    /// stream, see [`StreamKind::RevisionCreate`]
+    pub fn on_revision_create<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&RevisionCreateEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::RevisionCreate(revision) => {
+                listener(revision).keep_listening()
+            }
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every page move from the `page-move` stream
+    pub fn on_page_move<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&PageMoveEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::PageMove(move_) => listener(move_).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every page deletion from the `page-delete`
+    /// stream
+    pub fn on_page_delete<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&PageDeleteEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::PageDelete(delete) => listener(delete).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every restored page from the `page-undelete`
+    /// stream
+    pub fn on_page_undelete<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&PageUndeleteEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::PageUndelete(undelete) => {
+                listener(undelete).keep_listening()
+            }
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every page created, deleted, undeleted or moved,
+    /// e.g. to keep track of which pages exist. The stream needs to include
+    /// the `page-create`, `page-delete`, `page-undelete` and `page-move`
+    /// streams.
+    pub fn on_page_lifecycle<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(PageLifecycle<'_>) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event.page_lifecycle() {
+            Some(lifecycle) => listener(lifecycle).keep_listening(),
+            None => true,
+        })
+    }
+
+    /// Call `listener` for every change made elsewhere, e.g. on Wikidata
+    pub fn on_external<R: ListenerResult>(
+        &self,
+        mut listener: impl FnMut(&ExternalEvent) -> R + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_event(move |event| match event {
+            Event::External(external) => listener(external).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` with `owner` for every event, for as long as the
+    /// owner is alive. Once it has been dropped, the listener is removed
+    /// the next time an event comes in.
+    pub fn on_event_weak<T, R>(
+        &self,
+        owner: Weak<T>,
+        mut listener: impl FnMut(&T, &Event) -> R + Send + 'static,
+    ) -> ListenerHandle
+    where
+        T: Send + Sync + 'static,
+        R: ListenerResult,
+    {
+        self.on_event(move |event| match owner.upgrade() {
+            Some(owner) => listener(&owner, event).keep_listening(),
+            None => false,
+        })
+    }
+
+    /// Call `listener` with `owner` for every edit, see
+    /// [`on_event_weak()`](Self::on_event_weak)
+    pub fn on_edit_weak<T, R>(
+        &self,
+        owner: Weak<T>,
+        mut listener: impl FnMut(&T, &EditEvent) -> R + Send + 'static,
+    ) -> ListenerHandle
+    where
+        T: Send + Sync + 'static,
+        R: ListenerResult,
+    {
+        self.on_event_weak(owner, move |owner, event| match event {
+            Event::Edit(edit) => listener(owner, edit).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` with `owner` for every log entry, see
+    /// [`on_event_weak()`](Self::on_event_weak)
+    pub fn on_log_weak<T, R>(
+        &self,
+        owner: Weak<T>,
+        mut listener: impl FnMut(&T, &LogEvent) -> R + Send + 'static,
+    ) -> ListenerHandle
+    where
+        T: Send + Sync + 'static,
+        R: ListenerResult,
+    {
+        self.on_event_weak(owner, move |owner, event| match event {
+            Event::Log(log) => listener(owner, log).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Call `listener` for every change to the connection
+    pub fn on_connection(
+        &self,
+        listener: impl FnMut(&ConnectionEvent) + Send + 'static,
+    ) -> ListenerHandle {
+        let listener = Mutex::new(listener);
+        let mut registry = self.inner.lock().unwrap();
+        let id = registry.next_id();
+        registry.connection.push((
+            id,
+            Arc::new(move |event| {
+                let mut listener =
+                    listener.lock().unwrap_or_else(PoisonError::into_inner);
+                listener(event)
+            }),
+        ));
+        self.handle(id)
+    }
+
+    /// Call `listener` whenever the connection opens, initially and after
+    /// every reconnect
+    pub fn on_open(
+        &self,
+        mut listener: impl FnMut() + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_connection(move |event| {
+            if let ConnectionEvent::Open = event {
+                listener();
+            }
+        })
+    }
+
+    /// Call `listener` once the stream is shut down or dropped, e.g. to
+    /// flush anything other listeners have buffered
+    pub fn on_close(
+        &self,
+        mut listener: impl FnMut() + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_connection(move |event| {
+            if let ConnectionEvent::Closed = event {
+                listener();
+            }
+        })
+    }
+
+    /// Call `listener` whenever the connection is lost or fails
+    pub fn on_disconnect(
+        &self,
+        mut listener: impl FnMut(&BackendError) + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_connection(move |event| {
+            if let ConnectionEvent::Disconnected(err) = event {
+                listener(err);
+            }
+        })
+    }
+
+    /// Call `listener` with the attempt number and delay before each
+    /// reconnect
+    pub fn on_reconnect(
+        &self,
+        mut listener: impl FnMut(u32, Duration) + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_connection(move |event| {
+            if let ConnectionEvent::Reconnecting { attempt, delay } = event {
+                listener(*attempt, *delay);
+            }
+        })
+    }
+
+    /// Call `listener` with whatever the server sends besides events, if
+    /// [enabled](crate::EventStreamBuilder::diagnostics)
+    pub fn on_diagnostic(
+        &self,
+        mut listener: impl FnMut(&Diagnostic) + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_connection(move |event| {
+            if let ConnectionEvent::Diagnostic(diagnostic) = event {
+                listener(diagnostic);
+            }
+        })
+    }
+
+    /// Call `listener` for every error, like a message that couldn't be
+    /// parsed. Errors don't end the stream.
+    pub fn on_error(
+        &self,
+        listener: impl FnMut(&EventStreamError) + Send + 'static,
+    ) -> ListenerHandle {
+        let listener = Mutex::new(listener);
+        let mut registry = self.inner.lock().unwrap();
+        let id = registry.next_id();
+        registry.errors.push((
+            id,
+            Arc::new(move |err| {
+                let mut listener =
+                    listener.lock().unwrap_or_else(PoisonError::into_inner);
+                listener(err)
+            }),
+        ));
+        self.handle(id)
+    }
+
+    /// Call `listener` for every canary event, which Wikimedia injects into
+    /// streams to check that they work (`meta.domain` is `canary`). These
+    /// don't reach the other listeners unless the stream
+    /// [keeps them](crate::EventStreamBuilder::keep_canaries).
+    pub fn on_canary(
+        &self,
+        listener: impl FnMut(&Event) + Send + 'static,
+    ) -> ListenerHandle {
+        let listener = Mutex::new(listener);
+        let mut registry = self.inner.lock().unwrap();
+        let id = registry.next_id();
+        registry.canaries.push((
+            id,
+            Arc::new(move |event| {
+                let mut listener =
+                    listener.lock().unwrap_or_else(PoisonError::into_inner);
+                listener(event)
+            }),
+        ));
+        self.handle(id)
+    }
+
+    /// Call `listener` with whatever could be read from each message that
+    /// couldn't be parsed into an [`Event`], see [`partial`](crate::partial).
+    /// Messages that aren't JSON objects at all, e.g. truncated ones, are
+    /// only reported through [`on_error()`](Self::on_error).
+    pub fn on_partial(
+        &self,
+        mut listener: impl FnMut(&PartialEvent) + Send + 'static,
+    ) -> ListenerHandle {
+        self.on_error(move |err| {
+            if let EventStreamError::Malformed { data, reason } = err {
+                if let Some(partial) = PartialEvent::parse(data, reason) {
+                    listener(&partial);
+                }
+            }
+        })
+    }
+
+    pub(crate) fn counts(&self) -> ListenerCounts {
+        let registry = self.inner.lock().unwrap();
+        ListenerCounts {
+            events: registry.callbacks.len(),
+            connection: registry.connection.len(),
+            errors: registry.errors.len(),
+            canaries: registry.canaries.len(),
+        }
+    }
+
+    pub(crate) fn compact(&self) {
+        self.inner.lock().unwrap().compact();
+    }
+
+    /// Number of registered event listeners
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().callbacks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Call every connection listener with `event`
+    pub(crate) fn dispatch_connection(&self, event: &ConnectionEvent) {
+        let callbacks = self.inner.lock().unwrap().connection.clone();
+        for (_, callback) in callbacks {
+            callback(event);
+        }
+    }
+
+    /// Call every error listener with `err`
+    pub(crate) fn dispatch_error(&self, err: &EventStreamError) {
+        let callbacks = self.inner.lock().unwrap().errors.clone();
+        for (_, callback) in callbacks {
+            callback(err);
+        }
+    }
+
+    /// Call every canary listener with `event`
+    pub(crate) fn dispatch_canary(&self, event: &Event) {
+        let callbacks = self.inner.lock().unwrap().canaries.clone();
+        for (_, callback) in callbacks {
+            callback(event);
+        }
+    }
+
+    /// Call every listener with `event`, removing those that unsubscribe.
+    /// Listeners are called without holding the lock, so they may add more
+    /// listeners; those only see the next event.
+    pub fn dispatch(&self, event: &Event) {
+        self.deliver(Delivery {
+            event,
+            shared: OnceCell::new(),
+        });
+    }
+
+    /// Like [`dispatch()`](Self::dispatch), but for an event that is
+    /// already shared, which is then passed to
+    /// [`on_shared()`](Self::on_shared) listeners without copying it
+    pub fn dispatch_shared(&self, event: &Arc<Event>) {
+        self.deliver(Delivery {
+            event,
+            shared: OnceCell::from(event.clone()),
+        });
+    }
+
+    fn deliver(&self, delivery: Delivery<'_>) {
+        let callbacks = self.inner.lock().unwrap().callbacks.clone();
+        #[cfg(feature = "tracing")]
+        let _span =
+            tracing::debug_span!("dispatch", listeners = callbacks.len())
+                .entered();
+        #[cfg(feature = "tracing")]
+        let started = std::time::Instant::now();
+        let done: Vec<u64> = callbacks
+            .iter()
+            .filter(|(_, callback)| !callback(&delivery))
+            .map(|(id, _)| *id)
+            .collect();
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            elapsed_us = started.elapsed().as_micros() as u64,
+            unsubscribed = done.len(),
+            "dispatched"
+        );
+        if !done.is_empty() {
+            let mut registry = self.inner.lock().unwrap();
+            Arc::make_mut(&mut registry.callbacks)
+                .retain(|(id, _)| !done.contains(id));
+        }
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Listeners")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// Handlers for a single wiki, copied on write like [`Registry`]
+type WikiHandlers = Arc<Vec<(u64, Callback)>>;
+
+/// Routes events to handlers subscribed to their wiki, see the
+/// [module documentation](self). Clones refer to the same set of
+/// subscriptions.
+#[derive(Clone, Default)]
+pub struct SubscriptionManager {
+    inner: Arc<Mutex<(u64, HashMap<String, WikiHandlers>)>>,
+}
+
+impl SubscriptionManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `handler` for every event on `wiki`, until it unsubscribes
+    /// itself or the wiki is [unsubscribed](Self::unsubscribe). The wiki
+    /// can be given by domain, server URL or internal database name, as
+    /// with [`Event::is_on_wiki()`].
+    pub fn subscribe<R: ListenerResult>(
+        &self,
+        wiki: impl Into<String>,
+        handler: impl FnMut(&Event) -> R + Send + 'static,
+    ) {
+        let handler = Mutex::new(handler);
+        let callback: Callback = Arc::new(move |delivery| {
+            let mut handler =
+                handler.lock().unwrap_or_else(PoisonError::into_inner);
+            handler(delivery.event).keep_listening()
+        });
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.0;
+        inner.0 += 1;
+        let wiki = project::wiki_id(&wiki.into()).to_string();
+        Arc::make_mut(inner.1.entry(wiki).or_default()).push((id, callback));
+    }
+
+    /// Call `handler` for every edit on `wiki`, see
+    /// [`subscribe()`](Self::subscribe)
+    pub fn subscribe_edits<R: ListenerResult>(
+        &self,
+        wiki: impl Into<String>,
+        mut handler: impl FnMut(&EditEvent) -> R + Send + 'static,
+    ) {
+        self.subscribe(wiki, move |event| match event {
+            Event::Edit(edit) => handler(edit).keep_listening(),
+            _ => true,
+        })
+    }
+
+    /// Remove every handler for `wiki`, returning whether there were any.
+    /// This only affects handlers subscribed using the same form of the
+    /// wiki's name.
+    pub fn unsubscribe(&self, wiki: &str) -> bool {
+        let wiki = project::wiki_id(wiki);
+        self.inner.lock().unwrap().1.remove(wiki).is_some()
+    }
+
+    pub fn is_subscribed(&self, wiki: &str) -> bool {
+        let wiki = project::wiki_id(wiki);
+        self.inner.lock().unwrap().1.contains_key(wiki)
+    }
+
+    /// Wikis with at least one handler, sorted
+    pub fn wikis(&self) -> Vec<String> {
+        let mut wikis: Vec<_> =
+            self.inner.lock().unwrap().1.keys().cloned().collect();
+        wikis.sort();
+        wikis
+    }
+
+    /// Call the handlers for `event`'s wiki, removing those that
+    /// unsubscribe. As with [`Listeners::dispatch()`], handlers are called
+    /// without holding the lock, so they may change subscriptions.
+    pub fn dispatch(&self, event: &Event) {
+        for wiki in [event.server_name(), event.wiki()].iter().copied() {
+            if !wiki.is_empty() {
+                self.dispatch_to(wiki, event);
+            }
+        }
+    }
+
+    fn dispatch_to(&self, wiki: &str, event: &Event) {
+        let handlers = match self.inner.lock().unwrap().1.get(wiki) {
+            Some(handlers) => handlers.clone(),
+            None => return,
+        };
+        let delivery = Delivery {
+            event,
+            shared: OnceCell::new(),
+        };
+        let done: Vec<u64> = handlers
+            .iter()
+            .filter(|(_, handler)| !handler(&delivery))
+            .map(|(id, _)| *id)
+            .collect();
+        if done.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(handlers) = inner.1.get_mut(wiki) {
+            Arc::make_mut(handlers).retain(|(id, _)| !done.contains(id));
+            if handlers.is_empty() {
+                inner.1.remove(wiki);
+            }
+        }
+    }
+
+    /// Dispatch every event passing through `listeners`
+    pub fn attach(&self, listeners: &Listeners) -> ListenerHandle {
+        let manager = self.clone();
+        listeners.on_event(move |event| manager.dispatch(event))
+    }
+}
+
+impl fmt::Debug for SubscriptionManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionManager")
+            .field("wikis", &self.wikis())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn guards_remove_their_listener() {
+        let listeners = Listeners::new();
+        let calls = Arc::new(Mutex::new(0));
+        let counter = calls.clone();
+        let guard = listeners
+            .on_event(move |_: &Event| *counter.lock().unwrap() += 1)
+            .guard();
+        let event = testing::edit_event(1, "A", at(0));
+        listeners.dispatch(&event);
+        drop(guard);
+        listeners.dispatch(&event);
+        assert_eq!(*calls.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn guards_can_be_turned_back_into_handles() {
+        let listeners = Listeners::new();
+        let handle = listeners.on_event(|_: &Event| {});
+        let handles = Arc::weak_count(&listeners.inner);
+        let handle = handle.guard().into_handle();
+        assert!(handle.is_active());
+        assert_eq!(Arc::weak_count(&listeners.inner), handles);
+        drop(handle);
+        assert_eq!(Arc::weak_count(&listeners.inner), handles - 1);
+    }
+
+    #[test]
+    fn listeners_can_unsubscribe_themselves() {
+        let listeners = Listeners::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let titles = seen.clone();
+        let handle = listeners.on_event(move |event: &Event| {
+            titles.lock().unwrap().push(event.title().to_string());
+            if event.title() == "B" {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        let only_a = listeners.on_event(|event: &Event| event.title() == "A");
+        for (offset, title) in ["A", "B", "C"].iter().enumerate() {
+            let edit = testing::edit(offset as u64, title, at(0));
+            listeners.dispatch(&testing::event(&edit));
+        }
+        assert_eq!(*seen.lock().unwrap(), ["A", "B"]);
+        assert!(!handle.is_active());
+        assert!(!only_a.is_active());
+        assert!(listeners.is_empty());
+    }
+
+    #[test]
+    fn fn_mut_listeners_are_called_one_at_a_time() {
+        let listeners = Listeners::new();
+        let (done, finished) = std::sync::mpsc::channel();
+        let mut count = 0;
+        listeners.on_event(move |_: &Event| {
+            count += 1;
+            if count == 100 {
+                done.send(count).unwrap();
+            }
+        });
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let listeners = listeners.clone();
+                std::thread::spawn(move || {
+                    let event = testing::edit_event(1, "A", at(0));
+                    for _ in 0..25 {
+                        listeners.dispatch(&event);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(finished.try_recv(), Ok(100));
+    }
+
+    #[test]
+    fn weak_listeners_go_away_with_their_owner() {
+        let listeners = Listeners::new();
+        let owner = Arc::new(Mutex::new(vec![]));
+        let handle = listeners.on_edit_weak(
+            Arc::downgrade(&owner),
+            |titles: &Mutex<Vec<String>>, edit: &EditEvent| {
+                titles.lock().unwrap().push(edit.title.clone())
+            },
+        );
+        let upload = testing::event(&testing::log(1, "File:A.png", at(0)));
+        listeners.dispatch(&upload);
+        listeners.dispatch(&testing::edit_event(2, "A", at(0)));
+        assert_eq!(*owner.lock().unwrap(), ["A"]);
+        drop(owner);
+        assert!(handle.is_active());
+        listeners.dispatch(&testing::edit_event(3, "B", at(0)));
+        assert!(!handle.is_active());
+    }
+
+    #[test]
+    fn external_listeners_only_get_external_changes() {
+        let listeners = Listeners::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let changes = seen.clone();
+        listeners.on_external(move |external: &ExternalEvent| {
+            let change = format!("{} by {}", external.title, external.user);
+            changes.lock().unwrap().push(change)
+        });
+        let mut external = testing::log(2, "Ada Lovelace", at(0));
+        let fields = external.as_object_mut().unwrap();
+        fields.insert("type".to_string(), "external".into());
+        fields.insert("user".to_string(), "Q42 bot".into());
+        listeners.dispatch(&testing::edit_event(1, "A", at(0)));
+        listeners.dispatch(&testing::event(&external));
+        assert_eq!(*seen.lock().unwrap(), ["Ada Lovelace by Q42 bot"]);
+    }
+
+    #[test]
+    fn shared_listeners_get_the_same_copy() {
+        let listeners = Listeners::new();
+        let kept = Arc::new(Mutex::new(vec![]));
+        for _ in 0..2 {
+            let kept = kept.clone();
+            listeners.on_shared(move |event| kept.lock().unwrap().push(event));
+        }
+        listeners.dispatch(&testing::edit_event(1, "A", at(0)));
+        let event = Arc::new(testing::edit_event(2, "B", at(0)));
+        listeners.dispatch_shared(&event);
+        let kept = kept.lock().unwrap();
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[0].title(), "A");
+        assert!(Arc::ptr_eq(&kept[0], &kept[1]));
+        assert!(Arc::ptr_eq(&kept[2], &event));
+        assert!(Arc::ptr_eq(&kept[3], &event));
+    }
+
+    #[test]
+    fn stream_listeners_only_get_their_stream() {
+        let listeners = Listeners::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let titles = seen.clone();
+        listeners.on_stream(StreamKind::RecentChange, move |event: &Event| {
+            titles.lock().unwrap().push(event.title().to_string())
+        });
+        let mut tags = testing::edit(2, "B", at(0));
+        tags["meta"]["stream"] = "mediawiki.revision-tags-change".into();
+        listeners.dispatch(&testing::edit_event(1, "A", at(0)));
+        listeners.dispatch(&testing::event(&tags));
+        assert_eq!(*seen.lock().unwrap(), ["A"]);
+    }
+
+    #[test]
+    fn routes_events_to_their_wikis_handlers() {
+        let listeners = Listeners::new();
+        let manager = SubscriptionManager::new();
+        manager.attach(&listeners);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let by_domain = seen.clone();
+        manager.subscribe("https://en.wikipedia.org/", move |event: &Event| {
+            by_domain
+                .lock()
+                .unwrap()
+                .push(format!("domain {}", event.title()))
+        });
+        let by_database = seen.clone();
+        manager.subscribe_edits("enwiki", move |edit: &EditEvent| {
+            by_database
+                .lock()
+                .unwrap()
+                .push(format!("db {}", edit.title));
+            edit.title != "B"
+        });
+        let on_dewiki = seen.clone();
+        manager.subscribe("dewiki", move |event: &Event| {
+            on_dewiki
+                .lock()
+                .unwrap()
+                .push(format!("de {}", event.title()))
+        });
+        assert_eq!(manager.wikis(), ["dewiki", "en.wikipedia.org", "enwiki"]);
+
+        listeners.dispatch(&testing::event(&testing::log(1, "A", at(0))));
+        for (offset, title) in [(2, "B"), (3, "C")] {
+            let edit = testing::edit(offset, title, at(0));
+            listeners.dispatch(&testing::event(&edit));
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["domain A", "domain B", "db B", "domain C"]
+        );
+        assert!(!manager.is_subscribed("enwiki"));
+        assert!(manager.unsubscribe("en.wikipedia.org"));
+        assert!(!manager.unsubscribe("en.wikipedia.org"));
+        assert_eq!(manager.wikis(), ["dewiki"]);
+    }
+
+    #[test]
+    fn lifecycle_listeners_follow_pages_coming_and_going() {
+        let page = |offset, stream: &str, fields: serde_json::Value| {
+            let mut meta = testing::edit(offset, "A", at(0))["meta"].clone();
+            meta["stream"] = format!("mediawiki.{}", stream).into();
+            let mut page = serde_json::json!({
+                "$schema": "/mediawiki/page/x/1.0.0",
+                "meta": meta,
+                "database": "enwiki",
+                "page_id": 12,
+                "page_title": "A",
+                "page_namespace": 0,
+            });
+            page.as_object_mut()
+                .unwrap()
+                .extend(fields.as_object().unwrap().clone());
+            testing::event(&page)
+        };
+        let events = [
+            page(
+                1,
+                "page-create",
+                serde_json::json!({
+                    "rev_id": 1,
+                    "rev_timestamp": at(0).to_rfc3339(),
+                }),
+            ),
+            testing::edit_event(2, "A", at(0)),
+            page(
+                3,
+                "page-move",
+                serde_json::json!({
+                    "page_title": "B",
+                    "rev_id": 3,
+                    "prior_state": {
+                        "page_title": "A",
+                        "page_namespace": 0,
+                        "rev_id": 2,
+                    },
+                }),
+            ),
+            page(4, "page-delete", serde_json::json!({ "page_title": "B" })),
+            page(
+                5,
+                "page-undelete",
+                serde_json::json!({
+                    "page_title": "B",
+                    "prior_state": { "page_id": 11 },
+                }),
+            ),
+        ];
+
+        let listeners = Listeners::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let lifecycle = seen.clone();
+        listeners.on_page_lifecycle(move |change: PageLifecycle<'_>| {
+            lifecycle.lock().unwrap().push(format!(
+                "{} {} {:?}",
+                change.page_id(),
+                change.exists(),
+                change.old_title()
+            ))
+        });
+        let undeleted = seen.clone();
+        listeners.on_page_undelete(move |undelete: &PageUndeleteEvent| {
+            let prior = undelete.prior_state.as_ref().unwrap().page_id;
+            undeleted.lock().unwrap().push(format!("was {}", prior))
+        });
+        for event in &events {
+            listeners.dispatch(event);
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                "12 true None",
+                "12 true Some(\"A\")",
+                "12 false None",
+                "12 true None",
+                "was 11",
+            ]
+        );
+    }
+}
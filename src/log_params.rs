@@ -0,0 +1,210 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Typed parameters of log entries
+//!
+//! What's in [`LogEvent::log_params`] depends on the log type, and has
+//! changed between MediaWiki versions, e.g. lists that used to be
+//! comma-separated strings. [`LogEvent::params()`] parses the common
+//! ones, leaving the rest as they are.
+use crate::types::log::{list_param, string_param};
+use crate::LogEvent;
+use serde_json::Value;
+
+/// Parameters of a log entry, see [`LogEvent::params()`]
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogParams {
+    /// `block/block`, `block/reblock` and `block/unblock`
+    Block {
+        /// Requested duration, e.g. `1 week` or `infinite`
+        duration: Option<String>,
+        /// Block options, e.g. `nocreate`
+        flags: Vec<String>,
+        /// Whether the block covers the whole wiki rather than specific
+        /// pages or namespaces
+        sitewide: Option<bool>,
+    },
+    /// `move/move` and `move/move_redir`
+    Move {
+        /// Prefixed title the page was moved to
+        target: String,
+        /// Whether no redirect was left behind
+        no_redirect: bool,
+    },
+    /// `protect/protect`, `protect/modify`, `protect/unprotect` and
+    /// `protect/move_prot`
+    Protect {
+        /// Summary of the protection levels and expiries
+        description: Option<String>,
+        cascade: bool,
+    },
+    /// `rights/rights` and `rights/autopromote`
+    Rights {
+        old_groups: Vec<String>,
+        new_groups: Vec<String>,
+    },
+    /// `delete/delete`, which has no parameters
+    Delete,
+    /// `delete/restore`
+    Restore {
+        /// Number of revisions restored
+        revisions: Option<u64>,
+        /// Number of file versions restored
+        files: Option<u64>,
+    },
+    /// `patrol/patrol`
+    Patrol {
+        /// The revision that was patrolled
+        revision: Option<u64>,
+        /// The revision before it
+        previous: Option<u64>,
+        /// Whether it was patrolled automatically
+        auto: bool,
+    },
+    /// Changes to [AbuseFilter](https://www.mediawiki.org/wiki/Extension:AbuseFilter)
+    /// filters
+    AbuseFilter {
+        filter_id: Option<u64>,
+        /// Entry in the filter's history recording the change
+        history_id: Option<u64>,
+    },
+    /// Anything else, as sent
+    Other(Value),
+}
+
+/// Numbers are sometimes sent as strings
+fn number_param(value: &Value) -> Option<u64> {
+    match value {
+        Value::Number(number) => number.as_u64(),
+        Value::String(number) => number.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Flags are `true`, or `"1"` in older entries
+fn flag_param(value: &Value) -> bool {
+    match value {
+        Value::Bool(flag) => *flag,
+        Value::Number(number) => number.as_u64().is_some_and(|n| n != 0),
+        Value::String(flag) => !flag.is_empty() && flag != "0",
+        _ => false,
+    }
+}
+
+impl LogEvent {
+    /// Parse [`log_params`](LogEvent#structfield.log_params) according to
+    /// the log type and action
+    pub fn params(&self) -> LogParams {
+        let params = &self.log_params;
+        match (self.log_type.as_str(), self.log_action.as_str()) {
+            ("block", _) => LogParams::Block {
+                duration: string_param(&params["duration"]),
+                flags: list_param(&params["flags"]),
+                sitewide: params["sitewide"].as_bool(),
+            },
+            ("move", _) => match params["target"].as_str() {
+                Some(target) => LogParams::Move {
+                    target: target.to_string(),
+                    no_redirect: flag_param(&params["noredir"]),
+                },
+                None => LogParams::Other(params.clone()),
+            },
+            ("protect", _) => LogParams::Protect {
+                description: string_param(&params["description"]),
+                cascade: flag_param(&params["cascade"]),
+            },
+            ("rights", _) => LogParams::Rights {
+                old_groups: list_param(&params["oldgroups"]),
+                new_groups: list_param(&params["newgroups"]),
+            },
+            ("delete", "delete") => LogParams::Delete,
+            ("delete", "restore") => LogParams::Restore {
+                revisions: number_param(&params["count"]["revisions"]),
+                files: number_param(&params["count"]["files"]),
+            },
+            ("patrol", "patrol") => LogParams::Patrol {
+                revision: number_param(&params["curid"]),
+                previous: number_param(&params["previd"]),
+                auto: flag_param(&params["auto"]),
+            },
+            ("abusefilter", _) => LogParams::AbuseFilter {
+                filter_id: number_param(&params["newId"]),
+                history_id: number_param(&params["historyId"]),
+            },
+            _ => LogParams::Other(params.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+    use serde_json::json;
+
+    fn params(log_type: &str, action: &str, params: Value) -> LogParams {
+        let mut log = testing::log(1, "A", at(0));
+        log["log_type"] = log_type.into();
+        log["log_action"] = action.into();
+        log["log_params"] = params;
+        match testing::event(&log) {
+            Event::Log(log) => log.params(),
+            event => panic!("expected a log event, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn parses_old_and_new_formats() {
+        assert_eq!(
+            params("move", "move", json!({"target": "B", "noredir": "1"})),
+            LogParams::Move {
+                target: "B".to_string(),
+                no_redirect: true,
+            }
+        );
+        assert_eq!(
+            params(
+                "block",
+                "block",
+                json!({"duration": "1 week", "flags": "nocreate, noautoblock"})
+            ),
+            LogParams::Block {
+                duration: Some("1 week".to_string()),
+                flags: vec!["nocreate".to_string(), "noautoblock".to_string()],
+                sitewide: None,
+            }
+        );
+        assert_eq!(
+            params(
+                "patrol",
+                "patrol",
+                json!({"curid": "5", "previd": 4, "auto": 0})
+            ),
+            LogParams::Patrol {
+                revision: Some(5),
+                previous: Some(4),
+                auto: false,
+            }
+        );
+        assert_eq!(params("delete", "delete", json!([])), LogParams::Delete);
+        let other = json!({"img_sha1": "abc"});
+        assert_eq!(
+            params("upload", "upload", other.clone()),
+            LogParams::Other(other)
+        );
+    }
+}
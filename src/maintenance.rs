@@ -0,0 +1,247 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Announced maintenance windows
+//!
+//! Wikimedia announces datacenter switchovers and other maintenance ahead
+//! of time, and EventStreams is usually unavailable for part of them. A
+//! [`MaintenanceCalendar`] holds those windows, so that a client expecting
+//! an outage can reconnect [less eagerly](crate::backend::Backoff::maintenance)
+//! and keep [readiness checks](crate::readiness::ReadinessProbe::maintenance)
+//! from raising alarms while it lasts.
+//!
+//! Calendars are JSON arrays of windows, which can be shipped alongside the
+//! application and [loaded](MaintenanceCalendar::load) on startup, or
+//! [refreshed](MaintenanceCalendar::refresh) from a URL:
+//!
+//! ```json
+//! [
+//!   {
+//!     "start": "2026-03-18T14:00:00Z",
+//!     "end": "2026-03-18T15:00:00Z",
+//!     "description": "Datacenter switchover"
+//!   }
+//! ]
+//! ```
+use crate::backend::Backoff;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::backend::ClientOptions;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// How long before and after a window to treat as part of it by default
+const DEFAULT_MARGIN: Duration = Duration::from_secs(15 * 60);
+
+/// A period during which EventStreams may be unavailable
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// What the maintenance is, e.g. `Datacenter switchover`
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub description: String,
+}
+
+impl MaintenanceWindow {
+    /// Whether `time` is between the start and end of the window
+    pub fn contains(&self, time: DateTime<Utc>) -> bool {
+        self.start <= time && time < self.end
+    }
+}
+
+impl fmt::Display for MaintenanceWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to {}",
+            self.start.to_rfc3339(),
+            self.end.to_rfc3339()
+        )?;
+        if !self.description.is_empty() {
+            write!(f, " ({})", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+/// A calendar couldn't be refreshed
+#[derive(Debug)]
+pub enum RefreshError {
+    /// The request failed
+    Request(String),
+    /// The server responded with an error status
+    Status(u16),
+    /// The response wasn't a calendar
+    Invalid(serde_json::Error),
+}
+
+impl fmt::Display for RefreshError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request(err) => write!(f, "request failed: {}", err),
+            Self::Status(status) => write!(f, "HTTP {}", status),
+            Self::Invalid(err) => write!(f, "invalid calendar: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RefreshError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Invalid(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Known maintenance windows, see the [module documentation](self)
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceCalendar {
+    /// Sorted by start
+    windows: Vec<MaintenanceWindow>,
+    margin: Duration,
+    backoff: Backoff,
+}
+
+impl MaintenanceCalendar {
+    /// An empty calendar, treating 15 minutes either side of each window
+    /// as part of it, and reconnecting after 30 seconds, doubling up to 5
+    /// minutes, during them
+    pub fn new() -> Self {
+        Self {
+            windows: vec![],
+            margin: DEFAULT_MARGIN,
+            backoff: Backoff::new(
+                Duration::from_secs(30),
+                Duration::from_secs(300),
+                2.0,
+            )
+            .jitter(0.5),
+        }
+    }
+
+    /// Parse a calendar in the format shown in the
+    /// [module documentation](self)
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        Ok(Self::new().with_windows(serde_json::from_str(json)?))
+    }
+
+    /// Read a calendar from a file, e.g. one shipped with the application
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::from_json(&fs::read_to_string(path)?)?)
+    }
+
+    /// Add a window
+    pub fn window(mut self, window: MaintenanceWindow) -> Self {
+        self.windows.push(window);
+        self.windows.sort_by_key(|window| window.start);
+        self
+    }
+
+    fn with_windows(mut self, windows: Vec<MaintenanceWindow>) -> Self {
+        self.windows = windows;
+        self.windows.sort_by_key(|window| window.start);
+        self
+    }
+
+    /// Also treat `margin` before and after each window as part of it,
+    /// since maintenance rarely runs exactly to schedule (default 15
+    /// minutes)
+    pub fn margin(mut self, margin: Duration) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// Reconnect with `backoff` during windows
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Backoff to reconnect with during windows
+    pub fn maintenance_backoff(&self) -> &Backoff {
+        &self.backoff
+    }
+
+    /// All windows, earliest first
+    pub fn windows(&self) -> &[MaintenanceWindow] {
+        &self.windows
+    }
+
+    /// The window `time` falls in, counting the margin around it
+    pub fn active_at(&self, time: DateTime<Utc>) -> Option<&MaintenanceWindow> {
+        let margin = chrono::Duration::from_std(self.margin)
+            .unwrap_or(chrono::Duration::MAX);
+        self.windows.iter().find(|window| {
+            window.start - margin <= time && time < window.end + margin
+        })
+    }
+
+    /// The window in progress now, if any
+    pub fn active(&self) -> Option<&MaintenanceWindow> {
+        self.active_at(Utc::now())
+    }
+
+    /// The next window starting after `time`
+    pub fn next_after(
+        &self,
+        time: DateTime<Utc>,
+    ) -> Option<&MaintenanceWindow> {
+        self.windows.iter().find(|window| window.start > time)
+    }
+
+    /// Replace the windows with the calendar at `url`, keeping the margin
+    /// and backoff. Returns the number of windows.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub async fn refresh(
+        &mut self,
+        url: &str,
+        options: &ClientOptions,
+    ) -> Result<usize, RefreshError> {
+        let mut resp = transport::client(options)
+            .map_err(|err| RefreshError::Request(err.to_string()))?
+            .get(url)
+            .header("User-Agent", options.user_agent_or_default())
+            .send()
+            .await
+            .map_err(|err| RefreshError::Request(err.to_string()))?;
+        let status: u16 = resp.status().into();
+        if !(200..300).contains(&status) {
+            return Err(RefreshError::Status(status));
+        }
+        let body = resp
+            .body_string()
+            .await
+            .map_err(|err| RefreshError::Request(err.to_string()))?;
+        let windows =
+            serde_json::from_str(&body).map_err(RefreshError::Invalid)?;
+        *self = self.clone().with_windows(windows);
+        Ok(self.windows.len())
+    }
+}
+
+impl Default for MaintenanceCalendar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
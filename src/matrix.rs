@@ -0,0 +1,240 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Posting events to a Matrix room
+//!
+//! [`MatrixSink`] sends a short notice for each event to a room through
+//! the [client-server API](https://spec.matrix.org/latest/client-server-api/#put_matrixclientv3roomsroomidsendeventtypetxnid),
+//! as a bot account that has already joined the room. Notices are sent
+//! as `m.notice`, which other bots are expected not to respond to.
+use crate::backend::{ClientOptions, USER_AGENT};
+use crate::runtime;
+use crate::sink::{self, escape, link, Sink, SinkError};
+use crate::transport::{self, TransportError};
+use crate::Event;
+use futures::future::BoxFuture;
+use serde_json::{json, Value};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use surf::{StatusCode, Url};
+
+#[derive(Debug)]
+pub enum MatrixError {
+    /// Making the request failed
+    Http(surf::Error),
+    /// The homeserver refused the message, e.g. because the bot isn't in
+    /// the room. `errcode` is the Matrix error code, like `M_FORBIDDEN`.
+    Rejected { status: u16, errcode: String },
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "HTTP request failed: {}", err),
+            Self::Rejected { status, errcode } => {
+                write!(f, "homeserver responded {}: {}", status, errcode)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+impl From<surf::Error> for MatrixError {
+    fn from(err: surf::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+/// Posts a notice with a one-line summary of each event, linking to the
+/// diff for edits, to a Matrix room
+pub struct MatrixSink {
+    client: surf::Client,
+    url: Url,
+    access_token: String,
+    /// Transaction IDs have to be unique for the access token, so they're
+    /// prefixed with when the sink was created
+    txn_prefix: u128,
+    txn: u64,
+    retries: u32,
+}
+
+impl MatrixSink {
+    /// Post to `room_id` (e.g. `!abcdef:matrix.org`, not an alias) on
+    /// `homeserver` (e.g. `https://matrix.org`), authenticating with the
+    /// bot's `access_token`. Messages that are rate limited are retried up
+    /// to 3 times, waiting as long as the homeserver asks.
+    ///
+    /// Panics if `homeserver` isn't an HTTP(S) URL.
+    pub fn new(
+        homeserver: Url,
+        room_id: &str,
+        access_token: impl Into<String>,
+    ) -> Self {
+        let mut url = homeserver;
+        url.path_segments_mut()
+            .expect("homeserver must be an HTTP(S) URL")
+            .pop_if_empty()
+            .extend(&[
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                room_id,
+                "send",
+                "m.room.message",
+            ]);
+        Self {
+            client: transport::default_client(),
+            url,
+            access_token: access_token.into(),
+            txn_prefix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            txn: 0,
+            retries: 3,
+        }
+    }
+
+    /// Make requests through `options`' transport, with its proxy and
+    /// certificates. Fails if no client can be created with them, e.g.
+    /// because the proxy URL is invalid.
+    pub fn client_options(
+        mut self,
+        options: &ClientOptions,
+    ) -> Result<Self, TransportError> {
+        self.client = transport::client(options)?;
+        Ok(self)
+    }
+
+    /// Retry rate limited messages up to `retries` times
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Send a message with the given bodies, retrying under the same
+    /// transaction ID so the homeserver can tell repeats apart
+    async fn post(
+        &mut self,
+        text: String,
+        html: String,
+    ) -> Result<(), MatrixError> {
+        self.txn += 1;
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .unwrap()
+            .push(&format!("eventstreams.{}.{}", self.txn_prefix, self.txn));
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": text,
+            "format": "org.matrix.custom.html",
+            "formatted_body": html,
+        });
+        let mut attempt = 0;
+        loop {
+            let mut resp = self
+                .client
+                .put(url.clone())
+                .header(
+                    "Authorization",
+                    format!("Bearer {}", self.access_token),
+                )
+                .header("User-Agent", USER_AGENT)
+                .body_json(&content)?
+                .await?;
+            if resp.status().is_success() {
+                return Ok(());
+            }
+            let status = resp.status();
+            let body: Value = resp.body_json().await.unwrap_or_default();
+            if status == StatusCode::TooManyRequests && attempt < self.retries {
+                attempt += 1;
+                let wait = body["retry_after_ms"].as_u64().unwrap_or(1000);
+                runtime::sleep(Duration::from_millis(wait)).await;
+                continue;
+            }
+            return Err(MatrixError::Rejected {
+                status: status.into(),
+                errcode: body["errcode"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        }
+    }
+}
+
+impl Sink for MatrixSink {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        let summary = sink::summary(event);
+        let (text, html) = match link(event) {
+            Some(url) => (
+                format!("{} {}", summary, url),
+                format!(
+                    "<a href=\"{}\">{}</a>",
+                    escape(&url),
+                    escape(&summary)
+                ),
+            ),
+            None => (summary.clone(), escape(&summary)),
+        };
+        Box::pin(async move { Ok(self.post(text, html).await?) })
+    }
+}
+
+impl fmt::Debug for MatrixSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MatrixSink")
+            .field("url", &self.url.as_str())
+            .field("retries", &self.retries)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    #[test]
+    fn posts_notices_to_the_room() {
+        let server = testing::serve_json(vec![json!({ "event_id": "$1" })]);
+        let homeserver = format!("http://{}", server.addr()).parse().unwrap();
+        let mut sink = MatrixSink::new(homeserver, "!room:example.org", "t0k");
+        let edit = testing::edit_event(1, "A", at(0));
+        block_on(sink.send(&edit)).unwrap();
+        // Out of responses, so this one is refused
+        assert!(block_on(sink.send(&edit)).is_err());
+
+        let requests = server.requests();
+        let prefix = "/_matrix/client/v3/rooms/!room:example.org/send/\
+                      m.room.message/eventstreams.";
+        assert!(requests[0].path.starts_with(prefix));
+        assert!(requests[0].path.ends_with(".1"));
+        assert!(requests[1].path.ends_with(".2"));
+        assert_eq!(requests[0].headers["authorization"], "Bearer t0k");
+        let content: Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(content["msgtype"], "m.notice");
+        let body = content["body"].as_str().unwrap();
+        assert!(body.starts_with("en.wikipedia.org: Alice edited A http"));
+    }
+}
@@ -0,0 +1,296 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Metrics about what the crate is doing internally
+//!
+//! Components that buffer events can report how much they're holding on to
+//! via [`Metrics`]. Most of them aren't capped by default. For a strictly
+//! bounded memory footprint, turn on bounded mode with [`set_bounded()`]
+//! before creating them, or cap a single stream's buffers with
+//! [`EventStreamBuilder::bounded()`](crate::EventStreamBuilder::bounded).
+//! Each then holds at most that many items, and handles overflow in its
+//! own way:
+//!
+//! * [`TumblingWindows`](crate::window::TumblingWindows) close their oldest
+//!   windows early, as with
+//!   [`max_buffered()`](crate::window::TumblingWindows::max_buffered)
+//! * [`SessionWindows`](crate::window::SessionWindows) close the sessions
+//!   idle the longest early, as with
+//!   [`max_sessions()`](crate::window::SessionWindows::max_sessions)
+//! * an [`EditLogJoin`](crate::join::EditLogJoin) gives up on its oldest
+//!   events, as with
+//!   [`max_pending()`](crate::join::EditLogJoin::max_pending)
+//! * [`KeyedState`](crate::keyed::KeyedState) forgets the keys it has
+//!   held the longest, as with
+//!   [`max_keys()`](crate::keyed::KeyedState::max_keys)
+//! * a [`Throttle`](crate::pacing::Throttle) forgets the wikis closest to
+//!   their full limit, as with
+//!   [`max_wikis()`](crate::pacing::Throttle::max_wikis)
+//! * a [`DedupWindow`](crate::dedup::DedupWindow) remembers fewer event
+//!   IDs
+//! * a [`Queue`](crate::queue::Queue),
+//!   [`DispatchPool`](crate::pool::DispatchPool),
+//!   [`ConcurrentSink`](crate::sink::ConcurrentSink),
+//!   [`Relay`](crate::relay::Relay) and the channels of
+//!   [`into_channel()`](crate::EventStreamBuilder::into_channel) and
+//!   [`spawn()`](crate::EventStreamBuilder::spawn) hold fewer events, and
+//!   then follow their usual policy for a full buffer
+//! * a [`SideOutput`](crate::side_output::SideOutput) created with
+//!   [`new()`](crate::side_output::SideOutput::new) is
+//!   [bounded](crate::side_output::SideOutput::bounded), dropping new
+//!   events and counting them
+//! * [`EventStream::errors()`](crate::EventStream::errors) streams drop
+//!   errors that don't fit. They still reach
+//!   [`on_error()`](crate::EventStream::on_error) listeners.
+//!
+//! The cap is a ceiling: a component given a smaller cap of its own keeps
+//! it, and a larger one is lowered to the cap. It's read when a component
+//! is created, or when a stream is built, so changing it doesn't affect
+//! buffers that already exist.
+//!
+//! To also track allocations, install [`CountingAllocator`]
+//! as the global allocator:
+//!
+//! ```
+//! #[global_allocator]
+//! static ALLOC: eventstreams::metrics::CountingAllocator =
+//!     eventstreams::metrics::CountingAllocator;
+//! ```
+//!
+//! With the `metrics` feature, an [`EventStream`](crate::EventStream) can
+//! also be [instrumented](crate::EventStream::instrument), reporting to any
+//! [`MetricsSink`]. [`Metrics`] is one, exported in the Prometheus format
+//! with [`MetricsSnapshot::to_prometheus()`]; [`LogMetrics`] is another.
+use serde::Serialize;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Cap set with [`set_bounded()`], or 0 while bounded mode is off
+static BOUND: AtomicUsize = AtomicUsize::new(0);
+
+/// Turn on bounded mode, capping every buffer created from now on at
+/// `limit` items (at least 1), see [above](self) for what happens once
+/// one is full. `None` turns it off again, for buffers created afterwards.
+pub fn set_bounded(limit: Option<usize>) {
+    BOUND.store(limit.map_or(0, |limit| limit.max(1)), Ordering::Relaxed);
+}
+
+/// The cap set with [`set_bounded()`], if bounded mode is on
+pub fn bounded() -> Option<usize> {
+    match BOUND.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// `capacity`, lowered to the [bounded mode](set_bounded) cap if it's on
+pub(crate) fn cap(capacity: usize) -> usize {
+    bounded().map_or(capacity, |limit| capacity.min(limit))
+}
+
+/// The lower of `limit` and the [bounded mode](set_bounded) cap, if
+/// either is set
+pub(crate) fn cap_limit(limit: Option<usize>) -> Option<usize> {
+    match (limit, bounded()) {
+        (Some(limit), Some(bound)) => Some(limit.min(bound)),
+        (limit, bound) => limit.or(bound),
+    }
+}
+
+/// Shared set of named gauges and counters. Clones share the same values.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    inner: Arc<Mutex<MetricsSnapshot>>,
+}
+
+/// Values of all metrics at a point in time
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct MetricsSnapshot {
+    /// Values that go up and down, like buffer sizes
+    pub gauges: BTreeMap<String, u64>,
+    /// Values that only go up, like number of events dropped
+    pub counters: BTreeMap<String, u64>,
+    /// Allocations made, if [`CountingAllocator`] is installed
+    pub allocations: u64,
+    /// Bytes currently allocated, if [`CountingAllocator`] is installed
+    pub allocated_bytes: u64,
+}
+
+impl MetricsSnapshot {
+    /// Format in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut write = |name: &str, kind: &str, value: u64| {
+            let name: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            out.push_str(&format!(
+                "# TYPE eventstreams_{name} {kind}\neventstreams_{name} {value}\n"
+            ));
+        };
+        for (name, value) in &self.gauges {
+            write(name, "gauge", *value);
+        }
+        for (name, value) in &self.counters {
+            write(name, "counter", *value);
+        }
+        write("allocations", "counter", self.allocations);
+        write("allocated_bytes", "gauge", self.allocated_bytes);
+        out
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_gauge(&self, name: &str, value: u64) {
+        self.inner
+            .lock()
+            .unwrap()
+            .gauges
+            .insert(name.to_string(), value);
+    }
+
+    pub fn increment(&self, name: &str, by: u64) {
+        *self
+            .inner
+            .lock()
+            .unwrap()
+            .counters
+            .entry(name.to_string())
+            .or_default() += by;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let mut snapshot = self.inner.lock().unwrap().clone();
+        snapshot.allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        snapshot.allocated_bytes = ALLOCATED_BYTES.load(Ordering::Relaxed);
+        snapshot
+    }
+}
+
+/// Somewhere to report metrics to, see
+/// [`EventStream::instrument()`](crate::EventStream::instrument)
+#[cfg(feature = "metrics")]
+pub trait MetricsSink: Send + Sync {
+    /// Add `by` to the counter `name`
+    fn increment(&self, name: &str, by: u64);
+    /// Set the gauge `name` to `value`
+    fn set_gauge(&self, name: &str, value: u64);
+}
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for Metrics {
+    fn increment(&self, name: &str, by: u64) {
+        Metrics::increment(self, name, by);
+    }
+
+    fn set_gauge(&self, name: &str, value: u64) {
+        Metrics::set_gauge(self, name, value);
+    }
+}
+
+/// Logs every update at debug level, with the metric's name and value as
+/// key-value pairs, e.g. for structured logging backends
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogMetrics;
+
+#[cfg(feature = "metrics")]
+impl MetricsSink for LogMetrics {
+    fn increment(&self, name: &str, by: u64) {
+        log::debug!(
+            target: "eventstreams::metrics",
+            name,
+            by;
+            "counter {} += {}",
+            name,
+            by
+        );
+    }
+
+    fn set_gauge(&self, name: &str, value: u64) {
+        log::debug!(
+            target: "eventstreams::metrics",
+            name,
+            value;
+            "gauge {} = {}",
+            name,
+            value
+        );
+    }
+}
+
+/// A component's handle for reporting into [`Metrics`] under a name
+#[derive(Clone, Debug)]
+pub(crate) struct Reporter {
+    metrics: Metrics,
+    name: String,
+}
+
+impl Reporter {
+    pub(crate) fn new(metrics: Metrics, name: impl Into<String>) -> Self {
+        Self {
+            metrics,
+            name: name.into(),
+        }
+    }
+
+    pub(crate) fn buffered(&self, value: usize) {
+        self.metrics
+            .set_gauge(&format!("{}.buffered", self.name), value as u64);
+    }
+
+    #[cfg(feature = "analytics")]
+    pub(crate) fn evicted(&self, by: usize) {
+        if by > 0 {
+            self.metrics
+                .increment(&format!("{}.evicted", self.name), by as u64);
+        }
+    }
+
+    pub(crate) fn dropped(&self) {
+        self.metrics.increment(&format!("{}.dropped", self.name), 1);
+    }
+}
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Wraps the system allocator, counting allocations for [`Metrics`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+            ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED_BYTES.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+    }
+}
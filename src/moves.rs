@@ -0,0 +1,128 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Following page moves
+//!
+//! [`MoveTracker`] remembers recent page moves so titles from before a
+//! move, e.g. on a watchlist, can be resolved to where the page is now.
+use crate::title;
+use crate::{Event, LogParams};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+
+/// Maps old titles to new titles for moves within the last `ttl`
+#[derive(Clone, Debug)]
+pub struct MoveTracker {
+    ttl: Duration,
+    latest: Option<DateTime<Utc>>,
+    /// New title and time of move, keyed by wiki and old title
+    moves: HashMap<(String, String), (String, DateTime<Utc>)>,
+}
+
+impl MoveTracker {
+    /// Remember moves for `ttl` of event time
+    pub fn new(ttl: std::time::Duration) -> Self {
+        Self {
+            ttl: Duration::from_std(ttl).expect("ttl out of range"),
+            latest: None,
+            moves: HashMap::new(),
+        }
+    }
+
+    /// Record an event, returning the old and new title if it was a move
+    pub fn push(&mut self, event: &Event) -> Option<(String, String)> {
+        let dt = event.dt();
+        if self.latest.is_none_or(|latest| dt > latest) {
+            self.latest = Some(dt);
+            let oldest = dt - self.ttl;
+            self.moves.retain(|_, (_, moved)| *moved >= oldest);
+        }
+        let (log, target) = match event {
+            Event::Log(log) => match log.params() {
+                LogParams::Move { target, .. } => (log, target),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        self.moves.insert(
+            (log.wiki.to_string(), log.title.clone()),
+            (target.clone(), dt),
+        );
+        Some((log.title.clone(), target))
+    }
+
+    /// Where the page that was at `title` on `wiki` (internal database
+    /// name) is now, following chains of moves. Titles that haven't been
+    /// moved recently are returned [canonicalized](crate::title::canonicalize).
+    pub fn resolve_current_title(&self, wiki: &str, title: &str) -> String {
+        let title = title::canonicalize(wiki, title);
+        let mut current = title.clone();
+        // Bounded, in case pages were moved back and forth
+        for _ in 0..=self.moves.len() {
+            match self.moves.get(&(wiki.to_string(), current.clone())) {
+                Some((target, _)) if *target != title => {
+                    current = target.clone()
+                }
+                _ => break,
+            }
+        }
+        current
+    }
+
+    /// Number of moves being remembered
+    pub fn len(&self) -> usize {
+        self.moves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.moves.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    fn moved(offset: u64, from: &str, to: &str, seconds: i64) -> Event {
+        let mut log = testing::log(offset, from, at(seconds));
+        log["log_type"] = "move".into();
+        log["log_action"] = "move".into();
+        log["log_params"] = serde_json::json!({ "target": to, "noredir": "0" });
+        testing::event(&log)
+    }
+
+    #[test]
+    fn follows_chains_of_moves() {
+        let mut moves = MoveTracker::new(StdDuration::from_secs(3600));
+        let edit = testing::edit_event(1, "Rex", at(0));
+        assert!(moves.push(&edit).is_none());
+        assert_eq!(
+            moves.push(&moved(2, "Rex", "Rex (dog)", 10)),
+            Some(("Rex".to_string(), "Rex (dog)".to_string()))
+        );
+        moves.push(&moved(3, "Rex (dog)", "Rex (film)", 20));
+        assert_eq!(moves.resolve_current_title("enwiki", "rex"), "Rex (film)");
+        assert_eq!(moves.resolve_current_title("dewiki", "Rex"), "Rex");
+        assert_eq!(moves.len(), 2);
+
+        // The first move has expired by now
+        moves.push(&testing::edit_event(4, "A", at(3615)));
+        assert_eq!(moves.len(), 1);
+        assert_eq!(moves.resolve_current_title("enwiki", "Rex"), "Rex");
+    }
+}
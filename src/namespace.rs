@@ -0,0 +1,111 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Canonical namespace names
+//!
+//! Wikis translate namespace names into their content language and can
+//! add aliases, but every namespace also has a canonical English name that
+//! works on all of them, e.g. `User talk:Example` on any wiki. This only
+//! covers namespaces built into MediaWiki and a few from extensions that
+//! nearly every Wikimedia wiki has; IDs of other namespaces vary between
+//! wikis.
+
+/// Canonical names of namespaces, by ID
+const CANONICAL: &[(i32, &str)] = &[
+    (-2, "Media"),
+    (-1, "Special"),
+    (0, ""),
+    (1, "Talk"),
+    (2, "User"),
+    (3, "User talk"),
+    (4, "Project"),
+    (5, "Project talk"),
+    (6, "File"),
+    (7, "File talk"),
+    (8, "MediaWiki"),
+    (9, "MediaWiki talk"),
+    (10, "Template"),
+    (11, "Template talk"),
+    (12, "Help"),
+    (13, "Help talk"),
+    (14, "Category"),
+    (15, "Category talk"),
+    (710, "TimedText"),
+    (711, "TimedText talk"),
+    (828, "Module"),
+    (829, "Module talk"),
+];
+
+/// Canonical name of namespace `id`, or `None` if it isn't a well-known
+/// one. The main namespace's name is empty.
+pub fn canonical_name(id: i32) -> Option<&'static str> {
+    CANONICAL
+        .iter()
+        .find(|(other, _)| *other == id)
+        .map(|(_, name)| *name)
+}
+
+/// ID of the namespace with canonical name `name`, ignoring case and
+/// treating underscores as spaces, e.g. `user_talk`. The main namespace
+/// has no name, so it isn't found.
+pub fn id(name: &str) -> Option<i32> {
+    let name = name.trim().replace('_', " ");
+    CANONICAL
+        .iter()
+        .find(|(id, canonical)| {
+            *id != 0 && canonical.eq_ignore_ascii_case(&name)
+        })
+        .map(|(id, _)| *id)
+}
+
+/// Whether namespace `id` is a talk namespace. Talk namespaces have odd
+/// IDs, each following its subject namespace.
+pub fn is_talk(id: i32) -> bool {
+    id > 0 && id % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    #[test]
+    fn looks_up_canonical_names() {
+        assert_eq!(canonical_name(3), Some("User talk"));
+        assert_eq!(canonical_name(0), Some(""));
+        assert_eq!(canonical_name(100), None);
+        assert_eq!(id(" user_TALK"), Some(3));
+        assert_eq!(id(""), None);
+        assert!(is_talk(829));
+        assert!(!is_talk(-1) && !is_talk(0) && !is_talk(14));
+
+        let mut talk = testing::edit(1, "Benutzer Diskussion:Q&A é", at(0));
+        talk["namespace"] = 3.into();
+        let edit = match testing::event(&talk) {
+            Event::Edit(edit) => edit,
+            event => panic!("expected an edit, got {:?}", event),
+        };
+        assert_eq!(edit.page_title_without_namespace(), "Q&A é");
+        assert_eq!(edit.namespace_name(), Some("User talk"));
+        assert!(edit.is_talk_page());
+        assert_eq!(
+            edit.full_url(),
+            "https://en.wikipedia.org/w/index.php?\
+             title=Benutzer_Diskussion:Q%26A_%C3%A9"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Smoothing out bursts of events
+//!
+//! After a reconnect, or when replaying from
+//! [`since`](crate::EventStreamBuilder::since), thousands of backlogged
+//! events can arrive at once. Passing them straight on to e.g. a chat
+//! webhook is a quick way to get rate limited or banned. A [`Pacer`]
+//! spreads them out to a steady rate instead, while letting normal live
+//! traffic through without delay.
+//!
+//! A [`Throttle`] applies the same limit to events themselves, either
+//! across all wikis or separately for each, and can shed events over the
+//! limit instead of holding them back, e.g. when every event costs an API
+//! request downstream.
+//!
+//! Both measure and wait with a [`Clock`], so they can be tested with a
+//! [`ManualClock`](crate::clock::ManualClock).
+use crate::clock::{Clock, SystemClock};
+use crate::compaction::Compaction;
+use crate::drops::DropLogger;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A token bucket: up to `burst` events go through immediately, after
+/// which they are let through at `per_second`
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    interval: Duration,
+    burst: u32,
+    tokens: f64,
+    last: DateTime<Utc>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Pacer {
+    /// Allow `per_second` events a second on average, and bursts of up to
+    /// `burst` events
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        assert!(per_second > 0.0, "rate must be positive");
+        let burst = burst.max(1);
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+        Self {
+            interval: Duration::from_secs_f64(1.0 / per_second),
+            burst,
+            tokens: burst.into(),
+            last: clock.now(),
+            clock,
+        }
+    }
+
+    /// Measure and wait with `clock` rather than the system time
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.last = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = (now - self.last).to_std().unwrap_or_default();
+        self.last = now;
+        self.tokens = (self.tokens
+            + elapsed.as_secs_f64() / self.interval.as_secs_f64())
+        .min(self.burst.into());
+    }
+
+    /// Whether the bucket is full, so it acts like a new one
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.burst.into()
+    }
+
+    /// How many events could go through right now
+    fn available(&mut self) -> f64 {
+        self.refill();
+        self.tokens
+    }
+
+    /// How long the next event would have to wait
+    pub fn delay(&mut self) -> Duration {
+        self.refill();
+        if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            self.interval.mul_f64(1.0 - self.tokens)
+        }
+    }
+
+    /// Wait until the next event may go through
+    pub async fn wait(&mut self) {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            self.clock.sleep(delay).await;
+            self.refill();
+        }
+        self.tokens -= 1.0;
+    }
+
+    /// Let the next event through if it doesn't have to wait
+    pub fn try_acquire(&mut self) -> bool {
+        if self.delay().is_zero() {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// What a [`Throttle`] does with events over its limit
+#[derive(Clone, Debug)]
+pub enum ThrottlePolicy {
+    /// Hold them back until they're within the limit. While one wiki is
+    /// being held back, so is everything behind it.
+    Queue,
+    /// Drop them, recording each with the [`DropLogger`] as `throttled`
+    Drop(DropLogger),
+}
+
+/// Limits events to `per_second` on average with bursts of up to `burst`,
+/// like a [`Pacer`]
+#[derive(Clone, Debug)]
+pub struct Throttle {
+    per_second: f64,
+    burst: u32,
+    policy: ThrottlePolicy,
+    per_wiki: bool,
+    buckets: HashMap<String, Pacer>,
+    max_wikis: Option<usize>,
+    compaction: Compaction,
+    clock: Arc<dyn Clock>,
+}
+
+impl Throttle {
+    /// Limit all events together
+    pub fn new(per_second: f64, burst: u32, policy: ThrottlePolicy) -> Self {
+        assert!(per_second > 0.0, "rate must be positive");
+        Self {
+            per_second,
+            burst,
+            policy,
+            per_wiki: false,
+            buckets: HashMap::new(),
+            max_wikis: crate::metrics::bounded(),
+            compaction: Compaction::default(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Limit events to one every `interval`, without bursts
+    pub fn interval(interval: Duration, policy: ThrottlePolicy) -> Self {
+        Self::new(1.0 / interval.as_secs_f64(), 1, policy)
+    }
+
+    /// Apply the limit to each wiki (`server_name`) separately instead
+    pub fn per_wiki(mut self) -> Self {
+        self.per_wiki = true;
+        self
+    }
+
+    /// Keep limits for at most `max` wikis (at least 1) with
+    /// [`per_wiki()`](Self::per_wiki). When another wiki comes along,
+    /// wikis whose limit has fully recovered are forgotten, and if that's
+    /// not enough, the one closest to recovering is, so its next events
+    /// may go through early. No more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap, if it's on.
+    pub fn max_wikis(mut self, max: usize) -> Self {
+        self.max_wikis = Some(crate::metrics::cap(max.max(1)));
+        self
+    }
+
+    /// Measure and wait with `clock` rather than the system time
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.compaction = self.compaction.clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// How often to forget wikis whose limit has fully recovered, with
+    /// [`per_wiki()`](Self::per_wiki) (default every 10 minutes). `None`
+    /// keeps every wiki seen for as long as the throttle lives.
+    pub fn compaction_interval(mut self, interval: Option<Duration>) -> Self {
+        self.compaction = Compaction::new(interval).clock(self.clock.clone());
+        self
+    }
+
+    /// Wait until `event` may go through, or with
+    /// [`ThrottlePolicy::Drop`], return `false` if it's over the limit
+    pub async fn admit(&mut self, event: &Event) -> bool {
+        let key = if self.per_wiki {
+            event.server_name()
+        } else {
+            ""
+        };
+        if self.compaction.due() {
+            self.buckets.retain(|_, pacer| !pacer.is_full());
+            self.buckets.shrink_to_fit();
+        }
+        if !self.buckets.contains_key(key) {
+            self.make_room();
+        }
+        let (per_second, burst) = (self.per_second, self.burst);
+        let clock = &self.clock;
+        let pacer = self.buckets.entry(key.to_string()).or_insert_with(|| {
+            Pacer::new(per_second, burst).clock(clock.clone())
+        });
+        match &self.policy {
+            ThrottlePolicy::Queue => {
+                pacer.wait().await;
+                true
+            }
+            ThrottlePolicy::Drop(drop_logger) => {
+                let admitted = pacer.try_acquire();
+                if !admitted {
+                    drop_logger.record("throttled", Some(event.server_name()));
+                }
+                admitted
+            }
+        }
+    }
+
+    /// Forget wikis until there's room for another one
+    fn make_room(&mut self) {
+        let max = match self.max_wikis {
+            Some(max) => max,
+            None => return,
+        };
+        if self.buckets.len() < max {
+            return;
+        }
+        self.buckets.retain(|_, pacer| !pacer.is_full());
+        while self.buckets.len() >= max {
+            let closest = self
+                .buckets
+                .iter_mut()
+                .map(|(wiki, pacer)| (pacer.available(), wiki))
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, wiki)| wiki.clone());
+            match closest {
+                Some(wiki) => self.buckets.remove(&wiki),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+    use futures::FutureExt;
+
+    #[test]
+    fn lets_bursts_through_then_paces() {
+        let clock = ManualClock::new(at(0));
+        let mut pacer = Pacer::new(2.0, 2).clock(Arc::new(clock.clone()));
+        assert!(pacer.try_acquire());
+        assert!(pacer.try_acquire());
+        assert!(!pacer.try_acquire());
+        assert_eq!(pacer.delay(), Duration::from_millis(500));
+        assert_eq!(pacer.wait().now_or_never(), None);
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(pacer.delay(), Duration::from_millis(300));
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(pacer.wait().now_or_never(), Some(()));
+        assert!(!pacer.try_acquire());
+        // Idle time refills the bucket, but only up to the burst size
+        clock.advance(Duration::from_secs(60));
+        assert!(pacer.try_acquire());
+        assert!(pacer.try_acquire());
+        assert!(!pacer.try_acquire());
+    }
+
+    #[test]
+    fn drops_events_over_each_wikis_limit() {
+        let clock = ManualClock::new(at(0));
+        let drops = DropLogger::new(Duration::from_secs(3600));
+        let policy = ThrottlePolicy::Drop(drops.clone());
+        let mut throttle = Throttle::interval(Duration::from_secs(1), policy)
+            .per_wiki()
+            .clock(Arc::new(clock.clone()));
+        let mut german = testing::edit(3, "C", at(0));
+        german["server_name"] = "de.wikipedia.org".into();
+        let events = [
+            testing::edit_event(1, "A", at(0)),
+            testing::edit_event(2, "B", at(0)),
+            testing::event(&german),
+        ];
+        let mut admit = |event| throttle.admit(event).now_or_never().unwrap();
+        let admitted: Vec<_> = events.iter().map(&mut admit).collect();
+        assert_eq!(admitted, [true, false, true]);
+        clock.advance(Duration::from_secs(1));
+        assert!(admit(&events[1]));
+        let reports = drops.flush();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].reason, "throttled");
+        assert_eq!(reports[0].wikis["en.wikipedia.org"], 1);
+    }
+
+    #[test]
+    fn forgets_wikis_beyond_the_limit() {
+        let clock = ManualClock::new(at(0));
+        let drops = DropLogger::new(Duration::from_secs(3600));
+        let policy = ThrottlePolicy::Drop(drops);
+        let mut throttle = Throttle::new(1.0, 2, policy)
+            .per_wiki()
+            .max_wikis(2)
+            .clock(Arc::new(clock.clone()));
+        let on = |wiki: &str| {
+            let mut edit = testing::edit(1, "A", at(0));
+            edit["server_name"] = wiki.into();
+            testing::event(&edit)
+        };
+        let mut admit =
+            |wiki| throttle.admit(&on(wiki)).now_or_never().unwrap();
+        // English uses up its burst, German only half of it
+        assert!(admit("en.wikipedia.org"));
+        assert!(admit("en.wikipedia.org"));
+        assert!(!admit("en.wikipedia.org"));
+        assert!(admit("de.wikipedia.org"));
+        // French makes German, which is closest to recovering, forgotten
+        assert!(admit("fr.wikipedia.org"));
+        assert!(!admit("en.wikipedia.org"));
+        assert!(admit("de.wikipedia.org"));
+        assert!(admit("de.wikipedia.org"));
+        assert_eq!(throttle.buckets.len(), 2);
+    }
+}
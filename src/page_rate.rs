@@ -0,0 +1,154 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Edit rate alerts for specific pages
+//!
+//! A burst of edits to one page is a classic sign of breaking news or an
+//! edit war. [`PageRateMonitor`] counts edits to the pages it watches over
+//! a sliding window and raises a [`PageRateAlert`] when one of them goes
+//! over the limit.
+use crate::title;
+use crate::{EditEvent, Event};
+use chrono::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+/// A watched page being edited faster than allowed
+#[derive(Clone, Debug)]
+pub struct PageRateAlert {
+    /// Internal database name of the wiki
+    pub wiki: String,
+    pub title: String,
+    /// The edits within the window, oldest first
+    pub edits: Vec<EditEvent>,
+}
+
+/// Counts edits to watched pages
+#[derive(Clone, Debug)]
+pub struct PageRateMonitor {
+    max_edits: usize,
+    window: Duration,
+    /// Watched pages, keyed by wiki and title
+    watched: HashSet<(String, String)>,
+    recent: HashMap<(String, String), VecDeque<EditEvent>>,
+}
+
+impl PageRateMonitor {
+    /// Alert when a watched page gets more than `max_edits` edits within
+    /// `window`
+    pub fn new(max_edits: usize, window: std::time::Duration) -> Self {
+        Self {
+            max_edits,
+            window: Duration::from_std(window).expect("window out of range"),
+            watched: HashSet::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Watch `title` on `wiki`, e.g. `enwiki`. The title is
+    /// [canonicalized](crate::title::canonicalize) first.
+    pub fn watch(mut self, wiki: &str, title: &str) -> Self {
+        self.watched.insert(key(wiki, title));
+        self
+    }
+
+    /// Stop watching `title` on `wiki`
+    pub fn unwatch(&mut self, wiki: &str, title: &str) {
+        let key = key(wiki, title);
+        self.watched.remove(&key);
+        self.recent.remove(&key);
+    }
+
+    pub fn is_watched(&self, wiki: &str, title: &str) -> bool {
+        self.watched.contains(&key(wiki, title))
+    }
+
+    /// Record an event, returning an alert if it takes a watched page over
+    /// the limit. Page creations count as edits. After an alert the count
+    /// starts over, so a page keeps being reported for as long as the
+    /// burst continues.
+    pub fn push(&mut self, event: &Event) -> Option<PageRateAlert> {
+        let edit = match event {
+            Event::Edit(edit) | Event::New(edit) => edit,
+            _ => return None,
+        };
+        // Forget edits that have dropped out of the window
+        let oldest = edit.meta.dt - self.window;
+        self.recent.retain(|_, recent| {
+            while recent.front().is_some_and(|edit| edit.meta.dt < oldest) {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+        let key = (edit.wiki.to_string(), edit.title.clone());
+        if !self.watched.contains(&key) {
+            return None;
+        }
+        let recent = self.recent.entry(key).or_default();
+        recent.push_back(edit.clone());
+        if recent.len() <= self.max_edits {
+            return None;
+        }
+        Some(PageRateAlert {
+            wiki: edit.wiki.to_string(),
+            title: edit.title.clone(),
+            edits: recent.drain(..).collect(),
+        })
+    }
+}
+
+/// Key for a watched page
+fn key(wiki: &str, title: &str) -> (String, String) {
+    (wiki.to_string(), title::canonicalize(wiki, title))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, edit_event};
+    use std::time::Duration as StdDuration;
+
+    #[test]
+    fn alerts_on_bursts_of_edits() {
+        let mut monitor = PageRateMonitor::new(2, StdDuration::from_secs(60))
+            .watch("enwiki", "breaking news");
+        assert!(monitor.is_watched("enwiki", "Breaking_news"));
+        assert!(monitor
+            .push(&edit_event(1, "Breaking news", at(0)))
+            .is_none());
+        assert!(monitor
+            .push(&edit_event(2, "Breaking news", at(10)))
+            .is_none());
+        assert!(monitor.push(&edit_event(3, "Other page", at(20))).is_none());
+        // The first edit has dropped out of the window
+        assert!(monitor
+            .push(&edit_event(4, "Breaking news", at(65)))
+            .is_none());
+        let alert = monitor
+            .push(&edit_event(5, "Breaking news", at(66)))
+            .unwrap();
+        assert_eq!(alert.title, "Breaking news");
+        let offsets: Vec<_> =
+            alert.edits.iter().map(|edit| edit.meta.offset).collect();
+        assert_eq!(offsets, [2, 4, 5]);
+
+        monitor.unwatch("enwiki", "Breaking news");
+        for offset in 6..10 {
+            assert!(monitor
+                .push(&edit_event(offset, "Breaking news", at(70)))
+                .is_none());
+        }
+    }
+}
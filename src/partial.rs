@@ -0,0 +1,127 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Best-effort parsing of messages that don't fit the schema
+//!
+//! A message that fails to deserialize into an [`Event`](crate::Event),
+//! e.g. because upstream changed a field's type, is reported as
+//! [`EventStreamError::Malformed`](crate::EventStreamError::Malformed).
+//! Rather than dropping it, a pipeline can still act on whatever fields
+//! were readable with [`Listeners::on_partial()`](crate::listener::Listeners::on_partial).
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+/// The fields that could be read from a malformed message, each on its
+/// own, along with the raw message
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartialEvent {
+    /// Why the message couldn't be parsed as a whole
+    pub reason: String,
+    /// Unique ID of the event (`meta.id`)
+    pub id: Option<String>,
+    /// Time the event happened (`meta.dt`)
+    pub dt: Option<DateTime<Utc>>,
+    /// Name of the stream (`meta.stream`)
+    pub stream: Option<String>,
+    /// Domain of the wiki (`meta.domain`)
+    pub domain: Option<String>,
+    /// Internal database name of the wiki
+    pub wiki: Option<String>,
+    /// `type` of a recent change, e.g. `edit`
+    pub event_type: Option<String>,
+    /// Prefixed page title
+    pub title: Option<String>,
+    pub namespace: Option<i64>,
+    /// Name of the user who caused the event
+    pub user: Option<String>,
+    /// The whole message
+    pub raw: Value,
+}
+
+impl PartialEvent {
+    /// Read what's there from `data`, which failed to parse for `reason`.
+    /// Returns `None` if it isn't a JSON object at all.
+    pub fn parse(data: &str, reason: &str) -> Option<Self> {
+        let raw: Value = serde_json::from_str(data).ok()?;
+        if !raw.is_object() {
+            return None;
+        }
+        let string = |value: &Value| value.as_str().map(str::to_string);
+        let meta = &raw["meta"];
+        Some(Self {
+            reason: reason.to_string(),
+            id: string(&meta["id"]),
+            dt: meta["dt"]
+                .as_str()
+                .and_then(|dt| DateTime::parse_from_rfc3339(dt).ok())
+                .map(|dt| dt.with_timezone(&Utc)),
+            stream: string(&meta["stream"]),
+            domain: string(&meta["domain"]),
+            wiki: string(&raw["wiki"]).or_else(|| string(&raw["database"])),
+            event_type: string(&raw["type"]),
+            title: string(&raw["title"]).or_else(|| string(&raw["page_title"])),
+            namespace: raw["namespace"]
+                .as_i64()
+                .or_else(|| raw["page_namespace"].as_i64()),
+            user: string(&raw["user"])
+                .or_else(|| string(&raw["performer"]["user_text"])),
+            raw,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::Listeners;
+    use crate::testing::{self, at};
+    use crate::EventStreamError;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn reads_what_it_can_from_malformed_messages() {
+        let listeners = Listeners::new();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let partials = seen.clone();
+        listeners.on_partial(move |partial: &PartialEvent| {
+            partials.lock().unwrap().push(partial.clone())
+        });
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["length"] = "long".into();
+        edit["namespace"] = "main".into();
+        for data in [edit.to_string(), "[1, 2]".to_string()] {
+            listeners.dispatch_error(&EventStreamError::Malformed {
+                data,
+                reason: "invalid type".to_string(),
+            });
+        }
+        listeners.dispatch_error(&EventStreamError::Truncated {
+            data: "{\"meta\":".to_string(),
+        });
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        let partial = &seen[0];
+        assert_eq!(partial.reason, "invalid type");
+        assert_eq!(partial.id.as_deref(), Some("id-1"));
+        assert_eq!(partial.dt, Some(at(0)));
+        assert_eq!(partial.wiki.as_deref(), Some("enwiki"));
+        assert_eq!(partial.title.as_deref(), Some("A"));
+        assert_eq!(partial.namespace, None);
+        assert_eq!(partial.user.as_deref(), Some("Alice"));
+        assert_eq!(partial.raw, edit);
+    }
+}
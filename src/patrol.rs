@@ -0,0 +1,200 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Patrol backlog monitoring
+//!
+//! On wikis with patrolling enabled, every edit arrives marked as
+//! patrolled or not, and patrolling it later shows up as a `patrol` log
+//! entry. [`PatrolBacklog`] keeps track of the unpatrolled edits seen that
+//! haven't been patrolled since, and samples the size of the backlog at a
+//! regular interval so coordinators can see whether it's growing.
+use crate::{Event, LogEvent, LogParams};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{BTreeMap, HashMap};
+
+/// Unpatrolled changes on a single wiki
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Backlog {
+    /// Unpatrolled edits, including page creations
+    pub edits: u64,
+    /// Unpatrolled page creations
+    pub new_pages: u64,
+}
+
+/// The backlog of every wiki at a point in time
+#[derive(Clone, Debug)]
+pub struct BacklogSample {
+    /// Event time the sample was taken at
+    pub at: DateTime<Utc>,
+    /// Backlog keyed by internal database name of the wiki
+    pub wikis: BTreeMap<String, Backlog>,
+}
+
+#[derive(Clone, Debug)]
+struct Unpatrolled {
+    dt: DateTime<Utc>,
+    new_page: bool,
+}
+
+/// Follows unpatrolled edits until they are patrolled or expire
+#[derive(Clone, Debug)]
+pub struct PatrolBacklog {
+    interval: Duration,
+    max_age: Duration,
+    next_sample: Option<DateTime<Utc>>,
+    /// Unpatrolled revisions keyed by wiki and revision ID
+    pending: HashMap<String, HashMap<u64, Unpatrolled>>,
+}
+
+impl PatrolBacklog {
+    /// Sample the backlog every `interval` of event time. Edits are
+    /// dropped from the backlog once they are older than 30 days, when
+    /// MediaWiki stops letting them be patrolled.
+    pub fn new(interval: std::time::Duration) -> Self {
+        Self {
+            interval: Duration::from_std(interval)
+                .expect("interval out of range"),
+            max_age: Duration::days(30),
+            next_sample: None,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Drop edits from the backlog once they are older than `max_age`
+    pub fn max_age(mut self, max_age: std::time::Duration) -> Self {
+        self.max_age =
+            Duration::from_std(max_age).expect("max age out of range");
+        self
+    }
+
+    /// Add an event, returning a sample if one is due
+    pub fn push(&mut self, event: &Event) -> Option<BacklogSample> {
+        match event {
+            Event::Edit(edit) | Event::New(edit)
+                if edit.patrolled == Some(false) =>
+            {
+                self.pending
+                    .entry(edit.wiki.to_string())
+                    .or_default()
+                    .insert(
+                        edit.revision.new.into(),
+                        Unpatrolled {
+                            dt: edit.meta.dt,
+                            new_page: edit.is_page_creation(),
+                        },
+                    );
+            }
+            Event::Log(log) if log.log_type == "patrol" => {
+                if let Some(revision) = patrolled_revision(log) {
+                    if let Some(pending) =
+                        self.pending.get_mut(log.wiki.as_str())
+                    {
+                        pending.remove(&revision);
+                    }
+                }
+            }
+            _ => {}
+        }
+        let dt = event.dt();
+        let next_sample = *self.next_sample.get_or_insert(dt);
+        if dt < next_sample {
+            return None;
+        }
+        self.next_sample = Some(dt + self.interval);
+        Some(self.sample(dt))
+    }
+
+    /// The current backlog, expiring edits older than `max_age` as of `at`
+    pub fn sample(&mut self, at: DateTime<Utc>) -> BacklogSample {
+        let oldest = at - self.max_age;
+        let mut wikis = BTreeMap::new();
+        for (wiki, pending) in &mut self.pending {
+            pending.retain(|_, unpatrolled| unpatrolled.dt >= oldest);
+            let backlog = Backlog {
+                edits: pending.len() as u64,
+                new_pages: pending
+                    .values()
+                    .filter(|unpatrolled| unpatrolled.new_page)
+                    .count() as u64,
+            };
+            wikis.insert(wiki.clone(), backlog);
+        }
+        self.pending.retain(|_, pending| !pending.is_empty());
+        BacklogSample { at, wikis }
+    }
+}
+
+/// Revision ID from a `patrol` log entry's `curid` parameter
+fn patrolled_revision(log: &LogEvent) -> Option<u64> {
+    match log.params() {
+        LogParams::Patrol { revision, .. } => revision,
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    fn unpatrolled(offset: u64, seconds: i64, new_page: bool) -> Event {
+        let mut edit = testing::edit(offset, "A", at(seconds));
+        if new_page {
+            edit["type"] = "new".into();
+            edit["revision"]["old"] = serde_json::Value::Null;
+        }
+        testing::event(&edit)
+    }
+
+    fn patrol(revision: u64, seconds: i64) -> Event {
+        let mut log = testing::log(100, "A", at(seconds));
+        log["log_type"] = "patrol".into();
+        log["log_action"] = "patrol".into();
+        log["log_params"] =
+            serde_json::json!({ "curid": revision, "previd": 0, "auto": 0 });
+        testing::event(&log)
+    }
+
+    #[test]
+    fn samples_the_backlog() {
+        let mut backlog = PatrolBacklog::new(StdDuration::from_secs(60))
+            .max_age(StdDuration::from_secs(3600));
+        let sample = backlog.push(&unpatrolled(1, 0, false)).unwrap();
+        assert_eq!(
+            sample.wikis["enwiki"],
+            Backlog {
+                edits: 1,
+                new_pages: 0
+            }
+        );
+        assert!(backlog.push(&unpatrolled(2, 10, true)).is_none());
+        assert!(backlog.push(&unpatrolled(3, 20, false)).is_none());
+        assert!(backlog.push(&patrol(2, 30)).is_none());
+        let sample = backlog.push(&patrol(99, 60)).unwrap();
+        assert_eq!(
+            sample.wikis["enwiki"],
+            Backlog {
+                edits: 2,
+                new_pages: 1
+            }
+        );
+        // Everything has expired by now
+        let sample = backlog.sample(at(7200));
+        assert_eq!(sample.wikis["enwiki"], Backlog::default());
+        assert!(backlog.sample(at(7200)).wikis.is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Reading events from stdin or any other byte stream
+//!
+//! Sometimes the process consuming events isn't allowed to open network
+//! connections itself, or the connection is better handled by another
+//! tool, e.g.
+//!
+//! ```text
+//! curl -s https://stream.wikimedia.org/v2/stream/recentchange | my-tool
+//! ssh relay-host cat /var/log/events.ndjson | my-tool
+//! ```
+//!
+//! [`stdin()`] and [`reader()`] turn such input into a
+//! [backend](crate::backend), as server-sent events the way EventStreams
+//! sends them or as one JSON message per line, see [`Framing`]. Pass it
+//! to [`EventStream::from_backend()`](crate::EventStream::from_backend)
+//! to get events:
+//!
+//! ```no_run
+//! use eventstreams::pipe::{self, Framing};
+//! use eventstreams::{EventStream, StreamExt};
+//!
+//! # async fn run() {
+//! let mut stream = EventStream::from_backend(pipe::stdin(Framing::Auto), None);
+//! while let Some(event) = stream.next().await {
+//!     println!("{}", event.title());
+//! }
+//! # }
+//! ```
+//!
+//! The stream ends when the input does. There's no reconnecting, and a
+//! message cut off at the end of the input is discarded.
+use crate::backend::BackendError;
+use async_stream::stream;
+use futures::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use futures::{Stream, StreamExt};
+use std::io;
+
+/// How messages are laid out in the input
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// [Server-sent events](https://html.spec.whatwg.org/multipage/server-sent-events.html),
+    /// as EventStreams sends them. Only `data:` fields are used.
+    Sse,
+    /// One JSON message per line, as written by a
+    /// [`Recorder`](crate::recorder::Recorder)
+    Ndjson,
+    /// Server-sent events if the first line that isn't blank is a field or
+    /// a comment, e.g. `data: {…}` or `:ok`, otherwise one message per
+    /// line
+    #[default]
+    Auto,
+}
+
+/// Messages read from `reader`
+pub fn reader(
+    reader: impl AsyncRead + Unpin,
+    framing: Framing,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    frame(BufReader::new(reader).lines(), framing)
+}
+
+/// Messages read from standard input, on a background thread so reading
+/// doesn't block the executor
+#[cfg(not(target_arch = "wasm32"))]
+pub fn stdin(
+    framing: Framing,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    use futures::SinkExt as _;
+    use std::io::BufRead;
+
+    // Bounded, so a slow consumer makes the pipe apply backpressure
+    let (mut sender, receiver) = futures::channel::mpsc::channel(64);
+    std::thread::Builder::new()
+        .name("eventstreams-stdin".into())
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let failed = line.is_err();
+                let sent = futures::executor::block_on(sender.send(line));
+                if failed || sent.is_err() {
+                    break;
+                }
+            }
+        })
+        .expect("failed to spawn stdin reader");
+    frame(receiver, framing)
+}
+
+/// Group `lines` into messages
+fn frame(
+    lines: impl Stream<Item = io::Result<String>>,
+    framing: Framing,
+) -> impl Stream<Item = Result<String, BackendError>> {
+    stream! {
+        let mut framing = framing;
+        // `data:` fields of the message being read, for server-sent events
+        let mut data: Option<String> = None;
+        futures::pin_mut!(lines);
+        while let Some(line) = lines.next().await {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    yield Err(BackendError::Io(err.to_string()));
+                    return;
+                }
+            };
+            let line = line.strip_suffix('\r').unwrap_or(&line);
+            if framing == Framing::Auto && !line.trim().is_empty() {
+                framing = if is_sse(line) {
+                    Framing::Sse
+                } else {
+                    Framing::Ndjson
+                };
+            }
+            match framing {
+                Framing::Ndjson | Framing::Auto => {
+                    if !line.trim().is_empty() {
+                        yield Ok(line.to_string());
+                    }
+                }
+                Framing::Sse => {
+                    if line.is_empty() {
+                        if let Some(message) = data.take() {
+                            yield Ok(message);
+                        }
+                        continue;
+                    }
+                    let (field, value) = match line.split_once(':') {
+                        Some((field, value)) => {
+                            (field, value.strip_prefix(' ').unwrap_or(value))
+                        }
+                        None => (line, ""),
+                    };
+                    if field == "data" {
+                        match &mut data {
+                            Some(data) => {
+                                data.push('\n');
+                                data.push_str(value);
+                            }
+                            None => data = Some(value.to_string()),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether `line` is a server-sent events field or comment
+fn is_sse(line: &str) -> bool {
+    line.starts_with(':')
+        || ["data", "event", "id", "retry"]
+            .iter()
+            .any(|field| line.starts_with(&format!("{}:", field)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    fn read(input: &str, framing: Framing) -> Vec<String> {
+        block_on(reader(input.as_bytes(), framing).collect::<Vec<_>>())
+            .into_iter()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn frames_server_sent_events() {
+        let input =
+            ":ok\r\n\r\nevent: message\nid: [{}]\ndata: {\"a\":\ndata: 1}\n\n\
+                     data: {\"b\": 2}\n\ndata: {\"cut\": ";
+        let expected = ["{\"a\":\n1}", "{\"b\": 2}"];
+        assert_eq!(read(input, Framing::Sse), expected);
+        assert_eq!(read(input, Framing::Auto), expected);
+    }
+
+    #[test]
+    fn frames_one_message_per_line() {
+        let input = "\n{\"a\": 1}\r\n\n{\"b\": 2}\n{\"c\": 3}";
+        let expected = ["{\"a\": 1}", "{\"b\": 2}", "{\"c\": 3}"];
+        assert_eq!(read(input, Framing::Ndjson), expected);
+        assert_eq!(read(input, Framing::Auto), expected);
+    }
+}
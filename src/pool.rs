@@ -0,0 +1,274 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Dispatching events to listeners on a pool of threads
+//!
+//! Listeners are normally called inline as each event is read, so a slow
+//! one, e.g. one making an API call per event, holds up everything else.
+//! A [`DispatchPool`] has [`Listeners`] of its own that are called from
+//! worker threads instead. It's [attached](DispatchPool::attach) to a
+//! stream's listeners to receive its events:
+//!
+//! ```no_run
+//! use eventstreams::pool::{DispatchOrder, DispatchPool};
+//!
+//! let stream = eventstreams::EventStreamBuilder::new().build().unwrap();
+//! let pool = DispatchPool::new(8, DispatchOrder::PerPage);
+//! pool.listeners().on_event_sync(|event| {
+//!     // Look something up about the event
+//! });
+//! pool.attach(&stream.listeners());
+//! ```
+//!
+//! Only listeners registered with
+//! [`on_event_sync()`](Listeners::on_event_sync) run on several threads at
+//! once; the others are wrapped in a mutex, so each of them is still
+//! called for one event at a time.
+//!
+//! With [`DispatchOrder::PerPage`], events about the same page are always
+//! handled by the same thread, in the order they were read. Each thread
+//! holds a bounded queue of events, and dispatching blocks while the
+//! chosen thread's queue is full.
+use crate::listener::{ListenerHandle, Listeners};
+use crate::Event;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// Default number of events queued per thread
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Which thread of a [`DispatchPool`] handles an event
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DispatchOrder {
+    /// Spread events over the threads in turn, with no ordering between
+    /// them
+    Unordered,
+    /// Send all events about the same page (`wiki` and title) to the same
+    /// thread, so they're handled in order
+    PerPage,
+}
+
+struct Inner {
+    /// `None` once the pool has been shut down
+    senders: Mutex<Option<Vec<SyncSender<Arc<Event>>>>>,
+    order: DispatchOrder,
+    next: AtomicUsize,
+    pending: AtomicUsize,
+}
+
+/// Calls its listeners from a pool of threads, see the
+/// [module documentation](self)
+pub struct DispatchPool {
+    inner: Arc<Inner>,
+    listeners: Listeners,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl DispatchPool {
+    /// Start `threads` threads (at least 1), each queueing up to 1024
+    /// events
+    pub fn new(threads: usize, order: DispatchOrder) -> Self {
+        Self::with_capacity(threads, order, DEFAULT_CAPACITY)
+    }
+
+    /// Like [`new()`](Self::new), queueing up to `capacity` events per
+    /// thread, or fewer in [bounded mode](crate::metrics::set_bounded)
+    pub fn with_capacity(
+        threads: usize,
+        order: DispatchOrder,
+        capacity: usize,
+    ) -> Self {
+        let capacity = crate::metrics::cap(capacity);
+        let listeners = Listeners::new();
+        let inner = Arc::new(Inner {
+            senders: Mutex::new(None),
+            order,
+            next: AtomicUsize::new(0),
+            pending: AtomicUsize::new(0),
+        });
+        let mut senders = vec![];
+        let threads = (0..threads.max(1))
+            .map(|index| {
+                let (sender, receiver) = mpsc::sync_channel(capacity);
+                senders.push(sender);
+                let listeners = listeners.clone();
+                let inner = inner.clone();
+                thread::Builder::new()
+                    .name(format!("eventstreams-dispatch-{}", index))
+                    .spawn(move || {
+                        for event in receiver {
+                            listeners.dispatch_shared(&event);
+                            inner.pending.fetch_sub(1, Ordering::Relaxed);
+                        }
+                    })
+                    .expect("failed to spawn dispatch thread")
+            })
+            .collect();
+        *inner.senders.lock().unwrap() = Some(senders);
+        Self {
+            inner,
+            listeners,
+            threads,
+        }
+    }
+
+    /// Listeners called from the pool's threads
+    pub fn listeners(&self) -> Listeners {
+        self.listeners.clone()
+    }
+
+    /// Number of events queued or being handled
+    pub fn pending(&self) -> usize {
+        self.inner.pending.load(Ordering::Relaxed)
+    }
+
+    /// Queue `event` for the pool's listeners, waiting if the chosen
+    /// thread's queue is full. Returns false if the pool was shut down.
+    pub fn dispatch(&self, event: &Event) -> bool {
+        dispatch(&self.inner, event)
+    }
+
+    /// Dispatch every event passing through `listeners`, until the pool
+    /// is shut down
+    pub fn attach(&self, listeners: &Listeners) -> ListenerHandle {
+        let inner = self.inner.clone();
+        listeners.on_event(move |event| dispatch(&inner, event))
+    }
+
+    /// Stop accepting events and wait for the ones already queued to be
+    /// handled
+    pub fn shutdown(mut self) {
+        self.inner.senders.lock().unwrap().take();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn dispatch(inner: &Inner, event: &Event) -> bool {
+    let sender = {
+        let senders = inner.senders.lock().unwrap();
+        let senders = match senders.as_ref() {
+            Some(senders) => senders,
+            None => return false,
+        };
+        let index = match inner.order {
+            DispatchOrder::Unordered => {
+                inner.next.fetch_add(1, Ordering::Relaxed)
+            }
+            DispatchOrder::PerPage => {
+                let key = [event.wiki().as_bytes(), event.title().as_bytes()]
+                    .join(&0);
+                fnv1a(&key) as usize
+            }
+        };
+        // Sent outside the lock, since it may block
+        senders[index % senders.len()].clone()
+    };
+    inner.pending.fetch_add(1, Ordering::Relaxed);
+    if sender.send(Arc::new(event.clone())).is_err() {
+        inner.pending.fetch_sub(1, Ordering::Relaxed);
+        return false;
+    }
+    true
+}
+
+impl Drop for DispatchPool {
+    fn drop(&mut self) {
+        // The threads finish the events already queued in the background
+        self.inner.senders.lock().unwrap().take();
+    }
+}
+
+impl fmt::Debug for DispatchPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DispatchPool")
+            .field("threads", &self.threads.len())
+            .field("order", &self.inner.order)
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+/// FNV-1a, which unlike std's hasher is stable across processes and Rust
+/// versions
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::sync::Barrier;
+
+    #[test]
+    fn keeps_each_pages_events_in_order() {
+        let pool = DispatchPool::with_capacity(4, DispatchOrder::PerPage, 1);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let pages = seen.clone();
+        pool.listeners().on_event(move |event: &Event| {
+            pages
+                .lock()
+                .unwrap()
+                .push((event.title().to_string(), event.meta().offset))
+        });
+        let listeners = Listeners::new();
+        let attached = pool.attach(&listeners);
+        for offset in 0..40 {
+            let title = ["A", "B", "C", "D"][offset as usize % 4];
+            let edit = testing::edit(offset, title, at(0));
+            listeners.dispatch(&testing::event(&edit));
+        }
+        pool.shutdown();
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 40);
+        for title in ["A", "B", "C", "D"] {
+            let offsets: Vec<_> = seen
+                .iter()
+                .filter(|(page, _)| page == title)
+                .map(|(_, offset)| *offset)
+                .collect();
+            assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+        // The pool is gone, so the listener unsubscribes
+        listeners.dispatch(&testing::edit_event(40, "A", at(0)));
+        assert!(!attached.is_active());
+    }
+
+    #[test]
+    fn runs_sync_listeners_concurrently() {
+        let pool = DispatchPool::new(2, DispatchOrder::Unordered);
+        // Only passes once both threads are inside the listener together
+        let barrier = Arc::new(Barrier::new(2));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        pool.listeners().on_event_sync(move |_: &Event| {
+            barrier.wait();
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+        let edit = testing::edit_event(1, "A", at(0));
+        assert!(pool.dispatch(&edit));
+        assert!(pool.dispatch(&edit));
+        pool.shutdown();
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+    }
+}
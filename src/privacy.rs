@@ -0,0 +1,133 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Differential privacy for published aggregates
+//!
+//! Counts of activity can give away what individual editors did, e.g. a
+//! small wiki's hourly edit count going from 0 to 1. Adding [`Noise`]
+//! drawn from the Laplace distribution before publishing makes the
+//! output
+//! [ε-differentially private](https://en.wikipedia.org/wiki/Differential_privacy)
+//! with respect to a single contribution. Smaller ε means more noise and
+//! more privacy; each published count spends ε of the privacy budget.
+//!
+//! Only the counts are protected. Which wikis or languages appear at all
+//! is still exact, so callers publishing a fixed set of keys should fill
+//! in zero counts for the rest before adding noise.
+use std::fmt;
+
+/// Laplace noise for counts
+#[derive(Clone)]
+pub struct Noise {
+    epsilon: f64,
+    sensitivity: f64,
+    rng: fastrand::Rng,
+}
+
+impl Noise {
+    /// Noise for a privacy budget of `epsilon` per count, where one
+    /// contribution changes a count by at most 1
+    pub fn laplace(epsilon: f64) -> Self {
+        assert!(epsilon > 0.0, "epsilon must be positive");
+        Self {
+            epsilon,
+            sensitivity: 1.0,
+            rng: fastrand::Rng::new(),
+        }
+    }
+
+    /// Protect contributions that can change a count by up to
+    /// `sensitivity`, e.g. a user's edits when each user is capped at 10
+    /// per period
+    pub fn sensitivity(mut self, sensitivity: f64) -> Self {
+        assert!(sensitivity > 0.0, "sensitivity must be positive");
+        self.sensitivity = sensitivity;
+        self
+    }
+
+    /// Draw noise from a generator seeded with `seed`, for reproducible
+    /// output in tests. Not for publishing, since anyone with the seed can
+    /// take the noise back out.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = fastrand::Rng::with_seed(seed);
+        self
+    }
+
+    pub fn epsilon(&self) -> f64 {
+        self.epsilon
+    }
+
+    /// A sample from the Laplace distribution centered on 0
+    pub fn sample(&mut self) -> f64 {
+        let scale = self.sensitivity / self.epsilon;
+        loop {
+            let u = self.rng.f64() - 0.5;
+            let tail = 1.0 - 2.0 * u.abs();
+            // ln(0) would be infinite
+            if tail > 0.0 {
+                return -scale * u.signum() * tail.ln();
+            }
+        }
+    }
+
+    /// `count` plus noise, rounded to the nearest whole number. Negative
+    /// results are clamped to 0, which doesn't weaken the guarantee.
+    pub fn count(&mut self, count: u64) -> u64 {
+        (count as f64 + self.sample()).round().max(0.0) as u64
+    }
+}
+
+impl fmt::Debug for Noise {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Noise")
+            .field("epsilon", &self.epsilon)
+            .field("sensitivity", &self.sensitivity)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scales_noise_by_sensitivity_over_epsilon() {
+        let samples = |mut noise: Noise| -> Vec<f64> {
+            (0..10_000).map(|_| noise.sample()).collect()
+        };
+        let first = samples(Noise::laplace(0.5).seed(1));
+        assert_eq!(first, samples(Noise::laplace(0.5).seed(1)));
+        // The mean absolute deviation of Laplace(0, b) is b
+        let spread = |samples: &[f64]| {
+            samples.iter().map(|sample| sample.abs()).sum::<f64>()
+                / samples.len() as f64
+        };
+        assert!((spread(&first) - 2.0).abs() < 0.1);
+        let wide = samples(Noise::laplace(0.5).sensitivity(10.0).seed(1));
+        assert!((spread(&wide) - 20.0).abs() < 1.0);
+
+        let mut noise = Noise::laplace(0.01).seed(2);
+        let counts: Vec<u64> = (0..100).map(|_| noise.count(0)).collect();
+        assert!(counts.contains(&0));
+        assert!(counts.iter().any(|count| *count > 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "epsilon must be positive")]
+    fn rejects_zero_epsilon() {
+        Noise::laplace(0.0);
+    }
+}
@@ -0,0 +1,177 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Wikimedia projects, as told apart by domain
+use serde::{Deserialize, Serialize};
+
+/// Family of wikis an event is from, see [`Event::project()`](crate::Event::project)
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Project {
+    Wikipedia,
+    Wiktionary,
+    Wikibooks,
+    Wikinews,
+    Wikiquote,
+    Wikisource,
+    Wikiversity,
+    Wikivoyage,
+    /// `www.wikidata.org` and `test.wikidata.org`
+    Wikidata,
+    /// `commons.wikimedia.org`
+    Commons,
+    /// `meta.wikimedia.org`
+    Meta,
+    /// `species.wikimedia.org`
+    Species,
+    /// `www.mediawiki.org`
+    MediaWiki,
+    /// `www.wikifunctions.org`
+    Wikifunctions,
+    /// Any other wiki, e.g. a chapter's wiki or a wiki outside of
+    /// Wikimedia
+    Other,
+}
+
+/// Projects whose subdomains are language codes
+const LANGUAGE_PROJECTS: &[(&str, Project)] = &[
+    ("wikipedia.org", Project::Wikipedia),
+    ("wiktionary.org", Project::Wiktionary),
+    ("wikibooks.org", Project::Wikibooks),
+    ("wikinews.org", Project::Wikinews),
+    ("wikiquote.org", Project::Wikiquote),
+    ("wikisource.org", Project::Wikisource),
+    ("wikiversity.org", Project::Wikiversity),
+    ("wikivoyage.org", Project::Wikivoyage),
+];
+
+/// Domains of wikis run by the Wikimedia Foundation in production
+const PRODUCTION_DOMAINS: &[&str] = &[
+    "wikipedia.org",
+    "wiktionary.org",
+    "wikibooks.org",
+    "wikinews.org",
+    "wikiquote.org",
+    "wikisource.org",
+    "wikiversity.org",
+    "wikivoyage.org",
+    "wikidata.org",
+    "wikimedia.org",
+    "mediawiki.org",
+    "wikifunctions.org",
+];
+
+impl Project {
+    /// Project of the wiki at `server_name`, e.g. `en.wikipedia.org`
+    pub fn from_server_name(server_name: &str) -> Self {
+        if let Some((_, project)) = LANGUAGE_PROJECTS
+            .iter()
+            .find(|(domain, _)| is_subdomain(server_name, domain))
+        {
+            return *project;
+        }
+        match server_name {
+            "www.wikidata.org" | "test.wikidata.org" => Project::Wikidata,
+            "commons.wikimedia.org" => Project::Commons,
+            "meta.wikimedia.org" => Project::Meta,
+            "species.wikimedia.org" => Project::Species,
+            "www.mediawiki.org" => Project::MediaWiki,
+            "www.wikifunctions.org" => Project::Wikifunctions,
+            _ => Project::Other,
+        }
+    }
+}
+
+/// Whether `server_name` is `domain` itself or a direct subdomain of it
+fn is_subdomain(server_name: &str, domain: &str) -> bool {
+    server_name
+        .strip_suffix(domain)
+        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+}
+
+/// A wiki given by domain, server URL or internal database name, with
+/// any URL reduced to its domain, e.g. `https://en.wikipedia.org/` to
+/// `en.wikipedia.org`
+pub(crate) fn wiki_id(wiki: &str) -> &str {
+    let wiki = wiki.trim();
+    let wiki = ["https://", "http://", "//"]
+        .iter()
+        .find_map(|scheme| wiki.strip_prefix(scheme))
+        .unwrap_or(wiki);
+    wiki.trim_end_matches('/')
+}
+
+/// Language code from a wiki's domain, for projects with language editions
+pub(crate) fn language(server_name: &str) -> Option<&str> {
+    let (subdomain, project) = server_name.split_once('.')?;
+    if subdomain == "www"
+        || !LANGUAGE_PROJECTS
+            .iter()
+            .any(|(domain, _)| *domain == project)
+    {
+        return None;
+    }
+    Some(subdomain)
+}
+
+/// Whether `server_name` is a production Wikimedia wiki, rather than e.g.
+/// the beta cluster or a third-party wiki
+pub(crate) fn is_wikimedia_production(server_name: &str) -> bool {
+    PRODUCTION_DOMAINS
+        .iter()
+        .any(|domain| is_subdomain(server_name, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn tells_projects_apart_by_domain() {
+        let project = Project::from_server_name;
+        assert_eq!(project("de.wiktionary.org"), Project::Wiktionary);
+        assert_eq!(project("www.wikidata.org"), Project::Wikidata);
+        assert_eq!(project("commons.wikimedia.org"), Project::Commons);
+        assert_eq!(project("wikipedia.org.example.com"), Project::Other);
+        assert_eq!(project("notwikipedia.org"), Project::Other);
+        assert_eq!(language("pt.wikivoyage.org"), Some("pt"));
+        assert_eq!(language("www.wikidata.org"), None);
+        assert!(is_wikimedia_production("meta.wikimedia.org"));
+        assert!(!is_wikimedia_production("en.wikipedia.beta.wmflabs.org"));
+        assert_eq!(wiki_id("https://en.wikipedia.org/"), "en.wikipedia.org");
+
+        let mut beta = testing::edit(1, "A", at(0));
+        beta["server_name"] = "en.wikipedia.beta.wmflabs.org".into();
+        let beta = testing::event(&beta);
+        assert_eq!(beta.project(), Project::Other);
+        assert!(!beta.is_wikimedia_production());
+        let edit = testing::edit_event(2, "A", at(0));
+        assert_eq!(edit.project(), Project::Wikipedia);
+        assert!(edit.is_wikimedia_production());
+    }
+}
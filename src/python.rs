@@ -0,0 +1,498 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Python module, for the many bots and tools written in Python
+//!
+//! The module is called `eventstreams` and has three classes: `Event`,
+//! `Filter` and `EventStream`. A stream reads on a background thread, and
+//! can be iterated over either way:
+//!
+//! ```python
+//! import eventstreams
+//!
+//! wanted = eventstreams.Filter().wiki("enwiki").exclude_bots()
+//! stream = eventstreams.EventStream(["recentchange"], filter=wanted,
+//!                                   user_agent="MyBot/1.0 (me@example.org)")
+//! for event in stream:
+//!     print(event.kind, event.title, event.user)
+//!
+//! async def watch():
+//!     async for event in stream:
+//!         print(event.to_dict())
+//! ```
+//!
+//! Build the module with [maturin](https://www.maturin.rs/), which reads
+//! `pyproject.toml`:
+//!
+//! ```text
+//! maturin develop --release
+//! ```
+use crate::filter::Filter;
+use crate::worker::StreamWorker;
+use crate::{Event, EventStreamBuilder, Overflow};
+use pyo3::exceptions::{
+    PyRuntimeError, PyStopAsyncIteration, PyStopIteration, PyValueError,
+};
+use pyo3::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long to wait for an event before checking for Ctrl+C
+const SIGNAL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long [`PyEventStream::close()`] waits for the thread to finish
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An event, with accessors for the fields most events share
+#[pyclass(name = "Event", module = "eventstreams", frozen)]
+pub struct PyEvent(Event);
+
+impl PyEvent {
+    /// Name of the variant, e.g. `edit` or `page_delete`
+    fn kind_name(&self) -> &'static str {
+        match &self.0 {
+            Event::Edit(_) => "edit",
+            Event::New(_) => "new",
+            Event::Log(_) => "log",
+            Event::Categorize(_) => "categorize",
+            Event::External(_) => "external",
+            Event::RevisionCreate(_) => "revision_create",
+            Event::PageCreate(_) => "page_create",
+            Event::PageDelete(_) => "page_delete",
+            Event::PageMove(_) => "page_move",
+            Event::PageUndelete(_) => "page_undelete",
+            Event::PageLinksChange(_) => "page_links_change",
+            Event::RevisionScore(_) => "revision_score",
+            Event::Test(_) => "test",
+            Event::Extension(_) => "extension",
+            Event::Unknown(_) => "unknown",
+        }
+    }
+}
+
+#[pymethods]
+impl PyEvent {
+    /// Unique ID of the event
+    #[getter]
+    fn id(&self) -> &str {
+        &self.0.meta().id
+    }
+
+    /// Name of the stream, e.g. `mediawiki.recentchange`
+    #[getter]
+    fn stream(&self) -> &str {
+        &self.0.meta().stream
+    }
+
+    /// What kind of event this is, e.g. `edit`, `log` or `page_delete`
+    #[getter]
+    fn kind(&self) -> &'static str {
+        self.kind_name()
+    }
+
+    #[getter]
+    fn title(&self) -> &str {
+        self.0.title()
+    }
+
+    #[getter]
+    fn comment(&self) -> &str {
+        self.0.comment()
+    }
+
+    #[getter]
+    fn user(&self) -> &str {
+        self.0.user()
+    }
+
+    /// Database name of the wiki, e.g. `enwiki`
+    #[getter]
+    fn wiki(&self) -> &str {
+        self.0.wiki()
+    }
+
+    /// Domain of the wiki, e.g. `en.wikipedia.org`
+    #[getter]
+    fn server_name(&self) -> &str {
+        self.0.server_name()
+    }
+
+    #[getter]
+    fn namespace(&self) -> Option<i32> {
+        self.0.namespace()
+    }
+
+    #[getter]
+    fn is_bot(&self) -> bool {
+        self.0.is_bot()
+    }
+
+    #[getter]
+    fn is_canary(&self) -> bool {
+        self.0.is_canary()
+    }
+
+    /// Time the event happened, in RFC 3339
+    #[getter]
+    fn dt(&self) -> String {
+        self.0.dt().to_rfc3339()
+    }
+
+    /// The event as it was received, as JSON
+    fn to_json(&self) -> PyResult<String> {
+        serde_json::to_string(&self.0)
+            .map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    /// The event as it was received, as a `dict`
+    fn to_dict<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        py.import("json")?.call_method1("loads", (self.to_json()?,))
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "<Event {} {} {:?}>",
+            self.kind_name(),
+            self.0.wiki(),
+            self.0.title()
+        )
+    }
+}
+
+/// A [`Filter`], built up by chaining methods that each return a new one
+#[pyclass(name = "Filter", module = "eventstreams", frozen)]
+pub struct PyFilter(Filter);
+
+fn regex_error(err: regex::Error) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+#[pymethods]
+impl PyFilter {
+    #[new]
+    fn new() -> Self {
+        Self(Filter::new())
+    }
+
+    fn wikis(&self, wikis: Vec<String>) -> Self {
+        Self(self.0.clone().wikis(wikis))
+    }
+
+    fn wiki(&self, wiki: String) -> Self {
+        Self(self.0.clone().wiki(wiki))
+    }
+
+    fn titles(&self, titles: Vec<String>) -> Self {
+        Self(self.0.clone().titles(titles))
+    }
+
+    fn users(&self, users: Vec<String>) -> Self {
+        Self(self.0.clone().users(users))
+    }
+
+    fn user(&self, user: String) -> Self {
+        Self(self.0.clone().user(user))
+    }
+
+    fn namespaces(&self, namespaces: Vec<i32>) -> Self {
+        Self(self.0.clone().namespaces(namespaces))
+    }
+
+    fn namespace(&self, namespace: i32) -> Self {
+        Self(self.0.clone().namespace(namespace))
+    }
+
+    fn exclude_bots(&self) -> Self {
+        Self(self.0.clone().exclude_bots())
+    }
+
+    fn only_bots(&self) -> Self {
+        Self(self.0.clone().only_bots())
+    }
+
+    fn min_byte_change(&self, bytes: u64) -> Self {
+        Self(self.0.clone().min_byte_change(bytes))
+    }
+
+    /// Raises `ValueError` if the patterns are too many or too long
+    fn title_contains(&self, patterns: Vec<String>) -> PyResult<Self> {
+        self.0
+            .clone()
+            .title_contains(patterns)
+            .map(Self)
+            .map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    /// Raises `ValueError` if a pattern isn't a valid regex
+    fn title_matches(&self, patterns: Vec<String>) -> PyResult<Self> {
+        self.0
+            .clone()
+            .title_matches(patterns)
+            .map(Self)
+            .map_err(regex_error)
+    }
+
+    /// Raises `ValueError` if a pattern isn't a valid regex
+    fn comment_matches(&self, patterns: Vec<String>) -> PyResult<Self> {
+        self.0
+            .clone()
+            .comment_matches(patterns)
+            .map(Self)
+            .map_err(regex_error)
+    }
+
+    fn matches(&self, event: &PyEvent) -> bool {
+        self.0.matches(&event.0)
+    }
+
+    /// Why `event` matches or not, one condition per line
+    fn explain(&self, event: &PyEvent) -> String {
+        self.0.explain(&event.0).to_string()
+    }
+
+    fn __and__(&self, other: &PyFilter) -> Self {
+        Self(self.0.clone().and(other.0.clone()))
+    }
+
+    fn __or__(&self, other: &PyFilter) -> Self {
+        Self(self.0.clone().or(other.0.clone()))
+    }
+
+    fn __invert__(&self) -> Self {
+        Self(!self.0.clone())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
+
+/// A stream read on a background thread, which reconnects and resumes as
+/// [`EventStream`](crate::EventStream) does. Iterating
+/// blocks without holding the GIL; async iteration waits in the event
+/// loop's default executor.
+#[pyclass(name = "EventStream", module = "eventstreams", frozen)]
+pub struct PyEventStream {
+    worker: Mutex<Option<StreamWorker>>,
+    /// Taken from the worker, so that waiting for an event doesn't hold up
+    /// [`close()`](Self::close)
+    receiver: Mutex<Option<mpsc::Receiver<Event>>>,
+    closed: AtomicBool,
+    filter: Option<Filter>,
+}
+
+impl PyEventStream {
+    /// The next event the filter lets through, or `None` once the stream
+    /// has ended or been closed
+    fn next_event(&self, py: Python<'_>) -> PyResult<Option<PyEvent>> {
+        loop {
+            let received = py.detach(|| {
+                let mut receiver = self.receiver.lock().expect("not poisoned");
+                let received = match receiver.as_ref() {
+                    Some(receiver) if !self.closed.load(Ordering::SeqCst) => {
+                        receiver.recv_timeout(SIGNAL_INTERVAL)
+                    }
+                    _ => Err(RecvTimeoutError::Disconnected),
+                };
+                // Closed while this was waiting, even if an event came in,
+                // so drop the channel to free a thread waiting for room
+                if self.closed.load(Ordering::SeqCst) {
+                    receiver.take();
+                    return Err(RecvTimeoutError::Disconnected);
+                }
+                received
+            });
+            match received {
+                Ok(event) => {
+                    if self.filter.as_ref().is_none_or(|f| f.matches(&event)) {
+                        return Ok(Some(PyEvent(event)));
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => py.check_signals()?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+}
+
+#[pymethods]
+impl PyEventStream {
+    /// Start reading `streams` (stream names such as `recentchange` or
+    /// `page-create`, recent changes by default) in the background. Raises
+    /// `ValueError` if `url` isn't a valid URL.
+    #[new]
+    #[pyo3(signature = (
+        streams = None,
+        *,
+        url = None,
+        user_agent = None,
+        filter = None,
+        capacity = 1024
+    ))]
+    fn new(
+        streams: Option<Vec<String>>,
+        url: Option<String>,
+        user_agent: Option<String>,
+        filter: Option<PyRef<'_, PyFilter>>,
+        capacity: usize,
+    ) -> PyResult<Self> {
+        let mut builder = EventStreamBuilder::new();
+        if let Some(streams) = streams {
+            builder = builder.stream_names(streams);
+        }
+        if let Some(url) = url {
+            builder = builder.url(url);
+        }
+        if let Some(user_agent) = user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+        let mut worker = builder
+            .spawn(capacity, Overflow::Block)
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(Self {
+            receiver: Mutex::new(Some(worker.take_receiver())),
+            worker: Mutex::new(Some(worker)),
+            closed: AtomicBool::new(false),
+            filter: filter.map(|filter| filter.0.clone()),
+        })
+    }
+
+    fn __iter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __next__(&self, py: Python<'_>) -> PyResult<PyEvent> {
+        self.next_event(py)?
+            .ok_or_else(|| PyStopIteration::new_err(()))
+    }
+
+    fn __aiter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(slf: Bound<'py, Self>) -> PyResult<Bound<'py, PyAny>> {
+        let py = slf.py();
+        let event_loop =
+            py.import("asyncio")?.call_method0("get_running_loop")?;
+        let next = slf.getattr("_next_async")?;
+        event_loop.call_method1("run_in_executor", (py.None(), next))
+    }
+
+    /// `__next__()` for executors, whose futures can't end in
+    /// `StopIteration`
+    fn _next_async(&self, py: Python<'_>) -> PyResult<PyEvent> {
+        self.next_event(py)?
+            .ok_or_else(|| PyStopAsyncIteration::new_err(()))
+    }
+
+    /// Stop reading, waiting a few seconds for the thread to finish.
+    /// Iterating afterwards ends straight away.
+    fn close(&self, py: Python<'_>) -> PyResult<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        // Dropping the channel frees a thread waiting for room in it. If
+        // it's being received from, next_event() drops it instead within
+        // SIGNAL_INTERVAL, at which point the lock is free.
+        py.detach(|| self.receiver.lock().expect("not poisoned").take());
+        let worker = self.worker.lock().expect("not poisoned").take();
+        match worker {
+            Some(worker) => py
+                .detach(|| worker.shutdown(Some(CLOSE_TIMEOUT)))
+                .map(drop)
+                .map_err(|_| {
+                    PyRuntimeError::new_err("stream did not stop in time")
+                }),
+            None => Ok(()),
+        }
+    }
+
+    fn __enter__(slf: Bound<'_, Self>) -> Bound<'_, Self> {
+        slf
+    }
+
+    fn __exit__(
+        &self,
+        py: Python<'_>,
+        _exc_type: Option<Bound<'_, PyAny>>,
+        _exc_value: Option<Bound<'_, PyAny>>,
+        _traceback: Option<Bound<'_, PyAny>>,
+    ) -> PyResult<()> {
+        self.close(py)
+    }
+}
+
+#[pymodule]
+#[pyo3(name = "eventstreams")]
+fn init(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyEvent>()?;
+    module.add_class::<PyFilter>()?;
+    module.add_class::<PyEventStream>()?;
+    module.add("__version__", env!("CARGO_PKG_VERSION"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at, MockServer};
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn raises_value_error_for_invalid_urls() {
+        let err = match PyEventStream::new(
+            None,
+            Some("not a URL".to_string()),
+            None,
+            None,
+            1,
+        ) {
+            Ok(_) => panic!("built a stream for an invalid URL"),
+            Err(err) => err,
+        };
+        Python::initialize();
+        Python::attach(|py| assert!(err.is_instance_of::<PyValueError>(py)));
+    }
+
+    #[test]
+    fn closes_while_another_thread_is_iterating() {
+        let messages = (0..1000)
+            .map(|offset| testing::edit(offset, "A", at(0)).to_string());
+        let server = MockServer::new(messages).start().unwrap();
+        let stream = Arc::new(
+            PyEventStream::new(None, Some(server.url()), None, None, 1)
+                .unwrap(),
+        );
+        Python::initialize();
+        let reader = {
+            let stream = stream.clone();
+            thread::spawn(move || {
+                Python::attach(|py| {
+                    let mut received = 0;
+                    while stream.next_event(py).unwrap().is_some() {
+                        received += 1;
+                        // Fall behind so the worker waits for room
+                        py.detach(|| thread::sleep(SIGNAL_INTERVAL / 10));
+                    }
+                    received
+                })
+            })
+        };
+        thread::sleep(SIGNAL_INTERVAL);
+        Python::attach(|py| stream.close(py)).unwrap();
+        assert!(reader.join().unwrap() < 1000);
+    }
+}
@@ -0,0 +1,386 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Buffering between the network and event dispatch
+//!
+//! Normally messages are only read from the connection when the stream is
+//! polled, so slow [listeners](crate::listener) hold up reading, and
+//! EventStreams may eventually give up on the connection. With a
+//! [`Queue`], a background thread reads messages into a bounded buffer
+//! ahead of time, and its [`QueuePolicy`] decides what happens once the
+//! buffer is full. [`QueueStats`] reports how full it is and what was
+//! dropped, to help pick a capacity.
+//!
+//! Dropped messages never reach the stream, so they're missing from its
+//! [resume token](crate::EventStream::resume_token) too. Connection
+//! errors are never dropped.
+use crate::backend::BackendError;
+use crate::clock::Clock;
+use crate::compaction::Compaction;
+use crate::metrics::{Metrics, Reporter};
+use futures::{Stream, StreamExt};
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::Duration;
+
+type Message<T = String> = Result<T, BackendError>;
+
+/// What a [`Queue`] does with a new message once it's full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// Stop reading until there's room again, as without a queue
+    Block,
+    /// Drop the oldest message waiting, favoring recent events
+    DropOldest,
+    /// Drop the new message, favoring events already waiting
+    DropNewest,
+}
+
+/// How full a [`Queue`] is and what it dropped. Clones share the same
+/// counts.
+#[derive(Clone, Debug, Default)]
+pub struct QueueStats {
+    capacity: usize,
+    depth: Arc<AtomicUsize>,
+    max_depth: Arc<AtomicUsize>,
+    dropped_oldest: Arc<AtomicU64>,
+    dropped_newest: Arc<AtomicU64>,
+}
+
+impl QueueStats {
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of messages waiting to be dispatched
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Highest depth so far
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped under [`QueuePolicy::DropOldest`]
+    pub fn dropped_oldest(&self) -> u64 {
+        self.dropped_oldest.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped under [`QueuePolicy::DropNewest`]
+    pub fn dropped_newest(&self) -> u64 {
+        self.dropped_newest.load(Ordering::Relaxed)
+    }
+
+    /// Messages dropped in total
+    pub fn dropped(&self) -> u64 {
+        self.dropped_oldest() + self.dropped_newest()
+    }
+}
+
+struct State<T> {
+    messages: VecDeque<Message<T>>,
+    waker: Option<Waker>,
+    /// The reader finished, because the backend ended
+    finished: bool,
+    /// The stream was dropped, so the reader should stop
+    closed: bool,
+}
+
+impl<T> Default for State<T> {
+    fn default() -> Self {
+        Self {
+            messages: VecDeque::new(),
+            waker: None,
+            finished: false,
+            closed: false,
+        }
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    /// Signalled when there's room again, or the stream was dropped
+    room: Condvar,
+}
+
+/// A bounded buffer filled from a background thread, see the
+/// [module documentation](self) and
+/// [`EventStreamBuilder::queue()`](crate::EventStreamBuilder::queue)
+#[derive(Clone, Debug)]
+pub struct Queue {
+    policy: QueuePolicy,
+    stats: QueueStats,
+    reporter: Option<Reporter>,
+    compaction: Compaction,
+}
+
+impl Queue {
+    /// Buffer up to `capacity` messages, at least 1, and no more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Self {
+        let capacity = crate::metrics::cap(capacity);
+        Self {
+            policy,
+            stats: QueueStats {
+                capacity: capacity.max(1),
+                ..Default::default()
+            },
+            reporter: None,
+            compaction: Compaction::default(),
+        }
+    }
+
+    /// Report `queue.buffered` and `queue.dropped` to `metrics`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.reporter = Some(Reporter::new(metrics, "queue"));
+        self
+    }
+
+    /// Shrink the buffer to what it holds every `interval`, see
+    /// [`EventStreamBuilder::compaction_interval()`](crate::EventStreamBuilder::compaction_interval)
+    pub(crate) fn compaction_interval(
+        mut self,
+        interval: Option<Duration>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        self.compaction = Compaction::new(interval).clock(clock);
+        self
+    }
+
+    /// Lower the capacity to `limit`, if there is one, for
+    /// [`EventStreamBuilder::bounded()`](crate::EventStreamBuilder::bounded)
+    pub(crate) fn cap(mut self, limit: Option<usize>) -> Self {
+        if let Some(limit) = limit {
+            self.stats.capacity = self.stats.capacity.min(limit.max(1));
+        }
+        self
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.stats.clone()
+    }
+
+    /// Start reading from the backend returned by `connect` on a
+    /// background thread. The thread stops once the returned stream is
+    /// dropped and the next message comes in.
+    pub fn start<T, B>(
+        self,
+        connect: impl FnOnce() -> B + Send + 'static,
+    ) -> impl Stream<Item = Message<T>>
+    where
+        T: Send + 'static,
+        B: Stream<Item = Message<T>>,
+    {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            room: Condvar::new(),
+        });
+        let reader = shared.clone();
+        let stats = self.stats.clone();
+        let compaction = self.compaction.clone();
+        thread::spawn(move || {
+            futures::executor::block_on(async {
+                let backend = connect();
+                futures::pin_mut!(backend);
+                while let Some(message) = backend.next().await {
+                    if !self.push(&reader, message) {
+                        return;
+                    }
+                }
+            });
+            let mut state = reader.state.lock().unwrap();
+            state.finished = true;
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        });
+        QueueStream {
+            shared,
+            stats,
+            compaction,
+        }
+    }
+
+    /// Add a message, returning false if the stream is gone
+    fn push<T>(&self, shared: &Shared<T>, message: Message<T>) -> bool {
+        let capacity = self.stats.capacity;
+        let mut state = shared.state.lock().unwrap();
+        while state.messages.len() >= capacity && !state.closed {
+            match (self.policy, &message) {
+                (QueuePolicy::DropOldest, _) => {
+                    let oldest = state
+                        .messages
+                        .iter()
+                        .position(|message| message.is_ok());
+                    match oldest {
+                        Some(index) => {
+                            state.messages.remove(index);
+                            self.dropped(&self.stats.dropped_oldest);
+                        }
+                        // Nothing but errors waiting, so keep them all
+                        None => break,
+                    }
+                }
+                (QueuePolicy::DropNewest, Ok(_)) => {
+                    self.dropped(&self.stats.dropped_newest);
+                    return true;
+                }
+                // Errors go over capacity rather than being dropped
+                (QueuePolicy::DropNewest, Err(_)) => break,
+                (QueuePolicy::Block, _) => {
+                    state = shared.room.wait(state).unwrap();
+                }
+            }
+        }
+        if state.closed {
+            return false;
+        }
+        state.messages.push_back(message);
+        let depth = state.messages.len();
+        self.stats.depth.store(depth, Ordering::Relaxed);
+        self.stats.max_depth.fetch_max(depth, Ordering::Relaxed);
+        if let Some(reporter) = &self.reporter {
+            reporter.buffered(depth);
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn dropped(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(reporter) = &self.reporter {
+            reporter.dropped();
+        }
+    }
+}
+
+/// Receiving end of a [`Queue`]
+struct QueueStream<T> {
+    shared: Arc<Shared<T>>,
+    stats: QueueStats,
+    compaction: Compaction,
+}
+
+impl<T> Stream for QueueStream<T> {
+    type Item = Message<T>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Message<T>>> {
+        let this = self.get_mut();
+        let mut state = this.shared.state.lock().unwrap();
+        match state.messages.pop_front() {
+            Some(message) => {
+                this.stats
+                    .depth
+                    .store(state.messages.len(), Ordering::Relaxed);
+                // A burst may have grown the buffer to its capacity
+                if this.compaction.due() {
+                    state.messages.shrink_to_fit();
+                }
+                this.shared.room.notify_one();
+                Poll::Ready(Some(message))
+            }
+            None if state.finished => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for QueueStream<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.messages.clear();
+        self.shared.room.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    /// Fill a queue from `messages` before reading anything from it
+    fn fill(queue: Queue, messages: Vec<Message>) -> Vec<Message> {
+        let stats = queue.stats();
+        let total = messages.len();
+        let stream = queue.start(move || futures::stream::iter(messages));
+        while stats.depth() + (stats.dropped() as usize) < total {
+            thread::sleep(Duration::from_millis(1));
+        }
+        block_on(stream.collect())
+    }
+
+    fn messages(count: usize) -> Vec<Message> {
+        (0..count).map(|n| Ok(n.to_string())).collect()
+    }
+
+    fn texts(messages: &[Message]) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| match message {
+                Ok(data) => data.as_str(),
+                Err(_) => "error",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn drops_by_policy_once_full() {
+        let newest = Queue::new(2, QueuePolicy::DropNewest);
+        let stats = newest.stats();
+        assert_eq!(texts(&fill(newest, messages(5))), ["0", "1"]);
+        assert_eq!(stats.dropped_newest(), 3);
+        assert_eq!(stats.max_depth(), 2);
+        assert_eq!(stats.depth(), 0);
+
+        let oldest = Queue::new(2, QueuePolicy::DropOldest);
+        let stats = oldest.stats();
+        assert_eq!(texts(&fill(oldest, messages(5))), ["3", "4"]);
+        assert_eq!(stats.dropped_oldest(), 3);
+    }
+
+    #[test]
+    fn never_drops_errors() {
+        let mut with_errors = messages(2);
+        with_errors.insert(1, Err(BackendError::Disconnected));
+        with_errors.push(Err(BackendError::Disconnected));
+        let newest = Queue::new(1, QueuePolicy::DropNewest);
+        assert_eq!(texts(&fill(newest, with_errors)), ["0", "error", "error"]);
+    }
+
+    #[test]
+    fn blocks_until_there_is_room() {
+        let queue = Queue::new(1, QueuePolicy::Block);
+        let stats = queue.stats();
+        let stream = queue.start(|| futures::stream::iter(messages(10)));
+        assert_eq!(block_on(stream.collect::<Vec<_>>()).len(), 10);
+        assert_eq!(stats.dropped(), 0);
+        assert_eq!(stats.max_depth(), 1);
+    }
+}
@@ -0,0 +1,226 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Converting events to MediaWiki's RCFeed format
+//!
+//! MediaWiki can send recent changes as JSON over UDP or Redis
+//! ([`$wgRCFeeds`](https://www.mediawiki.org/wiki/Manual:$wgRCFeeds)),
+//! which is what the recentchange stream is built from. An
+//! [`RcFeedMessage`] is that original message, without the `$schema`,
+//! `meta` and `parsedcomment` fields that EventStreams adds, so tools
+//! written for an RCFeed can read from this crate instead.
+use crate::types::{EventLength, EventRevision};
+use crate::{CategorizeEvent, EditEvent, Event, ExternalEvent, LogEvent};
+use serde::Serialize;
+use serde_json::Value;
+
+/// A recent change as MediaWiki's JSON RCFeed formatter sends it. Fields
+/// are serialized in the same order, and those that MediaWiki leaves out
+/// for a type of change are left out here too.
+#[derive(Clone, Debug, Serialize)]
+pub struct RcFeedMessage {
+    /// Recent changes ID, `null` if it isn't known
+    pub id: Option<u64>,
+    /// `edit`, `new`, `log`, `categorize` or `external`
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub namespace: i32,
+    pub title: String,
+    pub comment: String,
+    pub timestamp: u32,
+    pub user: String,
+    pub bot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub minor: Option<bool>,
+    /// Only sent by wikis with patrolling enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub patrolled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<EventLength>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<EventRevision>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_action: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_params: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_action_comment: Option<String>,
+    pub server_url: String,
+    pub server_name: String,
+    pub server_script_path: String,
+    pub wiki: String,
+}
+
+impl RcFeedMessage {
+    /// The message as one line of JSON, as sent over the feed
+    pub fn to_line(&self) -> String {
+        serde_json::to_string(self).expect("serializing to a string")
+    }
+}
+
+impl From<&EditEvent> for RcFeedMessage {
+    fn from(edit: &EditEvent) -> Self {
+        Self {
+            id: Some(edit.id.into()),
+            type_: edit.type_.to_string(),
+            namespace: edit.namespace,
+            title: edit.title.clone(),
+            comment: edit.comment.clone(),
+            timestamp: edit.timestamp,
+            user: edit.user.to_string(),
+            bot: edit.bot,
+            minor: Some(edit.is_minor()),
+            patrolled: edit.patrolled,
+            length: Some(edit.length.clone()),
+            revision: Some(edit.revision.clone()),
+            log_id: None,
+            log_type: None,
+            log_action: None,
+            log_params: None,
+            log_action_comment: None,
+            server_url: edit.server_url.to_string(),
+            server_name: edit.server_name.to_string(),
+            server_script_path: edit.server_script_path.to_string(),
+            wiki: edit.wiki.to_string(),
+        }
+    }
+}
+
+impl From<&LogEvent> for RcFeedMessage {
+    fn from(log: &LogEvent) -> Self {
+        Self {
+            id: log.id,
+            type_: "log".to_string(),
+            namespace: log.namespace,
+            title: log.title.clone(),
+            comment: log.comment.clone(),
+            timestamp: log.timestamp,
+            user: log.user.to_string(),
+            bot: log.bot,
+            minor: None,
+            patrolled: None,
+            length: None,
+            revision: None,
+            log_id: Some(log.log_id),
+            log_type: Some(log.log_type.to_string()),
+            log_action: Some(log.log_action.to_string()),
+            log_params: Some(log.log_params.clone()),
+            log_action_comment: Some(log.log_action_comment.clone()),
+            server_url: log.server_url.to_string(),
+            server_name: log.server_name.to_string(),
+            server_script_path: log.server_script_path.to_string(),
+            wiki: log.wiki.to_string(),
+        }
+    }
+}
+
+impl From<&CategorizeEvent> for RcFeedMessage {
+    fn from(categorize: &CategorizeEvent) -> Self {
+        Self {
+            id: categorize.id,
+            type_: "categorize".to_string(),
+            namespace: categorize.namespace,
+            title: categorize.title.clone(),
+            comment: categorize.comment.clone(),
+            timestamp: categorize.timestamp,
+            user: categorize.user.to_string(),
+            bot: categorize.bot,
+            minor: None,
+            patrolled: None,
+            length: None,
+            revision: None,
+            log_id: None,
+            log_type: None,
+            log_action: None,
+            log_params: None,
+            log_action_comment: None,
+            server_url: categorize.server_url.to_string(),
+            server_name: categorize.server_name.to_string(),
+            server_script_path: categorize.server_script_path.to_string(),
+            wiki: categorize.wiki.to_string(),
+        }
+    }
+}
+
+impl From<&ExternalEvent> for RcFeedMessage {
+    fn from(external: &ExternalEvent) -> Self {
+        Self {
+            id: external.id,
+            type_: "external".to_string(),
+            namespace: external.namespace,
+            title: external.title.clone(),
+            comment: external.comment.clone(),
+            timestamp: external.timestamp,
+            user: external.user.to_string(),
+            bot: external.bot,
+            minor: None,
+            patrolled: None,
+            length: None,
+            revision: None,
+            log_id: None,
+            log_type: None,
+            log_action: None,
+            log_params: None,
+            log_action_comment: None,
+            server_url: external.server_url.to_string(),
+            server_name: external.server_name.to_string(),
+            server_script_path: external.server_script_path.to_string(),
+            wiki: external.wiki.to_string(),
+        }
+    }
+}
+
+impl Event {
+    /// The event as an RCFeed message, or `None` if it isn't from the
+    /// recentchange stream
+    pub fn to_rcfeed(&self) -> Option<RcFeedMessage> {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => Some(edit.into()),
+            Event::Log(log) => Some(log.into()),
+            Event::Categorize(categorize) => Some(categorize.into()),
+            Event::External(external) => Some(external.into()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, at};
+    use serde_json::json;
+
+    #[test]
+    fn drops_fields_added_by_eventstreams() {
+        let edit = testing::edit_event(1, "A", at(0));
+        let line = edit.to_rcfeed().unwrap().to_line();
+        assert!(line.starts_with(r#"{"id":1,"type":"edit","namespace":0,"#));
+        let message: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(message["revision"], json!({"old": 1, "new": 2}));
+        assert!(message.get("meta").is_none());
+        assert!(message.get("parsedcomment").is_none());
+        assert!(message.get("log_type").is_none());
+
+        let log = testing::event(&testing::log(2, "File:A.png", at(0)));
+        let message = serde_json::to_value(log.to_rcfeed().unwrap()).unwrap();
+        assert_eq!(message["log_type"], "upload");
+        assert_eq!(message["log_action_comment"], "uploaded");
+        assert!(message.get("revision").is_none());
+    }
+}
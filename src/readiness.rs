@@ -0,0 +1,249 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Readiness checks using the `test` stream
+//!
+//! EventGate regularly produces events to the `test` stream
+//! ([`StreamKind::Test`](crate::StreamKind::Test)) to check that the
+//! pipeline works end to end. Subscribing to it alongside the real streams
+//! gives deployments a concrete readiness probe: if test events keep
+//! arriving, so should everything else. A [`ReadinessProbe`] keeps track
+//! of when the last one arrived and reports whether that was recent
+//! enough.
+use crate::clock::{Clock, SystemClock};
+use crate::listener::{ListenerHandle, Listeners};
+use crate::maintenance::MaintenanceCalendar;
+#[cfg(feature = "sinks")]
+use crate::sink::{FnSink, Sink};
+#[cfg(feature = "sinks")]
+use crate::subscription::Subscription;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Whether `event` came from the `test` stream
+pub fn is_test_event(event: &Event) -> bool {
+    matches!(event, Event::Test(_))
+}
+
+/// Subscription that only passes test events on to `sink`
+#[cfg(feature = "sinks")]
+pub fn subscription(
+    name: impl Into<String>,
+    sink: impl Sink + 'static,
+) -> Subscription {
+    Subscription::new(name, is_test_event, sink)
+}
+
+/// Test events haven't been arriving as often as expected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NotFlowing {
+    /// When the last test event arrived, if any did
+    pub last_seen: Option<DateTime<Utc>>,
+    /// How often test events were expected
+    pub expected: Duration,
+}
+
+impl fmt::Display for NotFlowing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.last_seen {
+            Some(last_seen) => write!(
+                f,
+                "last test event arrived at {}, expected one every {}s",
+                last_seen.to_rfc3339(),
+                self.expected.as_secs()
+            ),
+            None => write!(
+                f,
+                "no test event arrived yet, expected one every {}s",
+                self.expected.as_secs()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NotFlowing {}
+
+#[derive(Debug, Default)]
+struct State {
+    last_seen: Option<DateTime<Utc>>,
+    received: u64,
+}
+
+/// Checks that test events arrive at least every so often. Clones share
+/// the same state, so one can observe events while another is asked
+/// whether the stream is ready.
+#[derive(Clone, Debug)]
+pub struct ReadinessProbe {
+    expected: Duration,
+    clock: Arc<dyn Clock>,
+    maintenance: Option<MaintenanceCalendar>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ReadinessProbe {
+    /// Expect a test event at least every `expected`
+    pub fn new(expected: Duration) -> Self {
+        Self::with_clock(expected, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(expected: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            expected,
+            clock,
+            maintenance: None,
+            state: Arc::default(),
+        }
+    }
+
+    /// Count the stream as ready during the windows in `calendar`, even if
+    /// test events stop, since an outage is expected then
+    pub fn maintenance(mut self, calendar: MaintenanceCalendar) -> Self {
+        self.maintenance = Some(calendar);
+        self
+    }
+
+    /// Record `event` if it's a test event, returning whether it was.
+    /// Arrival is measured when it's observed rather than by `meta.dt`,
+    /// so that delays anywhere in the pipeline count.
+    pub fn observe(&self, event: &Event) -> bool {
+        if !is_test_event(event) {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.last_seen = Some(self.clock.now());
+        state.received += 1;
+        true
+    }
+
+    /// Whether a test event arrived within the expected interval, or
+    /// there's [maintenance](Self::maintenance) going on
+    pub fn check(&self) -> Result<(), NotFlowing> {
+        let last_seen = self.state.lock().unwrap().last_seen;
+        let expected = chrono::Duration::from_std(self.expected)
+            .unwrap_or(chrono::Duration::MAX);
+        let now = self.clock.now();
+        let maintenance = self
+            .maintenance
+            .as_ref()
+            .is_some_and(|calendar| calendar.active_at(now).is_some());
+        match last_seen {
+            Some(seen) if now - seen <= expected => Ok(()),
+            _ if maintenance => Ok(()),
+            _ => Err(NotFlowing {
+                last_seen,
+                expected: self.expected,
+            }),
+        }
+    }
+
+    /// See [`check()`](Self::check)
+    pub fn is_ready(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// When the last test event arrived
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.state.lock().unwrap().last_seen
+    }
+
+    /// Number of test events observed
+    pub fn received(&self) -> u64 {
+        self.state.lock().unwrap().received
+    }
+
+    /// Observe every event passing through `listeners`
+    pub fn watch(&self, listeners: &Listeners) -> ListenerHandle {
+        let probe = self.clone();
+        listeners.on_event(move |event| {
+            probe.observe(event);
+        })
+    }
+
+    /// Subscription that observes test events, to deliver alongside the
+    /// real subscriptions
+    #[cfg(feature = "sinks")]
+    pub fn subscription(&self, name: impl Into<String>) -> Subscription {
+        let probe = self.clone();
+        subscription(
+            name,
+            FnSink(move |event: &Event| {
+                probe.observe(event);
+                Ok(())
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::maintenance::MaintenanceWindow;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn is_ready_while_test_events_keep_arriving() {
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let calendar = MaintenanceCalendar::new()
+            .margin(Duration::ZERO)
+            .window(MaintenanceWindow {
+                start: at(600),
+                end: at(900),
+                description: String::new(),
+            });
+        let probe =
+            ReadinessProbe::with_clock(Duration::from_secs(60), clock.clone())
+                .maintenance(calendar);
+        let err = probe.check().unwrap_err();
+        assert_eq!(err.last_seen, None);
+        assert_eq!(
+            err.to_string(),
+            "no test event arrived yet, expected one every 60s"
+        );
+
+        let mut test = testing::edit(1, "A", at(0))["meta"].clone();
+        test["stream"] = "eventgate-main.test.event".into();
+        let test = testing::event(&serde_json::json!({
+            "$schema": "/test/event/1.0.0",
+            "meta": test,
+            "test": "specific test value",
+        }));
+        let listeners = Listeners::new();
+        probe.clone().watch(&listeners);
+        listeners.dispatch(&testing::edit_event(2, "A", at(0)));
+        assert!(!probe.is_ready());
+        clock.advance(Duration::from_secs(10));
+        listeners.dispatch(&test);
+        assert_eq!(probe.received(), 1);
+        assert_eq!(probe.last_seen(), Some(at(10)));
+        clock.advance(Duration::from_secs(60));
+        assert!(probe.is_ready());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            probe.check(),
+            Err(NotFlowing {
+                last_seen: Some(at(10)),
+                expected: Duration::from_secs(60),
+            })
+        );
+        // Expected during maintenance
+        clock.advance(Duration::from_secs(600 - 71));
+        assert!(probe.is_ready());
+    }
+}
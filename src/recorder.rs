@@ -0,0 +1,424 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Capturing the stream to disk
+//!
+//! A [`Recorder`] writes raw messages to NDJSON files in a directory,
+//! starting a new file once the current one gets too big or too old, so
+//! the firehose can be captured for batch analysis later, e.g. by
+//! replaying it through an [`Archive`](crate::archive::Archive). Messages
+//! are usually recorded straight from the backend with
+//! [`Recorder::tee()`], which keeps them exactly as received, but parsed
+//! events can be recorded too, encoded with the recorder's
+//! [serializer](Recorder::serializer) when the `sinks` feature is
+//! enabled. With the `gzip` feature, files can be compressed as they're
+//! written.
+use crate::backend::BackendError;
+use crate::clock::{Clock, SystemClock};
+#[cfg(feature = "sinks")]
+use crate::sink::{self, Serializer, Sink, SinkError};
+use crate::Event;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "sinks")]
+use futures::future::{self, BoxFuture};
+use futures::{Stream, StreamExt};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How recordings are compressed
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Plain files, e.g. `.ndjson`
+    #[default]
+    None,
+    /// Gzipped files, e.g. `.ndjson.gz`
+    #[cfg(feature = "gzip")]
+    Gzip,
+}
+
+impl Compression {
+    fn suffix(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => ".gz",
+        }
+    }
+}
+
+enum Writer {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "gzip")]
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+impl Writer {
+    fn create(path: &PathBuf, compression: Compression) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::None => Writer::Plain(file),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Writer::Gzip(GzEncoder::new(
+                file,
+                flate2::Compression::default(),
+            )),
+        })
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Writer::Plain(mut writer) => writer.flush(),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(writer) => writer.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Writer::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Writer::Plain(writer) => writer.flush(),
+            #[cfg(feature = "gzip")]
+            Writer::Gzip(writer) => writer.flush(),
+        }
+    }
+}
+
+/// The file currently being written
+struct Current {
+    path: PathBuf,
+    writer: Writer,
+    opened: DateTime<Utc>,
+    /// Uncompressed bytes written so far
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct State {
+    current: Option<Current>,
+    /// Files started so far, oldest first
+    files: Vec<PathBuf>,
+}
+
+/// Writes messages to rotating NDJSON files. Clones share the same files.
+#[derive(Clone)]
+pub struct Recorder {
+    dir: PathBuf,
+    prefix: String,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    compression: Compression,
+    clock: Arc<dyn Clock>,
+    #[cfg(feature = "sinks")]
+    serializer: Arc<dyn Serializer>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Recorder {
+    /// Record into files in `dir` named like
+    /// `<prefix>-20210101T000000-0.ndjson`. Without limits everything goes
+    /// into one file. With the `sinks` feature, the extension comes from
+    /// the [serializer](Self::serializer).
+    pub fn new(dir: impl Into<PathBuf>, prefix: &str) -> Self {
+        Self {
+            dir: dir.into(),
+            prefix: prefix.to_string(),
+            max_bytes: None,
+            max_age: None,
+            compression: Compression::None,
+            clock: Arc::new(SystemClock),
+            #[cfg(feature = "sinks")]
+            serializer: sink::serializer(),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    /// Start a new file once the current one holds `max_bytes`, before
+    /// compression
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Start a new file once the current one was started `max_age` ago,
+    /// e.g. an hour for hourly files
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Measure file ages against `clock` rather than the system time
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Encode parsed events with `serializer` instead of the
+    /// [default](sink::set_serializer). Raw messages are always written
+    /// as lines of JSON, so recordings that mix the two should stay with
+    /// [`Json`](sink::Json).
+    #[cfg(feature = "sinks")]
+    pub fn serializer(mut self, serializer: Arc<dyn Serializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    fn extension(&self) -> &'static str {
+        #[cfg(feature = "sinks")]
+        {
+            self.serializer.extension()
+        }
+        #[cfg(not(feature = "sinks"))]
+        {
+            "ndjson"
+        }
+    }
+
+    /// Write a raw message as one line, first starting a new file if the
+    /// current one is full
+    pub fn record(&self, message: &str) -> io::Result<()> {
+        let mut line = Vec::with_capacity(message.len() + 1);
+        line.extend_from_slice(message.as_bytes());
+        line.push(b'\n');
+        self.write(&line)
+    }
+
+    fn write(&self, bytes: &[u8]) -> io::Result<()> {
+        let now = self.clock.now();
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = &state.current {
+            let too_big =
+                self.max_bytes.is_some_and(|max| current.bytes >= max);
+            let too_old = self.max_age.is_some_and(|max| {
+                (now - current.opened).to_std().unwrap_or_default() >= max
+            });
+            if too_big || too_old {
+                state.current.take().unwrap().writer.finish()?;
+            }
+        }
+        if state.current.is_none() {
+            fs::create_dir_all(&self.dir)?;
+            let path = self.dir.join(format!(
+                "{}-{}-{}.{}{}",
+                self.prefix,
+                now.format("%Y%m%dT%H%M%S"),
+                state.files.len(),
+                self.extension(),
+                self.compression.suffix()
+            ));
+            let writer = Writer::create(&path, self.compression)?;
+            state.files.push(path.clone());
+            state.current = Some(Current {
+                path,
+                writer,
+                opened: now,
+                bytes: 0,
+            });
+        }
+        let current = state.current.as_mut().unwrap();
+        current.writer.write_all(bytes)?;
+        current.bytes += bytes.len() as u64;
+        Ok(())
+    }
+
+    /// Write a parsed event, encoded with the
+    /// [serializer](Self::serializer), or serialized back to JSON without
+    /// the `sinks` feature
+    pub fn record_event(&self, event: &Event) -> io::Result<()> {
+        #[cfg(feature = "sinks")]
+        {
+            let bytes = self.serializer.serialize(event).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, err)
+            })?;
+            self.write(&bytes)
+        }
+        #[cfg(not(feature = "sinks"))]
+        {
+            self.record(&serde_json::to_string(event)?)
+        }
+    }
+
+    /// Finish the current file, so it's complete on disk. The next message
+    /// starts a new file.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.state.lock().unwrap().current.take() {
+            Some(current) => current.writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// The file currently being written, if any
+    pub fn current_file(&self) -> Option<PathBuf> {
+        let state = self.state.lock().unwrap();
+        state.current.as_ref().map(|current| current.path.clone())
+    }
+
+    /// Every file started so far, oldest first
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.state.lock().unwrap().files.clone()
+    }
+
+    /// Record every message from `backend` on its way through. Failures to
+    /// write are logged, and don't interrupt the stream. The current file
+    /// is finished once the backend ends.
+    pub fn tee<B>(
+        self,
+        backend: B,
+    ) -> impl Stream<Item = Result<String, BackendError>>
+    where
+        B: Stream<Item = Result<String, BackendError>>,
+    {
+        let recorder = self.clone();
+        backend
+            .inspect(move |message| {
+                if let Ok(data) = message {
+                    if let Err(err) = self.record(data) {
+                        log::warn!(
+                            target: "eventstreams::recorder",
+                            "failed to record message: {}",
+                            err
+                        );
+                    }
+                }
+            })
+            .chain(futures::stream::poll_fn(move |_| {
+                if let Err(err) = recorder.finish() {
+                    log::warn!(
+                        target: "eventstreams::recorder",
+                        "failed to finish recording: {}",
+                        err
+                    );
+                }
+                std::task::Poll::Ready(None)
+            }))
+    }
+}
+
+impl std::fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Recorder")
+            .field("dir", &self.dir)
+            .field("prefix", &self.prefix)
+            .field("max_bytes", &self.max_bytes)
+            .field("max_age", &self.max_age)
+            .field("compression", &self.compression)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "sinks")]
+impl Sink for Recorder {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(future::ready(self.record_event(event).map_err(Into::into)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    fn dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "eventstreams-recorder-{}-{}",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn rotates_files_by_size_and_age() {
+        let dir = dir("rotate");
+        let clock = Arc::new(ManualClock::new(at(0)));
+        let recorder = Recorder::new(&dir, "rc")
+            .max_bytes(8)
+            .max_age(Duration::from_secs(60))
+            .clock(clock.clone());
+        assert_eq!(recorder.current_file(), None);
+        recorder.record("first").unwrap();
+        recorder.record("second").unwrap();
+        recorder.record("third").unwrap();
+        clock.advance(Duration::from_secs(60));
+        recorder.record("4th").unwrap();
+        recorder.finish().unwrap();
+        assert_eq!(recorder.current_file(), None);
+
+        let files = recorder.files();
+        let names: Vec<_> = files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "rc-20210101T000000-0.ndjson",
+                "rc-20210101T000000-1.ndjson",
+                "rc-20210101T000100-2.ndjson",
+            ]
+        );
+        let contents: Vec<_> = files
+            .iter()
+            .map(|path| fs::read_to_string(path).unwrap())
+            .collect();
+        assert_eq!(contents, ["first\nsecond\n", "third\n", "4th\n"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_messages_passing_through() {
+        let dir = dir("tee");
+        let recorder = Recorder::new(&dir, "rc");
+        let messages = vec![
+            testing::edit(1, "A", at(0)).to_string(),
+            testing::edit(2, "B", at(1)).to_string(),
+        ];
+        let passed: Vec<_> = block_on(
+            recorder
+                .clone()
+                .tee(crate::backend::memory(messages.clone()))
+                .collect(),
+        );
+        assert_eq!(passed.len(), 2);
+        let files = recorder.files();
+        assert_eq!(files.len(), 1);
+        let recorded = fs::read_to_string(&files[0]).unwrap();
+        assert_eq!(recorded, messages.join("\n") + "\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
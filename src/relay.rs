@@ -0,0 +1,385 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Re-serving one upstream connection to many local clients
+//!
+//! A [`Relay`] lets a fleet of internal tools share a single connection to
+//! Wikimedia. Clients connect to `/stream` on the relay's address, either
+//! as an SSE client or by upgrading to a WebSocket, and pick what they
+//! receive with a [`Filter`] in its JSON form, URL-encoded in the `filter`
+//! query parameter, e.g. `/stream?filter={"wikis":["en.wikipedia.org"]}`.
+//! Without one they receive everything. Each event is encoded with the
+//! relay's [serializer](Relay::serializer), JSON unless
+//! [changed](crate::sink::set_serializer), and sent as an SSE `message`
+//! or a WebSocket message. Text encodings are sent as they are, in WebSocket
+//! text messages; binary ones are base64-encoded for SSE clients and sent
+//! in WebSocket binary messages.
+//!
+//! The relay is a [`Sink`], so it's usually fed by a
+//! [`Daemon`](crate::daemon::Daemon) subscription. Clients that fall too
+//! far behind are disconnected rather than holding up the others.
+use crate::admin::{read_request, write_response};
+use crate::filter::Filter;
+use crate::sink::{self, Serializer, Sink, SinkError};
+use crate::Event;
+use base64::Engine;
+use futures::future::{self, BoxFuture};
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use url::Url;
+
+/// Default number of events buffered per client
+const DEFAULT_BUFFER: usize = 1024;
+
+/// Appended to a client's key to accept a WebSocket handshake
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// How a client receives events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Protocol {
+    Sse,
+    WebSocket,
+}
+
+struct Client {
+    filter: Filter,
+    sender: SyncSender<Arc<[u8]>>,
+}
+
+/// Serves events to local SSE and WebSocket clients, see the
+/// [module documentation](self)
+#[derive(Clone)]
+pub struct Relay {
+    addr: SocketAddr,
+    clients: Arc<Mutex<Vec<Client>>>,
+    serializer: Arc<dyn Serializer>,
+}
+
+impl Relay {
+    /// Listen on `addr`, buffering up to 1024 events per client
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::with_buffer(addr, DEFAULT_BUFFER)
+    }
+
+    /// Listen on `addr`, disconnecting clients that have `buffer` events
+    /// waiting to be sent, or fewer in
+    /// [bounded mode](crate::metrics::set_bounded)
+    pub fn with_buffer(
+        addr: impl ToSocketAddrs,
+        buffer: usize,
+    ) -> io::Result<Self> {
+        let buffer = crate::metrics::cap(buffer);
+        let listener = TcpListener::bind(addr)?;
+        let relay = Self {
+            addr: listener.local_addr()?,
+            clients: Arc::new(Mutex::new(vec![])),
+            serializer: sink::serializer(),
+        };
+        let clients = relay.clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let clients = clients.clone();
+                thread::spawn(move || {
+                    let _ = accept(stream, &clients, buffer);
+                });
+            }
+        });
+        Ok(relay)
+    }
+
+    /// Encode events with `serializer` instead of the
+    /// [default](sink::set_serializer)
+    pub fn serializer(mut self, serializer: Arc<dyn Serializer>) -> Self {
+        self.serializer = serializer;
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Send `event` to every client whose filter matches it
+    pub fn publish(&self, event: &Event) {
+        let mut clients = self.clients.lock().unwrap();
+        let mut payload: Option<Arc<[u8]>> = None;
+        clients.retain(|client| {
+            if !client.filter.matches(event) {
+                return true;
+            }
+            let payload = payload.get_or_insert_with(|| {
+                let mut bytes =
+                    self.serializer.serialize(event).unwrap_or_default();
+                // Messages are delimited already
+                if bytes.last() == Some(&b'\n') {
+                    bytes.pop();
+                }
+                bytes.into()
+            });
+            match client.sender.try_send(payload.clone()) {
+                Ok(()) => true,
+                // Too slow, or already gone
+                Err(TrySendError::Full(_))
+                | Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+impl std::fmt::Debug for Relay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Relay")
+            .field("addr", &self.addr)
+            .field("clients", &self.clients())
+            .field("content_type", &self.serializer.content_type())
+            .finish()
+    }
+}
+
+impl Sink for Relay {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        self.publish(event);
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// Handle a new connection, then send it events until it goes away
+fn accept(
+    mut stream: TcpStream,
+    clients: &Mutex<Vec<Client>>,
+    buffer: usize,
+) -> io::Result<()> {
+    let request = read_request(&stream)?;
+    let url = match Url::parse("http://relay")
+        .and_then(|base| base.join(&request.path))
+    {
+        Ok(url) if request.method == "GET" && url.path() == "/stream" => url,
+        _ => return write_response(stream, "404 Not Found", "text/plain", ""),
+    };
+    let filter = match url.query_pairs().find(|(name, _)| name == "filter") {
+        Some((_, json)) => match serde_json::from_str::<Filter>(&json) {
+            Ok(filter) => filter,
+            Err(err) => {
+                return write_response(
+                    stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    &format!("invalid filter: {}", err),
+                )
+            }
+        },
+        None => Filter::new(),
+    };
+    let websocket_key = request
+        .headers
+        .get("upgrade")
+        .filter(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+        .and(request.headers.get("sec-websocket-key"));
+    let protocol = match websocket_key {
+        Some(key) => {
+            write!(
+                stream,
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                websocket_accept(key)
+            )?;
+            Protocol::WebSocket
+        }
+        None => {
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                 Cache-Control: no-cache\r\n\r\n"
+            )?;
+            Protocol::Sse
+        }
+    };
+    stream.flush()?;
+    let (sender, receiver) = mpsc::sync_channel(buffer);
+    clients.lock().unwrap().push(Client { filter, sender });
+    // Ends once the relay drops the client, or the client goes away
+    for payload in receiver {
+        match protocol {
+            Protocol::Sse => write_sse_message(&mut stream, &payload)?,
+            Protocol::WebSocket => write_frame(&mut stream, &payload)?,
+        }
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// Write `payload` as an SSE `message`, base64-encoded unless it's text
+fn write_sse_message(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    writeln!(stream, "event: message")?;
+    match std::str::from_utf8(payload) {
+        Ok(text) => {
+            for line in text.split('\n') {
+                writeln!(stream, "data: {}", line)?;
+            }
+        }
+        Err(_) => writeln!(
+            stream,
+            "data: {}",
+            base64::engine::general_purpose::STANDARD.encode(payload)
+        )?,
+    }
+    writeln!(stream)
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`
+fn websocket_accept(key: &str) -> String {
+    let digest = sha1_smol::Sha1::from(format!("{}{}", key, WEBSOCKET_GUID))
+        .digest()
+        .bytes();
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// Write `payload` as a single unmasked WebSocket frame, a text frame if
+/// it's text and a binary one otherwise
+fn write_frame(stream: &mut TcpStream, payload: &[u8]) -> io::Result<()> {
+    let len = payload.len();
+    let opcode = match std::str::from_utf8(payload) {
+        Ok(_) => 0x1,
+        Err(_) => 0x2,
+    };
+    let mut header = vec![0x80 | opcode];
+    if len < 126 {
+        header.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        header.push(126);
+        header.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        header.push(127);
+        header.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    stream.write_all(&header)?;
+    stream.write_all(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Json;
+    use crate::testing::{self, at};
+    use std::io::{BufRead, BufReader, Read};
+    use std::time::Duration;
+
+    /// Request `path` from `relay` with extra `headers`, returning the
+    /// connection once the relay has registered the client
+    fn connect(
+        relay: &Relay,
+        path: &str,
+        headers: &str,
+    ) -> BufReader<TcpStream> {
+        let clients = relay.clients();
+        let mut stream = TcpStream::connect(relay.local_addr()).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\n{}\r\n", path, headers).unwrap();
+        while relay.clients() == clients {
+            thread::sleep(Duration::from_millis(1));
+        }
+        BufReader::new(stream)
+    }
+
+    fn next_line(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn serves_filtered_events_over_sse_and_websockets() {
+        let relay = Relay::bind("127.0.0.1:0")
+            .unwrap()
+            .serializer(Arc::new(Json));
+        let filter: String =
+            url::form_urlencoded::byte_serialize(br#"{"users":["Bob"]}"#)
+                .collect();
+        let mut sse =
+            connect(&relay, &format!("/stream?filter={}", filter), "");
+        let mut websocket = connect(
+            &relay,
+            "/stream",
+            "Upgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        let by_alice = testing::edit_event(1, "A", at(0));
+        let mut by_bob = testing::edit(2, "B", at(0));
+        by_bob["user"] = "Bob".into();
+        let by_bob = testing::event(&by_bob);
+        relay.publish(&by_alice);
+        relay.publish(&by_bob);
+
+        assert_eq!(next_line(&mut sse), "HTTP/1.1 200 OK");
+        while !next_line(&mut sse).is_empty() {}
+        assert_eq!(next_line(&mut sse), "event: message");
+        let data = next_line(&mut sse);
+        let event: serde_json::Value =
+            serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(event["title"], "B");
+
+        assert_eq!(
+            next_line(&mut websocket),
+            "HTTP/1.1 101 Switching Protocols"
+        );
+        let mut accepted = false;
+        loop {
+            let line = next_line(&mut websocket);
+            if line.is_empty() {
+                break;
+            }
+            accepted |=
+                line == "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+        }
+        assert!(accepted);
+        let mut header = [0; 2];
+        websocket.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81);
+        // Events are longer than 125 bytes, so a 16-bit length follows
+        assert_eq!(header[1], 126);
+        let mut len = [0; 2];
+        websocket.read_exact(&mut len).unwrap();
+        let mut payload = vec![0; u16::from_be_bytes(len) as usize];
+        websocket.read_exact(&mut payload).unwrap();
+        let event: serde_json::Value =
+            serde_json::from_slice(&payload).unwrap();
+        assert_eq!(event["title"], "A");
+    }
+
+    #[test]
+    fn rejects_unknown_paths_and_invalid_filters() {
+        let relay = Relay::bind("127.0.0.1:0").unwrap();
+        for (path, status) in [
+            ("/other", "HTTP/1.1 404 Not Found"),
+            ("/stream?filter=nope", "HTTP/1.1 400 Bad Request"),
+        ] {
+            let mut stream = TcpStream::connect(relay.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\n\r\n", path).unwrap();
+            let mut reader = BufReader::new(stream);
+            assert_eq!(next_line(&mut reader), status);
+        }
+        assert_eq!(relay.clients(), 0);
+    }
+}
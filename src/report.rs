@@ -0,0 +1,139 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Summarizing what a run accomplished
+use crate::clock::Clock;
+use crate::resume::ResumeToken;
+use crate::{Event, EventStreamError};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// What an [`EventStream`](crate::EventStream) did before it was shut
+/// down, e.g. for batch jobs to log and to save `resume_token` for the
+/// next run
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ShutdownReport {
+    /// Events delivered, per stream name (`meta.stream`)
+    pub events: BTreeMap<String, u64>,
+    /// Events that never arrived from EventStreams, as detected from gaps
+    /// in Kafka offsets. Events dropped locally, e.g. by a full
+    /// [`Queue`](crate::queue::Queue), aren't counted here.
+    pub missed: u64,
+    /// Messages that couldn't be parsed
+    pub parse_errors: u64,
+    /// Times the backend failed and had to reconnect
+    pub backend_errors: u64,
+    /// Position just after the last event delivered
+    pub resume_token: ResumeToken,
+    /// How long the stream was running
+    pub uptime: Duration,
+}
+
+impl ShutdownReport {
+    /// Total events delivered across all streams
+    pub fn total_events(&self) -> u64 {
+        self.events.values().sum()
+    }
+}
+
+/// Builds a [`ShutdownReport`] as events and errors pass through
+#[derive(Debug)]
+pub(crate) struct Tally {
+    clock: Arc<dyn Clock>,
+    /// Wall-clock time rather than an `Instant`, which browsers don't have
+    started: DateTime<Utc>,
+    report: ShutdownReport,
+}
+
+impl Tally {
+    pub(crate) fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            started: clock.now(),
+            clock,
+            report: ShutdownReport::default(),
+        }
+    }
+
+    pub(crate) fn event(&mut self, event: &Event) {
+        *self
+            .report
+            .events
+            .entry(event.meta().stream.to_string())
+            .or_default() += 1;
+        self.report.resume_token.observe(event);
+    }
+
+    pub(crate) fn error(&mut self, err: &EventStreamError) {
+        match err {
+            EventStreamError::Backend(_) => self.report.backend_errors += 1,
+            EventStreamError::Malformed { .. }
+            | EventStreamError::Truncated { .. } => {
+                self.report.parse_errors += 1
+            }
+            EventStreamError::Gap(gap) => self.report.missed += gap.missing(),
+            EventStreamError::SchemaDrift(_) => {}
+        }
+    }
+
+    pub(crate) fn resume_token(&self) -> ResumeToken {
+        self.report.resume_token.clone()
+    }
+
+    pub(crate) fn report(&self) -> ShutdownReport {
+        ShutdownReport {
+            uptime: (self.clock.now() - self.started)
+                .to_std()
+                .unwrap_or_default(),
+            ..self.report.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::gaps::GapDetected;
+    use crate::testing;
+
+    #[test]
+    fn tallies_events_and_errors() {
+        let clock = ManualClock::new(Utc::now());
+        let mut tally = Tally::new(Arc::new(clock.clone()));
+        tally.event(&testing::edit_event(1, "A", clock.now()));
+        tally.event(&testing::edit_event(5, "B", clock.now()));
+        tally.error(&EventStreamError::Gap(GapDetected {
+            topic: "eqiad.mediawiki.recentchange".to_string(),
+            partition: 0,
+            first_missing: 2,
+            last_missing: 4,
+        }));
+        tally.error(&EventStreamError::Truncated {
+            data: "{".to_string(),
+        });
+        clock.advance(Duration::from_secs(90));
+        let report = tally.report();
+        assert_eq!(report.events["mediawiki.recentchange"], 2);
+        assert_eq!(report.total_events(), 2);
+        assert_eq!(report.missed, 3);
+        assert_eq!(report.parse_errors, 1);
+        assert_eq!(report.uptime, Duration::from_secs(90));
+        assert_eq!(report.resume_token, tally.resume_token());
+    }
+}
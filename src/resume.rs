@@ -0,0 +1,172 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Tracking the position in the stream
+use crate::Event;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Position of a single Kafka topic partition
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub topic: String,
+    pub partition: u32,
+    pub offset: u64,
+}
+
+/// Just the position of an event, for reading it from raw JSON without
+/// deserializing everything else
+#[derive(Deserialize)]
+pub(crate) struct RawEvent {
+    pub(crate) meta: Position,
+}
+
+/// How far into the stream events have been delivered, across all of the
+/// Kafka topics and partitions that make it up
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "Vec<Position>", into = "Vec<Position>")]
+pub struct ResumeToken {
+    positions: BTreeMap<(String, u32), u64>,
+}
+
+impl ResumeToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record an event as delivered
+    pub fn observe(&mut self, event: &Event) {
+        let meta = event.meta();
+        self.positions
+            .insert((meta.topic.clone(), meta.partition), meta.offset);
+    }
+
+    /// Record an event as delivered from its raw JSON, e.g. for events
+    /// that won't be parsed. Only `meta` is deserialized, the rest of the
+    /// event is skipped over.
+    pub fn observe_raw(&mut self, data: &str) {
+        if let Ok(RawEvent { meta }) = serde_json::from_str(data) {
+            self.positions
+                .insert((meta.topic, meta.partition), meta.offset);
+        }
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = Position> + '_ {
+        self.positions
+            .iter()
+            .map(|((topic, partition), offset)| Position {
+                topic: topic.to_string(),
+                partition: *partition,
+                offset: *offset,
+            })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    /// Whether the event at `position` has already been delivered
+    pub fn contains(&self, position: &Position) -> bool {
+        self.positions
+            .get(&(position.topic.clone(), position.partition))
+            .is_some_and(|offset| position.offset <= *offset)
+    }
+
+    /// Format as a `Last-Event-ID` header value, which EventStreams uses to
+    /// continue from just after these positions
+    pub fn to_last_event_id(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// Parse a `Last-Event-ID` value, as sent by EventStreams with every
+    /// event. Timestamp-based entries are skipped.
+    pub fn from_last_event_id(id: &str) -> Option<Self> {
+        let entries: Vec<serde_json::Value> = serde_json::from_str(id).ok()?;
+        Some(
+            entries
+                .into_iter()
+                .filter_map(|entry| serde_json::from_value(entry).ok())
+                .collect::<Vec<Position>>()
+                .into(),
+        )
+    }
+}
+
+impl From<Vec<Position>> for ResumeToken {
+    fn from(positions: Vec<Position>) -> Self {
+        Self {
+            positions: positions
+                .into_iter()
+                .map(|pos| ((pos.topic, pos.partition), pos.offset))
+                .collect(),
+        }
+    }
+}
+
+impl From<ResumeToken> for Vec<Position> {
+    fn from(token: ResumeToken) -> Self {
+        token.positions().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn tracks_the_latest_offset_per_partition() {
+        let mut token = ResumeToken::new();
+        token.observe(&testing::edit_event(7, "A", at(0)));
+        token.observe_raw(&testing::edit(9, "B", at(0)).to_string());
+        let position = |offset| Position {
+            topic: "eqiad.mediawiki.recentchange".to_string(),
+            partition: 0,
+            offset,
+        };
+        assert!(token.contains(&position(9)));
+        assert!(!token.contains(&position(10)));
+        assert_eq!(token.positions().collect::<Vec<_>>(), [position(9)]);
+    }
+
+    #[test]
+    fn round_trips_through_last_event_id() {
+        let id = r#"[{"topic":"eqiad.mediawiki.recentchange","partition":0,"offset":5},{"topic":"codfw.mediawiki.recentchange","partition":0,"timestamp":1609459200000}]"#;
+        let token = ResumeToken::from_last_event_id(id).unwrap();
+        assert_eq!(token.positions().count(), 1);
+        assert_eq!(
+            ResumeToken::from_last_event_id(&token.to_last_event_id()),
+            Some(token)
+        );
+        assert_eq!(ResumeToken::from_last_event_id("not json"), None);
+    }
+
+    #[test]
+    fn only_needs_the_meta_of_raw_messages() {
+        let mut token = ResumeToken::new();
+        token.observe_raw(
+            r#"{"meta": {"topic": "t", "partition": 1, "offset": 3}, "x": [1, {}]}"#,
+        );
+        token.observe_raw(r#"{"meta": {"topic": "t"}}"#);
+        token.observe_raw("not json");
+        let position = Position {
+            topic: "t".to_string(),
+            partition: 1,
+            offset: 3,
+        };
+        assert_eq!(token.positions().collect::<Vec<_>>(), [position]);
+    }
+}
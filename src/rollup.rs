@@ -0,0 +1,239 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Hourly and daily activity rollups
+//!
+//! Counts are bucketed by when events happened (`meta.dt`), in UTC. A
+//! [`Rollup`] is emitted once the [`Watermark`] passes the end of its
+//! period, so e.g. a reporting bot gets one summary per wiki per day.
+use crate::privacy::Noise;
+use crate::watermark::Watermark;
+use crate::Event;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+/// Length of a rollup period
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    Hour,
+    Day,
+}
+
+impl Period {
+    fn duration(self) -> Duration {
+        match self {
+            Period::Hour => Duration::hours(1),
+            Period::Day => Duration::days(1),
+        }
+    }
+
+    /// Start of the period that `dt` falls in
+    pub fn start(self, dt: DateTime<Utc>) -> DateTime<Utc> {
+        let size = self.duration().num_seconds();
+        let secs = dt.timestamp();
+        Utc.timestamp_opt(secs - secs.rem_euclid(size), 0).unwrap()
+    }
+}
+
+/// Activity on a single wiki
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    /// Edits, including page creations
+    pub edits: u64,
+    /// Edits that created a new page
+    pub new_pages: u64,
+    /// Blocks, including changes to existing blocks
+    pub blocks: u64,
+    /// Edits that are
+    /// [probably reverts](crate::EditEvent::is_probable_revert)
+    pub reverts: u64,
+}
+
+impl Counts {
+    fn add(&mut self, event: &Event) {
+        match event {
+            Event::Edit(edit) | Event::New(edit) => {
+                self.edits += 1;
+                if edit.is_page_creation() {
+                    self.new_pages += 1;
+                }
+                if edit.is_probable_revert() {
+                    self.reverts += 1;
+                }
+            }
+            Event::Log(log)
+                if log.log_type == "block"
+                    && (log.log_action == "block"
+                        || log.log_action == "reblock") =>
+            {
+                self.blocks += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Counts for every wiki with activity during a single period
+#[derive(Clone, Debug)]
+pub struct Rollup {
+    pub period: Period,
+    /// Inclusive start of the period
+    pub start: DateTime<Utc>,
+    /// Exclusive end of the period
+    pub end: DateTime<Utc>,
+    /// Counts keyed by internal database name of the wiki
+    pub wikis: BTreeMap<String, Counts>,
+    /// Events that arrived after the period had already been emitted in
+    /// the previous rollup, and weren't counted there
+    pub late: u64,
+}
+
+impl Rollup {
+    /// Counts summed over all wikis
+    pub fn total(&self) -> Counts {
+        let mut total = Counts::default();
+        for counts in self.wikis.values() {
+            total.edits += counts.edits;
+            total.new_pages += counts.new_pages;
+            total.blocks += counts.blocks;
+            total.reverts += counts.reverts;
+        }
+        total
+    }
+
+    /// A copy with `noise` added to every wiki's counts, for publishing.
+    /// Each wiki's counts together spend up to three times `noise`'s
+    /// epsilon, since one edit can be counted under edits, new pages and
+    /// reverts.
+    pub fn with_noise(&self, noise: &mut Noise) -> Rollup {
+        let mut noisy = self.clone();
+        for counts in noisy.wikis.values_mut() {
+            counts.edits = noise.count(counts.edits);
+            counts.new_pages = noise.count(counts.new_pages);
+            counts.blocks = noise.count(counts.blocks);
+            counts.reverts = noise.count(counts.reverts);
+        }
+        noisy
+    }
+}
+
+/// Accumulates per-wiki [`Counts`] and emits a [`Rollup`] at each period
+/// boundary
+#[derive(Clone, Debug)]
+pub struct RollupEmitter {
+    period: Period,
+    watermark: Watermark,
+    open: BTreeMap<DateTime<Utc>, BTreeMap<String, Counts>>,
+    late: u64,
+}
+
+impl RollupEmitter {
+    /// Emit a rollup every `period`, waiting `allowed_lateness` past its
+    /// end for out-of-order events
+    pub fn new(period: Period, allowed_lateness: std::time::Duration) -> Self {
+        Self {
+            period,
+            watermark: Watermark::new(allowed_lateness),
+            open: BTreeMap::new(),
+            late: 0,
+        }
+    }
+
+    /// Count an event, returning any rollups whose period ended as a result
+    pub fn push(&mut self, event: &Event) -> Vec<Rollup> {
+        let start = self.period.start(event.dt());
+        let size = self.period.duration();
+        if self
+            .watermark
+            .current()
+            .is_some_and(|watermark| start + size <= watermark)
+        {
+            self.late += 1;
+            return vec![];
+        }
+        self.watermark.observe(event);
+        self.open
+            .entry(start)
+            .or_default()
+            .entry(event.wiki().to_string())
+            .or_default()
+            .add(event);
+        let watermark = self.watermark.current().unwrap();
+        let mut closed = vec![];
+        while let Some(entry) = self.open.first_entry() {
+            if *entry.key() + size > watermark {
+                break;
+            }
+            let (start, wikis) = entry.remove_entry();
+            closed.push(self.rollup(start, wikis));
+        }
+        closed
+    }
+
+    /// Emit all remaining rollups, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<Rollup> {
+        std::mem::take(&mut self.open)
+            .into_iter()
+            .map(|(start, wikis)| self.rollup(start, wikis))
+            .collect()
+    }
+
+    fn rollup(
+        &mut self,
+        start: DateTime<Utc>,
+        wikis: BTreeMap<String, Counts>,
+    ) -> Rollup {
+        Rollup {
+            period: self.period,
+            start,
+            end: start + self.period.duration(),
+            wikis,
+            late: std::mem::take(&mut self.late),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, edit_event};
+
+    #[test]
+    fn rolls_up_each_period_once_it_has_passed() {
+        let mut emitter =
+            RollupEmitter::new(Period::Hour, std::time::Duration::ZERO);
+        assert!(emitter.push(&edit_event(1, "A", at(0))).is_empty());
+        assert!(emitter.push(&edit_event(2, "A", at(600))).is_empty());
+        let rollups = emitter.push(&edit_event(3, "A", at(3610)));
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].start, at(0));
+        assert_eq!(rollups[0].end, at(3600));
+        assert_eq!(rollups[0].wikis["enwiki"].edits, 2);
+        assert_eq!(rollups[0].total().edits, 2);
+        // Too late for the first hour, so counted in the next rollup
+        assert!(emitter.push(&edit_event(4, "A", at(100))).is_empty());
+        let rollups = emitter.flush();
+        assert_eq!(rollups.len(), 1);
+        assert_eq!(rollups[0].wikis["enwiki"].edits, 1);
+        assert_eq!(rollups[0].late, 1);
+    }
+
+    #[test]
+    fn periods_start_on_utc_boundaries() {
+        assert_eq!(Period::Hour.start(at(3 * 3600 + 59)), at(3 * 3600));
+        assert_eq!(Period::Day.start(at(86400 + 3600)), at(86400));
+    }
+}
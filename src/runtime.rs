@@ -0,0 +1,68 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! The parts of an async runtime the crate relies on
+//!
+//! Connections go through surf, whose clients bring their own I/O, so
+//! timers for backoff, read timeouts and pacing are all that's needed from
+//! a runtime. They come from futures-timer's background thread unless a
+//! runtime is picked with the `tokio` or `async-std` feature.
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// Wait for `duration`. With the `tokio` feature, tokio's timer is used
+/// when called from within a tokio runtime, which has to have timers
+/// enabled, as `#[tokio::main]` does. Threads of the crate's own, e.g. a
+/// [`Queue`](crate::queue::Queue)'s, aren't in one.
+pub(crate) fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+    #[cfg(feature = "tokio")]
+    if tokio::runtime::Handle::try_current().is_ok() {
+        return Box::pin(tokio::time::sleep(duration));
+    }
+    #[cfg(feature = "async-std")]
+    {
+        Box::pin(async_std::task::sleep(duration))
+    }
+    #[cfg(not(feature = "async-std"))]
+    {
+        Box::pin(futures_timer::Delay::new(duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn sleeps_outside_any_runtime() {
+        let started = Instant::now();
+        futures::executor::block_on(sleep(Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn sleeps_within_tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        runtime.block_on(sleep(Duration::from_millis(20)));
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,574 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Deserializing messages whose schema changed upstream
+//!
+//! Fields that an event type doesn't know about are always kept in its
+//! `extra` map. Fields it needs that are missing or of the wrong type make
+//! the message [malformed](crate::EventStreamError::Malformed) by default.
+//! With [`DeserializeMode::Lenient`], they are given an empty value
+//! instead, e.g. `""`, `0` or `null`, so the event still arrives with
+//! whatever else it carried. Either way,
+//! [`EventStreamBuilder::on_schema_mismatch()`](crate::EventStreamBuilder::on_schema_mismatch)
+//! is told which fields didn't fit.
+//!
+//! When messages of one type keep failing, e.g. after a schema change that
+//! this crate doesn't know about yet, the stream stops trying: after
+//! [`EventStreamBuilder::degrade_after()`](crate::EventStreamBuilder::degrade_after)
+//! failures in a row, a warning is logged and from then on that type is
+//! delivered as [`Event::Unknown`], with the message as a plain
+//! [`Value`], instead of being dropped.
+use crate::side_output::Excluded;
+use crate::{Event, EventMeta, UnknownEvent};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+/// Failures in a row after which a type is delivered raw, by default
+pub(crate) const DEGRADE_AFTER: u32 = 20;
+
+/// Most fields to fill in or replace in one message before giving up
+const MAX_REPAIRS: usize = 8;
+
+/// What to do with messages that don't fit their event type
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DeserializeMode {
+    /// Treat them as malformed
+    #[default]
+    Strict,
+    /// Give missing fields, and those of the wrong type, an empty value.
+    /// This keeps a copy of each message while it's parsed.
+    Lenient,
+}
+
+/// A message that didn't fit its event type
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SchemaMismatch {
+    /// Name of the stream (`meta.stream`), if there was one
+    pub stream: Option<String>,
+    /// `type` of the message, for streams that mix several, e.g. `edit`
+    pub event_type: Option<String>,
+    /// Why the message couldn't be parsed as it was
+    pub reason: String,
+    /// Paths to fields that were missing and given an empty value, e.g.
+    /// `length.new`
+    pub missing: Vec<String>,
+    /// Paths to fields of the wrong type, whose value was replaced
+    pub invalid: Vec<String>,
+    /// Whether the event was still delivered, in lenient mode
+    pub repaired: bool,
+}
+
+impl fmt::Display for SchemaMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} message", self.stream.as_deref().unwrap_or("unknown"))?;
+        if let Some(event_type) = &self.event_type {
+            write!(f, " ({})", event_type)?;
+        }
+        write!(f, " doesn't fit its schema: {}", self.reason)?;
+        if self.repaired {
+            let fields: Vec<_> =
+                self.invalid.iter().chain(&self.missing).cloned().collect();
+            write!(f, ", filled in {}", fields.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+type Hook = Arc<dyn Fn(&SchemaMismatch) + Send + Sync>;
+
+/// How messages are turned into events, as configured on the builder
+#[derive(Clone)]
+pub(crate) struct Deserializer {
+    pub(crate) mode: DeserializeMode,
+    pub(crate) hook: Option<Hook>,
+    /// Keep each message in its event, see [`Event::raw()`]
+    pub(crate) keep_raw: bool,
+    /// See [`Degrader`]
+    pub(crate) degrade_after: Option<u32>,
+}
+
+impl Default for Deserializer {
+    fn default() -> Self {
+        Self {
+            mode: DeserializeMode::default(),
+            hook: None,
+            keep_raw: false,
+            degrade_after: Some(DEGRADE_AFTER),
+        }
+    }
+}
+
+impl fmt::Debug for Deserializer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deserializer")
+            .field("mode", &self.mode)
+            .field("hook", &self.hook.is_some())
+            .field("keep_raw", &self.keep_raw)
+            .field("degrade_after", &self.degrade_after)
+            .finish()
+    }
+}
+
+pub(crate) type Parse = fn(Value) -> serde_json::Result<Event>;
+
+impl Deserializer {
+    /// Parse `value` with `parse`, repairing it in lenient mode
+    pub(crate) fn parse(
+        &self,
+        value: Value,
+        parse: Parse,
+    ) -> serde_json::Result<Event> {
+        if self.mode == DeserializeMode::Strict && self.hook.is_none() {
+            return parse(value);
+        }
+        let string = |value: &Value| value.as_str().map(str::to_string);
+        let stream = string(&value["meta"]["stream"]);
+        let event_type = string(&value["type"]);
+        let (err, repaired) = match self.mode {
+            DeserializeMode::Strict => match parse(value) {
+                Ok(event) => return Ok(event),
+                Err(err) => (err, None),
+            },
+            DeserializeMode::Lenient => match parse(value.clone()) {
+                Ok(event) => return Ok(event),
+                Err(err) => (err, repair(value, parse)),
+            },
+        };
+        let mismatch = |missing, invalid, repaired| SchemaMismatch {
+            stream,
+            event_type,
+            reason: err.to_string(),
+            missing,
+            invalid,
+            repaired,
+        };
+        match repaired {
+            Some(Repaired {
+                event,
+                missing,
+                invalid,
+            }) => {
+                if let Some(hook) = &self.hook {
+                    hook(&mismatch(missing, invalid, true));
+                }
+                Ok(event)
+            }
+            None => {
+                if let Some(hook) = &self.hook {
+                    hook(&mismatch(vec![], vec![], false));
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+struct Repaired {
+    event: Event,
+    missing: Vec<String>,
+    invalid: Vec<String>,
+}
+
+/// Values to try for a missing field, in order
+fn empty_values() -> Vec<Value> {
+    vec![
+        Value::Null,
+        Value::String(String::new()),
+        Value::from(0),
+        Value::Bool(false),
+        Value::Object(Map::new()),
+        Value::Array(vec![]),
+    ]
+}
+
+/// How serde describes `value` when it's of the wrong type
+fn unexpected(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => format!("boolean `{}`", b),
+        Value::Number(n) if n.is_f64() => format!("floating point `{}`", n),
+        Value::Number(n) => format!("integer `{}`", n),
+        Value::String(s) => format!("string {:?}", s),
+        Value::Array(_) => "sequence".to_string(),
+        Value::Object(_) => "map".to_string(),
+    }
+}
+
+/// The field named in a "missing field" error
+fn missing_field(message: &str) -> Option<String> {
+    let rest = message.strip_prefix("missing field `")?;
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+/// Paths to every object in `value`, outermost first
+fn objects(value: &Value) -> Vec<Vec<String>> {
+    let mut paths = vec![vec![]];
+    let mut i = 0;
+    while i < paths.len() {
+        if let Some(object) =
+            lookup(value, &paths[i]).and_then(Value::as_object)
+        {
+            for (key, child) in object {
+                if child.is_object() {
+                    let mut path = paths[i].clone();
+                    path.push(key.clone());
+                    paths.push(path);
+                }
+            }
+        }
+        i += 1;
+    }
+    paths
+}
+
+fn lookup<'a>(value: &'a Value, path: &[String]) -> Option<&'a Value> {
+    path.iter().try_fold(value, |value, key| value.get(key))
+}
+
+fn object_at<'a>(
+    value: &'a mut Value,
+    path: &[String],
+) -> Option<&'a mut Map<String, Value>> {
+    path.iter()
+        .try_fold(value, |value, key| value.get_mut(key))?
+        .as_object_mut()
+}
+
+/// Fill in or replace fields of `value` until it parses
+fn repair(mut value: Value, parse: Parse) -> Option<Repaired> {
+    let mut missing = vec![];
+    let mut invalid: Vec<String> = vec![];
+    for _ in 0..=MAX_REPAIRS {
+        let err = match parse(value.clone()) {
+            Ok(event) => {
+                return Some(Repaired {
+                    event,
+                    missing,
+                    invalid,
+                })
+            }
+            Err(err) => err.to_string(),
+        };
+        let error =
+            |value: &Value| parse(value.clone()).err().map(|e| e.to_string());
+        match missing_field(&err) {
+            // Find the object it's missing from, and a value that fits
+            Some(field) => {
+                let (path, filled) =
+                    objects(&value).into_iter().find_map(|path| {
+                        let object = lookup(&value, &path)?.as_object()?;
+                        if object.contains_key(&field) {
+                            return None;
+                        }
+                        empty_values().into_iter().find_map(|empty| {
+                            let blame =
+                                format!("invalid type: {}", unexpected(&empty));
+                            let mut candidate = value.clone();
+                            object_at(&mut candidate, &path)?
+                                .insert(field.clone(), empty);
+                            match error(&candidate) {
+                                None => Some((path.clone(), candidate)),
+                                Some(new)
+                                    if new != err
+                                        && !new.starts_with(&blame) =>
+                                {
+                                    Some((path.clone(), candidate))
+                                }
+                                Some(_) => None,
+                            }
+                        })
+                    })?;
+                value = filled;
+                let mut path = path;
+                path.push(field);
+                let path = path.join(".");
+                if !invalid.contains(&path) {
+                    missing.push(path);
+                }
+            }
+            // Find the field at fault, and drop it so that it's filled in
+            // as missing. Errors usually quote the value, which narrows
+            // it down; otherwise try the innermost fields first. Fields
+            // are checked in the order they appear and missing ones last,
+            // so only the culprit changes the error when it's dropped.
+            None => {
+                let mut fields = vec![];
+                for path in objects(&value).into_iter().rev() {
+                    let object =
+                        lookup(&value, &path).and_then(Value::as_object);
+                    for (key, field) in object.into_iter().flatten() {
+                        let quoted = err.contains(&unexpected(field));
+                        fields.push((quoted, path.clone(), key.clone()));
+                    }
+                }
+                let quoted = fields.iter().any(|(quoted, ..)| *quoted);
+                fields.retain(|(q, ..)| *q || !quoted);
+                let (path, dropped) =
+                    fields.into_iter().find_map(|(_, mut path, key)| {
+                        let mut candidate = value.clone();
+                        object_at(&mut candidate, &path)?.remove(&key);
+                        let fixed = error(&candidate).as_ref() != Some(&err);
+                        path.push(key);
+                        Some((path, candidate)).filter(|_| fixed)
+                    })?;
+                let path = path.join(".");
+                // An empty value didn't do either
+                if missing.contains(&path) || invalid.contains(&path) {
+                    return None;
+                }
+                value = dropped;
+                invalid.push(path);
+            }
+        }
+    }
+    None
+}
+
+/// Stream name and `type` that failures are counted by
+type Kind = (String, Option<String>);
+
+/// Delivers messages of a kind that keeps failing to parse as
+/// [`Event::Unknown`], see the [module documentation](self)
+#[derive(Debug)]
+pub(crate) struct Degrader {
+    after: Option<u32>,
+    keep_raw: bool,
+    /// Failures in a row, for kinds that aren't degraded yet
+    failures: HashMap<Kind, u32>,
+    degraded: HashSet<Kind>,
+}
+
+impl Degrader {
+    pub(crate) fn new(deserializer: &Deserializer) -> Self {
+        Self {
+            after: deserializer.degrade_after,
+            keep_raw: deserializer.keep_raw,
+            failures: HashMap::new(),
+            degraded: HashSet::new(),
+        }
+    }
+
+    /// Pass on how `data` was parsed, or deliver it raw if its kind is
+    /// degraded
+    pub(crate) fn check(
+        &mut self,
+        data: &str,
+        parsed: Option<Result<Event, Excluded>>,
+    ) -> Option<Result<Event, Excluded>> {
+        let after = match self.after {
+            Some(after) => after,
+            None => return parsed,
+        };
+        match parsed {
+            Some(Ok(Event::Unknown(_)))
+            | Some(Err(Excluded::Filtered { .. }))
+            | None => parsed,
+            Some(Ok(event)) => {
+                let kind = (
+                    event.meta().stream.to_string(),
+                    event_type(&event).map(str::to_string),
+                );
+                if !self.degraded.contains(&kind) {
+                    self.failures.remove(&kind);
+                    return Some(Ok(event));
+                }
+                // Keep degraded kinds consistent, even for messages that
+                // happen to parse
+                match serde_json::from_str(data) {
+                    Ok(value) => Some(Ok(self.unknown(
+                        event.meta().clone(),
+                        value,
+                        data,
+                    ))),
+                    Err(_) => Some(Ok(event)),
+                }
+            }
+            Some(Err(excluded)) => {
+                let value: Value = match serde_json::from_str(data) {
+                    Ok(value) => value,
+                    Err(_) => return Some(Err(excluded)),
+                };
+                let meta: EventMeta =
+                    match serde_json::from_value(value["meta"].clone()) {
+                        Ok(meta) => meta,
+                        Err(_) => return Some(Err(excluded)),
+                    };
+                let kind = (
+                    meta.stream.to_string(),
+                    value["type"].as_str().map(str::to_string),
+                );
+                if !self.degraded.contains(&kind) {
+                    let failures =
+                        self.failures.entry(kind.clone()).or_default();
+                    *failures += 1;
+                    if *failures < after {
+                        return Some(Err(excluded));
+                    }
+                    self.failures.remove(&kind);
+                    log::warn!(
+                        target: "eventstreams::schema",
+                        "{}{} messages failed to parse {} times in a row, \
+                         delivering them as unknown events from now on",
+                        kind.0,
+                        kind.1.as_deref().map(|type_| format!(" ({})", type_)).unwrap_or_default(),
+                        after
+                    );
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        stream = kind.0.as_str(),
+                        event_type = kind.1.as_deref(),
+                        failures = after,
+                        "degrading to unknown events"
+                    );
+                    self.degraded.insert(kind);
+                }
+                Some(Ok(self.unknown(meta, value, data)))
+            }
+        }
+    }
+
+    fn unknown(&self, mut meta: EventMeta, value: Value, data: &str) -> Event {
+        if self.keep_raw {
+            meta.raw = Some(data.into());
+        }
+        Event::Unknown(UnknownEvent { meta, value })
+    }
+}
+
+/// The `type` a recent change was parsed from
+fn event_type(event: &Event) -> Option<&'static str> {
+    Some(match event {
+        Event::Edit(_) => "edit",
+        Event::New(_) => "new",
+        Event::Log(_) => "log",
+        Event::Categorize(_) => "categorize",
+        Event::External(_) => "external",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::sync::Mutex;
+
+    fn deserializer(
+        mode: DeserializeMode,
+    ) -> (Deserializer, Arc<Mutex<Vec<SchemaMismatch>>>) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mismatches = seen.clone();
+        let deserializer = Deserializer {
+            mode,
+            hook: Some(Arc::new(move |mismatch: &SchemaMismatch| {
+                mismatches.lock().unwrap().push(mismatch.clone())
+            })),
+            ..Deserializer::default()
+        };
+        (deserializer, seen)
+    }
+
+    #[test]
+    fn fills_in_fields_that_dont_fit() {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["length"].as_object_mut().unwrap().remove("new");
+        edit["user"] = 5.into();
+        let data = edit.to_string();
+
+        let (strict, seen) = deserializer(DeserializeMode::Strict);
+        assert!(matches!(
+            crate::handle_event_with(&data, &strict),
+            Some(Err(Excluded::Malformed { .. }))
+        ));
+        assert!(!seen.lock().unwrap()[0].repaired);
+
+        let (lenient, seen) = deserializer(DeserializeMode::Lenient);
+        let edit = match crate::handle_event_with(&data, &lenient) {
+            Some(Ok(Event::Edit(edit))) => edit,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(edit.title, "A");
+        assert_eq!(edit.user, "");
+        let mismatch = seen.lock().unwrap()[0].clone();
+        assert_eq!(mismatch.stream.as_deref(), Some("mediawiki.recentchange"));
+        assert_eq!(mismatch.event_type.as_deref(), Some("edit"));
+        assert_eq!(mismatch.missing, ["length.new"]);
+        assert_eq!(mismatch.invalid, ["user"]);
+        assert!(mismatch.repaired);
+        assert!(mismatch.to_string().ends_with("filled in user, length.new"));
+
+        // Messages that fit aren't reported
+        let fits = testing::edit(2, "B", at(0)).to_string();
+        assert!(matches!(
+            crate::handle_event_with(&fits, &lenient),
+            Some(Ok(Event::Edit(_)))
+        ));
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn degrades_kinds_that_keep_failing() {
+        let deserializer = Deserializer {
+            degrade_after: Some(2),
+            keep_raw: true,
+            ..Deserializer::default()
+        };
+        let mut degrader = Degrader::new(&deserializer);
+        let mut check = |message: serde_json::Value| {
+            let data = message.to_string();
+            let parsed = crate::handle_event_with(&data, &deserializer);
+            degrader.check(&data, parsed).unwrap().ok()
+        };
+        let mut broken = testing::edit(1, "A", at(0));
+        broken.as_object_mut().unwrap().remove("length");
+        assert!(check(broken.clone()).is_none());
+        // A message that parses starts the count over
+        assert!(check(testing::edit(2, "B", at(0))).is_some());
+        assert!(check(broken.clone()).is_none());
+        match check(broken.clone()) {
+            Some(Event::Unknown(unknown)) => {
+                assert_eq!(unknown.value, broken);
+                assert_eq!(
+                    unknown.meta.raw.as_deref(),
+                    Some(&*broken.to_string())
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+        // Every edit from now on, but only edits
+        assert!(matches!(
+            check(testing::edit(3, "C", at(0))),
+            Some(Event::Unknown(_))
+        ));
+        assert!(matches!(
+            check(testing::log(4, "File:D.png", at(0))),
+            Some(Event::Log(_))
+        ));
+
+        let mut never = Degrader::new(&Deserializer {
+            degrade_after: None,
+            ..Deserializer::default()
+        });
+        for _ in 0..3 {
+            let data = broken.to_string();
+            let parsed =
+                crate::handle_event_with(&data, &Deserializer::default());
+            assert!(never.check(&data, parsed).unwrap().is_err());
+        }
+    }
+}
@@ -0,0 +1,375 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Scoring edits with ORES models
+//!
+//! Patrolling bots usually want to know how likely an edit is to be
+//! damaging, and whether it was made in good faith. There are two ways to
+//! get those scores for each edit:
+//!
+//! * [`ScoreJoin`] pairs edits with events from the `revision-score`
+//!   stream, which needs both streams in the same
+//!   [multiplexed](crate::EventStreamBuilder::streams) connection. Scores
+//!   are only published for some wikis.
+//! * [`LiftWingClient`] asks the
+//!   [Lift Wing](https://api.wikimedia.org/wiki/Lift_Wing_API) API, one
+//!   request per model and edit.
+//!
+//! Either way, edits come out as [`Scored`], with [`Scores`] left empty
+//! where no score could be found.
+use crate::api::{ApiClient, ApiError};
+use crate::cache::{Cache, MemoryCache};
+#[cfg(feature = "analytics")]
+use crate::watermark::Watermark;
+#[cfg(feature = "analytics")]
+use crate::Event;
+use crate::{EditEvent, RevisionScoreEvent};
+#[cfg(feature = "analytics")]
+use chrono::Duration;
+use serde_json::{json, Value};
+#[cfg(feature = "analytics")]
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+const LIFT_WING: &str =
+    "https://api.wikimedia.org/service/lw/inference/v1/models";
+
+const DEFAULT_TTL: std::time::Duration =
+    std::time::Duration::from_secs(24 * 3600);
+
+/// Probabilities from the `damaging` and `goodfaith` models, `None` where
+/// there's no score
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Scores {
+    /// Probability that the edit is damaging
+    pub damaging: Option<f64>,
+    /// Probability that the edit was made in good faith
+    pub goodfaith: Option<f64>,
+}
+
+impl Scores {
+    /// Scores from a `revision-score` event
+    pub fn from_event(event: &RevisionScoreEvent) -> Self {
+        let probability = |model: &str| {
+            event.scores.get(model)?.probability.get("true").copied()
+        };
+        Self {
+            damaging: probability("damaging"),
+            goodfaith: probability("goodfaith"),
+        }
+    }
+
+    /// Whether neither model had a score
+    pub fn is_empty(&self) -> bool {
+        self.damaging.is_none() && self.goodfaith.is_none()
+    }
+}
+
+/// An edit along with its scores
+#[derive(Clone, Debug)]
+pub struct Scored {
+    pub edit: EditEvent,
+    pub scores: Scores,
+}
+
+/// Pairs edits with `revision-score` events for the same revision that
+/// arrive within `window` of each other, in event time
+#[cfg(feature = "analytics")]
+#[derive(Clone, Debug)]
+pub struct ScoreJoin {
+    window: Duration,
+    watermark: Watermark,
+    edits: HashMap<(String, u64), EditEvent>,
+    scores: HashMap<(String, u64), (chrono::DateTime<chrono::Utc>, Scores)>,
+}
+
+#[cfg(feature = "analytics")]
+impl ScoreJoin {
+    pub fn new(window: std::time::Duration) -> Self {
+        Self {
+            window: Duration::from_std(window).expect("window out of range"),
+            watermark: Watermark::new(window),
+            edits: HashMap::new(),
+            scores: HashMap::new(),
+        }
+    }
+
+    /// Number of edits waiting for their scores
+    pub fn buffered(&self) -> usize {
+        self.edits.len()
+    }
+
+    /// Add an event, returning edits that were scored as a result, or
+    /// waited longer than `window` and are passed on without scores.
+    /// Events other than edits and revision scores are ignored.
+    pub fn push(&mut self, event: &Event) -> Vec<Scored> {
+        let mut scored = vec![];
+        match event {
+            Event::Edit(edit) | Event::New(edit) => {
+                let key = (edit.wiki.to_string(), edit.revision.new.into());
+                match self.scores.remove(&key) {
+                    Some((_, scores)) => scored.push(Scored {
+                        edit: edit.clone(),
+                        scores,
+                    }),
+                    None => {
+                        self.edits.insert(key, edit.clone());
+                    }
+                }
+            }
+            Event::RevisionScore(score) => {
+                let key = (score.database.to_string(), score.rev_id);
+                let scores = Scores::from_event(score);
+                match self.edits.remove(&key) {
+                    Some(edit) => scored.push(Scored { edit, scores }),
+                    None => {
+                        self.scores.insert(key, (score.meta.dt, scores));
+                    }
+                }
+            }
+            _ => return scored,
+        }
+        self.watermark.observe(event);
+        let expired = self.watermark.current().unwrap() - self.window;
+        self.scores.retain(|_, (dt, _)| *dt >= expired);
+        let stale: Vec<_> = self
+            .edits
+            .iter()
+            .filter(|(_, edit)| edit.meta.dt < expired)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            let edit = self.edits.remove(&key).unwrap();
+            scored.push(Scored {
+                edit,
+                scores: Scores::default(),
+            });
+        }
+        scored
+    }
+
+    /// Pass on all edits still waiting, without scores, e.g. once the
+    /// stream has ended
+    pub fn flush(&mut self) -> Vec<Scored> {
+        self.scores.clear();
+        self.edits
+            .drain()
+            .map(|(_, edit)| Scored {
+                edit,
+                scores: Scores::default(),
+            })
+            .collect()
+    }
+}
+
+/// Fetches and caches [`Scores`] from Lift Wing
+#[derive(Clone)]
+pub struct LiftWingClient {
+    api: ApiClient,
+    cache: Arc<dyn Cache>,
+    ttl: std::time::Duration,
+    url: String,
+}
+
+impl LiftWingClient {
+    pub fn new() -> Self {
+        Self {
+            api: ApiClient::new(),
+            cache: Arc::new(MemoryCache::default()),
+            ttl: DEFAULT_TTL,
+            url: LIFT_WING.to_string(),
+        }
+    }
+
+    /// Make requests with `api`, e.g. to share its limits with other
+    /// stages
+    pub fn api(mut self, api: ApiClient) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// Keep scores in `cache` instead, e.g. one shared with other stages
+    pub fn cache(mut self, cache: Arc<dyn Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// How long scores are cached for (default 1 day)
+    pub fn ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Use the models at `url` instead, e.g. an internal endpoint
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// Score `edit` with both models. A model that isn't available for the
+    /// wiki leaves its score empty.
+    pub async fn score(&self, edit: &EditEvent) -> Result<Scores, ApiError> {
+        let rev_id = u64::from(edit.revision.new);
+        let (damaging, goodfaith) = futures::join!(
+            self.probability(&edit.wiki, "damaging", rev_id),
+            self.probability(&edit.wiki, "goodfaith", rev_id)
+        );
+        Ok(Scores {
+            damaging: damaging?,
+            goodfaith: goodfaith?,
+        })
+    }
+
+    /// Probability of `true` from `model` for revision `rev_id` on `wiki`
+    async fn probability(
+        &self,
+        wiki: &str,
+        model: &str,
+        rev_id: u64,
+    ) -> Result<Option<f64>, ApiError> {
+        let key = format!("score:{}:{}:{}", wiki, model, rev_id);
+        if let Some(cached) = self
+            .cache
+            .get(&key)
+            .and_then(|cached| serde_json::from_str(&cached).ok())
+        {
+            return Ok(cached);
+        }
+        let url = surf::Url::parse(&format!(
+            "{}/{}-{}:predict",
+            self.url, wiki, model
+        ))?;
+        let probability =
+            match self.api.post(url, &json!({ "rev_id": rev_id })).await {
+                Ok(resp) => resp[wiki]["scores"][rev_id.to_string()][model]
+                    ["score"]["probability"]["true"]
+                    .as_f64(),
+                // No such model for the wiki
+                Err(ApiError::Status(404)) => None,
+                Err(err) => return Err(err),
+            };
+        self.cache
+            .put(&key, Value::from(probability).to_string(), self.ttl);
+        Ok(probability)
+    }
+}
+
+impl fmt::Debug for LiftWingClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LiftWingClient")
+            .field("url", &self.url)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for LiftWingClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+
+    /// A `revision-score` event for the revision created by
+    /// `testing::edit(offset, ..)`
+    fn score(offset: u64, seconds: i64) -> serde_json::Value {
+        let mut score = testing::edit(offset, "A", at(seconds));
+        score["meta"]["stream"] = "mediawiki.revision-score".into();
+        json!({
+            "$schema": "/mediawiki/revision/score/2.0.0",
+            "meta": score["meta"],
+            "database": "enwiki",
+            "page_id": 1,
+            "page_title": "A",
+            "page_namespace": 0,
+            "rev_id": offset + 1,
+            "rev_timestamp": at(seconds).to_rfc3339(),
+            "scores": {
+                "damaging": {
+                    "model_name": "damaging",
+                    "model_version": "0.5.0",
+                    "prediction": ["false"],
+                    "probability": { "false": 0.9, "true": 0.1 },
+                },
+            },
+        })
+    }
+
+    #[cfg(feature = "analytics")]
+    #[test]
+    fn joins_edits_with_their_scores() {
+        let mut join = ScoreJoin::new(std::time::Duration::from_secs(60));
+        let edit =
+            |offset, seconds| testing::edit_event(offset, "A", at(seconds));
+        assert!(join.push(&edit(1, 0)).is_empty());
+        let scored = join.push(&testing::event(&score(1, 5)));
+        assert_eq!(scored.len(), 1);
+        assert_eq!(scored[0].edit.revision.new, 2);
+        assert_eq!(scored[0].scores.damaging, Some(0.1));
+        assert_eq!(scored[0].scores.goodfaith, None);
+        // Scores can arrive first too
+        assert!(join.push(&testing::event(&score(3, 10))).is_empty());
+        assert_eq!(join.push(&edit(3, 12)).len(), 1);
+
+        assert!(join.push(&edit(5, 20)).is_empty());
+        assert_eq!(join.buffered(), 1);
+        let unscored = join.push(&edit(6, 200));
+        assert_eq!(unscored.len(), 1);
+        assert_eq!(unscored[0].edit.revision.new, 6);
+        assert!(unscored[0].scores.is_empty());
+        assert_eq!(join.flush().len(), 1);
+    }
+
+    #[test]
+    fn fetches_and_caches_lift_wing_scores() {
+        let prediction = json!({
+            "enwiki": { "scores": { "2": {
+                "damaging": { "score": { "probability": { "true": 0.25 } } },
+                "goodfaith": { "score": { "probability": { "true": 0.75 } } },
+            } } },
+        });
+        let server = testing::serve_json(vec![prediction.clone(), prediction]);
+        let client = LiftWingClient::new()
+            .api(ApiClient::new().rate_limit(1000.0))
+            .url(format!("http://{}/models", server.addr()));
+        let edit = match testing::edit_event(1, "A", at(0)) {
+            crate::Event::Edit(edit) => edit,
+            other => panic!("not an edit: {:?}", other),
+        };
+        let expected = Scores {
+            damaging: Some(0.25),
+            goodfaith: Some(0.75),
+        };
+        assert_eq!(block_on(client.score(&edit)).unwrap(), expected);
+        assert_eq!(block_on(client.score(&edit)).unwrap(), expected);
+        let mut paths: Vec<_> =
+            server.requests().into_iter().map(|req| req.path).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "/models/enwiki-damaging:predict",
+                "/models/enwiki-goodfaith:predict"
+            ]
+        );
+    }
+}
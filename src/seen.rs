@@ -0,0 +1,288 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Remembering which revisions have been processed
+//!
+//! [`SeenRevisions`] records revision IDs per wiki in a Bloom filter, which
+//! takes the same amount of memory however many revisions go in. It can
+//! report a revision that was never recorded as seen, at about the
+//! configured false positive rate, but never the other way around, so
+//! e.g. a revert bot that checks it first won't act on a revision twice.
+//!
+//! Revisions are kept in two generations of up to `capacity` each. Once
+//! the current one is full, the previous one is forgotten, so the false
+//! positive rate doesn't creep up over time and only the oldest revisions
+//! are lost. It can be [saved](SeenRevisions::save) and
+//! [loaded](SeenRevisions::load) again to survive restarts, without a
+//! database.
+use crate::dedup::DedupStatus;
+use crate::Event;
+use sha2::{Digest, Sha256};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Start of saved files, followed by the format version
+const MAGIC: &[u8; 8] = b"ESSEEN\x00\x01";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Filter {
+    words: Vec<u64>,
+    count: u64,
+}
+
+impl Filter {
+    fn new(bits: u64) -> Self {
+        Self {
+            words: vec![0; bits.div_ceil(64) as usize],
+            count: 0,
+        }
+    }
+
+    fn get(&self, bit: u64) -> bool {
+        self.words[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+    }
+
+    fn set(&mut self, bit: u64) {
+        self.words[(bit / 64) as usize] |= 1 << (bit % 64);
+    }
+}
+
+/// Probabilistic set of revisions per wiki, see the
+/// [module documentation](self)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeenRevisions {
+    capacity: u64,
+    hashes: u32,
+    bits: u64,
+    current: Filter,
+    previous: Filter,
+}
+
+impl SeenRevisions {
+    /// Remember at least the last `capacity` revisions, reporting unseen
+    /// ones as seen at about `false_positive_rate`, e.g. 0.001. Memory use
+    /// is around `capacity * 1.44 * log2(1 / false_positive_rate) / 4`
+    /// bytes, e.g. 3.6 MB for a million revisions at 0.001.
+    pub fn new(capacity: usize, false_positive_rate: f64) -> Self {
+        assert!(capacity > 0, "capacity must be positive");
+        assert!(
+            false_positive_rate > 0.0 && false_positive_rate < 1.0,
+            "false positive rate must be between 0 and 1"
+        );
+        let ln2 = std::f64::consts::LN_2;
+        let per_item = -false_positive_rate.ln() / (ln2 * ln2);
+        let bits = (capacity as f64 * per_item).ceil().max(64.0) as u64;
+        let hashes = (per_item * ln2).round().max(1.0) as u32;
+        Self {
+            capacity: capacity as u64,
+            hashes,
+            bits,
+            current: Filter::new(bits),
+            previous: Filter::new(bits),
+        }
+    }
+
+    /// Bit positions for a revision, by double hashing
+    fn positions(
+        &self,
+        wiki: &str,
+        rev_id: u64,
+    ) -> impl Iterator<Item = u64> + '_ {
+        let mut hasher = Sha256::new();
+        hasher.update(wiki.as_bytes());
+        hasher.update([0]);
+        hasher.update(rev_id.to_le_bytes());
+        let hash = hasher.finalize();
+        let first = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        let second = u64::from_le_bytes(hash[8..16].try_into().unwrap()) | 1;
+        (0..u64::from(self.hashes)).map(move |i| {
+            first.wrapping_add(i.wrapping_mul(second)) % self.bits
+        })
+    }
+
+    /// Whether `rev_id` on `wiki` (internal database name) was probably
+    /// recorded
+    pub fn contains(&self, wiki: &str, rev_id: u64) -> bool {
+        let positions: Vec<_> = self.positions(wiki, rev_id).collect();
+        positions.iter().all(|&bit| self.current.get(bit))
+            || positions.iter().all(|&bit| self.previous.get(bit))
+    }
+
+    /// Record `rev_id` on `wiki`, returning whether it was probably
+    /// recorded already
+    pub fn insert(&mut self, wiki: &str, rev_id: u64) -> bool {
+        if self.contains(wiki, rev_id) {
+            return true;
+        }
+        let positions: Vec<_> = self.positions(wiki, rev_id).collect();
+        for bit in positions {
+            self.current.set(bit);
+        }
+        self.current.count += 1;
+        if self.current.count >= self.capacity {
+            self.previous =
+                std::mem::replace(&mut self.current, Filter::new(self.bits));
+        }
+        false
+    }
+
+    /// Check whether the revision an edit, page creation or revision
+    /// event is about was probably seen before, and record it. `None` for
+    /// events without a revision.
+    pub fn check(&mut self, event: &Event) -> Option<DedupStatus> {
+        let rev_id = match event {
+            Event::Edit(edit) | Event::New(edit) => edit.revision.new.into(),
+            Event::RevisionCreate(revision) => revision.rev_id,
+            _ => return None,
+        };
+        Some(if self.insert(event.wiki(), rev_id) {
+            DedupStatus::Duplicate
+        } else {
+            DedupStatus::Unique
+        })
+    }
+
+    /// Approximate memory used, in bytes
+    pub fn memory(&self) -> usize {
+        (self.current.words.len() + self.previous.words.len()) * 8
+    }
+
+    /// Write to `path`, replacing it in one step
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut out = Vec::with_capacity(self.memory() + 48);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&self.capacity.to_le_bytes());
+        out.extend_from_slice(&self.hashes.to_le_bytes());
+        out.extend_from_slice(&self.bits.to_le_bytes());
+        for filter in [&self.current, &self.previous] {
+            out.extend_from_slice(&filter.count.to_le_bytes());
+            for word in &filter.words {
+                out.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Read what was [saved](Self::save) to `path`, or `None` if there's
+    /// nothing there yet. Settings are read from the file, so the ones it
+    /// was created with apply.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(err),
+        };
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidData, "not a saved set");
+        let mut rest = data.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        let mut take = |len: usize| -> io::Result<&[u8]> {
+            if rest.len() < len {
+                return Err(invalid());
+            }
+            let (taken, remaining) = rest.split_at(len);
+            rest = remaining;
+            Ok(taken)
+        };
+        let capacity = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let hashes = u32::from_le_bytes(take(4)?.try_into().unwrap());
+        let bits = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        if capacity == 0 || hashes == 0 || bits == 0 {
+            return Err(invalid());
+        }
+        let mut filters = vec![];
+        for _ in 0..2 {
+            let mut filter = Filter::new(bits);
+            filter.count = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            for word in &mut filter.words {
+                *word = u64::from_le_bytes(take(8)?.try_into().unwrap());
+            }
+            filters.push(filter);
+        }
+        if !rest.is_empty() {
+            return Err(invalid());
+        }
+        let previous = filters.pop().unwrap();
+        let current = filters.pop().unwrap();
+        Ok(Some(Self {
+            capacity,
+            hashes,
+            bits,
+            current,
+            previous,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn forgets_the_oldest_generation() {
+        let mut seen = SeenRevisions::new(10, 0.001);
+        for rev_id in 0..25 {
+            assert!(!seen.insert("enwiki", rev_id));
+        }
+        assert!(seen.insert("enwiki", 24));
+        assert!((10..25).all(|rev_id| seen.contains("enwiki", rev_id)));
+        assert!(!(0..10).any(|rev_id| seen.contains("enwiki", rev_id)));
+        assert!(!seen.contains("dewiki", 24));
+
+        let edit = testing::edit_event(100, "A", at(0));
+        assert_eq!(seen.check(&edit), Some(DedupStatus::Unique));
+        assert_eq!(seen.check(&edit), Some(DedupStatus::Duplicate));
+        let upload = testing::event(&testing::log(200, "File:A.png", at(0)));
+        assert_eq!(seen.check(&upload), None);
+    }
+
+    #[test]
+    fn keeps_to_its_false_positive_rate() {
+        let mut seen = SeenRevisions::new(10_000, 0.01);
+        for rev_id in 0..9_999 {
+            seen.insert("enwiki", rev_id);
+        }
+        let false_positives = (1_000_000..1_010_000)
+            .filter(|&rev_id| seen.contains("enwiki", rev_id))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let path = std::env::temp_dir()
+            .join(format!("eventstreams-seen-{}", std::process::id()));
+        assert!(SeenRevisions::load(&path).unwrap().is_none());
+        let mut seen = SeenRevisions::new(100, 0.001);
+        for rev_id in 0..150 {
+            seen.insert("enwiki", rev_id);
+        }
+        seen.save(&path).unwrap();
+        assert_eq!(SeenRevisions::load(&path).unwrap(), Some(seen));
+
+        fs::write(&path, &MAGIC[..]).unwrap();
+        let err = SeenRevisions::load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
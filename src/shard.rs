@@ -0,0 +1,245 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Splitting the stream across several processes
+//!
+//! Each process owns a disjoint [`Shard`] of wikis, based on a hash of the
+//! wiki's `server_name`. Processes can coordinate which shard each of them
+//! owns through a [`FileCoordinator`], which rebalances as processes come
+//! and go.
+use crate::clock::{Clock, SystemClock};
+use crate::pool::fnv1a;
+use crate::Event;
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Heartbeats are written to `<member ID>.tmp` first
+const TMP_SUFFIX: &str = ".tmp";
+
+/// One of `count` disjoint subsets of wikis
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl Shard {
+    pub fn new(index: usize, count: usize) -> Self {
+        assert!(index < count, "shard index must be less than count");
+        Self { index, count }
+    }
+
+    /// Whether the wiki with this `server_name` is in this shard
+    pub fn owns_wiki(&self, server_name: &str) -> bool {
+        fnv1a(server_name.as_bytes()) % self.count as u64 == self.index as u64
+    }
+
+    /// Whether the event's wiki is in this shard
+    pub fn owns(&self, event: &Event) -> bool {
+        self.owns_wiki(event.server_name())
+    }
+}
+
+/// Coordinates shards between processes sharing a directory, e.g. on NFS.
+/// Every process regularly writes a heartbeat file; the processes with a
+/// recent heartbeat are the members, and each owns the shard matching its
+/// position in the sorted list of member IDs.
+#[derive(Debug)]
+pub struct FileCoordinator {
+    dir: PathBuf,
+    member_id: String,
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl FileCoordinator {
+    /// Coordinate through `dir` as `member_id`, which must be unique among
+    /// the processes and usable as a file name not ending in `.tmp`.
+    /// Members that haven't sent a heartbeat within `ttl` are considered
+    /// gone.
+    pub fn new(
+        dir: impl Into<PathBuf>,
+        member_id: impl Into<String>,
+        ttl: Duration,
+    ) -> io::Result<Self> {
+        let member_id = member_id.into();
+        let mut components = Path::new(&member_id).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(name)), None) if name == member_id.as_str()
+        ) || member_id.ends_with(TMP_SUFFIX)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid member ID {:?}", member_id),
+            ));
+        }
+        Ok(Self {
+            dir: dir.into(),
+            member_id,
+            ttl,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Record that this member is alive, and work out which shard it owns
+    pub fn heartbeat(&self) -> io::Result<Shard> {
+        fs::create_dir_all(&self.dir)?;
+        let now = self.clock.now();
+        // Write to a temporary file first so other members never read a
+        // truncated heartbeat
+        let tmp = self.dir.join(format!("{}{}", self.member_id, TMP_SUFFIX));
+        fs::write(&tmp, now.to_rfc3339())?;
+        fs::rename(&tmp, self.dir.join(&self.member_id))?;
+        let cutoff = chrono::Duration::from_std(self.ttl)
+            .ok()
+            .and_then(|ttl| now.checked_sub_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+        let mut members = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(TMP_SUFFIX) {
+                continue;
+            }
+            let alive = fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|contents| {
+                    DateTime::parse_from_rfc3339(contents.trim()).ok()
+                })
+                .is_some_and(|last| last.with_timezone(&Utc) >= cutoff);
+            if alive || name == self.member_id {
+                members.push(name);
+            }
+        }
+        members.sort();
+        let index = members
+            .iter()
+            .position(|member| member == &self.member_id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "heartbeat file disappeared",
+                )
+            })?;
+        Ok(Shard::new(index, members.len()))
+    }
+
+    /// Remove this member, so the others take over its share right away
+    pub fn leave(&self) -> io::Result<()> {
+        fs::remove_file(self.dir.join(&self.member_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use chrono::TimeZone;
+
+    fn coordinator_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "eventstreams-shard-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn shards_wikis_disjointly() {
+        let shards = [Shard::new(0, 3), Shard::new(1, 3), Shard::new(2, 3)];
+        for wiki in &["en.wikipedia.org", "de.wikipedia.org", "commons"] {
+            let owners = shards.iter().filter(|s| s.owns_wiki(wiki)).count();
+            assert_eq!(owners, 1);
+        }
+    }
+
+    #[test]
+    fn rebalances_as_members_come_and_go() {
+        let dir = coordinator_dir("rebalance");
+        let clock = ManualClock::new(
+            Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap(),
+        );
+        let ttl = Duration::from_secs(60);
+        let a = FileCoordinator::new(&dir, "a", ttl)
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        let b = FileCoordinator::new(&dir, "b", ttl)
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 1));
+        assert_eq!(b.heartbeat().unwrap(), Shard::new(1, 2));
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 2));
+        // b stops sending heartbeats, so a takes over
+        clock.advance(Duration::from_secs(61));
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 1));
+        assert_eq!(b.heartbeat().unwrap(), Shard::new(1, 2));
+        b.leave().unwrap();
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_heartbeats_being_written() {
+        let dir = coordinator_dir("tmp");
+        let a =
+            FileCoordinator::new(&dir, "a", Duration::from_secs(60)).unwrap();
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 1));
+        assert!(!dir.join("a.tmp").exists());
+        // Another member in the middle of writing its heartbeat
+        fs::write(dir.join("b.tmp"), "").unwrap();
+        assert_eq!(a.heartbeat().unwrap(), Shard::new(0, 1));
+        assert!(FileCoordinator::new(&dir, "b.tmp", Duration::ZERO).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn accepts_huge_ttls() {
+        let dir = coordinator_dir("huge-ttl");
+        let coordinator =
+            FileCoordinator::new(&dir, "a", Duration::MAX).unwrap();
+        assert_eq!(coordinator.heartbeat().unwrap(), Shard::new(0, 1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_ids_that_are_not_file_names() {
+        let ttl = Duration::from_secs(60);
+        for id in &["", ".", "..", "a/b", "/a"] {
+            let err = FileCoordinator::new("shards", *id, ttl).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
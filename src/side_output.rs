@@ -0,0 +1,204 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Side outputs for events that were excluded
+//!
+//! Instead of silently dropping events that couldn't be parsed or didn't
+//! match a filter, they can be sent to a [`SideOutput`] for auditing.
+use crate::drops::DropLogger;
+use crate::metrics::{Metrics, Reporter};
+use crate::Event;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// An event that was excluded from the main stream, and why
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Excluded {
+    /// The raw event data couldn't be turned into an [`Event`]
+    Malformed { data: String, reason: String },
+    /// The event was removed by a filter
+    Filtered { event: Event, reason: String },
+}
+
+#[derive(Clone, Debug)]
+enum Sender {
+    Unbounded(mpsc::UnboundedSender<Excluded>),
+    // Shared between clones, so the capacity is for the side output as a
+    // whole
+    Bounded(Arc<Mutex<mpsc::Sender<Excluded>>>),
+}
+
+/// A named channel that excluded events are sent to
+#[derive(Clone, Debug)]
+pub struct SideOutput {
+    name: String,
+    sender: Sender,
+    dropped: Arc<AtomicU64>,
+    reporter: Option<Reporter>,
+    drop_logger: Option<DropLogger>,
+}
+
+impl SideOutput {
+    /// Create a new side output, along with the stream excluded events can
+    /// be read from. It's unbounded, unless
+    /// [bounded mode](crate::metrics::set_bounded) is on.
+    pub fn new(
+        name: impl Into<String>,
+    ) -> (Self, impl Stream<Item = Excluded>) {
+        if let Some(limit) = crate::metrics::bounded() {
+            let (side_output, receiver) = Self::bounded(name, limit);
+            return (side_output, receiver.left_stream());
+        }
+        let (sender, receiver) = mpsc::unbounded();
+        (
+            Self::with_sender(name, Sender::Unbounded(sender)),
+            receiver.right_stream(),
+        )
+    }
+
+    /// Like [`new()`](SideOutput::new), but holding at most `capacity`
+    /// unread events, or the [bounded mode](crate::metrics::set_bounded)
+    /// cap if that's lower. Once full, further events are dropped and
+    /// counted in [`dropped()`](SideOutput::dropped).
+    pub fn bounded(
+        name: impl Into<String>,
+        capacity: usize,
+    ) -> (Self, impl Stream<Item = Excluded>) {
+        let capacity = crate::metrics::cap(capacity);
+        // The channel always has room for one more per sender
+        let (sender, receiver) = mpsc::channel(capacity.saturating_sub(1));
+        (
+            Self::with_sender(
+                name,
+                Sender::Bounded(Arc::new(Mutex::new(sender))),
+            ),
+            receiver,
+        )
+    }
+
+    fn with_sender(name: impl Into<String>, sender: Sender) -> Self {
+        Self {
+            name: name.into(),
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+            reporter: None,
+            drop_logger: None,
+        }
+    }
+
+    /// Record dropped events with `drop_logger`
+    pub fn with_drop_logger(mut self, drop_logger: DropLogger) -> Self {
+        self.drop_logger = Some(drop_logger);
+        self
+    }
+
+    /// Count dropped events in `metrics`, as `<name>.dropped`
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.reporter = Some(Reporter::new(metrics, self.name.clone()));
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of events dropped because a bounded side output was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Send an excluded event. If nobody is reading the side output
+    /// anymore, it is dropped.
+    pub fn send(&self, excluded: Excluded) {
+        match &self.sender {
+            Sender::Unbounded(sender) => {
+                let _ = sender.unbounded_send(excluded);
+            }
+            Sender::Bounded(sender) => {
+                if let Err(err) = sender.lock().unwrap().try_send(excluded) {
+                    if err.is_full() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        if let Some(reporter) = &self.reporter {
+                            reporter.dropped();
+                        }
+                        if let Some(drop_logger) = &self.drop_logger {
+                            let wiki = match err.into_inner() {
+                                Excluded::Filtered { event, .. } => {
+                                    Some(event.server_name().to_string())
+                                }
+                                Excluded::Malformed { .. } => None,
+                            };
+                            drop_logger.record(
+                                &format!("side output {} full", self.name),
+                                wiki.as_deref(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, edit_event};
+    use crate::EventStreamExt;
+    use futures::executor::block_on;
+
+    #[test]
+    fn receives_filtered_events() {
+        let (side_output, excluded) = SideOutput::new("talk");
+        let events = vec![
+            edit_event(1, "A", at(0)),
+            edit_event(2, "Talk:A", at(0)),
+            edit_event(3, "B", at(0)),
+        ];
+        let kept: Vec<_> = block_on(
+            futures::stream::iter(events)
+                .filter_with_side_output(side_output, "talk page", |event| {
+                    !event.title().starts_with("Talk:")
+                })
+                .map(|event| event.title().to_string())
+                .collect(),
+        );
+        assert_eq!(kept, ["A", "B"]);
+        let excluded: Vec<_> = block_on(excluded.collect());
+        assert!(matches!(
+            excluded.as_slice(),
+            [Excluded::Filtered { event, reason }]
+                if event.title() == "Talk:A" && reason == "talk page"
+        ));
+    }
+
+    #[test]
+    fn bounded_side_outputs_count_what_they_drop() {
+        let (side_output, excluded) = SideOutput::bounded("malformed", 2);
+        for _ in 0..3 {
+            side_output.send(Excluded::Malformed {
+                data: "{".to_string(),
+                reason: "EOF".to_string(),
+            });
+        }
+        assert_eq!(side_output.dropped(), 1);
+        drop(side_output);
+        assert_eq!(block_on(excluded.count()), 2);
+    }
+}
@@ -0,0 +1,258 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Signing events that are relayed to other consumers
+//!
+//! When events are re-broadcast, e.g. through a webhook, consumers
+//! downstream have to trust every intermediary along the way. Signing the
+//! serialized event with a [`SigningKey`] lets them check instead that it
+//! came unchanged from whoever holds the key, using the matching
+//! [`VerifyingKey`].
+//!
+//! Signatures are formatted as `<algorithm>=<hex>`, e.g.
+//! `hmac-sha256=5d41...`, and are meant to be sent in the
+//! [`SIGNATURE_HEADER`] header alongside the payload.
+//!
+//! ```
+//! use eventstreams::signing::{SigningKey, VerifyingKey};
+//!
+//! let payload = br#"{"type":"edit"}"#;
+//! let signature = SigningKey::hmac("secret").sign(payload);
+//! assert!(VerifyingKey::hmac("secret").verify(payload, &signature).is_ok());
+//! ```
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+
+/// Header that relays send signatures in
+pub const SIGNATURE_HEADER: &str = "X-EventStreams-Signature";
+
+const HMAC_SHA256: &str = "hmac-sha256";
+const ED25519: &str = "ed25519";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The signature isn't `<algorithm>=<hex>`
+    Malformed,
+    /// The signature was made with a different algorithm than the key is
+    /// for
+    WrongAlgorithm(String),
+    /// The signature doesn't match the payload
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed signature"),
+            Self::WrongAlgorithm(algorithm) => {
+                write!(f, "unexpected signature algorithm: {}", algorithm)
+            }
+            Self::Mismatch => write!(f, "signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Key for signing payloads, kept by the relay
+#[derive(Clone)]
+pub enum SigningKey {
+    /// Shared secret, also needed to verify signatures
+    Hmac(Vec<u8>),
+    /// Private key, whose public key can be given out for verifying
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+impl SigningKey {
+    pub fn hmac(secret: impl AsRef<[u8]>) -> Self {
+        Self::Hmac(secret.as_ref().to_vec())
+    }
+
+    pub fn ed25519(secret: &[u8; 32]) -> Self {
+        Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(secret))
+    }
+
+    /// Key that consumers can verify signatures with
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            Self::Hmac(secret) => VerifyingKey::Hmac(secret.clone()),
+            Self::Ed25519(key) => VerifyingKey::Ed25519(key.verifying_key()),
+        }
+    }
+
+    /// Sign `payload`, e.g. an event serialized as JSON
+    pub fn sign(&self, payload: &[u8]) -> String {
+        match self {
+            Self::Hmac(secret) => format!(
+                "{}={}",
+                HMAC_SHA256,
+                to_hex(&hmac(secret, payload).finalize().into_bytes())
+            ),
+            Self::Ed25519(key) => {
+                format!("{}={}", ED25519, to_hex(&key.sign(payload).to_bytes()))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the secret into logs
+        match self {
+            Self::Hmac(_) => write!(f, "SigningKey::Hmac(..)"),
+            Self::Ed25519(_) => write!(f, "SigningKey::Ed25519(..)"),
+        }
+    }
+}
+
+/// Key for checking signatures, held by consumers
+#[derive(Clone)]
+pub enum VerifyingKey {
+    Hmac(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl VerifyingKey {
+    pub fn hmac(secret: impl AsRef<[u8]>) -> Self {
+        Self::Hmac(secret.as_ref().to_vec())
+    }
+
+    /// Public key, as given out by the relay
+    pub fn ed25519(public: &[u8; 32]) -> Result<Self, SignatureError> {
+        ed25519_dalek::VerifyingKey::from_bytes(public)
+            .map(Self::Ed25519)
+            .map_err(|_| SignatureError::Malformed)
+    }
+
+    /// Check that `signature`, as created by [`SigningKey::sign()`], was
+    /// made for `payload` with this key
+    pub fn verify(
+        &self,
+        payload: &[u8],
+        signature: &str,
+    ) -> Result<(), SignatureError> {
+        let (algorithm, hex) =
+            signature.split_once('=').ok_or(SignatureError::Malformed)?;
+        let bytes = from_hex(hex).ok_or(SignatureError::Malformed)?;
+        match self {
+            Self::Hmac(secret) if algorithm == HMAC_SHA256 => {
+                hmac(secret, payload)
+                    .verify_slice(&bytes)
+                    .map_err(|_| SignatureError::Mismatch)
+            }
+            Self::Ed25519(key) if algorithm == ED25519 => {
+                let signature = ed25519_dalek::Signature::from_slice(&bytes)
+                    .map_err(|_| SignatureError::Malformed)?;
+                key.verify(payload, &signature)
+                    .map_err(|_| SignatureError::Mismatch)
+            }
+            _ => Err(SignatureError::WrongAlgorithm(algorithm.to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hmac(_) => write!(f, "VerifyingKey::Hmac(..)"),
+            Self::Ed25519(key) => f
+                .debug_tuple("VerifyingKey::Ed25519")
+                .field(&to_hex(key.as_bytes()))
+                .finish(),
+        }
+    }
+}
+
+fn hmac(secret: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac() {
+        // RFC 4231, test case 2
+        let key = SigningKey::hmac("Jefe");
+        let payload = b"what do ya want for nothing?";
+        let signature = key.sign(payload);
+        assert_eq!(
+            signature,
+            "hmac-sha256=5bdcc146bf60754e6a042426089575c7\
+             5a003f089d2739839dec58b964ec3843"
+        );
+        let verifying = key.verifying_key();
+        assert_eq!(verifying.verify(payload, &signature), Ok(()));
+        assert_eq!(
+            verifying.verify(b"something else", &signature),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            VerifyingKey::hmac("Jeff").verify(payload, &signature),
+            Err(SignatureError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn signs_with_ed25519() {
+        let key = SigningKey::ed25519(&[7; 32]);
+        let payload = br#"{"type":"edit"}"#;
+        let signature = key.sign(payload);
+        assert!(signature.starts_with("ed25519="));
+        let public = match key.verifying_key() {
+            VerifyingKey::Ed25519(public) => public.to_bytes(),
+            VerifyingKey::Hmac(_) => unreachable!(),
+        };
+        let verifying = VerifyingKey::ed25519(&public).unwrap();
+        assert_eq!(verifying.verify(payload, &signature), Ok(()));
+        assert_eq!(
+            verifying.verify(br#"{"type":"log"}"#, &signature),
+            Err(SignatureError::Mismatch)
+        );
+        assert_eq!(
+            verifying.verify(payload, &SigningKey::hmac("x").sign(payload)),
+            Err(SignatureError::WrongAlgorithm("hmac-sha256".to_string()))
+        );
+        assert_eq!(
+            verifying.verify(payload, "ed25519=zz"),
+            Err(SignatureError::Malformed)
+        );
+        assert_eq!(
+            verifying.verify(payload, "nonsense"),
+            Err(SignatureError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,778 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Destinations that events can be forwarded to
+//!
+//! Sinks that write whole events out, like [`WriterSink`], encode them
+//! with a [`Serializer`], so the wire format is picked once and shared
+//! between them. Sinks and re-broadcast servers that aren't given one
+//! use the [default](set_serializer), which is [`Json`] unless changed.
+//!
+//! An [`AgeRouter`] picks a sink by how old each event is, e.g. so fresh
+//! events go to alerting while the backlog replayed after an outage goes
+//! straight to an archive.
+//!
+//! A [`ConcurrentSink`] hands events to an async handler, e.g. one making
+//! API calls, with at most a set number of calls in flight at once:
+//!
+//! ```no_run
+//! use eventstreams::sink::{ConcurrentSink, SinkError};
+//! use eventstreams::subscription::Subscription;
+//!
+//! let sink = ConcurrentSink::new(4, |event| async move {
+//!     // Look something up about the event
+//!     Ok::<_, SinkError>(())
+//! });
+//! let subscription = Subscription::new("lookups", |_| true, sink);
+//! ```
+use crate::clock::{Clock, SystemClock};
+use crate::Event;
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture};
+use futures::{SinkExt as _, StreamExt};
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Default number of events a [`ConcurrentSink`] holds while all of its
+/// handlers are busy
+const DEFAULT_QUEUE: usize = 1024;
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Somewhere events are delivered to
+pub trait Sink: Send {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>>;
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        (**self).send(event)
+    }
+}
+
+/// Prints a one-line summary of each event
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        println!("{}", summary(event));
+        Box::pin(future::ready(Ok(())))
+    }
+}
+
+/// One-line summary of an event, e.g. `en.wikipedia.org: Foo edited Bar`
+pub(crate) fn summary(event: &Event) -> String {
+    match event {
+        Event::Edit(edit) => format!(
+            "{}: {} edited {}",
+            &edit.server_name, &edit.user, &edit.title
+        ),
+        Event::New(new) => format!(
+            "{}: {} created {}",
+            &new.server_name, &new.user, &new.title
+        ),
+        Event::Log(log) => format!(
+            "{}: {} performed {}/{} on {}",
+            &log.server_name,
+            &log.user,
+            &log.log_type,
+            &log.log_action,
+            &log.title
+        ),
+        Event::Categorize(categorize) => format!(
+            "{}: {} changed membership of {}",
+            &categorize.server_name, &categorize.user, &categorize.title
+        ),
+        Event::External(external) => format!(
+            "{}: {} changed {} externally",
+            &external.server_name, &external.user, &external.title
+        ),
+        Event::RevisionCreate(revision) => format!(
+            "{}: {} saved revision {} of {}",
+            event.server_name(),
+            event.user(),
+            revision.rev_id,
+            &revision.page_title
+        ),
+        Event::PageCreate(page) => format!(
+            "{}: {} created {}",
+            event.server_name(),
+            event.user(),
+            &page.page_title
+        ),
+        Event::PageDelete(delete) => format!(
+            "{}: {} deleted {}",
+            event.server_name(),
+            event.user(),
+            &delete.page_title
+        ),
+        Event::PageUndelete(undelete) => format!(
+            "{}: {} undeleted {}",
+            event.server_name(),
+            event.user(),
+            &undelete.page_title
+        ),
+        Event::PageMove(move_) => format!(
+            "{}: {} moved {} to {}",
+            event.server_name(),
+            event.user(),
+            &move_.prior_state.page_title,
+            &move_.page_title
+        ),
+        Event::PageLinksChange(links) => format!(
+            "{}: links changed on {}",
+            event.server_name(),
+            &links.page_title
+        ),
+        Event::RevisionScore(score) => format!(
+            "{}: revision {} of {} was scored",
+            event.server_name(),
+            score.rev_id,
+            &score.page_title
+        ),
+        Event::Test(test) => format!("{}: test event", &test.meta.domain),
+        Event::Extension(extension) => {
+            format!("{}: {} event", extension.domain(), extension.stream())
+        }
+        Event::Unknown(unknown) => {
+            format!("{}: {} event", event.server_name(), unknown.stream())
+        }
+    }
+}
+
+/// Where to see the change, if there's a single page for it
+pub(crate) fn link(event: &Event) -> Option<String> {
+    match event {
+        Event::Edit(edit) => Some(edit.diff_url()),
+        Event::New(new) => Some(new.short_diff_url()),
+        _ => None,
+    }
+}
+
+/// Escape `text` for use in HTML
+pub(crate) fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Encodes events for sinks that write them out. Encoded events are
+/// written back to back, so each one has to be self-delimiting.
+pub trait Serializer: Send + Sync {
+    /// MIME type of the encoding, e.g. for a `Content-Type` header
+    fn content_type(&self) -> &'static str;
+
+    /// Extension for files of encoded events, e.g. `ndjson`
+    fn extension(&self) -> &'static str {
+        "bin"
+    }
+
+    fn serialize(&self, event: &Event) -> Result<Vec<u8>, SinkError>;
+}
+
+fn default_serializer() -> &'static RwLock<Arc<dyn Serializer>> {
+    static DEFAULT: std::sync::OnceLock<RwLock<Arc<dyn Serializer>>> =
+        std::sync::OnceLock::new();
+    DEFAULT.get_or_init(|| RwLock::new(Arc::new(Json)))
+}
+
+/// Use `serializer` for every sink and re-broadcast server created from
+/// now on that isn't given one of its own, e.g.
+/// [`Recorder`](crate::recorder::Recorder) and the relay. The default is
+/// [`Json`].
+pub fn set_serializer(serializer: Arc<dyn Serializer>) {
+    *default_serializer().write().unwrap() = serializer;
+}
+
+/// The serializer set with [`set_serializer()`]
+pub fn serializer() -> Arc<dyn Serializer> {
+    default_serializer().read().unwrap().clone()
+}
+
+/// Each event as a line of JSON, making up NDJSON. Events whose
+/// [raw message](Event::raw) was kept are written exactly as received,
+/// others are serialized back to JSON, which may order or format fields
+/// differently.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Json;
+
+impl Serializer for Json {
+    fn content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+
+    fn extension(&self) -> &'static str {
+        "ndjson"
+    }
+
+    fn serialize(&self, event: &Event) -> Result<Vec<u8>, SinkError> {
+        let mut bytes = match event.raw() {
+            // A raw message spanning lines would break up the NDJSON
+            Some(raw) if !raw.contains('\n') => raw.as_bytes().to_vec(),
+            _ => serde_json::to_vec(event)?,
+        };
+        bytes.push(b'\n');
+        Ok(bytes)
+    }
+}
+
+/// Each event as MessagePack, with field names kept so that it decodes
+/// like the JSON
+#[cfg(feature = "msgpack")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Serializer for MessagePack {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn extension(&self) -> &'static str {
+        "msgpack"
+    }
+
+    fn serialize(&self, event: &Event) -> Result<Vec<u8>, SinkError> {
+        Ok(rmp_serde::to_vec_named(event)?)
+    }
+}
+
+/// Each event as CBOR
+#[cfg(feature = "cbor")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Serializer for Cbor {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn extension(&self) -> &'static str {
+        "cbor"
+    }
+
+    fn serialize(&self, event: &Event) -> Result<Vec<u8>, SinkError> {
+        let mut bytes = vec![];
+        ciborium::into_writer(event, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Each event as a [`google.protobuf.Struct`] holding its JSON fields,
+/// prefixed with its length as a varint, like protobuf's own
+/// `writeDelimitedTo()`. Any protobuf library can decode it with the
+/// well-known types, without a schema for each kind of event. As in JSON,
+/// numbers are doubles, so integers beyond 2^53 lose precision.
+///
+/// [`google.protobuf.Struct`]: https://protobuf.dev/reference/protobuf/google.protobuf/#struct
+#[cfg(feature = "protobuf")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Protobuf;
+
+#[cfg(feature = "protobuf")]
+impl Serializer for Protobuf {
+    fn content_type(&self) -> &'static str {
+        "application/x-protobuf"
+    }
+
+    fn extension(&self) -> &'static str {
+        "pb"
+    }
+
+    fn serialize(&self, event: &Event) -> Result<Vec<u8>, SinkError> {
+        let fields = match serde_json::to_value(event)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => return Err("event isn't a JSON object".into()),
+        };
+        let message = protobuf::encode_struct(&fields);
+        let mut bytes = Vec::with_capacity(message.len() + 5);
+        protobuf::put_varint(&mut bytes, message.len() as u64);
+        bytes.extend_from_slice(&message);
+        Ok(bytes)
+    }
+}
+
+/// Encoding of the `google.protobuf.Struct` well-known types
+#[cfg(feature = "protobuf")]
+mod protobuf {
+    use serde_json::{Map, Value};
+
+    const VARINT: u8 = 0;
+    const FIXED64: u8 = 1;
+    const LENGTH_DELIMITED: u8 = 2;
+
+    pub(super) fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn put_tag(out: &mut Vec<u8>, field: u8, wire_type: u8) {
+        out.push(field << 3 | wire_type);
+    }
+
+    fn put_bytes(out: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+        put_tag(out, field, LENGTH_DELIMITED);
+        put_varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    /// `Struct`, a map of field names to `Value`s in field 1
+    pub(super) fn encode_struct(fields: &Map<String, Value>) -> Vec<u8> {
+        let mut out = vec![];
+        for (name, value) in fields {
+            let mut entry = vec![];
+            put_bytes(&mut entry, 1, name.as_bytes());
+            put_bytes(&mut entry, 2, &encode_value(value));
+            put_bytes(&mut out, 1, &entry);
+        }
+        out
+    }
+
+    /// `Value`, with one of its fields set depending on the kind
+    fn encode_value(value: &Value) -> Vec<u8> {
+        let mut out = vec![];
+        match value {
+            Value::Null => {
+                put_tag(&mut out, 1, VARINT);
+                put_varint(&mut out, 0);
+            }
+            Value::Number(number) => {
+                put_tag(&mut out, 2, FIXED64);
+                let number = number.as_f64().unwrap_or(f64::NAN);
+                out.extend_from_slice(&number.to_le_bytes());
+            }
+            Value::String(string) => put_bytes(&mut out, 3, string.as_bytes()),
+            Value::Bool(boolean) => {
+                put_tag(&mut out, 4, VARINT);
+                put_varint(&mut out, *boolean as u64);
+            }
+            Value::Object(fields) => {
+                put_bytes(&mut out, 5, &encode_struct(fields))
+            }
+            Value::Array(values) => {
+                // `ListValue`, with every `Value` in field 1
+                let mut list = vec![];
+                for value in values {
+                    put_bytes(&mut list, 1, &encode_value(value));
+                }
+                put_bytes(&mut out, 6, &list);
+            }
+        }
+        out
+    }
+}
+
+/// Writes every event to `W`, e.g. a file or socket, encoded with a
+/// [`Serializer`]
+pub struct WriterSink<W> {
+    writer: W,
+    serializer: Arc<dyn Serializer>,
+}
+
+impl<W: Write + Send> WriterSink<W> {
+    pub fn new(writer: W, serializer: Arc<dyn Serializer>) -> Self {
+        Self { writer, serializer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Send> Sink for WriterSink<W> {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        let written = self
+            .serializer
+            .serialize(event)
+            .and_then(|bytes| Ok(self.writer.write_all(&bytes)?));
+        Box::pin(future::ready(written))
+    }
+}
+
+/// Wraps a closure as a [`Sink`]
+pub struct FnSink<F>(pub F);
+
+impl<F> Sink for FnSink<F>
+where
+    F: FnMut(&Event) -> Result<(), SinkError> + Send,
+{
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(future::ready((self.0)(event)))
+    }
+}
+
+/// Sends each event to the first tier it's young enough for, going by
+/// `meta.dt`, and older ones to a fallback sink
+pub struct AgeRouter {
+    /// Sorted by maximum age, youngest first
+    tiers: Vec<(Duration, Box<dyn Sink>)>,
+    fallback: Box<dyn Sink>,
+    clock: Arc<dyn Clock>,
+}
+
+impl AgeRouter {
+    /// Send events to `fallback` unless a [tier](Self::tier) takes them
+    pub fn new(fallback: impl Sink + 'static) -> Self {
+        Self {
+            tiers: vec![],
+            fallback: Box::new(fallback),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Send events less than `max_age` old to `sink`, unless a tier with a
+    /// lower maximum age takes them. Events from the future, e.g. due to
+    /// clock skew, count as fresh.
+    pub fn tier(
+        mut self,
+        max_age: Duration,
+        sink: impl Sink + 'static,
+    ) -> Self {
+        let index = self.tiers.partition_point(|(age, _)| *age <= max_age);
+        self.tiers.insert(index, (max_age, Box::new(sink)));
+        self
+    }
+
+    /// Measure the age of events with `clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Index of the tier the event goes to, or `None` for the fallback
+    pub fn route(&self, event: &Event) -> Option<usize> {
+        let age = (self.clock.now() - event.dt()).to_std().unwrap_or_default();
+        self.tiers.iter().position(|(max_age, _)| age < *max_age)
+    }
+}
+
+impl Sink for AgeRouter {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        match self.route(event) {
+            Some(index) => self.tiers[index].1.send(event),
+            None => self.fallback.send(event),
+        }
+    }
+}
+
+impl std::fmt::Debug for AgeRouter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tiers: Vec<_> = self.tiers.iter().map(|(age, _)| age).collect();
+        f.debug_struct("AgeRouter").field("tiers", &tiers).finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    queued: AtomicUsize,
+    in_flight: AtomicUsize,
+    errors: AtomicU64,
+}
+
+/// Calls an async handler for each event, running up to `limit` calls at
+/// once. Further events wait in a queue, and once that's full, sending
+/// waits for room, so bursts slow the subscription down rather than pile
+/// up unbounded work.
+///
+/// Handlers run on a thread of the sink's own, so they can't rely on a
+/// particular async runtime being around. Sending succeeds once the event
+/// is queued; errors from the handler are logged and counted in
+/// [`errors()`](Self::errors).
+pub struct ConcurrentSink {
+    /// `None` once shut down
+    sender: Option<mpsc::Sender<Arc<Event>>>,
+    counts: Arc<Counts>,
+    limit: usize,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ConcurrentSink {
+    /// Run at most `limit` calls of `handler` at once (at least 1),
+    /// queueing up to 1024 events beyond that
+    pub fn new<F, Fut>(limit: usize, handler: F) -> Self
+    where
+        F: Fn(Arc<Event>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), SinkError>> + 'static,
+    {
+        Self::with_queue(limit, DEFAULT_QUEUE, handler)
+    }
+
+    /// Like [`new()`](Self::new), queueing up to `queue` events, or fewer
+    /// in [bounded mode](crate::metrics::set_bounded)
+    pub fn with_queue<F, Fut>(limit: usize, queue: usize, handler: F) -> Self
+    where
+        F: Fn(Arc<Event>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), SinkError>> + 'static,
+    {
+        let limit = limit.max(1);
+        let queue = crate::metrics::cap(queue);
+        // The channel always has room for one more per sender
+        let (sender, receiver) = mpsc::channel(queue.saturating_sub(1));
+        let counts = Arc::new(Counts::default());
+        let thread = {
+            let counts = counts.clone();
+            thread::Builder::new()
+                .name("eventstreams-handler".to_string())
+                .spawn(move || {
+                    futures::executor::block_on(receiver.for_each_concurrent(
+                        limit,
+                        |event| {
+                            counts.queued.fetch_sub(1, Ordering::Relaxed);
+                            counts.in_flight.fetch_add(1, Ordering::Relaxed);
+                            let handled = handler(event);
+                            let counts = counts.clone();
+                            async move {
+                                if let Err(err) = handled.await {
+                                    counts
+                                        .errors
+                                        .fetch_add(1, Ordering::Relaxed);
+                                    log::warn!(
+                                        target: "eventstreams::sink",
+                                        "handler failed: {}",
+                                        err
+                                    );
+                                }
+                                counts
+                                    .in_flight
+                                    .fetch_sub(1, Ordering::Relaxed);
+                            }
+                        },
+                    ))
+                })
+                .expect("failed to spawn handler thread")
+        };
+        Self {
+            sender: Some(sender),
+            counts,
+            limit,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Number of handler calls currently running
+    pub fn in_flight(&self) -> usize {
+        self.counts.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Number of events waiting for a handler call to finish
+    pub fn queued(&self) -> usize {
+        self.counts.queued.load(Ordering::Relaxed)
+    }
+
+    /// Number of handler calls that failed
+    pub fn errors(&self) -> u64 {
+        self.counts.errors.load(Ordering::Relaxed)
+    }
+
+    /// Stop taking events, and wait for the queued ones to be handled
+    pub fn shutdown(mut self) {
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Sink for ConcurrentSink {
+    fn send<'a>(
+        &'a mut self,
+        event: &'a Event,
+    ) -> BoxFuture<'a, Result<(), SinkError>> {
+        Box::pin(async move {
+            let sender = self.sender.as_mut().ok_or("sink was shut down")?;
+            self.counts.queued.fetch_add(1, Ordering::Relaxed);
+            if sender.send(Arc::new(event.clone())).await.is_err() {
+                self.counts.queued.fetch_sub(1, Ordering::Relaxed);
+                return Err("handler thread stopped".into());
+            }
+            Ok(())
+        })
+    }
+}
+
+impl std::fmt::Debug for ConcurrentSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConcurrentSink")
+            .field("limit", &self.limit)
+            .field("counts", &self.counts)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use serde_json::Value;
+
+    fn messages() -> Vec<Value> {
+        let mut messages =
+            vec![testing::edit(1, "A", at(0)), testing::log(2, "B", at(0))];
+        for message in &mut messages {
+            // As EventStreams sends it
+            message["meta"]["dt"] = "2021-01-01T00:00:00Z".into();
+        }
+        messages
+    }
+
+    #[test]
+    fn writes_events_as_ndjson() {
+        let mut sink = WriterSink::new(vec![], Arc::new(Json));
+        for message in messages() {
+            block_on(sink.send(&testing::event(&message))).unwrap();
+        }
+        let written = String::from_utf8(sink.into_inner()).unwrap();
+        let lines: Vec<Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines, messages());
+        assert!(written.ends_with('\n'));
+    }
+
+    #[test]
+    fn routes_events_by_age() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let sink = |name: &'static str| {
+            let seen = seen.clone();
+            FnSink(move |event: &Event| {
+                let routed = format!("{} {}", name, event.title());
+                seen.lock().unwrap().push(routed);
+                Ok(())
+            })
+        };
+        let clock = Arc::new(crate::clock::ManualClock::new(at(3600)));
+        // Tiers can be added in any order
+        let mut router = AgeRouter::new(sink("cold"))
+            .tier(Duration::from_secs(3600), sink("warm"))
+            .tier(Duration::from_secs(60), sink("hot"))
+            .clock(clock);
+        for (offset, &(title, seconds)) in
+            [("A", 3590), ("B", 3000), ("C", 0), ("D", 3700)]
+                .iter()
+                .enumerate()
+        {
+            let edit = testing::edit(offset as u64, title, at(seconds));
+            block_on(router.send(&testing::event(&edit))).unwrap();
+        }
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["hot A", "warm B", "cold C", "hot D"]
+        );
+    }
+
+    #[test]
+    fn limits_handler_calls_in_flight() {
+        let (permits, gate) = mpsc::unbounded::<()>();
+        let gate = Arc::new(futures::lock::Mutex::new(gate));
+        let handled = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut sink = {
+            let handled = handled.clone();
+            ConcurrentSink::new(2, move |event: Arc<Event>| {
+                let (gate, handled) = (gate.clone(), handled.clone());
+                async move {
+                    gate.lock().await.next().await;
+                    handled.lock().unwrap().push(event.title().to_string());
+                    if event.title() == "E" {
+                        return Err("no such page".into());
+                    }
+                    Ok(())
+                }
+            })
+        };
+        for (offset, title) in ["A", "B", "C", "D", "E"].iter().enumerate() {
+            let edit = testing::edit(offset as u64, title, at(0));
+            block_on(sink.send(&testing::event(&edit))).unwrap();
+        }
+        let started = std::time::Instant::now();
+        while (sink.in_flight(), sink.queued()) != (2, 3) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(1));
+        }
+        for _ in 0..5 {
+            permits.unbounded_send(()).unwrap();
+        }
+        let counts = sink.counts.clone();
+        sink.shutdown();
+        let mut handled = handled.lock().unwrap().clone();
+        handled.sort();
+        assert_eq!(handled, ["A", "B", "C", "D", "E"]);
+        assert_eq!(counts.errors.load(Ordering::Relaxed), 1);
+        assert_eq!(counts.in_flight.load(Ordering::Relaxed), 0);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn writes_events_as_msgpack() {
+        for message in messages() {
+            let bytes = MessagePack.serialize(&testing::event(&message));
+            let decoded: Value =
+                rmp_serde::from_slice(&bytes.unwrap()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn writes_events_as_cbor() {
+        for message in messages() {
+            let bytes = Cbor.serialize(&testing::event(&message)).unwrap();
+            let decoded: Value = ciborium::from_reader(&bytes[..]).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Reproducible train/validation/test splits of recorded events
+//!
+//! Each event is assigned a [`Partition`] from a stable hash of its
+//! `meta.id`, so the same event always lands in the same partition, across
+//! runs and machines and in overlapping recordings. An
+//! [`Archive`](crate::archive::Archive) can be
+//! [split](crate::archive::Archive::split) into one NDJSON file per
+//! partition, e.g. to build a labeled dataset from a captured stream.
+use crate::pool::fnv1a;
+use crate::Event;
+
+/// Hash buckets that events are spread over
+const BUCKETS: u64 = 1_000_000;
+
+/// One part of a dataset
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Partition {
+    Train,
+    Validation,
+    Test,
+}
+
+impl Partition {
+    /// Lowercase name, also used for file names
+    pub fn name(self) -> &'static str {
+        match self {
+            Partition::Train => "train",
+            Partition::Validation => "validation",
+            Partition::Test => "test",
+        }
+    }
+}
+
+/// Assigns events to partitions by `meta.id`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Split {
+    validation: f64,
+    test: f64,
+    salt: String,
+}
+
+impl Split {
+    /// Put about `validation` and `test` (fractions between 0 and 1) of
+    /// events in those partitions, and the rest in
+    /// [`Train`](Partition::Train)
+    pub fn new(validation: f64, test: f64) -> Self {
+        assert!(
+            validation >= 0.0 && test >= 0.0 && validation + test <= 1.0,
+            "fractions must be non-negative and add up to at most 1"
+        );
+        Self {
+            validation,
+            test,
+            salt: String::new(),
+        }
+    }
+
+    /// Mix `salt` into the hash, for a different split of the same events
+    pub fn salt(mut self, salt: &str) -> Self {
+        self.salt = salt.to_string();
+        self
+    }
+
+    /// Partition for the event with this `meta.id`
+    pub fn partition_of(&self, id: &str) -> Partition {
+        let key = [self.salt.as_bytes(), id.as_bytes()].concat();
+        let bucket = (fnv1a(&key) % BUCKETS) as f64 / BUCKETS as f64;
+        if bucket < self.test {
+            Partition::Test
+        } else if bucket < self.test + self.validation {
+            Partition::Validation
+        } else {
+            Partition::Train
+        }
+    }
+
+    /// Partition for `event`
+    pub fn partition(&self, event: &Event) -> Partition {
+        self.partition_of(&event.meta().id)
+    }
+}
+
+/// What [`Archive::split()`](crate::archive::Archive::split) wrote
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SplitCounts {
+    pub train: u64,
+    pub validation: u64,
+    pub test: u64,
+    /// Lines left out because they had no `meta.id`
+    pub skipped: u64,
+}
+
+impl SplitCounts {
+    pub(crate) fn add(&mut self, partition: Partition) {
+        match partition {
+            Partition::Train => self.train += 1,
+            Partition::Validation => self.validation += 1,
+            Partition::Test => self.test += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitions_ids_stably_in_proportion() {
+        let split = Split::new(0.1, 0.2);
+        let ids: Vec<String> =
+            (0..10_000).map(|n| format!("id-{}", n)).collect();
+        let partitions: Vec<_> =
+            ids.iter().map(|id| split.partition_of(id)).collect();
+        let share = |partition| {
+            partitions.iter().filter(|p| **p == partition).count() as f64
+                / ids.len() as f64
+        };
+        assert!((share(Partition::Validation) - 0.1).abs() < 0.02);
+        assert!((share(Partition::Test) - 0.2).abs() < 0.02);
+        assert!((share(Partition::Train) - 0.7).abs() < 0.02);
+        assert_eq!(split.clone().partition_of("id-1"), partitions[1]);
+
+        let salted = split.salt("again");
+        assert!(ids
+            .iter()
+            .zip(&partitions)
+            .any(|(id, partition)| salted.partition_of(id) != *partition));
+        let everything = Split::new(0.0, 1.0);
+        assert_eq!(everything.partition_of("id-1"), Partition::Test);
+    }
+
+    #[test]
+    #[should_panic(expected = "fractions must be non-negative")]
+    fn rejects_fractions_over_one() {
+        Split::new(0.6, 0.6);
+    }
+}
@@ -0,0 +1,735 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::backend::{BackendError, ConnectionEvent};
+use crate::clock::{Clock, SystemClock};
+use crate::compaction::Compaction;
+use crate::debug::{self, ConnectionInfo, DebugSnapshot};
+use crate::health::{Health, HealthTracker};
+use crate::listener::{ListenerHandle, Listeners};
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsSink;
+use crate::queue::QueueStats;
+use crate::report::{ShutdownReport, Tally};
+use crate::resume::ResumeToken;
+use crate::side_output::SideOutput;
+use crate::{
+    Event, EventStreamBuilder, EventStreamError, SinceError, StreamKind,
+};
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::stream::LocalBoxStream;
+use futures::{Stream, StreamExt};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// A stream of events from EventStreams, as created by
+/// [`EventStreamBuilder`](crate::EventStreamBuilder). Errors are kept out
+/// of the way of events, but can be followed separately with
+/// [`errors()`](EventStream::errors) or
+/// [`on_error()`](EventStream::on_error). Every event is also passed to the
+/// stream's [`listeners()`](EventStream::listeners) on its way through.
+///
+/// The stream isn't `Send`. To receive events on another thread, use
+/// [`EventStreamBuilder::into_channel()`](crate::EventStreamBuilder::into_channel)
+/// or [`EventStreamBuilder::spawn()`](crate::EventStreamBuilder::spawn).
+pub struct EventStream {
+    inner: LocalBoxStream<'static, Result<Event, EventStreamError>>,
+    errors: Mutex<Vec<ErrorSender>>,
+    /// Errors each [`errors()`](EventStream::errors) stream holds, if
+    /// bounded
+    error_limit: Option<usize>,
+    listeners: Listeners,
+    tally: Tally,
+    health: HealthTracker,
+    keep_canaries: bool,
+    compaction: Compaction,
+    clock: Arc<dyn Clock>,
+    endpoints: Vec<String>,
+    queue: Option<QueueStats>,
+    generation: u64,
+    closed: bool,
+}
+
+impl EventStream {
+    pub(crate) fn new(
+        inner: LocalBoxStream<'static, Result<Event, EventStreamError>>,
+    ) -> Self {
+        Self::with_listeners(inner, Listeners::new())
+    }
+
+    pub(crate) fn with_listeners(
+        inner: LocalBoxStream<'static, Result<Event, EventStreamError>>,
+        listeners: Listeners,
+    ) -> Self {
+        Self {
+            inner,
+            errors: Mutex::new(vec![]),
+            error_limit: crate::metrics::bounded(),
+            listeners,
+            tally: Tally::new(Arc::new(SystemClock)),
+            health: HealthTracker::default(),
+            keep_canaries: false,
+            compaction: Compaction::default(),
+            clock: Arc::new(SystemClock),
+            endpoints: vec![],
+            queue: None,
+            generation: 0,
+            closed: false,
+        }
+    }
+
+    /// Stream events from a [`backend`](crate::backend), e.g. recorded
+    /// fixtures
+    pub fn from_backend(
+        backend: impl Stream<Item = Result<String, BackendError>> + 'static,
+        side_output: Option<SideOutput>,
+    ) -> Self {
+        Self::new(
+            crate::parse_with_errors(
+                backend,
+                side_output,
+                None,
+                Default::default(),
+            )
+            .boxed_local(),
+        )
+    }
+
+    /// Stream events from `streams`, see
+    /// [`EventStreamBuilder::streams()`](crate::EventStreamBuilder::streams)
+    pub fn for_streams(streams: &[StreamKind]) -> Self {
+        EventStreamBuilder::new().streams(streams).into_stream()
+    }
+
+    /// Replay recent changes from `since` onwards, e.g. to catch up on
+    /// edits missed while offline, then continue with live ones. See
+    /// [`EventStreamBuilder::checked_since()`] for the limits.
+    pub fn since(since: DateTime<Utc>) -> Result<Self, SinceError> {
+        Ok(EventStreamBuilder::new()
+            .checked_since(since)?
+            .into_stream())
+    }
+
+    /// Listeners called for each event as it's streamed
+    pub fn listeners(&self) -> Listeners {
+        self.listeners.clone()
+    }
+
+    /// Drive the stream to completion, for when events are only handled
+    /// by [`listeners()`](EventStream::listeners)
+    pub async fn run(mut self) -> ShutdownReport {
+        while self.next().await.is_some() {}
+        self.shutdown()
+    }
+
+    /// Position just after the last event streamed, to save for
+    /// [resuming](crate::EventStreamBuilder::resume_from) later
+    pub fn resume_token(&self) -> ResumeToken {
+        self.tally.resume_token()
+    }
+
+    /// Number of times the connection had been lost when the last event
+    /// was received, starting at 0. State built up from events of an
+    /// earlier generation may be stale, since events could have been
+    /// missed or repeated while reconnecting. See also
+    /// [`Envelope::generation`](crate::Envelope::generation).
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Summary of the stream so far
+    pub fn report(&self) -> ShutdownReport {
+        self.tally.report()
+    }
+
+    /// When each stream last delivered an event and a canary, e.g. to
+    /// detect a stalled connection, see [`health`](crate::health)
+    pub fn health(&self) -> Health {
+        self.health.health()
+    }
+
+    pub(crate) fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.tally = Tally::new(clock.clone());
+        self.health = HealthTracker::new(clock.clone());
+        self.compaction = self.compaction.clone().clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    /// Hold at most `limit` errors in each
+    /// [`errors()`](Self::errors) stream, see
+    /// [`EventStreamBuilder::bounded()`](crate::EventStreamBuilder::bounded)
+    pub(crate) fn bounded(mut self, limit: Option<usize>) -> Self {
+        self.error_limit = limit;
+        self
+    }
+
+    pub(crate) fn keep_canaries(mut self, keep: bool) -> Self {
+        self.keep_canaries = keep;
+        self
+    }
+
+    pub(crate) fn compaction_interval(
+        mut self,
+        interval: Option<Duration>,
+    ) -> Self {
+        self.compaction = Compaction::new(interval).clock(self.clock.clone());
+        self
+    }
+
+    /// Where the stream connects to and its queue, if it has one, for
+    /// [`debug_snapshot()`](Self::debug_snapshot)
+    pub(crate) fn origin(
+        mut self,
+        endpoints: Vec<String>,
+        queue: Option<QueueStats>,
+    ) -> Self {
+        self.endpoints = endpoints;
+        self.queue = queue;
+        self
+    }
+
+    /// Internal state of the stream, e.g. to attach to a bug report, see
+    /// [`debug`](crate::debug)
+    pub fn debug_snapshot(&self) -> DebugSnapshot {
+        let error_streams = self
+            .errors
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|sender| !sender.is_closed())
+            .count();
+        DebugSnapshot {
+            taken_at: self.clock.now(),
+            version: debug::VERSION,
+            connection: ConnectionInfo {
+                endpoints: self.endpoints.clone(),
+                generation: self.generation,
+                closed: self.closed,
+            },
+            report: self.report(),
+            health: self.health(),
+            listeners: self.listeners.counts(),
+            error_streams,
+            queue: self.queue.as_ref().map(Into::into),
+            keep_canaries: self.keep_canaries,
+            compaction_interval: self.compaction.interval(),
+        }
+    }
+
+    /// Give back memory held for error streams and listeners that are
+    /// gone, see [`compaction`](crate::compaction)
+    fn compact(&mut self) {
+        let errors = self.errors.get_mut().unwrap();
+        errors.retain(|sender| !sender.is_closed());
+        errors.shrink_to_fit();
+        self.listeners.compact();
+    }
+
+    /// Stop streaming, returning a summary of everything that happened.
+    /// The connection is closed, [`errors()`](EventStream::errors) streams
+    /// end, and [`on_close()`](Listeners::on_close) listeners are called.
+    /// Dropping the stream does the same, minus the report.
+    pub fn shutdown(mut self) -> ShutdownReport {
+        self.close();
+        self.report()
+    }
+
+    fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        self.inner = futures::stream::empty().boxed_local();
+        self.errors.get_mut().unwrap().clear();
+        self.listeners.dispatch_connection(&ConnectionEvent::Closed);
+    }
+
+    /// Errors that happen from now on, e.g. to `select!` over alongside
+    /// the events. Errors are only produced while the event stream is
+    /// being polled, and the error stream ends when it does. In
+    /// [bounded mode](crate::metrics::set_bounded), or if the stream was
+    /// [bounded](crate::EventStreamBuilder::bounded), errors that don't fit
+    /// are dropped until there's room again.
+    pub fn errors(&self) -> impl Stream<Item = EventStreamError> {
+        self.error_receiver()
+    }
+
+    fn error_receiver(&self) -> ErrorReceiver {
+        let (sender, receiver) = match self.error_limit {
+            Some(limit) => {
+                // The channel always has room for one more per sender
+                let (sender, receiver) = mpsc::channel(limit.max(1) - 1);
+                (
+                    ErrorSender::Bounded(sender),
+                    ErrorReceiver::Bounded(receiver),
+                )
+            }
+            None => {
+                let (sender, receiver) = mpsc::unbounded();
+                (
+                    ErrorSender::Unbounded(sender),
+                    ErrorReceiver::Unbounded(receiver),
+                )
+            }
+        };
+        self.errors.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Call `listener` for every error from now on, see
+    /// [`Listeners::on_error()`]
+    pub fn on_error(
+        &self,
+        listener: impl FnMut(&EventStreamError) + Send + 'static,
+    ) -> ListenerHandle {
+        self.listeners.on_error(listener)
+    }
+
+    /// Report what the stream receives to `sink` from now on:
+    ///
+    /// * `events.<stream>` counts events from each stream, and for recent
+    ///   changes, `events.<stream>.<type>` each type, e.g. `edit` or `log`
+    /// * `lag_ms` is how long ago the latest event happened, going by
+    ///   `meta.dt`
+    /// * `parse_failures` counts messages that couldn't be parsed
+    /// * `schema_drift` counts fields that appeared or vanished, if the
+    ///   stream [detects](crate::EventStreamBuilder::detect_schema_drift)
+    ///   them
+    /// * `reconnects` counts attempts to reconnect
+    ///
+    /// Components that buffer events report how many they hold with
+    /// their own `with_metrics()`, see [`metrics`](crate::metrics).
+    #[cfg(feature = "metrics")]
+    pub fn instrument(&self, sink: impl MetricsSink + 'static) {
+        let sink = Arc::new(sink);
+        let events = sink.clone();
+        let clock = self.clock.clone();
+        self.listeners.on_event(move |event: &Event| {
+            let stream = &event.meta().stream;
+            events.increment(&format!("events.{}", stream), 1);
+            if let Some(kind) = recent_change_type(event) {
+                events.increment(&format!("events.{}.{}", stream, kind), 1);
+            }
+            let lag = clock.now() - event.dt();
+            events.set_gauge("lag_ms", lag.num_milliseconds().max(0) as u64);
+        });
+        let errors = sink.clone();
+        self.listeners.on_error(move |err| {
+            if let EventStreamError::Malformed { .. }
+            | EventStreamError::Truncated { .. } = err
+            {
+                errors.increment("parse_failures", 1);
+            }
+            if let EventStreamError::SchemaDrift(_) = err {
+                errors.increment("schema_drift", 1);
+            }
+        });
+        self.listeners
+            .on_reconnect(move |_, _| sink.increment("reconnects", 1));
+    }
+
+    /// Wait for events one at a time, with errors inline, e.g. for simple
+    /// scripts that don't otherwise need async. This blocks the current
+    /// thread, so it mustn't be used from async code.
+    ///
+    /// ```no_run
+    /// for event in eventstreams::EventStream::since(chrono::Utc::now())
+    ///     .unwrap()
+    ///     .iter()
+    /// {
+    ///     match event {
+    ///         Ok(event) => println!("{}", event.title()),
+    ///         Err(err) => eprintln!("{}", err),
+    ///     }
+    /// }
+    /// ```
+    pub fn iter(&mut self) -> EventIter<'_> {
+        EventIter {
+            errors: self.error_receiver(),
+            stream: self,
+            pending: None,
+            done: false,
+        }
+    }
+}
+
+/// `type` of a recent change
+#[cfg(feature = "metrics")]
+fn recent_change_type(event: &Event) -> Option<&'static str> {
+    match event {
+        Event::Edit(_) => Some("edit"),
+        Event::New(_) => Some("new"),
+        Event::Log(_) => Some("log"),
+        Event::Categorize(_) => Some("categorize"),
+        Event::External(_) => Some("external"),
+        _ => None,
+    }
+}
+
+/// Feeds one of the [`errors()`](EventStream::errors) streams
+enum ErrorSender {
+    Unbounded(mpsc::UnboundedSender<EventStreamError>),
+    /// In [bounded mode](crate::metrics::set_bounded)
+    Bounded(mpsc::Sender<EventStreamError>),
+}
+
+impl ErrorSender {
+    fn is_closed(&self) -> bool {
+        match self {
+            Self::Unbounded(sender) => sender.is_closed(),
+            Self::Bounded(sender) => sender.is_closed(),
+        }
+    }
+
+    /// Send `err`, returning whether the receiver is still there. Errors
+    /// that don't fit a bounded stream are dropped.
+    fn send(&mut self, err: &EventStreamError) -> bool {
+        match self {
+            Self::Unbounded(sender) => {
+                sender.unbounded_send(err.clone()).is_ok()
+            }
+            Self::Bounded(sender) => match sender.try_send(err.clone()) {
+                Ok(()) => true,
+                Err(err) => err.is_full(),
+            },
+        }
+    }
+}
+
+/// Reads one of the [`errors()`](EventStream::errors) streams
+enum ErrorReceiver {
+    Unbounded(mpsc::UnboundedReceiver<EventStreamError>),
+    Bounded(mpsc::Receiver<EventStreamError>),
+}
+
+impl ErrorReceiver {
+    fn try_recv(&mut self) -> Result<EventStreamError, mpsc::TryRecvError> {
+        match self {
+            Self::Unbounded(receiver) => receiver.try_recv(),
+            Self::Bounded(receiver) => receiver.try_recv(),
+        }
+    }
+}
+
+impl Stream for ErrorReceiver {
+    type Item = EventStreamError;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<EventStreamError>> {
+        match &mut *self {
+            Self::Unbounded(receiver) => receiver.poll_next_unpin(cx),
+            Self::Bounded(receiver) => receiver.poll_next_unpin(cx),
+        }
+    }
+}
+
+/// Blocking iterator over an [`EventStream`], see
+/// [`EventStream::iter()`]
+pub struct EventIter<'a> {
+    stream: &'a mut EventStream,
+    errors: ErrorReceiver,
+    /// An event that was read along with errors that came before it
+    pending: Option<Event>,
+    done: bool,
+}
+
+impl Iterator for EventIter<'_> {
+    type Item = Result<Event, EventStreamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(err) = self.errors.try_recv() {
+            return Some(Err(err));
+        }
+        if let Some(event) = self.pending.take() {
+            return Some(Ok(event));
+        }
+        if self.done {
+            return None;
+        }
+        let event = futures::executor::block_on(self.stream.next());
+        self.done = event.is_none();
+        // Errors are passed on as they happen while waiting for an event,
+        // so any of them go first
+        match self.errors.try_recv() {
+            Ok(err) => {
+                self.pending = event;
+                Some(Err(err))
+            }
+            _ => event.map(Ok),
+        }
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+impl Stream for EventStream {
+    type Item = Event;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Event>> {
+        loop {
+            match self.inner.as_mut().poll_next(cx) {
+                Poll::Ready(Some(Ok(event))) => {
+                    self.health.observe(&event);
+                    if event.is_canary() {
+                        self.listeners.dispatch_canary(&event);
+                        if !self.keep_canaries {
+                            continue;
+                        }
+                    }
+                    self.tally.event(&event);
+                    self.listeners.dispatch(&event);
+                    if self.compaction.due() {
+                        self.compact();
+                    }
+                    return Poll::Ready(Some(event));
+                }
+                Poll::Ready(Some(Err(err))) => {
+                    // Backends reconnect after every error of their own
+                    if let EventStreamError::Backend(_) = err {
+                        self.generation += 1;
+                    }
+                    self.tally.error(&err);
+                    self.listeners.dispatch_error(&err);
+                    // Drop senders whose receiver is gone
+                    self.errors
+                        .get_mut()
+                        .unwrap()
+                        .retain_mut(|sender| sender.send(&err));
+                }
+                Poll::Ready(None) => {
+                    self.errors.get_mut().unwrap().clear();
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Backoff;
+    use crate::testing::{self, at, MockServer};
+    use crate::{EventStreamBuilder, EventStreamError};
+    use futures::executor::block_on;
+    use futures::StreamExt;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn keeps_errors_out_of_the_way() {
+        let messages = vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(5, "B", at(10)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let mut stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let errors = stream.errors();
+        let events: Vec<_> = block_on((&mut stream).take(2).collect());
+        let titles: Vec<_> = events.iter().map(|event| event.title()).collect();
+        assert_eq!(titles, ["A", "B"]);
+        // The error stream ends along with the event stream
+        drop(stream);
+        let errors: Vec<_> = block_on(errors.collect());
+        assert_eq!(errors.len(), 2);
+        assert!(matches!(errors[0], EventStreamError::Malformed { .. }));
+        match &errors[1] {
+            EventStreamError::Gap(gap) => assert_eq!(gap.missing(), 4),
+            err => panic!("expected a gap, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn bounded_error_streams_drop_what_does_not_fit() {
+        let mut messages = vec!["{\"type\": \"edit\"}".to_string(); 4];
+        messages.push(testing::edit(0, "A", at(0)).to_string());
+        let server = MockServer::new(messages).start().unwrap();
+        let mut stream = EventStreamBuilder::new()
+            .url(server.url())
+            .queue(crate::queue::Queue::new(
+                16,
+                crate::queue::QueuePolicy::Block,
+            ))
+            .bounded(2)
+            .build()
+            .unwrap();
+        let errors = stream.errors();
+        let heard = Arc::new(Mutex::new(0));
+        {
+            let heard = heard.clone();
+            stream.on_error(move |_| *heard.lock().unwrap() += 1);
+        }
+        let event = block_on(stream.next()).unwrap();
+        assert_eq!(event.title(), "A");
+        assert_eq!(stream.debug_snapshot().queue.unwrap().capacity, 2);
+        drop(stream);
+        let errors: Vec<_> = block_on(errors.collect());
+        assert_eq!(errors.len(), 2);
+        // Listeners still hear about every error
+        assert_eq!(*heard.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn reports_truncated_messages_to_error_listeners() {
+        let messages = vec![
+            "{\"meta\": {\"dt\": \"2021-01-01T00:00:00Z\"".to_string(),
+            testing::edit(0, "A", at(0)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let mut stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let errors = Arc::new(Mutex::new(vec![]));
+        {
+            let errors = errors.clone();
+            stream
+                .on_error(move |err| errors.lock().unwrap().push(err.clone()));
+        }
+        let event = block_on(stream.next()).unwrap();
+        assert_eq!(event.title(), "A");
+        let errors = errors.lock().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], EventStreamError::Truncated { .. }));
+    }
+
+    #[test]
+    fn iterates_with_errors_inline() {
+        let messages = vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(1, "B", at(10)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let mut stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let results: Vec<_> = stream.iter().take(3).collect();
+        assert_eq!(results[0].as_ref().unwrap().title(), "A");
+        assert!(matches!(
+            results[1],
+            Err(EventStreamError::Malformed { .. })
+        ));
+        assert_eq!(results[2].as_ref().unwrap().title(), "B");
+    }
+
+    #[test]
+    fn counts_connection_generations() {
+        let messages = ["A", "B", "C"].iter().enumerate().map(|(i, title)| {
+            testing::edit(i as u64, title, at(0)).to_string()
+        });
+        let server = MockServer::new(messages)
+            .disconnect_after(2)
+            .start()
+            .unwrap();
+        let mut stream = EventStreamBuilder::new()
+            .url(server.url())
+            .backoff(Backoff::new(
+                Duration::from_millis(1),
+                Duration::from_millis(1),
+                1.0,
+            ))
+            .build()
+            .unwrap();
+        let mut generations = vec![];
+        for _ in 0..3 {
+            block_on(stream.next()).unwrap();
+            generations.push(stream.generation());
+        }
+        assert_eq!(generations, [0, 0, 1]);
+    }
+
+    #[test]
+    fn closes_when_dropped() {
+        let server = MockServer::new(vec![]).start().unwrap();
+        let stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let closed = Arc::new(Mutex::new(0));
+        {
+            let closed = closed.clone();
+            stream
+                .listeners()
+                .on_close(move || *closed.lock().unwrap() += 1);
+        }
+        drop(stream);
+        assert_eq!(*closed.lock().unwrap(), 1);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn reports_metrics() {
+        let messages = vec![
+            testing::edit(0, "A", at(0)).to_string(),
+            "{\"type\": \"edit\"}".to_string(),
+            testing::edit(1, "B", at(0)).to_string(),
+        ];
+        let server = MockServer::new(messages).start().unwrap();
+        let clock = crate::clock::ManualClock::new(at(5));
+        let mut stream = EventStreamBuilder::new()
+            .url(server.url())
+            .clock(Arc::new(clock))
+            .build()
+            .unwrap();
+        let metrics = crate::metrics::Metrics::new();
+        stream.instrument(metrics.clone());
+        block_on((&mut stream).take(2).collect::<Vec<_>>());
+        let snapshot = metrics.snapshot();
+        let counter = |name: &str| snapshot.counters.get(name).copied();
+        assert_eq!(counter("events.mediawiki.recentchange"), Some(2));
+        assert_eq!(counter("events.mediawiki.recentchange.edit"), Some(2));
+        assert_eq!(counter("parse_failures"), Some(1));
+        assert_eq!(snapshot.gauges["lag_ms"], 5000);
+    }
+
+    #[test]
+    fn snapshots_state_for_bug_reports() {
+        let messages = vec![testing::edit(0, "A", at(0)).to_string()];
+        let server = MockServer::new(messages).start().unwrap();
+        let mut stream = EventStreamBuilder::new()
+            .url(server.url())
+            .header("Authorization", "Bearer secret")
+            .queue(crate::queue::Queue::new(
+                16,
+                crate::queue::QueuePolicy::DropOldest,
+            ))
+            .build()
+            .unwrap();
+        let _errors = stream.errors();
+        stream.listeners().on_event(|_: &crate::Event| {});
+        block_on((&mut stream).take(1).collect::<Vec<_>>());
+        let snapshot = stream.debug_snapshot();
+        assert_eq!(snapshot.connection.endpoints, [server.url()]);
+        assert!(!snapshot.connection.closed);
+        assert_eq!(snapshot.listeners.events, 1);
+        assert_eq!(snapshot.error_streams, 1);
+        assert_eq!(snapshot.queue.unwrap().capacity, 16);
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert!(json.contains(env!("CARGO_PKG_VERSION")));
+        assert!(!json.contains("secret"));
+    }
+}
@@ -0,0 +1,696 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Subscriptions route matching events to a sink
+//!
+//! Besides subscriptions built in code, a [`SubscriptionRegistry`] holds
+//! named, serializable [`SubscriptionDef`]s that can be added, changed,
+//! enabled and disabled while a [`Daemon`](crate::daemon::Daemon) is
+//! running. Definitions refer to sinks by name, which are registered with
+//! the registry up front.
+//!
+//! A subscription can also mirror a sample of its matching events to a
+//! [shadow](Subscription::shadow) sink, e.g. to validate a new sink
+//! against a trickle of production traffic before switching over, and
+//! compare its filter with a [candidate](Subscription::candidate) before
+//! switching to that. A [circuit breaker](Subscription::breaker) pauses
+//! delivery while the sink is failing.
+use crate::breaker::CircuitBreaker;
+use crate::dual_run::DualRun;
+use crate::filter::{Explanation, Filter};
+use crate::pool::fnv1a;
+use crate::sink::{Sink, SinkError};
+use crate::Event;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Events matching `filter` are sent to `sink`
+pub struct Subscription {
+    name: String,
+    filter: Box<dyn Fn(&Event) -> bool + Send>,
+    /// Kept around for [`explain()`](Self::explain), if there is one
+    compiled: Option<Filter>,
+    sink: Box<dyn Sink>,
+    shadow: Option<Shadow>,
+    dual_run: Option<DualRun>,
+    breaker: Option<CircuitBreaker>,
+    dry_run: bool,
+    alert: bool,
+    matched: u64,
+}
+
+/// Hash buckets that events are spread over for shadowing
+const BUCKETS: u64 = 1_000_000;
+
+/// Where a subscription mirrors its sample of events to
+struct Shadow {
+    sink: Box<dyn Sink>,
+    fraction: f64,
+    sent: u64,
+    errors: u64,
+}
+
+impl Shadow {
+    /// Whether the event is in the sample, going by its ID so every
+    /// replica picks the same events
+    fn samples(&self, event: &Event) -> bool {
+        let bucket = fnv1a(event.meta().id.as_bytes()) % BUCKETS;
+        (bucket as f64) < self.fraction * BUCKETS as f64
+    }
+}
+
+impl Subscription {
+    pub fn new(
+        name: impl Into<String>,
+        filter: impl Fn(&Event) -> bool + Send + 'static,
+        sink: impl Sink + 'static,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            filter: Box::new(filter),
+            compiled: None,
+            sink: Box::new(sink),
+            shadow: None,
+            dual_run: None,
+            breaker: None,
+            dry_run: false,
+            alert: false,
+            matched: 0,
+        }
+    }
+
+    /// Like [`new()`](Self::new), with a compiled [`Filter`]
+    pub fn with_filter(
+        name: impl Into<String>,
+        filter: Filter,
+        sink: impl Sink + 'static,
+    ) -> Self {
+        let compiled = filter.clone();
+        Self {
+            compiled: Some(compiled),
+            ..Self::new(name, move |event| filter.matches(event), sink)
+        }
+    }
+
+    /// Count matching events without sending them to the sink, to try out
+    /// a filter against live traffic
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Deliver straight from the reader, ahead of queues and other
+    /// subscriptions, for alerts where latency matters more than order.
+    /// This takes effect in a daemon created with `Daemon::with_builder()`,
+    /// see
+    /// [`EventStreamBuilder::alert_subscription()`](crate::EventStreamBuilder::alert_subscription).
+    pub fn alert(mut self) -> Self {
+        self.alert = true;
+        self
+    }
+
+    pub fn is_alert(&self) -> bool {
+        self.alert
+    }
+
+    /// Also send a `fraction` (between 0 and 1) of matching events to
+    /// `sink`. Which events are sampled depends only on their ID. Errors
+    /// from the shadow sink are logged and counted, but never affect
+    /// delivery to the main sink.
+    pub fn shadow(mut self, fraction: f64, sink: impl Sink + 'static) -> Self {
+        self.shadow = Some(Shadow {
+            sink: Box::new(sink),
+            fraction: fraction.clamp(0.0, 1.0),
+            sent: 0,
+            errors: 0,
+        });
+        self
+    }
+
+    /// Compare `filter` with the subscription's own on every event, without
+    /// changing what's delivered, see [`DualRun`]. Only subscriptions
+    /// created [with a `Filter`](Self::with_filter) can have a candidate.
+    pub fn candidate(mut self, filter: Filter) -> Self {
+        let current = self
+            .compiled
+            .clone()
+            .expect("candidate filters need a subscription with a Filter");
+        self.dual_run = Some(DualRun::new(current, filter));
+        self
+    }
+
+    /// Pause delivery to the sink while it exceeds `breaker`'s error
+    /// budget. Matching events are skipped while paused.
+    pub fn breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// The circuit breaker, if there is one, e.g. to check whether the
+    /// subscription is paused and where
+    pub fn circuit_breaker(&mut self) -> Option<&mut CircuitBreaker> {
+        self.breaker.as_mut()
+    }
+
+    /// Comparison with the candidate filter, if there is one
+    pub fn dual_run(&self) -> Option<&DualRun> {
+        self.dual_run.as_ref()
+    }
+
+    /// Number of events sent to the shadow sink so far
+    pub fn shadowed(&self) -> u64 {
+        self.shadow.as_ref().map_or(0, |shadow| shadow.sent)
+    }
+
+    /// Number of events the shadow sink failed to take
+    pub fn shadow_errors(&self) -> u64 {
+        self.shadow.as_ref().map_or(0, |shadow| shadow.errors)
+    }
+
+    /// Number of events that have matched so far, whether or not they were
+    /// delivered
+    pub fn matched(&self) -> u64 {
+        self.matched
+    }
+
+    /// Explain why the event does or doesn't match, if the subscription
+    /// was created with a [`Filter`]
+    pub fn explain(&self, event: &Event) -> Option<Explanation> {
+        self.compiled.as_ref().map(|filter| filter.explain(event))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        (self.filter)(event)
+    }
+
+    /// Send the event to the sink if it matches, returning whether it did,
+    /// and to the shadow sink if it's sampled. In dry-run mode, nothing is
+    /// ever sent, and while the circuit breaker is open, nothing is sent
+    /// to the main sink.
+    pub async fn deliver(&mut self, event: &Event) -> Result<bool, SinkError> {
+        if let Some(dual_run) = &mut self.dual_run {
+            dual_run.push(event);
+        }
+        if !self.matches(event) {
+            return Ok(false);
+        }
+        self.matched += 1;
+        if self.dry_run {
+            return Ok(false);
+        }
+        let allowed = self.breaker.as_mut().is_none_or(CircuitBreaker::allow);
+        let result = if allowed {
+            let result = self.sink.send(event).await;
+            if let Some(breaker) = &mut self.breaker {
+                match &result {
+                    Ok(()) => breaker.record_success(event),
+                    Err(_) => breaker.record_failure(),
+                }
+            }
+            result.map(|()| true)
+        } else {
+            Ok(false)
+        };
+        if let Some(shadow) = &mut self.shadow {
+            if shadow.samples(event) {
+                match shadow.sink.send(event).await {
+                    Ok(()) => shadow.sent += 1,
+                    Err(err) => {
+                        shadow.errors += 1;
+                        log::warn!(
+                            target: "eventstreams::subscription",
+                            "shadow sink for {} failed: {}",
+                            self.name,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("name", &self.name)
+            .field(
+                "shadow",
+                &self.shadow.as_ref().map(|shadow| shadow.fraction),
+            )
+            .field("candidate", &self.dual_run.is_some())
+            .field("breaker", &self.breaker)
+            .field("dry_run", &self.dry_run)
+            .field("alert", &self.alert)
+            .finish()
+    }
+}
+
+/// A named subscription that can be stored and managed at runtime
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionDef {
+    pub name: String,
+    #[serde(default)]
+    pub filter: Filter,
+    /// Name of a sink registered with
+    /// [`SubscriptionRegistry::register_sink()`]
+    pub sink: String,
+    /// Subscriptions with a higher priority are delivered to first
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// Sink to mirror a sample of matching events to, see
+    /// [`Subscription::shadow()`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shadow: Option<ShadowDef>,
+    /// Filter to compare with `filter` before switching to it, see
+    /// [`Subscription::candidate()`] and
+    /// [`SubscriptionRegistry::promote()`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candidate: Option<Filter>,
+    /// Error budget for pausing delivery, see [`Subscription::breaker()`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub breaker: Option<BreakerDef>,
+}
+
+/// A shadow sink in a [`SubscriptionDef`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShadowDef {
+    /// Name of a registered sink
+    pub sink: String,
+    /// Share of matching events to mirror, between 0 and 1
+    pub fraction: f64,
+}
+
+/// A circuit breaker in a [`SubscriptionDef`], see [`CircuitBreaker::new()`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BreakerDef {
+    /// Share of deliveries allowed to fail, between 0 and 1
+    pub budget: f64,
+    /// Number of recent deliveries the budget applies to
+    pub window: usize,
+    /// Seconds to pause for before probing the sink again
+    pub cooldown_secs: u64,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl SubscriptionDef {
+    /// An enabled subscription with the default priority
+    pub fn new(
+        name: impl Into<String>,
+        filter: Filter,
+        sink: impl Into<String>,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            filter,
+            sink: sink.into(),
+            priority: 0,
+            enabled: true,
+            shadow: None,
+            candidate: None,
+            breaker: None,
+        }
+    }
+
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Compare `filter` with the current one before switching to it
+    pub fn candidate(mut self, filter: Filter) -> Self {
+        self.candidate = Some(filter);
+        self
+    }
+
+    /// Pause delivery once more than `budget` of the last `window`
+    /// deliveries failed, probing the sink again after `cooldown_secs`
+    pub fn breaker(
+        mut self,
+        budget: f64,
+        window: usize,
+        cooldown_secs: u64,
+    ) -> Self {
+        self.breaker = Some(BreakerDef {
+            budget,
+            window,
+            cooldown_secs,
+        });
+        self
+    }
+
+    /// Mirror a `fraction` of matching events to the sink named `sink`
+    pub fn shadow(mut self, fraction: f64, sink: impl Into<String>) -> Self {
+        self.shadow = Some(ShadowDef {
+            sink: sink.into(),
+            fraction,
+        });
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryError {
+    /// No sink with this name has been registered
+    UnknownSink(String),
+    /// No subscription with this name has been defined
+    UnknownSubscription(String),
+    /// The subscription has no candidate filter to promote
+    NoCandidate(String),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownSink(name) => write!(f, "unknown sink: {}", name),
+            Self::UnknownSubscription(name) => {
+                write!(f, "unknown subscription: {}", name)
+            }
+            Self::NoCandidate(name) => {
+                write!(f, "subscription has no candidate filter: {}", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+type SinkFactory = Box<dyn Fn() -> Box<dyn Sink> + Send>;
+
+#[derive(Default)]
+struct Registry {
+    sinks: HashMap<String, SinkFactory>,
+    defs: Vec<SubscriptionDef>,
+    version: u64,
+}
+
+/// Shared set of [`SubscriptionDef`]s. Clones refer to the same registry.
+#[derive(Clone, Default)]
+pub struct SubscriptionRegistry {
+    inner: Arc<Mutex<Registry>>,
+}
+
+impl SubscriptionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Make a sink available to definitions as `name`. `factory` is called
+    /// whenever a subscription using it is created or changed.
+    pub fn register_sink<S: Sink + 'static>(
+        &self,
+        name: impl Into<String>,
+        factory: impl Fn() -> S + Send + 'static,
+    ) {
+        self.inner.lock().unwrap().sinks.insert(
+            name.into(),
+            Box::new(move || Box::new(factory()) as Box<dyn Sink>),
+        );
+    }
+
+    /// Add a subscription, replacing any existing one with the same name
+    pub fn define(&self, def: SubscriptionDef) -> Result<(), RegistryError> {
+        let mut registry = self.inner.lock().unwrap();
+        if !registry.sinks.contains_key(&def.sink) {
+            return Err(RegistryError::UnknownSink(def.sink));
+        }
+        if let Some(shadow) = &def.shadow {
+            if !registry.sinks.contains_key(&shadow.sink) {
+                return Err(RegistryError::UnknownSink(shadow.sink.clone()));
+            }
+        }
+        match registry.defs.iter_mut().find(|d| d.name == def.name) {
+            Some(existing) => *existing = def,
+            None => registry.defs.push(def),
+        }
+        registry.version += 1;
+        Ok(())
+    }
+
+    /// Remove a subscription, returning its definition
+    pub fn remove(&self, name: &str) -> Option<SubscriptionDef> {
+        let mut registry = self.inner.lock().unwrap();
+        let index = registry.defs.iter().position(|d| d.name == name)?;
+        registry.version += 1;
+        Some(registry.defs.remove(index))
+    }
+
+    pub fn set_enabled(
+        &self,
+        name: &str,
+        enabled: bool,
+    ) -> Result<(), RegistryError> {
+        let mut registry = self.inner.lock().unwrap();
+        let def = registry
+            .defs
+            .iter_mut()
+            .find(|d| d.name == name)
+            .ok_or_else(|| RegistryError::UnknownSubscription(name.into()))?;
+        def.enabled = enabled;
+        registry.version += 1;
+        Ok(())
+    }
+
+    /// Switch a subscription over to its candidate filter
+    pub fn promote(&self, name: &str) -> Result<(), RegistryError> {
+        let mut registry = self.inner.lock().unwrap();
+        let def = registry
+            .defs
+            .iter_mut()
+            .find(|d| d.name == name)
+            .ok_or_else(|| RegistryError::UnknownSubscription(name.into()))?;
+        def.filter = def
+            .candidate
+            .take()
+            .ok_or_else(|| RegistryError::NoCandidate(name.into()))?;
+        registry.version += 1;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<SubscriptionDef> {
+        let registry = self.inner.lock().unwrap();
+        registry.defs.iter().find(|d| d.name == name).cloned()
+    }
+
+    /// All definitions, in delivery order
+    pub fn list(&self) -> Vec<SubscriptionDef> {
+        let mut defs = self.inner.lock().unwrap().defs.clone();
+        defs.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        defs
+    }
+
+    /// Write all definitions to `path` as JSON
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(&self.list())?;
+        std::fs::write(path, json)
+    }
+
+    /// Define every subscription stored at `path` by [`save()`](Self::save)
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let defs: Vec<SubscriptionDef> =
+            serde_json::from_slice(&std::fs::read(path)?)?;
+        for def in defs {
+            self.define(def).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, err)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Incremented on every change, so users can tell when to rebuild
+    #[cfg(feature = "server")]
+    pub(crate) fn version(&self) -> u64 {
+        self.inner.lock().unwrap().version
+    }
+
+    /// Create a subscription from a definition
+    #[cfg(feature = "server")]
+    pub(crate) fn build(&self, def: &SubscriptionDef) -> Option<Subscription> {
+        let registry = self.inner.lock().unwrap();
+        let sink = (registry.sinks.get(&def.sink)?)();
+        let mut subscription = Subscription::with_filter(
+            def.name.clone(),
+            def.filter.clone(),
+            sink,
+        );
+        if let Some(candidate) = &def.candidate {
+            subscription = subscription.candidate(candidate.clone());
+        }
+        if let Some(breaker) = &def.breaker {
+            subscription = subscription.breaker(CircuitBreaker::new(
+                breaker.budget,
+                breaker.window,
+                std::time::Duration::from_secs(breaker.cooldown_secs),
+            ));
+        }
+        Some(match &def.shadow {
+            Some(shadow) => {
+                let sink = (registry.sinks.get(&shadow.sink)?)();
+                subscription.shadow(shadow.fraction, sink)
+            }
+            None => subscription,
+        })
+    }
+}
+
+impl fmt::Debug for SubscriptionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SubscriptionRegistry")
+            .field("defs", &self.inner.lock().unwrap().defs)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::FnSink;
+    use crate::testing::{at, edit_event};
+    use futures::executor::block_on;
+    use std::sync::{Arc, Mutex};
+
+    /// A sink that keeps the titles of the events sent to it
+    fn recorder() -> (impl Sink, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let titles = sent.clone();
+        let sink = FnSink(move |event: &Event| {
+            titles.lock().unwrap().push(event.title().to_string());
+            Ok(())
+        });
+        (sink, sent)
+    }
+
+    #[test]
+    fn counts_matches_without_sending_in_dry_run() {
+        let (sink, sent) = recorder();
+        let filter = Filter::new().titles(["A"]);
+        let mut subscription =
+            Subscription::with_filter("test", filter, sink).dry_run();
+        assert!(subscription.is_dry_run());
+        assert!(!block_on(subscription.deliver(&edit_event(1, "A", at(0))))
+            .unwrap());
+        assert!(!block_on(subscription.deliver(&edit_event(2, "B", at(0))))
+            .unwrap());
+        assert_eq!(subscription.matched(), 1);
+        assert!(sent.lock().unwrap().is_empty());
+        let explanation =
+            subscription.explain(&edit_event(3, "B", at(0))).unwrap();
+        assert!(!explanation.matched);
+    }
+
+    #[test]
+    fn manages_and_persists_definitions() {
+        let registry = SubscriptionRegistry::new();
+        let def = SubscriptionDef::new("low", Filter::new(), "stdout");
+        assert_eq!(
+            registry.define(def.clone()),
+            Err(RegistryError::UnknownSink("stdout".to_string()))
+        );
+        registry.register_sink("stdout", || crate::sink::StdoutSink);
+        registry.define(def).unwrap();
+        let high = SubscriptionDef::new(
+            "high",
+            Filter::new().wiki("enwiki"),
+            "stdout",
+        )
+        .priority(10);
+        registry.define(high.clone()).unwrap();
+        let names: Vec<_> =
+            registry.list().into_iter().map(|def| def.name).collect();
+        assert_eq!(names, ["high", "low"]);
+        registry.set_enabled("low", false).unwrap();
+        assert!(!registry.get("low").unwrap().enabled);
+        assert!(registry.set_enabled("none", false).is_err());
+
+        let path = std::env::temp_dir().join(format!(
+            "eventstreams-subscriptions-{}.json",
+            std::process::id()
+        ));
+        registry.save(&path).unwrap();
+        let loaded = SubscriptionRegistry::new();
+        loaded.register_sink("stdout", || crate::sink::StdoutSink);
+        loaded.load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.list(), registry.list());
+        assert_eq!(loaded.remove("high"), Some(high));
+        assert_eq!(loaded.list().len(), 1);
+    }
+
+    #[test]
+    fn mirrors_a_stable_sample_to_the_shadow() {
+        let (sink, sent) = recorder();
+        let (shadow, shadowed) = recorder();
+        let mut subscription =
+            Subscription::new("test", |_| true, sink).shadow(0.5, shadow);
+        let titles: Vec<_> = (0..100).map(|n| n.to_string()).collect();
+        for (offset, title) in titles.iter().enumerate() {
+            block_on(subscription.deliver(&edit_event(
+                offset as u64,
+                title,
+                at(0),
+            )))
+            .unwrap();
+        }
+        assert_eq!(sent.lock().unwrap().len(), 100);
+        let shadowed = shadowed.lock().unwrap().clone();
+        assert!((30..70).contains(&shadowed.len()), "{}", shadowed.len());
+        assert_eq!(subscription.shadowed(), shadowed.len() as u64);
+
+        // Another replica samples the same events, and shadow failures
+        // don't reach the main sink
+        let failing = crate::sink::FnSink(|_: &Event| Err("down".into()));
+        let (sink, _) = recorder();
+        let mut replica =
+            Subscription::new("test", |_| true, sink).shadow(0.5, failing);
+        for (offset, title) in titles.iter().enumerate() {
+            let event = edit_event(offset as u64, title, at(0));
+            assert!(block_on(replica.deliver(&event)).unwrap());
+        }
+        assert_eq!(replica.shadowed(), 0);
+        assert_eq!(replica.shadow_errors(), shadowed.len() as u64);
+
+        let registry = SubscriptionRegistry::new();
+        registry.register_sink("stdout", || crate::sink::StdoutSink);
+        let def = SubscriptionDef::new("test", Filter::new(), "stdout")
+            .shadow(0.1, "staging");
+        assert_eq!(
+            registry.define(def),
+            Err(RegistryError::UnknownSink("staging".to_string()))
+        );
+    }
+}
@@ -0,0 +1,480 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Testing against recorded events instead of the live feed
+//!
+//! [`replay_from_file()`] feeds an NDJSON fixture with one raw message per
+//! line, like the recordings an [`Archive`](crate::archive::Archive)
+//! reads, straight into the parsing and dispatch pipeline. To also cover
+//! connecting, reconnecting and resuming, [`MockServer`] serves the
+//! messages over SSE from a local port, for a stream built with
+//! [`EventStreamBuilder::url()`](crate::EventStreamBuilder::url). Neither
+//! touches the network, so tests are fast and deterministic.
+use crate::resume::{RawEvent, ResumeToken};
+use crate::{backend, EventStream};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How often idle connections check whether the server was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Stream the events in the NDJSON file at `path`, one raw message per
+/// line. Blank lines are skipped.
+pub fn replay_from_file(path: impl AsRef<Path>) -> io::Result<EventStream> {
+    let messages: Vec<String> = fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    Ok(EventStream::from_backend(backend::memory(messages), None))
+}
+
+/// `seconds` after the start of 2021, for timestamps in tests
+#[cfg(test)]
+pub(crate) fn at(seconds: i64) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(1_609_459_200 + seconds, 0).unwrap()
+}
+
+/// An edit to `title` on English Wikipedia at `dt`, `offset` in its
+/// partition
+#[cfg(test)]
+pub(crate) fn edit(
+    offset: u64,
+    title: &str,
+    dt: chrono::DateTime<chrono::Utc>,
+) -> serde_json::Value {
+    serde_json::json!({
+        "$schema": "/mediawiki/recentchange/1.0.0",
+        "meta": {
+            "uri": format!("https://en.wikipedia.org/wiki/{}", title),
+            "request_id": format!("request-{}", offset),
+            "id": format!("id-{}", offset),
+            "dt": dt.to_rfc3339(),
+            "domain": "en.wikipedia.org",
+            "stream": "mediawiki.recentchange",
+            "topic": "eqiad.mediawiki.recentchange",
+            "partition": 0,
+            "offset": offset,
+        },
+        "id": offset,
+        "type": "edit",
+        "namespace": 0,
+        "title": title,
+        "comment": "Fixed a typo",
+        "parsedcomment": "Fixed a typo",
+        "timestamp": dt.timestamp(),
+        "user": "Alice",
+        "bot": false,
+        "minor": false,
+        "patrolled": false,
+        "length": {"old": 10, "new": 20},
+        "revision": {"old": offset, "new": offset + 1},
+        "server_url": "https://en.wikipedia.org",
+        "server_name": "en.wikipedia.org",
+        "server_script_path": "/w",
+        "wiki": "enwiki",
+    })
+}
+
+/// [`edit()`] as an [`Event`](crate::Event)
+#[cfg(test)]
+pub(crate) fn edit_event(
+    offset: u64,
+    title: &str,
+    dt: chrono::DateTime<chrono::Utc>,
+) -> crate::Event {
+    event(&edit(offset, title, dt))
+}
+
+/// An upload of `title` on English Wikipedia at `dt`, `offset` in its
+/// partition
+#[cfg(test)]
+pub(crate) fn log(
+    offset: u64,
+    title: &str,
+    dt: chrono::DateTime<chrono::Utc>,
+) -> serde_json::Value {
+    let mut log = edit(offset, title, dt);
+    let fields = log.as_object_mut().unwrap();
+    for field in ["length", "revision", "minor", "patrolled"] {
+        fields.remove(field);
+    }
+    fields.insert("type".to_string(), "log".into());
+    fields.insert("log_id".to_string(), offset.into());
+    fields.insert("log_type".to_string(), "upload".into());
+    fields.insert("log_action".to_string(), "upload".into());
+    fields.insert("log_params".to_string(), serde_json::json!({}));
+    fields.insert("log_action_comment".to_string(), "uploaded".into());
+    log
+}
+
+/// Parse `message` like a message from EventStreams
+#[cfg(test)]
+pub(crate) fn event(message: &serde_json::Value) -> crate::Event {
+    crate::handle_event(&message.to_string()).unwrap().unwrap()
+}
+
+/// A request received by a [`MockServer`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MockRequest {
+    /// Path and query, e.g. `/v2/stream/recentchange?since=...`
+    pub path: String,
+    /// Header names are lowercased
+    pub headers: HashMap<String, String>,
+    /// Request body, e.g. for POST requests
+    pub body: String,
+}
+
+/// An SSE server on localhost that sends recorded messages to every
+/// connection, like EventStreams would. Each message is sent with its
+/// position as the SSE `id`, and a `Last-Event-ID` header skips the
+/// messages up to that position, so messages need a `meta` with a topic,
+/// partition and offset for resuming to work. Messages spanning several
+/// lines are sent as one `data:` field per line. Once all messages are
+/// sent, the connection is kept open until the server stops.
+#[derive(Clone, Debug)]
+pub struct MockServer {
+    messages: Vec<String>,
+    disconnect_after: Option<usize>,
+    write_size: Option<usize>,
+}
+
+impl MockServer {
+    pub fn new(messages: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            messages: messages.into_iter().collect(),
+            disconnect_after: None,
+            write_size: None,
+        }
+    }
+
+    /// Serve the messages in an NDJSON file, see [`new()`](Self::new)
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(
+            fs::read_to_string(path)?
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(str::to_string),
+        ))
+    }
+
+    /// Drop each connection after sending `messages` messages, to test
+    /// reconnecting and resuming
+    pub fn disconnect_after(mut self, messages: usize) -> Self {
+        self.disconnect_after = Some(messages);
+        self
+    }
+
+    /// Send messages `bytes` at a time, flushing in between, so that they
+    /// arrive split across reads
+    pub fn write_size(mut self, bytes: usize) -> Self {
+        self.write_size = Some(bytes.max(1));
+        self
+    }
+
+    /// Start listening on a free port. The server stops once the returned
+    /// handle is dropped.
+    pub fn start(self) -> io::Result<MockHandle> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let handle = MockHandle {
+            addr,
+            requests: Arc::new(Mutex::new(vec![])),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let server = Arc::new(self);
+        let requests = handle.requests.clone();
+        let stopped = handle.stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopped.load(Ordering::SeqCst) {
+                    return;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let server = server.clone();
+                let requests = requests.clone();
+                let stopped = stopped.clone();
+                thread::spawn(move || {
+                    let _ = server.serve(stream, &requests, &stopped);
+                });
+            }
+        });
+        Ok(handle)
+    }
+
+    fn serve(
+        &self,
+        mut stream: TcpStream,
+        requests: &Mutex<Vec<MockRequest>>,
+        stopped: &AtomicBool,
+    ) -> io::Result<()> {
+        let request = read_request(&stream)?;
+        let after = request
+            .headers
+            .get("last-event-id")
+            .and_then(|id| ResumeToken::from_last_event_id(id))
+            .unwrap_or_default();
+        requests.lock().unwrap().push(request);
+        stream.set_nodelay(true)?;
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\r\n",
+        )?;
+        let mut sent = 0;
+        for message in &self.messages {
+            let position = serde_json::from_str::<RawEvent>(message)
+                .ok()
+                .map(|raw| raw.meta);
+            if position.as_ref().is_some_and(|pos| after.contains(pos)) {
+                continue;
+            }
+            if self.disconnect_after == Some(sent) {
+                return Ok(());
+            }
+            let mut frame = String::new();
+            if let Some(position) = position {
+                let id = ResumeToken::from(vec![position]).to_last_event_id();
+                frame.push_str(&format!("id: {}\n", id));
+            }
+            frame.push_str("event: message\n");
+            for line in message.split('\n') {
+                frame.push_str(&format!("data: {}\n", line));
+            }
+            frame.push('\n');
+            let write_size = self.write_size.unwrap_or(frame.len());
+            for chunk in frame.as_bytes().chunks(write_size) {
+                stream.write_all(chunk)?;
+                stream.flush()?;
+            }
+            sent += 1;
+        }
+        while !stopped.load(Ordering::SeqCst) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+/// A running [`MockServer`]
+#[derive(Debug)]
+pub struct MockHandle {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl MockHandle {
+    /// URL to stream from, e.g. with
+    /// [`EventStreamBuilder::url()`](crate::EventStreamBuilder::url)
+    pub fn url(&self) -> String {
+        format!("http://{}/v2/stream/recentchange", self.addr)
+    }
+
+    /// Address the server is listening on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Requests received so far, oldest first
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockHandle {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake up the listening thread so it notices
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+/// Answers each request with the next of `responses` as JSON, and with a
+/// 404 once they run out, for testing API clients
+#[cfg(test)]
+pub(crate) fn serve_json(responses: Vec<serde_json::Value>) -> MockHandle {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let handle = MockHandle {
+        addr: listener.local_addr().unwrap(),
+        requests: Arc::new(Mutex::new(vec![])),
+        stopped: Arc::new(AtomicBool::new(false)),
+    };
+    let requests = handle.requests.clone();
+    let stopped = handle.stopped.clone();
+    thread::spawn(move || {
+        let mut responses = responses.into_iter();
+        for stream in listener.incoming() {
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let request = match read_request(&stream) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            requests.lock().unwrap().push(request);
+            let (status, body) = match responses.next() {
+                Some(response) => ("200 OK", response.to_string()),
+                None => ("404 Not Found", "{}".to_string()),
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+        }
+    });
+    handle
+}
+
+/// Read the request line, headers and body
+fn read_request(stream: &TcpStream) -> io::Result<MockRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line
+        .split_whitespace()
+        .nth(1)
+        .unwrap_or("/")
+        .to_string();
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(
+                name.trim().to_ascii_lowercase(),
+                value.trim().to_string(),
+            );
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    io::Read::read_exact(&mut reader, &mut body)?;
+    Ok(MockRequest {
+        path,
+        headers,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Backoff;
+    use crate::clock::ManualClock;
+    use crate::EventStreamBuilder;
+    use chrono::{TimeZone, Utc};
+    use futures::StreamExt;
+
+    fn messages(count: u64) -> Vec<String> {
+        let dt = Utc.with_ymd_and_hms(2021, 1, 1, 0, 0, 0).unwrap();
+        (1..=count)
+            .map(|offset| edit(offset, &format!("Page {}", offset), dt))
+            .map(|message| message.to_string())
+            .collect()
+    }
+
+    fn titles(events: &[crate::Event]) -> Vec<&str> {
+        events.iter().map(|event| event.title()).collect()
+    }
+
+    #[test]
+    fn serves_messages_as_sse() {
+        let server = MockServer::new(messages(3)).start().unwrap();
+        let stream =
+            EventStreamBuilder::new().url(server.url()).build().unwrap();
+        let events: Vec<_> =
+            futures::executor::block_on(stream.take(3).collect());
+        assert_eq!(titles(&events), ["Page 1", "Page 2", "Page 3"]);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].path, "/v2/stream/recentchange");
+        assert!(!requests[0].headers.contains_key("last-event-id"));
+    }
+
+    #[test]
+    fn resumes_from_last_event_id() {
+        let server = MockServer::new(messages(3))
+            .disconnect_after(1)
+            .start()
+            .unwrap();
+        let clock = ManualClock::new(Utc::now());
+        let stream = EventStreamBuilder::new()
+            .url(server.url())
+            .clock(Arc::new(clock.clone()))
+            .backoff(Backoff::new(
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                1.0,
+            ))
+            .build()
+            .unwrap();
+        // Reconnecting waits for the clock, not the system time
+        let stop = Arc::new(AtomicBool::new(false));
+        let ticker = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    clock.advance(Duration::from_secs(60));
+                    thread::sleep(Duration::from_millis(10));
+                }
+            })
+        };
+        let events: Vec<_> =
+            futures::executor::block_on(stream.take(3).collect());
+        stop.store(true, Ordering::SeqCst);
+        ticker.join().unwrap();
+        assert_eq!(titles(&events), ["Page 1", "Page 2", "Page 3"]);
+        let requests = server.requests();
+        assert!(requests.len() >= 3);
+        assert!(requests[1..]
+            .iter()
+            .all(|request| request.headers.contains_key("last-event-id")));
+    }
+
+    #[test]
+    fn replays_fixture_files() {
+        let path = std::env::temp_dir()
+            .join(format!("eventstreams-replay-{}.ndjson", std::process::id()));
+        fs::write(&path, messages(2).join("\n\n")).unwrap();
+        let stream = replay_from_file(&path).unwrap();
+        let events: Vec<_> = futures::executor::block_on(stream.collect());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(titles(&events), ["Page 1", "Page 2"]);
+    }
+}
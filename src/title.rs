@@ -0,0 +1,158 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Canonical page titles
+//!
+//! Titles in events are already in MediaWiki's canonical form, but titles
+//! from elsewhere, e.g. a watchlist typed in by a user, may not be:
+//! `foo_bar` and `Foo bar` are the same page on most wikis, and so are the
+//! composed and decomposed forms of `é`. [`canonicalize()`] brings titles
+//! into the form events use, so they can be compared and used as keys.
+//!
+//! Namespace prefixes are only recognized by their canonical English names
+//! (see [`namespace`](crate::namespace)); localized ones are left as they
+//! are.
+use crate::namespace;
+use icu_normalizer::ComposingNormalizerBorrowed;
+
+/// Namespaces whose titles always start with a capital letter, even on
+/// wikis that otherwise don't capitalize: Special, User, User talk,
+/// MediaWiki and MediaWiki talk
+const ALWAYS_CAPITALIZED: &[i32] = &[-1, 2, 3, 8, 9];
+
+/// Normalize `title` without changing the case of its first letter:
+/// Unicode NFC, underscores as spaces, no repeated or surrounding
+/// whitespace, and the canonical spelling of a known namespace prefix,
+/// e.g. `user_talk: Foo__bar` becomes `User talk:Foo bar`
+pub fn normalize(title: &str) -> String {
+    let title = ComposingNormalizerBorrowed::new_nfc().normalize(title);
+    let title = title
+        .split(|c: char| c == '_' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    match split_namespace(&title) {
+        Some((id, page)) => {
+            format!("{}:{}", namespace::canonical_name(id).unwrap(), page)
+        }
+        None => title,
+    }
+}
+
+/// Whether titles in namespace `namespace` on `wiki` (internal database
+/// name) start with a capital letter. Wiktionaries are case-sensitive, so
+/// e.g. `apple` and `Apple` are different pages there.
+pub fn capitalizes(wiki: &str, namespace: i32) -> bool {
+    ALWAYS_CAPITALIZED.contains(&namespace) || !wiki.ends_with("wiktionary")
+}
+
+/// The title as it appears in events from `wiki` (internal database name):
+/// [normalized](normalize), with the first letter after the namespace
+/// capitalized where the wiki [does so](capitalizes)
+pub fn canonicalize(wiki: &str, title: &str) -> String {
+    let title = normalize(title);
+    let (id, page) = split_namespace(&title).unwrap_or((0, &title));
+    if !capitalizes(wiki, id) {
+        return title;
+    }
+    let prefix = &title[..title.len() - page.len()];
+    format!("{}{}", prefix, change_first(page, char::to_uppercase))
+}
+
+/// `title` from `wiki` with the first letter after the namespace in lower
+/// case, if the wiki capitalizes it, for matching titles that were given
+/// without capitalizing
+#[cfg(feature = "sinks")]
+pub(crate) fn uncapitalized(
+    wiki: &str,
+    namespace: i32,
+    title: &str,
+) -> Option<String> {
+    if !capitalizes(wiki, namespace) {
+        return None;
+    }
+    let page = match title.split_once(':') {
+        Some((_, page)) if namespace != 0 => page,
+        _ => title,
+    };
+    let prefix = &title[..title.len() - page.len()];
+    let lower = change_first(page, char::to_lowercase);
+    (lower != page).then(|| format!("{}{}", prefix, lower))
+}
+
+/// The namespace ID and the rest of a title with a known namespace prefix,
+/// which may be followed by a space
+fn split_namespace(title: &str) -> Option<(i32, &str)> {
+    let (prefix, page) = title.split_once(':')?;
+    Some((namespace::id(prefix)?, page.trim_start()))
+}
+
+/// `text` with its first character mapped, unless that would turn it into
+/// several characters, e.g. `ß` into `SS`
+fn change_first<I>(text: &str, map: impl Fn(char) -> I) -> String
+where
+    I: Iterator<Item = char> + ExactSizeIterator,
+{
+    let mut chars = text.chars();
+    let first = match chars.next() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut mapped = map(first);
+    match (mapped.len(), mapped.next()) {
+        (1, Some(mapped)) => std::iter::once(mapped).chain(chars).collect(),
+        _ => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_spacing_composition_and_namespaces() {
+        assert_eq!(normalize("user_talk: Foo__bar "), "User talk:Foo bar");
+        assert_eq!(normalize("Cafe\u{301}"), "Caf\u{e9}");
+        assert_eq!(normalize("foo_bar"), "foo bar");
+        assert_eq!(normalize("Not a namespace:x"), "Not a namespace:x");
+    }
+
+    #[test]
+    fn capitalizes_where_the_wiki_does() {
+        assert_eq!(canonicalize("enwiki", "apple_pie"), "Apple pie");
+        assert_eq!(canonicalize("enwiki", "talk:apple"), "Talk:Apple");
+        assert_eq!(canonicalize("enwiktionary", "apple"), "apple");
+        assert_eq!(canonicalize("enwiktionary", "user:bob"), "User:Bob");
+        assert_eq!(canonicalize("dewiki", "ßtraße"), "ßtraße");
+        assert_eq!(canonicalize("enwiki", ""), "");
+    }
+
+    #[cfg(feature = "sinks")]
+    #[test]
+    fn filters_match_uncapitalized_titles() {
+        use crate::filter::Filter;
+        use crate::testing::{self, at};
+
+        assert_eq!(
+            uncapitalized("enwiki", 1, "Talk:Apple").as_deref(),
+            Some("Talk:apple")
+        );
+        assert_eq!(uncapitalized("enwiktionary", 0, "Apple"), None);
+        let filter = Filter::new().titles(["apple_pie"]);
+        let edit = testing::edit_event(1, "Apple pie", at(0));
+        assert!(filter.matches(&edit));
+    }
+}
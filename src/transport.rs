@@ -0,0 +1,293 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! HTTP clients that requests are made with
+//!
+//! Every request, whether to EventStreams itself or to the Action API,
+//! goes through a [`Transport`], which creates the underlying
+//! [`HttpClient`]. Which transports are built in depends on features:
+//!
+//! * `curl` (default): libcurl through isahc, which supports proxies
+//! * `native-tls`: async-h1 with the platform's TLS library, e.g. OpenSSL
+//!   on Linux
+//! * `rustls`: async-h1 with rustls and bundled root certificates, which
+//!   needs no system libraries, e.g. for static musl builds
+//!
+//! The first one enabled in that order is the [default](default_transport),
+//! and any other one can be set with
+//! [`ClientOptions::transport()`](crate::backend::ClientOptions::transport),
+//! including one wrapping an [`HttpClient`] of the application's own. With
+//! none of them enabled, e.g. with `default-features = false`, requests
+//! fail until a transport is set.
+//!
+//! Custom root certificates, e.g. for a corporate proxy that intercepts
+//! TLS, can be added with
+//! [`ClientOptions::ca_certificate()`](crate::backend::ClientOptions::ca_certificate).
+use crate::backend::ClientOptions;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+use http_client::h1::H1Client;
+pub use http_client::HttpClient;
+#[cfg(not(any(
+    feature = "curl",
+    feature = "native-tls",
+    feature = "rustls"
+)))]
+use http_client::{http_types::StatusCode, Error, Request, Response};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Creates the HTTP clients requests are made with
+pub trait Transport: fmt::Debug + Send + Sync {
+    /// A client configured with `options`' proxy, timeouts and
+    /// certificates, as far as it supports them
+    fn http_client(
+        &self,
+        options: &ClientOptions,
+    ) -> Result<Box<dyn HttpClient>, TransportError>;
+}
+
+/// Why a [`Transport`] couldn't create a client from the
+/// [`ClientOptions`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TransportError {
+    /// The proxy URL couldn't be parsed
+    InvalidProxy { proxy: String, reason: String },
+    /// The CA certificate file couldn't be read or parsed
+    CaCertificate { path: PathBuf, reason: String },
+    /// The underlying client couldn't be created
+    Client(String),
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidProxy { proxy, reason } => {
+                write!(f, "invalid proxy URL {}: {}", proxy, reason)
+            }
+            Self::CaCertificate { path, reason } => write!(
+                f,
+                "invalid CA certificate file {}: {}",
+                path.display(),
+                reason
+            ),
+            Self::Client(err) => {
+                write!(f, "failed to create HTTP client: {}", err)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// The first built-in transport that's enabled, see the
+/// [module documentation](self)
+pub fn default_transport() -> Arc<dyn Transport> {
+    #[cfg(feature = "curl")]
+    return Arc::new(CurlTransport);
+    #[cfg(all(
+        not(feature = "curl"),
+        any(feature = "native-tls", feature = "rustls")
+    ))]
+    return Arc::new(H1Transport);
+    #[cfg(not(any(
+        feature = "curl",
+        feature = "native-tls",
+        feature = "rustls"
+    )))]
+    return Arc::new(NoTransport);
+}
+
+/// A client for `options`, through its transport
+pub(crate) fn client(
+    options: &ClientOptions,
+) -> Result<surf::Client, TransportError> {
+    let transport = options.transport_or_default();
+    Ok(surf::Client::with_http_client(
+        transport.http_client(options)?,
+    ))
+}
+
+/// A client for the default options, which only fails if the HTTP library
+/// itself can't be set up
+pub(crate) fn default_client() -> surf::Client {
+    client(&ClientOptions::default()).expect("failed to create HTTP client")
+}
+
+/// libcurl through isahc
+#[cfg(feature = "curl")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CurlTransport;
+
+#[cfg(feature = "curl")]
+impl Transport for CurlTransport {
+    fn http_client(
+        &self,
+        options: &ClientOptions,
+    ) -> Result<Box<dyn HttpClient>, TransportError> {
+        use isahc::config::{CaCertificate, Configurable};
+        let mut builder = isahc::HttpClient::builder();
+        if let Some(proxy) = &options.proxy {
+            let uri: isahc::http::Uri = proxy.parse().map_err(
+                |err: isahc::http::uri::InvalidUri| {
+                    TransportError::InvalidProxy {
+                        proxy: proxy.clone(),
+                        reason: err.to_string(),
+                    }
+                },
+            )?;
+            builder = builder.proxy(uri);
+        }
+        if let Some(timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(path) = &options.ca_certificate {
+            builder = builder.ssl_ca_certificate(CaCertificate::file(path));
+        }
+        let client = builder
+            .build()
+            .map_err(|err| TransportError::Client(err.to_string()))?;
+        Ok(Box::new(http_client::isahc::IsahcClient::from_client(
+            client,
+        )))
+    }
+}
+
+/// async-h1, with rustls if the `rustls` feature is enabled and the
+/// platform's TLS library otherwise. Proxies aren't supported.
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct H1Transport;
+
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+impl Transport for H1Transport {
+    fn http_client(
+        &self,
+        options: &ClientOptions,
+    ) -> Result<Box<dyn HttpClient>, TransportError> {
+        use std::convert::TryFrom;
+        let mut config = http_client::Config::new()
+            .set_timeout(options.connect_timeout)
+            .set_http_keep_alive(true);
+        if let Some(path) = &options.ca_certificate {
+            let invalid = |reason: String| TransportError::CaCertificate {
+                path: path.clone(),
+                reason,
+            };
+            let pem =
+                std::fs::read(path).map_err(|err| invalid(err.to_string()))?;
+            let tls = tls_config(&pem).map_err(invalid)?;
+            config = config.set_tls_config(Some(Arc::new(tls)));
+        }
+        let client = H1Client::try_from(config)
+            .map_err(|err| TransportError::Client(err.to_string()))?;
+        Ok(Box::new(client))
+    }
+}
+
+/// Bundled roots plus the certificates in `pem`
+#[cfg(feature = "rustls")]
+fn tls_config(pem: &[u8]) -> Result<rustls::ClientConfig, String> {
+    let mut config = rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    match config.root_store.add_pem_file(&mut &pem[..]) {
+        Ok((valid, _)) if valid > 0 => Ok(config),
+        _ => Err("no valid certificates".to_string()),
+    }
+}
+
+/// System roots plus the certificate in `pem`
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn tls_config(pem: &[u8]) -> Result<async_native_tls::TlsConnector, String> {
+    let certificate = async_native_tls::Certificate::from_pem(pem)
+        .map_err(|err| err.to_string())?;
+    Ok(async_native_tls::TlsConnector::new().add_root_certificate(certificate))
+}
+
+/// Fails every request, for when no transport is built in
+#[cfg(not(any(feature = "curl", feature = "native-tls", feature = "rustls")))]
+#[derive(Clone, Copy, Debug, Default)]
+struct NoTransport;
+
+#[cfg(not(any(feature = "curl", feature = "native-tls", feature = "rustls")))]
+impl Transport for NoTransport {
+    fn http_client(
+        &self,
+        _: &ClientOptions,
+    ) -> Result<Box<dyn HttpClient>, TransportError> {
+        Ok(Box::new(NoClient))
+    }
+}
+
+#[cfg(not(any(feature = "curl", feature = "native-tls", feature = "rustls")))]
+#[derive(Clone, Copy, Debug)]
+struct NoClient;
+
+#[cfg(not(any(feature = "curl", feature = "native-tls", feature = "rustls")))]
+#[http_client::async_trait]
+impl HttpClient for NoClient {
+    async fn send(&self, _: Request) -> Result<Response, Error> {
+        Err(Error::from_str(
+            StatusCode::InternalServerError,
+            "no HTTP transport: enable the curl, native-tls or rustls \
+             feature, or set one with ClientOptions::transport()",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Unavailable;
+
+    impl Transport for Unavailable {
+        fn http_client(
+            &self,
+            _: &ClientOptions,
+        ) -> Result<Box<dyn HttpClient>, TransportError> {
+            Err(TransportError::Client("unavailable".to_string()))
+        }
+    }
+
+    #[test]
+    fn creates_clients_through_the_configured_transport() {
+        let options = ClientOptions::default().transport(Arc::new(Unavailable));
+        let err = client(&options).unwrap_err();
+        assert_eq!(err, TransportError::Client("unavailable".to_string()));
+        assert_eq!(
+            err.to_string(),
+            "failed to create HTTP client: unavailable"
+        );
+    }
+
+    #[cfg(feature = "curl")]
+    #[test]
+    fn rejects_invalid_proxies() {
+        let options = ClientOptions::default().proxy("http://proxy host:3128");
+        match CurlTransport.http_client(&options) {
+            Err(TransportError::InvalidProxy { proxy, .. }) => {
+                assert_eq!(proxy, "http://proxy host:3128")
+            }
+            other => panic!("{:?}", other.map(|_| ())),
+        }
+        let options = ClientOptions::default().proxy("http://proxy:3128");
+        assert!(CurlTransport.http_client(&options).is_ok());
+    }
+}
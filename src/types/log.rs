@@ -0,0 +1,212 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Log entries from the `recentchange` stream
+use super::recentchange::datetime;
+use super::EventMeta;
+use crate::project::{self, Project};
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Represents a log entry
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LogEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Recent changes ID, used by [`rcfeed`](crate::rcfeed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<u64>,
+    #[allow(dead_code)]
+    #[serde(rename = "type")]
+    pub(crate) type_: CompactString,
+    /// Namespace ID
+    pub namespace: i32,
+    /// Prefixed title (includes namespace name)
+    pub title: String,
+    /// Edit summary ([comment_text](https://www.mediawiki.org/wiki/Manual:Comment_table#comment_text))
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](LogEvent#structfield.comment)
+    pub parsedcomment: String,
+    /// Unix timestamp
+    pub timestamp: u32,
+    /// Username ([actor_name](https://www.mediawiki.org/wiki/Manual:Actor_table#actor_name))
+    pub user: CompactString,
+    /// Whether the edit was flagged as by a bot ([rc_bot](https://www.mediawiki.org/wiki/Manual:Recentchanges_table#rc_bot))
+    pub bot: bool,
+    pub log_id: u32,
+    pub log_type: CompactString,
+    pub log_action: CompactString,
+    /// Depends on the log type, see [`params()`](LogEvent::params)
+    pub log_params: Value,
+    pub log_action_comment: String,
+    /// URL of wiki with protocol, e.g. `https://www.wikidata.org`
+    pub server_url: CompactString,
+    /// Domain of wiki with no protocol, e.g. `www.wikidata.org` or `en.wikipedia.org`
+    pub server_name: CompactString,
+    /// Base URL path of wiki ([$wgScriptPath](https://www.mediawiki.org/wiki/Manual:$wgScriptPath))
+    pub server_script_path: CompactString,
+    /// Internal database name (usually [$wgDBname](https://www.mediawiki.org/wiki/Manual:$wgDBname))
+    pub wiki: CompactString,
+    #[serde(skip)]
+    pub(crate) backfilled: bool,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl LogEvent {
+    /// [`timestamp`](LogEvent#structfield.timestamp) as a date and time
+    pub fn datetime(&self) -> DateTime<Utc> {
+        datetime(self.timestamp)
+    }
+
+    /// Whether the event was reconstructed from the Action API by
+    /// [`backfill`](crate::backfill) rather than received from the stream
+    pub fn is_backfilled(&self) -> bool {
+        self.backfilled
+    }
+
+    /// URL to the wiki's api.php ("[Action API](https://www.mediawiki.org/wiki/API:Main_page)") endpoint
+    pub fn api_url(&self) -> String {
+        format!("{}{}/api.php", self.server_url, self.server_script_path)
+    }
+
+    /// Project the wiki belongs to, going by its domain
+    pub fn project(&self) -> Project {
+        Project::from_server_name(&self.server_name)
+    }
+
+    /// Language code from the wiki's domain, see
+    /// [`Event::language()`](crate::Event::language)
+    pub fn language(&self) -> Option<&str> {
+        project::language(&self.server_name)
+    }
+
+    /// Whether the wiki is a production Wikimedia wiki, as opposed to e.g.
+    /// the beta cluster
+    pub fn is_wikimedia_production(&self) -> bool {
+        project::is_wikimedia_production(&self.server_name)
+    }
+
+    /// For `rights` log entries, the user's groups before the change
+    pub fn old_groups(&self) -> Vec<String> {
+        self.rights_param("oldgroups")
+    }
+
+    /// For `rights` log entries, the user's groups after the change
+    pub fn new_groups(&self) -> Vec<String> {
+        self.rights_param("newgroups")
+    }
+
+    /// For `rights` log entries, groups the user was added to
+    pub fn added_groups(&self) -> Vec<String> {
+        let old = self.old_groups();
+        self.new_groups()
+            .into_iter()
+            .filter(|group| !old.contains(group))
+            .collect()
+    }
+
+    /// For `rights` log entries, groups the user was removed from
+    pub fn removed_groups(&self) -> Vec<String> {
+        let new = self.new_groups();
+        self.old_groups()
+            .into_iter()
+            .filter(|group| !new.contains(group))
+            .collect()
+    }
+
+    fn rights_param(&self, name: &str) -> Vec<String> {
+        if self.log_type != "rights" {
+            return vec![];
+        }
+        list_param(&self.log_params[name])
+    }
+}
+
+pub(crate) fn string_param(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) if !value.is_empty() => Some(value.clone()),
+        _ => None,
+    }
+}
+
+/// Parameters are lists in newer log entries, and comma-separated strings
+/// in older ones
+pub(crate) fn list_param(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .filter_map(|value| value.as_str().map(str::to_string))
+            .collect(),
+        Value::String(values) => values
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    fn rights(old: serde_json::Value, new: serde_json::Value) -> Event {
+        let mut log = testing::log(1, "User:Alice", at(0));
+        log["log_type"] = "rights".into();
+        log["log_action"] = "rights".into();
+        log["log_params"] =
+            serde_json::json!({ "oldgroups": old, "newgroups": new });
+        testing::event(&log)
+    }
+
+    #[test]
+    fn diffs_rights_changes() {
+        let event = rights(
+            serde_json::json!(["autopatrolled", "rollbacker"]),
+            serde_json::json!(["rollbacker", "sysop"]),
+        );
+        let log = match &event {
+            Event::Log(log) => log,
+            _ => unreachable!(),
+        };
+        assert_eq!(log.added_groups(), ["sysop"]);
+        assert_eq!(log.removed_groups(), ["autopatrolled"]);
+
+        // Older entries use comma-separated strings
+        let event = rights("".into(), "bot, flood".into());
+        let log = match &event {
+            Event::Log(log) => log,
+            _ => unreachable!(),
+        };
+        assert_eq!(log.added_groups(), ["bot", "flood"]);
+        assert!(log.removed_groups().is_empty());
+
+        let upload = testing::event(&testing::log(2, "File:A.png", at(0)));
+        match upload {
+            Event::Log(log) => assert!(log.new_groups().is_empty()),
+            _ => unreachable!(),
+        }
+    }
+}
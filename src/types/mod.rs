@@ -0,0 +1,628 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Typed events
+//!
+//! Event types are grouped by the streams they come from, in
+//! [`recentchange`], [`log`], [`revision`] and [`page`]. They're all
+//! re-exported here and at the crate root, and from [`prelude`] for glob
+//! imports.
+use crate::extension::ExtensionEvent;
+use crate::links::PageLinksChange;
+use crate::project::{self, Project};
+use crate::resume::Position;
+use crate::StreamKind;
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+pub mod log;
+pub mod page;
+pub mod recentchange;
+pub mod revision;
+
+pub use log::LogEvent;
+pub use page::{
+    PageCreateEvent, PageDeleteEvent, PageLifecycle, PageMoveEvent,
+    PageUndeleteEvent, PriorPageState, PriorUndeleteState,
+};
+pub use recentchange::{
+    CategorizeEvent, CategoryChange, EditEvent, EventLength, EventRevision,
+    ExternalEvent, NewPageEvent,
+};
+pub use revision::{
+    RevisionCreateEvent, RevisionScoreEvent, RevisionSlot, Score,
+};
+
+/// Event types, for `use eventstreams::types::prelude::*`
+pub mod prelude {
+    pub use super::{
+        CategorizeEvent, CategoryChange, EditEvent, Event, EventMeta,
+        ExternalEvent, LogEvent, NewPageEvent, PageCreateEvent,
+        PageDeleteEvent, PageLifecycle, PageMoveEvent, PageUndeleteEvent,
+        Performer, PriorPageState, PriorUndeleteState, RevisionCreateEvent,
+        RevisionScoreEvent, RevisionSlot, Score, TestEvent, UnknownEvent,
+    };
+}
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    Edit(EditEvent),
+    /// A page creation
+    New(NewPageEvent),
+    Log(LogEvent),
+    Categorize(CategorizeEvent),
+    /// A change to a page made elsewhere, e.g. on Wikidata
+    External(ExternalEvent),
+    /// From the `revision-create` stream
+    RevisionCreate(RevisionCreateEvent),
+    /// From the `page-create` stream
+    PageCreate(PageCreateEvent),
+    /// From the `page-delete` stream
+    PageDelete(PageDeleteEvent),
+    /// From the `page-move` stream
+    PageMove(PageMoveEvent),
+    /// From the `page-undelete` stream
+    PageUndelete(PageUndeleteEvent),
+    /// From the `page-links-change` stream
+    PageLinksChange(PageLinksChange),
+    /// From the `revision-score` stream
+    RevisionScore(RevisionScoreEvent),
+    /// From the `test` stream, see [`readiness`](crate::readiness)
+    Test(TestEvent),
+    /// An event from a stream registered by another crate, see
+    /// [`extension`](crate::extension)
+    Extension(ExtensionEvent),
+    /// An event of a type that isn't supported yet
+    Unknown(UnknownEvent),
+}
+
+impl Event {
+    /// Metadata added by EventStreams, like the event's unique ID and
+    /// where it is in the stream
+    pub fn meta(&self) -> &EventMeta {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.meta,
+            Event::Log(log) => &log.meta,
+            Event::Categorize(categorize) => &categorize.meta,
+            Event::External(external) => &external.meta,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                &revision.meta
+            }
+            Event::PageDelete(delete) => &delete.meta,
+            Event::PageMove(move_) => &move_.meta,
+            Event::PageUndelete(undelete) => &undelete.meta,
+            Event::PageLinksChange(links) => &links.meta,
+            Event::RevisionScore(score) => &score.meta,
+            Event::Test(test) => &test.meta,
+            Event::Extension(extension) => &extension.meta,
+            Event::Unknown(unknown) => &unknown.meta,
+        }
+    }
+
+    pub(crate) fn meta_mut(&mut self) -> &mut EventMeta {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &mut edit.meta,
+            Event::Log(log) => &mut log.meta,
+            Event::Categorize(categorize) => &mut categorize.meta,
+            Event::External(external) => &mut external.meta,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                &mut revision.meta
+            }
+            Event::PageDelete(delete) => &mut delete.meta,
+            Event::PageMove(move_) => &mut move_.meta,
+            Event::PageUndelete(undelete) => &mut undelete.meta,
+            Event::PageLinksChange(links) => &mut links.meta,
+            Event::RevisionScore(score) => &mut score.meta,
+            Event::Test(test) => &mut test.meta,
+            Event::Extension(extension) => &mut extension.meta,
+            Event::Unknown(unknown) => &mut unknown.meta,
+        }
+    }
+
+    /// Whether this is a canary event, which Wikimedia injects into
+    /// streams to check that they work rather than because anything
+    /// happened on a wiki
+    pub fn is_canary(&self) -> bool {
+        self.meta().domain == "canary"
+    }
+
+    /// The message the event was parsed from, exactly as received, if the
+    /// stream [keeps them](crate::EventStreamBuilder::keep_raw). Useful
+    /// for logging, archiving, or reading fields that aren't modelled
+    /// here yet.
+    pub fn raw(&self) -> Option<&str> {
+        self.meta().raw.as_deref()
+    }
+
+    /// [`raw()`](Self::raw) parsed as JSON
+    pub fn raw_value(&self) -> Option<Value> {
+        serde_json::from_str(self.raw()?).ok()
+    }
+
+    /// Prefixed title of the page the event is about, or for page moves,
+    /// its new title. Empty for test and extension events, and unknown
+    /// events without a title.
+    pub fn title(&self) -> &str {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.title,
+            Event::Log(log) => &log.title,
+            Event::Categorize(categorize) => &categorize.title,
+            Event::External(external) => &external.title,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                &revision.page_title
+            }
+            Event::PageDelete(delete) => &delete.page_title,
+            Event::PageMove(move_) => &move_.page_title,
+            Event::PageUndelete(undelete) => &undelete.page_title,
+            Event::PageLinksChange(links) => &links.page_title,
+            Event::RevisionScore(score) => &score.page_title,
+            Event::Test(_) | Event::Extension(_) => "",
+            Event::Unknown(unknown) => unknown.field(&["title", "page_title"]),
+        }
+    }
+
+    /// Edit summary, log comment, or automatic summary for
+    /// categorization. Empty for link changes, revision scores, test and
+    /// extension events.
+    pub fn comment(&self) -> &str {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.comment,
+            Event::Log(log) => &log.comment,
+            Event::Categorize(categorize) => &categorize.comment,
+            Event::External(external) => &external.comment,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                &revision.comment
+            }
+            Event::PageDelete(delete) => &delete.comment,
+            Event::PageMove(move_) => &move_.comment,
+            Event::PageUndelete(undelete) => &undelete.comment,
+            Event::PageLinksChange(_)
+            | Event::RevisionScore(_)
+            | Event::Test(_)
+            | Event::Extension(_) => "",
+            Event::Unknown(unknown) => unknown.field(&["comment"]),
+        }
+    }
+
+    /// Username of whoever caused the event. Empty if it has been
+    /// suppressed, and for link changes, test and extension events.
+    pub fn user(&self) -> &str {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.user,
+            Event::Log(log) => &log.user,
+            Event::Categorize(categorize) => &categorize.user,
+            Event::External(external) => &external.user,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                Performer::name(&revision.performer)
+            }
+            Event::PageDelete(delete) => Performer::name(&delete.performer),
+            Event::PageMove(move_) => Performer::name(&move_.performer),
+            Event::PageUndelete(undelete) => {
+                Performer::name(&undelete.performer)
+            }
+            Event::RevisionScore(score) => Performer::name(&score.performer),
+            Event::PageLinksChange(_)
+            | Event::Test(_)
+            | Event::Extension(_) => "",
+            Event::Unknown(unknown) => unknown.field(&["user"]),
+        }
+    }
+
+    /// Namespace ID of the page the event is about, or for page moves,
+    /// its new namespace. `None` for test and extension events, and
+    /// unknown events without a namespace.
+    pub fn namespace(&self) -> Option<i32> {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => Some(edit.namespace),
+            Event::Log(log) => Some(log.namespace),
+            Event::Categorize(categorize) => Some(categorize.namespace),
+            Event::External(external) => Some(external.namespace),
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                Some(revision.page_namespace)
+            }
+            Event::PageDelete(delete) => Some(delete.page_namespace),
+            Event::PageMove(move_) => Some(move_.page_namespace),
+            Event::PageUndelete(undelete) => Some(undelete.page_namespace),
+            Event::PageLinksChange(links) => Some(links.page_namespace),
+            Event::RevisionScore(score) => Some(score.page_namespace),
+            Event::Test(_) | Event::Extension(_) => None,
+            Event::Unknown(unknown) => ["namespace", "page_namespace"]
+                .iter()
+                .find_map(|name| unknown.value[name].as_i64())
+                .and_then(|namespace| i32::try_from(namespace).ok()),
+        }
+    }
+
+    /// Whether whoever caused the event is a bot. `false` if that isn't
+    /// known, e.g. for link changes.
+    pub fn is_bot(&self) -> bool {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => edit.bot,
+            Event::Log(log) => log.bot,
+            Event::Categorize(categorize) => categorize.bot,
+            Event::External(external) => external.bot,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                Performer::is_bot(&revision.performer)
+            }
+            Event::PageDelete(delete) => Performer::is_bot(&delete.performer),
+            Event::PageMove(move_) => Performer::is_bot(&move_.performer),
+            Event::PageUndelete(undelete) => {
+                Performer::is_bot(&undelete.performer)
+            }
+            Event::RevisionScore(score) => Performer::is_bot(&score.performer),
+            Event::PageLinksChange(_)
+            | Event::Test(_)
+            | Event::Extension(_) => false,
+            Event::Unknown(unknown) => {
+                unknown.value["bot"].as_bool().unwrap_or_default()
+            }
+        }
+    }
+
+    /// Domain of wiki with no protocol, e.g. `www.wikidata.org` or `en.wikipedia.org`
+    pub fn server_name(&self) -> &str {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.server_name,
+            Event::Log(log) => &log.server_name,
+            Event::Categorize(categorize) => &categorize.server_name,
+            Event::External(external) => &external.server_name,
+            // Events outside of recent changes only have the domain in meta
+            _ => &self.meta().domain,
+        }
+    }
+
+    /// Whether the event is on `wiki`, given by domain
+    /// (`en.wikipedia.org`), server URL (`https://en.wikipedia.org`) or
+    /// internal database name (`enwiki`)
+    pub fn is_on_wiki(&self, wiki: &str) -> bool {
+        let wiki = project::wiki_id(wiki);
+        wiki == self.server_name() || (!wiki.is_empty() && wiki == self.wiki())
+    }
+
+    /// Internal database name of the wiki, e.g. `enwiki`. Empty for test
+    /// and extension events.
+    pub fn wiki(&self) -> &str {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => &edit.wiki,
+            Event::Log(log) => &log.wiki,
+            Event::Categorize(categorize) => &categorize.wiki,
+            Event::External(external) => &external.wiki,
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                &revision.database
+            }
+            Event::PageDelete(delete) => &delete.database,
+            Event::PageMove(move_) => &move_.database,
+            Event::PageUndelete(undelete) => &undelete.database,
+            Event::PageLinksChange(links) => &links.database,
+            Event::RevisionScore(score) => &score.database,
+            Event::Test(_) | Event::Extension(_) => "",
+            Event::Unknown(unknown) => unknown.field(&["wiki", "database"]),
+        }
+    }
+
+    /// Language code from the wiki's domain, e.g. `en` for
+    /// `en.wikipedia.org`. `None` for wikis that aren't a language edition,
+    /// like `www.wikidata.org` or `commons.wikimedia.org`.
+    pub fn language(&self) -> Option<&str> {
+        project::language(self.server_name())
+    }
+
+    /// Project the wiki belongs to, going by its domain
+    pub fn project(&self) -> Project {
+        Project::from_server_name(self.server_name())
+    }
+
+    /// Whether the wiki is a production Wikimedia wiki, as opposed to e.g.
+    /// the beta cluster
+    pub fn is_wikimedia_production(&self) -> bool {
+        project::is_wikimedia_production(self.server_name())
+    }
+
+    /// Where the event is in the stream
+    pub fn position(&self) -> Position {
+        let meta = self.meta();
+        Position {
+            topic: meta.topic.clone(),
+            partition: meta.partition,
+            offset: meta.offset,
+        }
+    }
+
+    /// Time the event happened ([meta.dt](https://wikitech.wikimedia.org/wiki/Event_Platform/Schemas/Guidelines#Required_fields))
+    pub fn dt(&self) -> DateTime<Utc> {
+        self.meta().dt
+    }
+
+    /// The event as a page being created, deleted, undeleted or moved, if
+    /// it's from one of the page lifecycle streams
+    pub fn page_lifecycle(&self) -> Option<PageLifecycle<'_>> {
+        match self {
+            Event::PageCreate(create) => Some(PageLifecycle::Created(create)),
+            Event::PageDelete(delete) => Some(PageLifecycle::Deleted(delete)),
+            Event::PageUndelete(undelete) => {
+                Some(PageLifecycle::Undeleted(undelete))
+            }
+            Event::PageMove(move_) => Some(PageLifecycle::Moved(move_)),
+            _ => None,
+        }
+    }
+
+    /// Which stream the event came from, going by `meta.stream`. `None`
+    /// for streams not listed in [`StreamKind`], e.g. extension streams.
+    pub fn stream_kind(&self) -> Option<StreamKind> {
+        StreamKind::from_stream(&self.meta().stream)
+    }
+}
+
+/// Serializes to the JSON the event was parsed from, give or take
+/// whitespace and the order of object keys
+impl Serialize for Event {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match self {
+            Event::Edit(edit) | Event::New(edit) => edit.serialize(serializer),
+            Event::Log(log) => log.serialize(serializer),
+            Event::Categorize(categorize) => categorize.serialize(serializer),
+            Event::External(external) => external.serialize(serializer),
+            Event::RevisionCreate(revision) | Event::PageCreate(revision) => {
+                revision.serialize(serializer)
+            }
+            Event::PageDelete(delete) => delete.serialize(serializer),
+            Event::PageMove(move_) => move_.serialize(serializer),
+            Event::PageUndelete(undelete) => undelete.serialize(serializer),
+            Event::PageLinksChange(links) => links.serialize(serializer),
+            Event::RevisionScore(score) => score.serialize(serializer),
+            Event::Test(test) => test.serialize(serializer),
+            Event::Extension(extension) => extension.serialize(serializer),
+            Event::Unknown(unknown) => unknown.value.serialize(serializer),
+        }
+    }
+}
+
+/// An event that couldn't be turned into one of the other kinds because
+/// its type isn't supported, e.g. from a stream or recent changes type
+/// that is newer than this crate
+#[derive(Clone, Debug)]
+pub struct UnknownEvent {
+    pub meta: EventMeta,
+    /// The whole event as received
+    pub value: Value,
+}
+
+impl UnknownEvent {
+    /// Name of the stream the event came from
+    pub fn stream(&self) -> &str {
+        &self.meta.stream
+    }
+
+    /// The `type` of a recent change, if there is one
+    pub fn type_(&self) -> Option<&str> {
+        self.value["type"].as_str()
+    }
+
+    /// The first of `names` that is a string field of the event, or ""
+    fn field(&self, names: &[&str]) -> &str {
+        names
+            .iter()
+            .find_map(|name| self.value[name].as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Whoever caused an event, outside of recent changes
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Performer {
+    /// Username, or IP address for logged out users
+    pub user_text: CompactString,
+    /// User ID, `None` for logged out users
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<u64>,
+    #[serde(default)]
+    pub user_groups: Vec<String>,
+    /// Whether the user is in a group with the `bot` right
+    #[serde(default)]
+    pub user_is_bot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_edit_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_registration_dt: Option<DateTime<Utc>>,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Performer {
+    fn name(performer: &Option<Self>) -> &str {
+        performer
+            .as_ref()
+            .map(|performer| performer.user_text.as_str())
+            .unwrap_or_default()
+    }
+
+    fn is_bot(performer: &Option<Self>) -> bool {
+        performer
+            .as_ref()
+            .is_some_and(|performer| performer.user_is_bot)
+    }
+}
+
+/// A test event, from the `test` stream. EventGate produces these
+/// regularly to check that the pipeline works end to end.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TestEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Usually `specific test value`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test: Option<String>,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub test_map: Map<String, Value>,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Metadata that EventStreams adds to every event, see
+/// <https://wikitech.wikimedia.org/wiki/Event_Platform/Schemas/Guidelines#Required_fields>
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventMeta {
+    /// URI of the resource the event is about, e.g. the page
+    pub uri: String,
+    /// ID of the request that caused the event
+    pub request_id: CompactString,
+    /// Unique ID of the event, for deduplication
+    pub id: String,
+    /// Time the event happened
+    pub dt: DateTime<Utc>,
+    /// Domain of the wiki, e.g. `en.wikipedia.org`
+    pub domain: CompactString,
+    /// Name of the stream, e.g. `mediawiki.recentchange`
+    pub stream: CompactString,
+    /// Kafka topic the event was read from, e.g.
+    /// `eqiad.mediawiki.recentchange`
+    pub topic: String,
+    /// Kafka partition within the topic
+    pub partition: u32,
+    /// Kafka offset within the partition, which only ever increases
+    pub offset: u64,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+    /// The whole message, see [`Event::raw()`]
+    #[serde(skip)]
+    pub(crate) raw: Option<Arc<str>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+
+    #[test]
+    fn keeps_unsupported_events() {
+        let mut message = testing::edit(1, "A", at(0));
+        message["type"] = "flow".into();
+        let unknown = match testing::event(&message) {
+            Event::Unknown(unknown) => unknown,
+            event => panic!("expected an unknown event, got {:?}", event),
+        };
+        assert_eq!(unknown.type_(), Some("flow"));
+        assert_eq!(unknown.stream(), "mediawiki.recentchange");
+        assert_eq!(unknown.value, message);
+        let event = Event::Unknown(unknown);
+        assert_eq!(event.title(), "A");
+        assert_eq!(event.user(), "Alice");
+        assert_eq!(event.wiki(), "enwiki");
+
+        message.as_object_mut().unwrap().remove("meta");
+        assert!(crate::handle_event(&message.to_string()).unwrap().is_err());
+    }
+
+    #[test]
+    fn exposes_metadata_and_times() {
+        let event = testing::edit_event(3, "A", at(30));
+        let meta = event.meta();
+        assert_eq!(meta.id, "id-3");
+        assert_eq!(meta.dt, at(30));
+        assert_eq!(meta.domain, "en.wikipedia.org");
+        assert_eq!(meta.topic, "eqiad.mediawiki.recentchange");
+        assert_eq!((meta.partition, meta.offset), (0, 3));
+        match &event {
+            Event::Edit(edit) => assert_eq!(edit.datetime(), at(30)),
+            event => panic!("expected an edit, got {:?}", event),
+        }
+        match testing::event(&testing::log(4, "File:A.png", at(40))) {
+            Event::Log(log) => assert_eq!(log.datetime(), at(40)),
+            event => panic!("expected a log event, got {:?}", event),
+        }
+    }
+
+    #[test]
+    fn serializes_back_to_what_was_received() {
+        let mut edit = testing::edit(1, "A", at(0));
+        edit["added_later"] = serde_json::json!({"x": 1});
+        let mut flow = testing::edit(2, "B", at(0));
+        flow["type"] = "flow".into();
+        for mut message in [edit, testing::log(3, "File:C.png", at(0)), flow] {
+            // As EventStreams sends it
+            message["meta"]["dt"] = "2021-01-01T00:00:00Z".into();
+            let event = testing::event(&message);
+            assert_eq!(serde_json::to_value(&event).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn matches_wikis_in_any_form() {
+        let event = testing::edit_event(1, "A", at(0));
+        for wiki in ["en.wikipedia.org", "https://en.wikipedia.org/", "enwiki"]
+        {
+            assert!(event.is_on_wiki(wiki), "{}", wiki);
+            #[cfg(feature = "sinks")]
+            assert!(crate::filter::Filter::new().wikis([wiki]).matches(&event));
+        }
+        assert!(!event.is_on_wiki("dewiki"));
+        assert!(!event.is_on_wiki(""));
+    }
+
+    #[test]
+    fn keeps_raw_messages_on_request() {
+        let data = format!("{} ", testing::edit(1, "A", at(0)));
+        let event = crate::handle_event(&data).unwrap().unwrap();
+        assert_eq!(event.raw(), None);
+        let deserializer = crate::schema::Deserializer {
+            keep_raw: true,
+            ..Default::default()
+        };
+        let event = crate::handle_event_with(&data, &deserializer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.raw(), Some(data.as_str()));
+        assert_eq!(event.raw_value().unwrap()["title"], "A");
+        // Not part of what's serialized
+        assert_eq!(
+            serde_json::to_value(&event).unwrap()["meta"]
+                .as_object()
+                .unwrap()
+                .get("raw"),
+            None
+        );
+    }
+
+    #[test]
+    fn names_types_through_the_prelude() {
+        use crate::types::prelude as types;
+        let edit = match testing::edit_event(1, "A", at(0)) {
+            types::Event::Edit(edit) => edit,
+            event => panic!("expected an edit, got {:?}", event),
+        };
+        let edit: crate::EditEvent = edit;
+        let crate::types::EventLength { old, new } = edit.length;
+        let revision: crate::types::EventRevision = edit.revision;
+        assert_eq!((old, new), (Some(10), 20));
+        assert_eq!(u64::from(revision.new), 2);
+    }
+}
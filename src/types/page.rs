@@ -0,0 +1,178 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Events from the page lifecycle streams: `page-create`, `page-delete`,
+//! `page-undelete` and `page-move`
+use super::revision::RevisionCreateEvent;
+use super::{EventMeta, Performer};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Represents the first revision of a page, from the `page-create` stream
+pub type PageCreateEvent = RevisionCreateEvent;
+
+/// Represents a page deletion, from the `page-delete` stream
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PageDeleteEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// Prefixed title (includes namespace name)
+    pub page_title: String,
+    pub page_namespace: i32,
+    /// Latest revision of the page when it was deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_id: Option<u64>,
+    /// Number of revisions deleted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_count: Option<u64>,
+    /// `None` if the user has been suppressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performer: Option<Performer>,
+    /// Deletion reason
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](PageDeleteEvent#structfield.comment)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub parsedcomment: String,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Represents a page being renamed, from the `page-move` stream
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PageMoveEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// New prefixed title (includes namespace name)
+    pub page_title: String,
+    /// New namespace ID
+    pub page_namespace: i32,
+    /// Revision recording the move
+    pub rev_id: u64,
+    /// The page before it was moved
+    pub prior_state: PriorPageState,
+    /// `None` if the user has been suppressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performer: Option<Performer>,
+    /// Move reason
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](PageMoveEvent#structfield.comment)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub parsedcomment: String,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Title and latest revision of a page before it was moved
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PriorPageState {
+    /// Old prefixed title
+    pub page_title: String,
+    /// Old namespace ID
+    pub page_namespace: i32,
+    pub rev_id: u64,
+}
+
+/// Represents a deleted page being restored, from the `page-undelete`
+/// stream
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PageUndeleteEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// Prefixed title (includes namespace name)
+    pub page_title: String,
+    pub page_namespace: i32,
+    /// Latest revision of the restored page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_id: Option<u64>,
+    /// The page as it was deleted, if it had a different ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prior_state: Option<PriorUndeleteState>,
+    /// `None` if the user has been suppressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performer: Option<Performer>,
+    /// Undeletion reason
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](PageUndeleteEvent#structfield.comment)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub parsedcomment: String,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// ID a page had when it was deleted
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PriorUndeleteState {
+    pub page_id: u64,
+}
+
+/// A page coming into existence, going away or being renamed, from the
+/// page lifecycle streams
+#[derive(Clone, Copy, Debug)]
+pub enum PageLifecycle<'a> {
+    Created(&'a PageCreateEvent),
+    Deleted(&'a PageDeleteEvent),
+    Undeleted(&'a PageUndeleteEvent),
+    Moved(&'a PageMoveEvent),
+}
+
+impl PageLifecycle<'_> {
+    pub fn page_id(&self) -> u64 {
+        match self {
+            Self::Created(create) => create.page_id,
+            Self::Deleted(delete) => delete.page_id,
+            Self::Undeleted(undelete) => undelete.page_id,
+            Self::Moved(move_) => move_.page_id,
+        }
+    }
+
+    /// Whether the page exists afterwards
+    pub fn exists(&self) -> bool {
+        !matches!(self, Self::Deleted(_))
+    }
+
+    /// Title the page no longer has, if it was moved
+    pub fn old_title(&self) -> Option<&str> {
+        match self {
+            Self::Moved(move_) => Some(&move_.prior_state.page_title),
+            _ => None,
+        }
+    }
+}
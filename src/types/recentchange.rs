@@ -0,0 +1,460 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Events from the `recentchange` stream, other than log entries
+use super::EventMeta;
+use crate::comment;
+use crate::namespace;
+use crate::project::{self, Project};
+use crate::wikidata::{EntityId, Summary};
+use chrono::{DateTime, TimeZone, Utc};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Represents an edit
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EditEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Revision ID ([rev_id](https://www.mediawiki.org/wiki/Manual:Revision_table#rev_id))
+    pub id: u32,
+    #[allow(dead_code)]
+    #[serde(rename = "type")]
+    pub(crate) type_: CompactString,
+    /// Namespace ID
+    pub namespace: i32,
+    /// Prefixed title (includes namespace name)
+    pub title: String,
+    /// Edit summary ([comment_text](https://www.mediawiki.org/wiki/Manual:Comment_table#comment_text))
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](EditEvent#structfield.comment)
+    pub parsedcomment: String,
+    /// Unix timestamp
+    pub timestamp: u32,
+    /// Username ([actor_name](https://www.mediawiki.org/wiki/Manual:Actor_table#actor_name))
+    pub user: CompactString,
+    /// Whether the edit was flagged as by a bot ([rc_bot](https://www.mediawiki.org/wiki/Manual:Recentchanges_table#rc_bot))
+    pub bot: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) minor: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) patrolled: Option<bool>,
+    /// Length in bytes of new revision, and potentially old revision
+    pub length: EventLength,
+    /// Revision ID of new revision, and potentially old revision
+    pub revision: EventRevision,
+    /// URL of wiki with protocol, e.g. `https://www.wikidata.org`
+    pub server_url: CompactString,
+    /// Domain of wiki with no protocol, e.g. `www.wikidata.org` or `en.wikipedia.org`
+    pub server_name: CompactString,
+    /// Base URL path of wiki ([$wgScriptPath](https://www.mediawiki.org/wiki/Manual:$wgScriptPath))
+    pub server_script_path: CompactString,
+    /// Internal database name (usually [$wgDBname](https://www.mediawiki.org/wiki/Manual:$wgDBname))
+    pub wiki: CompactString,
+    #[serde(skip)]
+    pub(crate) backfilled: bool,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// `timestamp` fields of recent changes, which are in seconds
+pub(super) fn datetime(timestamp: u32) -> DateTime<Utc> {
+    Utc.timestamp_opt(timestamp.into(), 0).unwrap()
+}
+
+impl EditEvent {
+    /// [`timestamp`](EditEvent#structfield.timestamp) as a date and time
+    pub fn datetime(&self) -> DateTime<Utc> {
+        datetime(self.timestamp)
+    }
+
+    /// Whether the event was reconstructed from the Action API by
+    /// [`backfill`](crate::backfill) rather than received from the stream
+    pub fn is_backfilled(&self) -> bool {
+        self.backfilled
+    }
+
+    /// The entity edited, for edits on Wikidata, e.g. `Q42`
+    pub fn wikidata_entity(&self) -> Option<EntityId> {
+        if Project::from_server_name(&self.server_name) != Project::Wikidata {
+            return None;
+        }
+        EntityId::from_title(&self.title)
+    }
+
+    /// What changed, for edits made through the Wikibase API on Wikidata
+    pub fn wikidata_summary(&self) -> Option<Summary> {
+        if Project::from_server_name(&self.server_name) != Project::Wikidata {
+            return None;
+        }
+        Summary::parse(&self.comment)
+    }
+
+    /// Whether the edit is marked as minor
+    pub fn is_minor(&self) -> bool {
+        self.minor.unwrap_or(false)
+    }
+
+    /// Whether the edit has been marked as patrolled
+    pub fn is_patrolled(&self) -> bool {
+        self.patrolled.unwrap_or(false)
+    }
+
+    /// Change in length in bytes, negative if content was removed
+    pub fn byte_change(&self) -> i64 {
+        i64::from(self.length.new)
+            - i64::from(self.length.old.unwrap_or_default())
+    }
+
+    /// Whether the edit created the page
+    pub fn is_page_creation(&self) -> bool {
+        self.revision.old.is_none()
+    }
+
+    /// Whether the edit is probably a revert: its summary is the default
+    /// one for undo or rollback (including Twinkle's), or it has one of
+    /// the revert [change tags](https://www.mediawiki.org/wiki/Manual:Reverts#Change_tags),
+    /// for events that carry tags. Reverts with a custom summary aren't
+    /// recognized.
+    pub fn is_probable_revert(&self) -> bool {
+        let comment = self.comment.as_str();
+        let tagged = self.tags().iter().any(|tag| {
+            matches!(*tag, "mw-undo" | "mw-rollback" | "mw-manual-revert")
+        });
+        tagged
+            || comment.starts_with("Undid revision")
+            // Rollback's "Reverted edits by", and Twinkle's "Reverted 1
+            // edit by" or "Reverted 3 edits by"
+            || (comment.starts_with("Reverted")
+                && (comment.contains(" edit by ")
+                    || comment.contains(" edits by ")))
+    }
+
+    /// Name of the section edited, going by the `/* … */` autocomment
+    /// MediaWiki adds to the summary of section edits. It's missing if
+    /// the editor removed it.
+    pub fn section(&self) -> Option<&str> {
+        comment::section(&self.comment)
+    }
+
+    /// Targets of the wikilinks in the summary, in order
+    pub fn summary_links(&self) -> Vec<String> {
+        comment::wikilinks(&self.comment)
+    }
+
+    /// [Change tags](https://www.mediawiki.org/wiki/Manual:Tags) of the
+    /// edit, e.g. `mw-undo`. Empty unless the stream includes them.
+    pub fn tags(&self) -> Vec<&str> {
+        comment::tags(&self.extra)
+    }
+
+    /// Project the wiki belongs to, going by its domain
+    pub fn project(&self) -> Project {
+        Project::from_server_name(&self.server_name)
+    }
+
+    /// Language code from the wiki's domain, see
+    /// [`Event::language()`](crate::Event::language)
+    pub fn language(&self) -> Option<&str> {
+        project::language(&self.server_name)
+    }
+
+    /// Whether the wiki is a production Wikimedia wiki, as opposed to e.g.
+    /// the beta cluster
+    pub fn is_wikimedia_production(&self) -> bool {
+        project::is_wikimedia_production(&self.server_name)
+    }
+
+    fn endpoint(&self, path: &str) -> String {
+        format!(
+            "{}{}/{}.php",
+            self.server_url, self.server_script_path, path
+        )
+    }
+
+    /// URL to the wiki's api.php ("[Action API](https://www.mediawiki.org/wiki/API:Main_page)") endpoint
+    pub fn api_url(&self) -> String {
+        self.endpoint("api")
+    }
+
+    /// Title without the namespace prefix, e.g. `Example` for
+    /// `User talk:Example`
+    pub fn page_title_without_namespace(&self) -> &str {
+        match self.title.split_once(':') {
+            Some((_, title)) if self.namespace != 0 => title,
+            _ => &self.title,
+        }
+    }
+
+    /// Canonical (English) name of the page's namespace, e.g. `User talk`,
+    /// which may differ from the prefix in the title on wikis in other
+    /// languages. Empty for the main namespace, and `None` for namespaces
+    /// without a well-known canonical name, see
+    /// [`namespace`](crate::namespace).
+    pub fn namespace_name(&self) -> Option<&'static str> {
+        namespace::canonical_name(self.namespace)
+    }
+
+    /// Whether the page is in a talk namespace
+    pub fn is_talk_page(&self) -> bool {
+        namespace::is_talk(self.namespace)
+    }
+
+    /// URL to view the page
+    pub fn full_url(&self) -> String {
+        format!(
+            "{}?title={}",
+            self.endpoint("index"),
+            encode_title(&self.title)
+        )
+    }
+
+    /// URL to the diff for this edit, formatted for human readability
+    pub fn diff_url(&self) -> String {
+        format!(
+            "{}?title={}&diff={}",
+            self.endpoint("index"),
+            encode_title(&self.title),
+            self.revision.new
+        )
+    }
+
+    /// URL to the diff for this edit, as short as possible
+    pub fn short_diff_url(&self) -> String {
+        format!("{}?diff={}", self.server_url, self.revision.new)
+    }
+}
+
+/// Encode a title for use in URLs the way MediaWiki does, with spaces as
+/// underscores
+fn encode_title(title: &str) -> String {
+    let mut encoded = String::with_capacity(title.len());
+    for byte in title.bytes() {
+        match byte {
+            b' ' => encoded.push('_'),
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~'
+            | b'!'
+            | b'$'
+            | b'('
+            | b')'
+            | b'*'
+            | b','
+            | b';'
+            | b'/'
+            | b':'
+            | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Represents a page creation, which has the same fields as an edit
+/// except that there is no old revision
+pub type NewPageEvent = EditEvent;
+
+/// Represents a page being added to or removed from a category
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct CategorizeEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Recent changes ID, used by [`rcfeed`](crate::rcfeed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<u64>,
+    #[allow(dead_code)]
+    #[serde(rename = "type")]
+    pub(crate) type_: CompactString,
+    /// Namespace ID of the category, always 14
+    pub namespace: i32,
+    /// Prefixed title of the category
+    pub title: String,
+    /// Automatic summary naming the page that was added or removed, in the
+    /// wiki's content language
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](CategorizeEvent#structfield.comment)
+    pub parsedcomment: String,
+    /// Unix timestamp
+    pub timestamp: u32,
+    /// Username of whoever made the change that caused this
+    pub user: CompactString,
+    /// Whether the change was flagged as by a bot ([rc_bot](https://www.mediawiki.org/wiki/Manual:Recentchanges_table#rc_bot))
+    pub bot: bool,
+    /// URL of wiki with protocol, e.g. `https://www.wikidata.org`
+    pub server_url: CompactString,
+    /// Domain of wiki with no protocol, e.g. `www.wikidata.org` or `en.wikipedia.org`
+    pub server_name: CompactString,
+    /// Base URL path of wiki ([$wgScriptPath](https://www.mediawiki.org/wiki/Manual:$wgScriptPath))
+    pub server_script_path: CompactString,
+    /// Internal database name (usually [$wgDBname](https://www.mediawiki.org/wiki/Manual:$wgDBname))
+    pub wiki: CompactString,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Whether a page entered or left a category
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CategoryChange {
+    Added,
+    Removed,
+}
+
+impl CategorizeEvent {
+    /// [`timestamp`](CategorizeEvent#structfield.timestamp) as a date and
+    /// time
+    pub fn datetime(&self) -> DateTime<Utc> {
+        datetime(self.timestamp)
+    }
+
+    /// The page that was added or removed, parsed from the
+    /// [`comment`](CategorizeEvent#structfield.comment). Only English
+    /// summaries are understood; for other content languages this is
+    /// `None`.
+    pub fn change(&self) -> Option<(CategoryChange, &str)> {
+        let (page, rest) =
+            self.comment.strip_prefix("[[:")?.split_once("]]")?;
+        if rest.starts_with(" added to category") {
+            Some((CategoryChange::Added, page))
+        } else if rest.starts_with(" removed from category") {
+            Some((CategoryChange::Removed, page))
+        } else {
+            None
+        }
+    }
+}
+
+/// Represents a change that was made somewhere else but affects a page on
+/// this wiki, e.g. an edit to the Wikidata item connected to it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ExternalEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Recent changes ID, used by [`rcfeed`](crate::rcfeed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<u64>,
+    /// Namespace ID
+    pub namespace: i32,
+    /// Prefixed title of the affected page
+    pub title: String,
+    /// Summary of the change where it was made, e.g. a Wikibase
+    /// autocomment
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](ExternalEvent#structfield.comment)
+    pub parsedcomment: String,
+    /// Unix timestamp
+    pub timestamp: u32,
+    /// Username of whoever made the change, as known where it was made
+    pub user: CompactString,
+    /// Whether the change was flagged as by a bot ([rc_bot](https://www.mediawiki.org/wiki/Manual:Recentchanges_table#rc_bot))
+    pub bot: bool,
+    /// URL of wiki with protocol, e.g. `https://www.wikidata.org`
+    pub server_url: CompactString,
+    /// Domain of wiki with no protocol, e.g. `www.wikidata.org` or `en.wikipedia.org`
+    pub server_name: CompactString,
+    /// Base URL path of wiki ([$wgScriptPath](https://www.mediawiki.org/wiki/Manual:$wgScriptPath))
+    pub server_script_path: CompactString,
+    /// Internal database name (usually [$wgDBname](https://www.mediawiki.org/wiki/Manual:$wgDBname))
+    pub wiki: CompactString,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl ExternalEvent {
+    /// [`timestamp`](ExternalEvent#structfield.timestamp) as a date and
+    /// time
+    pub fn datetime(&self) -> DateTime<Utc> {
+        datetime(self.timestamp)
+    }
+}
+
+/// Length in bytes of new revision, and potentially old revision
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventLength {
+    /// Length of old revision, in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<u32>,
+    /// Length of new revision, in bytes
+    pub new: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EventRevision {
+    /// Revision ID for old revision
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old: Option<u32>,
+    /// Revision ID for new revision
+    pub new: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, at};
+    use crate::Event;
+
+    fn edit(message: &serde_json::Value) -> crate::EditEvent {
+        match testing::event(message) {
+            Event::Edit(edit) | Event::New(edit) => edit,
+            other => panic!("not an edit: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn summarizes_edits() {
+        let mut message = testing::edit(1, "A", at(0));
+        message["length"]["new"] = 4.into();
+        let shrunk = edit(&message);
+        assert_eq!(shrunk.byte_change(), -6);
+        assert!(!shrunk.is_page_creation());
+        assert!(!shrunk.is_probable_revert());
+
+        message["type"] = "new".into();
+        message["length"] = serde_json::json!({ "new": 30 });
+        message["revision"] = serde_json::json!({ "new": 2 });
+        let created = edit(&message);
+        assert_eq!(created.byte_change(), 30);
+        assert!(created.is_page_creation());
+
+        for comment in [
+            "Undid revision 123 by [[Special:Contributions/Bob|Bob]]",
+            "Reverted edits by [[Special:Contributions/Bob|Bob]] to last \
+             revision by Alice",
+            "Reverted 3 edits by [[Special:Contributions/Bob|Bob]] (talk)",
+        ] {
+            message["comment"] = comment.into();
+            assert!(edit(&message).is_probable_revert(), "{}", comment);
+        }
+        message["comment"] = "Reverted to the stable version".into();
+        assert!(!edit(&message).is_probable_revert());
+    }
+}
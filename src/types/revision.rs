@@ -0,0 +1,227 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Events from the `revision-create` and `revision-score` streams
+use super::{EventMeta, Performer};
+use chrono::{DateTime, Utc};
+use compact_str::CompactString;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+/// Represents a new revision, from the `revision-create` stream
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevisionCreateEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// Prefixed title (includes namespace name)
+    pub page_title: String,
+    pub page_namespace: i32,
+    #[serde(default)]
+    pub page_is_redirect: bool,
+    pub rev_id: u64,
+    /// Previous revision of the page, `None` for page creations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_parent_id: Option<u64>,
+    pub rev_timestamp: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_sha1: Option<String>,
+    #[serde(default)]
+    pub rev_minor_edit: bool,
+    /// Length in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_len: Option<u64>,
+    /// Content model of the main slot, e.g. `wikitext` or
+    /// `wikibase-item`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_content_model: Option<String>,
+    /// Serialization format of the main slot, e.g. `text/x-wiki`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_content_format: Option<String>,
+    /// Every slot of the revision by role, e.g. `main` and, on Commons,
+    /// `mediainfo`. Empty for events that predate slots.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rev_slots: BTreeMap<String, RevisionSlot>,
+    /// Whether the content changed, rather than e.g. just the edit summary
+    #[serde(default)]
+    pub rev_content_changed: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub rev_is_revert: bool,
+    /// `None` if the user has been suppressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performer: Option<Performer>,
+    /// Edit summary
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub comment: String,
+    /// HTML-parsed version of [`comment`](RevisionCreateEvent#structfield.comment)
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub parsedcomment: String,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl RevisionCreateEvent {
+    /// Whether the revision created the page
+    pub fn is_page_creation(&self) -> bool {
+        self.rev_parent_id.is_none()
+    }
+
+    /// Content model of the main slot, from
+    /// [`rev_content_model`](Self#structfield.rev_content_model) or the
+    /// slot itself
+    pub fn content_model(&self) -> Option<&str> {
+        self.rev_content_model.as_deref().or_else(|| {
+            self.main_slot()
+                .map(|slot| slot.rev_slot_content_model.as_str())
+        })
+    }
+
+    pub fn main_slot(&self) -> Option<&RevisionSlot> {
+        self.rev_slots.get("main")
+    }
+
+    /// Roles of slots whose content changed in this revision, rather than
+    /// being carried over from an earlier one
+    pub fn changed_slots(&self) -> impl Iterator<Item = &str> {
+        let rev_id = self.rev_id;
+        self.rev_slots
+            .iter()
+            .filter(move |(_, slot)| {
+                slot.rev_slot_origin_rev_id
+                    .is_none_or(|origin| origin == rev_id)
+            })
+            .map(|(role, _)| role.as_str())
+    }
+}
+
+/// One slot of a revision, see
+/// [`RevisionCreateEvent::rev_slots`](RevisionCreateEvent#structfield.rev_slots)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevisionSlot {
+    pub rev_slot_content_model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_slot_sha1: Option<String>,
+    /// Length in bytes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_slot_size: Option<u64>,
+    /// Revision the content was first saved in, which is an earlier one if
+    /// the slot wasn't changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_slot_origin_rev_id: Option<u64>,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Represents machine learning scores for a revision, from the
+/// `revision-score` stream
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RevisionScoreEvent {
+    #[allow(dead_code)]
+    #[serde(rename = "$schema")]
+    pub(crate) schema: String,
+    pub meta: EventMeta,
+    /// Internal database name of the wiki
+    pub database: CompactString,
+    pub page_id: u64,
+    /// Prefixed title (includes namespace name)
+    pub page_title: String,
+    pub page_namespace: i32,
+    pub rev_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rev_parent_id: Option<u64>,
+    pub rev_timestamp: DateTime<Utc>,
+    /// `None` if the user has been suppressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub performer: Option<Performer>,
+    /// Scores keyed by model name, e.g. `damaging`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub scores: HashMap<String, Score>,
+    /// Fields not covered above, kept so that serializing gives back what
+    /// was received
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// The output of one model for a revision
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Score {
+    pub model_name: String,
+    pub model_version: String,
+    /// Predicted classes, e.g. `["false"]`
+    #[serde(default)]
+    pub prediction: Vec<String>,
+    /// Probability of each class
+    #[serde(default)]
+    pub probability: HashMap<String, f64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, at};
+    use crate::Event;
+    use serde_json::json;
+
+    #[test]
+    fn reads_content_format_and_slots() {
+        let mut meta = testing::edit(1, "File:A.png", at(0))["meta"].clone();
+        meta["stream"] = "mediawiki.revision-create".into();
+        let revision = json!({
+            "$schema": "/mediawiki/revision/create/1.1.0",
+            "meta": meta,
+            "database": "commonswiki",
+            "page_id": 1,
+            "page_title": "File:A.png",
+            "page_namespace": 6,
+            "rev_id": 12,
+            "rev_parent_id": 11,
+            "rev_timestamp": at(0).to_rfc3339(),
+            "rev_content_format": "text/x-wiki",
+            "rev_slots": {
+                "main": {
+                    "rev_slot_content_model": "wikitext",
+                    "rev_slot_origin_rev_id": 10,
+                },
+                "mediainfo": {
+                    "rev_slot_content_model": "wikibase-mediainfo",
+                    "rev_slot_origin_rev_id": 12,
+                },
+            },
+        });
+        let revision = match testing::event(&revision) {
+            Event::RevisionCreate(revision) => revision,
+            other => panic!("not a revision: {:?}", other),
+        };
+        assert!(!revision.is_page_creation());
+        assert_eq!(revision.rev_content_format.as_deref(), Some("text/x-wiki"));
+        // Falls back to the main slot's model
+        assert_eq!(revision.content_model(), Some("wikitext"));
+        assert_eq!(revision.changed_slots().collect::<Vec<_>>(), ["mediainfo"]);
+        assert_eq!(
+            serde_json::to_value(&revision).unwrap()["rev_slots"]["main"]
+                ["rev_slot_origin_rev_id"],
+            10
+        );
+    }
+}
@@ -0,0 +1,411 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Checking on the EventStreams service itself
+//!
+//! When the connection drops, it helps to know whether Wikimedia is doing
+//! maintenance or the problem is closer to home. A [`StatusPoller`]
+//! periodically requests the service's OpenAPI spec and reports changes
+//! to its [`UpstreamStatus`] to hooks. It polls more often while the
+//! service is unhealthy, and right away after a disconnect if it's
+//! [watching](StatusHandle::watch) a stream's listeners.
+use crate::backend::{ClientOptions, USER_AGENT};
+use crate::clock::{Clock, SystemClock};
+use crate::listener::{ListenerHandle, Listeners};
+use crate::transport::{self, TransportError};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::future::{self, Either};
+use futures::StreamExt;
+use serde::Deserialize;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The service's OpenAPI spec, which is cheap to serve
+const SPEC_URL: &str = "https://stream.wikimedia.org/?spec";
+
+/// Longest maintenance notice kept from an error page
+const MAX_NOTICE: usize = 200;
+
+/// State of the EventStreams service, as of the last check
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpstreamStatus {
+    /// Not checked yet
+    Unknown,
+    /// Responding normally
+    Up,
+    /// Responding that it's unavailable, usually because of maintenance.
+    /// `notice` is the explanation from the error page, if any.
+    Maintenance {
+        notice: Option<String>,
+        retry_after: Option<Duration>,
+    },
+    /// Responding with some other error status
+    Degraded { status: u16 },
+    /// Couldn't be reached at all, which usually means the problem is on
+    /// this side, e.g. the network or DNS
+    Unreachable { error: String },
+}
+
+impl UpstreamStatus {
+    pub fn is_up(&self) -> bool {
+        matches!(self, UpstreamStatus::Up)
+    }
+
+    pub fn is_maintenance(&self) -> bool {
+        matches!(self, UpstreamStatus::Maintenance { .. })
+    }
+}
+
+impl fmt::Display for UpstreamStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamStatus::Unknown => write!(f, "unknown"),
+            UpstreamStatus::Up => write!(f, "up"),
+            UpstreamStatus::Maintenance {
+                notice: Some(notice),
+                ..
+            } => write!(f, "under maintenance: {}", notice),
+            UpstreamStatus::Maintenance { notice: None, .. } => {
+                write!(f, "under maintenance")
+            }
+            UpstreamStatus::Degraded { status } => {
+                write!(f, "degraded: HTTP {}", status)
+            }
+            UpstreamStatus::Unreachable { error } => {
+                write!(f, "unreachable: {}", error)
+            }
+        }
+    }
+}
+
+/// An error response in the service's JSON format
+#[derive(Deserialize)]
+struct Problem {
+    title: Option<String>,
+    detail: Option<String>,
+}
+
+/// Explanation from an error page, either the JSON error's detail or
+/// title, or an HTML page's title
+fn notice(body: &str) -> Option<String> {
+    let notice = match serde_json::from_str::<Problem>(body) {
+        Ok(problem) => problem.detail.or(problem.title)?,
+        Err(_) => {
+            let (_, rest) = body.split_once("<title>")?;
+            let (title, _) = rest.split_once("</title>")?;
+            title.to_string()
+        }
+    };
+    let notice = notice.split_whitespace().collect::<Vec<_>>().join(" ");
+    if notice.is_empty() {
+        None
+    } else {
+        Some(notice.chars().take(MAX_NOTICE).collect())
+    }
+}
+
+type Hook = Box<dyn FnMut(&UpstreamStatus) + Send>;
+
+/// Periodically checks the EventStreams service, see the
+/// [module documentation](self)
+pub struct StatusPoller {
+    client: surf::Client,
+    url: String,
+    user_agent: String,
+    interval: Duration,
+    unhealthy_interval: Duration,
+    timeout: Duration,
+    clock: Arc<dyn Clock>,
+    hooks: Vec<Hook>,
+    handle: StatusHandle,
+    wake: UnboundedReceiver<()>,
+}
+
+impl StatusPoller {
+    /// Check `https://stream.wikimedia.org/?spec` every 5 minutes, or every
+    /// 30 seconds while it isn't up, waiting up to 10 seconds for a
+    /// response
+    pub fn new() -> Self {
+        let (sender, wake) = mpsc::unbounded();
+        Self {
+            client: transport::default_client(),
+            url: SPEC_URL.to_string(),
+            user_agent: USER_AGENT.to_string(),
+            interval: Duration::from_secs(300),
+            unhealthy_interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+            clock: Arc::new(SystemClock),
+            hooks: vec![],
+            handle: StatusHandle {
+                status: Arc::new(Mutex::new(UpstreamStatus::Unknown)),
+                wake: sender,
+            },
+            wake,
+        }
+    }
+
+    /// Check `url` instead, e.g. for a mirror or a test server
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.user_agent = user_agent.into();
+        self
+    }
+
+    /// Make requests through `options`' transport, with its proxy and
+    /// certificates. Fails if no client can be created with them, e.g.
+    /// because the proxy URL is invalid.
+    pub fn client_options(
+        mut self,
+        options: &ClientOptions,
+    ) -> Result<Self, TransportError> {
+        self.client = transport::client(options)?;
+        Ok(self)
+    }
+
+    /// Check every `interval` while the service is up, and every
+    /// `unhealthy` otherwise
+    pub fn interval(mut self, interval: Duration, unhealthy: Duration) -> Self {
+        self.interval = interval;
+        self.unhealthy_interval = unhealthy;
+        self
+    }
+
+    /// Give up on a check after `timeout`, counting the service as
+    /// unreachable
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Clock used for waiting between checks
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Call `hook` whenever the status changes, including after the first
+    /// check
+    pub fn on_change(
+        mut self,
+        hook: impl FnMut(&UpstreamStatus) + Send + 'static,
+    ) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// For reading the status and triggering checks while the poller runs
+    pub fn handle(&self) -> StatusHandle {
+        self.handle.clone()
+    }
+
+    /// Check the service once
+    pub async fn check(&self) -> UpstreamStatus {
+        let request = Box::pin(
+            self.client
+                .get(&self.url)
+                .header("User-Agent", self.user_agent.as_str())
+                .send(),
+        );
+        let timeout = self.clock.sleep(self.timeout);
+        let mut resp = match future::select(request, timeout).await {
+            Either::Left((Ok(resp), _)) => resp,
+            Either::Left((Err(err), _)) => {
+                return UpstreamStatus::Unreachable {
+                    error: err.to_string(),
+                }
+            }
+            Either::Right(_) => {
+                return UpstreamStatus::Unreachable {
+                    error: "timed out".to_string(),
+                }
+            }
+        };
+        let status: u16 = resp.status().into();
+        match status {
+            200..=299 => UpstreamStatus::Up,
+            503 => {
+                let retry_after = resp
+                    .header("Retry-After")
+                    .and_then(|value| value.as_str().parse().ok())
+                    .map(Duration::from_secs);
+                let body = resp.body_string().await.unwrap_or_default();
+                UpstreamStatus::Maintenance {
+                    notice: notice(&body),
+                    retry_after,
+                }
+            }
+            _ => UpstreamStatus::Degraded { status },
+        }
+    }
+
+    /// Check the service until the poller is dropped, calling the hooks
+    /// whenever the status changes
+    pub async fn run(mut self) {
+        loop {
+            let status = self.check().await;
+            let changed = {
+                let mut current = self.handle.status.lock().unwrap();
+                let changed = *current != status;
+                *current = status.clone();
+                changed
+            };
+            if changed {
+                for hook in &mut self.hooks {
+                    hook(&status);
+                }
+            }
+            let wait = match &status {
+                UpstreamStatus::Up => self.interval,
+                // Don't check again before the server asked us to
+                UpstreamStatus::Maintenance {
+                    retry_after: Some(retry_after),
+                    ..
+                } => self.unhealthy_interval.max(*retry_after),
+                _ => self.unhealthy_interval,
+            };
+            let sleep = self.clock.sleep(wait);
+            future::select(sleep, self.wake.next()).await;
+            // Requests that came in during the check are covered by the
+            // next one
+            while self.wake.try_recv().is_ok() {}
+        }
+    }
+}
+
+impl Default for StatusPoller {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for StatusPoller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StatusPoller")
+            .field("url", &self.url)
+            .field("interval", &self.interval)
+            .field("unhealthy_interval", &self.unhealthy_interval)
+            .field("status", &self.handle.status())
+            .finish()
+    }
+}
+
+/// Shared access to a running [`StatusPoller`]
+#[derive(Clone, Debug)]
+pub struct StatusHandle {
+    status: Arc<Mutex<UpstreamStatus>>,
+    wake: UnboundedSender<()>,
+}
+
+impl StatusHandle {
+    /// Status as of the last check
+    pub fn status(&self) -> UpstreamStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Check again now instead of waiting for the next interval
+    pub fn check_now(&self) {
+        let _ = self.wake.unbounded_send(());
+    }
+
+    /// Check again whenever the connection is lost, so the status is up
+    /// to date when deciding what went wrong
+    pub fn watch(&self, listeners: &Listeners) -> ListenerHandle {
+        let handle = self.clone();
+        listeners.on_disconnect(move |_| handle.check_now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use futures::executor::block_on;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Answer one request with `response`, returning the server's URL
+    fn respond_once(response: String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/?spec", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn tells_maintenance_from_other_failures() {
+        let body =
+            r#"{"title": "Unavailable", "detail": "Down for  maintenance"}"#;
+        let response = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 120\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let poller = StatusPoller::new().url(respond_once(response));
+        let status = block_on(poller.check());
+        assert_eq!(
+            status,
+            UpstreamStatus::Maintenance {
+                notice: Some("Down for maintenance".to_string()),
+                retry_after: Some(Duration::from_secs(120)),
+            }
+        );
+        assert_eq!(
+            status.to_string(),
+            "under maintenance: Down for maintenance"
+        );
+
+        let server = testing::serve_json(vec![serde_json::json!({})]);
+        let poller =
+            StatusPoller::new().url(format!("http://{}/?spec", server.addr()));
+        assert_eq!(block_on(poller.check()), UpstreamStatus::Up);
+        assert_eq!(
+            block_on(poller.check()),
+            UpstreamStatus::Degraded { status: 404 }
+        );
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let poller = StatusPoller::new()
+            .url(format!("http://{}/?spec", closed.local_addr().unwrap()));
+        drop(closed);
+        assert!(matches!(
+            block_on(poller.check()),
+            UpstreamStatus::Unreachable { .. }
+        ));
+    }
+
+    #[test]
+    fn reads_notices_from_error_pages() {
+        let html = "<html><head><title>Wikimedia\n Error</title></head>";
+        assert_eq!(notice(html).as_deref(), Some("Wikimedia Error"));
+        assert_eq!(notice(r#"{"title": "Gone"}"#).as_deref(), Some("Gone"));
+        assert_eq!(notice("<title> </title>"), None);
+        assert_eq!(notice(&"x".repeat(500)), None);
+    }
+}
@@ -0,0 +1,184 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Looking up details about users
+//!
+//! Events only carry a username. [`UserInfoClient`] fetches the rest from
+//! the wiki's [Action API](https://www.mediawiki.org/wiki/API:Users) and
+//! [caches](crate::cache) it, so events can be enriched without a request
+//! for every one.
+use crate::api::{ApiClient, ApiError};
+use crate::cache::{Cache, MemoryCache};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+
+const DEFAULT_TTL: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Details about a registered user
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserInfo {
+    pub name: String,
+    /// When the account was created, if known. Very old accounts don't
+    /// have this recorded.
+    pub registration: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub editcount: u64,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl UserInfo {
+    /// How old the account was at `at`, if its registration is known
+    pub fn account_age(&self, at: DateTime<Utc>) -> Option<Duration> {
+        self.registration.map(|registration| at - registration)
+    }
+}
+
+/// Fetches and caches [`UserInfo`]
+#[derive(Clone)]
+pub struct UserInfoClient {
+    api: ApiClient,
+    cache: Arc<dyn Cache>,
+    ttl: std::time::Duration,
+}
+
+impl UserInfoClient {
+    pub fn new() -> Self {
+        Self {
+            api: ApiClient::new(),
+            cache: Arc::new(MemoryCache::default()),
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Make requests with `api`, e.g. to share its limits with other
+    /// stages
+    pub fn api(mut self, api: ApiClient) -> Self {
+        self.api = api;
+        self
+    }
+
+    /// Cache at most `capacity` users in memory, see [`MemoryCache`]
+    pub fn capacity(self, capacity: usize) -> Self {
+        self.cache(Arc::new(MemoryCache::new(capacity)))
+    }
+
+    /// Keep lookups in `cache` instead, e.g. one shared with other stages
+    pub fn cache(mut self, cache: Arc<dyn Cache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// How long lookups are cached for (default 1 hour). Group changes and
+    /// edit counts can be this out of date.
+    pub fn ttl(mut self, ttl: std::time::Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Look up `user` via the API at `api_url`, e.g. from
+    /// [`EditEvent::api_url()`](crate::EditEvent::api_url). Returns `None`
+    /// for users that aren't registered.
+    pub async fn lookup(
+        &self,
+        api_url: &str,
+        user: &str,
+    ) -> Result<Option<UserInfo>, ApiError> {
+        // Users that don't exist, e.g. IP addresses, are cached as null
+        let key = format!("user:{}:{}", api_url, user);
+        if let Some(info) = self
+            .cache
+            .get(&key)
+            .and_then(|cached| serde_json::from_str(&cached).ok())
+        {
+            return Ok(info);
+        }
+        let mut url = surf::Url::parse(api_url)?;
+        url.query_pairs_mut()
+            .append_pair("action", "query")
+            .append_pair("list", "users")
+            .append_pair("ususers", user)
+            .append_pair("usprop", "registration|editcount|groups")
+            .append_pair("format", "json")
+            .append_pair("formatversion", "2");
+        let resp: Value = self.api.get(url).await?;
+        let user = &resp["query"]["users"][0];
+        let info =
+            if user.get("missing").is_some() || user.get("invalid").is_some() {
+                None
+            } else {
+                serde_json::from_value(user.clone()).ok()
+            };
+        if let Ok(cached) = serde_json::to_string(&info) {
+            self.cache.put(&key, cached, self.ttl);
+        }
+        Ok(info)
+    }
+}
+
+impl std::fmt::Debug for UserInfoClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserInfoClient")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for UserInfoClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    #[test]
+    fn looks_up_and_caches_users() {
+        let server = testing::serve_json(vec![
+            json!({ "query": { "users": [{
+                "name": "Alice",
+                "registration": "2021-01-01T00:00:00Z",
+                "editcount": 12,
+                "groups": ["*", "user"],
+            }]}}),
+            json!({ "query": { "users": [{
+                "name": "127.0.0.1",
+                "invalid": true,
+            }]}}),
+        ]);
+        let api_url = format!("http://{}/w/api.php", server.addr());
+        let users = UserInfoClient::new();
+        let alice = block_on(users.lookup(&api_url, "Alice")).unwrap().unwrap();
+        assert_eq!(alice.registration, Some(at(0)));
+        assert_eq!(alice.editcount, 12);
+        let ip = block_on(users.lookup(&api_url, "127.0.0.1")).unwrap();
+        assert!(ip.is_none());
+        // Both answered from the cache this time
+        let alice = block_on(users.lookup(&api_url, "Alice")).unwrap();
+        assert_eq!(alice.unwrap().name, "Alice");
+        assert!(block_on(users.lookup(&api_url, "127.0.0.1"))
+            .unwrap()
+            .is_none());
+        assert_eq!(server.requests().len(), 2);
+    }
+}
@@ -0,0 +1,163 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Page creation velocity alerts
+//!
+//! A new account creating many pages in a short time is a common spam
+//! pattern. [`CreationVelocity`] counts page creations per user over a
+//! sliding hour and raises a [`VelocityAlert`] when a young account goes
+//! over the limit.
+use crate::users::UserInfo;
+use crate::NewPageEvent;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+
+/// A young account creating pages faster than allowed
+#[derive(Clone, Debug)]
+pub struct VelocityAlert {
+    /// Internal database name of the wiki
+    pub wiki: String,
+    pub user: UserInfo,
+    /// Age of the account as of the latest creation
+    pub account_age: Duration,
+    /// Titles of the pages created in the last hour
+    pub pages: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+struct Creation {
+    dt: DateTime<Utc>,
+    title: String,
+}
+
+/// Counts page creations by young accounts
+#[derive(Clone, Debug)]
+pub struct CreationVelocity {
+    max_account_age: Duration,
+    max_per_hour: usize,
+    /// Recent creations, keyed by wiki and user
+    recent: HashMap<(String, String), VecDeque<Creation>>,
+}
+
+impl CreationVelocity {
+    /// Alert when an account younger than `max_account_age` creates more
+    /// than `max_per_hour` pages within an hour
+    pub fn new(
+        max_account_age: std::time::Duration,
+        max_per_hour: usize,
+    ) -> Self {
+        Self {
+            max_account_age: Duration::from_std(max_account_age)
+                .expect("account age out of range"),
+            max_per_hour,
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Whether creations by `user` at `at` are counted at all, i.e. it's a
+    /// registered account younger than the limit
+    pub fn is_young(&self, user: &UserInfo, at: DateTime<Utc>) -> bool {
+        user.account_age(at)
+            .is_some_and(|age| age < self.max_account_age)
+    }
+
+    /// Record a page creation by `user`, returning an alert if they've now
+    /// gone over the limit. After an alert the count starts over, so a
+    /// user keeps being reported for as long as they keep going.
+    pub fn push(
+        &mut self,
+        new: &NewPageEvent,
+        user: &UserInfo,
+    ) -> Option<VelocityAlert> {
+        let dt = new.meta.dt;
+        // Forget creations that have dropped out of the hour
+        let oldest = dt - Duration::hours(1);
+        self.recent.retain(|_, recent| {
+            while recent.front().is_some_and(|creation| creation.dt < oldest) {
+                recent.pop_front();
+            }
+            !recent.is_empty()
+        });
+        if !self.is_young(user, dt) {
+            return None;
+        }
+        let key = (new.wiki.to_string(), new.user.to_string());
+        let recent = self.recent.entry(key).or_default();
+        recent.push_back(Creation {
+            dt,
+            title: new.title.clone(),
+        });
+        if recent.len() <= self.max_per_hour {
+            return None;
+        }
+        let pages = recent.drain(..).map(|creation| creation.title).collect();
+        Some(VelocityAlert {
+            wiki: new.wiki.to_string(),
+            account_age: user.account_age(dt).unwrap(),
+            user: user.clone(),
+            pages,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use crate::Event;
+    use std::time::Duration as StdDuration;
+
+    fn creation(offset: u64, title: &str, seconds: i64) -> NewPageEvent {
+        let mut edit = testing::edit(offset, title, at(seconds));
+        edit["type"] = "new".into();
+        edit["revision"]["old"] = serde_json::Value::Null;
+        match testing::event(&edit) {
+            Event::New(new) => new,
+            event => panic!("parsed as {:?}", event),
+        }
+    }
+
+    fn user(registered_at: i64) -> UserInfo {
+        UserInfo {
+            name: "Alice".to_string(),
+            registration: Some(at(registered_at)),
+            editcount: 0,
+            groups: vec![],
+        }
+    }
+
+    #[test]
+    fn alerts_on_young_accounts_creating_fast() {
+        let mut velocity =
+            CreationVelocity::new(StdDuration::from_secs(86400), 2);
+        let young = user(-60);
+        assert!(velocity.push(&creation(1, "A", 0), &young).is_none());
+        assert!(velocity.push(&creation(2, "B", 60), &young).is_none());
+        // A dropped out of the hour
+        assert!(velocity.push(&creation(3, "C", 3630), &young).is_none());
+        let alert = velocity.push(&creation(4, "D", 3640), &young).unwrap();
+        assert_eq!(alert.pages, ["B", "C", "D"]);
+        assert_eq!(alert.account_age, Duration::seconds(3700));
+        // Counting starts over after an alert
+        assert!(velocity.push(&creation(5, "E", 3650), &young).is_none());
+
+        let old = user(-86400 * 365);
+        for offset in 6..10 {
+            let new = creation(offset, "F", 3660);
+            assert!(velocity.push(&new, &old).is_none());
+        }
+    }
+}
@@ -0,0 +1,115 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Event-time watermarks
+//!
+//! Events don't necessarily arrive in the order they happened in. A
+//! watermark is the lowest event time (`meta.dt`) that may still be
+//! delivered: once the watermark has passed a point in time, no more events
+//! from before it are expected, so a window ending there can be closed.
+use crate::Event;
+use chrono::{DateTime, Duration, Utc};
+
+/// Tracks a watermark assuming events arrive at most `max_delay` out of
+/// order
+#[derive(Clone, Debug)]
+pub struct Watermark {
+    max_delay: Duration,
+    max_dt: Option<DateTime<Utc>>,
+}
+
+impl Watermark {
+    /// Create a new watermark tracker that tolerates events arriving up to
+    /// `max_delay` after a newer one
+    pub fn new(max_delay: std::time::Duration) -> Self {
+        Self {
+            max_delay: Duration::from_std(max_delay).unwrap_or(Duration::MAX),
+            max_dt: None,
+        }
+    }
+
+    /// Record an event as delivered, potentially advancing the watermark
+    pub fn observe(&mut self, event: &Event) {
+        let dt = event.dt();
+        if self.max_dt.is_none_or(|max_dt| dt > max_dt) {
+            self.max_dt = Some(dt);
+        }
+    }
+
+    /// The current watermark, or `None` if no events have been seen yet.
+    /// The watermark never moves backwards.
+    pub fn current(&self) -> Option<DateTime<Utc>> {
+        self.max_dt
+            .map(|max_dt| max_dt.checked_sub_signed(self.max_delay))
+            .map(|dt| dt.unwrap_or(DateTime::<Utc>::MIN_UTC))
+    }
+
+    /// Whether the event happened before the current watermark, i.e. it
+    /// arrived later than `max_delay` allows for
+    pub fn is_late(&self, event: &Event) -> bool {
+        match self.current() {
+            Some(watermark) => event.dt() < watermark,
+            None => false,
+        }
+    }
+}
+
+/// An event along with the watermark as of its delivery
+#[derive(Clone, Debug)]
+pub struct Watermarked {
+    pub event: Event,
+    /// No events from before this time are expected anymore
+    pub watermark: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{at, edit_event};
+    use std::time::Duration as StdDuration;
+
+    #[test]
+    fn trails_the_newest_event_by_max_delay() {
+        let mut watermark = Watermark::new(StdDuration::from_secs(10));
+        assert_eq!(watermark.current(), None);
+        watermark.observe(&edit_event(1, "A", at(30)));
+        assert_eq!(watermark.current(), Some(at(20)));
+    }
+
+    #[test]
+    fn never_moves_backwards() {
+        let mut watermark = Watermark::new(StdDuration::from_secs(10));
+        watermark.observe(&edit_event(1, "A", at(60)));
+        let late = edit_event(2, "A", at(0));
+        assert!(watermark.is_late(&late));
+        watermark.observe(&late);
+        assert_eq!(watermark.current(), Some(at(50)));
+    }
+
+    #[test]
+    fn tolerates_events_within_max_delay() {
+        let mut watermark = Watermark::new(StdDuration::from_secs(10));
+        watermark.observe(&edit_event(1, "A", at(60)));
+        assert!(!watermark.is_late(&edit_event(2, "A", at(55))));
+    }
+
+    #[test]
+    fn saturates_huge_delays() {
+        let mut watermark = Watermark::new(StdDuration::MAX);
+        watermark.observe(&edit_event(1, "A", at(0)));
+        assert_eq!(watermark.current(), Some(DateTime::<Utc>::MIN_UTC));
+    }
+}
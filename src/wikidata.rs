@@ -0,0 +1,305 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Helpers for events from Wikidata
+//!
+//! Pages on Wikidata are entities, titled by their ID: items like `Q42`
+//! in the main namespace, properties like `Property:P31` and lexemes like
+//! `Lexeme:L1`. [`EntityId`] reads those out of titles.
+//!
+//! Edits made through the Wikibase API get a machine-readable summary,
+//! e.g. `/* wbsetclaim-create:2||1 */ [[Property:P31]]: [[Q5]]`, which
+//! [`Summary`] parses to tell what kind of change was made.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Kind of entity, going by the letter its ID starts with
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub enum EntityKind {
+    /// `Q`
+    Item,
+    /// `P`
+    Property,
+    /// `L`
+    Lexeme,
+}
+
+impl EntityKind {
+    fn prefix(self) -> char {
+        match self {
+            EntityKind::Item => 'Q',
+            EntityKind::Property => 'P',
+            EntityKind::Lexeme => 'L',
+        }
+    }
+
+    /// Namespace name pages of this kind are in, empty for the main
+    /// namespace
+    fn namespace(self) -> &'static str {
+        match self {
+            EntityKind::Item => "",
+            EntityKind::Property => "Property",
+            EntityKind::Lexeme => "Lexeme",
+        }
+    }
+}
+
+/// ID of a Wikidata entity, e.g. `Q42`
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+pub struct EntityId {
+    pub kind: EntityKind,
+    pub number: u64,
+}
+
+impl EntityId {
+    /// Parse a bare ID like `Q42`, `P31` or `L1`
+    pub fn parse(id: &str) -> Option<Self> {
+        let mut chars = id.chars();
+        let kind = match chars.next()?.to_ascii_uppercase() {
+            'Q' => EntityKind::Item,
+            'P' => EntityKind::Property,
+            'L' => EntityKind::Lexeme,
+            _ => return None,
+        };
+        let digits = chars.as_str();
+        if digits.is_empty()
+            || digits.starts_with('0')
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        Some(Self {
+            kind,
+            number: digits.parse().ok()?,
+        })
+    }
+
+    /// The entity a page is about, from its prefixed title, e.g.
+    /// `Property:P31`. `None` for pages that aren't entities, like talk
+    /// pages or items outside the main namespace.
+    pub fn from_title(title: &str) -> Option<Self> {
+        let (namespace, id) = title.split_once(':').unwrap_or(("", title));
+        let id = Self::parse(id)?;
+        if id.kind.namespace() == namespace {
+            Some(id)
+        } else {
+            None
+        }
+    }
+
+    /// Prefixed title of the entity's page, e.g. `Property:P31`
+    pub fn title(&self) -> String {
+        match self.kind.namespace() {
+            "" => self.to_string(),
+            namespace => format!("{}:{}", namespace, self),
+        }
+    }
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.kind.prefix(), self.number)
+    }
+}
+
+/// What an edit changed, going by the Wikibase API module that made it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Change {
+    ClaimAdded,
+    ClaimChanged,
+    ClaimRemoved,
+    /// Qualifiers of a claim added, changed or removed
+    Qualifiers,
+    /// References of a claim added, changed or removed
+    References,
+    Label,
+    Description,
+    Aliases,
+    /// Labels, descriptions and aliases changed together
+    Terms,
+    Sitelink,
+    /// A new entity
+    Created,
+    /// Several parts of the entity changed at once, e.g. by a tool
+    /// editing the whole entity
+    Edited,
+    /// Merged into or from another item
+    Merged,
+    /// Turned into a redirect
+    Redirected,
+    /// A module not listed above, see [`Summary::module`]
+    Other,
+}
+
+/// A parsed Wikibase edit summary
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    /// API module and action, e.g. `wbsetclaim-create`
+    pub module: String,
+    pub change: Change,
+    /// Arguments after the module, e.g. `["2", "", "1"]`
+    pub args: Vec<String>,
+    /// The part after the autocomment, usually the value set or a
+    /// user-provided summary
+    pub text: String,
+}
+
+impl Summary {
+    /// Parse the summary of an edit made through the Wikibase API, e.g.
+    /// `/* wbsetlabel-set:1|en */ Douglas Adams`. `None` for summaries
+    /// that don't start with a Wikibase autocomment.
+    pub fn parse(comment: &str) -> Option<Self> {
+        let rest = comment.trim_start().strip_prefix("/*")?;
+        let (autocomment, text) = rest.split_once("*/")?;
+        let autocomment = autocomment.trim();
+        let (module, args) =
+            autocomment.split_once(':').unwrap_or((autocomment, ""));
+        if !module.starts_with("wb") {
+            return None;
+        }
+        Some(Self {
+            module: module.to_string(),
+            change: classify(module),
+            args: if args.is_empty() {
+                vec![]
+            } else {
+                args.split('|').map(str::to_string).collect()
+            },
+            text: text.trim().to_string(),
+        })
+    }
+
+    /// Language of the label, description or aliases that changed
+    pub fn language(&self) -> Option<&str> {
+        match self.change {
+            Change::Label | Change::Description | Change::Aliases => {
+                self.args.get(1).map(String::as_str)
+            }
+            _ => None,
+        }
+    }
+
+    /// Site ID of the sitelink that changed, e.g. `enwiki`
+    pub fn site(&self) -> Option<&str> {
+        match self.change {
+            Change::Sitelink => self.args.get(1).map(String::as_str),
+            _ => None,
+        }
+    }
+
+    /// The first property linked from the summary text, which for claim
+    /// changes is the claim's property
+    pub fn property(&self) -> Option<EntityId> {
+        self.text
+            .split("[[")
+            .skip(1)
+            .filter_map(|link| link.split([']', '|']).next())
+            .filter_map(EntityId::from_title)
+            .find(|id| id.kind == EntityKind::Property)
+    }
+}
+
+fn classify(module: &str) -> Change {
+    let (name, action) = module.split_once('-').unwrap_or((module, ""));
+    match name {
+        "wbcreateclaim" => Change::ClaimAdded,
+        "wbsetclaim" if action == "create" => Change::ClaimAdded,
+        "wbsetclaim" | "wbsetclaimvalue" => Change::ClaimChanged,
+        "wbremoveclaims" => Change::ClaimRemoved,
+        "wbsetqualifier" | "wbremovequalifiers" => Change::Qualifiers,
+        "wbsetreference" | "wbremovereferences" => Change::References,
+        "wbsetlabel" => Change::Label,
+        "wbsetdescription" => Change::Description,
+        "wbsetaliases" => Change::Aliases,
+        "wbsetlabeldescriptionaliases" => Change::Terms,
+        "wbsetsitelink" | "wblinktitles" => Change::Sitelink,
+        "wbeditentity" if action.starts_with("create") => Change::Created,
+        "wbeditentity" => Change::Edited,
+        "wbmergeitems" => Change::Merged,
+        "wbcreateredirect" => Change::Redirected,
+        _ => Change::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_entity_ids_from_titles() {
+        let property = EntityId::from_title("Property:P31").unwrap();
+        assert_eq!(property.kind, EntityKind::Property);
+        assert_eq!(property.number, 31);
+        assert_eq!(property.title(), "Property:P31");
+        assert_eq!(EntityId::from_title("Q42").unwrap().title(), "Q42");
+        assert_eq!(EntityId::parse("l1").unwrap().to_string(), "L1");
+        for title in ["Talk:Q42", "Property:Q42", "P31", "Q042", "Q", "Q4a"] {
+            assert_eq!(EntityId::from_title(title), None, "{}", title);
+        }
+    }
+
+    #[test]
+    fn parses_wikibase_summaries() {
+        let claim = Summary::parse(
+            "/* wbsetclaim-create:2||1 */ [[Property:P31]]: [[Q5]]",
+        )
+        .unwrap();
+        assert_eq!(claim.module, "wbsetclaim-create");
+        assert_eq!(claim.change, Change::ClaimAdded);
+        assert_eq!(claim.args, ["2", "", "1"]);
+        assert_eq!(claim.property(), EntityId::parse("P31"));
+        assert_eq!(claim.language(), None);
+
+        let label =
+            Summary::parse("/* wbsetlabel-set:1|en */ Douglas Adams").unwrap();
+        assert_eq!(label.change, Change::Label);
+        assert_eq!(label.language(), Some("en"));
+        assert_eq!(label.text, "Douglas Adams");
+
+        let sitelink =
+            Summary::parse("/* wbsetsitelink-add:1|enwiki */ A").unwrap();
+        assert_eq!(sitelink.site(), Some("enwiki"));
+        let created = Summary::parse("/* wbeditentity-create-item:0| */");
+        assert_eq!(created.unwrap().change, Change::Created);
+        let other = Summary::parse("/* wbfuture */").unwrap();
+        assert_eq!(other.change, Change::Other);
+        assert!(other.args.is_empty());
+        assert_eq!(Summary::parse("/* Early life */ typo"), None);
+        assert_eq!(Summary::parse("Fixed a typo"), None);
+    }
+}
@@ -0,0 +1,500 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Event-time windowing
+//!
+//! Windows are based on when an event happened (`meta.dt`), not when it
+//! was received. A window is closed once the [`Watermark`] passes its end;
+//! events that show up for an already closed window are emitted separately
+//! as [`WindowOutput::Late`].
+use crate::metrics::{Metrics, Reporter};
+use crate::watermark::Watermark;
+use crate::{EditEvent, Event};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// A closed window and all of the events that happened during it
+#[derive(Clone, Debug)]
+pub struct Window {
+    /// Inclusive start of the window
+    pub start: DateTime<Utc>,
+    /// Exclusive end of the window
+    pub end: DateTime<Utc>,
+    /// Events in the window, in order of arrival
+    pub events: Vec<Event>,
+}
+
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)] // late events should be rare
+pub enum WindowOutput {
+    /// A window that has been closed
+    Window(Window),
+    /// An event whose window was already closed when it arrived
+    Late(Event),
+}
+
+/// Groups events into fixed-size, non-overlapping windows aligned to the
+/// Unix epoch
+#[derive(Clone, Debug)]
+pub struct TumblingWindows {
+    size: Duration,
+    watermark: Watermark,
+    open: BTreeMap<DateTime<Utc>, Vec<Event>>,
+    buffered: usize,
+    max_buffered: Option<usize>,
+    /// End of the last window closed early, before the watermark passed it
+    closed_early: Option<DateTime<Utc>>,
+    reporter: Option<Reporter>,
+}
+
+impl TumblingWindows {
+    /// Create windows of length `size`, at least a millisecond, which stay
+    /// open until events are `allowed_lateness` past their end
+    pub fn new(
+        size: std::time::Duration,
+        allowed_lateness: std::time::Duration,
+    ) -> Self {
+        let size = Duration::from_std(size).expect("window size out of range");
+        // Windows are aligned in whole milliseconds
+        assert!(
+            size.num_milliseconds() > 0,
+            "window size must be at least a millisecond"
+        );
+        Self {
+            size,
+            watermark: Watermark::new(allowed_lateness),
+            open: BTreeMap::new(),
+            buffered: 0,
+            max_buffered: crate::metrics::bounded(),
+            closed_early: None,
+            reporter: None,
+        }
+    }
+
+    /// Hold at most `max` events across all open windows. If there are more,
+    /// the oldest windows are closed early, and events that show up for
+    /// them afterwards are [late](WindowOutput::Late). No more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap, if it's on.
+    pub fn max_buffered(mut self, max: usize) -> Self {
+        self.max_buffered = Some(crate::metrics::cap(max));
+        self
+    }
+
+    /// Report buffered events to `metrics`, as `<name>.buffered`, and
+    /// windows closed early as `<name>.evicted`
+    pub fn with_metrics(mut self, metrics: Metrics, name: &str) -> Self {
+        self.reporter = Some(Reporter::new(metrics, name));
+        self
+    }
+
+    /// Number of events in open windows
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Start and end of the window `dt` falls in, unless either is out of
+    /// range for chrono
+    fn window(
+        &self,
+        dt: DateTime<Utc>,
+    ) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let size = self.size.num_milliseconds();
+        let millis = dt.timestamp_millis();
+        let start = Utc
+            .timestamp_millis_opt(millis - millis.rem_euclid(size))
+            .single()?;
+        Some((start, start.checked_add_signed(self.size)?))
+    }
+
+    /// Add an event, returning any windows that were closed as a result.
+    /// Events too close to the limits of time for their window to be
+    /// represented are [late](WindowOutput::Late).
+    pub fn push(&mut self, event: Event) -> Vec<WindowOutput> {
+        let (start, end) = match self.window(event.dt()) {
+            Some(window) => window,
+            None => return vec![WindowOutput::Late(event)],
+        };
+        let closed_at = self.watermark.current().max(self.closed_early);
+        if let Some(closed_at) = closed_at {
+            if end <= closed_at {
+                return vec![WindowOutput::Late(event)];
+            }
+        }
+        self.watermark.observe(&event);
+        self.open.entry(start).or_default().push(event);
+        self.buffered += 1;
+        let watermark = self.watermark.current().unwrap();
+        let mut closed = vec![];
+        let mut evicted = 0;
+        while let Some(entry) = self.open.first_entry() {
+            // Only windows with an end in range are opened
+            let end = *entry.key() + self.size;
+            let buffered = self.buffered;
+            let over_limit =
+                self.max_buffered.is_some_and(|max| buffered > max);
+            if end > watermark && !over_limit {
+                break;
+            }
+            if end > watermark {
+                evicted += 1;
+                self.closed_early = Some(end);
+            }
+            let (start, events) = entry.remove_entry();
+            self.buffered -= events.len();
+            closed.push(WindowOutput::Window(Window { start, end, events }));
+        }
+        if let Some(reporter) = &self.reporter {
+            reporter.buffered(self.buffered);
+            reporter.evicted(evicted);
+        }
+        closed
+    }
+
+    /// Close all remaining windows, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<Window> {
+        let size = self.size;
+        self.buffered = 0;
+        std::mem::take(&mut self.open)
+            .into_iter()
+            .map(|(start, events)| Window {
+                start,
+                end: start + size,
+                events,
+            })
+            .collect()
+    }
+}
+
+/// A run of edits by the same user on the same wiki
+#[derive(Clone, Debug)]
+pub struct EditSession {
+    pub user: String,
+    /// Internal database name of the wiki
+    pub wiki: String,
+    /// Edits in the session, in order of arrival
+    pub edits: Vec<EditEvent>,
+    /// Time between the first and last edit
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug)]
+struct OpenSession {
+    first: DateTime<Utc>,
+    last: DateTime<Utc>,
+    edits: Vec<EditEvent>,
+}
+
+/// Groups edits by the same user on the same wiki into sessions, where a
+/// session ends once the user hasn't edited for `gap`
+#[derive(Clone, Debug)]
+pub struct SessionWindows {
+    gap: Duration,
+    watermark: Watermark,
+    open: HashMap<(String, String), OpenSession>,
+    /// Keys of the open sessions, ordered by their last edit, so expired
+    /// and idle sessions can be found without scanning all of them
+    by_last: BTreeSet<(DateTime<Utc>, (String, String))>,
+    max_sessions: Option<usize>,
+    reporter: Option<Reporter>,
+}
+
+impl SessionWindows {
+    /// Create session windows separated by `gap`, which are kept open for
+    /// `allowed_lateness` longer in case of out-of-order edits
+    pub fn new(
+        gap: std::time::Duration,
+        allowed_lateness: std::time::Duration,
+    ) -> Self {
+        Self {
+            gap: Duration::from_std(gap).expect("session gap out of range"),
+            watermark: Watermark::new(allowed_lateness),
+            open: HashMap::new(),
+            by_last: BTreeSet::new(),
+            max_sessions: crate::metrics::bounded(),
+            reporter: None,
+        }
+    }
+
+    /// Keep at most `max` sessions open. If there are more, the sessions
+    /// that have been idle the longest are closed early. No more than the
+    /// [bounded mode](crate::metrics::set_bounded) cap, if it's on.
+    pub fn max_sessions(mut self, max: usize) -> Self {
+        self.max_sessions = Some(crate::metrics::cap(max));
+        self
+    }
+
+    /// Report open sessions to `metrics`, as `<name>.buffered`, and
+    /// sessions closed early as `<name>.evicted`
+    pub fn with_metrics(mut self, metrics: Metrics, name: &str) -> Self {
+        self.reporter = Some(Reporter::new(metrics, name));
+        self
+    }
+
+    /// Number of open sessions
+    pub fn buffered(&self) -> usize {
+        self.open.len()
+    }
+
+    /// Add an event, returning any sessions that were closed as a result.
+    /// Non-edit events are ignored.
+    pub fn push(&mut self, event: Event) -> Vec<EditSession> {
+        self.watermark.observe(&event);
+        let dt = event.dt();
+        let gap = self.gap;
+        let mut closed = vec![];
+        if let Event::Edit(edit) | Event::New(edit) = event {
+            let key = (edit.user.to_string(), edit.wiki.to_string());
+            // An edit more than `gap` away from the user's open session
+            // starts a new one, even if the watermark hasn't closed the old
+            // one yet
+            if let Some(session) = self.open.get(&key) {
+                if dt > session.last + gap || dt + gap < session.first {
+                    let session = self.open.remove(&key).unwrap();
+                    self.by_last.remove(&(session.last, key.clone()));
+                    closed.push(Self::close(key.clone(), session));
+                }
+            }
+            let session =
+                self.open.entry(key.clone()).or_insert_with(|| OpenSession {
+                    first: dt,
+                    last: dt,
+                    edits: vec![],
+                });
+            self.by_last.remove(&(session.last, key.clone()));
+            session.first = session.first.min(dt);
+            session.last = session.last.max(dt);
+            session.edits.push(edit);
+            self.by_last.insert((session.last, key));
+        }
+        let watermark = self.watermark.current().unwrap();
+        while let Some((last, _)) = self.by_last.first() {
+            if *last + gap > watermark {
+                break;
+            }
+            closed.push(self.close_idlest());
+        }
+        let mut evicted = 0;
+        while self.max_sessions.is_some_and(|max| self.open.len() > max) {
+            closed.push(self.close_idlest());
+            evicted += 1;
+        }
+        if let Some(reporter) = &self.reporter {
+            reporter.buffered(self.open.len());
+            reporter.evicted(evicted);
+        }
+        closed
+    }
+
+    /// Close the session whose last edit is the oldest
+    fn close_idlest(&mut self) -> EditSession {
+        let (_, key) = self.by_last.pop_first().unwrap();
+        let session = self.open.remove(&key).unwrap();
+        Self::close(key, session)
+    }
+
+    /// Close all remaining sessions, e.g. once the stream has ended
+    pub fn flush(&mut self) -> Vec<EditSession> {
+        self.by_last.clear();
+        std::mem::take(&mut self.open)
+            .into_iter()
+            .map(|(key, session)| Self::close(key, session))
+            .collect()
+    }
+
+    fn close(
+        (user, wiki): (String, String),
+        session: OpenSession,
+    ) -> EditSession {
+        EditSession {
+            user,
+            wiki,
+            edits: session.edits,
+            duration: session.last - session.first,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, at};
+    use std::time::Duration as StdDuration;
+
+    const MINUTE: StdDuration = StdDuration::from_secs(60);
+
+    fn edit_by(offset: u64, user: &str, dt: DateTime<Utc>) -> Event {
+        let mut edit = testing::edit(offset, "Foo", dt);
+        edit["user"] = user.into();
+        testing::event(&edit)
+    }
+
+    fn windows(outputs: Vec<WindowOutput>) -> Vec<Window> {
+        outputs
+            .into_iter()
+            .map(|output| match output {
+                WindowOutput::Window(window) => window,
+                WindowOutput::Late(event) => panic!("late: {:?}", event),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tumbling_windows_close_once_the_watermark_passes() {
+        let mut tumbling = TumblingWindows::new(MINUTE, StdDuration::ZERO);
+        assert!(tumbling.push(edit_by(1, "A", at(10))).is_empty());
+        assert!(tumbling.push(edit_by(2, "A", at(30))).is_empty());
+        assert_eq!(tumbling.buffered(), 2);
+        let closed = windows(tumbling.push(edit_by(3, "A", at(90))));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start, at(0));
+        assert_eq!(closed[0].end, at(60));
+        assert_eq!(closed[0].events.len(), 2);
+        assert_eq!(tumbling.buffered(), 1);
+        let flushed = tumbling.flush();
+        assert_eq!(flushed.len(), 1);
+        assert_eq!(flushed[0].start, at(60));
+    }
+
+    #[test]
+    fn tumbling_windows_emit_late_events_separately() {
+        let mut tumbling = TumblingWindows::new(MINUTE, StdDuration::ZERO);
+        tumbling.push(edit_by(1, "A", at(0)));
+        tumbling.push(edit_by(2, "A", at(120)));
+        let outputs = tumbling.push(edit_by(3, "A", at(0)));
+        assert!(matches!(outputs.as_slice(), [WindowOutput::Late(_)]));
+    }
+
+    #[test]
+    fn tumbling_windows_wait_for_allowed_lateness() {
+        let mut tumbling = TumblingWindows::new(MINUTE, MINUTE);
+        tumbling.push(edit_by(1, "A", at(0)));
+        assert!(tumbling.push(edit_by(2, "A", at(90))).is_empty());
+        // Still within the allowed lateness, so not late
+        assert!(tumbling.push(edit_by(3, "A", at(30))).is_empty());
+        let closed = windows(tumbling.push(edit_by(4, "A", at(120))));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].events.len(), 2);
+    }
+
+    #[test]
+    fn tumbling_windows_treat_events_at_the_limits_of_time_as_late() {
+        // Sized so the window of the earliest time would start before it
+        let size = StdDuration::from_secs(13);
+        let mut tumbling = TumblingWindows::new(size, StdDuration::ZERO);
+        for dt in [DateTime::<Utc>::MIN_UTC, DateTime::<Utc>::MAX_UTC] {
+            let mut event = edit_by(1, "A", at(0));
+            event.meta_mut().dt = dt;
+            let outputs = tumbling.push(event);
+            assert!(matches!(outputs.as_slice(), [WindowOutput::Late(_)]));
+        }
+        // Without moving the watermark
+        assert!(tumbling.push(edit_by(2, "A", at(0))).is_empty());
+        assert_eq!(tumbling.flush().len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least a millisecond")]
+    fn tumbling_windows_reject_sub_millisecond_sizes() {
+        TumblingWindows::new(StdDuration::from_micros(10), StdDuration::ZERO);
+    }
+
+    #[test]
+    fn sessions_end_after_a_gap() {
+        let mut sessions = SessionWindows::new(5 * MINUTE, StdDuration::ZERO);
+        assert!(sessions.push(edit_by(1, "A", at(0))).is_empty());
+        assert!(sessions.push(edit_by(2, "A", at(60))).is_empty());
+        assert!(sessions.push(edit_by(3, "B", at(60))).is_empty());
+        assert_eq!(sessions.buffered(), 2);
+        let mut closed = sessions.push(edit_by(4, "C", at(660)));
+        closed.sort_by(|a, b| a.user.cmp(&b.user));
+        assert_eq!(closed.len(), 2);
+        assert_eq!(closed[0].user, "A");
+        assert_eq!(closed[0].edits.len(), 2);
+        assert_eq!(closed[0].duration, Duration::minutes(1));
+        assert_eq!(closed[1].user, "B");
+        assert_eq!(sessions.flush().len(), 1);
+    }
+
+    #[test]
+    fn sessions_are_extended_by_later_edits() {
+        let mut sessions = SessionWindows::new(5 * MINUTE, StdDuration::ZERO);
+        sessions.push(edit_by(1, "A", at(0)));
+        sessions.push(edit_by(2, "A", at(240)));
+        // A's session started over 5 minutes ago, but it's still going
+        assert!(sessions.push(edit_by(3, "B", at(360))).is_empty());
+        let closed = sessions.push(edit_by(4, "B", at(600)));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].user, "A");
+        assert_eq!(closed[0].duration, Duration::minutes(4));
+    }
+
+    #[test]
+    fn sessions_end_after_a_gap_without_other_traffic() {
+        let mut sessions = SessionWindows::new(5 * MINUTE, StdDuration::ZERO);
+        assert!(sessions.push(edit_by(1, "A", at(0))).is_empty());
+        let closed = sessions.push(edit_by(2, "A", at(600)));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].edits.len(), 1);
+        assert_eq!(closed[0].edits[0].meta.offset, 1);
+        let open = sessions.flush();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].edits[0].meta.offset, 2);
+
+        // Same for an out-of-order edit long before the session
+        let mut sessions = SessionWindows::new(5 * MINUTE, 60 * MINUTE);
+        sessions.push(edit_by(1, "A", at(600)));
+        let closed = sessions.push(edit_by(2, "A", at(0)));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].edits[0].meta.offset, 1);
+    }
+
+    #[test]
+    fn sessions_idle_the_longest_are_evicted_first() {
+        let mut sessions =
+            SessionWindows::new(5 * MINUTE, StdDuration::ZERO).max_sessions(2);
+        sessions.push(edit_by(1, "A", at(0)));
+        sessions.push(edit_by(2, "B", at(10)));
+        sessions.push(edit_by(3, "A", at(20)));
+        let closed = sessions.push(edit_by(4, "C", at(30)));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].user, "B");
+        assert_eq!(sessions.buffered(), 2);
+    }
+
+    #[test]
+    fn tumbling_windows_close_early_past_max_buffered() {
+        let metrics = Metrics::new();
+        let mut tumbling = TumblingWindows::new(MINUTE, 10 * MINUTE)
+            .max_buffered(2)
+            .with_metrics(metrics.clone(), "windows");
+        tumbling.push(edit_by(1, "A", at(0)));
+        tumbling.push(edit_by(2, "A", at(60)));
+        let closed = windows(tumbling.push(edit_by(3, "A", at(120))));
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start, at(0));
+        assert_eq!(tumbling.buffered(), 2);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.gauges["windows.buffered"], 2);
+        assert_eq!(snapshot.counters["windows.evicted"], 1);
+        // The window closed early isn't opened again
+        let late = tumbling.push(edit_by(4, "A", at(30)));
+        assert!(matches!(&late[..], [WindowOutput::Late(_)]));
+        assert_eq!(tumbling.buffered(), 2);
+        let flushed = tumbling.flush();
+        assert_eq!(flushed.len(), 2);
+        assert_eq!(flushed[0].start, at(60));
+    }
+}
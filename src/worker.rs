@@ -0,0 +1,212 @@
+/*
+Copyright (C) 2020-2021 Kunal Mehta <legoktm@member.fsf.org>
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+//! Streaming on a background thread
+//!
+//! An [`EventStream`] can't be moved between threads once it's connected,
+//! so [`EventStreamBuilder::spawn()`](crate::EventStreamBuilder::spawn)
+//! connects on a thread of its own and forwards events over a channel. The
+//! returned [`StreamWorker`] stops that thread when it's dropped, or can be
+//! [shut down](StreamWorker::shutdown) to also collect whatever was still
+//! in flight.
+use crate::clock::Clock;
+use crate::report::ShutdownReport;
+use crate::{Event, EventStream, Overflow};
+use futures::channel::oneshot;
+use futures::future::{self, Either};
+use futures::{Future, StreamExt};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// How often [`StreamWorker::shutdown()`] empties the channel while
+/// waiting
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Send events from `stream` to `sender` until the receiver is gone or
+/// `stop` completes
+pub(crate) fn forward(
+    mut stream: EventStream,
+    sender: SyncSender<Event>,
+    overflow: Overflow,
+    mut stop: impl Future + Unpin,
+) -> ShutdownReport {
+    futures::executor::block_on(async {
+        loop {
+            let event = match future::select(stream.next(), &mut stop).await {
+                Either::Left((Some(event), _)) => event,
+                _ => break,
+            };
+            let receiving = match &overflow {
+                Overflow::Block => sender.send(event).is_ok(),
+                Overflow::Drop(drop_logger) => match sender.try_send(event) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(event)) => {
+                        drop_logger
+                            .record("channel full", Some(event.server_name()));
+                        true
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                },
+            };
+            if !receiving {
+                break;
+            }
+        }
+    });
+    stream.shutdown()
+}
+
+/// What a [`StreamWorker`] left behind once it stopped
+#[derive(Debug)]
+pub struct Drained {
+    /// Events that were sent but not received yet, in order
+    pub events: Vec<Event>,
+    pub report: ShutdownReport,
+}
+
+/// Handle to a stream running on a background thread, see
+/// [`EventStreamBuilder::spawn()`](crate::EventStreamBuilder::spawn)
+#[derive(Debug)]
+pub struct StreamWorker {
+    receiver: mpsc::Receiver<Event>,
+    stop: Option<oneshot::Sender<()>>,
+    done: mpsc::Receiver<ShutdownReport>,
+    thread: Option<JoinHandle<()>>,
+    /// Events drained by a shutdown that timed out
+    drained: Vec<Event>,
+    /// Measures the shutdown timeout
+    clock: Arc<dyn Clock>,
+}
+
+impl StreamWorker {
+    pub(crate) fn spawn(
+        connect: impl FnOnce() -> EventStream + Send + 'static,
+        capacity: usize,
+        overflow: Overflow,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let (stop, stopped) = oneshot::channel();
+        let (finished, done) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            let report = forward(connect(), sender, overflow, stopped);
+            let _ = finished.send(report);
+        });
+        Self {
+            receiver,
+            stop: Some(stop),
+            done,
+            thread: Some(thread),
+            drained: vec![],
+            clock,
+        }
+    }
+
+    /// Channel the events are sent to
+    pub fn receiver(&self) -> &mpsc::Receiver<Event> {
+        &self.receiver
+    }
+
+    /// Take the channel the events are sent to, so it can be received
+    /// from without borrowing the worker. [`shutdown()`](Self::shutdown)
+    /// can't collect events from it anymore, and a thread waiting for room
+    /// in it only finishes once it's emptied or dropped.
+    #[cfg(feature = "python")]
+    pub(crate) fn take_receiver(&mut self) -> mpsc::Receiver<Event> {
+        std::mem::replace(&mut self.receiver, mpsc::sync_channel(0).1)
+    }
+
+    /// Stop the thread and wait up to `timeout` by the stream's
+    /// [clock](crate::EventStreamBuilder::clock) (or for as long as it
+    /// takes, if `None`) for it to finish, collecting the events it had
+    /// already sent. The channel keeps being emptied in the meantime, so a
+    /// thread waiting for room can finish too. If the thread doesn't
+    /// finish in time, the worker is handed back, e.g. to try again.
+    pub fn shutdown(
+        mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Drained, StreamWorker> {
+        drop(self.stop.take());
+        let deadline = timeout.map(|timeout| {
+            self.clock.now()
+                + chrono::Duration::from_std(timeout)
+                    .expect("timeout out of range")
+        });
+        loop {
+            self.drained.extend(self.receiver.try_iter());
+            match self.done.recv_timeout(DRAIN_INTERVAL) {
+                Ok(report) => {
+                    self.join();
+                    self.drained.extend(self.receiver.try_iter());
+                    return Ok(Drained {
+                        events: std::mem::take(&mut self.drained),
+                        report,
+                    });
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    // The thread panicked before it could report, so this
+                    // passes the panic on
+                    self.join();
+                    unreachable!("the thread ended without a report");
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if deadline
+                        .is_some_and(|deadline| self.clock.now() >= deadline)
+                    {
+                        return Err(self);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the thread to end, passing on its panic if it had one
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if let Err(panic) = thread.join() {
+                std::panic::resume_unwind(panic);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, at, MockServer};
+    use crate::{EventStreamBuilder, Overflow};
+    use std::time::Duration;
+
+    #[test]
+    fn hands_back_events_in_flight_on_shutdown() {
+        let messages = ["A", "B", "C"].iter().enumerate().map(|(i, title)| {
+            testing::edit(i as u64, title, at(0)).to_string()
+        });
+        let server = MockServer::new(messages).start().unwrap();
+        let worker = EventStreamBuilder::new()
+            .url(server.url())
+            .spawn(1, Overflow::Block)
+            .unwrap();
+        let first = worker.receiver().recv_timeout(Duration::from_secs(5));
+        let mut titles = vec![first.unwrap().title().to_string()];
+        let drained = worker.shutdown(Some(Duration::from_secs(5))).unwrap();
+        titles.extend(drained.events.iter().map(|e| e.title().to_string()));
+        // Whatever had been read is handed back, in order
+        assert_eq!(titles, ["A", "B", "C"][..titles.len()]);
+        assert_eq!(drained.report.total_events(), titles.len() as u64);
+    }
+}